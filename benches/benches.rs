@@ -1,6 +1,7 @@
 use criterion::Criterion;
 use std::time::Duration;
 
+mod filter;
 mod mem;
 fn main() {
     let mut c = Criterion::default()
@@ -10,5 +11,6 @@ fn main() {
         .configure_from_args();
     mem::arena::bench_arena(&mut c);
     mem::skiplist::bench_skiplist(&mut c);
+    filter::bloom::bench_bloom(&mut c);
     c.final_summary();
 }
@@ -0,0 +1,54 @@
+use criterion::{Bencher, BenchmarkId, Criterion};
+use wickdb::filter::FilterPolicy;
+use wickdb::BloomFilter;
+
+static KEYS_NUM: [usize; 3] = [1000, 100_000, 1_000_000];
+
+fn new_keys(n: usize, offset: usize) -> Vec<Vec<u8>> {
+    (offset..offset + n)
+        .map(|i| format!("key{:010}", i).into_bytes())
+        .collect()
+}
+
+fn bench_may_contain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BloomFilter::may_contain");
+    let policy = BloomFilter::new(10);
+    for n in KEYS_NUM.iter() {
+        let keys = new_keys(*n, 0);
+        let filter = policy.create_filter(&keys.iter().map(|k| k.as_slice()).collect());
+        let probes = new_keys(1000, n / 2);
+        group.bench_with_input(BenchmarkId::from_parameter(n), n, |b: &mut Bencher, _| {
+            b.iter(|| {
+                probes
+                    .iter()
+                    .filter(|k| policy.may_contain(&filter, k))
+                    .count()
+            })
+        });
+    }
+}
+
+fn bench_may_contain_many(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BloomFilter::may_contain_many");
+    let policy = BloomFilter::new(10);
+    for n in KEYS_NUM.iter() {
+        let keys = new_keys(*n, 0);
+        let filter = policy.create_filter(&keys.iter().map(|k| k.as_slice()).collect());
+        let probes = new_keys(1000, n / 2);
+        let probes: Vec<&[u8]> = probes.iter().map(|k| k.as_slice()).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(n), n, |b: &mut Bencher, _| {
+            b.iter(|| {
+                policy
+                    .may_contain_many(&filter, &probes)
+                    .into_iter()
+                    .filter(|r| *r)
+                    .count()
+            })
+        });
+    }
+}
+
+pub fn bench_bloom(c: &mut Criterion) {
+    bench_may_contain(c);
+    bench_may_contain_many(c);
+}
@@ -1,11 +1,29 @@
+use crate::db::column_family::{is_reserved_key, is_reserved_range, ColumnFamilyHandle};
 use crate::db::format::ValueType;
 use crate::mem::MemTable;
 use crate::util::coding::{decode_fixed_32, decode_fixed_64, encode_fixed_32, encode_fixed_64};
+use crate::util::hash::hash;
 use crate::util::varint::VarintU32;
 use crate::{Comparator, Error, Result};
 
 pub const HEADER_SIZE: usize = 12;
 
+/// The fewest records of a part worth being inserted by another thread, so a
+/// small batch is not slowed down by handing it over
+pub(crate) const MIN_RECORDS_PER_INSERTER: u32 = 64;
+
+/// A continuous part of the records in a `WriteBatch`, e.g. the batch of a
+/// writer in a write group, which is inserted into the memtable separately
+#[derive(Clone, Debug)]
+pub(crate) struct BatchPart {
+    // The sequence of the first record
+    pub seq: u64,
+    // The offsets of the encoded records in the batch
+    pub start: usize,
+    pub end: usize,
+    pub count: u32,
+}
+
 /// `WriteBatch` holds a collection of updates to apply atomically to a DB.
 ///
 ///
@@ -39,12 +57,22 @@ pub const HEADER_SIZE: usize = 12;
 #[derive(Clone)]
 pub struct WriteBatch {
     contents: Vec<u8>,
+    // Whether a key of the default column family takes the keyspace reserved
+    // for the column families, which makes the db reject the batch
+    has_reserved_keys: bool,
+    // The (record count, contents length, has_reserved_keys) at each save point,
+    // the latest last
+    save_points: Vec<(u32, usize, bool)>,
 }
 
 impl Default for WriteBatch {
     fn default() -> Self {
         let contents = vec![0; HEADER_SIZE];
-        Self { contents }
+        Self {
+            contents,
+            has_reserved_keys: false,
+            save_points: vec![],
+        }
     }
 }
 
@@ -56,6 +84,11 @@ impl WriteBatch {
 
     /// Stores the mapping "key -> value" in the database
     pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.has_reserved_keys |= is_reserved_key(key);
+        self.put_record(key, value)
+    }
+
+    fn put_record(&mut self, key: &[u8], value: &[u8]) {
         self.set_count(self.get_count() + 1);
         self.contents.push(ValueType::Value as u8);
         VarintU32::put_varint(&mut self.contents, key.len() as u32);
@@ -66,12 +99,66 @@ impl WriteBatch {
 
     /// If the database contains a mapping for "key", erase it. Else do nothing
     pub fn delete(&mut self, key: &[u8]) {
+        self.has_reserved_keys |= is_reserved_key(key);
+        self.delete_record(key)
+    }
+
+    fn delete_record(&mut self, key: &[u8]) {
         self.set_count(self.get_count() + 1);
         self.contents.push(ValueType::Deletion as u8);
         VarintU32::put_varint(&mut self.contents, key.len() as u32);
         self.contents.extend_from_slice(key);
     }
 
+    /// Stores a merge operand for "key", which is merged into the existing value
+    /// by `Options::merge_operator` when the key is read or compacted
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.has_reserved_keys |= is_reserved_key(key);
+        self.set_count(self.get_count() + 1);
+        self.contents.push(ValueType::Merge as u8);
+        VarintU32::put_varint(&mut self.contents, key.len() as u32);
+        self.contents.extend_from_slice(key);
+        VarintU32::put_varint(&mut self.contents, operand.len() as u32);
+        self.contents.extend_from_slice(operand);
+    }
+
+    /// Erases all the keys in the range `[begin, end)` in the database
+    pub fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.has_reserved_keys |= is_reserved_range(begin, end);
+        self.delete_range_record(begin, end)
+    }
+
+    fn delete_range_record(&mut self, begin: &[u8], end: &[u8]) {
+        self.set_count(self.get_count() + 1);
+        self.contents.push(ValueType::RangeDeletion as u8);
+        VarintU32::put_varint(&mut self.contents, begin.len() as u32);
+        self.contents.extend_from_slice(begin);
+        VarintU32::put_varint(&mut self.contents, end.len() as u32);
+        self.contents.extend_from_slice(end);
+    }
+
+    /// Stores the mapping "key -> value" in the given column family
+    pub fn put_cf(&mut self, cf: &ColumnFamilyHandle, key: &[u8], value: &[u8]) {
+        self.put_record(&cf.key(key), value)
+    }
+
+    /// Erases the mapping for "key" in the given column family if it exists
+    pub fn delete_cf(&mut self, cf: &ColumnFamilyHandle, key: &[u8]) {
+        self.delete_record(&cf.key(key))
+    }
+
+    /// Erases all the keys in the range `[begin, end)` in the given column family
+    pub fn delete_range_cf(&mut self, cf: &ColumnFamilyHandle, begin: &[u8], end: &[u8]) {
+        self.delete_range_record(&cf.key(begin), &cf.key(end))
+    }
+
+    /// Returns whether a key (or a range) of the default column family starts with
+    /// `COLUMN_FAMILY_KEY_PREFIX`, which is reserved for the column families
+    #[inline]
+    pub fn has_reserved_keys(&self) -> bool {
+        self.has_reserved_keys
+    }
+
     /// The size of the database changes caused by this batch.
     #[inline]
     pub fn approximate_size(&self) -> usize {
@@ -85,60 +172,149 @@ impl WriteBatch {
             "[batch] malformed WriteBatch (too small) to append"
         );
         self.set_count(self.get_count() + src.get_count());
+        self.has_reserved_keys |= src.has_reserved_keys;
         src.contents.drain(0..HEADER_SIZE);
         self.contents.append(&mut src.contents)
     }
 
-    /// Clears all updates buffered in this batch
+    /// Clears all updates buffered in this batch and all the save points
     #[inline]
     pub fn clear(&mut self) {
         self.contents.clear();
         self.contents.resize(HEADER_SIZE, 0);
         self.set_count(0);
+        self.has_reserved_keys = false;
+        self.save_points.clear();
+    }
+
+    /// Records the current state of the batch, which can be restored by
+    /// `rollback_to_save_point`. The save points are nested.
+    pub fn set_save_point(&mut self) {
+        self.save_points.push((
+            self.get_count(),
+            self.contents.len(),
+            self.has_reserved_keys,
+        ));
+    }
+
+    /// Removes the updates added since the latest save point, and the save
+    /// point itself
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::NotFound` if there is no save point
+    pub fn rollback_to_save_point(&mut self) -> Result<()> {
+        match self.save_points.pop() {
+            Some((count, size, has_reserved_keys)) => {
+                self.contents.truncate(size);
+                self.set_count(count);
+                self.has_reserved_keys = has_reserved_keys;
+                Ok(())
+            }
+            None => Err(Error::NotFound(Some(
+                "[batch] no save point to roll back".to_owned(),
+            ))),
+        }
+    }
+
+    /// Removes the latest save point without rolling back the updates
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::NotFound` if there is no save point
+    pub fn pop_save_point(&mut self) -> Result<()> {
+        match self.save_points.pop() {
+            Some(_) => Ok(()),
+            None => Err(Error::NotFound(Some(
+                "[batch] no save point to pop".to_owned(),
+            ))),
+        }
     }
 
     /// Insert all the records in the batch into the given `MemTable`
     pub fn insert_into<C: Comparator>(&self, mem: &MemTable<C>) -> Result<()> {
+        let mut inserter = MemTableInserter {
+            mem,
+            seq: self.get_sequence(),
+        };
+        self.iterate(&mut inserter)
+    }
+
+    /// Splits the records in the batch into the continuous parts holding `counts`
+    /// records each, which must add up to the count of the batch
+    pub(crate) fn split(&self, counts: &[u32]) -> Result<Vec<BatchPart>> {
+        let corrupted = || Error::Corruption("[batch] WriteBatch has wrong count".to_owned());
         if self.contents.len() < HEADER_SIZE {
             return Err(Error::Corruption(
                 "[batch] malformed WriteBatch (too small)".to_owned(),
             ));
         }
+        let mut parts = Vec::with_capacity(counts.len());
         let mut s = &self.contents[HEADER_SIZE..];
-        let mut found = 0;
         let mut seq = self.get_sequence();
-        while !s.is_empty() {
-            found += 1;
-            let tag = s[0];
-            s = &s[1..];
-            match ValueType::from(u64::from(tag)) {
-                ValueType::Value => {
-                    if let Some(key) = VarintU32::get_varint_prefixed_slice(&mut s) {
-                        if let Some(value) = VarintU32::get_varint_prefixed_slice(&mut s) {
-                            mem.add(seq, ValueType::Value, key, value);
-                            seq += 1;
-                            continue;
-                        }
-                    }
-                    return Err(Error::Corruption("[batch] bad WriteBatch put".to_owned()));
-                }
-                ValueType::Deletion => {
-                    if let Some(key) = VarintU32::get_varint_prefixed_slice(&mut s) {
-                        mem.add(seq, ValueType::Deletion, key, b"");
-                        seq += 1;
-                        continue;
-                    }
-                    return Err(Error::Corruption(
-                        "[batch] bad WriteBatch delete".to_owned(),
-                    ));
-                }
-                ValueType::Unknown => {
-                    return Err(Error::Corruption(
-                        "[batch] unknown WriteBatch value type".to_owned(),
-                    ))
-                }
+        for &count in counts {
+            let start = self.contents.len() - s.len();
+            if decode_records(&mut s, count, &mut Skipper)? != count {
+                return Err(corrupted());
             }
+            parts.push(BatchPart {
+                seq,
+                start,
+                end: self.contents.len() - s.len(),
+                count,
+            });
+            seq += u64::from(count);
+        }
+        if !s.is_empty() || seq - self.get_sequence() != u64::from(self.get_count()) {
+            return Err(corrupted());
+        }
+        Ok(parts)
+    }
+
+    /// Inserts the records of a part returned by `split` into the given `MemTable`.
+    /// The parts can be inserted by different threads concurrently if the
+    /// `MemTable` supports concurrent inserts.
+    pub(crate) fn insert_part_into<C: Comparator>(
+        &self,
+        part: &BatchPart,
+        mem: &MemTable<C>,
+    ) -> Result<()> {
+        let mut inserter = MemTableInserter { mem, seq: part.seq };
+        let mut data = &self.contents[part.start..part.end];
+        decode_records(&mut data, part.count, &mut inserter).map(|_| ())
+    }
+
+    /// Inserts the records in the batch whose keys fall into the `partition` of
+    /// `partitions` partitions by hash into the given `MemTable`. The range
+    /// deletions all fall into the partition 0. The records of a key are always in
+    /// the same partition, so they're inserted in order by the same thread.
+    pub(crate) fn insert_partition_into<C: Comparator>(
+        &self,
+        mem: &MemTable<C>,
+        partition: usize,
+        partitions: usize,
+    ) -> Result<()> {
+        let mut inserter = PartitionInserter {
+            inner: MemTableInserter {
+                mem,
+                seq: self.get_sequence(),
+            },
+            partition: partition as u32,
+            partitions: partitions as u32,
+        };
+        self.iterate(&mut inserter)
+    }
+
+    /// Decodes the records in the batch and calls `handler` for each of them
+    /// in the order they are added
+    pub fn iterate<H: WriteBatchHandler>(&self, handler: &mut H) -> Result<()> {
+        if self.contents.len() < HEADER_SIZE {
+            return Err(Error::Corruption(
+                "[batch] malformed WriteBatch (too small)".to_owned(),
+            ));
         }
+        let mut s = &self.contents[HEADER_SIZE..];
+        let found = decode_records(&mut s, u32::MAX, handler)?;
         if found != self.get_count() {
             return Err(Error::Corruption(
                 "[batch] WriteBatch has wrong count".to_owned(),
@@ -151,6 +327,8 @@ impl WriteBatch {
     pub(crate) fn set_contents(&mut self, src: &mut Vec<u8>) {
         self.contents.clear();
         self.contents.append(src);
+        self.has_reserved_keys = false;
+        self.save_points.clear();
     }
 
     /// Returns the number of entires included in this entry
@@ -182,9 +360,172 @@ impl WriteBatch {
     }
 }
 
+// Decodes at most `limit` records from `s` for `handler` and returns the
+// number of the decoded ones. `s` is advanced to the next record.
+fn decode_records<H: WriteBatchHandler>(s: &mut &[u8], limit: u32, handler: &mut H) -> Result<u32> {
+    let mut found = 0;
+    while found < limit && !s.is_empty() {
+        found += 1;
+        let tag = s[0];
+        *s = &s[1..];
+        match ValueType::from(u64::from(tag)) {
+            ValueType::Value => {
+                if let Some(key) = VarintU32::get_varint_prefixed_slice(s) {
+                    if let Some(value) = VarintU32::get_varint_prefixed_slice(s) {
+                        handler.put(key, value);
+                        continue;
+                    }
+                }
+                return Err(Error::Corruption("[batch] bad WriteBatch put".to_owned()));
+            }
+            ValueType::Merge => {
+                if let Some(key) = VarintU32::get_varint_prefixed_slice(s) {
+                    if let Some(operand) = VarintU32::get_varint_prefixed_slice(s) {
+                        handler.merge(key, operand);
+                        continue;
+                    }
+                }
+                return Err(Error::Corruption("[batch] bad WriteBatch merge".to_owned()));
+            }
+            ValueType::Deletion => {
+                if let Some(key) = VarintU32::get_varint_prefixed_slice(s) {
+                    handler.delete(key);
+                    continue;
+                }
+                return Err(Error::Corruption(
+                    "[batch] bad WriteBatch delete".to_owned(),
+                ));
+            }
+            ValueType::RangeDeletion => {
+                if let Some(begin) = VarintU32::get_varint_prefixed_slice(s) {
+                    if let Some(end) = VarintU32::get_varint_prefixed_slice(s) {
+                        handler.delete_range(begin, end);
+                        continue;
+                    }
+                }
+                return Err(Error::Corruption(
+                    "[batch] bad WriteBatch delete range".to_owned(),
+                ));
+            }
+            ValueType::Unknown => {
+                return Err(Error::Corruption(
+                    "[batch] unknown WriteBatch value type".to_owned(),
+                ))
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// `WriteBatchHandler` receives the records decoded by `WriteBatch::iterate`,
+/// e.g. to ship a batch to the replicas or to transform it into another one
+/// without knowing the encoding of the batch.
+pub trait WriteBatchHandler {
+    /// Called for a record added by `WriteBatch::put`
+    fn put(&mut self, key: &[u8], value: &[u8]);
+
+    /// Called for a record added by `WriteBatch::delete`
+    fn delete(&mut self, key: &[u8]);
+
+    /// Called for a record added by `WriteBatch::merge`
+    fn merge(&mut self, key: &[u8], operand: &[u8]);
+
+    /// Called for a record added by `WriteBatch::delete_range`
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]);
+}
+
+// Adds the records into a `MemTable` with the increasing sequence numbers
+struct MemTableInserter<'a, C: Comparator> {
+    mem: &'a MemTable<C>,
+    seq: u64,
+}
+
+impl<'a, C: Comparator> WriteBatchHandler for MemTableInserter<'a, C> {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.mem.add(self.seq, ValueType::Value, key, value);
+        self.seq += 1;
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.mem.add(self.seq, ValueType::Deletion, key, b"");
+        self.seq += 1;
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.mem.add(self.seq, ValueType::Merge, key, operand);
+        self.seq += 1;
+    }
+
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.mem.add_range_tombstone(self.seq, begin, end);
+        self.seq += 1;
+    }
+}
+
+// Adds the records of a partition into a `MemTable` and skips the others
+struct PartitionInserter<'a, C: Comparator> {
+    inner: MemTableInserter<'a, C>,
+    partition: u32,
+    partitions: u32,
+}
+
+impl<'a, C: Comparator> PartitionInserter<'a, C> {
+    fn owns(&self, key: &[u8]) -> bool {
+        hash(key, 0) % self.partitions == self.partition
+    }
+}
+
+impl<'a, C: Comparator> WriteBatchHandler for PartitionInserter<'a, C> {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        if self.owns(key) {
+            self.inner.put(key, value);
+        } else {
+            self.inner.seq += 1;
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        if self.owns(key) {
+            self.inner.delete(key);
+        } else {
+            self.inner.seq += 1;
+        }
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        if self.owns(key) {
+            self.inner.merge(key, operand);
+        } else {
+            self.inner.seq += 1;
+        }
+    }
+
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        if self.partition == 0 {
+            self.inner.delete_range(begin, end);
+        } else {
+            self.inner.seq += 1;
+        }
+    }
+}
+
+// Skips the records without doing anything
+struct Skipper;
+
+impl WriteBatchHandler for Skipper {
+    fn put(&mut self, _key: &[u8], _value: &[u8]) {}
+
+    fn delete(&mut self, _key: &[u8]) {}
+
+    fn merge(&mut self, _key: &[u8], _operand: &[u8]) {}
+
+    fn delete_range(&mut self, _begin: &[u8], _end: &[u8]) {}
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::batch::WriteBatch;
+    use crate::batch::{WriteBatch, WriteBatchHandler, HEADER_SIZE};
+    use crate::db::column_family::{ColumnFamilyHandle, COLUMN_FAMILY_KEY_PREFIX};
     use crate::db::format::{InternalKeyComparator, ParsedInternalKey, ValueType};
     use crate::iterator::Iterator;
     use crate::mem::MemTable;
@@ -217,6 +558,15 @@ mod tests {
                         s.push_str(tmp.as_str());
                         count += 1
                     }
+                    ValueType::Merge => {
+                        let tmp = format!(
+                            "Merge({}, {})",
+                            ikey.as_str(),
+                            std::str::from_utf8(iter.value()).unwrap()
+                        );
+                        s.push_str(tmp.as_str());
+                        count += 1
+                    }
                     _ => {}
                 }
                 s.push('@');
@@ -225,6 +575,18 @@ mod tests {
             }
             iter.next();
         }
+        for t in mem.range_tombstones() {
+            s.push_str(
+                format!(
+                    "DeleteRange({}, {})@{}|",
+                    std::str::from_utf8(&t.begin).unwrap(),
+                    std::str::from_utf8(&t.end).unwrap(),
+                    t.seq
+                )
+                .as_str(),
+            );
+            count += 1;
+        }
         if result.is_err() {
             s.push_str("ParseError()")
         } else if count != batch.get_count() {
@@ -240,6 +602,34 @@ mod tests {
         assert!(b.is_empty());
     }
 
+    #[test]
+    fn test_merge_records() {
+        let mut b = WriteBatch::default();
+        b.put("foo".as_bytes(), "bar".as_bytes());
+        b.merge("foo".as_bytes(), "baz".as_bytes());
+        b.merge("box".as_bytes(), "".as_bytes());
+        b.set_sequence(100);
+        assert_eq!(3, b.get_count());
+        assert_eq!(
+            "Merge(box, )@102|Merge(foo, baz)@101|Put(foo, bar)@100|",
+            print_contents(&b).as_str()
+        );
+    }
+
+    #[test]
+    fn test_delete_range_records() {
+        let mut b = WriteBatch::default();
+        b.put("foo".as_bytes(), "bar".as_bytes());
+        b.delete_range("a".as_bytes(), "g".as_bytes());
+        b.delete_range("x".as_bytes(), "z".as_bytes());
+        b.set_sequence(100);
+        assert_eq!(3, b.get_count());
+        assert_eq!(
+            "Put(foo, bar)@100|DeleteRange(a, g)@101|DeleteRange(x, z)@102|",
+            print_contents(&b).as_str()
+        );
+    }
+
     #[test]
     fn test_multiple_records() {
         let mut b = WriteBatch::default();
@@ -291,6 +681,186 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reserved_keys() {
+        let cf = ColumnFamilyHandle::new(1, "cf".to_owned());
+        let mut b = WriteBatch::default();
+        b.put(b"foo", b"v");
+        b.put_cf(&cf, b"foo", b"v");
+        b.delete_cf(&cf, b"foo");
+        b.delete_range_cf(&cf, b"a", b"z");
+        b.delete_range(b"a", COLUMN_FAMILY_KEY_PREFIX);
+        assert!(!b.has_reserved_keys());
+
+        b.set_save_point();
+        b.put(&cf.key(b"foo"), b"v");
+        assert!(b.has_reserved_keys());
+        b.rollback_to_save_point().unwrap();
+        assert!(!b.has_reserved_keys());
+
+        let mut other = WriteBatch::default();
+        other.delete_range(b"a", &cf.key(b""));
+        assert!(other.has_reserved_keys());
+        b.append(other);
+        assert!(b.has_reserved_keys());
+        b.clear();
+        assert!(!b.has_reserved_keys());
+        b.merge(COLUMN_FAMILY_KEY_PREFIX, b"v");
+        assert!(b.has_reserved_keys());
+    }
+
+    #[test]
+    fn test_save_points() {
+        let mut b = WriteBatch::default();
+        assert!(b.rollback_to_save_point().is_err());
+        assert!(b.pop_save_point().is_err());
+        b.set_sequence(100);
+        b.put("a".as_bytes(), "va".as_bytes());
+        b.set_save_point();
+        b.delete("a".as_bytes());
+        b.set_save_point();
+        b.put("b".as_bytes(), "vb".as_bytes());
+        b.merge("c".as_bytes(), "vc".as_bytes());
+        assert_eq!(4, b.get_count());
+        b.rollback_to_save_point().unwrap();
+        assert_eq!("Delete(a)@101|Put(a, va)@100|", print_contents(&b));
+        b.put("d".as_bytes(), "vd".as_bytes());
+        b.rollback_to_save_point().unwrap();
+        assert_eq!("Put(a, va)@100|", print_contents(&b));
+        assert!(b.rollback_to_save_point().is_err());
+
+        // A popped save point can't be rolled back to
+        b.set_save_point();
+        b.put("e".as_bytes(), "ve".as_bytes());
+        b.set_save_point();
+        b.pop_save_point().unwrap();
+        b.rollback_to_save_point().unwrap();
+        assert_eq!("Put(a, va)@100|", print_contents(&b));
+        b.set_save_point();
+        b.clear();
+        assert!(b.rollback_to_save_point().is_err());
+    }
+
+    // Rebuilds the batch from the records, which is the simplest transformation
+    #[derive(Default)]
+    struct Copier {
+        batch: WriteBatch,
+        records: Vec<String>,
+    }
+
+    impl WriteBatchHandler for Copier {
+        fn put(&mut self, key: &[u8], value: &[u8]) {
+            self.batch.put(key, value);
+            self.records.push(format!("put {:?}", key));
+        }
+
+        fn delete(&mut self, key: &[u8]) {
+            self.batch.delete(key);
+            self.records.push(format!("delete {:?}", key));
+        }
+
+        fn merge(&mut self, key: &[u8], operand: &[u8]) {
+            self.batch.merge(key, operand);
+            self.records.push(format!("merge {:?}", key));
+        }
+
+        fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+            self.batch.delete_range(begin, end);
+            self.records.push(format!("delete_range {:?}", begin));
+        }
+    }
+
+    #[test]
+    fn test_iterate() {
+        let mut b = WriteBatch::default();
+        b.put(b"a", b"va");
+        b.delete(b"b");
+        b.merge(b"c", b"vc");
+        b.delete_range(b"d", b"e");
+        b.set_sequence(100);
+        let mut copier = Copier::default();
+        b.iterate(&mut copier).unwrap();
+        assert_eq!(
+            copier.records,
+            vec![
+                "put [97]",
+                "delete [98]",
+                "merge [99]",
+                "delete_range [100]"
+            ]
+        );
+        assert_eq!(copier.batch.data()[HEADER_SIZE..], b.data()[HEADER_SIZE..]);
+        assert_eq!(copier.batch.get_count(), 4);
+
+        // Coalesce the copy into the source batch
+        b.append(copier.batch);
+        assert_eq!(b.get_count(), 8);
+        let mut copier = Copier::default();
+        b.iterate(&mut copier).unwrap();
+        assert_eq!(copier.records.len(), 8);
+
+        b.contents.truncate(b.contents.len() - 1);
+        assert!(b.iterate(&mut Copier::default()).is_err());
+    }
+
+    #[test]
+    fn test_insert_parts_concurrently() {
+        let mut b = WriteBatch::default();
+        for i in 0..1000 {
+            let key = format!("{:04}", i % 300);
+            match i % 10 {
+                3 => b.delete(key.as_bytes()),
+                7 => b.merge(key.as_bytes(), b"operand"),
+                9 if i % 100 == 9 => b.delete_range(key.as_bytes(), b"9999"),
+                _ => b.put(key.as_bytes(), format!("v{}", i).as_bytes()),
+            }
+        }
+        b.set_sequence(100);
+        let new_mem = || {
+            MemTable::new(
+                1 << 24,
+                InternalKeyComparator::new(BytewiseComparator::default()),
+            )
+        };
+        let entries = |mem: &MemTable<BytewiseComparator>| {
+            let mut iter = mem.iter();
+            iter.seek_to_first();
+            let mut entries = vec![];
+            while iter.valid() {
+                entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.next();
+            }
+            let mut tombstones = mem.range_tombstones();
+            tombstones.sort_by_key(|t| t.seq);
+            (entries, tombstones)
+        };
+        let expected = new_mem();
+        b.insert_into(&expected).unwrap();
+        let (expected_entries, expected_tombstones) = entries(&expected);
+        assert_eq!(expected_entries.len(), 990);
+        assert_eq!(expected_tombstones.len(), 10);
+        for counts in [vec![1000], vec![1, 999], vec![250, 0, 500, 250]] {
+            let mem = new_mem();
+            let parts = b.split(&counts).unwrap();
+            assert_eq!(parts.len(), counts.len());
+            crossbeam_utils::thread::scope(|scope| {
+                for part in parts.iter() {
+                    let (b, mem) = (&b, &mem);
+                    scope.spawn(move |_| b.insert_part_into(part, mem).unwrap());
+                }
+            })
+            .unwrap();
+            let (entries, tombstones) = entries(&mem);
+            assert_eq!(entries, expected_entries);
+            assert_eq!(tombstones, expected_tombstones);
+        }
+
+        assert!(b.split(&[500, 400]).is_err());
+        assert!(b.split(&[500, 600]).is_err());
+        b.set_count(1001);
+        assert!(b.split(&[1001]).is_err());
+    }
+
     #[test]
     fn test_approximate_size() {
         let mut b = WriteBatch::default();
@@ -0,0 +1,288 @@
+//! An admin tool operating on the db in a directory, e.g.
+//!
+//! ```text
+//! caskdb-ldb --db=<path> [--hex] <command> [<args>]
+//! ```
+//!
+//! See `USAGE` for the commands. The db must use the bytewise comparator. `get`
+//! and `scan` open the db read-only, while `put`, `delete` and `compact` fail
+//! if the db is opened by another process. `dump_manifest`, `dump_wal` and
+//! `checkconsistency` read the files directly without opening the db.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use wickdb::file::FileStorage;
+use wickdb::{
+    check_live_files, dump_manifest, dump_wal, BytewiseComparator, Error, Iterator, OpenMode,
+    Options, ReadOptions, ReadableKey, Result, Storage, WickDB, WriteOptions, DB,
+};
+
+const USAGE: &str = "usage: caskdb-ldb --db=<path> [--hex] [--create_if_missing] <command> [<args>]
+
+commands:
+  get <key>
+  put <key> <value>
+  delete <key>
+  scan [--from=<key>] [--to=<key>] [--max_keys=<n>]
+  compact [--from=<key>] [--to=<key>]
+  dump_manifest [<MANIFEST file>]      the current MANIFEST by default
+  dump_wal <log file> [--header_only]
+  checkconsistency
+
+--hex takes the keys and the values in hex and prints them in hex.
+--create_if_missing lets put create the db if it's missing.
+--from is inclusive and --to is exclusive for scan, both are inclusive for compact.";
+
+struct Args {
+    db: Option<String>,
+    hex: bool,
+    create_if_missing: bool,
+    from: Option<Vec<u8>>,
+    to: Option<Vec<u8>>,
+    max_keys: Option<usize>,
+    header_only: bool,
+    // The command and its positional arguments
+    positional: Vec<String>,
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let res = run(&args, &mut out);
+    let _ = out.flush();
+    match res {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("Failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_args() -> std::result::Result<Args, String> {
+    let mut args = Args {
+        db: None,
+        hex: false,
+        create_if_missing: false,
+        from: None,
+        to: None,
+        max_keys: None,
+        header_only: false,
+        positional: vec![],
+    };
+    let raw: Vec<String> = std::env::args().skip(1).collect();
+    // The keys are decoded after `--hex` is known
+    let (mut from, mut to) = (None, None);
+    for arg in raw.iter() {
+        if let Some(v) = arg.strip_prefix("--db=") {
+            args.db = Some(v.to_owned());
+        } else if let Some(v) = arg.strip_prefix("--from=") {
+            from = Some(v);
+        } else if let Some(v) = arg.strip_prefix("--to=") {
+            to = Some(v);
+        } else if let Some(v) = arg.strip_prefix("--max_keys=") {
+            args.max_keys = Some(v.parse().map_err(|_| format!("invalid --max_keys {}", v))?);
+        } else if arg == "--hex" {
+            args.hex = true;
+        } else if arg == "--create_if_missing" {
+            args.create_if_missing = true;
+        } else if arg == "--header_only" {
+            args.header_only = true;
+        } else if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        } else if arg.starts_with("--") {
+            return Err(format!("unknown option {}", arg));
+        } else {
+            args.positional.push(arg.clone());
+        }
+    }
+    args.from = from.map(|k| decode(&args, k)).transpose()?;
+    args.to = to.map(|k| decode(&args, k)).transpose()?;
+    Ok(args)
+}
+
+// Returns false if the command finds a problem
+fn run<W: Write>(args: &Args, out: &mut W) -> Result<bool> {
+    let (command, params) = match args.positional.split_first() {
+        Some((command, params)) => (command.as_str(), params),
+        None => return Err(Error::InvalidArgument("no command given".to_owned())),
+    };
+    let expect_params = |n: usize| {
+        if params.len() == n {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "{} takes {} arguments but {} are given",
+                command,
+                n,
+                params.len()
+            )))
+        }
+    };
+    let key = |i: usize| decode(args, &params[i]).map_err(Error::InvalidArgument);
+    let max_levels = Options::<BytewiseComparator>::default().max_levels;
+    match command {
+        "get" => {
+            expect_params(1)?;
+            let db = open(args, OpenMode::ReadOnly)?;
+            match db.get(ReadOptions::default(), &key(0)?)? {
+                Some(value) => writeln!(out, "{}", encode(args, &value)).map_err(Error::IO)?,
+                None => {
+                    writeln!(out, "NotFound").map_err(Error::IO)?;
+                    return Ok(false);
+                }
+            }
+        }
+        "put" => {
+            expect_params(2)?;
+            let open_mode = if args.create_if_missing {
+                OpenMode::OpenOrCreate
+            } else {
+                OpenMode::OpenExisting
+            };
+            let mut db = open(args, open_mode)?;
+            db.put(WriteOptions::default(), &key(0)?, &key(1)?)?;
+            db.close()?;
+            writeln!(out, "OK").map_err(Error::IO)?;
+        }
+        "delete" => {
+            expect_params(1)?;
+            let mut db = open(args, OpenMode::OpenExisting)?;
+            db.delete(WriteOptions::default(), &key(0)?)?;
+            db.close()?;
+            writeln!(out, "OK").map_err(Error::IO)?;
+        }
+        "scan" => {
+            expect_params(0)?;
+            let db = open(args, OpenMode::ReadOnly)?;
+            let mut iter = db.iter_range(
+                ReadOptions::default(),
+                args.from.as_deref(),
+                args.to.as_deref(),
+            )?;
+            iter.seek_to_first();
+            let mut n = 0;
+            while iter.valid() && args.max_keys.is_none_or(|max| n < max) {
+                writeln!(
+                    out,
+                    "{} => {}",
+                    encode(args, iter.key()),
+                    encode(args, iter.value())
+                )
+                .map_err(Error::IO)?;
+                n += 1;
+                iter.next();
+            }
+            iter.status()?;
+        }
+        "compact" => {
+            expect_params(0)?;
+            let mut db = open(args, OpenMode::OpenExisting)?;
+            db.compact_range(args.from.as_deref(), args.to.as_deref())?;
+            db.close()?;
+            writeln!(out, "OK").map_err(Error::IO)?;
+        }
+        "dump_manifest" => {
+            let path = match params {
+                [path] => path.clone(),
+                [] => {
+                    let db_path = db_path(args)?;
+                    let report = check_live_files(&FileStorage, db_path, max_levels)?;
+                    Path::new(db_path)
+                        .join(report.manifest)
+                        .to_string_lossy()
+                        .into_owned()
+                }
+                _ => return expect_params(1).map(|_| true),
+            };
+            let summary = dump_manifest(FileStorage.open(&path)?, max_levels, out)?;
+            return Ok(summary.dropped_bytes == 0);
+        }
+        "dump_wal" => {
+            expect_params(1)?;
+            let file = fs::File::open(&params[0]).map_err(Error::IO)?;
+            let summary = dump_wal(file, !args.header_only, out)?;
+            return Ok(summary.dropped_bytes == 0);
+        }
+        "checkconsistency" => {
+            expect_params(0)?;
+            let report = check_live_files(&FileStorage, db_path(args)?, max_levels)?;
+            for number in report.missing_files.iter() {
+                writeln!(out, "missing table #{}", number).map_err(Error::IO)?;
+            }
+            for (number, expect, actual) in report.size_mismatches.iter() {
+                writeln!(
+                    out,
+                    "table #{} is {} bytes but {} bytes in the MANIFEST",
+                    number, actual, expect
+                )
+                .map_err(Error::IO)?;
+            }
+            if !report.is_consistent() {
+                return Ok(false);
+            }
+            writeln!(
+                out,
+                "OK: {} live tables in {}",
+                report.files_checked, report.manifest
+            )
+            .map_err(Error::IO)?;
+        }
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "unknown command {}",
+                command
+            )))
+        }
+    }
+    Ok(true)
+}
+
+fn db_path(args: &Args) -> Result<&str> {
+    args.db
+        .as_deref()
+        .ok_or_else(|| Error::InvalidArgument("--db is required".to_owned()))
+}
+
+fn open(args: &Args, open_mode: OpenMode) -> Result<WickDB<FileStorage, BytewiseComparator>> {
+    let options = Options::<BytewiseComparator> {
+        open_mode,
+        ..Options::default()
+    };
+    WickDB::open_db(options, db_path(args)?, FileStorage)
+}
+
+// Decodes a key or a value given in the arguments
+fn decode(args: &Args, s: &str) -> std::result::Result<Vec<u8>, String> {
+    if !args.hex {
+        return Ok(s.as_bytes().to_vec());
+    }
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("invalid hex {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid hex {}", s)))
+        .collect()
+}
+
+// Formats a key or a value to print
+fn encode(args: &Args, bytes: &[u8]) -> String {
+    if args.hex {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    } else {
+        ReadableKey(bytes).to_string()
+    }
+}
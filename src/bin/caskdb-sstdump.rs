@@ -0,0 +1,113 @@
+//! Prints the structure of sstables for debugging, e.g.
+//!
+//! ```text
+//! caskdb-sstdump [--entries] [--hex] <file.sst>...
+//! caskdb-sstdump --dedup-fixed=<size> | --dedup-cdc=<avg size> <file.sst>...
+//! ```
+//!
+//! `--entries` prints all the entries of the data blocks, and `--hex` prints
+//! the raw internal keys and the values in hex besides the decoded ones.
+//! It exits with 1 if a table can't be read or has corrupted blocks.
+//!
+//! `--dedup-fixed` and `--dedup-cdc` report how much content of the decoded data
+//! blocks is duplicated across the files instead, cutting the blocks into chunks
+//! of a fixed size or content-defined chunks of about the given size.
+
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use wickdb::file::FileStorage;
+use wickdb::{analyze_dedup, dump_table, Chunking, DumpOptions, File};
+
+const USAGE: &str = "usage: caskdb-sstdump [--entries] [--hex] <file.sst>...
+       caskdb-sstdump --dedup-fixed=<size> | --dedup-cdc=<avg size> <file.sst>...";
+
+fn main() {
+    let mut options = DumpOptions::default();
+    let mut dedup = None;
+    let mut paths = vec![];
+    for arg in std::env::args().skip(1) {
+        let chunk_size = |v: &str| {
+            v.parse().unwrap_or_else(|_| {
+                eprintln!("invalid chunk size in {}\n{}", arg, USAGE);
+                process::exit(2);
+            })
+        };
+        if let Some(v) = arg.strip_prefix("--dedup-fixed=") {
+            dedup = Some(Chunking::FixedSize(chunk_size(v)));
+            continue;
+        }
+        if let Some(v) = arg.strip_prefix("--dedup-cdc=") {
+            dedup = Some(Chunking::ContentDefined(chunk_size(v)));
+            continue;
+        }
+        match arg.as_str() {
+            "--entries" => options.print_entries = true,
+            "--hex" => options.hex = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option {}\n{}", arg, USAGE);
+                process::exit(2);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    if let Some(chunking) = dedup {
+        match analyze_dedup(&FileStorage, &paths, chunking) {
+            Ok(stats) => {
+                println!("Files: {}", stats.num_files);
+                println!("Decoded data blocks: {} bytes", stats.total_bytes);
+                println!(
+                    "Chunks: {} ({:?}), {} unique ({} bytes), {} duplicate ({} bytes)",
+                    stats.num_chunks,
+                    chunking,
+                    stats.unique_chunks,
+                    stats.unique_bytes,
+                    stats.duplicate_chunks,
+                    stats.duplicate_bytes
+                );
+                println!(
+                    "Duplicate across files: {} bytes",
+                    stats.cross_file_duplicate_bytes
+                );
+                println!("Saving ratio: {:.2}%", stats.saving_ratio() * 100.0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut failed = false;
+    for path in paths.iter() {
+        let _ = writeln!(out, "==> {} <==", path);
+        let res = fs::File::open(path)
+            .map_err(wickdb::Error::IO)
+            .and_then(|file| {
+                let len = file.len()?;
+                dump_table(&file, len, options, &mut out)
+            });
+        match res {
+            Ok(stats) => failed |= stats.corrupted_blocks > 0,
+            Err(e) => {
+                let _ = out.flush();
+                eprintln!("{}: {}", path, e);
+                failed = true;
+            }
+        }
+    }
+    let _ = out.flush();
+    if failed {
+        process::exit(1);
+    }
+}
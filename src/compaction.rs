@@ -1,17 +1,21 @@
 use crate::db::format::{InternalKey, InternalKeyComparator};
+use crate::db::range_del::{truncate_range_tombstones, RangeTombstone};
 use crate::error::Result;
 use crate::iterator::{ConcatenateIterator, KMergeIter};
-use crate::options::{Options, ReadOptions};
-use crate::sstable::table::TableBuilder;
-use crate::storage::{File, Storage};
+use crate::options::{CompressionType, Options, ReadOptions};
+use crate::sstable::factory::TableWriter;
+use crate::storage::{IoPriority, Storage};
 use crate::table_cache::TableCache;
 use crate::util::comparator::Comparator;
 use crate::version::version_edit::{FileMetaData, VersionEdit};
-use crate::version::version_set::{total_file_size, FileIterFactory, SSTableIters};
+use crate::version::version_set::{
+    total_file_size, FileIterFactory, LazyTableIterator, SSTableIters,
+};
 use crate::version::{LevelFileNumIterator, Version};
 use crossbeam_channel::Sender;
 use std::cmp::Ordering as CmpOrdering;
 use std::sync::Arc;
+use std::time::Duration;
 
 /// Information for a manual compaction
 #[derive(Clone)]
@@ -28,7 +32,11 @@ pub struct ManualCompaction {
 pub struct CompactionInputs {
     // level n files
     pub base: Vec<Arc<FileMetaData>>,
-    // level n+1 files
+    // The files of the levels between the base level and the output level, each of
+    // which is compacted as a whole. Only a universal compaction merging several
+    // sorted runs has them.
+    pub intermediate: Vec<(usize, Vec<Arc<FileMetaData>>)>,
+    // output level files
     pub parent: Vec<Arc<FileMetaData>>,
 }
 
@@ -41,7 +49,10 @@ impl CompactionInputs {
     }
 
     fn iter_all(&self) -> impl Iterator<Item = &Arc<FileMetaData>> {
-        self.base.iter().chain(self.parent.iter())
+        self.base
+            .iter()
+            .chain(self.intermediate.iter().flat_map(|(_, files)| files.iter()))
+            .chain(self.parent.iter())
     }
 
     #[inline]
@@ -63,20 +74,34 @@ impl CompactionInputs {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum CompactionReason {
     MaxSize,
     SeekLimit,
     Manual,
+    // There are too many small files in a level, which are merged within the level
+    SmallFiles,
+    // The sorted runs newer than the oldest one are too large compared to it
+    UniversalSizeAmplification,
+    // The sizes of the newest sorted runs are similar
+    UniversalSizeRatio,
+    // There are too many sorted runs
+    UniversalSortedRunNum,
+    // The total size of the tables exceeds `fifo_max_table_files_size`
+    FifoMaxSize,
+    // The tables are older than `fifo_ttl`
+    FifoTtl,
 }
 
 // 封装了数据库压缩操作中的各种信息
-pub struct Compaction<F: File, C: Comparator> {
+pub struct Compaction<C: Comparator> {
     options: Arc<Options<C>>,
     // 触发压缩的原因
     pub reason: CompactionReason,
     // 目标压缩level层级
     pub level: usize,
+    // 输出文件所在的层级，除了 universal 压缩和合并小文件的压缩外总是 level + 1
+    pub output_level: usize,
     // 参与压缩的版本信息
     pub input_version: Option<Arc<Version<C>>>,
     // 压缩结果的总结
@@ -104,22 +129,31 @@ pub struct Compaction<F: File, C: Comparator> {
 
     // 压缩操作生成的所有输出文件的元数据。
     pub outputs: Vec<FileMetaData>,
+    // 已经登记到 MANIFEST 但还没有被输出文件使用的文件编号
+    pub reserved_outputs: Vec<u64>,
 
     // 当前用于输出sst文件的表构建器
     // 在达到某些条件时（如文件大小达到阈值），会创建新的构建器。`should_stop_before`
-    pub builder: Option<TableBuilder<InternalKeyComparator<C>, F>>,
+    pub builder: Option<Box<dyn TableWriter>>,
 
     // 已经写入的总字节数
     pub total_bytes: u64,
+
+    // 需要写入输出文件的范围墓碑
+    pub range_tombstones: Vec<RangeTombstone>,
+    // 当前输出文件中范围墓碑的下界，`None` 表示无下界。
+    // 每个输出文件只保存墓碑落在 [下界, 下一个输出文件的第一个 user key) 中的部分
+    pub output_lower_bound: Option<Vec<u8>>,
 }
 
-impl<O: File, C: Comparator + 'static> Compaction<O, C> {
+impl<C: Comparator + 'static> Compaction<C> {
     pub fn new(options: Arc<Options<C>>, level: usize, reason: CompactionReason) -> Self {
         let max_levels = options.max_levels;
         Self {
             reason,
             options,
             level,
+            output_level: level + 1,
             input_version: None,
             edit: VersionEdit::new(max_levels),
             inputs: CompactionInputs::default(),
@@ -129,17 +163,78 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
             overlapped_bytes: 0,
             oldest_snapshot_alive: 0,
             outputs: vec![],
+            reserved_outputs: vec![],
             builder: None,
             total_bytes: 0,
+            range_tombstones: vec![],
+            output_lower_bound: None,
+        }
+    }
+
+    /// Returns how many file numbers to reserve for the next outputs. The first
+    /// batch covers the outputs expected from the size of the inputs, and every
+    /// later one doubles the outputs so far.
+    pub fn output_numbers_to_reserve(&self) -> usize {
+        if self.outputs.is_empty() {
+            let input_bytes = self.inputs.iter_all().map(|f| f.file_size).sum::<u64>();
+            (input_bytes / self.options.max_file_size.max(1)) as usize + 1
+        } else {
+            self.outputs.len()
         }
     }
 
-    /// 主要通过检查当前层级的输入文件以及与父层级和祖父层级文件的重叠情况，决定是否可以直接将文件移动到下一个层级，而无需进行合并或拆分
-    // TODO: improve this to satisfy more complicate moving
+    /// Reports whether the inputs are dropped without being rewritten, which is
+    /// how a FIFO compaction works
+    pub fn is_deletion_only(&self) -> bool {
+        matches!(
+            self.reason,
+            CompactionReason::FifoMaxSize | CompactionReason::FifoTtl
+        )
+    }
+
+    /// 主要通过检查当前层级的输入文件以及与父层级和祖父层级文件的重叠情况，决定是否可以直接将文件移动到输出层级，而无需进行合并或拆分
+    /// The inputs can be moved if none of them overlaps the output level or each other.
     pub fn is_trivial_move(&self) -> bool {
-        self.inputs.base.len() == 1
-            && self.inputs.parent.is_empty()
-            && total_file_size(&self.grand_parents) <= self.options.max_grandparent_overlap_bytes()
+        if self.is_deletion_only()
+            || self.output_level == self.level
+            || self.inputs.base.is_empty()
+            || !self.inputs.parent.is_empty()
+            || !self.inputs.intermediate.is_empty()
+            || total_file_size(&self.grand_parents) > self.options.max_grandparent_overlap_bytes()
+        {
+            return false;
+        }
+        // The files in the levels above 0 never overlap each other
+        self.level > 0 || self.inputs.base.len() == 1 || self.level0_inputs_disjoint()
+    }
+
+    // Reports whether the user key ranges of the level 0 inputs are disjoint
+    fn level0_inputs_disjoint(&self) -> bool {
+        let ucmp = &self
+            .input_version
+            .as_ref()
+            .unwrap()
+            .comparator()
+            .user_comparator;
+        let mut files: Vec<&Arc<FileMetaData>> = self.inputs.base.iter().collect();
+        files.sort_by(|a, b| ucmp.compare(a.smallest.user_key(), b.smallest.user_key()));
+        files.windows(2).all(|w| {
+            ucmp.compare(w[0].largest.user_key(), w[1].smallest.user_key()) == CmpOrdering::Less
+        })
+    }
+
+    /// Moves the base inputs to the output level by the edit instead of rewriting them
+    pub fn apply_trivial_move(&mut self) {
+        for f in self.inputs.base.iter() {
+            self.edit.delete_file(self.level, f.number);
+            self.edit.add_file(
+                self.output_level,
+                f.number,
+                f.file_size,
+                f.smallest.clone(),
+                f.largest.clone(),
+            );
+        }
     }
 
     /// Create an iterator that reads over all the compaction input tables with merged order.
@@ -160,6 +255,11 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
             verify_checksums: self.options.paranoid_checks,
             fill_cache: false,
             snapshot: None,
+            min_sequence_visible: None,
+            timestamp: None,
+            deadline: None,
+            io_priority: IoPriority::Background,
+            drop_page_cache: self.options.compaction_drop_page_cache,
         };
         // Level-0 files have to be merged together so we generate a merging iterator includes iterators for each level 0 file.
         // For other levels, we will make a concatenating iterator per level.
@@ -171,12 +271,8 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
                     "new level {} table iter: number {}, file size {}, [{:?} ... {:?}]",
                     self.level, file.number, file.file_size, file.smallest, file.largest
                 );
-                level0.push(table_cache.new_iter(
-                    icmp.clone(),
-                    read_options,
-                    file.number,
-                    file.file_size,
-                )?);
+                let factory = FileIterFactory::new(icmp.clone(), read_options, table_cache.clone());
+                level0.push(LazyTableIterator::new(file.clone(), factory));
             }
         } else {
             for f in &self.inputs.base {
@@ -189,15 +285,16 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
             let factory = FileIterFactory::new(icmp.clone(), read_options, table_cache.clone());
             leveln.push(ConcatenateIterator::new(origin, factory));
         }
+        for (_, files) in self.inputs.intermediate.iter() {
+            let origin = LevelFileNumIterator::new(icmp.clone(), files.clone());
+            let factory = FileIterFactory::new(icmp.clone(), read_options, table_cache.clone());
+            leveln.push(ConcatenateIterator::new(origin, factory));
+        }
         if !self.inputs.parent.is_empty() {
             for f in &self.inputs.parent {
                 debug!(
                     "new level {} table iter: number {}, file size {}, [{:?} ... {:?}]",
-                    self.level + 1,
-                    f.number,
-                    f.file_size,
-                    f.smallest,
-                    f.largest
+                    self.output_level, f.number, f.file_size, f.smallest, f.largest
                 );
             }
             let origin = LevelFileNumIterator::new(icmp.clone(), self.inputs.parent.clone());
@@ -235,7 +332,7 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
     }
 
     /// Reports whether it is guaranteed that there are no
-    /// key/value pairs at c.level+2 or higher that have the user key ukey.
+    /// key/value pairs below the output level that have the user key ukey.
     pub fn key_exist_in_deeper_level(&mut self, ukey: &[u8]) -> bool {
         let v = self.input_version.as_ref().unwrap();
         let ucmp = &self
@@ -245,8 +342,8 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
             .comparator()
             .user_comparator;
         let max_levels = self.options.max_levels;
-        if self.level + 2 < max_levels {
-            for level in self.level + 2..max_levels {
+        if self.output_level + 1 < max_levels {
+            for level in self.output_level + 1..max_levels {
                 for f in v.get_level_files(level) {
                     if ucmp.compare(ukey, f.largest.user_key()) != CmpOrdering::Greater {
                         if ucmp.compare(ukey, f.smallest.user_key()) != CmpOrdering::Less {
@@ -262,20 +359,199 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
         false
     }
 
+    /// Reports whether there might be key/value pairs in `[begin, end)` below
+    /// the output level
+    pub fn range_exist_in_deeper_level(&self, begin: &[u8], end: &[u8]) -> bool {
+        let v = self.input_version.as_ref().unwrap();
+        (self.output_level + 1..self.options.max_levels)
+            .any(|level| v.overlap_in_level(level, Some(begin), Some(end)))
+    }
+
+    /// Returns the compression of the output files. The outputs written into the
+    /// bottommost level, i.e. there are no files below the output level, use
+    /// `bottommost_compression` if it's configured.
+    pub fn output_compression(&self) -> CompressionType {
+        let output_level = self.output_level;
+        if let Some(compression) = self.options.bottommost_compression {
+            let v = self.input_version.as_ref().unwrap();
+            if (output_level + 1..self.options.max_levels).all(|l| v.get_level_files(l).is_empty())
+            {
+                return compression;
+            }
+        }
+        self.options.compression_for_level(output_level)
+    }
+
+    /// Returns the range tombstones in all the input files
+    pub fn input_range_tombstones<S: Storage + Clone + 'static>(
+        &self,
+        table_cache: &TableCache<S, C>,
+    ) -> Result<Vec<RangeTombstone>> {
+        let mut tombstones = vec![];
+        for f in self.inputs.iter_all() {
+            let table = table_cache.find_table(f.number, f.file_size)?;
+            tombstones.extend_from_slice(table.range_tombstones());
+        }
+        Ok(tombstones)
+    }
+
+    /// Returns the parts of the range tombstones belonging to the current output file
+    /// if the next output file starts at `upper`
+    pub fn output_range_tombstones(&self, ucmp: &C, upper: Option<&[u8]>) -> Vec<RangeTombstone> {
+        truncate_range_tombstones(
+            ucmp,
+            &self.range_tombstones,
+            self.output_lower_bound.as_deref(),
+            upper,
+        )
+    }
+
+    /// Returns true if the budget of the compaction job is used up after running
+    /// for `elapsed`
+    pub fn budget_exhausted(&self, elapsed: Duration) -> bool {
+        let written = self.total_bytes + self.builder.as_ref().map_or(0, |b| b.file_size());
+        self.options
+            .compaction_job_max_time
+            .is_some_and(|t| elapsed >= t)
+            || self
+                .options
+                .compaction_job_max_bytes
+                .is_some_and(|b| written >= b)
+    }
+
+    /// Returns true if the compaction can stop before `ikey`, which requires
+    /// every input file to be either entirely before `ikey` or not consumed at all.
+    pub fn can_checkpoint_before(&self, ikey: &[u8], icmp: &InternalKeyComparator<C>) -> bool {
+        self.inputs.iter_all().all(|f| {
+            icmp.compare(f.largest.data(), ikey) == CmpOrdering::Less
+                || icmp.compare(f.smallest.data(), ikey) != CmpOrdering::Less
+        })
+    }
+
+    /// Stops the compaction before `ikey`. Only the input files consumed are kept
+    /// in the inputs to be deleted, and the compaction pointer is moved back so the
+    /// next compaction of the level continues from `ikey`.
+    pub fn checkpoint(&mut self, ikey: &[u8], icmp: &InternalKeyComparator<C>) {
+        let consumed =
+            |f: &Arc<FileMetaData>| icmp.compare(f.largest.data(), ikey) == CmpOrdering::Less;
+        self.inputs.base.retain(consumed);
+        for (_, files) in self.inputs.intermediate.iter_mut() {
+            files.retain(consumed);
+        }
+        self.inputs.parent.retain(consumed);
+        let pointer = self
+            .inputs
+            .iter_all()
+            .map(|f| &f.largest)
+            .max_by(|a, b| icmp.compare(a.data(), b.data()));
+        let level = self.level;
+        self.edit
+            .file_delta
+            .compaction_pointers
+            .retain(|(l, _)| *l != level);
+        if let Some(pointer) = pointer.cloned() {
+            self.edit
+                .file_delta
+                .compaction_pointers
+                .push((level, pointer));
+        }
+    }
+
     /// Apply deletion for current inputs and current output files to the edit
     pub fn apply_to_edit(&mut self) {
         for f in self.inputs.base.iter() {
             self.edit.delete_file(self.level, f.number)
         }
+        for (level, files) in self.inputs.intermediate.iter() {
+            for f in files.iter() {
+                self.edit.delete_file(*level, f.number)
+            }
+        }
         for f in self.inputs.parent.iter() {
-            self.edit.delete_file(self.level + 1, f.number)
+            self.edit.delete_file(self.output_level, f.number)
         }
         for output in self.outputs.drain(..) {
             self.edit
                 .file_delta
                 .new_files
-                .push((self.level + 1, output))
+                .push((self.output_level, output))
+        }
+    }
+
+    /// Returns the user keys splitting the compaction into at most `max` subcompactions.
+    /// The keys are picked evenly from the smallest keys of the input files, so the
+    /// compaction of a single file is never split.
+    pub fn subcompaction_boundaries(&self, max: usize) -> Vec<Vec<u8>> {
+        let ucmp = &self
+            .input_version
+            .as_ref()
+            .unwrap()
+            .comparator()
+            .user_comparator;
+        let mut keys: Vec<&[u8]> = self
+            .inputs
+            .iter_all()
+            .map(|f| f.smallest.user_key())
+            .collect();
+        keys.sort_by(|a, b| ucmp.compare(a, b));
+        keys.dedup_by(|a, b| ucmp.compare(a, b) == CmpOrdering::Equal);
+        if max <= 1 || keys.len() <= 1 {
+            return vec![];
         }
+        // The smallest key of all the inputs splits nothing
+        let candidates = &keys[1..];
+        let n = max.min(candidates.len() + 1);
+        (1..n)
+            .map(|i| candidates[i * candidates.len() / n].to_vec())
+            .collect()
+    }
+
+    /// Returns a compaction of the user keys in `[begin, end)` of this compaction,
+    /// which produces its own outputs. `None` means unbounded.
+    pub fn new_subcompaction(&self, begin: Option<&[u8]>, end: Option<&[u8]>) -> Self {
+        let ucmp = &self
+            .input_version
+            .as_ref()
+            .unwrap()
+            .comparator()
+            .user_comparator;
+        let overlapped = |f: &&Arc<FileMetaData>| {
+            begin.is_none_or(|k| ucmp.compare(f.largest.user_key(), k) != CmpOrdering::Less)
+                && end.is_none_or(|k| ucmp.compare(f.smallest.user_key(), k) == CmpOrdering::Less)
+        };
+        let mut c = Self::new(self.options.clone(), self.level, self.reason);
+        c.output_level = self.output_level;
+        c.input_version = self.input_version.clone();
+        c.inputs.base = self
+            .inputs
+            .base
+            .iter()
+            .filter(overlapped)
+            .cloned()
+            .collect();
+        c.inputs.intermediate = self
+            .inputs
+            .intermediate
+            .iter()
+            .map(|(level, files)| (*level, files.iter().filter(overlapped).cloned().collect()))
+            .collect();
+        c.inputs.parent = self
+            .inputs
+            .parent
+            .iter()
+            .filter(overlapped)
+            .cloned()
+            .collect();
+        c.grand_parents = self
+            .grand_parents
+            .iter()
+            .filter(overlapped)
+            .cloned()
+            .collect();
+        c.oldest_snapshot_alive = self.oldest_snapshot_alive;
+        c.range_tombstones = self.range_tombstones.clone();
+        c.output_lower_bound = begin.map(|k| k.to_vec());
+        c
     }
 
     /// Calculate the read bytes
@@ -289,7 +565,9 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
     /// Calculate the written bytes
     #[inline]
     pub fn bytes_written(&self) -> u64 {
-        self.outputs.iter().fold(0, |sum, file| sum + file.number)
+        self.outputs
+            .iter()
+            .fold(0, |sum, file| sum + file.file_size)
     }
 }
 
@@ -0,0 +1,456 @@
+use crate::db::scheduler::Priority;
+use crate::db::{DBImpl, WickDB};
+use crate::options::CompactRangeOptions;
+use crate::storage::Storage;
+use crate::util::comparator::Comparator;
+use crate::version::version_set::total_file_size;
+use crate::{Error, Result};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How long a connection may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `AdminServer` is the tiny HTTP server started by `WickDB::serve_admin`. Each
+/// endpoint responds with a JSON document:
+///
+/// * `GET /properties`: the last sequence, the memtables, the write stall
+///   condition, the number of the degraded tables and the files and bytes of
+///   each level
+/// * `GET /stats`: `WickDB::db_stats`, `WickDB::read_stats` and `WickDB::scrub_stats`
+/// * `GET /live_files`: the level, number, size and key range of every live table.
+///   The keys are hex encoded.
+/// * `GET /jobs`: the scheduled flush and compaction and the queued manual compactions
+/// * `POST /compact?begin=<key>&end=<key>`: runs `WickDB::compact_range` and responds
+///   once it finishes. The keys are percent encoded and both are optional.
+///
+/// The server has no authentication, so it should only listen on a trusted
+/// interface. It's stopped when dropped and never keeps the db open.
+pub struct AdminServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    /// Returns the address the server listens on, which tells the port picked
+    /// by the system if the server was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        // Wake up the blocking `accept`
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> WickDB<S, C> {
+    /// Starts an `AdminServer` listening on `addr` for the operators to inspect
+    /// the db without attaching a debugger to the process.
+    pub fn serve_admin<A: ToSocketAddrs>(&self, addr: A) -> Result<AdminServer> {
+        let listener = map_io_res!(TcpListener::bind(addr))?;
+        let addr = map_io_res!(listener.local_addr())?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let db = Arc::downgrade(&self.inner);
+        let stop = stopped.clone();
+        let handle = thread::Builder::new()
+            .name("admin http".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Acquire) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Admin server failed to accept: {}", e);
+                            continue;
+                        }
+                    };
+                    // A manual compaction must not block the other requests
+                    let db = db.clone();
+                    let _ = thread::Builder::new()
+                        .name("admin http conn".to_owned())
+                        .spawn(move || {
+                            if let Err(e) = handle_connection(&db, stream) {
+                                warn!("Admin server failed to serve a request: {}", e);
+                            }
+                        });
+                }
+                info!("admin http thread shut down");
+            })
+            .unwrap();
+        info!("Admin server listening on {}", addr);
+        Ok(AdminServer {
+            addr,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+}
+
+fn handle_connection<S: Storage + Clone + 'static, C: Comparator + 'static>(
+    db: &Weak<DBImpl<S, C>>,
+    mut stream: TcpStream,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not used
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return respond(&mut stream, 400, &error_json("malformed request")),
+    };
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+    let db = match db.upgrade() {
+        Some(db) if !db.is_shutting_down.load(Ordering::Acquire) => db,
+        _ => return respond(&mut stream, 503, &error_json("db is closed")),
+    };
+    let expected = if path == "/compact" { "POST" } else { "GET" };
+    let body = match path {
+        "/properties" | "/stats" | "/live_files" | "/jobs" | "/compact" if method != expected => {
+            return respond(&mut stream, 405, &error_json("method not allowed"));
+        }
+        "/properties" => properties_json(&db),
+        "/stats" => stats_json(&db),
+        "/live_files" => live_files_json(&db),
+        "/jobs" => jobs_json(&db),
+        "/compact" => match compact(&db, query) {
+            Ok(()) => "{\"ok\":true}".to_owned(),
+            Err(e) => return respond(&mut stream, 500, &error_json(&e.to_string())),
+        },
+        _ => return respond(&mut stream, 404, &error_json("not found")),
+    };
+    respond(&mut stream, 200, &body)
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &str) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn error_json(msg: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(msg))
+}
+
+fn properties_json<S: Storage + Clone + 'static, C: Comparator + 'static>(
+    db: &DBImpl<S, C>,
+) -> String {
+    let (last_sequence, current) = {
+        let versions = db.versions.lock().unwrap();
+        (versions.last_sequence(), versions.current())
+    };
+    let im_mems = db.im_mems.read().unwrap();
+    let mut out = format!(
+        "{{\"path\":{},\"last_sequence\":{},\"memtable_bytes\":{},\"immutable_memtables\":{},\"immutable_memtable_bytes\":{},\"write_stall\":{},\"background_error\":",
+        json_string(&db.db_path),
+        last_sequence,
+        db.mem.read().unwrap().approximate_memory_usage(),
+        im_mems.len(),
+        im_mems
+            .iter()
+            .map(|im| im.mem.approximate_memory_usage())
+            .sum::<usize>(),
+        json_string(&format!("{:?}", *db.write_stall.lock().unwrap())),
+    );
+    match &*db.bg_error.read().unwrap() {
+        Some(e) => out.push_str(&json_string(&e.to_string())),
+        None => out.push_str("null"),
+    }
+    let _ = write!(
+        out,
+        ",\"degraded_tables\":{}",
+        db.table_cache.num_degraded_tables()
+    );
+    out.push_str(",\"levels\":[");
+    for level in 0..db.options.max_levels {
+        let files = current.get_level_files(level);
+        if level > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"level\":{},\"files\":{},\"bytes\":{}}}",
+            level,
+            files.len(),
+            total_file_size(files)
+        );
+    }
+    out.push_str("]}");
+    out
+}
+
+fn stats_json<S: Storage + Clone + 'static, C: Comparator + 'static>(db: &DBImpl<S, C>) -> String {
+    let s = db.stats_counters.stats();
+    let r = db.read_counters.stats();
+    let v = db.scrub_counters.stats();
+    format!(
+        "{{\"db\":{{\"flushes\":{},\"flush_bytes_written\":{},\"compactions\":{},\"compaction_bytes_read\":{},\"compaction_bytes_written\":{},\"compaction_micros\":{},\"write_delays\":{},\"write_stops\":{},\"write_stall_micros\":{},\"background_panics\":{},\"trivial_moves\":{}}},\
+         \"read\":{{\"memtable_hits\":{},\"level0_hits\":{},\"leveln_hits\":{},\"misses\":{},\"block_cache_hits\":{},\"block_cache_misses\":{}}},\
+         \"scrub\":{{\"blocks_verified\":{},\"bytes_verified\":{},\"corruptions\":{}}}}}",
+        s.flushes,
+        s.flush_bytes_written,
+        s.compactions,
+        s.compaction_bytes_read,
+        s.compaction_bytes_written,
+        s.compaction_micros,
+        s.write_delays,
+        s.write_stops,
+        s.write_stall_micros,
+        s.background_panics,
+        s.trivial_moves,
+        r.memtable_hits,
+        r.level0_hits,
+        r.leveln_hits,
+        r.misses,
+        r.block_cache_hits,
+        r.block_cache_misses,
+        v.blocks_verified,
+        v.bytes_verified,
+        v.corruptions,
+    )
+}
+
+fn live_files_json<S: Storage + Clone + 'static, C: Comparator + 'static>(
+    db: &DBImpl<S, C>,
+) -> String {
+    let current = db.versions.lock().unwrap().current();
+    let mut out = String::from("[");
+    for level in 0..db.options.max_levels {
+        for f in current.get_level_files(level) {
+            if out.len() > 1 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"level\":{},\"number\":{},\"size\":{},\"smallest\":\"{}\",\"largest\":\"{}\"}}",
+                level,
+                f.number,
+                f.file_size,
+                hex(f.smallest.user_key()),
+                hex(f.largest.user_key())
+            );
+        }
+    }
+    out.push(']');
+    out
+}
+
+fn jobs_json<S: Storage + Clone + 'static, C: Comparator + 'static>(db: &DBImpl<S, C>) -> String {
+    format!(
+        "{{\"flush_scheduled\":{},\"compaction_scheduled\":{},\"manual_compactions_queued\":{},\"flush_threads\":{},\"compaction_threads\":{}}}",
+        db.flush_scheduled.load(Ordering::Acquire),
+        db.background_compaction_scheduled.load(Ordering::Acquire),
+        db.manual_compaction_queue.lock().unwrap().len(),
+        db.scheduler.threads(Priority::High),
+        db.scheduler.threads(Priority::Low),
+    )
+}
+
+fn compact<S: Storage + Clone + 'static, C: Comparator + 'static>(
+    db: &DBImpl<S, C>,
+    query: &str,
+) -> Result<()> {
+    let mut begin = None;
+    let mut end = None;
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(i) => (&param[..i], &param[i + 1..]),
+            None => (param, ""),
+        };
+        match name {
+            "begin" => begin = Some(percent_decode(value)?),
+            "end" => end = Some(percent_decode(value)?),
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "unknown parameter {}",
+                    name
+                )))
+            }
+        }
+    }
+    db.compact_range(
+        &CompactRangeOptions::default(),
+        begin.as_deref(),
+        end.as_deref(),
+    )
+}
+
+fn percent_decode(s: &str) -> Result<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let n = s
+                    .get(i + 1..i + 3)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!("invalid percent encoding {}", s))
+                    })?;
+                decoded.push(n);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DB;
+    use crate::options::{Options, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use std::io::Read;
+
+    fn request(addr: SocketAddr, method: &str, target: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            method, target
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap().to_owned();
+        (status, body)
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Fb+c").unwrap(), b"a/b c");
+        assert_eq!(percent_decode("%00%ff").unwrap(), vec![0, 255]);
+        assert!(percent_decode("a%2").is_err());
+        assert!(percent_decode("%zz").is_err());
+    }
+
+    #[test]
+    fn test_admin_server() {
+        let mut db = WickDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "admin",
+            MemStorage::default(),
+        )
+        .unwrap();
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), b"value")
+                .unwrap();
+        }
+        db.inner.force_compact_mem_table().unwrap();
+        let server = db.serve_admin("127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+
+        let (status, body) = request(addr, "GET", "/properties");
+        assert_eq!(status, 200);
+        assert!(body.contains("\"last_sequence\":100"), "{}", body);
+        assert!(body.contains("\"write_stall\":\"Normal\""), "{}", body);
+        let (status, body) = request(addr, "GET", "/stats");
+        assert_eq!(status, 200);
+        assert!(body.contains("\"flushes\":1"), "{}", body);
+        let (status, body) = request(addr, "GET", "/live_files");
+        assert_eq!(status, 200);
+        assert!(body.contains(&format!("\"smallest\":\"{}\"", hex(b"key000"))));
+        assert!(body.contains(&format!("\"largest\":\"{}\"", hex(b"key099"))));
+        let (status, body) = request(addr, "GET", "/jobs");
+        assert_eq!(status, 200);
+        assert!(body.contains("\"manual_compactions_queued\":0"), "{}", body);
+
+        let (status, body) = request(addr, "POST", "/compact?begin=key%30&end=key1");
+        assert_eq!((status, body.as_str()), (200, "{\"ok\":true}"));
+        let (_, body) = request(addr, "GET", "/properties");
+        assert!(
+            body.contains("{\"level\":0,\"files\":0,\"bytes\":0}"),
+            "{}",
+            body
+        );
+
+        assert_eq!(request(addr, "GET", "/compact").0, 405);
+        assert_eq!(request(addr, "POST", "/compact?foo=bar").0, 500);
+        assert_eq!(request(addr, "GET", "/unknown").0, 404);
+        db.close().unwrap();
+        assert_eq!(request(addr, "GET", "/stats").0, 503);
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use crate::batch::{WriteBatch, HEADER_SIZE};
+use crate::db::DB;
+use crate::options::WriteOptions;
+use crate::{Error, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Options to control when a `BatchedWriter` commits the buffered writes
+#[derive(Clone)]
+pub struct BatchedWriterOptions {
+    /// The buffered writes are committed once their approximate size reaches
+    /// this many bytes.
+    /// Default: 1MB
+    pub max_batch_bytes: usize,
+
+    /// The buffered writes are committed once the oldest of them has waited
+    /// this long.
+    /// Default: 1ms
+    pub max_delay: Duration,
+
+    /// The options of the committed `WriteBatch`es
+    pub write_options: WriteOptions,
+}
+
+impl Default for BatchedWriterOptions {
+    fn default() -> Self {
+        Self {
+            max_batch_bytes: 1 << 20,
+            max_delay: Duration::from_millis(1),
+            write_options: WriteOptions::default(),
+        }
+    }
+}
+
+struct Request {
+    batch: WriteBatch,
+    done: Sender<Result<()>>,
+}
+
+/// A write submitted to a `BatchedWriter` which is not committed yet
+pub struct PendingWrite {
+    done: Receiver<Result<()>>,
+}
+
+impl PendingWrite {
+    /// Blocks until the write is committed into the db
+    pub fn wait(self) -> Result<()> {
+        self.done
+            .recv()
+            .unwrap_or_else(|_| Err(Error::DBClosed("batched writer".to_owned())))
+    }
+}
+
+/// `BatchedWriter` buffers the writes from many callers and commits them into
+/// the db as one `WriteBatch` when the size or the time threshold in
+/// `BatchedWriterOptions` is reached. This amortizes the cost of each commit
+/// (e.g. the log sync) over the callers like the group commit does, but above
+/// the `DB` API, so a caller could keep submitting writes without waiting for
+/// the previous ones.
+///
+/// The buffered writes are committed in the order they are submitted. If a
+/// commit fails, its writes are committed again one by one, so a write only
+/// fails with its own error. Dropping the writer commits the buffered writes
+/// and waits for them.
+pub struct BatchedWriter {
+    requests: Option<Sender<Request>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BatchedWriter {
+    pub fn new<D: DB + Send + Sync + 'static>(db: Arc<D>, options: BatchedWriterOptions) -> Self {
+        let (requests, recv) = crossbeam_channel::unbounded();
+        let handle = thread::Builder::new()
+            .name("batched writer".to_owned())
+            .spawn(move || Self::run(db.as_ref(), options, recv))
+            .unwrap();
+        Self {
+            requests: Some(requests),
+            handle: Some(handle),
+        }
+    }
+
+    /// Submits a put and blocks until it is committed
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    /// Submits a delete and blocks until it is committed
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// Submits the operations in `batch` and blocks until they are committed.
+    /// The operations are always committed together.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.submit(batch).wait()
+    }
+
+    /// Submits the operations in `batch` without waiting for the commit
+    pub fn submit(&self, batch: WriteBatch) -> PendingWrite {
+        let (done, recv) = crossbeam_channel::bounded(1);
+        // The sender is only taken when dropped
+        let requests = self.requests.as_ref().unwrap();
+        if let Err(e) = requests.send(Request { batch, done }) {
+            // The committing thread has exited
+            let _ =
+                e.0.done
+                    .send(Err(Error::DBClosed("batched writer".to_owned())));
+        }
+        PendingWrite { done: recv }
+    }
+
+    fn run<D: DB>(db: &D, options: BatchedWriterOptions, requests: Receiver<Request>) {
+        // Exits when the writer is dropped and all the requests are committed
+        while let Ok(first) = requests.recv() {
+            let deadline = Instant::now() + options.max_delay;
+            // The size of the batch merging all the requests in the group
+            let mut size = first.batch.approximate_size();
+            let mut group = vec![first];
+            while size < options.max_batch_bytes {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match requests.recv_timeout(timeout) {
+                    Ok(req) => {
+                        size += req.batch.approximate_size() - HEADER_SIZE;
+                        group.push(req);
+                    }
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            Self::commit(db, &options.write_options, group);
+        }
+    }
+
+    // Commits the requests as one `WriteBatch`. If it fails, nothing is written
+    // and the requests are committed one by one, so an invalid write (e.g. to a
+    // fenced range) only fails its own caller and each caller gets the original
+    // error of its write.
+    fn commit<D: DB>(db: &D, write_options: &WriteOptions, group: Vec<Request>) {
+        if group.len() > 1 {
+            let mut batch = WriteBatch::default();
+            for req in group.iter() {
+                batch.append(req.batch.clone());
+            }
+            let count = batch.get_count();
+            match db.write(write_options.clone(), batch) {
+                Ok(()) => {
+                    for req in group {
+                        let _ = req.done.send(Ok(()));
+                    }
+                    return;
+                }
+                Err(e) => warn!(
+                    "[batched writer] commit {} operations failed: {}, retry the {} writes separately",
+                    count,
+                    e,
+                    group.len()
+                ),
+            }
+        }
+        for req in group {
+            let _ = req.done.send(db.write(write_options.clone(), req.batch));
+        }
+    }
+}
+
+impl Drop for BatchedWriter {
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::WickDB;
+    use crate::options::{Options, ReadOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    fn new_db() -> Arc<WickDB<MemStorage, BytewiseComparator>> {
+        let db = WickDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "batched_writer",
+            MemStorage::default(),
+        )
+        .unwrap();
+        Arc::new(db)
+    }
+
+    fn get(db: &WickDB<MemStorage, BytewiseComparator>, key: &str) -> Option<Vec<u8>> {
+        db.get(ReadOptions::default(), key.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_commit_by_size() {
+        let db = new_db();
+        let mut batches: Vec<WriteBatch> = (0..10)
+            .map(|i| {
+                let mut batch = WriteBatch::default();
+                batch.put(format!("k{}", i).as_bytes(), b"v");
+                batch
+            })
+            .collect();
+        batches[9].delete(b"k0");
+        let mut all = WriteBatch::default();
+        for b in batches.iter() {
+            all.append(b.clone());
+        }
+        let writer = BatchedWriter::new(
+            db.clone(),
+            BatchedWriterOptions {
+                // Reached by all the batches
+                max_batch_bytes: all.approximate_size(),
+                max_delay: Duration::from_secs(3600),
+                ..BatchedWriterOptions::default()
+            },
+        );
+        let last = batches.pop().unwrap();
+        let mut pending: Vec<PendingWrite> =
+            batches.into_iter().map(|b| writer.submit(b)).collect();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(get(&db, "k0"), None);
+        pending.push(writer.submit(last));
+        for p in pending {
+            p.wait().unwrap();
+        }
+        assert_eq!(get(&db, "k0"), None);
+        for i in 1..10 {
+            assert_eq!(get(&db, &format!("k{}", i)), Some(b"v".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_commit_by_time() {
+        let db = new_db();
+        let writer = Arc::new(BatchedWriter::new(
+            db.clone(),
+            BatchedWriterOptions {
+                max_delay: Duration::from_millis(10),
+                ..BatchedWriterOptions::default()
+            },
+        ));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    for i in 0..20 {
+                        let key = format!("t{}k{}", t, i);
+                        writer.put(key.as_bytes(), key.as_bytes()).unwrap();
+                    }
+                    writer.delete(format!("t{}k0", t).as_bytes()).unwrap();
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        for t in 0..4 {
+            assert_eq!(get(&db, &format!("t{}k0", t)), None);
+            for i in 1..20 {
+                let key = format!("t{}k{}", t, i);
+                assert_eq!(get(&db, &key), Some(key.into_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_commit_on_drop() {
+        let db = new_db();
+        let writer = BatchedWriter::new(
+            db.clone(),
+            BatchedWriterOptions {
+                max_delay: Duration::from_secs(3600),
+                ..BatchedWriterOptions::default()
+            },
+        );
+        let mut batch = WriteBatch::default();
+        batch.put(b"k", b"v");
+        let pending = writer.submit(batch);
+        drop(writer);
+        pending.wait().unwrap();
+        assert_eq!(get(&db, "k"), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_failed_write_only_fails_its_caller() {
+        let db = new_db();
+        let fence = db.fence_range(b"f", b"g").unwrap();
+        let writer = BatchedWriter::new(
+            db.clone(),
+            BatchedWriterOptions {
+                max_delay: Duration::from_secs(3600),
+                ..BatchedWriterOptions::default()
+            },
+        );
+        let put = |key: &str| {
+            let mut batch = WriteBatch::default();
+            batch.put(key.as_bytes(), b"v");
+            writer.submit(batch)
+        };
+        let pending = vec![put("a"), put("f1"), put("z")];
+        drop(writer);
+        let results: Vec<_> = pending.into_iter().map(|p| p.wait()).collect();
+        assert!(results[0].is_ok() && results[2].is_ok());
+        assert!(matches!(results[1], Err(Error::RangeFenced(_))));
+        assert_eq!(get(&db, "a"), Some(b"v".to_vec()));
+        assert_eq!(get(&db, "f1"), None);
+        assert_eq!(get(&db, "z"), Some(b"v".to_vec()));
+        assert!(db.unfence_range(&fence));
+    }
+}
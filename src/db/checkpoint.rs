@@ -0,0 +1,257 @@
+use crate::db::filename::{generate_filename, parse_filename, update_current, FileType};
+use crate::db::WickDB;
+use crate::options::WriteOptions;
+use crate::storage::{File, Storage};
+use crate::util::comparator::Comparator;
+use crate::{Error, Result, WriteBatch};
+use std::path::Path;
+use std::time::Duration;
+
+// The size of the chunks the MANIFEST and the WAL files are copied by
+const COPY_CHUNK_SIZE: u64 = 1 << 20;
+
+/// `Checkpoint` creates an openable snapshot of a running db in another
+/// directory, which is used for fast and consistent backups.
+///
+/// The memtable is flushed first, so the checkpoint mostly consists of the
+/// sst files, which are hard linked into the checkpoint directory if the
+/// storage supports it (see `Storage::link`) or copied otherwise. The MANIFEST
+/// and the live WAL files are copied up to the point the checkpoint is taken,
+/// and a CURRENT pointing to the MANIFEST is written at last. The writes after
+/// that point are not included.
+///
+/// # Example
+///
+/// ```ignore
+/// Checkpoint::create(&db, "backup/db-20200101")?;
+/// let backup = WickDB::open_db(options, "backup/db-20200101", storage)?;
+/// ```
+pub struct Checkpoint;
+
+impl Checkpoint {
+    /// Creates a checkpoint of `db` in the directory `path`, which must not
+    /// exist and must be in the same storage as the db. The directory can be
+    /// opened as an independent db. If the creation fails, the partially
+    /// written directory is removed.
+    pub fn create<S: Storage + Clone + 'static, C: Comparator + 'static, P: AsRef<Path>>(
+        db: &WickDB<S, C>,
+        path: P,
+    ) -> Result<()> {
+        let dir = match path.as_ref().to_str() {
+            Some(dir) => dir.to_owned(),
+            None => {
+                return Err(Error::InvalidArgument(
+                    "Invalid checkpoint path. Expect to use Unicode path.".to_owned(),
+                ))
+            }
+        };
+        let db = &db.inner;
+        if db.env.exists(&dir) {
+            return Err(Error::InvalidArgument(format!(
+                "checkpoint directory {} already exists",
+                &dir
+            )));
+        }
+        if !db.read_only && !db.mem.read().unwrap().is_empty() {
+            db.schedule_batch_and_wait(WriteOptions::default(), WriteBatch::default(), true)?;
+        }
+
+        let mut versions = db.versions.lock().unwrap();
+        // Waits for the flushes so the WAL files to copy are as small as possible.
+        // The data of the pending flushes is still in the copied WAL files if the
+        // flushes fail. The memtables replayed by a read-only db are never flushed.
+        while !db.read_only && !db.im_mems.read().unwrap().is_empty() {
+            if let Some(e) = db.take_bg_error() {
+                return Err(e);
+            }
+            versions = db
+                .background_work_finished_signal
+                .wait_timeout(versions, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        // Both the MANIFEST and the WAL are appended with the lock held, so their
+        // current sizes are at the boundaries of the records. The opened files are
+        // still readable if they're deleted or renamed after the lock is released,
+        // and the tables of `current` are kept until it's dropped.
+        let current = versions.current();
+        let manifest_number = versions.manifest_number();
+        let manifest = db.env.open(generate_filename(
+            &db.db_path,
+            FileType::Manifest,
+            manifest_number,
+        ))?;
+        let manifest_size = manifest.len()?;
+        let mut logs = vec![];
+        for file in db.env.list(&db.db_path)? {
+            if let Some((FileType::Log, number)) = parse_filename(&file) {
+                if number >= versions.log_number() || number == versions.prev_log_number() {
+                    let log = db.env.open(&file)?;
+                    let size = log.len()?;
+                    logs.push((number, log, size));
+                }
+            }
+        }
+        drop(versions);
+
+        db.env.mkdir_all(&dir)?;
+        let result = (|| {
+            for level in 0..db.options.max_levels {
+                for f in current.get_level_files(level) {
+                    let src = generate_filename(&db.db_path, FileType::Table, f.number);
+                    let dst = generate_filename(&dir, FileType::Table, f.number);
+                    if let Err(e) = db.env.link(&src, &dst) {
+                        // e.g. the checkpoint is on another device
+                        debug!("Link {} failed, copy it instead: {:?}", &src, e);
+                        let table = db.env.open(&src)?;
+                        copy_prefix(&db.env, &table, f.file_size, &dst)?;
+                    }
+                }
+            }
+            let dst = generate_filename(&dir, FileType::Manifest, manifest_number);
+            copy_prefix(&db.env, &manifest, manifest_size, &dst)?;
+            for (number, log, size) in logs.iter() {
+                let dst = generate_filename(&dir, FileType::Log, *number);
+                copy_prefix(&db.env, log, *size, &dst)?;
+            }
+            update_current(&db.env, &dir, manifest_number)?;
+            db.env.sync_dir(&dir)
+        })();
+        match result {
+            Ok(()) => {
+                info!(
+                    "Checkpoint created in {} [MANIFEST #{}, {} WAL files]",
+                    &dir,
+                    manifest_number,
+                    logs.len()
+                );
+                Ok(())
+            }
+            Err(e) => {
+                error!("Create checkpoint in {} failed: {:?}", &dir, e);
+                let _ = db.env.remove_dir(&dir, true);
+                Err(e)
+            }
+        }
+    }
+}
+
+// Copies the first `size` bytes of `src` into the new file `dst`, and syncs it
+fn copy_prefix<S: Storage>(env: &S, src: &S::F, size: u64, dst: &str) -> Result<()> {
+    let mut file = env.create(dst)?;
+    let mut buf = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let n = (size - offset).min(COPY_CHUNK_SIZE);
+        buf.resize(n as usize, 0);
+        src.read_exact_at(&mut buf, offset)?;
+        file.write(&buf)?;
+        offset += n;
+    }
+    file.sync()?;
+    file.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DB;
+    use crate::options::{Options, ReadOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn get(db: &WickDB<MemStorage, BytewiseComparator>, key: &str) -> Option<String> {
+        db.get(ReadOptions::default(), key.as_bytes())
+            .unwrap()
+            .map(|v| String::from_utf8(v.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let store = MemStorage::default();
+        let opts = Options::<BytewiseComparator> {
+            write_buffer_size: 64 * 1024,
+            ..Default::default()
+        };
+        let db = WickDB::open_db(opts.clone(), "db", store.clone()).unwrap();
+        for i in 0..100 {
+            let (k, v) = (format!("key{:03}", i), format!("v{}", i));
+            db.put(WriteOptions::default(), k.as_bytes(), v.as_bytes())
+                .unwrap();
+        }
+        db.inner.force_compact_mem_table().unwrap();
+        for i in 50..150 {
+            let (k, v) = (format!("key{:03}", i), format!("v{}-2", i));
+            db.put(WriteOptions::default(), k.as_bytes(), v.as_bytes())
+                .unwrap();
+        }
+        db.delete(WriteOptions::default(), b"key000").unwrap();
+        Checkpoint::create(&db, "checkpoint").unwrap();
+        // The memtable is flushed
+        assert!(db.inner.mem.read().unwrap().is_empty());
+        assert!(matches!(
+            Checkpoint::create(&db, "checkpoint"),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // The writes after the checkpoint are not included
+        db.put(WriteOptions::default(), b"key001", b"new").unwrap();
+        db.put(WriteOptions::default(), b"key200", b"new").unwrap();
+        let checkpoint = WickDB::open_db(opts, "checkpoint", store).unwrap();
+        assert_eq!(get(&checkpoint, "key000"), None);
+        assert_eq!(get(&checkpoint, "key001"), Some("v1".to_owned()));
+        assert_eq!(get(&checkpoint, "key049"), Some("v49".to_owned()));
+        assert_eq!(get(&checkpoint, "key050"), Some("v50-2".to_owned()));
+        assert_eq!(get(&checkpoint, "key149"), Some("v149-2".to_owned()));
+        assert_eq!(get(&checkpoint, "key200"), None);
+
+        // The checkpoint is independent of the db
+        checkpoint
+            .put(WriteOptions::default(), b"key002", b"checkpoint")
+            .unwrap();
+        assert_eq!(get(&db, "key001"), Some("new".to_owned()));
+        assert_eq!(get(&db, "key002"), Some("v2".to_owned()));
+        assert_eq!(get(&checkpoint, "key002"), Some("checkpoint".to_owned()));
+    }
+
+    #[test]
+    fn test_checkpoint_with_concurrent_writes() {
+        let store = MemStorage::default();
+        let opts = Options::<BytewiseComparator> {
+            write_buffer_size: 16 * 1024,
+            ..Default::default()
+        };
+        let db = WickDB::open_db(opts.clone(), "db", store.clone()).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (db, done) = (db.clone(), done.clone());
+            thread::spawn(move || {
+                let mut n = 0;
+                while !done.load(Ordering::Acquire) || n < 1000 {
+                    let key = format!("key{:06}", n);
+                    db.put(WriteOptions::default(), key.as_bytes(), &[b'v'; 100])
+                        .unwrap();
+                    n += 1;
+                }
+                n
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        Checkpoint::create(&db, "checkpoint").unwrap();
+        done.store(true, Ordering::Release);
+        let written = writer.join().unwrap();
+        let checkpoint = WickDB::open_db(opts, "checkpoint", store).unwrap();
+        // The checkpoint holds a prefix of the writes
+        let mut n = 0;
+        while n < written && get(&checkpoint, &format!("key{:06}", n)).is_some() {
+            n += 1;
+        }
+        assert!(n > 0);
+        for i in n..written {
+            assert_eq!(get(&checkpoint, &format!("key{:06}", i)), None);
+        }
+    }
+}
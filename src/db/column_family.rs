@@ -0,0 +1,306 @@
+use crate::iterator::Iterator;
+use crate::util::collection::{HashMap, HashSet};
+use crate::version::version_edit::VersionEdit;
+use crate::Result;
+
+/// The user keys starting with this prefix are reserved for the column families
+/// once one is created. Before that they're ordinary keys of the default column
+/// family, and creating the first column family fails if any of them is live.
+///
+/// The keys of a column family are stored in the shared keyspace as
+/// `COLUMN_FAMILY_KEY_PREFIX + id (4 bytes, big endian) + key`, so they sort after
+/// all the keys of the default column family under the bytewise ordering.
+/// Column families require a comparator whose `Comparator::is_bytewise` returns
+/// true.
+pub const COLUMN_FAMILY_KEY_PREFIX: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+
+/// A handle to a column family created by `WickDB::create_cf`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnFamilyHandle {
+    id: u32,
+    name: String,
+}
+
+impl ColumnFamilyHandle {
+    pub(crate) fn new(id: u32, name: String) -> Self {
+        Self { id, name }
+    }
+
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the prefix of all the keys in this column family
+    pub(crate) fn prefix(&self) -> Vec<u8> {
+        column_family_prefix(self.id)
+    }
+
+    /// Returns the key in the shared keyspace
+    pub(crate) fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut k = self.prefix();
+        k.extend_from_slice(key);
+        k
+    }
+}
+
+fn column_family_prefix(id: u32) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(COLUMN_FAMILY_KEY_PREFIX.len() + 4);
+    prefix.extend_from_slice(COLUMN_FAMILY_KEY_PREFIX);
+    prefix.extend_from_slice(&id.to_be_bytes());
+    prefix
+}
+
+/// Returns the prefix of the keys visible in the given column family (or the
+/// default one if `cf` is `None`) and the exclusive upper bound of the keys in
+/// the shared keyspace.
+pub(crate) fn visible_range(
+    cf: Option<&ColumnFamilyHandle>,
+    has_column_families: bool,
+) -> (Vec<u8>, Option<Vec<u8>>) {
+    match cf {
+        Some(cf) => (cf.prefix(), cf.id.checked_add(1).map(column_family_prefix)),
+        None if has_column_families => (vec![], Some(COLUMN_FAMILY_KEY_PREFIX.to_vec())),
+        None => (vec![], None),
+    }
+}
+
+/// Returns the id of the column family the given user key belongs to.
+/// Returns `None` for the keys of the default column family.
+pub(crate) fn column_family_of(user_key: &[u8]) -> Option<u32> {
+    let n = COLUMN_FAMILY_KEY_PREFIX.len();
+    if user_key.len() >= n + 4 && user_key.starts_with(COLUMN_FAMILY_KEY_PREFIX) {
+        let mut id = [0; 4];
+        id.copy_from_slice(&user_key[n..n + 4]);
+        Some(u32::from_be_bytes(id))
+    } else {
+        None
+    }
+}
+
+/// Returns whether a key of the default column family takes the keyspace
+/// reserved for the column families
+pub(crate) fn is_reserved_key(key: &[u8]) -> bool {
+    key.starts_with(COLUMN_FAMILY_KEY_PREFIX)
+}
+
+/// Returns whether a range `[begin, end)` of the default column family covers
+/// any key reserved for the column families. Every key after the prefix starts
+/// with it under the bytewise ordering.
+pub(crate) fn is_reserved_range(begin: &[u8], end: &[u8]) -> bool {
+    is_reserved_key(begin) || (is_reserved_key(end) && end != COLUMN_FAMILY_KEY_PREFIX)
+}
+
+/// Returns the given user key without the prefix of its column family
+pub(crate) fn strip_column_family(user_key: &[u8]) -> &[u8] {
+    match column_family_of(user_key) {
+        Some(_) => &user_key[COLUMN_FAMILY_KEY_PREFIX.len() + 4..],
+        None => user_key,
+    }
+}
+
+/// The column families recorded in the MANIFEST
+#[derive(Default)]
+pub(crate) struct ColumnFamilySet {
+    // The id for the next created column family. 0 is never used.
+    next_id: u32,
+    live: HashMap<String, u32>,
+    // The data of the dropped column families is removed by compactions
+    dropped: HashSet<u32>,
+}
+
+impl ColumnFamilySet {
+    /// Returns true if no column family has ever been created
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<ColumnFamilyHandle> {
+        self.live
+            .get(name)
+            .map(|id| ColumnFamilyHandle::new(*id, name.to_owned()))
+    }
+
+    pub fn is_live(&self, cf: &ColumnFamilyHandle) -> bool {
+        self.live.get(&cf.name) == Some(&cf.id)
+    }
+
+    pub fn handles(&self) -> Vec<ColumnFamilyHandle> {
+        let mut handles: Vec<_> = self
+            .live
+            .iter()
+            .map(|(name, id)| ColumnFamilyHandle::new(*id, name.clone()))
+            .collect();
+        handles.sort_by_key(|h| h.id);
+        handles
+    }
+
+    pub fn next_id(&self) -> u32 {
+        self.next_id.max(1)
+    }
+
+    pub fn dropped(&self) -> HashSet<u32> {
+        self.dropped.clone()
+    }
+
+    /// Apply the column family changes in a `VersionEdit`
+    pub fn apply(&mut self, added: &[(u32, String)], dropped: &[u32]) {
+        for (id, name) in added.iter() {
+            self.live.insert(name.clone(), *id);
+            self.next_id = self.next_id.max(*id + 1);
+        }
+        for id in dropped.iter() {
+            self.live.retain(|_, v| v != id);
+            self.dropped.insert(*id);
+        }
+    }
+
+    /// Save all the column families into the given `VersionEdit`
+    pub fn save_to(&self, edit: &mut VersionEdit) {
+        for h in self.handles() {
+            edit.add_column_family(h.id, h.name);
+        }
+        for id in self.dropped.iter() {
+            // The name of a dropped column family is not needed anymore
+            edit.add_column_family(*id, String::new());
+            edit.drop_column_family(*id);
+        }
+    }
+}
+
+/// `ColumnFamilyIterator` yields the entries of a column family from an iterator
+/// over the whole keyspace. The column family prefix is stripped from the keys.
+pub struct ColumnFamilyIterator<I: Iterator> {
+    inner: I,
+    // The prefix of all the visible keys
+    prefix: Vec<u8>,
+    // The visible keys are less than `upper`
+    upper: Option<Vec<u8>>,
+}
+
+impl<I: Iterator> ColumnFamilyIterator<I> {
+    /// Creates an iterator over the given column family or the default one if
+    /// `cf` is `None`. The keys of the other column families are always hidden
+    /// unless `has_column_families` is false.
+    pub(crate) fn new(
+        inner: I,
+        cf: Option<&ColumnFamilyHandle>,
+        has_column_families: bool,
+    ) -> Self {
+        let (prefix, upper) = visible_range(cf, has_column_families);
+        Self {
+            inner,
+            prefix,
+            upper,
+        }
+    }
+
+    #[inline]
+    fn in_range(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.prefix) && self.upper.as_ref().is_none_or(|u| key < u.as_slice())
+    }
+}
+
+impl<I: Iterator> Iterator for ColumnFamilyIterator<I> {
+    fn valid(&self) -> bool {
+        self.inner.valid() && self.in_range(self.inner.key())
+    }
+
+    fn seek_to_first(&mut self) {
+        if self.prefix.is_empty() {
+            self.inner.seek_to_first()
+        } else {
+            self.inner.seek(&self.prefix)
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        match &self.upper {
+            Some(upper) => {
+                self.inner.seek(upper);
+                if self.inner.valid() {
+                    self.inner.prev()
+                } else {
+                    self.inner.seek_to_last()
+                }
+            }
+            None => self.inner.seek_to_last(),
+        }
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        if self.prefix.is_empty() {
+            self.inner.seek(target)
+        } else {
+            let mut key = self.prefix.clone();
+            key.extend_from_slice(target);
+            self.inner.seek(&key)
+        }
+    }
+
+    fn next(&mut self) {
+        self.inner.next()
+    }
+
+    fn prev(&mut self) {
+        self.inner.prev()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.inner.key()[self.prefix.len()..]
+    }
+
+    fn value(&self) -> &[u8] {
+        self.inner.value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.inner.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_family_of() {
+        let cf = ColumnFamilyHandle::new(258, "cf".to_owned());
+        assert_eq!(column_family_of(&cf.key(b"foo")), Some(258));
+        assert_eq!(column_family_of(&cf.key(b"")), Some(258));
+        assert_eq!(column_family_of(b"foo"), None);
+        assert_eq!(column_family_of(COLUMN_FAMILY_KEY_PREFIX), None);
+    }
+
+    #[test]
+    fn test_column_family_set() {
+        let mut set = ColumnFamilySet::default();
+        assert!(set.is_empty());
+        assert_eq!(set.next_id(), 1);
+        let mut edit = VersionEdit::new(7);
+        edit.add_column_family(1, "a".to_owned());
+        edit.add_column_family(2, "b".to_owned());
+        set.apply(&edit.column_families_added, &edit.column_families_dropped);
+        let mut edit = VersionEdit::new(7);
+        edit.drop_column_family(1);
+        set.apply(&edit.column_families_added, &edit.column_families_dropped);
+        assert_eq!(set.next_id(), 3);
+        assert!(set.get("a").is_none());
+        let b = set.get("b").unwrap();
+        assert!(set.is_live(&b));
+        assert!(!set.is_live(&ColumnFamilyHandle::new(1, "a".to_owned())));
+
+        let mut edit = VersionEdit::new(7);
+        set.save_to(&mut edit);
+        let mut recovered = ColumnFamilySet::default();
+        recovered.apply(&edit.column_families_added, &edit.column_families_dropped);
+        assert_eq!(recovered.handles(), vec![b]);
+        assert_eq!(recovered.next_id(), 3);
+        assert!(recovered.dropped().contains(&1));
+    }
+}
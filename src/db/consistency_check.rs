@@ -0,0 +1,488 @@
+use crate::db::{WickDB, DB};
+use crate::iterator::Iterator;
+use crate::options::{ReadOptions, WriteOptions};
+use crate::snapshot::Snapshot;
+use crate::storage::Storage;
+use crate::util::collection::HashMap;
+use crate::util::comparator::Comparator;
+use crate::{Result, WriteBatch};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// The options of `check_consistency`
+#[derive(Debug, Clone)]
+pub struct ConsistencyCheckOptions {
+    /// The number of the concurrent workers
+    pub threads: usize,
+    /// The number of operations done by each worker
+    pub ops_per_thread: usize,
+    /// The number of keys owned by each worker. Only the owner writes these keys
+    /// so the expected result of every read is known.
+    pub keys_per_thread: usize,
+    /// The number of key groups shared by all the workers. A group is always
+    /// overwritten as a whole by a `WriteBatch`.
+    pub groups: usize,
+    /// The number of keys in each group
+    pub keys_per_group: usize,
+    /// The seed of the random workloads. The worker `i` uses `seed + i`, so a
+    /// failed check can be replayed with the same seed.
+    pub seed: u64,
+    /// The prefix of all the keys written by the check, which makes it possible
+    /// to run the check against a db holding other data. There must be no key
+    /// with this prefix before the check.
+    pub key_prefix: Vec<u8>,
+}
+
+impl Default for ConsistencyCheckOptions {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            ops_per_thread: 1000,
+            keys_per_thread: 100,
+            groups: 10,
+            keys_per_group: 5,
+            seed: 0,
+            key_prefix: b"__consistency_check/".to_vec(),
+        }
+    }
+}
+
+/// The kind of a `Violation`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A `get` does not return the latest value written by the only writer of the key
+    ReadYourWrites,
+    /// A `get` with a snapshot does not return the value at the time the snapshot
+    /// was taken
+    SnapshotRead,
+    /// An iterator disagrees with the `get`s using the same snapshot
+    SnapshotIterator,
+    /// A snapshot observes a partially applied `WriteBatch`
+    AtomicBatch,
+}
+
+/// A `Violation` is an observed result which is not allowed by the snapshot
+/// isolation semantics of the db
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub key: Vec<u8>,
+    pub expected: Option<Vec<u8>>,
+    pub actual: Option<Vec<u8>>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |v: &Option<Vec<u8>>| match v {
+            Some(v) => String::from_utf8_lossy(v).into_owned(),
+            None => "<none>".to_owned(),
+        };
+        write!(
+            f,
+            "{:?} on key {:?}: expected {}, got {}",
+            self.kind,
+            String::from_utf8_lossy(&self.key),
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+/// `ConsistencyReport` describes the result of `check_consistency`
+#[derive(Debug, Clone, Default)]
+pub struct ConsistencyReport {
+    /// The number of operations done against the db
+    pub operations: u64,
+    /// All the violations found
+    pub violations: Vec<Violation>,
+}
+
+impl ConsistencyReport {
+    /// Returns true if no violation is found
+    #[inline]
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Runs concurrent randomized workloads against a live db and validates the
+/// get/put/snapshot semantics, which is useful to validate the custom `Storage`
+/// and `Comparator` implementations.
+///
+/// Each worker mixes the following operations:
+/// * `put`/`delete` of its own keys, each followed by a `get` which must observe it
+/// * `get` of its own keys, which must return the latest value it wrote
+/// * taking a snapshot, writing more, then reading its own keys by both `get`s
+///   and an iterator at the snapshot, which must return the values at the time
+///   the snapshot was taken
+/// * overwriting a shared group of keys by a `WriteBatch`
+/// * reading a shared group at a snapshot, which must observe either all or none
+///   of a batch
+///
+/// After all the workers finish, the latest values of all the owned keys are
+/// verified again. The db errors abort the check while the violations are
+/// collected into the report.
+pub fn check_consistency<S: Storage + Clone + 'static, C: Comparator + 'static>(
+    db: &WickDB<S, C>,
+    options: &ConsistencyCheckOptions,
+) -> Result<ConsistencyReport> {
+    let stamp = Arc::new(AtomicU64::new(0));
+    let mut handles = Vec::with_capacity(options.threads);
+    for id in 0..options.threads {
+        let mut worker = Worker {
+            id,
+            db: db.clone(),
+            options: options.clone(),
+            rng: StdRng::seed_from_u64(options.seed.wrapping_add(id as u64)),
+            model: HashMap::default(),
+            version: 0,
+            stamp: stamp.clone(),
+            report: ConsistencyReport::default(),
+        };
+        handles.push(thread::spawn(move || worker.run().map(|_| worker)));
+    }
+    let mut workers = Vec::with_capacity(handles.len());
+    for h in handles {
+        workers.push(h.join().expect("consistency check worker panicked")?);
+    }
+    let mut report = ConsistencyReport::default();
+    for mut w in workers {
+        w.verify_latest()?;
+        report.operations += w.report.operations;
+        report.violations.append(&mut w.report.violations);
+    }
+    Ok(report)
+}
+
+struct Worker<S: Storage + Clone + 'static, C: Comparator> {
+    id: usize,
+    db: WickDB<S, C>,
+    options: ConsistencyCheckOptions,
+    rng: StdRng,
+    // The latest values of the owned keys
+    model: HashMap<Vec<u8>, Vec<u8>>,
+    // The version of the next value written by this worker
+    version: u64,
+    // The global counter for the values of the groups
+    stamp: Arc<AtomicU64>,
+    report: ConsistencyReport,
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> Worker<S, C> {
+    fn run(&mut self) -> Result<()> {
+        if self.options.keys_per_thread == 0 {
+            return Ok(());
+        }
+        for _ in 0..self.options.ops_per_thread {
+            match self.rng.gen_range(0, 10) {
+                0..=3 => self.put_and_get()?,
+                4 => self.delete_and_get()?,
+                5 | 6 => self.get()?,
+                7 => self.check_snapshot()?,
+                _ if self.options.groups == 0 => self.get()?,
+                8 => self.write_group()?,
+                _ => self.check_group()?,
+            }
+        }
+        Ok(())
+    }
+
+    fn owned_prefix(&self) -> Vec<u8> {
+        let mut prefix = self.options.key_prefix.clone();
+        prefix.extend_from_slice(format!("o{:04}/", self.id).as_bytes());
+        prefix
+    }
+
+    fn random_owned_key(&mut self) -> Vec<u8> {
+        let mut key = self.owned_prefix();
+        let i = self.rng.gen_range(0, self.options.keys_per_thread);
+        key.extend_from_slice(format!("{:08}", i).as_bytes());
+        key
+    }
+
+    fn group_prefix(&self, group: usize) -> Vec<u8> {
+        let mut prefix = self.options.key_prefix.clone();
+        prefix.extend_from_slice(format!("g{:04}/", group).as_bytes());
+        prefix
+    }
+
+    fn snapshot_read_options(snapshot: &Snapshot) -> ReadOptions {
+        ReadOptions {
+            snapshot: Some(*snapshot),
+            ..Default::default()
+        }
+    }
+
+    fn violation(
+        &mut self,
+        kind: ViolationKind,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        actual: Option<&[u8]>,
+    ) {
+        self.report.violations.push(Violation {
+            kind,
+            key: key.to_vec(),
+            expected: expected.map(|v| v.to_vec()),
+            actual: actual.map(|v| v.to_vec()),
+        })
+    }
+
+    // Reads the key and compares the result with the model
+    fn expect_latest(&mut self, key: &[u8]) -> Result<()> {
+        let actual = self.db.get(ReadOptions::default(), key)?;
+        self.report.operations += 1;
+        let expected = self.model.get(key).cloned();
+        if actual != expected {
+            self.violation(
+                ViolationKind::ReadYourWrites,
+                key,
+                expected.as_deref(),
+                actual.as_deref(),
+            );
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, key: Vec<u8>) -> Result<()> {
+        let value = format!("{}:{}", self.id, self.version).into_bytes();
+        self.version += 1;
+        self.db.put(WriteOptions::default(), &key, &value)?;
+        self.report.operations += 1;
+        self.model.insert(key, value);
+        Ok(())
+    }
+
+    fn put_and_get(&mut self) -> Result<()> {
+        let key = self.random_owned_key();
+        self.put(key.clone())?;
+        self.expect_latest(&key)
+    }
+
+    fn delete_and_get(&mut self) -> Result<()> {
+        let key = self.random_owned_key();
+        self.db.delete(WriteOptions::default(), &key)?;
+        self.report.operations += 1;
+        self.model.remove(&key);
+        self.expect_latest(&key)
+    }
+
+    fn get(&mut self) -> Result<()> {
+        let key = self.random_owned_key();
+        self.expect_latest(&key)
+    }
+
+    // Takes a snapshot, writes more and then checks the snapshot still observes
+    // the old values by both `get` and iterator
+    fn check_snapshot(&mut self) -> Result<()> {
+        let snapshot = self.db.snapshot();
+        let expected = self.model.clone();
+        for _ in 0..self.rng.gen_range(1, 5) {
+            let key = self.random_owned_key();
+            if self.rng.gen_bool(0.8) {
+                self.put(key)?;
+            } else {
+                self.db.delete(WriteOptions::default(), &key)?;
+                self.report.operations += 1;
+                self.model.remove(&key);
+            }
+        }
+        let read_opt = Self::snapshot_read_options(&snapshot);
+        let prefix = self.owned_prefix();
+        for i in 0..self.options.keys_per_thread {
+            let mut key = prefix.clone();
+            key.extend_from_slice(format!("{:08}", i).as_bytes());
+            let actual = self.db.get(read_opt, &key)?;
+            self.report.operations += 1;
+            let e = expected.get(&key).map(|v| v.as_slice());
+            if actual.as_deref() != e {
+                self.violation(ViolationKind::SnapshotRead, &key, e, actual.as_deref());
+            }
+        }
+        let actual = self.scan(read_opt, &prefix)?;
+        let mut expected: Vec<(Vec<u8>, Vec<u8>)> = expected.into_iter().collect();
+        expected.sort();
+        self.compare_scan(&expected, &actual);
+        self.db.release_snapshot(snapshot);
+        Ok(())
+    }
+
+    // Returns all the entries with the given prefix
+    fn scan(&mut self, read_opt: ReadOptions, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut iter = self.db.iter(read_opt)?;
+        self.report.operations += 1;
+        let mut entries = vec![];
+        iter.seek(prefix);
+        while iter.valid() && iter.key().starts_with(prefix) {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        iter.status()?;
+        Ok(entries)
+    }
+
+    // Both `expected` and `actual` are sorted by the keys
+    fn compare_scan(&mut self, expected: &[(Vec<u8>, Vec<u8>)], actual: &[(Vec<u8>, Vec<u8>)]) {
+        let (mut i, mut j) = (0, 0);
+        while i < expected.len() || j < actual.len() {
+            let (e, a) = (expected.get(i), actual.get(j));
+            match (e, a) {
+                (Some(e), Some(a)) if e.0 == a.0 => {
+                    if e.1 != a.1 {
+                        self.violation(
+                            ViolationKind::SnapshotIterator,
+                            &e.0,
+                            Some(&e.1),
+                            Some(&a.1),
+                        );
+                    }
+                    i += 1;
+                    j += 1;
+                }
+                (Some(e), a) if a.is_none_or(|a| e.0 < a.0) => {
+                    self.violation(ViolationKind::SnapshotIterator, &e.0, Some(&e.1), None);
+                    i += 1;
+                }
+                (_, Some(a)) => {
+                    self.violation(ViolationKind::SnapshotIterator, &a.0, None, Some(&a.1));
+                    j += 1;
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    // Overwrites all the keys in a random group by one `WriteBatch`
+    fn write_group(&mut self) -> Result<()> {
+        let group = self.rng.gen_range(0, self.options.groups);
+        let prefix = self.group_prefix(group);
+        let value = self.stamp.fetch_add(1, Ordering::SeqCst).to_string();
+        let mut batch = WriteBatch::default();
+        for i in 0..self.options.keys_per_group {
+            let mut key = prefix.clone();
+            key.extend_from_slice(format!("{:08}", i).as_bytes());
+            batch.put(&key, value.as_bytes());
+        }
+        self.db.write(WriteOptions::default(), batch)?;
+        self.report.operations += 1;
+        Ok(())
+    }
+
+    // Reads a random group at a snapshot, all the keys must have the same value
+    fn check_group(&mut self) -> Result<()> {
+        let group = self.rng.gen_range(0, self.options.groups);
+        let prefix = self.group_prefix(group);
+        let snapshot = self.db.snapshot();
+        let read_opt = Self::snapshot_read_options(&snapshot);
+        let mut values = Vec::with_capacity(self.options.keys_per_group);
+        for i in 0..self.options.keys_per_group {
+            let mut key = prefix.clone();
+            key.extend_from_slice(format!("{:08}", i).as_bytes());
+            let value = self.db.get(read_opt, &key)?;
+            self.report.operations += 1;
+            values.push((key, value));
+        }
+        if let Some((_, first)) = values.first() {
+            let first = first.clone();
+            for (key, value) in values.iter() {
+                if *value != first {
+                    self.violation(
+                        ViolationKind::AtomicBatch,
+                        key,
+                        first.as_deref(),
+                        value.as_deref(),
+                    );
+                }
+            }
+        }
+        let actual = self.scan(read_opt, &prefix)?;
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = values
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v)))
+            .collect();
+        self.compare_scan(&expected, &actual);
+        self.db.release_snapshot(snapshot);
+        Ok(())
+    }
+
+    // Verifies the latest values of all the owned keys after the workload
+    fn verify_latest(&mut self) -> Result<()> {
+        let prefix = self.owned_prefix();
+        for i in 0..self.options.keys_per_thread {
+            let mut key = prefix.clone();
+            key.extend_from_slice(format!("{:08}", i).as_bytes());
+            self.expect_latest(&key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+    use crate::{BytewiseComparator, Options};
+    use std::cmp::Ordering as CmpOrdering;
+
+    fn small_options() -> ConsistencyCheckOptions {
+        ConsistencyCheckOptions {
+            threads: 3,
+            ops_per_thread: 300,
+            keys_per_thread: 20,
+            groups: 4,
+            keys_per_group: 4,
+            seed: 42,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_consistency() {
+        let opts = Options::<BytewiseComparator>::default();
+        let mut db = WickDB::open_db(opts, "db", MemStorage::default()).unwrap();
+        let report = check_consistency(&db, &small_options()).unwrap();
+        assert!(report.is_consistent(), "{:?}", report.violations);
+        assert!(report.operations >= 3 * 300);
+        db.close().unwrap();
+    }
+
+    // A broken comparator treating the keys differing only in the last byte as equal
+    #[derive(Default, Clone, Copy)]
+    struct IgnoreLastByteComparator(BytewiseComparator);
+
+    impl Comparator for IgnoreLastByteComparator {
+        fn compare(&self, a: &[u8], b: &[u8]) -> CmpOrdering {
+            let trim = |k: &[u8]| k.len().saturating_sub(1);
+            self.0.compare(&a[..trim(a)], &b[..trim(b)])
+        }
+
+        fn name(&self) -> &str {
+            "IgnoreLastByteComparator"
+        }
+
+        fn separator(&self, a: &[u8], _b: &[u8]) -> Vec<u8> {
+            a.to_vec()
+        }
+
+        fn successor(&self, key: &[u8]) -> Vec<u8> {
+            key.to_vec()
+        }
+    }
+
+    #[test]
+    fn test_check_consistency_with_broken_comparator() {
+        let opts = Options::<IgnoreLastByteComparator>::default();
+        let mut db = WickDB::open_db(opts, "db", MemStorage::default()).unwrap();
+        let report = check_consistency(&db, &small_options()).unwrap();
+        assert!(!report.is_consistent());
+        assert!(report
+            .violations
+            .iter()
+            .any(|v| v.kind == ViolationKind::ReadYourWrites));
+        db.close().unwrap();
+    }
+}
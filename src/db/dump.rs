@@ -0,0 +1,351 @@
+use crate::batch::{WriteBatch, WriteBatchHandler, HEADER_SIZE};
+use crate::db::filename::{decode_current, generate_filename, FileType};
+use crate::db::format::ReadableKey;
+use crate::db::vetoed_sequence;
+use crate::record::reader::Reader;
+use crate::storage::{File, Storage};
+use crate::util::reporter::LogReporter;
+use crate::version::version_edit::VersionEdit;
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// The state of a db at the end of a MANIFEST, returned by `dump_manifest`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestSummary {
+    /// The number of the version edits decoded
+    pub edits: usize,
+    pub comparator: Option<String>,
+    pub log_number: u64,
+    pub prev_log_number: u64,
+    pub next_file_number: u64,
+    pub last_sequence: u64,
+    /// The live tables as (level, file number, file size), sorted by the levels
+    /// and the file numbers
+    pub files: Vec<(usize, u64, u64)>,
+    /// The column families as (id, name)
+    pub column_families: Vec<(u32, String)>,
+    /// The bytes dropped due to the corrupted records
+    pub dropped_bytes: u64,
+}
+
+/// Prints every version edit in the MANIFEST `file` to `out`, then the state of
+/// the db they add up to. The corrupted records and the edits failing to decode
+/// are reported in the output and skipped, like `WickDB::open_db` with
+/// `Options::paranoid_checks` unset would do.
+///
+/// `max_levels` must be the `Options::max_levels` of the db, which bounds the
+/// levels in the edits.
+pub fn dump_manifest<F: File, W: Write>(
+    file: F,
+    max_levels: usize,
+    out: &mut W,
+) -> Result<ManifestSummary> {
+    let reporter = LogReporter::new();
+    let mut reader = Reader::new(file, Some(Box::new(reporter.clone())), true, 0);
+    let mut record = vec![];
+    let mut summary = ManifestSummary::default();
+    // (level, file number) -> file size
+    let mut files = BTreeMap::new();
+    let mut column_families = BTreeMap::new();
+    while reader.read_record(&mut record) {
+        let mut edit = VersionEdit::new(max_levels);
+        if let Err(e) = edit.decoded_from(&record) {
+            writeln!(out, "Skip an edit failing to decode: {}", e).map_err(Error::IO)?;
+            continue;
+        }
+        summary.edits += 1;
+        write!(out, "#{} {:?}", summary.edits, edit).map_err(Error::IO)?;
+        if let Some(name) = edit.comparator_name.take() {
+            summary.comparator = Some(name);
+        }
+        summary.log_number = edit.log_number.unwrap_or(summary.log_number);
+        summary.prev_log_number = edit.prev_log_number.unwrap_or(summary.prev_log_number);
+        summary.next_file_number = edit.next_file_number.unwrap_or(summary.next_file_number);
+        summary.last_sequence = edit.last_sequence.unwrap_or(summary.last_sequence);
+        for (level, number) in edit.file_delta.deleted_files.iter() {
+            files.remove(&(*level, *number));
+        }
+        for (level, meta) in edit.file_delta.new_files.iter() {
+            files.insert((*level, meta.number), meta.file_size);
+        }
+        for (id, name) in edit.column_families_added.drain(..) {
+            column_families.insert(id, name);
+        }
+        for id in edit.column_families_dropped.iter() {
+            column_families.remove(id);
+        }
+    }
+    summary.dropped_bytes = reporter.dropped_bytes();
+    if let Err(e) = reporter.result() {
+        writeln!(
+            out,
+            "Dropped {} bytes of corrupted records: {}",
+            summary.dropped_bytes, e
+        )
+        .map_err(Error::IO)?;
+    }
+    summary.files = files
+        .into_iter()
+        .map(|((level, number), size)| (level, number, size))
+        .collect();
+    summary.column_families = column_families.into_iter().collect();
+    writeln!(out, "{:#?}", summary).map_err(Error::IO)?;
+    Ok(summary)
+}
+
+/// The summary of a WAL file returned by `dump_wal`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalSummary {
+    /// The number of the write batches, including the vetoed ones
+    pub batches: usize,
+    /// The number of the records in the batches
+    pub records: u64,
+    /// The write groups vetoed by the `PreCommitHook`
+    pub vetoed_groups: usize,
+    /// The first and the last sequence numbers in the file, or 0 if it's empty
+    pub first_sequence: u64,
+    pub last_sequence: u64,
+    /// The bytes dropped due to the corrupted records
+    pub dropped_bytes: u64,
+}
+
+/// Prints the write batches in the WAL `file` to `out` with their sequence
+/// numbers, and all their records if `print_records` is true. The corrupted
+/// records are reported in the output and skipped.
+pub fn dump_wal<F: File, W: Write>(
+    file: F,
+    print_records: bool,
+    out: &mut W,
+) -> Result<WalSummary> {
+    let reporter = LogReporter::new();
+    let mut reader = Reader::new(file, Some(Box::new(reporter.clone())), true, 0);
+    let mut record = vec![];
+    let mut summary = WalSummary::default();
+    while reader.read_record(&mut record) {
+        if record.len() < HEADER_SIZE {
+            writeln!(out, "Skip a too small record of {} bytes", record.len())
+                .map_err(Error::IO)?;
+            continue;
+        }
+        let size = record.len();
+        let mut batch = WriteBatch::default();
+        batch.set_contents(&mut record);
+        summary.batches += 1;
+        let seq = batch.get_sequence();
+        if let Some(seq) = vetoed_sequence(&batch) {
+            summary.vetoed_groups += 1;
+            writeln!(out, "seq {}: the write group is vetoed", seq).map_err(Error::IO)?;
+            continue;
+        }
+        let count = u64::from(batch.get_count());
+        if summary.first_sequence == 0 {
+            summary.first_sequence = seq;
+        }
+        summary.last_sequence = seq + count - 1;
+        summary.records += count;
+        writeln!(out, "seq {}: {} records, {} bytes", seq, count, size).map_err(Error::IO)?;
+        if print_records {
+            let mut printer = RecordPrinter {
+                out: &mut *out,
+                res: Ok(()),
+            };
+            if let Err(e) = batch.iterate(&mut printer) {
+                writeln!(out, "  {}", e).map_err(Error::IO)?;
+            } else {
+                printer.res.map_err(Error::IO)?;
+            }
+        }
+    }
+    summary.dropped_bytes = reporter.dropped_bytes();
+    if let Err(e) = reporter.result() {
+        writeln!(
+            out,
+            "Dropped {} bytes of corrupted records: {}",
+            summary.dropped_bytes, e
+        )
+        .map_err(Error::IO)?;
+    }
+    writeln!(out, "{:#?}", summary).map_err(Error::IO)?;
+    Ok(summary)
+}
+
+// Prints the records of a write batch
+struct RecordPrinter<'a, W: Write> {
+    out: &'a mut W,
+    // The first error of writing `out`
+    res: std::io::Result<()>,
+}
+
+impl<'a, W: Write> RecordPrinter<'a, W> {
+    fn print(&mut self, args: std::fmt::Arguments) {
+        if self.res.is_ok() {
+            self.res = writeln!(self.out, "  {}", args);
+        }
+    }
+}
+
+impl<'a, W: Write> WriteBatchHandler for RecordPrinter<'a, W> {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.print(format_args!(
+            "Put {:?} => {:?}",
+            ReadableKey(key),
+            ReadableKey(value)
+        ));
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.print(format_args!("Delete {:?}", ReadableKey(key)));
+    }
+
+    fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.print(format_args!(
+            "Merge {:?} => {:?}",
+            ReadableKey(key),
+            ReadableKey(operand)
+        ));
+    }
+
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.print(format_args!(
+            "DeleteRange [{:?}, {:?})",
+            ReadableKey(begin),
+            ReadableKey(end)
+        ));
+    }
+}
+
+/// The problems of the live tables found by `check_live_files`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveFilesReport {
+    /// The MANIFEST pointed by `CURRENT`
+    pub manifest: String,
+    /// The number of the live tables checked
+    pub files_checked: usize,
+    /// The file numbers of the live tables not found
+    pub missing_files: Vec<u64>,
+    /// The live tables whose sizes differ from the MANIFEST as
+    /// (file number, size in the MANIFEST, actual size)
+    pub size_mismatches: Vec<(u64, u64, u64)>,
+}
+
+impl LiveFilesReport {
+    /// Returns true if all the live tables are found with the expected sizes
+    #[inline]
+    pub fn is_consistent(&self) -> bool {
+        self.missing_files.is_empty() && self.size_mismatches.is_empty()
+    }
+}
+
+/// Checks that all the tables in the current version of the db in `db_path` exist
+/// with the sizes recorded in the MANIFEST, without opening the db. The MANIFEST
+/// is read the same way as `dump_manifest`.
+///
+/// # Error
+///
+/// Returns an error if `CURRENT` or the MANIFEST can't be read.
+pub fn check_live_files<S: Storage>(
+    storage: &S,
+    db_path: &str,
+    max_levels: usize,
+) -> Result<LiveFilesReport> {
+    let mut current = vec![];
+    storage
+        .open(generate_filename(db_path, FileType::Current, 0))?
+        .read_all(&mut current)?;
+    let manifest = decode_current(&current)?;
+    let file = storage.open(Path::new(db_path).join(&manifest))?;
+    let summary = dump_manifest(file, max_levels, &mut std::io::sink())?;
+    let mut report = LiveFilesReport {
+        manifest,
+        files_checked: summary.files.len(),
+        ..LiveFilesReport::default()
+    };
+    for (_, number, size) in summary.files {
+        let filename = generate_filename(db_path, FileType::Table, number);
+        if !storage.exists(&filename) {
+            report.missing_files.push(number);
+            continue;
+        }
+        let actual = storage.open(&filename)?.len()?;
+        if actual != size {
+            report.size_mismatches.push((number, size, actual));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{WickDB, DB};
+    use crate::options::{Options, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    #[test]
+    fn test_dump_manifest_and_wal() {
+        let store = MemStorage::default();
+        let opt = Options::<BytewiseComparator>::default();
+        let max_levels = opt.max_levels;
+        let mut db = WickDB::open_db(opt, "db", store.clone()).unwrap();
+        db.put(WriteOptions::default(), b"a", b"v1").unwrap();
+        db.put(WriteOptions::default(), b"b", b"v1").unwrap();
+        db.inner.force_compact_mem_table().unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put(b"c", b"v2");
+        batch.delete(b"a");
+        batch.delete_range(b"x", b"z");
+        db.write(WriteOptions::default(), batch).unwrap();
+        let log_number = db.inner.versions.lock().unwrap().log_number();
+        db.close().unwrap();
+
+        let report = check_live_files(&store, "db", max_levels).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+        assert_eq!(report.files_checked, 1);
+        let manifest = store.open(Path::new("db").join(&report.manifest)).unwrap();
+        let mut out = vec![];
+        let manifest_summary = dump_manifest(manifest, max_levels, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(manifest_summary.files.len(), 1);
+        assert_eq!(manifest_summary.log_number, log_number);
+        assert_eq!(manifest_summary.dropped_bytes, 0);
+        assert!(
+            out.contains(&format!("AddFile: @{}", manifest_summary.files[0].0)),
+            "{}",
+            out
+        );
+
+        let wal = store
+            .open(generate_filename("db", FileType::Log, log_number))
+            .unwrap();
+        let mut out = vec![];
+        let summary = dump_wal(wal, true, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(summary.batches, 1);
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.first_sequence, 3);
+        assert_eq!(summary.last_sequence, 5);
+        assert!(out.contains("seq 3: 3 records"), "{}", out);
+        assert!(out.contains("Put 'c' => 'v2'"), "{}", out);
+        assert!(out.contains("Delete 'a'"), "{}", out);
+        assert!(out.contains("DeleteRange ['x', 'z')"), "{}", out);
+
+        // A table is lost and another is truncated
+        let (_, number, size) = manifest_summary.files[0];
+        let table = generate_filename("db", FileType::Table, number);
+        let mut contents = vec![];
+        store.open(&table).unwrap().read_all(&mut contents).unwrap();
+        store.remove(&table).unwrap();
+        let report = check_live_files(&store, "db", max_levels).unwrap();
+        assert_eq!(report.missing_files, vec![number]);
+        store
+            .create(&table)
+            .unwrap()
+            .write(&contents[..contents.len() - 1])
+            .unwrap();
+        let report = check_live_files(&store, "db", max_levels).unwrap();
+        assert_eq!(report.size_mismatches, vec![(number, size, size - 1)]);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::batch::{WriteBatch, WriteBatchHandler};
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+
+/// A key range `[begin, end)` made read-only by `WickDB::fence_range`.
+///
+/// All the writes to the range accepted before the fence have sequences no
+/// larger than `sequence()`, and the writes after it are rejected with
+/// `Error::RangeFenced` until the fence is lifted by `WickDB::unfence_range`.
+/// So a shard move reading the range at the fenced sequence, e.g. with a
+/// snapshot or `ReadOptions::min_sequence_visible`, captures all its writes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeFence {
+    id: u64,
+    begin: Vec<u8>,
+    end: Vec<u8>,
+    sequence: u64,
+}
+
+impl RangeFence {
+    pub(crate) fn new(id: u64, begin: Vec<u8>, end: Vec<u8>, sequence: u64) -> Self {
+        Self {
+            id,
+            begin,
+            end,
+            sequence,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The inclusive start of the fenced range
+    #[inline]
+    pub fn begin(&self) -> &[u8] {
+        &self.begin
+    }
+
+    /// The exclusive end of the fenced range
+    #[inline]
+    pub fn end(&self) -> &[u8] {
+        &self.end
+    }
+
+    /// The sequence of the last write accepted before the fence
+    #[inline]
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+// The ranges currently fenced in a db
+#[derive(Default)]
+pub(crate) struct RangeFences {
+    next_id: u64,
+    // (id, begin, end) in the shared keyspace
+    ranges: Vec<(u64, Vec<u8>, Vec<u8>)>,
+}
+
+impl RangeFences {
+    // Fences `[begin, end)` and returns the id of the fence
+    pub(crate) fn add(&mut self, begin: Vec<u8>, end: Vec<u8>) -> u64 {
+        self.next_id += 1;
+        self.ranges.push((self.next_id, begin, end));
+        self.next_id
+    }
+
+    // Returns false if the fence has been removed
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        let len = self.ranges.len();
+        self.ranges.retain(|(i, _, _)| *i != id);
+        self.ranges.len() != len
+    }
+
+    /// Returns `Error::RangeFenced` if any record in `batch` touches a fenced range
+    pub(crate) fn check<C: Comparator>(&self, cmp: &C, batch: &WriteBatch) -> Result<()> {
+        if self.ranges.is_empty() || batch.is_empty() {
+            return Ok(());
+        }
+        let mut checker = FenceChecker {
+            cmp,
+            ranges: &self.ranges,
+            fenced: None,
+        };
+        batch.iterate(&mut checker)?;
+        match checker.fenced {
+            Some(hint) => Err(Error::RangeFenced(hint)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `Error::RangeFenced` if the keys in `[smallest, largest]` overlap a
+    /// fenced range
+    pub(crate) fn check_key_range<C: Comparator>(
+        &self,
+        cmp: &C,
+        smallest: &[u8],
+        largest: &[u8],
+    ) -> Result<()> {
+        for (_, fence_begin, fence_end) in &self.ranges {
+            if cmp.compare(smallest, fence_end) == Ordering::Less
+                && cmp.compare(fence_begin, largest) != Ordering::Greater
+            {
+                return Err(Error::RangeFenced(format!(
+                    "[{:?}, {:?}] overlaps the fenced range [{:?}, {:?})",
+                    smallest, largest, fence_begin, fence_end
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Finds the first record overlapping a fenced range
+struct FenceChecker<'a, C: Comparator> {
+    cmp: &'a C,
+    ranges: &'a [(u64, Vec<u8>, Vec<u8>)],
+    fenced: Option<String>,
+}
+
+impl<'a, C: Comparator> FenceChecker<'a, C> {
+    fn check_key(&mut self, key: &[u8]) {
+        self.check_range(key, None)
+    }
+
+    // `end` is `None` for a single key
+    fn check_range(&mut self, begin: &[u8], end: Option<&[u8]>) {
+        if self.fenced.is_some() {
+            return;
+        }
+        for (_, fence_begin, fence_end) in self.ranges {
+            let overlapped = self.cmp.compare(begin, fence_end) == Ordering::Less
+                && match end {
+                    Some(end) => self.cmp.compare(fence_begin, end) == Ordering::Less,
+                    None => self.cmp.compare(fence_begin, begin) != Ordering::Greater,
+                };
+            if overlapped {
+                self.fenced = Some(format!(
+                    "{:?} overlaps the fenced range [{:?}, {:?})",
+                    begin, fence_begin, fence_end
+                ));
+                return;
+            }
+        }
+    }
+}
+
+impl<'a, C: Comparator> WriteBatchHandler for FenceChecker<'a, C> {
+    fn put(&mut self, key: &[u8], _: &[u8]) {
+        self.check_key(key)
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.check_key(key)
+    }
+
+    fn merge(&mut self, key: &[u8], _: &[u8]) {
+        self.check_key(key)
+    }
+
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.check_range(begin, Some(end))
+    }
+}
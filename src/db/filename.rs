@@ -1,5 +1,6 @@
-use crate::storage::{do_write_string_to_file, Storage};
-use crate::Result;
+use crate::storage::Storage;
+use crate::util::crc32::{hash, mask, unmask};
+use crate::{Error, Result};
 use std::ffi::OsStr;
 use std::path::Path;
 
@@ -15,6 +16,9 @@ pub enum FileType {
     /// MANIFEST 文件记录了 LevelDB 内部状态的详细快照，包括当前的version和对应的文件索引
     /// 这包括哪些 SST 文件（Sorted String Tables）当前被数据库使用它们的元数据（如大小、键的范围等），以及它们之间的关系和层级信息
     Manifest,
+    /// `MANIFEST.bak` file is the previous `MANIFEST` kept for one generation. It's used
+    /// for recovering when the `MANIFEST` pointed by `CURRENT` is invalid.
+    ManifestBackup,
     /// `CURRENT` file saves the current used manifest filename.
     Current,
     /// `*.dbtmp` file
@@ -49,6 +53,11 @@ pub fn generate_filename(dirname: &str, filetype: FileType, seq: u64) -> String
             .into_os_string()
             .into_string()
             .unwrap(),
+        FileType::ManifestBackup => dirname
+            .join("MANIFEST.bak")
+            .into_os_string()
+            .into_string()
+            .unwrap(),
         FileType::Current => dirname
             .join("CURRENT")
             .into_os_string()
@@ -68,6 +77,35 @@ pub fn generate_filename(dirname: &str, filetype: FileType, seq: u64) -> String
     }
 }
 
+/// Returns the directory keeping the archived WAL files of the db in `dirname`
+/// (see `Options::wal_archive_ttl`)
+pub fn archive_dir(dirname: &str) -> String {
+    Path::new(dirname)
+        .join("archive")
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+/// Returns the directory keeping the files moved away by `repair_db` in `dirname`
+pub fn lost_dir(dirname: &str) -> String {
+    Path::new(dirname)
+        .join("lost")
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+/// Returns the file keeping the hot blocks sampled before the db in `dirname`
+/// was closed (see `Options::hot_blocks_to_persist`)
+pub fn hot_blocks_file(dirname: &str) -> String {
+    Path::new(dirname)
+        .join("HOT_BLOCKS")
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
 /// 返回一个tuple，包含文件类型和文件序列号
 /// The `filename` should be a valid path.
 pub fn parse_filename<P: AsRef<Path>>(filename: P) -> Option<(FileType, u64)> {
@@ -82,6 +120,10 @@ pub fn parse_filename<P: AsRef<Path>>(filename: P) -> Option<(FileType, u64)> {
             Some("LOG.old") => Some((FileType::OldInfoLog, 0)),
             _ => None,
         },
+        Some("MANIFEST") => match path.file_name().unwrap_or_else(|| OsStr::new("")).to_str() {
+            Some("MANIFEST.bak") => Some((FileType::ManifestBackup, 0)),
+            _ => None,
+        },
         Some(with_seq) => {
             if with_seq.starts_with("MANIFEST") {
                 let strs: Vec<&str> = with_seq.split('-').collect();
@@ -119,21 +161,64 @@ pub fn parse_filename<P: AsRef<Path>>(filename: P) -> Option<(FileType, u64)> {
     }
 }
 
+/// Encodes the content of `CURRENT` file for the given manifest filename:
+///
+/// | manifest filename | '\n' | masked crc32 of the filename | '\n' |
+///
+/// The checksum is written in decimal.
+pub fn encode_current(manifest: &str) -> String {
+    format!("{}\n{}\n", manifest, mask(hash(manifest.as_bytes())))
+}
+
+/// Decodes the manifest filename from the content of `CURRENT` file.
+///
+/// The legacy content that only contains the manifest filename is still accepted,
+/// but the filename must be a valid `MANIFEST-*` name anyway.
+pub fn decode_current(content: &[u8]) -> Result<String> {
+    let content = std::str::from_utf8(content)
+        .map_err(|e| Error::Corruption(format!("Invalid CURRENT file content: {}", e)))?;
+    let mut lines = content.split_terminator('\n');
+    let manifest = match lines.next() {
+        Some(name) if !name.is_empty() => name,
+        _ => return Err(Error::Corruption("CURRENT file is empty".to_owned())),
+    };
+    if let Some(checksum) = lines.next() {
+        let valid = checksum
+            .parse::<u32>()
+            .map(|c| unmask(c) == hash(manifest.as_bytes()))
+            .unwrap_or(false);
+        if !valid {
+            return Err(Error::Corruption(format!(
+                "CURRENT file checksum mismatch for {}",
+                manifest
+            )));
+        }
+    }
+    if lines.next().is_some() {
+        return Err(Error::Corruption(
+            "CURRENT file has unexpected trailing content".to_owned(),
+        ));
+    }
+    match parse_filename(manifest) {
+        Some((FileType::Manifest, _)) if !manifest.contains(['/', '\\']) => Ok(manifest.to_owned()),
+        _ => Err(Error::Corruption(format!(
+            "CURRENT file points to an invalid MANIFEST: {}",
+            manifest
+        ))),
+    }
+}
+
 /// 更新一个存储系统中的当前文件
 pub fn update_current<S: Storage>(env: &S, dir: &str, manifest_file_num: u64) -> Result<()> {
     // 生成manifest文件
     let mut manifest = generate_filename(dir, FileType::Manifest, manifest_file_num);
     // 只留下文件名
     manifest.drain(0..=dir.len());
-    // 生成临时文件
-    let tmp = generate_filename(dir, FileType::Temp, manifest_file_num);
-    // 文件名写入到新的临时文件中
-    let result = do_write_string_to_file(env, manifest, &tmp, true);
-    match &result {
-        Ok(()) => env.rename(&tmp, &generate_filename(dir, FileType::Current, 0))?,
-        Err(_) => env.remove(&tmp)?,
-    }
-    result
+    // 文件名及其校验和原子地写入 CURRENT
+    env.atomic_write(
+        generate_filename(dir, FileType::Current, 0),
+        encode_current(&manifest).as_bytes(),
+    )
 }
 
 #[cfg(test)]
@@ -149,6 +234,7 @@ mod tests {
                 (FileType::Lock, 1, "test\\LOCK"),
                 (FileType::Table, 123, "test\\000123.sst"),
                 (FileType::Manifest, 9, "test\\MANIFEST-000009"),
+                (FileType::ManifestBackup, 9, "test\\MANIFEST.bak"),
                 (FileType::Current, 1, "test\\CURRENT"),
                 (FileType::Temp, 100, "test\\000100.dbtmp"),
                 (FileType::InfoLog, 1, "test\\LOG"),
@@ -160,6 +246,7 @@ mod tests {
                 (FileType::Lock, 1, "test/LOCK"),
                 (FileType::Table, 123, "test/000123.sst"),
                 (FileType::Manifest, 9, "test/MANIFEST-000009"),
+                (FileType::ManifestBackup, 9, "test/MANIFEST.bak"),
                 (FileType::Current, 1, "test/CURRENT"),
                 (FileType::Temp, 100, "test/000100.dbtmp"),
                 (FileType::InfoLog, 1, "test/LOG"),
@@ -181,6 +268,7 @@ mod tests {
                 ("a\\b\\c\\LOCK", Some((FileType::Lock, 0))),
                 ("a\\b\\c\\010666.sst", Some((FileType::Table, 10666))),
                 ("a\\b\\c\\MANIFEST-000009", Some((FileType::Manifest, 9))),
+                ("a\\b\\c\\MANIFEST.bak", Some((FileType::ManifestBackup, 0))),
                 ("a\\b\\c\\000123.dbtmp", Some((FileType::Temp, 123))),
                 ("a\\b\\c\\CURRENT", Some((FileType::Current, 0))),
                 ("a\\b\\c\\LOG", Some((FileType::InfoLog, 0))),
@@ -191,6 +279,7 @@ mod tests {
                 ("a\\b\\c\\000def.log", None),
                 ("a\\b\\c\\MANIFEST-abcedf", None),
                 ("a\\b\\c\\MANIFEST", None),
+                ("a\\b\\c\\MANIFEST.old", None),
                 ("a\\b\\c\\MANIFEST-123123-abcdef", None),
             ]
         } else {
@@ -199,6 +288,7 @@ mod tests {
                 ("a/b/c/LOCK", Some((FileType::Lock, 0))),
                 ("a/b/c/010666.sst", Some((FileType::Table, 10666))),
                 ("a/b/c/MANIFEST-000009", Some((FileType::Manifest, 9))),
+                ("a/b/c/MANIFEST.bak", Some((FileType::ManifestBackup, 0))),
                 ("a/b/c/000123.dbtmp", Some((FileType::Temp, 123))),
                 ("a/b/c/CURRENT", Some((FileType::Current, 0))),
                 ("a/b/c/LOG", Some((FileType::InfoLog, 0))),
@@ -210,6 +300,7 @@ mod tests {
                 ("a/b/c/000def.log", None),
                 ("a/b/c/MANIFEST-abcedf", None),
                 ("a/b/c/MANIFEST", None),
+                ("a/b/c/MANIFEST.old", None),
                 ("a/b/c/MANIFEST-123123-abcdef", None),
             ]
         };
@@ -219,4 +310,40 @@ mod tests {
             assert_eq!(result, expect);
         }
     }
+
+    #[test]
+    fn test_encode_decode_current() {
+        let content = encode_current("MANIFEST-000009");
+        assert!(content.starts_with("MANIFEST-000009\n"));
+        assert!(content.ends_with('\n'));
+        assert_eq!(
+            decode_current(content.as_bytes()).unwrap(),
+            "MANIFEST-000009"
+        );
+        // legacy format
+        for legacy in ["MANIFEST-000009", "MANIFEST-000009\n"].iter() {
+            assert_eq!(
+                decode_current(legacy.as_bytes()).unwrap(),
+                "MANIFEST-000009"
+            );
+        }
+        // corrupted
+        let flipped = content.replace("000009", "000008");
+        let mut tests = vec![
+            flipped.as_bytes(),
+            b"",
+            b"\n",
+            b"MANIFEST-000009\nabc\n",
+            b"MANIFEST-000009\n1\n2\n",
+            b"000009.log\n",
+            b"a/MANIFEST-000009\n",
+            b"\xffMANIFEST\n",
+        ];
+        for c in tests.drain(..) {
+            match decode_current(c) {
+                Err(Error::Corruption(_)) => {}
+                other => panic!("expect corruption for {:?} but got {:?}", c, other),
+            }
+        }
+    }
 }
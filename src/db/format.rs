@@ -15,18 +15,39 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use crate::filter::FilterPolicy;
+use crate::filter::{FilterBuilder, FilterPolicy};
 use crate::util::coding::{decode_fixed_64, put_fixed_64};
 use crate::util::comparator::Comparator;
+use crate::util::slice_transform::SliceTransform;
 use crate::util::varint::VarintU32;
 use std::cmp::Ordering;
-use std::fmt::{Debug, Error, Formatter};
+use std::fmt::{Debug, Display, Error, Formatter};
 use std::str;
 use std::sync::Arc;
 
-/// The max key sequence number. The value is 2^56 - 1 because the seq number
-/// only takes 56 bits when is serialized to `InternalKey`
-pub const MAX_KEY_SEQUENCE: u64 = (1u64 << 56) - 1;
+/// The number of the low bits taken by the value type in the 8 bytes tail of
+/// an internal key.
+///
+/// With the `extended-sequence` feature only 4 bits are reserved for the type
+/// (the value types use 2 bits so far), which widens the sequence number space
+/// from 2^56 to 2^60. The format is recorded in the MANIFEST and a database
+/// can't be opened by a build using the other format.
+#[cfg(not(feature = "extended-sequence"))]
+pub const VALUE_TYPE_BITS: u32 = 8;
+#[cfg(feature = "extended-sequence")]
+pub const VALUE_TYPE_BITS: u32 = 4;
+
+/// The number of bits of the sequence number serialized to `InternalKey`
+pub const SEQUENCE_BITS: u32 = 64 - VALUE_TYPE_BITS;
+
+/// The number of sequence bits of the databases whose MANIFEST doesn't record it
+pub const LEGACY_SEQUENCE_BITS: u32 = 56;
+
+/// The max key sequence number. The value is 2^56 - 1 by default because the
+/// seq number only takes 56 bits when is serialized to `InternalKey`
+pub const MAX_KEY_SEQUENCE: u64 = (1u64 << SEQUENCE_BITS) - 1;
+
+const VALUE_TYPE_MASK: u64 = (1u64 << VALUE_TYPE_BITS) - 1;
 
 /// The tail bytes length of an internal key
 /// 7bytes sequence number + 1byte type number
@@ -38,6 +59,11 @@ pub enum ValueType {
     Deletion = 0,
     /// A normal value
     Value = 1,
+    /// An operand to be merged into the older values by the `MergeOperator`
+    Merge = 2,
+    /// A tombstone deleting a range of keys. It only appears in the `WriteBatch`
+    /// since the range tombstones are stored apart from the point entries.
+    RangeDeletion = 3,
 
     /// Unknown type
     Unknown,
@@ -49,11 +75,27 @@ pub enum ValueType {
 /// and the value type is embedded as the low 8 bits in the sequence
 /// number in internal keys, we need to use the highest-numbered
 /// ValueType, not the lowest).
-pub const VALUE_TYPE_FOR_SEEK: ValueType = ValueType::Value;
+pub const VALUE_TYPE_FOR_SEEK: ValueType = ValueType::RangeDeletion;
+
+// The names are the same as the operations printed by `WriteBatch`
+impl Display for ValueType {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let name = match self {
+            ValueType::Deletion => "Delete",
+            ValueType::Value => "Put",
+            ValueType::Merge => "Merge",
+            ValueType::RangeDeletion => "DeleteRange",
+            ValueType::Unknown => "Unknown",
+        };
+        f.write_str(name)
+    }
+}
 
 impl From<u64> for ValueType {
     fn from(v: u64) -> Self {
         match v {
+            3 => ValueType::RangeDeletion,
+            2 => ValueType::Merge,
             1 => ValueType::Value,
             0 => ValueType::Deletion,
             _ => ValueType::Unknown,
@@ -81,11 +123,10 @@ impl<'a> ParsedInternalKey<'a> {
             return None;
         }
         let num = decode_fixed_64(&internal_key[size - INTERNAL_KEY_TAIL..]);
-        let t = ValueType::from(num & 0xff);
+        let (seq, t) = unpack_seq_and_type(num);
         if t == ValueType::Unknown {
             return None;
         }
-        let seq = num >> INTERNAL_KEY_TAIL;
         Some(Self {
             user_key: &internal_key[..size - INTERNAL_KEY_TAIL],
             seq,
@@ -114,16 +155,56 @@ impl<'a> ParsedInternalKey<'a> {
     }
 }
 
-impl<'a> Debug for ParsedInternalKey<'a> {
+/// Parses the internal key, or returns `Error::Corruption` describing the bad key
+pub fn parse_internal_key(internal_key: &[u8]) -> crate::Result<ParsedInternalKey<'_>> {
+    ParsedInternalKey::decode_from(internal_key).ok_or_else(|| {
+        crate::Error::Corruption(format!("bad internal key {:?}", ReadableKey(internal_key)))
+    })
+}
+
+/// Formats the key as `'user key' @ seq : type`, e.g. `'foo' @ 100 : Put`
+impl<'a> Display for ParsedInternalKey<'a> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         write!(
             f,
-            "{:?} @ {} : {:?}",
-            self.user_key, self.seq, self.value_type
+            "{:?} @ {} : {}",
+            ReadableKey(self.user_key),
+            self.seq,
+            self.value_type
         )
     }
 }
 
+impl<'a> Debug for ParsedInternalKey<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Display::fmt(self, f)
+    }
+}
+
+/// `ReadableKey` formats the key bytes readably: the printable ASCII characters
+/// are kept, `\` is escaped as `\\` and the other bytes are escaped as `\xNN`,
+/// e.g. `user\x00key`. The `Debug` output is quoted by `'`.
+pub struct ReadableKey<'a>(pub &'a [u8]);
+
+impl<'a> Display for ReadableKey<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        for &b in self.0 {
+            match b {
+                b'\\' => f.write_str("\\\\")?,
+                0x20..=0x7e => write!(f, "{}", b as char)?,
+                _ => write!(f, "\\x{:02x}", b)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Debug for ReadableKey<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "'{}'", self)
+    }
+}
+
 /// A `InternalKey` is a encoding of a `ParsedInternalKey`
 ///
 /// The format of `InternalKey`:
@@ -175,34 +256,31 @@ impl InternalKey {
         &self.data[..length - INTERNAL_KEY_TAIL]
     }
 
-    /// Returns a `ParsedInternalKey`
+    /// Returns a `ParsedInternalKey`, or `None` if the key is too short or has
+    /// an unknown value type
     pub fn parsed(&self) -> Option<ParsedInternalKey<'_>> {
-        let size = self.data.len();
-        let user_key = &(self.data.as_slice())[..size - INTERNAL_KEY_TAIL];
-        let num = decode_fixed_64(&(self.data.as_slice())[size - INTERNAL_KEY_TAIL..]);
-        let t = ValueType::from(num & 0xff_u64);
-        match t {
-            ValueType::Unknown => None,
-            _ => Some(ParsedInternalKey {
-                user_key,
-                seq: num >> 8,
-                value_type: t,
-            }),
-        }
+        ParsedInternalKey::decode_from(&self.data)
     }
 }
 
-impl Debug for InternalKey {
+/// Formats a valid key the same as `ParsedInternalKey`, or the raw bytes prefixed
+/// with `(bad)` otherwise
+impl Display for InternalKey {
     fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
         if let Some(parsed) = self.parsed() {
-            write!(f, "{:?}", parsed)
+            write!(f, "{}", parsed)
         } else {
-            let s = unsafe { ::std::str::from_utf8_unchecked(self.data.as_slice()) };
-            write!(f, "(bad){}", s)
+            write!(f, "(bad){:?}", ReadableKey(&self.data))
         }
     }
 }
 
+impl Debug for InternalKey {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
 /// A `LookupKey` represents a 'Get' request from the user by the give key with a
 /// specific sequence number to perform a MVCC style query.
 /// 
@@ -252,8 +330,16 @@ impl LookupKey {
         let len = self.data.len();
         &self.data[self.ukey_start..len - INTERNAL_KEY_TAIL]
     }
+
+    /// Returns the sequence number of the lookup
+    pub fn sequence(&self) -> u64 {
+        decode_fixed_64(&self.data[self.data.len() - INTERNAL_KEY_TAIL..]) >> VALUE_TYPE_BITS
+    }
 }
 
+/// The name of `InternalKeyComparator`
+pub const INTERNAL_KEY_COMPARATOR_NAME: &str = "leveldb.InternalKeyComparator";
+
 /// `InternalKeyComparator` 用于比较 LevelDB 的 internal key(user key+ sequence number+type tag),里面封装了user key 比较器，
 /// 比较结果按以下顺序排序：
 //      user key升序（根据用户提供的比较器）
@@ -301,7 +387,7 @@ impl<C: Comparator> Comparator for InternalKeyComparator<C> {
 
     #[inline]
     fn name(&self) -> &str {
-        "leveldb.InternalKeyComparator"
+        INTERNAL_KEY_COMPARATOR_NAME
     }
 
     fn separator(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
@@ -335,36 +421,210 @@ impl<C: Comparator> Comparator for InternalKeyComparator<C> {
             s.to_owned()
         }
     }
+
+    #[inline]
+    fn numeric_key(&self, key: &[u8]) -> Option<u64> {
+        self.user_comparator.numeric_key(extract_user_key(key))
+    }
 }
 
 /// internal key过滤策略的包装器
 /// 快速判断一个键是否存在于一个集合中
-pub struct InternalFilterPolicy {
+///
+/// The table builder feeds internal keys into the filter block, so the user
+/// provided `FilterPolicy` only ever sees user keys through this wrapper: the
+/// sequence number and the value type are stripped before hashing. Adjacent
+/// versions of the same user key (judged by the user comparator) are added
+/// to the filter only once.
+///
+/// With a prefix extractor, the prefixes of the user keys are added as well
+/// for prefix seeks, and the whole keys could be left out to make the filters
+/// smaller at the cost of point lookups probing the prefixes instead.
+pub struct InternalFilterPolicy<C: Comparator> {
     user_policy: Arc<dyn FilterPolicy>,
+    user_comparator: C,
+    prefix_extractor: Option<Arc<dyn SliceTransform>>,
+    whole_key_filtering: bool,
+    // The name of the user policy with the kinds of keys in the filters, so
+    // the filters built with a different setting are ignored by the readers
+    name: String,
 }
 
-impl InternalFilterPolicy {
-    pub fn new(user_policy: Arc<dyn FilterPolicy>) -> Self {
-        Self { user_policy }
+impl<C: Comparator> InternalFilterPolicy<C> {
+    pub fn new(user_policy: Arc<dyn FilterPolicy>, user_comparator: C) -> Self {
+        let name = user_policy.name().to_owned();
+        Self {
+            user_policy,
+            user_comparator,
+            prefix_extractor: None,
+            whole_key_filtering: true,
+            name,
+        }
+    }
+
+    /// Adds the prefixes extracted by `prefix_extractor` into the filters.
+    /// The whole user keys are added too if `whole_key_filtering` is true.
+    pub fn with_prefix_extractor(
+        mut self,
+        prefix_extractor: Arc<dyn SliceTransform>,
+        whole_key_filtering: bool,
+    ) -> Self {
+        self.name = format!(
+            "{}.{}:{}",
+            self.user_policy.name(),
+            if whole_key_filtering {
+                "WholeKeyAndPrefix"
+            } else {
+                "Prefix"
+            },
+            prefix_extractor.name()
+        );
+        self.prefix_extractor = Some(prefix_extractor);
+        self.whole_key_filtering = whole_key_filtering;
+        self
+    }
+
+    // Returns the key probed by a point lookup of `ukey`, or `None` if the
+    // filters can't tell whether the key exists
+    fn point_probe<'a>(&self, ukey: &'a [u8]) -> Option<&'a [u8]> {
+        match &self.prefix_extractor {
+            Some(extractor) if !self.whole_key_filtering => {
+                if extractor.in_domain(ukey) {
+                    Some(extractor.transform(ukey))
+                } else {
+                    None
+                }
+            }
+            _ => Some(ukey),
+        }
     }
 }
 
-impl FilterPolicy for InternalFilterPolicy {
+impl<C: Comparator + 'static> FilterPolicy for InternalFilterPolicy<C> {
     fn name(&self) -> &str {
-        self.user_policy.name()
+        &self.name
     }
 
     fn may_contain(&self, filter: &[u8], key: &[u8]) -> bool {
-        let user_key = extract_user_key(key);
-        self.user_policy.may_contain(filter, user_key)
+        match self.point_probe(extract_user_key(key)) {
+            Some(probe) => self.user_policy.may_contain(filter, probe),
+            None => true,
+        }
     }
 
+    fn may_contain_many(&self, filter: &[u8], keys: &[&[u8]]) -> Vec<bool> {
+        let probes: Vec<Option<&[u8]>> = keys
+            .iter()
+            .map(|key| self.point_probe(extract_user_key(key)))
+            .collect();
+        let to_check: Vec<&[u8]> = probes.iter().filter_map(|p| *p).collect();
+        let mut checked = self
+            .user_policy
+            .may_contain_many(filter, &to_check)
+            .into_iter();
+        probes
+            .iter()
+            .map(|p| p.is_none() || checked.next().unwrap_or(true))
+            .collect()
+    }
+
+    fn may_contain_prefix(&self, filter: &[u8], prefix: &[u8]) -> bool {
+        if self.prefix_extractor.is_some() {
+            self.user_policy.may_contain(filter, prefix)
+        } else {
+            true
+        }
+    }
 
     fn create_filter(&self, keys: &Vec<&[u8]>) -> Vec<u8> {
-        let user_keys = keys.iter()
-            .map(|key| extract_user_key(key))
-            .collect();
-        self.user_policy.create_filter(&user_keys)
+        let mut filter_keys: Vec<&[u8]> = Vec::with_capacity(keys.len());
+        let mut last_user_key: Option<&[u8]> = None;
+        let mut last_prefix: Option<&[u8]> = None;
+        for key in keys {
+            let ukey = extract_user_key(key);
+            // internal keys are sorted so the versions of a user key are adjacent
+            if let Some(last) = last_user_key {
+                if self.user_comparator.compare(last, ukey) == Ordering::Equal {
+                    continue;
+                }
+            }
+            last_user_key = Some(ukey);
+            if self.whole_key_filtering {
+                filter_keys.push(ukey);
+            }
+            if let Some(extractor) = &self.prefix_extractor {
+                if extractor.in_domain(ukey) {
+                    let prefix = extractor.transform(ukey);
+                    if last_prefix != Some(prefix) {
+                        filter_keys.push(prefix);
+                        last_prefix = Some(prefix);
+                    }
+                }
+            }
+        }
+        self.user_policy.create_filter(&filter_keys)
+    }
+
+    fn new_filter_builder(&self) -> Option<Box<dyn FilterBuilder>> {
+        self.user_policy.new_filter_builder().map(|user_builder| {
+            Box::new(InternalFilterBuilder {
+                user_builder,
+                user_comparator: self.user_comparator.clone(),
+                prefix_extractor: self.prefix_extractor.clone(),
+                whole_key_filtering: self.whole_key_filtering,
+                last_user_key: None,
+                last_prefix: None,
+            }) as Box<dyn FilterBuilder>
+        })
+    }
+}
+
+// The `FilterBuilder` of `InternalFilterPolicy`. Only the latest added user key
+// and prefix are kept for collapsing the versions of the same user key and the
+// keys of the same prefix.
+struct InternalFilterBuilder<C: Comparator> {
+    user_builder: Box<dyn FilterBuilder>,
+    user_comparator: C,
+    prefix_extractor: Option<Arc<dyn SliceTransform>>,
+    whole_key_filtering: bool,
+    last_user_key: Option<Vec<u8>>,
+    last_prefix: Option<Vec<u8>>,
+}
+
+impl<C: Comparator> FilterBuilder for InternalFilterBuilder<C> {
+    fn add_key(&mut self, key: &[u8]) {
+        let ukey = extract_user_key(key);
+        if let Some(last) = self.last_user_key.as_mut() {
+            if self.user_comparator.compare(last, ukey) == Ordering::Equal {
+                return;
+            }
+            last.clear();
+            last.extend_from_slice(ukey);
+        } else {
+            self.last_user_key = Some(ukey.to_vec());
+        }
+        if self.whole_key_filtering {
+            self.user_builder.add_key(ukey);
+        }
+        if let Some(extractor) = &self.prefix_extractor {
+            if extractor.in_domain(ukey) {
+                let prefix = extractor.transform(ukey);
+                if self.last_prefix.as_deref() != Some(prefix) {
+                    self.user_builder.add_key(prefix);
+                    self.last_prefix = Some(prefix.to_vec());
+                }
+            }
+        }
+    }
+
+    fn num_keys(&self) -> usize {
+        self.user_builder.num_keys()
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        self.last_user_key = None;
+        self.last_prefix = None;
+        self.user_builder.finish()
     }
 }
 
@@ -390,27 +650,39 @@ fn extract_seq_number(key: &[u8]) -> u64 {
         "[internal key] invalid size of internal key : expect >= 8 but got {}",
         size
     );
-    decode_fixed_64(&key[size - INTERNAL_KEY_TAIL..]) >> INTERNAL_KEY_TAIL
+    decode_fixed_64(&key[size - INTERNAL_KEY_TAIL..]) >> VALUE_TYPE_BITS
 }
 
 #[inline]
 // compose sequence number and value type into a single u64
-fn pack_seq_and_type(seq: u64, v_type: ValueType) -> u64 {
+pub(crate) fn pack_seq_and_type(seq: u64, v_type: ValueType) -> u64 {
     assert!(
         seq <= MAX_KEY_SEQUENCE,
         "[key seq] the sequence number should be <= {}, but got {}",
         MAX_KEY_SEQUENCE,
         seq
     );
-    seq << 8 | v_type as u64
+    seq << VALUE_TYPE_BITS | v_type as u64
+}
+
+/// Splits the tail of an internal key into the sequence number and value type
+#[inline]
+pub(crate) fn unpack_seq_and_type(tag: u64) -> (u64, ValueType) {
+    (
+        tag >> VALUE_TYPE_BITS,
+        ValueType::from(tag & VALUE_TYPE_MASK),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filter::bloom::BloomFilter;
     use crate::util::comparator::BytewiseComparator;
+    use crate::util::slice_transform::FixedPrefixTransform;
 
     #[test]
+    #[cfg(not(feature = "extended-sequence"))]
     fn test_pack_seq_and_type() {
         let mut tests: Vec<(u64, ValueType, Vec<u8>)> = vec![
             (1, ValueType::Value, vec![1, 1, 0, 0, 0, 0, 0, 0]),
@@ -427,10 +699,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_internal_key_format() {
+        let key = InternalKey::new(b"foo", 100, ValueType::Value);
+        assert_eq!(format!("{}", key), "'foo' @ 100 : Put");
+        assert_eq!(format!("{:?}", key), "'foo' @ 100 : Put");
+        let parsed = parse_internal_key(key.data()).unwrap();
+        assert_eq!(parsed.user_key, b"foo");
+        assert_eq!(format!("{}", parsed), "'foo' @ 100 : Put");
+        let key = InternalKey::new(b"a\x00\\\xff", 7, ValueType::RangeDeletion);
+        assert_eq!(format!("{}", key), "'a\\x00\\\\\\xff' @ 7 : DeleteRange");
+        assert_eq!(
+            format!("{}", ParsedInternalKey::new(b"", 1, ValueType::Deletion)),
+            "'' @ 1 : Delete"
+        );
+        assert_eq!(format!("{}", ValueType::Merge), "Merge");
+
+        // Too short or an unknown value type
+        for bad in [&b"abc"[..], &[b'k', 0xff, 0, 0, 0, 0, 0, 0, 0]].iter() {
+            assert!(matches!(
+                parse_internal_key(bad),
+                Err(crate::Error::Corruption(_))
+            ));
+            let key = InternalKey::decoded_from(bad);
+            assert!(key.parsed().is_none());
+            assert!(format!("{}", key).starts_with("(bad)'"));
+        }
+        assert_eq!(
+            format!("{}", InternalKey::decoded_from(b"abc")),
+            "(bad)'abc'"
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_pack_seq_and_type_panic() {
-        pack_seq_and_type(1 << 56, ValueType::Value);
+        pack_seq_and_type(MAX_KEY_SEQUENCE + 1, ValueType::Value);
     }
 
     fn assert_encoded_decoded(key: &str, seq: u64, vt: ValueType) {
@@ -458,6 +762,7 @@ mod tests {
             (1u64 << 32) - 1,
             1u64 << 32,
             (1u64 << 32) + 1,
+            MAX_KEY_SEQUENCE,
         ];
         for i in 0..test_keys.len() {
             for j in 0..test_seqs.len() {
@@ -578,4 +883,141 @@ mod tests {
             );
         }
     }
+
+    // A `FilterPolicy` that records every key it's asked to build a filter from
+    struct KeysRecorder {}
+
+    impl FilterPolicy for KeysRecorder {
+        fn name(&self) -> &str {
+            "KeysRecorder"
+        }
+
+        fn may_contain(&self, filter: &[u8], key: &[u8]) -> bool {
+            filter.split(|b| *b == b',').any(|k| k == key)
+        }
+
+        fn create_filter(&self, keys: &Vec<&[u8]>) -> Vec<u8> {
+            keys.join(&b',')
+        }
+    }
+
+    #[test]
+    fn test_internal_filter_policy_strips_key_tail() {
+        let policy = InternalFilterPolicy::new(
+            Arc::new(BloomFilter::new(10)),
+            BytewiseComparator::default(),
+        );
+        let keys = [
+            InternalKey::new(b"bar", 3, ValueType::Value),
+            InternalKey::new(b"foo", 2, ValueType::Deletion),
+        ];
+        let filter = policy.create_filter(&keys.iter().map(|k| k.data()).collect());
+        // any sequence number of the same user key should match
+        for (ukey, seq) in &[(b"bar", 100), (b"foo", 1), (b"foo", MAX_KEY_SEQUENCE)] {
+            let ikey = InternalKey::new(*ukey, *seq, VALUE_TYPE_FOR_SEEK);
+            assert!(policy.may_contain(&filter, ikey.data()));
+        }
+        let missing = InternalKey::new(b"hello", 3, ValueType::Value);
+        assert!(!policy.may_contain(&filter, missing.data()));
+    }
+
+    #[test]
+    fn test_internal_filter_policy_dedup_versions() {
+        let policy =
+            InternalFilterPolicy::new(Arc::new(KeysRecorder {}), BytewiseComparator::default());
+        let keys = [
+            InternalKey::new(b"a", 5, ValueType::Value),
+            InternalKey::new(b"a", 4, ValueType::Deletion),
+            InternalKey::new(b"a", 1, ValueType::Value),
+            InternalKey::new(b"b", 3, ValueType::Value),
+            InternalKey::new(b"c", 9, ValueType::Value),
+            InternalKey::new(b"c", 2, ValueType::Value),
+        ];
+        let filter = policy.create_filter(&keys.iter().map(|k| k.data()).collect());
+        assert_eq!(filter.as_slice(), b"a,b,c");
+    }
+
+    #[test]
+    fn test_internal_filter_builder() {
+        let policy = InternalFilterPolicy::new(
+            Arc::new(BloomFilter::new(10)),
+            BytewiseComparator::default(),
+        );
+        let keys = [
+            InternalKey::new(b"a", 5, ValueType::Value),
+            InternalKey::new(b"a", 4, ValueType::Deletion),
+            InternalKey::new(b"b", 3, ValueType::Value),
+            InternalKey::new(b"c", 9, ValueType::Value),
+            InternalKey::new(b"c", 2, ValueType::Value),
+        ];
+        let mut builder = policy.new_filter_builder().unwrap();
+        for _ in 0..2 {
+            for k in keys.iter() {
+                builder.add_key(k.data());
+            }
+            assert_eq!(builder.num_keys(), 3);
+            assert_eq!(
+                builder.finish(),
+                policy.create_filter(&keys.iter().map(|k| k.data()).collect())
+            );
+        }
+        let recorder =
+            InternalFilterPolicy::new(Arc::new(KeysRecorder {}), BytewiseComparator::default());
+        assert!(recorder.new_filter_builder().is_none());
+    }
+
+    #[test]
+    fn test_internal_filter_policy_with_prefixes() {
+        let keys = [
+            InternalKey::new(b"aa1", 5, ValueType::Value),
+            InternalKey::new(b"aa1", 4, ValueType::Value),
+            InternalKey::new(b"aa2", 3, ValueType::Value),
+            InternalKey::new(b"b", 3, ValueType::Value),
+            InternalKey::new(b"bb1", 9, ValueType::Value),
+        ];
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.data()).collect();
+        for (whole_key_filtering, expected) in
+            [(true, &b"aa1,aa,aa2,b,bb1,bb"[..]), (false, &b"aa,bb"[..])]
+        {
+            let policy =
+                InternalFilterPolicy::new(Arc::new(KeysRecorder {}), BytewiseComparator::default())
+                    .with_prefix_extractor(
+                        Arc::new(FixedPrefixTransform::new(2)),
+                        whole_key_filtering,
+                    );
+            let filter = policy.create_filter(&keys);
+            assert_eq!(filter.as_slice(), expected);
+            assert!(policy.may_contain_prefix(&filter, b"aa"));
+            assert!(!policy.may_contain_prefix(&filter, b"cc"));
+            let probe = InternalKey::new(b"aa3", 1, VALUE_TYPE_FOR_SEEK);
+            // Only the prefix of "aa3" is in the filter
+            assert_eq!(
+                policy.may_contain(&filter, probe.data()),
+                !whole_key_filtering
+            );
+            // A key out of the domain can't be ruled out by the prefixes
+            let probe = InternalKey::new(b"c", 1, VALUE_TYPE_FOR_SEEK);
+            assert_eq!(
+                policy.may_contain(&filter, probe.data()),
+                !whole_key_filtering
+            );
+        }
+
+        let policy = InternalFilterPolicy::new(
+            Arc::new(BloomFilter::new(10)),
+            BytewiseComparator::default(),
+        );
+        assert_eq!(policy.name(), "wickdb.BuiltinBloomFilter2");
+        let policy = policy.with_prefix_extractor(Arc::new(FixedPrefixTransform::new(2)), false);
+        assert_eq!(
+            policy.name(),
+            "wickdb.BuiltinBloomFilter2.Prefix:wickdb.FixedPrefix.2"
+        );
+        let mut builder = policy.new_filter_builder().unwrap();
+        for k in keys.iter() {
+            builder.add_key(k);
+        }
+        assert_eq!(builder.num_keys(), 2);
+        assert_eq!(builder.finish(), policy.create_filter(&keys));
+    }
 }
@@ -1,13 +1,17 @@
 use crate::db::format::ValueType;
-use crate::db::format::{extract_user_key, InternalKey, ParsedInternalKey, VALUE_TYPE_FOR_SEEK};
+use crate::db::format::{
+    extract_user_key, InternalKey, ParsedInternalKey, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK,
+};
+use crate::db::range_del::FragmentedRangeTombstones;
 use crate::db::DBImpl;
 use crate::iterator::{Iterator, KMergeCore};
 use crate::storage::Storage;
-use crate::util::comparator::Comparator;
+use crate::util::comparator::{key_with_timestamp, split_timestamp, Comparator};
 use crate::{Error, Result};
 use rand::Rng;
 use std::cmp::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Eq, PartialEq)]
 enum Direction {
@@ -41,50 +45,88 @@ pub struct DBIterator<I: Iterator, S: Storage + Clone + 'static, C: Comparator>
     saved_key: Vec<u8>,
     // Current value when direction is Reverse
     saved_value: Vec<u8>,
+    // Current key and merged value when direction is Forward and the current
+    // entry is a merge operand. The inner iterator has been moved past all
+    // the entries of the key.
+    merged: Option<(Vec<u8>, Vec<u8>)>,
+    // The range tombstones hiding the entries they cover
+    range_del: FragmentedRangeTombstones<C>,
+    // The visible user keys are in `[lower_bound, upper_bound)`
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+    // The newest timestamp visible if the user keys have timestamps. The
+    // versions of a user key with different timestamps are combined like the
+    // versions with different sequences.
+    timestamp: Option<u64>,
+    // The deadline of the block reads. The iterator stops at the first read
+    // timing out instead of skipping the table.
+    deadline: Option<Instant>,
 }
 
 impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> Iterator for DBIterator<I, S, C> {
     fn valid(&self) -> bool {
-        self.valid
+        self.valid && self.in_bounds(self.current_key())
     }
 
     fn seek_to_first(&mut self) {
+        if let Some(lower) = self.lower_bound.clone() {
+            // The bound already has the timestamp
+            return self.seek_key(lower);
+        }
         self.direction = Direction::Forward;
         self.saved_value.clear();
+        self.merged = None;
         self.inner.seek_to_first();
         if self.inner.valid() {
             self.find_next_user_entry(false);
         } else {
             self.valid = false;
         }
+        self.check_timed_out();
     }
 
     fn seek_to_last(&mut self) {
         self.direction = Direction::Reverse;
         self.saved_value.clear();
-        self.inner.seek_to_last();
+        self.merged = None;
+        match &self.upper_bound {
+            Some(upper) => {
+                // Position before all the entries of `upper`
+                let ikey =
+                    ParsedInternalKey::new(upper, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK).encode();
+                self.inner.seek(ikey.data());
+                if self.inner.valid() {
+                    self.inner.prev();
+                } else {
+                    self.inner.seek_to_last();
+                }
+            }
+            None => self.inner.seek_to_last(),
+        }
         self.find_prev_user_key();
+        self.check_timed_out();
     }
 
     fn seek(&mut self, target: &[u8]) {
-        self.direction = Direction::Forward;
-        self.saved_value.clear();
-        self.saved_key.clear();
-        let ikey = ParsedInternalKey::new(target, self.sequence, VALUE_TYPE_FOR_SEEK).encode();
-        self.inner.seek(ikey.data());
-        if self.inner.valid() {
-            self.find_next_user_entry(false)
-        } else {
-            self.valid = false;
-        }
+        let target = match self.timestamp {
+            Some(ts) => key_with_timestamp(target, ts),
+            None => target.to_vec(),
+        };
+        self.seek_key(target);
     }
 
     fn next(&mut self) {
         self.valid_or_panic();
         match self.direction {
             Direction::Forward => {
-                self.saved_key = Vec::from(extract_user_key(self.inner.key()));
-                self.inner.next();
+                match self.merged.take() {
+                    // The inner iter has been moved past the entries of the merged key
+                    Some((key, _)) => self.saved_key = key,
+                    None => {
+                        self.saved_key = Vec::from(extract_user_key(self.inner.key()));
+                        self.inner.next();
+                    }
+                }
                 if !self.inner.valid() {
                     self.valid = false;
                     self.saved_key.clear();
@@ -108,6 +150,7 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> Iterator for DBIt
             }
         }
         self.find_next_user_entry(true);
+        self.check_timed_out();
     }
 
     fn prev(&mut self) {
@@ -115,7 +158,16 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> Iterator for DBIt
         // inner iter is pointing at the current entry.  Scan backwards until
         // the key changes so we can use the normal reverse scanning code.
         if self.direction == Direction::Forward {
-            self.saved_key = Vec::from(extract_user_key(self.inner.key()));
+            match self.merged.take() {
+                Some((key, _)) => {
+                    self.saved_key = key;
+                    if !self.inner.valid() {
+                        // The merged key is the last one, so the last entry belongs to it
+                        self.inner.seek_to_last();
+                    }
+                }
+                None => self.saved_key = Vec::from(extract_user_key(self.inner.key())),
+            }
             loop {
                 self.inner.prev();
                 if !self.inner.valid() {
@@ -124,7 +176,7 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> Iterator for DBIt
                     self.saved_value.clear();
                     return;
                 }
-                if self.ucmp.compare(
+                if self.ucmp.compare_without_timestamp(
                     extract_user_key(self.inner.key()),
                     self.saved_key.as_slice(),
                 ) == Ordering::Less
@@ -135,20 +187,24 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> Iterator for DBIt
             self.direction = Direction::Reverse;
         }
         self.find_prev_user_key();
+        self.check_timed_out();
     }
 
     fn key(&self) -> &[u8] {
         self.valid_or_panic();
-        match self.direction {
-            Direction::Forward => extract_user_key(self.inner.key()),
-            Direction::Reverse => &self.saved_key,
+        match self.timestamp {
+            Some(_) => split_timestamp(self.current_key()).0,
+            None => self.current_key(),
         }
     }
 
     fn value(&self) -> &[u8] {
         self.valid_or_panic();
         match self.direction {
-            Direction::Forward => self.inner.value(),
+            Direction::Forward => match &self.merged {
+                Some((_, value)) => value,
+                None => self.inner.value(),
+            },
             Direction::Reverse => &self.saved_value,
         }
     }
@@ -163,7 +219,13 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> Iterator for DBIt
 }
 
 impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> DBIterator<I, S, C> {
-    pub fn new(iter: I, db: Arc<DBImpl<S, C>>, sequence: u64, ucmp: C) -> Self {
+    pub fn new(
+        iter: I,
+        db: Arc<DBImpl<S, C>>,
+        sequence: u64,
+        ucmp: C,
+        range_del: FragmentedRangeTombstones<C>,
+    ) -> Self {
         Self {
             valid: false,
             db: db.clone(),
@@ -175,7 +237,102 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> DBIterator<I, S,
             bytes_util_read_sampling: random_compaction_period(db.options.read_bytes_period),
             saved_key: Default::default(),
             saved_value: Default::default(),
+            merged: None,
+            range_del,
+            lower_bound: None,
+            upper_bound: None,
+            timestamp: None,
+            deadline: None,
+        }
+    }
+
+    /// Limits the visible user keys to `[lower, upper)`. `None` means unbounded.
+    pub fn with_bounds(mut self, lower: Option<Vec<u8>>, upper: Option<Vec<u8>>) -> Self {
+        self.lower_bound = lower;
+        self.upper_bound = upper;
+        self
+    }
+
+    /// Only yields the newest version of each user key whose timestamp is not
+    /// newer than `ts`. The keys are yielded without timestamps and the seek
+    /// targets are given without timestamps. The bounds must have timestamps.
+    pub fn with_timestamp(mut self, ts: u64) -> Self {
+        self.timestamp = Some(ts);
+        self
+    }
+
+    /// Makes the iterator invalid once a block read exceeds `deadline`, and the
+    /// `Error::TimedOut` is returned by `status`.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // Invalidates the iterator if a read of the inner iterator has timed out. The
+    // inner iterator skips the table timing out and keeps going, so its error is
+    // checked after every move.
+    fn check_timed_out(&mut self) {
+        if self.deadline.is_none() {
+            return;
+        }
+        if let Err(e) = self.inner.status() {
+            if let Error::TimedOut(_) = e {
+                self.valid = false;
+                self.saved_key.clear();
+                self.saved_value.clear();
+                self.merged = None;
+                self.direction = Direction::Forward;
+            }
+            // The error taken from the inner iterator is returned by `status`
+            if self.err.is_none() {
+                self.err = Some(e);
+            }
+        }
+    }
+
+    // Seeks to the first entry at or after `target`, which has the timestamp if
+    // the user keys have timestamps. A target before the lower bound is clamped
+    // to the bound.
+    fn seek_key(&mut self, target: Vec<u8>) {
+        self.direction = Direction::Forward;
+        self.saved_value.clear();
+        self.saved_key.clear();
+        self.merged = None;
+        let target = match &self.lower_bound {
+            Some(lower) if self.ucmp.compare(&target, lower) == Ordering::Less => lower.clone(),
+            _ => target,
+        };
+        let ikey = ParsedInternalKey::new(&target, self.sequence, VALUE_TYPE_FOR_SEEK).encode();
+        self.inner.seek(ikey.data());
+        if self.inner.valid() {
+            self.find_next_user_entry(false)
+        } else {
+            self.valid = false;
         }
+        self.check_timed_out();
+    }
+
+    // The user key of the current entry. Only meaningful if `self.valid` is true.
+    // The key has the timestamp if there is one.
+    pub(crate) fn current_key(&self) -> &[u8] {
+        match self.direction {
+            Direction::Forward => match &self.merged {
+                Some((key, _)) => key,
+                None if self.inner.valid() => extract_user_key(self.inner.key()),
+                None => &[],
+            },
+            Direction::Reverse => &self.saved_key,
+        }
+    }
+
+    fn in_bounds(&self, key: &[u8]) -> bool {
+        self.lower_bound
+            .as_ref()
+            .is_none_or(|l| self.ucmp.compare(key, l) != Ordering::Less)
+            && self
+                .upper_bound
+                .as_ref()
+                .is_none_or(|u| self.ucmp.compare(key, u) == Ordering::Less)
     }
 
     #[inline]
@@ -197,20 +354,43 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> DBIterator<I, S,
         InternalKey::decoded_from(k)
     }
 
+    // Returns the value type of the entry, or `Deletion` if it's covered by a
+    // visible range tombstone
+    #[inline]
+    fn value_type_of(&self, pkey: &ParsedInternalKey) -> ValueType {
+        if self
+            .range_del
+            .should_delete(pkey.user_key, pkey.seq, self.sequence)
+        {
+            ValueType::Deletion
+        } else {
+            pkey.value_type
+        }
+    }
+
+    // Returns true if the entry is visible by the sequence and the timestamp
+    #[inline]
+    fn is_visible(&self, pkey: &ParsedInternalKey) -> bool {
+        pkey.seq <= self.sequence
+            && self
+                .timestamp
+                .is_none_or(|ts| split_timestamp(pkey.user_key).1 <= ts)
+    }
+
     // Try to point the inner iter to yield a internal key whose user key is greater than previous
     // user key with sequence limitation. We only need to find the first entry that has a different
     // user key.
     fn find_next_user_entry(&mut self, mut skipping: bool) {
         let ucmp = self.ucmp.clone();
-        let seq = self.sequence;
         loop {
             let saved_key = self.saved_key.clone();
             if let Some(pkey) = self.parse_key().parsed() {
-                if pkey.seq <= seq {
-                    match pkey.value_type {
+                if self.is_visible(&pkey) {
+                    match self.value_type_of(&pkey) {
                         ValueType::Value => {
                             if skipping
-                                && ucmp.compare(pkey.user_key, saved_key.as_slice())
+                                && ucmp
+                                    .compare_without_timestamp(pkey.user_key, saved_key.as_slice())
                                     != Ordering::Greater
                             {
                                 // not greater than saved_key, so the key is skipped
@@ -229,6 +409,18 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> DBIterator<I, S,
                             self.saved_key = Vec::from(pkey.user_key);
                             skipping = true;
                         }
+                        ValueType::Merge => {
+                            if skipping
+                                && ucmp
+                                    .compare_without_timestamp(pkey.user_key, saved_key.as_slice())
+                                    != Ordering::Greater
+                            {
+                                // not greater than saved_key, so the key is skipped
+                            } else {
+                                self.merge_forward();
+                                return;
+                            }
+                        }
                         _ => { /* ignore the unknown value type */ }
                     }
                 }
@@ -252,29 +444,42 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> DBIterator<I, S,
     fn find_prev_user_key(&mut self) {
         let mut value_type = ValueType::Deletion;
         let ucmp = self.ucmp.clone();
-        let seq = self.sequence;
+        // The merge operands of the current key from the oldest to the newest
+        let mut operands = vec![];
+        // Whether `saved_value` holds the value the operands are merged into
+        let mut has_base = false;
         if self.inner.valid() {
             loop {
                 let saved_key = self.saved_key.clone();
                 if let Some(pkey) = self.parse_key().parsed() {
-                    if pkey.seq <= seq {
-                        if value_type == ValueType::Value
-                            && ucmp.compare(pkey.user_key, saved_key.as_slice()) == Ordering::Less
+                    if self.is_visible(&pkey) {
+                        if (value_type == ValueType::Value || value_type == ValueType::Merge)
+                            && ucmp.compare_without_timestamp(pkey.user_key, saved_key.as_slice())
+                                == Ordering::Less
                         {
                             // found the key that less than
                             break;
                         }
-                        value_type = pkey.value_type;
+                        value_type = self.value_type_of(&pkey);
                         match value_type {
                             ValueType::Deletion => {
                                 self.saved_key.clear();
                                 self.saved_value.clear();
+                                operands.clear();
+                                has_base = false;
                             }
                             ValueType::Value => {
                                 // record the current key for later comparing
                                 self.saved_key = Vec::from(extract_user_key(self.inner.key()));
                                 // record the current value for later yielding
                                 self.saved_value = self.inner.value().to_vec();
+                                operands.clear();
+                                has_base = true;
+                            }
+                            ValueType::Merge => {
+                                // The operand is newer than the entries of the key seen before
+                                self.saved_key = Vec::from(extract_user_key(self.inner.key()));
+                                operands.push(self.inner.value().to_vec());
                             }
                             _ => { /* ignore the unknown value type */ }
                         }
@@ -286,16 +491,185 @@ impl<I: Iterator, S: Storage + Clone, C: Comparator + 'static> DBIterator<I, S,
                 }
             }
         }
-        if value_type != ValueType::Value {
+        if value_type != ValueType::Value && value_type != ValueType::Merge {
             // We reach the end of inner iter but didn't find a valid user key
             self.valid = false;
             self.saved_key.clear();
             self.saved_value.clear();
             self.direction = Direction::Forward;
+        } else if !operands.is_empty() {
+            operands.reverse();
+            let base = if has_base {
+                Some(self.saved_value.as_slice())
+            } else {
+                None
+            };
+            match self.db.options.full_merge(&self.saved_key, base, &operands) {
+                Ok(value) => {
+                    self.saved_value = value;
+                    self.valid = true;
+                }
+                Err(e) => {
+                    self.err = Some(e);
+                    self.valid = false;
+                    self.saved_key.clear();
+                    self.saved_value.clear();
+                    self.direction = Direction::Forward;
+                }
+            }
         } else {
             self.valid = true;
         }
     }
+
+    // The inner iter is pointing at the newest visible merge operand of a user key.
+    // Collect all the operands of the key until reaching a value or a deletion and
+    // merge them.
+    fn merge_forward(&mut self) {
+        let key = extract_user_key(self.inner.key()).to_vec();
+        // from the newest to the oldest
+        let mut operands = vec![self.inner.value().to_vec()];
+        let mut base = None;
+        self.inner.next();
+        while self.inner.valid() {
+            match self.parse_key().parsed() {
+                Some(pkey)
+                    if self.ucmp.compare_without_timestamp(pkey.user_key, &key)
+                        == Ordering::Equal =>
+                {
+                    if !self.is_visible(&pkey) {
+                        // A version newer than the timestamp
+                        self.inner.next();
+                        continue;
+                    }
+                    match self.value_type_of(&pkey) {
+                        ValueType::Merge => operands.push(self.inner.value().to_vec()),
+                        ValueType::Value => {
+                            base = Some(self.inner.value().to_vec());
+                            break;
+                        }
+                        ValueType::Deletion => break,
+                        _ => { /* ignore the unknown value type */ }
+                    }
+                }
+                _ => break,
+            }
+            self.inner.next();
+        }
+        self.saved_key.clear();
+        match self.db.options.full_merge(&key, base.as_deref(), &operands) {
+            Ok(value) => {
+                self.merged = Some((key, value));
+                self.valid = true;
+            }
+            Err(e) => {
+                self.err = Some(e);
+                self.valid = false;
+            }
+        }
+    }
+}
+
+/// `RawIterator` yields every internal entry visible at its sequence, including
+/// the deletions, the merge operands and the versions shadowed by newer ones,
+/// which are combined by `DBIterator`. The keys are the user keys ordered by the
+/// user comparator, and the versions of a user key are ordered from the newest
+/// to the oldest. The seeks take user keys. The range tombstones are not
+/// yielded. Created by `WickDB::raw_iter`.
+pub struct RawIterator<I: Iterator> {
+    inner: I,
+    // The entries newer than it are skipped
+    sequence: u64,
+}
+
+impl<I: Iterator> RawIterator<I> {
+    pub(crate) fn new(inner: I, sequence: u64) -> Self {
+        Self { inner, sequence }
+    }
+
+    /// Returns the internal key of the current entry
+    pub fn internal_key(&self) -> &[u8] {
+        self.inner.key()
+    }
+
+    /// Returns the parsed internal key of the current entry, or `None` if the
+    /// internal key is corrupted
+    pub fn parsed_key(&self) -> Option<ParsedInternalKey<'_>> {
+        ParsedInternalKey::decode_from(self.inner.key())
+    }
+
+    /// Returns the sequence number of the current entry
+    pub fn sequence(&self) -> u64 {
+        self.parsed_key().map_or(0, |k| k.seq)
+    }
+
+    /// Returns the type of the current entry
+    pub fn value_type(&self) -> ValueType {
+        self.parsed_key()
+            .map_or(ValueType::Unknown, |k| k.value_type)
+    }
+
+    // The corrupted keys are yielded as they are
+    fn is_visible(&self) -> bool {
+        self.parsed_key().is_none_or(|k| k.seq <= self.sequence)
+    }
+
+    fn skip_forward(&mut self) {
+        while self.inner.valid() && !self.is_visible() {
+            self.inner.next()
+        }
+    }
+
+    fn skip_backward(&mut self) {
+        while self.inner.valid() && !self.is_visible() {
+            self.inner.prev()
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for RawIterator<I> {
+    fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.inner.seek_to_first();
+        self.skip_forward()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.inner.seek_to_last();
+        self.skip_backward()
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.inner
+            .seek(InternalKey::new(target, self.sequence, VALUE_TYPE_FOR_SEEK).data());
+        self.skip_forward()
+    }
+
+    fn next(&mut self) {
+        self.inner.next();
+        self.skip_forward()
+    }
+
+    fn prev(&mut self) {
+        self.inner.prev();
+        self.skip_backward()
+    }
+
+    // The whole internal key is returned if it's corrupted
+    fn key(&self) -> &[u8] {
+        self.parsed_key().map_or(self.inner.key(), |k| k.user_key)
+    }
+
+    fn value(&self) -> &[u8] {
+        self.inner.value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.inner.status()
+    }
 }
 
 // Picks the number of bytes that can be read until a compaction is scheduled
@@ -400,18 +774,15 @@ impl<C: Comparator, M: Iterator, T: Iterator> KMergeCore for DBIteratorCore<C, M
     where
         F: FnMut(&mut dyn Iterator, &Self::Cmp),
     {
-        if n < self.mem_iters.len() {
-            for (i, child) in self.mem_iters.iter_mut().enumerate() {
-                if i != n {
-                    f(child as &mut dyn Iterator, &self.cmp)
-                }
+        let mem_len = self.mem_iters.len();
+        for (i, child) in self.mem_iters.iter_mut().enumerate() {
+            if i != n {
+                f(child as &mut dyn Iterator, &self.cmp)
             }
-        } else {
-            let current = n - self.mem_iters.len();
-            for (i, child) in self.table_iters.iter_mut().enumerate() {
-                if i != current {
-                    f(child as &mut dyn Iterator, &self.cmp)
-                }
+        }
+        for (i, child) in self.table_iters.iter_mut().enumerate() {
+            if i + mem_len != n {
+                f(child as &mut dyn Iterator, &self.cmp)
             }
         }
     }
@@ -0,0 +1,14 @@
+use std::path::PathBuf;
+
+/// `OrphanFilesReport` describes the result of a scan for the `.sst`, `.dbtmp`
+/// and `.log` files that are no longer referenced by the db.
+#[derive(Debug, Clone, Default)]
+pub struct OrphanFilesReport {
+    /// Whether the scan is a dry run. Nothing is deleted in a dry run.
+    pub dry_run: bool,
+    /// The orphaned files that have outlived the grace period. They are deleted
+    /// unless it's a dry run.
+    pub deleted: Vec<PathBuf>,
+    /// The orphaned files that are still in the grace period
+    pub in_grace_period: Vec<PathBuf>,
+}
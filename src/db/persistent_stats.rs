@@ -0,0 +1,277 @@
+use crate::compaction::CompactionStats;
+use crate::db::write_stall::WriteStallCondition;
+use crate::util::coding::{decode_fixed_64, put_fixed_64};
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The name of the column family reserved for the statistics snapshots persisted
+/// when `Options::stats_persist_period` is set. It's hidden from
+/// `WickDB::column_families` and can't be created by the users.
+pub const PERSISTENT_STATS_CF: &str = "__wickdb_persistent_stats";
+
+// The number of the counters encoded in a snapshot
+const ENCODED_COUNTERS: usize = 11;
+// The number of the counters encoded by the first version, which lacks
+// `background_panics` and `trivial_moves`
+const MIN_ENCODED_COUNTERS: usize = 9;
+
+/// `DBStats` counts the background work and the write stalls of a db since it
+/// was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DBStats {
+    /// The memtable flushes
+    pub flushes: u64,
+    /// The bytes of the tables written by the flushes
+    pub flush_bytes_written: u64,
+    /// The compactions rewriting their inputs. The trivial moves and the files
+    /// dropped by the FIFO compactions are not counted.
+    pub compactions: u64,
+    /// The bytes of the input tables read by the compactions
+    pub compaction_bytes_read: u64,
+    /// The bytes of the tables written by the compactions
+    pub compaction_bytes_written: u64,
+    /// The microseconds spent by the compactions
+    pub compaction_micros: u64,
+    /// The times the writes became delayed
+    pub write_delays: u64,
+    /// The times the writes became stopped
+    pub write_stops: u64,
+    /// The microseconds the writes were delayed or stopped
+    pub write_stall_micros: u64,
+    /// The flush and compaction jobs that panicked
+    pub background_panics: u64,
+    /// The tables moved to the output level of a compaction by editing the
+    /// manifest instead of being rewritten
+    pub trivial_moves: u64,
+}
+
+impl DBStats {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut dst = Vec::with_capacity(ENCODED_COUNTERS * 8);
+        for n in [
+            self.flushes,
+            self.flush_bytes_written,
+            self.compactions,
+            self.compaction_bytes_read,
+            self.compaction_bytes_written,
+            self.compaction_micros,
+            self.write_delays,
+            self.write_stops,
+            self.write_stall_micros,
+            self.background_panics,
+            self.trivial_moves,
+        ] {
+            put_fixed_64(&mut dst, n);
+        }
+        dst
+    }
+
+    // The counters appended by the later versions are ignored, and the ones
+    // missing in the earlier versions are zero
+    pub(crate) fn decode(src: &[u8]) -> Result<Self> {
+        if src.len() < MIN_ENCODED_COUNTERS * 8 {
+            return Err(Error::Corruption(format!(
+                "persistent stats of {} bytes is too short",
+                src.len()
+            )));
+        }
+        let n = |i: usize| {
+            if src.len() < (i + 1) * 8 {
+                0
+            } else {
+                decode_fixed_64(&src[i * 8..])
+            }
+        };
+        Ok(Self {
+            flushes: n(0),
+            flush_bytes_written: n(1),
+            compactions: n(2),
+            compaction_bytes_read: n(3),
+            compaction_bytes_written: n(4),
+            compaction_micros: n(5),
+            write_delays: n(6),
+            write_stops: n(7),
+            write_stall_micros: n(8),
+            background_panics: n(9),
+            trivial_moves: n(10),
+        })
+    }
+}
+
+/// A `DBStats` persisted at `time`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// The seconds since the unix epoch
+    pub time: u64,
+    /// The counters since the db was opened at `time`, so the counters of the
+    /// snapshots taken before and after a restart are not comparable
+    pub stats: DBStats,
+}
+
+/// Returns the key of the snapshot taken at `time` in `PERSISTENT_STATS_CF`,
+/// which sorts in the order of the time. A snapshot replaces the one taken
+/// earlier in the same second.
+pub(crate) fn stats_key(time: u64) -> [u8; 8] {
+    time.to_be_bytes()
+}
+
+/// Returns the time of a key created by `stats_key`
+pub(crate) fn decode_stats_key(key: &[u8]) -> Result<u64> {
+    if key.len() != 8 {
+        return Err(Error::Corruption(format!(
+            "invalid persistent stats key {:?}",
+            key
+        )));
+    }
+    let mut time = [0; 8];
+    time.copy_from_slice(key);
+    Ok(u64::from_be_bytes(time))
+}
+
+/// The counters behind `DBStats`
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    flushes: AtomicU64,
+    flush_bytes_written: AtomicU64,
+    compactions: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    compaction_micros: AtomicU64,
+    write_delays: AtomicU64,
+    write_stops: AtomicU64,
+    write_stall_micros: AtomicU64,
+    background_panics: AtomicU64,
+    trivial_moves: AtomicU64,
+    // When the current write stall began
+    stall_started: Mutex<Option<Instant>>,
+}
+
+impl StatsCounters {
+    pub fn record_flush(&self, stats: &CompactionStats) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flush_bytes_written
+            .fetch_add(stats.bytes_written, Ordering::Relaxed);
+    }
+
+    pub fn record_compaction(&self, stats: &CompactionStats) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(stats.bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(stats.bytes_written, Ordering::Relaxed);
+        self.compaction_micros
+            .fetch_add(stats.micros, Ordering::Relaxed);
+    }
+
+    pub fn record_background_panic(&self) {
+        self.background_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_trivial_move(&self, files: usize) {
+        self.trivial_moves.fetch_add(files as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_write_stall(&self, prev: WriteStallCondition, current: WriteStallCondition) {
+        match current {
+            WriteStallCondition::Delayed { .. } => {
+                self.write_delays.fetch_add(1, Ordering::Relaxed);
+            }
+            WriteStallCondition::Stopped { .. } => {
+                self.write_stops.fetch_add(1, Ordering::Relaxed);
+            }
+            WriteStallCondition::Normal => {}
+        }
+        let mut started = self.stall_started.lock().unwrap();
+        if !prev.is_stalled() && current.is_stalled() {
+            *started = Some(Instant::now());
+        } else if !current.is_stalled() {
+            if let Some(start) = started.take() {
+                self.write_stall_micros
+                    .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> DBStats {
+        // The ongoing stall is counted too
+        let ongoing = self
+            .stall_started
+            .lock()
+            .unwrap()
+            .map_or(0, |start| start.elapsed().as_micros() as u64);
+        DBStats {
+            flushes: self.flushes.load(Ordering::Relaxed),
+            flush_bytes_written: self.flush_bytes_written.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compaction_bytes_read: self.compaction_bytes_read.load(Ordering::Relaxed),
+            compaction_bytes_written: self.compaction_bytes_written.load(Ordering::Relaxed),
+            compaction_micros: self.compaction_micros.load(Ordering::Relaxed),
+            write_delays: self.write_delays.load(Ordering::Relaxed),
+            write_stops: self.write_stops.load(Ordering::Relaxed),
+            write_stall_micros: self.write_stall_micros.load(Ordering::Relaxed) + ongoing,
+            background_panics: self.background_panics.load(Ordering::Relaxed),
+            trivial_moves: self.trivial_moves.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::write_stall::WriteStallReason;
+
+    #[test]
+    fn test_stats_counters() {
+        let counters = StatsCounters::default();
+        counters.record_flush(&CompactionStats {
+            micros: 10,
+            bytes_read: 0,
+            bytes_written: 100,
+        });
+        counters.record_compaction(&CompactionStats {
+            micros: 20,
+            bytes_read: 300,
+            bytes_written: 200,
+        });
+        let delayed = WriteStallCondition::Delayed {
+            micros: 1000,
+            reason: WriteStallReason::Level0Slowdown,
+        };
+        let stopped = WriteStallCondition::Stopped {
+            reason: WriteStallReason::Level0Stop,
+        };
+        counters.record_write_stall(WriteStallCondition::Normal, delayed);
+        counters.record_write_stall(delayed, stopped);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        counters.record_write_stall(stopped, WriteStallCondition::Normal);
+        let stats = counters.stats();
+        assert_eq!(stats.flushes, 1);
+        assert_eq!(stats.flush_bytes_written, 100);
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.compaction_bytes_read, 300);
+        assert_eq!(stats.compaction_bytes_written, 200);
+        assert_eq!(stats.compaction_micros, 20);
+        assert_eq!((stats.write_delays, stats.write_stops), (1, 1));
+        assert!(stats.write_stall_micros >= 2000);
+        assert_eq!(
+            counters.stats().write_stall_micros,
+            stats.write_stall_micros
+        );
+
+        counters.record_background_panic();
+        counters.record_trivial_move(2);
+        let stats = counters.stats();
+        assert_eq!(stats.background_panics, 1);
+        assert_eq!(stats.trivial_moves, 2);
+
+        let decoded = DBStats::decode(&stats.encode()).unwrap();
+        assert_eq!(decoded, stats);
+        // A snapshot of the first version has no `background_panics` and `trivial_moves`
+        let decoded = DBStats::decode(&stats.encode()[..MIN_ENCODED_COUNTERS * 8]).unwrap();
+        assert_eq!((decoded.background_panics, decoded.trivial_moves), (0, 0));
+        assert_eq!(decoded.write_stall_micros, stats.write_stall_micros);
+        assert!(DBStats::decode(&stats.encode()[..MIN_ENCODED_COUNTERS * 8 - 1]).is_err());
+    }
+}
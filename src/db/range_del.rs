@@ -0,0 +1,371 @@
+use crate::db::format::{InternalKey, InternalKeyComparator, ValueType, MAX_KEY_SEQUENCE};
+use crate::util::coding::{decode_fixed_64, put_fixed_64};
+use crate::util::comparator::Comparator;
+use crate::util::varint::VarintU32;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+
+/// A `RangeTombstone` deletes all the user keys in `[begin, end)` whose
+/// sequence numbers are less than `seq`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub begin: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    pub fn new(begin: &[u8], end: &[u8], seq: u64) -> Self {
+        Self {
+            begin: begin.to_vec(),
+            end: end.to_vec(),
+            seq,
+        }
+    }
+
+    /// Returns true if the tombstone covers `user_key`
+    #[inline]
+    pub fn contains<C: Comparator>(&self, ucmp: &C, user_key: &[u8]) -> bool {
+        ucmp.compare(user_key, &self.begin) != Ordering::Less
+            && ucmp.compare(user_key, &self.end) == Ordering::Less
+    }
+
+    /// Returns true if the tombstone covers no key
+    #[inline]
+    pub fn is_empty<C: Comparator>(&self, ucmp: &C) -> bool {
+        ucmp.compare(&self.begin, &self.end) != Ordering::Less
+    }
+
+    /// The approximate memory used by the tombstone
+    #[inline]
+    pub fn approximate_size(&self) -> usize {
+        self.begin.len() + self.end.len() + 8
+    }
+}
+
+/// Encodes the tombstones into the contents of a range deletion block
+///
+/// ```text
+///   +--------------------+------------+-------------------+----------+---------+
+///   | begin len (varint) | begin data | end len (varint)  | end data | seq (8) |  ...
+///   +--------------------+------------+-------------------+----------+---------+
+/// ```
+pub fn encode_range_tombstones(tombstones: &[RangeTombstone]) -> Vec<u8> {
+    let mut dst = vec![];
+    for t in tombstones {
+        VarintU32::put_varint(&mut dst, t.begin.len() as u32);
+        dst.extend_from_slice(&t.begin);
+        VarintU32::put_varint(&mut dst, t.end.len() as u32);
+        dst.extend_from_slice(&t.end);
+        put_fixed_64(&mut dst, t.seq);
+    }
+    dst
+}
+
+/// Decodes the contents of a range deletion block
+pub fn decode_range_tombstones(mut src: &[u8]) -> Result<Vec<RangeTombstone>> {
+    let mut tombstones = vec![];
+    while !src.is_empty() {
+        let begin = VarintU32::get_varint_prefixed_slice(&mut src);
+        let end = VarintU32::get_varint_prefixed_slice(&mut src);
+        match (begin, end) {
+            (Some(begin), Some(end)) if src.len() >= 8 => {
+                tombstones.push(RangeTombstone::new(begin, end, decode_fixed_64(src)));
+                src = &src[8..];
+            }
+            _ => {
+                return Err(Error::Corruption(
+                    "bad entry in range deletion block".to_owned(),
+                ))
+            }
+        }
+    }
+    Ok(tombstones)
+}
+
+/// Extends the key range `[smallest, largest]` of a table to cover its tombstones.
+/// An empty `InternalKey` means the bound is not set yet.
+///
+/// A tombstone spans from `(begin, MAX_KEY_SEQUENCE - 1)` to
+/// `(end, MAX_KEY_SEQUENCE)`, which sort before all the entries of `begin` and
+/// `end`. The end bound also sorts before the start bound of a tombstone
+/// beginning at `end`, so the tables split at `end` in a level don't overlap.
+pub fn extend_key_range<C: Comparator>(
+    icmp: &InternalKeyComparator<C>,
+    tombstones: &[RangeTombstone],
+    smallest: &mut InternalKey,
+    largest: &mut InternalKey,
+) {
+    for t in tombstones {
+        let begin = InternalKey::new(&t.begin, MAX_KEY_SEQUENCE - 1, ValueType::Deletion);
+        if smallest.is_empty() || icmp.compare(begin.data(), smallest.data()) == Ordering::Less {
+            *smallest = begin;
+        }
+        let end = InternalKey::new(&t.end, MAX_KEY_SEQUENCE, ValueType::RangeDeletion);
+        if largest.is_empty() || icmp.compare(end.data(), largest.data()) == Ordering::Greater {
+            *largest = end;
+        }
+    }
+}
+
+/// Returns the parts of the tombstones within `[lower, upper)`. `None` means unbounded.
+pub fn truncate_range_tombstones<C: Comparator>(
+    ucmp: &C,
+    tombstones: &[RangeTombstone],
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+) -> Vec<RangeTombstone> {
+    tombstones
+        .iter()
+        .filter_map(|t| {
+            let begin = match lower {
+                Some(l) if ucmp.compare(l, &t.begin) == Ordering::Greater => l,
+                _ => t.begin.as_slice(),
+            };
+            let end = match upper {
+                Some(u) if ucmp.compare(u, &t.end) == Ordering::Less => u,
+                _ => t.end.as_slice(),
+            };
+            let part = RangeTombstone::new(begin, end, t.seq);
+            if part.is_empty(ucmp) {
+                None
+            } else {
+                Some(part)
+            }
+        })
+        .collect()
+}
+
+/// Returns the max sequence number of the tombstones covering `user_key` which
+/// are visible to `snapshot`, or 0 if there is no such tombstone.
+pub fn max_covering_seq<'a, C: Comparator>(
+    ucmp: &C,
+    tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    user_key: &[u8],
+    snapshot: u64,
+) -> u64 {
+    tombstones
+        .into_iter()
+        .filter(|t| t.seq <= snapshot && t.contains(ucmp, user_key))
+        .map(|t| t.seq)
+        .max()
+        .unwrap_or(0)
+}
+
+// A piece of the key space `[begin, end)` covered by the same set of tombstones
+struct Fragment {
+    begin: Vec<u8>,
+    end: Vec<u8>,
+    // in decreasing order
+    seqs: Vec<u64>,
+}
+
+/// `FragmentedRangeTombstones` splits a set of possibly overlapping tombstones
+/// into non-overlapping fragments sorted by the user key, so the tombstones
+/// covering a key are found by a binary search.
+///
+/// It's the overlay applied by the iterators and compactions to hide the
+/// deleted entries.
+pub struct FragmentedRangeTombstones<C: Comparator> {
+    ucmp: C,
+    fragments: Vec<Fragment>,
+}
+
+impl<C: Comparator> FragmentedRangeTombstones<C> {
+    pub fn new<'a>(ucmp: C, tombstones: impl IntoIterator<Item = &'a RangeTombstone>) -> Self {
+        let tombstones: Vec<&RangeTombstone> = tombstones
+            .into_iter()
+            .filter(|t| !t.is_empty(&ucmp))
+            .collect();
+        let mut points: Vec<&[u8]> = tombstones
+            .iter()
+            .flat_map(|t| vec![t.begin.as_slice(), t.end.as_slice()])
+            .collect();
+        points.sort_by(|a, b| ucmp.compare(a, b));
+        points.dedup_by(|a, b| ucmp.compare(a, b) == Ordering::Equal);
+        let mut fragments = vec![];
+        for w in points.windows(2) {
+            let (begin, end) = (w[0], w[1]);
+            let mut seqs: Vec<u64> = tombstones
+                .iter()
+                .filter(|t| {
+                    ucmp.compare(&t.begin, begin) != Ordering::Greater
+                        && ucmp.compare(end, &t.end) != Ordering::Greater
+                })
+                .map(|t| t.seq)
+                .collect();
+            if !seqs.is_empty() {
+                seqs.sort_unstable_by(|a, b| b.cmp(a));
+                fragments.push(Fragment {
+                    begin: begin.to_vec(),
+                    end: end.to_vec(),
+                    seqs,
+                });
+            }
+        }
+        Self { ucmp, fragments }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Returns the max sequence number of the tombstones covering `user_key` which
+    /// are visible to `snapshot`, or 0 if there is no such tombstone.
+    pub fn max_covering_seq(&self, user_key: &[u8], snapshot: u64) -> u64 {
+        // the first fragment whose end is greater than the key
+        let i = self
+            .fragments
+            .partition_point(|f| self.ucmp.compare(&f.end, user_key) != Ordering::Greater);
+        match self.fragments.get(i) {
+            Some(f) if self.ucmp.compare(&f.begin, user_key) != Ordering::Greater => f
+                .seqs
+                .iter()
+                .find(|s| **s <= snapshot)
+                .copied()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Returns true if the entry of `user_key` at `seq` is deleted by a tombstone
+    /// visible to `snapshot`
+    #[inline]
+    pub fn should_delete(&self, user_key: &[u8], seq: u64, snapshot: u64) -> bool {
+        self.max_covering_seq(user_key, snapshot) > seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BytewiseComparator;
+
+    #[test]
+    fn test_encode_decode_range_tombstones() {
+        let tombstones = vec![
+            RangeTombstone::new(b"a", b"c", 3),
+            RangeTombstone::new(b"", b"zz", 100),
+        ];
+        let data = encode_range_tombstones(&tombstones);
+        assert_eq!(decode_range_tombstones(&data).unwrap(), tombstones);
+        assert!(decode_range_tombstones(&data[..data.len() - 1]).is_err());
+        assert!(decode_range_tombstones(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_truncate_range_tombstones() {
+        let ucmp = BytewiseComparator::default();
+        let tombstones = vec![
+            RangeTombstone::new(b"b", b"f", 10),
+            RangeTombstone::new(b"d", b"h", 20),
+        ];
+        assert_eq!(
+            truncate_range_tombstones(&ucmp, &tombstones, None, None),
+            tombstones
+        );
+        assert_eq!(
+            truncate_range_tombstones(&ucmp, &tombstones, Some(b"c"), Some(b"e")),
+            vec![
+                RangeTombstone::new(b"c", b"e", 10),
+                RangeTombstone::new(b"d", b"e", 20),
+            ]
+        );
+        assert_eq!(
+            truncate_range_tombstones(&ucmp, &tombstones, Some(b"f"), None),
+            vec![RangeTombstone::new(b"f", b"h", 20)]
+        );
+        assert!(truncate_range_tombstones(&ucmp, &tombstones, None, Some(b"b")).is_empty());
+    }
+
+    #[test]
+    fn test_extend_key_range() {
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let (mut smallest, mut largest) = (InternalKey::default(), InternalKey::default());
+        extend_key_range(
+            &icmp,
+            &[RangeTombstone::new(b"c", b"e", 10)],
+            &mut smallest,
+            &mut largest,
+        );
+        assert_eq!(smallest.user_key(), b"c");
+        assert_eq!(largest.user_key(), b"e");
+        let mut smallest = InternalKey::new(b"a", 1, ValueType::Value);
+        let mut largest = InternalKey::new(b"e", 1, ValueType::Value);
+        extend_key_range(
+            &icmp,
+            &[RangeTombstone::new(b"c", b"e", 10)],
+            &mut smallest,
+            &mut largest,
+        );
+        // The entries of `e` are not covered by the tombstone
+        assert_eq!(largest, InternalKey::new(b"e", 1, ValueType::Value));
+        assert_eq!(smallest, InternalKey::new(b"a", 1, ValueType::Value));
+        // The tables split at `e` don't overlap
+        let (mut next_smallest, mut next_largest) =
+            (InternalKey::default(), InternalKey::default());
+        extend_key_range(
+            &icmp,
+            &[RangeTombstone::new(b"e", b"g", 10)],
+            &mut next_smallest,
+            &mut next_largest,
+        );
+        let mut prev_largest = InternalKey::default();
+        extend_key_range(
+            &icmp,
+            &[RangeTombstone::new(b"c", b"e", 10)],
+            &mut InternalKey::default(),
+            &mut prev_largest,
+        );
+        assert_eq!(
+            icmp.compare(prev_largest.data(), next_smallest.data()),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_fragmented_range_tombstones() {
+        let tombstones = [
+            RangeTombstone::new(b"b", b"f", 10),
+            RangeTombstone::new(b"d", b"h", 20),
+            RangeTombstone::new(b"m", b"n", 5),
+            // empty range
+            RangeTombstone::new(b"x", b"x", 30),
+        ];
+        let ucmp = BytewiseComparator::default();
+        let frags = FragmentedRangeTombstones::new(ucmp, tombstones.iter());
+        let cases: Vec<(&[u8], u64, u64)> = vec![
+            (b"a", 100, 0),
+            (b"b", 100, 10),
+            (b"c", 9, 0),
+            (b"d", 100, 20),
+            (b"e", 15, 10),
+            (b"f", 100, 20),
+            (b"h", 100, 0),
+            (b"j", 100, 0),
+            (b"m", 100, 5),
+            (b"ma", 4, 0),
+            (b"n", 100, 0),
+            (b"x", 100, 0),
+        ];
+        for (key, snapshot, expect) in cases {
+            assert_eq!(
+                frags.max_covering_seq(key, snapshot),
+                expect,
+                "key {:?} at {}",
+                key,
+                snapshot
+            );
+            assert_eq!(
+                max_covering_seq(&ucmp, tombstones.iter(), key, snapshot),
+                expect
+            );
+        }
+        assert!(frags.should_delete(b"e", 11, 100));
+        assert!(!frags.should_delete(b"e", 21, 100));
+        assert!(!frags.should_delete(b"e", 11, 19));
+        assert!(frags.should_delete(b"e", 9, 19));
+        assert!(FragmentedRangeTombstones::new(ucmp, [].iter()).is_empty());
+    }
+}
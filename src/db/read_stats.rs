@@ -0,0 +1,146 @@
+use crate::db::warmup::HotBlocks;
+use crate::sstable::BlockHandle;
+use crate::util::histogram::{Histogram, HistogramData};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// `ReadStats` counts which source served the point lookups of a db.
+///
+/// A lookup is served by the newest source holding an entry for the key
+/// visible to its snapshot, whether the entry is a value or a deletion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReadStats {
+    /// Served by the memtable or the immutable memtable
+    pub memtable_hits: u64,
+    /// Served by a level 0 table
+    pub level0_hits: u64,
+    /// Served by a table in level 1 or a deeper level
+    pub leveln_hits: u64,
+    /// No source holds the key
+    pub misses: u64,
+    /// The blocks read by the lookups that were found in the block cache
+    pub block_cache_hits: u64,
+    /// The blocks read by the lookups from the storage
+    pub block_cache_misses: u64,
+}
+
+/// The source a `get` is served from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReadSource {
+    Memtable,
+    Level(usize),
+    Miss,
+}
+
+/// `LevelLatencyStats` is the latency distribution in nanoseconds of the
+/// iterator operations on the tables of a level, recorded when
+/// `Options::enable_iter_latency_stats` is true.
+///
+/// A growing latency of level 0 usually means that too many level 0 tables
+/// are merged by the scans, while the latency of the bottom level is
+/// dominated by the reads of the storage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LevelLatencyStats {
+    /// `seek`, `seek_to_first` and `seek_to_last`
+    pub seek: HistogramData,
+    /// `next` and `prev`
+    pub next: HistogramData,
+}
+
+/// The iterator operations timed per level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IterOp {
+    Seek,
+    Next,
+}
+
+/// The lock-free counters behind `ReadStats`
+pub struct ReadCounters {
+    memtable_hits: AtomicU64,
+    level0_hits: AtomicU64,
+    leveln_hits: AtomicU64,
+    misses: AtomicU64,
+    block_cache_hits: AtomicU64,
+    block_cache_misses: AtomicU64,
+    // The histograms of (seek, next) per level
+    iter_latencies: Vec<(Histogram, Histogram)>,
+    // Samples the blocks read if `Options::hot_blocks_to_persist` is not 0
+    hot_blocks: Option<HotBlocks>,
+}
+
+impl ReadCounters {
+    pub(crate) fn new(max_levels: usize, hot_blocks_to_persist: usize) -> Self {
+        Self {
+            memtable_hits: AtomicU64::new(0),
+            level0_hits: AtomicU64::new(0),
+            leveln_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            block_cache_hits: AtomicU64::new(0),
+            block_cache_misses: AtomicU64::new(0),
+            iter_latencies: (0..max_levels).map(|_| Default::default()).collect(),
+            hot_blocks: if hot_blocks_to_persist > 0 {
+                Some(HotBlocks::new(hot_blocks_to_persist))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub(crate) fn record(&self, source: ReadSource) {
+        let counter = match source {
+            ReadSource::Memtable => &self.memtable_hits,
+            ReadSource::Level(0) => &self.level0_hits,
+            ReadSource::Level(_) => &self.leveln_hits,
+            ReadSource::Miss => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_block_read(&self, cached: bool) {
+        let counter = if cached {
+            &self.block_cache_hits
+        } else {
+            &self.block_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sample_block(&self, file_number: u64, handle: &BlockHandle) {
+        if let Some(hot_blocks) = &self.hot_blocks {
+            hot_blocks.record(file_number, handle);
+        }
+    }
+
+    pub(crate) fn hot_blocks(&self) -> Option<&HotBlocks> {
+        self.hot_blocks.as_ref()
+    }
+
+    pub(crate) fn stats(&self) -> ReadStats {
+        ReadStats {
+            memtable_hits: self.memtable_hits.load(Ordering::Relaxed),
+            level0_hits: self.level0_hits.load(Ordering::Relaxed),
+            leveln_hits: self.leveln_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            block_cache_hits: self.block_cache_hits.load(Ordering::Relaxed),
+            block_cache_misses: self.block_cache_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record_iter_latency(&self, level: usize, op: IterOp, nanos: u64) {
+        if let Some((seek, next)) = self.iter_latencies.get(level) {
+            match op {
+                IterOp::Seek => seek.record(nanos),
+                IterOp::Next => next.record(nanos),
+            }
+        }
+    }
+
+    pub(crate) fn iter_latency_stats(&self) -> Vec<LevelLatencyStats> {
+        self.iter_latencies
+            .iter()
+            .map(|(seek, next)| LevelLatencyStats {
+                seek: seek.data(),
+                next: next.data(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::Duration;
+
+/// `RecoveryReport` describes what has been done to recover the db when it's opened.
+/// It helps to find out whether the recovery was lossy.
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+    /// The MANIFEST file the versions are recovered from
+    pub manifest: String,
+    /// Whether `manifest` is not the one pointed by `CURRENT` because either
+    /// `CURRENT` or the MANIFEST it points to is invalid
+    pub manifest_fallback: bool,
+    /// The number of `VersionEdit`s applied from `manifest`
+    pub manifest_records_applied: u64,
+    /// The number of WAL files replayed
+    pub wal_files_replayed: usize,
+    /// The number of WAL records (`WriteBatch`es) replayed
+    pub wal_records_replayed: u64,
+    /// The number of bytes in the WAL files that are not replayed
+    pub wal_bytes_skipped: u64,
+    /// Whether any WAL file ends with an incomplete record, which is usually
+    /// left by a crash during writing
+    pub corrupted_tail: bool,
+    /// The number of partial compaction outputs left by an unfinished compaction
+    /// and removed during the recovery
+    pub orphaned_outputs_removed: usize,
+    /// The time spent on the whole recovery
+    pub duration: Duration,
+}
+
+impl RecoveryReport {
+    /// Returns true if some data may be lost during the recovery
+    pub fn is_lossy(&self) -> bool {
+        self.manifest_fallback || self.wal_bytes_skipped > 0 || self.corrupted_tail
+    }
+}
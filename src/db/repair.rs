@@ -0,0 +1,467 @@
+use crate::batch::{WriteBatch, HEADER_SIZE};
+use crate::db::filename::{generate_filename, lost_dir, parse_filename, FileType};
+use crate::db::format::{InternalKeyComparator, ParsedInternalKey};
+use crate::db::{build_table, lock_db, new_memtable, vetoed_sequence};
+use crate::mem::arena::ArenaBlockPool;
+use crate::mem::MemTable;
+use crate::options::Options;
+use crate::record::reader::Reader;
+use crate::storage::{File, Storage};
+use crate::table_cache::TableCache;
+use crate::util::comparator::Comparator;
+use crate::util::reporter::LogReporter;
+use crate::version::manifest_builder::ManifestBuilder;
+use crate::version::version_edit::FileMetaData;
+use crate::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// `RepairReport` describes what has been done by `repair_db`
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// The number of the tables in the new MANIFEST
+    pub tables: usize,
+    /// The number of the WAL files converted into tables
+    pub logs_converted: usize,
+    /// The number of bytes in the WAL files dropped due to the corruptions
+    pub log_bytes_dropped: u64,
+    /// The number of the tables rewritten with the entries in their intact blocks
+    pub tables_salvaged: usize,
+    /// The number of the corrupted blocks dropped from the salvaged tables
+    pub blocks_dropped: usize,
+    /// The files moved into the `lost` directory
+    pub lost_files: Vec<PathBuf>,
+    /// The last sequence number of the repaired db
+    pub last_sequence: u64,
+}
+
+/// Rebuilds the MANIFEST of the db in `db_path` from the files in the directory,
+/// which makes the db openable again after its MANIFEST is lost or corrupted. It
+/// parallels LevelDB's `RepairDB`:
+///
+/// 1. Every WAL file is replayed into the memtables, which are written into
+///    new tables.
+/// 2. Every table is scanned with the checksums verified. A table with corrupted
+///    blocks is rewritten with the entries in the intact blocks, and a table
+///    whose footer or index can't be read is dropped.
+/// 3. A new MANIFEST with all the tables in level 0 is written. The tables are
+///    renumbered by their largest sequence numbers if needed, since the lookups
+///    visit the tables in level 0 by decreasing file numbers.
+///
+/// The WAL files, the old MANIFESTs and the corrupted tables are moved into the
+/// `lost` subdirectory instead of being deleted. The db must not be opened during
+/// the repair.
+///
+/// NOTE: Some data may be lost, and the deleted keys may reappear if the tables
+/// with their tombstones are dropped. The column families are only recorded in
+/// the MANIFEST, so they are lost as well.
+///
+/// # Error
+///
+/// Returns `Error::NotFound` if `db_path` doesn't exist, or `Error::Busy` if the
+/// db is opened.
+pub fn repair_db<S: Storage + Clone + 'static, C: Comparator + 'static>(
+    storage: S,
+    db_path: &str,
+    mut options: Options<C>,
+) -> Result<RepairReport> {
+    if !storage.exists(db_path) {
+        return Err(Error::NotFound(Some(format!(
+            "db {} does not exist",
+            db_path
+        ))));
+    }
+    options.initialize(db_path, &storage);
+    let lock_file = storage.create(generate_filename(db_path, FileType::Lock, 0))?;
+    lock_db(&lock_file, db_path)?;
+    let options = Arc::new(options);
+    let mut repairer = Repairer {
+        table_cache: TableCache::new(
+            db_path.to_owned(),
+            options.clone(),
+            options.table_cache_size(),
+            storage.clone(),
+        ),
+        storage,
+        db_path: db_path.to_owned(),
+        icmp: InternalKeyComparator::new(options.comparator.clone()),
+        options,
+        arena_blocks: Arc::new(ArenaBlockPool::default()),
+        next_file_number: 1,
+        report: RepairReport::default(),
+    };
+    let result = repairer.run();
+    lock_file.unlock()?;
+    result.map(|_| {
+        info!("Repair db {} finished: {:?}", db_path, &repairer.report);
+        repairer.report
+    })
+}
+
+struct Repairer<S: Storage + Clone, C: Comparator> {
+    storage: S,
+    db_path: String,
+    options: Arc<Options<C>>,
+    icmp: InternalKeyComparator<C>,
+    table_cache: TableCache<S, C>,
+    arena_blocks: Arc<ArenaBlockPool>,
+    next_file_number: u64,
+    report: RepairReport,
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> Repairer<S, C> {
+    fn run(&mut self) -> Result<()> {
+        let mut logs = vec![];
+        let mut table_numbers = vec![];
+        let mut manifests = vec![];
+        for path in self.storage.list(&self.db_path)? {
+            if let Some((file_type, number)) = parse_filename(&path) {
+                match file_type {
+                    FileType::Log => logs.push(number),
+                    FileType::Table => table_numbers.push(number),
+                    FileType::Manifest | FileType::ManifestBackup => manifests.push(path),
+                    _ => {}
+                }
+                self.next_file_number = self.next_file_number.max(number + 1);
+            }
+        }
+        self.storage.mkdir_all(lost_dir(&self.db_path))?;
+
+        logs.sort_unstable();
+        for number in logs {
+            let log = generate_filename(&self.db_path, FileType::Log, number);
+            match self.convert_log(number, &mut table_numbers) {
+                Ok(()) => self.report.logs_converted += 1,
+                Err(e) => warn!("Convert log #{} failed: {:?}", number, e),
+            }
+            self.move_to_lost(&log)?;
+        }
+
+        // The tables with their largest sequence numbers
+        let mut tables = vec![];
+        for number in table_numbers {
+            if let Some(max_sequence) = self.scan_table(number)? {
+                tables.push((number, max_sequence));
+            }
+        }
+        tables.sort_unstable_by_key(|(number, seq)| (*seq, *number));
+        if tables.windows(2).any(|w| w[0].0 > w[1].0) {
+            for (number, _) in tables.iter_mut() {
+                let new_number = self.next_file_number;
+                self.next_file_number += 1;
+                self.storage.rename(
+                    generate_filename(&self.db_path, FileType::Table, *number),
+                    generate_filename(&self.db_path, FileType::Table, new_number),
+                )?;
+                debug!("Renumber table #{} to #{}", number, new_number);
+                *number = new_number;
+            }
+        }
+
+        let mut builder =
+            ManifestBuilder::new(self.storage.clone(), &self.db_path, (*self.options).clone());
+        for (number, max_sequence) in tables.iter() {
+            builder.add_table(0, *number)?;
+            self.report.last_sequence = self.report.last_sequence.max(*max_sequence);
+        }
+        // All the WAL files are converted
+        builder.set_log_number(self.next_file_number);
+        builder.finish()?;
+        self.report.tables = tables.len();
+        for manifest in manifests {
+            self.move_to_lost(&manifest)?;
+        }
+        Ok(())
+    }
+
+    // Replays the log file into the memtables and writes them into new tables,
+    // whose numbers are appended to `tables`
+    fn convert_log(&mut self, log_number: u64, tables: &mut Vec<u64>) -> Result<()> {
+        let file_name = generate_filename(&self.db_path, FileType::Log, log_number);
+        let file = self.storage.open(&file_name)?;
+        // The corrupted records are dropped but the following ones are still read
+        let reporter = LogReporter::new();
+        let mut reader = Reader::new(file, Some(Box::new(reporter.clone())), true, 0);
+        let mut record = vec![];
+        let mut mem = self.new_memtable();
+        // A write group is replayed once the next record shows it's not vetoed
+        let mut pending: Option<WriteBatch> = None;
+        let mut replay = |repairer: &mut Self, mem: &mut MemTable<C>, batch: WriteBatch| {
+            if let Err(e) = batch.insert_into(mem) {
+                warn!("Ignore the corrupted batch in log #{}: {:?}", log_number, e);
+            }
+            if mem.approximate_memory_usage() > repairer.options.write_buffer_size {
+                let full = std::mem::replace(mem, repairer.new_memtable());
+                tables.extend(repairer.write_table(&full)?);
+            }
+            Ok::<(), Error>(())
+        };
+        while reader.read_record(&mut record) {
+            if record.len() < HEADER_SIZE {
+                warn!("Ignore the too small record in log #{}", log_number);
+                continue;
+            }
+            let mut batch = WriteBatch::default();
+            batch.set_contents(&mut record);
+            if let Some(seq) = vetoed_sequence(&batch) {
+                if pending.as_ref().is_some_and(|p| p.get_sequence() == seq) {
+                    pending = None;
+                }
+                continue;
+            }
+            if let Some(accepted) = pending.replace(batch) {
+                replay(self, &mut mem, accepted)?;
+            }
+        }
+        if let Some(batch) = pending {
+            let accepted = self
+                .options
+                .pre_commit_hook
+                .as_ref()
+                .is_none_or(|hook| hook.recover(batch.get_sequence(), &batch));
+            if accepted {
+                replay(self, &mut mem, batch)?;
+            }
+        }
+        self.report.log_bytes_dropped += reporter.dropped_bytes();
+        tables.extend(self.write_table(&mem)?);
+        info!("Log #{} is converted", log_number);
+        Ok(())
+    }
+
+    // Writes the memtable into a new table and returns its number, or `None` if
+    // the memtable is empty
+    fn write_table(&mut self, mem: &MemTable<C>) -> Result<Option<u64>> {
+        let mut meta = FileMetaData {
+            number: self.next_file_number,
+            ..Default::default()
+        };
+        self.next_file_number += 1;
+        build_table(
+            self.options.clone(),
+            &self.storage,
+            &self.db_path,
+            &self.table_cache,
+            &mut mem.iter(),
+            &mem.range_tombstones(),
+            &mut meta,
+        )?;
+        Ok(if meta.file_size > 0 {
+            Some(meta.number)
+        } else {
+            None
+        })
+    }
+
+    // Scans the table and salvages the entries in the intact blocks if some are
+    // corrupted. Returns the largest sequence number in the table, or `None` if
+    // the table is dropped.
+    fn scan_table(&mut self, number: u64) -> Result<Option<u64>> {
+        let file_name = generate_filename(&self.db_path, FileType::Table, number);
+        let file = self.storage.open(&file_name)?;
+        let file_size = file.len()?;
+        let table = match self.options.table_factory.open(
+            Box::new(file),
+            number,
+            file_size,
+            self.icmp.clone(),
+            &self.options,
+        ) {
+            Ok(table) => table,
+            Err(e) => {
+                warn!("Drop table #{} which can't be opened: {:?}", number, e);
+                self.move_to_lost(&file_name)?;
+                return Ok(None);
+            }
+        };
+        let mut max_sequence = 0;
+        let mut entries = 0;
+        let mut bad_keys = 0;
+        let skipped = table.scan(&mut |key, _| {
+            match ParsedInternalKey::decode_from(key) {
+                Some(parsed) => {
+                    max_sequence = max_sequence.max(parsed.seq);
+                    entries += 1;
+                }
+                None => bad_keys += 1,
+            }
+            Ok(())
+        })?;
+        for t in table.range_tombstones() {
+            max_sequence = max_sequence.max(t.seq);
+        }
+        if entries == 0 && table.range_tombstones().is_empty() {
+            warn!("Drop table #{} without any intact entry", number);
+            self.move_to_lost(&file_name)?;
+            return Ok(None);
+        }
+        if skipped == 0 && bad_keys == 0 {
+            return Ok(Some(max_sequence));
+        }
+
+        // Copy the intact entries into a new table which replaces the corrupted one
+        let tmp_name = generate_filename(&self.db_path, FileType::Temp, number);
+        let mut builder = self.options.table_factory.new_builder(
+            Box::new(self.storage.create(&tmp_name)?),
+            self.icmp.clone(),
+            &self.options,
+        );
+        let mut status = Ok(());
+        table.scan(&mut |key, value| {
+            if status.is_ok() && ParsedInternalKey::decode_from(key).is_some() {
+                status = builder.add(key, value);
+            }
+            Ok(())
+        })?;
+        for t in table.range_tombstones() {
+            builder.add_range_tombstone(t.clone());
+        }
+        if let Err(e) = status.and_then(|_| builder.finish(true)) {
+            self.storage.remove(&tmp_name)?;
+            return Err(e);
+        }
+        self.move_to_lost(&file_name)?;
+        self.storage.rename(&tmp_name, &file_name)?;
+        warn!(
+            "Salvage {} entries of table #{} with {} corrupted blocks and {} bad keys",
+            entries, number, skipped, bad_keys
+        );
+        self.report.tables_salvaged += 1;
+        self.report.blocks_dropped += skipped;
+        Ok(Some(max_sequence))
+    }
+
+    fn new_memtable(&self) -> MemTable<C> {
+        new_memtable(&self.options, self.icmp.clone(), &self.arena_blocks)
+    }
+
+    fn move_to_lost<P: AsRef<Path>>(&mut self, file: P) -> Result<()> {
+        let file = file.as_ref();
+        let dst = Path::new(&lost_dir(&self.db_path)).join(file.file_name().unwrap());
+        self.storage.rename(file, &dst)?;
+        self.report.lost_files.push(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{WickDB, DB};
+    use crate::options::{ReadOptions, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::BytewiseComparator;
+
+    fn open(storage: &MemStorage) -> Result<WickDB<MemStorage, BytewiseComparator>> {
+        WickDB::open_db(Options::default(), "db", storage.clone())
+    }
+
+    fn get(db: &WickDB<MemStorage, BytewiseComparator>, key: &str) -> Option<Vec<u8>> {
+        db.get(ReadOptions::default(), key.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_repair_lost_manifest() {
+        let storage = MemStorage::default();
+        let mut db = open(&storage).unwrap();
+        db.put(WriteOptions::default(), b"a", b"v1").unwrap();
+        db.put(WriteOptions::default(), b"b", b"v1").unwrap();
+        db.inner.force_compact_mem_table().unwrap();
+        db.put(WriteOptions::default(), b"a", b"v2").unwrap();
+        db.delete(WriteOptions::default(), b"b").unwrap();
+        // Left in the WAL
+        db.put(WriteOptions::default(), b"c", b"v3").unwrap();
+        db.close().unwrap();
+        for path in storage.list("db").unwrap() {
+            if let Some((FileType::Manifest, _)) | Some((FileType::ManifestBackup, _)) =
+                parse_filename(&path)
+            {
+                storage.remove(&path).unwrap();
+            }
+        }
+        assert!(open(&storage).is_err());
+
+        let report = repair_db(
+            storage.clone(),
+            "db",
+            Options::<BytewiseComparator>::default(),
+        )
+        .unwrap();
+        assert!(report.logs_converted > 0);
+        assert_eq!(report.tables_salvaged, 0);
+        assert_eq!(report.last_sequence, 5);
+        let db = open(&storage).unwrap();
+        assert_eq!(get(&db, "a"), Some(b"v2".to_vec()));
+        assert_eq!(get(&db, "b"), None);
+        assert_eq!(get(&db, "c"), Some(b"v3".to_vec()));
+        // The new writes use the sequence numbers after the recovered ones
+        db.put(WriteOptions::default(), b"a", b"v4").unwrap();
+        assert_eq!(get(&db, "a"), Some(b"v4".to_vec()));
+    }
+
+    #[test]
+    fn test_repair_corrupted_table() {
+        let storage = MemStorage::default();
+        let opts = Options::<BytewiseComparator> {
+            block_size: 1024,
+            ..Default::default()
+        };
+        let mut db = WickDB::open_db(opts.clone(), "db", storage.clone()).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), &[b'v'; 100])
+                .unwrap();
+        }
+        db.inner.force_compact_mem_table().unwrap();
+        db.close().unwrap();
+        let mut table = PathBuf::new();
+        for path in storage.list("db").unwrap() {
+            match parse_filename(&path) {
+                Some((FileType::Table, _)) => table = path,
+                // The entries are only recovered from the table
+                Some((FileType::Log, _)) => storage.remove(&path).unwrap(),
+                _ => {}
+            }
+        }
+        // Corrupt the first data block
+        let mut data = vec![];
+        storage.open(&table).unwrap().read_all(&mut data).unwrap();
+        data[100] ^= 0xff;
+        storage.remove(&table).unwrap();
+        let mut file = storage.create(&table).unwrap();
+        file.write(&data).unwrap();
+        file.close().unwrap();
+
+        let report = repair_db(storage.clone(), "db", opts.clone()).unwrap();
+        assert_eq!(report.tables_salvaged, 1);
+        assert_eq!(report.blocks_dropped, 1);
+        let db = WickDB::open_db(opts, "db", storage.clone()).unwrap();
+        assert_eq!(get(&db, "key0000"), None);
+        assert_eq!(get(&db, "key0999"), Some(vec![b'v'; 100]));
+        let lost = Path::new(&lost_dir("db")).join(table.file_name().unwrap());
+        assert!(report.lost_files.contains(&lost));
+        assert!(storage.exists(&lost));
+    }
+
+    #[test]
+    fn test_repair_locked_db() {
+        let storage = MemStorage::default();
+        assert!(matches!(
+            repair_db(
+                storage.clone(),
+                "db",
+                Options::<BytewiseComparator>::default()
+            ),
+            Err(Error::NotFound(_))
+        ));
+        let _db = open(&storage).unwrap();
+        assert!(matches!(
+            repair_db(
+                storage.clone(),
+                "db",
+                Options::<BytewiseComparator>::default()
+            ),
+            Err(Error::Busy(_))
+        ));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::batch::WriteBatch;
+use crate::mem::MemTable;
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// The number of the batches queued for each worker. The reader waits for the
+// workers once the queues are full, which bounds the memory of the batches and
+// how much the memtable usage lags behind.
+const REPLAY_QUEUE_SIZE: usize = 64;
+
+enum ReplayTask<C: Comparator> {
+    Insert(Arc<MemTable<C>>, Arc<WriteBatch>),
+    // Replied after all the tasks before it are done
+    Sync(Sender<Result<()>>),
+}
+
+/// `ReplayWorkers` inserts the write batches decoded from the WAL files into the
+/// memtables with a pool of threads when recovering the db (see
+/// `Options::wal_recovery_threads`).
+///
+/// Every batch is sent to all the workers, and each of them inserts the records
+/// whose keys fall into its partition by hash. So the records of a key are
+/// inserted in the sequence order by the same worker.
+pub(crate) struct ReplayWorkers<C: Comparator> {
+    senders: Vec<Sender<ReplayTask<C>>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<C: Comparator + 'static> ReplayWorkers<C> {
+    /// Starts `threads` workers. If `paranoid_checks` is true, the first error
+    /// of inserting the batches is returned by `sync`, otherwise it's ignored.
+    pub fn new(threads: usize, paranoid_checks: bool) -> Self {
+        let mut senders = Vec::with_capacity(threads);
+        let mut handles = Vec::with_capacity(threads);
+        for partition in 0..threads {
+            let (tx, rx) = crossbeam_channel::bounded(REPLAY_QUEUE_SIZE);
+            senders.push(tx);
+            handles.push(
+                thread::Builder::new()
+                    .name(format!("wal_replay_{}", partition))
+                    .spawn(move || run_worker(rx, partition, threads, paranoid_checks))
+                    .unwrap(),
+            );
+        }
+        Self { senders, handles }
+    }
+
+    /// Queues the batch to be inserted into `mem`
+    pub fn insert(&self, mem: &Arc<MemTable<C>>, batch: WriteBatch) {
+        let batch = Arc::new(batch);
+        for tx in self.senders.iter() {
+            // The workers only exit when the senders are dropped
+            let _ = tx.send(ReplayTask::Insert(mem.clone(), batch.clone()));
+        }
+    }
+
+    /// Waits for all the queued batches to be inserted. The memtables are no
+    /// longer referenced by the workers afterwards.
+    pub fn sync(&self) -> Result<()> {
+        let (tx, rx) = crossbeam_channel::bounded(self.senders.len());
+        for sender in self.senders.iter() {
+            let _ = sender.send(ReplayTask::Sync(tx.clone()));
+        }
+        drop(tx);
+        let mut res = Ok(());
+        for _ in 0..self.senders.len() {
+            let r = rx.recv().unwrap_or_else(|e| {
+                Err(Error::Customized(format!(
+                    "WAL replay worker exited: {}",
+                    e
+                )))
+            });
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+}
+
+impl<C: Comparator> Drop for ReplayWorkers<C> {
+    fn drop(&mut self) {
+        self.senders.clear();
+        for h in self.handles.drain(..) {
+            let _ = h.join();
+        }
+    }
+}
+
+fn run_worker<C: Comparator>(
+    tasks: Receiver<ReplayTask<C>>,
+    partition: usize,
+    partitions: usize,
+    paranoid_checks: bool,
+) {
+    let mut error = None;
+    for task in tasks {
+        match task {
+            ReplayTask::Insert(mem, batch) => {
+                if let Err(e) = batch.insert_partition_into(&mem, partition, partitions) {
+                    if paranoid_checks {
+                        error.get_or_insert(e);
+                    } else {
+                        info!("ignore errors when replaying log file : {:?}", e);
+                    }
+                }
+            }
+            ReplayTask::Sync(reply) => {
+                let _ = reply.send(error.take().map_or(Ok(()), Err));
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long an idle background thread waits for a new job before exiting
+pub const BACKGROUND_THREAD_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Returns the message of a panic caught by `catch_unwind`
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// The priority of a background job, which decides the thread pool it runs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// The memtable flushes the foreground writes are waiting for
+    High,
+    /// The compactions
+    Low,
+}
+
+/// `BackgroundScheduler` runs the background jobs of a db in a thread pool per
+/// priority, so a flush never waits for a long running compaction to finish.
+pub struct BackgroundScheduler {
+    high: ThreadPool,
+    low: ThreadPool,
+}
+
+impl BackgroundScheduler {
+    /// Splits `max_background_jobs` threads between the priorities. A quarter of
+    /// them runs the flushes and each pool has at least one thread.
+    pub fn new(max_background_jobs: usize, idle_timeout: Duration) -> Self {
+        let high = (max_background_jobs / 4).max(1);
+        let low = max_background_jobs.saturating_sub(high).max(1);
+        Self {
+            high: ThreadPool::new("flush", high, idle_timeout),
+            low: ThreadPool::new("compaction", low, idle_timeout),
+        }
+    }
+
+    /// Queues `job` to the pool of `priority`. Returns false and drops the job if
+    /// the scheduler has been shut down.
+    pub fn schedule<F>(&self, priority: Priority, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool(priority).schedule(Box::new(job))
+    }
+
+    /// Drops the queued jobs and waits until the running ones are finished
+    pub fn shutdown(&self) {
+        self.high.shutdown();
+        self.low.shutdown();
+    }
+
+    /// The number of threads alive in the pool of `priority`
+    pub fn threads(&self, priority: Priority) -> usize {
+        self.pool(priority).inner.state.lock().unwrap().threads
+    }
+
+    fn pool(&self, priority: Priority) -> &ThreadPool {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Low => &self.low,
+        }
+    }
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    // The threads alive, including the idle ones
+    threads: usize,
+    // The threads waiting for a job
+    idle: usize,
+    shutting_down: bool,
+}
+
+struct PoolInner {
+    name: &'static str,
+    max_threads: usize,
+    idle_timeout: Duration,
+    state: Mutex<PoolState>,
+    // Signaled when a job is queued or the pool is shut down
+    job_available: Condvar,
+    // Signaled when a thread exits
+    thread_exited: Condvar,
+}
+
+/// A pool spawning its threads on demand up to `max_threads`. A thread exits
+/// after being idle for `idle_timeout` so an idle db holds no thread.
+struct ThreadPool {
+    inner: Arc<PoolInner>,
+}
+
+impl ThreadPool {
+    fn new(name: &'static str, max_threads: usize, idle_timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                name,
+                max_threads,
+                idle_timeout,
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    shutting_down: false,
+                }),
+                job_available: Condvar::new(),
+                thread_exited: Condvar::new(),
+            }),
+        }
+    }
+
+    fn schedule(&self, job: Job) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.shutting_down {
+            return false;
+        }
+        state.queue.push_back(job);
+        if state.idle >= state.queue.len() || state.threads >= self.inner.max_threads {
+            self.inner.job_available.notify_one();
+            return true;
+        }
+        let inner = self.inner.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("{}-{}", self.inner.name, state.threads))
+            .spawn(move || inner.run());
+        match spawned {
+            Ok(_) => state.threads += 1,
+            Err(e) => {
+                error!("Fail to spawn a {} thread: {}", self.inner.name, e);
+                // The job runs when a thread of the pool becomes free
+                if state.threads == 0 {
+                    state.queue.pop_back();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn shutdown(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.shutting_down = true;
+        state.queue.clear();
+        self.inner.job_available.notify_all();
+        while state.threads > 0 {
+            state = self.inner.thread_exited.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // The last reference of the db might be dropped by a job, so don't wait
+        // for the threads here
+        let mut state = self.inner.state.lock().unwrap();
+        state.shutting_down = true;
+        state.queue.clear();
+        self.inner.job_available.notify_all();
+    }
+}
+
+impl PoolInner {
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutting_down {
+                break;
+            }
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                // A panicking job must not kill the thread, or `shutdown` would
+                // wait for it forever
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    error!(
+                        "A {} job panicked: {}",
+                        self.name,
+                        panic_message(&*payload)
+                    );
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state.idle += 1;
+            let (s, res) = self
+                .job_available
+                .wait_timeout(state, self.idle_timeout)
+                .unwrap();
+            state = s;
+            state.idle -= 1;
+            if res.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+        state.threads -= 1;
+        self.thread_exited.notify_all();
+        debug!("{} thread exited, {} left", self.name, state.threads);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    fn wait_until(f: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_high_priority_not_blocked_by_low() {
+        let scheduler = BackgroundScheduler::new(2, Duration::from_secs(10));
+        let (release, blocked) = crossbeam_channel::bounded::<()>(0);
+        // Occupy the only LOW thread
+        assert!(scheduler.schedule(Priority::Low, move || {
+            let _ = blocked.recv();
+        }));
+        let (done, finished) = crossbeam_channel::bounded(1);
+        assert!(scheduler.schedule(Priority::High, move || done.send(()).unwrap()));
+        finished
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the flush should run while the compaction is running");
+        assert_eq!(scheduler.threads(Priority::Low), 1);
+        release.send(()).unwrap();
+        scheduler.shutdown();
+        assert_eq!(scheduler.threads(Priority::High), 0);
+        assert_eq!(scheduler.threads(Priority::Low), 0);
+        assert!(!scheduler.schedule(Priority::High, || {}));
+    }
+
+    #[test]
+    fn test_threads_limit_and_recycling() {
+        let scheduler = BackgroundScheduler::new(4, Duration::from_millis(50));
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let counter = counter.clone();
+            scheduler.schedule(Priority::Low, move || {
+                thread::sleep(Duration::from_millis(5));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            assert!(scheduler.threads(Priority::Low) <= 3);
+        }
+        wait_until(|| counter.load(Ordering::SeqCst) == 20);
+        // The idle threads exit
+        wait_until(|| scheduler.threads(Priority::Low) == 0);
+        // and are spawned again on demand
+        let c = counter.clone();
+        scheduler.schedule(Priority::Low, move || {
+            c.fetch_add(1, Ordering::SeqCst);
+        });
+        wait_until(|| counter.load(Ordering::SeqCst) == 21);
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn test_panicking_job_keeps_thread() {
+        let scheduler = BackgroundScheduler::new(2, Duration::from_secs(10));
+        assert!(scheduler.schedule(Priority::Low, || panic!("boom")));
+        let (done, finished) = crossbeam_channel::bounded(1);
+        assert!(scheduler.schedule(Priority::Low, move || done.send(()).unwrap()));
+        finished
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the pool should survive a panicking job");
+        assert_eq!(scheduler.threads(Priority::Low), 1);
+        // The thread is accounted and doesn't block the shutdown
+        scheduler.shutdown();
+        assert_eq!(scheduler.threads(Priority::Low), 0);
+        assert_eq!(panic_message(&"boom"), "boom");
+        assert_eq!(panic_message(&"boom".to_owned()), "boom");
+        assert_eq!(panic_message(&1), "unknown panic");
+    }
+}
@@ -0,0 +1,53 @@
+use crate::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// `ScrubStats` counts the data blocks verified by the background scrubber
+/// enabled by `Options::block_scrub_interval` since the db was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrubStats {
+    /// The data blocks read and verified
+    pub blocks_verified: u64,
+    /// The bytes of the verified blocks
+    pub bytes_verified: u64,
+    /// The blocks failing the verification
+    pub corruptions: u64,
+}
+
+/// A `ScrubListener` is notified when the background scrubber finds a corrupted
+/// block, so the corruption can be alerted and repaired long before a query
+/// reads the block.
+///
+/// The callback is invoked on the scrubber thread without holding any lock of
+/// the db.
+pub trait ScrubListener: Send + Sync {
+    /// Called with the file number of the table and the verification error,
+    /// which describes the offset of the corrupted block
+    fn on_block_corruption(&self, file_number: u64, err: &Error);
+}
+
+/// The lock-free counters behind `ScrubStats`
+#[derive(Default)]
+pub(crate) struct ScrubCounters {
+    blocks_verified: AtomicU64,
+    bytes_verified: AtomicU64,
+    corruptions: AtomicU64,
+}
+
+impl ScrubCounters {
+    pub fn record_verified(&self, bytes: u64) {
+        self.blocks_verified.fetch_add(1, Ordering::Relaxed);
+        self.bytes_verified.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_corruption(&self) {
+        self.corruptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ScrubStats {
+        ScrubStats {
+            blocks_verified: self.blocks_verified.load(Ordering::Relaxed),
+            bytes_verified: self.bytes_verified.load(Ordering::Relaxed),
+            corruptions: self.corruptions.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,566 @@
+use crate::batch::{WriteBatch, WriteBatchHandler};
+use crate::db::DB;
+use crate::iterator::Iterator;
+use crate::options::{ReadOptions, WriteOptions};
+use crate::snapshot::Snapshot;
+use crate::{Error, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// The most bytes of a key or value shown in the traces
+const MAX_DISPLAY_BYTES: usize = 64;
+
+/// Options to control a `ShadowDB`
+#[derive(Clone)]
+pub struct ShadowOptions {
+    /// The number of the latest operations kept as the trace of a divergence
+    /// Default: 64
+    pub trace_len: usize,
+
+    /// Whether to panic at a divergence instead of recording it
+    /// Default: false
+    pub panic_on_divergence: bool,
+}
+
+impl Default for ShadowOptions {
+    fn default() -> Self {
+        Self {
+            trace_len: 64,
+            panic_on_divergence: false,
+        }
+    }
+}
+
+/// A `Divergence` is a read whose result from the db differs from the one
+/// from the model
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// The diverged read
+    pub op: String,
+    /// The result from the model
+    pub expected: String,
+    /// The result from the db
+    pub actual: String,
+    /// The latest operations before the read, the oldest first
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} diverged: expect {} but got {}",
+            self.op, self.expected, self.actual
+        )?;
+        write!(f, "trace:")?;
+        for op in self.trace.iter() {
+            write!(f, "\n  {}", op)?;
+        }
+        Ok(())
+    }
+}
+
+struct Model {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+    // The latest writes
+    trace: VecDeque<String>,
+}
+
+struct Shared {
+    options: ShadowOptions,
+    model: Mutex<Model>,
+    divergences: Mutex<Vec<Divergence>>,
+}
+
+impl Shared {
+    fn report(&self, op: String, expected: String, actual: String, trace: Vec<String>) {
+        let divergence = Divergence {
+            op,
+            expected,
+            actual,
+            trace,
+        };
+        if self.options.panic_on_divergence {
+            panic!("[shadow] {}", divergence);
+        }
+        error!("[shadow] {}", divergence);
+        self.divergences.lock().unwrap().push(divergence);
+    }
+}
+
+/// `ShadowDB` is a testing wrapper of a `DB`, which mirrors all the writes to
+/// an in-memory `BTreeMap` model and compares the result of every read and
+/// every iterator move with the model. A divergence is reported with the trace
+/// of the latest writes.
+///
+/// The model only supports the bytewise order, and the writes must not contain
+/// merge operands. The reads with a snapshot or a timestamp are not compared.
+/// Writing the wrapped db directly makes the model out of date.
+pub struct ShadowDB<D: DB> {
+    db: D,
+    shared: Arc<Shared>,
+}
+
+impl<D: DB> ShadowDB<D> {
+    /// Wraps an empty `db`
+    pub fn new(db: D, options: ShadowOptions) -> Self {
+        Self {
+            db,
+            shared: Arc::new(Shared {
+                options,
+                model: Mutex::new(Model {
+                    data: BTreeMap::new(),
+                    trace: VecDeque::new(),
+                }),
+                divergences: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Returns the wrapped db
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Replaces the wrapped db with `db`, e.g. the reopened one, and keeps
+    /// comparing with the same model. Returns the old db.
+    pub fn replace_db(&mut self, db: D) -> D {
+        std::mem::replace(&mut self.db, db)
+    }
+
+    /// Returns all the divergences reported
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.shared.divergences.lock().unwrap().clone()
+    }
+}
+
+// Returns whether the read can be answered by the model
+fn comparable(read_opt: &ReadOptions) -> bool {
+    read_opt.snapshot.is_none() && read_opt.timestamp.is_none()
+}
+
+// Formats the bytes as an escaped string, which is truncated if it's too long
+fn display(bytes: &[u8]) -> String {
+    let mut s: String = bytes
+        .iter()
+        .take(MAX_DISPLAY_BYTES)
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect();
+    if bytes.len() > MAX_DISPLAY_BYTES {
+        s.push_str(&format!("...({} bytes)", bytes.len()));
+    }
+    format!("\"{}\"", s)
+}
+
+fn display_entry(entry: Option<(&[u8], &[u8])>) -> String {
+    match entry {
+        Some((k, v)) => format!("{} -> {}", display(k), display(v)),
+        None => "nothing".to_owned(),
+    }
+}
+
+enum ModelOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+impl fmt::Display for ModelOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelOp::Put(k, v) => write!(f, "put {} -> {}", display(k), display(v)),
+            ModelOp::Delete(k) => write!(f, "delete {}", display(k)),
+            ModelOp::DeleteRange(begin, end) => {
+                write!(f, "delete range [{}, {})", display(begin), display(end))
+            }
+        }
+    }
+}
+
+// Collects the records of a batch as the operations of the model
+#[derive(Default)]
+struct ModelOps {
+    ops: Vec<ModelOp>,
+    has_merge: bool,
+}
+
+impl WriteBatchHandler for ModelOps {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(ModelOp::Put(key.to_vec(), value.to_vec()));
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.ops.push(ModelOp::Delete(key.to_vec()));
+    }
+
+    fn merge(&mut self, _key: &[u8], _operand: &[u8]) {
+        self.has_merge = true;
+    }
+
+    fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.ops
+            .push(ModelOp::DeleteRange(begin.to_vec(), end.to_vec()));
+    }
+}
+
+impl<D: DB> DB for ShadowDB<D>
+where
+    D::Iterator: Iterator,
+{
+    type Iterator = ShadowIterator<D::Iterator>;
+
+    fn put(&self, write_opt: WriteOptions, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+        self.write(write_opt, batch)
+    }
+
+    fn get(&self, read_opt: ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !comparable(&read_opt) {
+            return self.db.get(read_opt, key);
+        }
+        // Hold the model so no write happens between the two reads
+        let model = self.shared.model.lock().unwrap();
+        let actual = self.db.get(read_opt, key)?;
+        let expected = model.data.get(key);
+        if actual.as_ref() != expected {
+            let show = |v: Option<&Vec<u8>>| v.map_or("nothing".to_owned(), |v| display(v));
+            self.shared.report(
+                format!("get {}", display(key)),
+                show(expected),
+                show(actual.as_ref()),
+                model.trace.iter().cloned().collect(),
+            );
+        }
+        Ok(actual)
+    }
+
+    fn iter(&self, read_opt: ReadOptions) -> Result<Self::Iterator> {
+        let compare = comparable(&read_opt);
+        let model = self.shared.model.lock().unwrap();
+        let inner = self.db.iter(read_opt)?;
+        let entries = if compare {
+            model
+                .data
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok(ShadowIterator {
+            inner,
+            compare,
+            pos: entries.len(),
+            entries,
+            trace: model.trace.clone(),
+            shared: self.shared.clone(),
+        })
+    }
+
+    fn delete(&self, write_opt: WriteOptions, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(key);
+        self.write(write_opt, batch)
+    }
+
+    fn write(&self, write_opt: WriteOptions, batch: WriteBatch) -> Result<()> {
+        let mut ops = ModelOps::default();
+        batch.iterate(&mut ops)?;
+        if ops.has_merge {
+            return Err(Error::InvalidArgument(
+                "[shadow] the merge operands are not supported by the model".to_owned(),
+            ));
+        }
+        // Hold the model so the writes are applied in the same order
+        let mut model = self.shared.model.lock().unwrap();
+        let res = self.db.write(write_opt, batch);
+        let trace_len = self.shared.options.trace_len;
+        for op in ops.ops {
+            let desc = match &res {
+                Ok(_) => op.to_string(),
+                Err(e) => format!("{} (failed: {})", op, e),
+            };
+            if res.is_ok() {
+                match op {
+                    ModelOp::Put(k, v) => {
+                        model.data.insert(k, v);
+                    }
+                    ModelOp::Delete(k) => {
+                        model.data.remove(&k);
+                    }
+                    ModelOp::DeleteRange(begin, end) => {
+                        if begin < end {
+                            let keys: Vec<_> = model
+                                .data
+                                .range(begin..end)
+                                .map(|(k, _)| k.clone())
+                                .collect();
+                            for k in keys {
+                                model.data.remove(&k);
+                            }
+                        }
+                    }
+                }
+            }
+            model.trace.push_back(desc);
+            while model.trace.len() > trace_len {
+                model.trace.pop_front();
+            }
+        }
+        res
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.db.close()
+    }
+
+    fn destroy(&mut self) -> Result<()> {
+        self.db.destroy()
+    }
+
+    fn snapshot(&self) -> Arc<Snapshot> {
+        self.db.snapshot()
+    }
+}
+
+/// The iterator of a `ShadowDB`, which compares the entry after every move
+/// with the model at the time it's created
+pub struct ShadowIterator<I: Iterator> {
+    inner: I,
+    // Whether the iterator is compared with the model
+    compare: bool,
+    // The entries of the model
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    // The position in `entries`, which is `entries.len()` if it's invalid
+    pos: usize,
+    // The latest writes before the creation and then the moves
+    trace: VecDeque<String>,
+    shared: Arc<Shared>,
+}
+
+impl<I: Iterator> ShadowIterator<I> {
+    fn seek_model(&mut self, target: &[u8]) {
+        self.pos = self.entries.partition_point(|(k, _)| k.as_slice() < target);
+    }
+
+    fn check(&mut self, op: String) {
+        if !self.compare {
+            return;
+        }
+        let expected = self
+            .entries
+            .get(self.pos)
+            .map(|(k, v)| (k.as_slice(), v.as_slice()));
+        let actual = if self.inner.valid() {
+            Some((self.inner.key(), self.inner.value()))
+        } else {
+            None
+        };
+        if expected != actual {
+            self.shared.report(
+                format!("iterator {}", op),
+                display_entry(expected),
+                display_entry(actual),
+                self.trace.iter().cloned().collect(),
+            );
+            // Follow the db so one divergence is not reported by all the
+            // following moves
+            if self.inner.valid() {
+                let key = self.inner.key().to_vec();
+                self.seek_model(&key);
+            } else {
+                self.pos = self.entries.len();
+            }
+        }
+        self.trace.push_back(format!("iterator {}", op));
+        while self.trace.len() > self.shared.options.trace_len {
+            self.trace.pop_front();
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for ShadowIterator<I> {
+    fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.inner.seek_to_first();
+        self.pos = 0;
+        self.check("seek_to_first".to_owned());
+    }
+
+    fn seek_to_last(&mut self) {
+        self.inner.seek_to_last();
+        self.pos = self.entries.len().saturating_sub(1);
+        self.check("seek_to_last".to_owned());
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.inner.seek(target);
+        self.seek_model(target);
+        self.check(format!("seek {}", display(target)));
+    }
+
+    fn next(&mut self) {
+        self.inner.next();
+        if self.pos < self.entries.len() {
+            self.pos += 1;
+        }
+        self.check("next".to_owned());
+    }
+
+    fn prev(&mut self) {
+        self.inner.prev();
+        self.pos = if self.pos == 0 {
+            self.entries.len()
+        } else {
+            self.pos - 1
+        };
+        self.check("prev".to_owned());
+    }
+
+    fn key(&self) -> &[u8] {
+        self.inner.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.inner.value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.inner.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::WickDB;
+    use crate::options::Options;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use rand::Rng;
+
+    fn new_shadow_db() -> ShadowDB<WickDB<MemStorage, BytewiseComparator>> {
+        let opts = Options::<BytewiseComparator> {
+            write_buffer_size: 64 * 1024,
+            ..Default::default()
+        };
+        let db = WickDB::open_db(opts, "shadow", MemStorage::default()).unwrap();
+        ShadowDB::new(db, ShadowOptions::default())
+    }
+
+    fn scan<D: DB>(db: &D)
+    where
+        D::Iterator: Iterator,
+    {
+        let mut iter = db.iter(ReadOptions::default()).unwrap();
+        iter.seek_to_first();
+        while iter.valid() {
+            iter.next();
+        }
+        iter.seek_to_last();
+        while iter.valid() {
+            iter.prev();
+        }
+        iter.seek(b"250");
+        if iter.valid() {
+            iter.next();
+        }
+        if iter.valid() {
+            iter.prev();
+        }
+    }
+
+    #[test]
+    fn test_random_ops_without_divergence() {
+        let db = new_shadow_db();
+        let mut rng = rand::thread_rng();
+        for i in 0..3000 {
+            let key = format!("{:03}", rng.gen_range(0, 500));
+            match rng.gen_range(0, 10) {
+                0 => db.delete(WriteOptions::default(), key.as_bytes()).unwrap(),
+                1 => {
+                    let mut batch = WriteBatch::default();
+                    batch.put(key.as_bytes(), b"batch");
+                    batch.delete_range(key.as_bytes(), b"300");
+                    db.write(WriteOptions::default(), batch).unwrap();
+                }
+                2 | 3 => {
+                    db.get(ReadOptions::default(), key.as_bytes()).unwrap();
+                }
+                _ => {
+                    let value = format!("{}", i).repeat(rng.gen_range(1, 50));
+                    db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                        .unwrap();
+                }
+            }
+            if i % 500 == 0 {
+                scan(&db);
+            }
+        }
+        scan(&db);
+        assert!(db.divergences().is_empty(), "{}", db.divergences()[0]);
+    }
+
+    #[test]
+    fn test_report_divergence() {
+        let db = new_shadow_db();
+        db.put(WriteOptions::default(), b"a", b"1").unwrap();
+        db.put(WriteOptions::default(), b"b", b"2").unwrap();
+        // Bypass the model
+        db.db().put(WriteOptions::default(), b"b", b"3").unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), b"b").unwrap(),
+            Some(b"3".to_vec())
+        );
+        let divergences = db.divergences();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].op, "get \"b\"");
+        assert_eq!(divergences[0].expected, "\"2\"");
+        assert_eq!(divergences[0].actual, "\"3\"");
+        assert_eq!(
+            divergences[0].trace,
+            vec!["put \"a\" -> \"1\"", "put \"b\" -> \"2\""]
+        );
+
+        let mut iter = db.iter(ReadOptions::default()).unwrap();
+        iter.seek_to_first();
+        iter.next();
+        iter.next();
+        let divergences = db.divergences();
+        // Only the diverged move is reported
+        assert_eq!(divergences.len(), 2);
+        assert_eq!(divergences[1].op, "iterator next");
+        assert_eq!(
+            divergences[1].trace.last().unwrap(),
+            "iterator seek_to_first"
+        );
+
+        // The snapshot reads are not compared
+        let snapshot = db.snapshot();
+        let opts = ReadOptions {
+            snapshot: Some(snapshot.sequence().into()),
+            ..Default::default()
+        };
+        db.get(opts, b"b").unwrap();
+        assert_eq!(db.divergences().len(), 2);
+    }
+
+    #[test]
+    fn test_reject_merge() {
+        let db = new_shadow_db();
+        let mut batch = WriteBatch::default();
+        batch.put(b"a", b"1");
+        batch.merge(b"a", b"2");
+        assert!(matches!(
+            db.write(WriteOptions::default(), batch),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(db.get(ReadOptions::default(), b"a").unwrap(), None);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::batch::{WriteBatch, HEADER_SIZE};
+use crate::db::vetoed_sequence;
+use crate::record::reader::Reader;
+use crate::storage::File;
+use crate::util::reporter::LogReporter;
+use crate::{Error, Result};
+use std::collections::VecDeque;
+
+/// `WalUpdatesIterator` yields the write batches in the WAL files of a db with
+/// their first sequence numbers, in the order they're written. It's created by
+/// `WickDB::get_updates_since`.
+///
+/// The iterator ends at the last sequence published when it's created, so the
+/// updates written after that are read by calling `get_updates_since` again
+/// with the next sequence. The write groups vetoed by the `PreCommitHook` are
+/// skipped. The files ingested by `WickDB::ingest_external_file` are not in
+/// the WAL, so their sequences are missing from the stream.
+pub struct WalUpdatesIterator<F: File> {
+    // The log files to read, the oldest first
+    files: VecDeque<F>,
+    reader: Option<Reader<F>>,
+    reporter: LogReporter,
+    record: Vec<u8>,
+    // The batches ending before it are skipped
+    start: u64,
+    // The last sequence published when the iterator is created
+    last_sequence: u64,
+    // A write group is yielded once the next record shows it's not vetoed
+    pending: Option<WriteBatch>,
+    finished: bool,
+}
+
+impl<F: File> WalUpdatesIterator<F> {
+    pub(crate) fn new(files: VecDeque<F>, start: u64, last_sequence: u64) -> Self {
+        Self {
+            files,
+            reader: None,
+            reporter: LogReporter::new(),
+            record: vec![],
+            start,
+            last_sequence,
+            pending: None,
+            finished: false,
+        }
+    }
+
+    // Reads the next record of the files, or returns `None` if all the files are read
+    fn read_batch(&mut self) -> Option<Result<WriteBatch>> {
+        loop {
+            if self.reader.is_none() {
+                let file = self.files.pop_front()?;
+                self.reader = Some(Reader::new(
+                    file,
+                    Some(Box::new(self.reporter.clone())),
+                    true,
+                    0,
+                ));
+            }
+            let reader = self.reader.as_mut().unwrap();
+            let read = reader.read_record(&mut self.record);
+            if let Err(e) = self.reporter.result() {
+                return Some(Err(e));
+            }
+            if !read {
+                self.reader = None;
+                continue;
+            }
+            if self.record.len() < HEADER_SIZE {
+                return Some(Err(Error::Corruption("log record too small".to_owned())));
+            }
+            let mut batch = WriteBatch::default();
+            batch.set_contents(&mut self.record);
+            return Some(Ok(batch));
+        }
+    }
+
+    // Returns the batch if it should be yielded
+    fn accept(&mut self, batch: WriteBatch) -> Option<(u64, WriteBatch)> {
+        let first = batch.get_sequence();
+        if first > self.last_sequence {
+            self.finished = true;
+            return None;
+        }
+        if first + u64::from(batch.get_count()) <= self.start {
+            return None;
+        }
+        Some((first, batch))
+    }
+}
+
+impl<F: File> std::iter::Iterator for WalUpdatesIterator<F> {
+    type Item = Result<(u64, WriteBatch)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            match self.read_batch() {
+                Some(Ok(batch)) => {
+                    if let Some(seq) = vetoed_sequence(&batch) {
+                        if self
+                            .pending
+                            .as_ref()
+                            .is_some_and(|p| p.get_sequence() == seq)
+                        {
+                            self.pending = None;
+                        }
+                        continue;
+                    }
+                    if let Some(accepted) = self.pending.replace(batch) {
+                        if let Some(update) = self.accept(accepted) {
+                            return Some(Ok(update));
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.finished = true;
+                    if let Some(batch) = self.pending.take() {
+                        return self.accept(batch).map(Ok);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,163 @@
+use crate::sstable::BlockHandle;
+use crate::util::coding::{decode_fixed_32, put_fixed_32};
+use crate::util::crc32::{hash, mask, unmask};
+use crate::util::varint::VarintU64;
+use crate::{Error, Result};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// One of every `HOT_BLOCK_SAMPLE_INTERVAL` block reads is sampled at random, so
+// the lookups rarely contend on the lock
+const HOT_BLOCK_SAMPLE_INTERVAL: u32 = 8;
+
+/// `HotBlocks` samples the blocks read by the point lookups of a db and keeps
+/// the most frequently read ones, which are persisted when the db is closed
+/// (see `Options::hot_blocks_to_persist`).
+pub(crate) struct HotBlocks {
+    capacity: usize,
+    sample_interval: u32,
+    // (file number, block offset) -> (block size, sampled reads)
+    sampled: Mutex<HashMap<(u64, u64), (u64, u64)>>,
+}
+
+impl HotBlocks {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            sample_interval: HOT_BLOCK_SAMPLE_INTERVAL,
+            sampled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, file_number: u64, handle: &BlockHandle) {
+        if self.sample_interval > 1 && !rand::thread_rng().gen_ratio(1, self.sample_interval) {
+            return;
+        }
+        let mut sampled = self.sampled.lock().unwrap();
+        sampled
+            .entry((file_number, handle.offset()))
+            .or_insert((handle.size(), 0))
+            .1 += 1;
+        // Drops the coldest half once there are twice as many blocks as kept, and
+        // halves the reads of the rest so the blocks no longer read cool down
+        if sampled.len() > self.capacity * 2 {
+            let mut blocks = sampled.drain().collect::<Vec<_>>();
+            blocks.sort_by_key(|(_, (_, reads))| std::cmp::Reverse(*reads));
+            blocks.truncate(self.capacity);
+            sampled.extend(
+                blocks
+                    .into_iter()
+                    .map(|(k, (size, reads))| (k, (size, reads / 2 + 1))),
+            );
+        }
+    }
+
+    /// Adds the blocks persisted by the last run as sampled once, so they're kept
+    /// unless they're replaced by the blocks read more frequently
+    pub fn seed(&self, blocks: &[(u64, BlockHandle)]) {
+        let mut sampled = self.sampled.lock().unwrap();
+        for (file_number, handle) in blocks.iter().take(self.capacity) {
+            sampled
+                .entry((*file_number, handle.offset()))
+                .or_insert((handle.size(), 1));
+        }
+    }
+
+    /// Returns at most `capacity` sampled blocks as (file number, handle), the
+    /// most frequently read first
+    pub fn hottest(&self) -> Vec<(u64, BlockHandle)> {
+        let mut blocks = self
+            .sampled
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(file_number, offset), &(size, reads))| (reads, file_number, offset, size))
+            .collect::<Vec<_>>();
+        blocks.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+        blocks
+            .into_iter()
+            .take(self.capacity)
+            .map(|(_, file_number, offset, size)| (file_number, BlockHandle::new(offset, size)))
+            .collect()
+    }
+}
+
+/// Encodes the blocks into the content of the `HOT_BLOCKS` file:
+///
+/// ```text
+///   +----------------------+-----------------------+---------------------+-----+------------------------+
+///   | file number (varint) | block offset (varint) | block size (varint) | ... | masked crc32 (4-bytes) |
+///   +----------------------+-----------------------+---------------------+-----+------------------------+
+/// ```
+pub(crate) fn encode_hot_blocks(blocks: &[(u64, BlockHandle)]) -> Vec<u8> {
+    let mut dst = vec![];
+    for (file_number, handle) in blocks.iter() {
+        VarintU64::put_varint(&mut dst, *file_number);
+        VarintU64::put_varint(&mut dst, handle.offset());
+        VarintU64::put_varint(&mut dst, handle.size());
+    }
+    let checksum = mask(hash(&dst));
+    put_fixed_32(&mut dst, checksum);
+    dst
+}
+
+/// Decodes the blocks from the content of the `HOT_BLOCKS` file
+pub(crate) fn decode_hot_blocks(src: &[u8]) -> Result<Vec<(u64, BlockHandle)>> {
+    let corruption = || Error::Corruption("bad hot blocks file".to_owned());
+    if src.len() < 4 {
+        return Err(corruption());
+    }
+    let (mut data, checksum) = src.split_at(src.len() - 4);
+    if unmask(decode_fixed_32(checksum)) != hash(data) {
+        return Err(Error::Corruption(
+            "hot blocks file checksum mismatch".to_owned(),
+        ));
+    }
+    let mut blocks = vec![];
+    while !data.is_empty() {
+        let file_number = VarintU64::drain_read(&mut data).ok_or_else(corruption)?;
+        let offset = VarintU64::drain_read(&mut data).ok_or_else(corruption)?;
+        let size = VarintU64::drain_read(&mut data).ok_or_else(corruption)?;
+        blocks.push((file_number, BlockHandle::new(offset, size)));
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_blocks() {
+        // Every read is sampled
+        let hot = HotBlocks {
+            sample_interval: 1,
+            ..HotBlocks::new(2)
+        };
+        let block = |i: u64| BlockHandle::new(i * 100, 100);
+        hot.record(1, &block(0));
+        for _ in 0..3 {
+            hot.record(1, &block(1));
+        }
+        for _ in 0..2 {
+            hot.record(2, &block(0));
+        }
+        assert_eq!(hot.hottest(), vec![(1, block(1)), (2, block(0))]);
+
+        // The coldest blocks are dropped
+        for i in 2..10 {
+            hot.record(3, &block(i));
+        }
+        assert!(hot.sampled.lock().unwrap().len() <= 4);
+        assert_eq!(hot.hottest()[0], (1, block(1)));
+
+        let encoded = encode_hot_blocks(&hot.hottest());
+        assert_eq!(decode_hot_blocks(&encoded).unwrap(), hot.hottest());
+        assert_eq!(decode_hot_blocks(&encode_hot_blocks(&[])).unwrap(), vec![]);
+        let mut corrupted = encoded.clone();
+        corrupted[0] ^= 1;
+        assert!(decode_hot_blocks(&corrupted).is_err());
+        assert!(decode_hot_blocks(&encoded[..3]).is_err());
+    }
+}
@@ -0,0 +1,45 @@
+/// The delay applied to each write when there are too many level 0 files
+pub const LEVEL0_SLOWDOWN_WRITE_DELAY_MICROS: u64 = 1000;
+
+/// The reason why the writes are delayed or stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStallReason {
+    /// The number of level 0 files reaches `l0_slowdown_writes_threshold`
+    Level0Slowdown,
+    /// The number of level 0 files reaches `l0_stop_writes_threshold`
+    Level0Stop,
+    /// The memtable is full and `max_write_buffer_number - 1` immutable
+    /// memtables are still being flushed
+    MemtableFull,
+}
+
+/// `WriteStallCondition` describes how the writes are currently throttled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteStallCondition {
+    /// Writes are not throttled
+    #[default]
+    Normal,
+    /// Each write is delayed by `micros` microseconds
+    Delayed {
+        micros: u64,
+        reason: WriteStallReason,
+    },
+    /// Writes are blocked until the background work catches up
+    Stopped { reason: WriteStallReason },
+}
+
+impl WriteStallCondition {
+    /// Returns true if the writes are delayed or stopped
+    pub fn is_stalled(&self) -> bool {
+        *self != WriteStallCondition::Normal
+    }
+}
+
+/// A `WriteStallListener` is notified every time the `WriteStallCondition` of
+/// the db changes, so that the upstream can shed load before writes stall.
+///
+/// The callback is invoked while the db holds its internal lock, so it must be
+/// cheap and must not call back into the db.
+pub trait WriteStallListener: Send + Sync {
+    fn on_write_stall_changed(&self, prev: WriteStallCondition, current: WriteStallCondition);
+}
@@ -16,9 +16,31 @@ quick_error! {
         InvalidArgument(hint: String) {
             display("invalid argument: {}", hint)
         }
+        /// The db to create already exists, e.g. opened with `OpenMode::CreateNew`
+        AlreadyExists(hint: String) {
+            display("already exists: {}", hint)
+        }
         DBClosed(hint: String) {
             display("try to operate a closed db: {}", hint)
         }
+        /// The operation conflicts with another one, e.g. a transaction writing a
+        /// key written by another transaction after it began
+        Busy(hint: String) {
+            display("resource busy: {}", hint)
+        }
+        /// The operation exceeds its deadline, e.g. `ReadOptions::deadline`
+        TimedOut(hint: String) {
+            display("timed out: {}", hint)
+        }
+        /// The operation needs a component the crate is built without, e.g. a
+        /// compression type whose cargo feature is disabled
+        NotSupported(hint: String) {
+            display("not supported: {}", hint)
+        }
+        /// The write touches a key range made read-only by `WickDB::fence_range`
+        RangeFenced(hint: String) {
+            display("write to a fenced key range: {}", hint)
+        }
         CompressionFailed(err: snap::Error) {
             display("compression failed: {}", err)
             cause(err)
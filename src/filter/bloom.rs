@@ -1,6 +1,25 @@
-use crate::filter::FilterPolicy;
+use crate::filter::{FilterBuilder, FilterPolicy};
 use crate::util::hash::hash;
 
+// All the probes for a key land in a window of this many bytes (one cache line)
+const CACHE_LINE_SIZE: usize = 64;
+
+/// A cache-local bloom filter.
+///
+/// The bits are split into windows of at most 64 bytes. Every key is mapped to
+/// one window first and all the `k` probes of the key are performed inside
+/// that window. So a lookup touches at most two cache lines no matter how
+/// large the filter is.
+///
+/// The filter layout:
+///
+/// ```text
+///     +----------------------------+------------------+
+///     | bits (n bytes)             | k (1 byte)       |
+///     +----------------------------+------------------+
+/// ```
+///
+/// When the filter has no more than 64 bytes, the window is the whole filter.
 pub struct BloomFilter {
     // the hash count for a key
     k: usize,
@@ -21,22 +40,66 @@ impl BloomFilter {
             bits_per_key,
         }
     }
+
     fn bloom_hash(data: &[u8]) -> u32 {
         hash(data, 0xc6a4a793)
     }
+
+    // Returns the byte offset of the probing window for hash `h` in a filter
+    // with `bytes` bytes of bits and the size of the window in bits.
+    //
+    // The bits are split evenly into `ceil(bytes / 64)` windows so every
+    // window is no larger than a cache line.
+    #[inline]
+    fn window(h: u32, bytes: usize) -> (usize, u32) {
+        if bytes <= CACHE_LINE_SIZE {
+            return (0, (bytes * 8) as u32);
+        }
+        let windows = bytes.div_ceil(CACHE_LINE_SIZE);
+        let window_size = bytes / windows;
+        // Remix the hash so that the window choice is independent of the
+        // bit positions inside the window
+        let remixed = h.wrapping_mul(0x9e37_79b9);
+        let index = (u64::from(remixed) * windows as u64) >> 32;
+        (index as usize * window_size, (window_size * 8) as u32)
+    }
+
+    #[inline]
+    fn probe(bits: &[u8], k: u8, mut h: u32) -> bool {
+        let (start, window_bits) = Self::window(h, bits.len());
+        let delta = h.rotate_right(17);
+        for _ in 0..k {
+            let bit_pos = h % window_bits;
+            if (bits[start + (bit_pos / 8) as usize] & (1 << (bit_pos % 8))) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+
+    #[inline]
+    fn prefetch(bits: &[u8], offset: usize) {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            _mm_prefetch::<_MM_HINT_T0>(bits.as_ptr().add(offset) as *const i8);
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = (bits, offset);
+    }
 }
 
 impl FilterPolicy for BloomFilter {
     fn name(&self) -> &str {
-        "wickdb.BuiltinBloomFilter"
+        "wickdb.BuiltinBloomFilter2"
     }
 
     fn may_contain(&self, filter: &[u8], key: &[u8]) -> bool {
-        let n = filter.len() - 1; // exclude the k
-        if filter.is_empty() || n < 1 {
+        if filter.len() < 2 {
             return false;
-        };
-        let bits = n * 8;
+        }
+        let n = filter.len() - 1; // exclude the k
 
         // Use the encoded k so that we can read filters generated by
         // bloom filters created using different parameters.
@@ -46,21 +109,55 @@ impl FilterPolicy for BloomFilter {
             // Consider it a match.
             return true;
         };
-        let mut h = Self::bloom_hash(key);
-        let delta = (h >> 17) | (h << 15); // rotate right 17 bits
-        for _ in 0..k {
-            let bit_pos = h % (bits as u32);
-            if (filter[(bit_pos / 8) as usize] & (1 << (bit_pos % 8))) == 0 {
-                return false;
-            }
-            h = h.wrapping_add(delta);
+        Self::probe(&filter[..n], k, Self::bloom_hash(key))
+    }
+
+    fn may_contain_many(&self, filter: &[u8], keys: &[&[u8]]) -> Vec<bool> {
+        if filter.len() < 2 {
+            return vec![false; keys.len()];
         }
-        true
+        let n = filter.len() - 1;
+        let k = filter[n];
+        if k > 30 {
+            return vec![true; keys.len()];
+        }
+        let bits = &filter[..n];
+        // Hash all the keys and issue the memory loads of their windows
+        // before probing any of them, so the cache misses overlap.
+        let hashes: Vec<u32> = keys
+            .iter()
+            .map(|key| {
+                let h = Self::bloom_hash(key);
+                let (start, _) = Self::window(h, n);
+                Self::prefetch(bits, start);
+                h
+            })
+            .collect();
+        hashes
+            .into_iter()
+            .map(|h| Self::probe(bits, k, h))
+            .collect()
     }
 
     fn create_filter(&self, keys: &Vec<&[u8]>) -> Vec<u8> {
+        let hashes: Vec<u32> = keys.iter().map(|key| Self::bloom_hash(key)).collect();
+        Self::encode(self.k, self.bits_per_key, &hashes)
+    }
+
+    fn new_filter_builder(&self) -> Option<Box<dyn FilterBuilder>> {
+        Some(Box::new(BloomFilterBuilder {
+            k: self.k,
+            bits_per_key: self.bits_per_key,
+            hashes: vec![],
+        }))
+    }
+}
+
+impl BloomFilter {
+    // Encodes the keys represented by their bloom hashes into a filter
+    fn encode(k: usize, bits_per_key: usize, hashes: &[u32]) -> Vec<u8> {
         // Compute bloom filter size (in both bits and bytes)
-        let mut bits = keys.len() * self.bits_per_key;
+        let mut bits = hashes.len() * bits_per_key;
 
         // For small n, we can see a very high false positive rate.  Fix it
         // by enforcing a minimum bloom filter length.
@@ -68,17 +165,17 @@ impl FilterPolicy for BloomFilter {
             bits = 64;
         };
         let bytes = (bits + 7) / 8;
-        bits = bytes * 8; // round to multi 8
 
         let mut dst: Vec<u8> = vec![0; bytes + 1]; // the extra place of the length bits
-        dst[bytes] = self.k as u8;
-
-        for key in keys {
-            let mut h = Self::bloom_hash(*key);
-            let delta = (h >> 17) | (h << 15); // rotate right 17 bits
-            for _ in 0..self.k {
-                let bit_pos = h % (bits as u32);
-                dst[(bit_pos / 8) as usize] |= 1 << (bit_pos % 8);
+        dst[bytes] = k as u8;
+
+        for h in hashes {
+            let mut h = *h;
+            let (start, window_bits) = Self::window(h, bytes);
+            let delta = h.rotate_right(17);
+            for _ in 0..k {
+                let bit_pos = h % window_bits;
+                dst[start + (bit_pos / 8) as usize] |= 1 << (bit_pos % 8);
                 h = h.wrapping_add(delta);
             }
         }
@@ -86,6 +183,30 @@ impl FilterPolicy for BloomFilter {
     }
 }
 
+/// A `FilterBuilder` for `BloomFilter` that only keeps the 4 bytes hash of each
+/// added key instead of the key itself.
+pub struct BloomFilterBuilder {
+    k: usize,
+    bits_per_key: usize,
+    hashes: Vec<u32>,
+}
+
+impl FilterBuilder for BloomFilterBuilder {
+    fn add_key(&mut self, key: &[u8]) {
+        self.hashes.push(BloomFilter::bloom_hash(key));
+    }
+
+    fn num_keys(&self) -> usize {
+        self.hashes.len()
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let filter = BloomFilter::encode(self.k, self.bits_per_key, &self.hashes);
+        self.hashes.clear();
+        filter
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -229,4 +350,49 @@ mod tests {
             "mediocre false positive rate is more than expected"
         );
     }
+
+    #[test]
+    fn test_bloom_filter_may_contain_many() {
+        let mut h = Harness::new();
+        for i in 0..10000 {
+            h.add_num(i);
+        }
+        h.build();
+        let keys: Vec<Vec<u8>> = (0..20000u32)
+            .map(|i| {
+                let mut k = vec![0; 4];
+                encode_fixed_32(k.as_mut_slice(), i * 7);
+                k
+            })
+            .collect();
+        let refs: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let got = h.policy.may_contain_many(&h.filter, &refs);
+        assert_eq!(got.len(), refs.len());
+        for (key, res) in refs.iter().zip(got) {
+            assert_eq!(h.policy.may_contain(&h.filter, key), res);
+        }
+        assert!(h.policy.may_contain_many(&[], &refs).iter().all(|r| !r));
+    }
+
+    #[test]
+    fn test_bloom_filter_builder() {
+        let policy = BloomFilter::new(10);
+        let mut builder = policy.new_filter_builder().unwrap();
+        for n in [0u32, 1, 10, 100, 10000].iter() {
+            let keys: Vec<Vec<u8>> = (0..*n)
+                .map(|i| {
+                    let mut k = vec![0; 4];
+                    encode_fixed_32(k.as_mut_slice(), i);
+                    k
+                })
+                .collect();
+            for k in keys.iter() {
+                builder.add_key(k);
+            }
+            assert_eq!(builder.num_keys(), *n as usize);
+            let expect = policy.create_filter(&keys.iter().map(|k| k.as_slice()).collect());
+            assert_eq!(builder.finish(), expect);
+            assert_eq!(builder.num_keys(), 0);
+        }
+    }
 }
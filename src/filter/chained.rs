@@ -0,0 +1,139 @@
+use crate::filter::FilterPolicy;
+use crate::util::varint::VarintU32;
+use std::sync::Arc;
+
+/// The keys a policy chained in a `ChainedFilterPolicy` adds into its filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKeys {
+    /// The whole user keys, which are probed by the point lookups
+    WholeKey,
+    /// The prefixes extracted by `Options::prefix_extractor`, which are probed
+    /// by both the prefix seeks and the point lookups. The policy is ignored
+    /// if there is no prefix extractor.
+    Prefix,
+}
+
+/// `ChainedFilterPolicy` combines several filter policies, e.g. a whole key
+/// bloom filter for the point lookups with a prefix bloom filter for the prefix
+/// seeks.
+///
+/// Set as `Options::filter_policy`, each chained policy builds its own filter
+/// which is stored as a separate meta block in the sstables, and a key is ruled
+/// out if any of the filters rules it out. A table built with a subset of the
+/// policies is still filtered by the filters it has.
+#[derive(Default, Clone)]
+pub struct ChainedFilterPolicy {
+    policies: Vec<(Arc<dyn FilterPolicy>, FilterKeys)>,
+    name: String,
+}
+
+impl ChainedFilterPolicy {
+    /// Creates an empty chain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a policy building its filters from the given kind of keys
+    pub fn chain(mut self, policy: Arc<dyn FilterPolicy>, keys: FilterKeys) -> Self {
+        if !self.name.is_empty() {
+            self.name.push('+');
+        }
+        self.name.push_str(policy.name());
+        if keys == FilterKeys::Prefix {
+            self.name.push_str(".Prefix");
+        }
+        self.policies.push((policy, keys));
+        self
+    }
+
+    /// Returns the chained policies in the order they are added
+    pub fn policies(&self) -> &[(Arc<dyn FilterPolicy>, FilterKeys)] {
+        &self.policies
+    }
+
+    // Splits a filter created by `create_filter` into the filters of the
+    // chained policies. Returns `None` if the filter is malformed.
+    fn split_filter<'a>(&self, mut filter: &'a [u8]) -> Option<Vec<&'a [u8]>> {
+        let mut filters = Vec::with_capacity(self.policies.len());
+        for _ in 0..self.policies.len() {
+            let (len, n) = VarintU32::read(filter)?;
+            let end = n + len as usize;
+            if end > filter.len() {
+                return None;
+            }
+            filters.push(&filter[n..end]);
+            filter = &filter[end..];
+        }
+        Some(filters)
+    }
+}
+
+/// Used alone, the policy encodes the filters of the chained policies into a
+/// single filter, each prefixed by its length. The sstables never store such a
+/// filter since they keep the filters apart.
+impl FilterPolicy for ChainedFilterPolicy {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn may_contain(&self, filter: &[u8], key: &[u8]) -> bool {
+        match self.split_filter(filter) {
+            Some(filters) => self
+                .policies
+                .iter()
+                .zip(filters)
+                .all(|((policy, _), f)| policy.may_contain(f, key)),
+            None => true,
+        }
+    }
+
+    fn may_contain_prefix(&self, filter: &[u8], prefix: &[u8]) -> bool {
+        match self.split_filter(filter) {
+            Some(filters) => self
+                .policies
+                .iter()
+                .zip(filters)
+                .all(|((policy, _), f)| policy.may_contain_prefix(f, prefix)),
+            None => true,
+        }
+    }
+
+    fn create_filter(&self, keys: &Vec<&[u8]>) -> Vec<u8> {
+        let mut dst = vec![];
+        for (policy, _) in self.policies.iter() {
+            let filter = policy.create_filter(keys);
+            VarintU32::put_varint(&mut dst, filter.len() as u32);
+            dst.extend_from_slice(&filter);
+        }
+        dst
+    }
+
+    fn as_chained(&self) -> Option<&ChainedFilterPolicy> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::bloom::BloomFilter;
+
+    #[test]
+    fn test_chained_filter_policy() {
+        let policy = ChainedFilterPolicy::new()
+            .chain(Arc::new(BloomFilter::new(10)), FilterKeys::WholeKey)
+            .chain(Arc::new(BloomFilter::new(10)), FilterKeys::Prefix);
+        assert_eq!(
+            policy.name(),
+            "wickdb.BuiltinBloomFilter2+wickdb.BuiltinBloomFilter2.Prefix"
+        );
+        assert_eq!(policy.policies().len(), 2);
+        let keys: Vec<&[u8]> = vec![b"hello", b"world"];
+        let filter = policy.create_filter(&keys);
+        assert!(policy.may_contain(&filter, b"hello"));
+        assert!(policy.may_contain(&filter, b"world"));
+        assert!(!policy.may_contain(&filter, b"foo"));
+        // A malformed filter rules out nothing
+        assert!(policy.may_contain(&filter[..3], b"foo"));
+    }
+}
@@ -1,4 +1,8 @@
 pub mod bloom;
+pub mod chained;
+
+use crate::filter::chained::ChainedFilterPolicy;
+use std::sync::Arc;
 
 /// `FilterPolicy` is an algorithm for probabilistically encoding a set of keys.
 /// The canonical implementation is a Bloom filter.
@@ -24,6 +28,64 @@ pub trait FilterPolicy: Send + Sync {
     /// original set.
     fn may_contain(&self, filter: &[u8], key: &[u8]) -> bool;
 
+    /// Probes a batch of keys against the same encoded filter. The i-th
+    /// element of the result is `may_contain(filter, keys[i])`.
+    ///
+    /// Policies could override this to amortize the memory latency of the
+    /// probes across the whole batch.
+    fn may_contain_many(&self, filter: &[u8], keys: &[&[u8]]) -> Vec<bool> {
+        keys.iter()
+            .map(|key| self.may_contain(filter, key))
+            .collect()
+    }
+
+    /// Returns whether the encoded filter may contain a key with the given
+    /// prefix. This is the probe of a prefix seek while `may_contain` is the
+    /// probe of a point lookup.
+    ///
+    /// Returns true by default since a policy knows nothing about prefixes
+    /// unless it adds them into the filters.
+    fn may_contain_prefix(&self, _filter: &[u8], _prefix: &[u8]) -> bool {
+        true
+    }
+
     /// Creates a filter based on given keys
     fn create_filter(&self, keys: &Vec<&[u8]>) -> Vec<u8>;
+
+    /// Returns a `FilterBuilder` that digests keys one by one so the caller
+    /// does not have to keep all the keys in memory until the filter is built.
+    ///
+    /// Returns `None` if the policy can only build a filter from the whole key
+    /// set by `create_filter`. This is the default.
+    fn new_filter_builder(&self) -> Option<Box<dyn FilterBuilder>> {
+        None
+    }
+
+    /// Returns the chain if the policy is a `ChainedFilterPolicy`, whose
+    /// policies build the separate filters of a table.
+    fn as_chained(&self) -> Option<&ChainedFilterPolicy> {
+        None
+    }
+}
+
+/// Returns the policies building the separate filters of a table
+pub(crate) fn table_filter_policies(policy: &Arc<dyn FilterPolicy>) -> Vec<Arc<dyn FilterPolicy>> {
+    match policy.as_chained() {
+        Some(chain) => chain.policies().iter().map(|(p, _)| p.clone()).collect(),
+        None => vec![policy.clone()],
+    }
+}
+
+/// `FilterBuilder` builds a filter incrementally. The filter it produces must
+/// be the same as the one `create_filter` produces for the same keys.
+pub trait FilterBuilder: Send {
+    /// Adds a key into the filter being built
+    fn add_key(&mut self, key: &[u8]);
+
+    /// Returns the number of keys added since the last `finish`
+    fn num_keys(&self) -> usize;
+
+    /// Encodes all the keys added so far into a filter and resets the builder
+    /// for the next filter
+    fn finish(&mut self) -> Vec<u8>;
 }
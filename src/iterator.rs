@@ -33,6 +33,44 @@ pub trait Iterator {
     fn status(&mut self) -> Result<()>;
 }
 
+impl<I: Iterator + ?Sized> Iterator for Box<I> {
+    fn valid(&self) -> bool {
+        (**self).valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        (**self).seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        (**self).seek_to_last()
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        (**self).seek(target)
+    }
+
+    fn next(&mut self) {
+        (**self).next()
+    }
+
+    fn prev(&mut self) {
+        (**self).prev()
+    }
+
+    fn key(&self) -> &[u8] {
+        (**self).key()
+    }
+
+    fn value(&self) -> &[u8] {
+        (**self).value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        (**self).status()
+    }
+}
+
 /// A concatenated iterator contains an original iterator `origin` and a `DerivedIterFactory`.
 /// New derived iterator is generated by `factory(origin.value())`.
 /// The origin Iterator should yield out the last key but not the first.
@@ -78,7 +116,8 @@ impl<I: Iterator, F: DerivedIterFactory> ConcatenateIterator<I, F> {
     // Same as `InitDataBlock` in C++ implementation
     fn init_derived_iter(&mut self) {
         if !self.origin.valid() {
-            self.derived = None
+            // Keeps the error of the dropped derived iter
+            self.set_derived(None)
         } else {
             let v = self.origin.value();
             if self.derived.is_none()
@@ -390,91 +429,95 @@ impl<T: KMergeCore> Iterator for KMergeIter<T> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::iterator::*;
-    use crate::rand::Rng;
-    use crate::util::comparator::BytewiseComparator;
-    use crate::Result;
-    use std::cmp::Ordering;
-    use std::str;
+/// An helper to merge several `I` in merge iterating style
+pub struct SimpleKMerger<I: Iterator, C: Comparator> {
+    cmp: C,
+    children: Vec<I>,
+}
 
-    /// An helper to merge several `I` in merge iterating style
-    struct SimpleKMerger<I: Iterator, C: Comparator> {
-        cmp: C,
-        children: Vec<I>,
+impl<I: Iterator, C: Comparator> SimpleKMerger<I, C> {
+    pub fn new(cmp: C, children: Vec<I>) -> Self {
+        Self { cmp, children }
     }
+}
 
-    impl<I: Iterator, C: Comparator> KMergeCore for SimpleKMerger<I, C> {
-        type Cmp = C;
-        fn cmp(&self) -> &Self::Cmp {
-            &self.cmp
-        }
+impl<I: Iterator, C: Comparator> KMergeCore for SimpleKMerger<I, C> {
+    type Cmp = C;
+    fn cmp(&self) -> &Self::Cmp {
+        &self.cmp
+    }
 
-        fn iters_len(&self) -> usize {
-            self.children.len()
-        }
+    fn iters_len(&self) -> usize {
+        self.children.len()
+    }
 
-        fn find_smallest(&mut self) -> usize {
-            let mut smallest: Option<&[u8]> = None;
-            let mut index = self.iters_len();
-            for (i, child) in self.children.iter().enumerate() {
-                if self.smaller(&mut smallest, child) {
-                    index = i
-                }
+    fn find_smallest(&mut self) -> usize {
+        let mut smallest: Option<&[u8]> = None;
+        let mut index = self.iters_len();
+        for (i, child) in self.children.iter().enumerate() {
+            if self.smaller(&mut smallest, child) {
+                index = i
             }
-            index
         }
+        index
+    }
 
-        fn find_largest(&mut self) -> usize {
-            let mut largest: Option<&[u8]> = None;
-            let mut index = self.iters_len();
-            for (i, child) in self.children.iter().enumerate() {
-                if self.larger(&mut largest, child) {
-                    index = i
-                }
+    fn find_largest(&mut self) -> usize {
+        let mut largest: Option<&[u8]> = None;
+        let mut index = self.iters_len();
+        for (i, child) in self.children.iter().enumerate() {
+            if self.larger(&mut largest, child) {
+                index = i
             }
-            index
         }
+        index
+    }
 
-        fn get_child(&self, i: usize) -> &dyn Iterator {
-            self.children.get(i).unwrap() as &dyn Iterator
-        }
+    fn get_child(&self, i: usize) -> &dyn Iterator {
+        self.children.get(i).unwrap() as &dyn Iterator
+    }
 
-        fn get_child_mut(&mut self, i: usize) -> &mut dyn Iterator {
-            self.children.get_mut(i).unwrap() as &mut dyn Iterator
-        }
+    fn get_child_mut(&mut self, i: usize) -> &mut dyn Iterator {
+        self.children.get_mut(i).unwrap() as &mut dyn Iterator
+    }
 
-        fn for_each_child<F>(&mut self, mut f: F)
-        where
-            F: FnMut(&mut dyn Iterator),
-        {
-            self.children
-                .iter_mut()
-                .for_each(|i| f(i as &mut dyn Iterator));
-        }
+    fn for_each_child<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut dyn Iterator),
+    {
+        self.children
+            .iter_mut()
+            .for_each(|i| f(i as &mut dyn Iterator));
+    }
 
-        fn for_not_ith<F>(&mut self, n: usize, mut f: F)
-        where
-            F: FnMut(&mut dyn Iterator, &Self::Cmp),
-        {
-            for (i, child) in self.children.iter_mut().enumerate() {
-                if i != n {
-                    f(child as &mut dyn Iterator, &self.cmp)
-                }
+    fn for_not_ith<F>(&mut self, n: usize, mut f: F)
+    where
+        F: FnMut(&mut dyn Iterator, &Self::Cmp),
+    {
+        for (i, child) in self.children.iter_mut().enumerate() {
+            if i != n {
+                f(child as &mut dyn Iterator, &self.cmp)
             }
         }
+    }
 
-        fn take_err(&mut self) -> Result<()> {
-            for i in self.children.iter_mut() {
-                let status = i.status();
-                if status.is_err() {
-                    return status;
-                }
-            }
-            Ok(())
+    fn take_err(&mut self) -> Result<()> {
+        for i in self.children.iter_mut() {
+            i.status()?;
         }
+        Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iterator::*;
+    use crate::rand::Rng;
+    use crate::util::comparator::BytewiseComparator;
+    use crate::Result;
+    use std::cmp::Ordering;
+    use std::str;
+
     // Divide given ordered `src` into `n` lists and then construct a `MergingIterator` with them
     fn new_test_merging_iter(
         mut src: Vec<String>,
@@ -15,6 +15,8 @@ extern crate quick_error;
 extern crate rand;
 extern crate snap;
 
+#[macro_use]
+mod trace;
 pub mod batch;
 pub mod cache;
 mod util;
@@ -32,18 +34,76 @@ mod snapshot;
 mod sstable;
 pub mod storage;
 mod table_cache;
+pub mod transaction;
 mod version;
 
-pub use batch::WriteBatch;
+pub use batch::{WriteBatch, WriteBatchHandler};
 pub use cache::Cache;
 pub use compaction::ManualCompaction;
+#[cfg(feature = "admin-http")]
+pub use db::admin::AdminServer;
+pub use db::batched_writer::{BatchedWriter, BatchedWriterOptions, PendingWrite};
+pub use db::checkpoint::Checkpoint;
+pub use db::column_family::{ColumnFamilyHandle, ColumnFamilyIterator, COLUMN_FAMILY_KEY_PREFIX};
+pub use db::consistency_check::{
+    check_consistency, ConsistencyCheckOptions, ConsistencyReport, Violation, ViolationKind,
+};
+pub use db::dump::{
+    check_live_files, dump_manifest, dump_wal, LiveFilesReport, ManifestSummary, WalSummary,
+};
+pub use db::fence::RangeFence;
+pub use db::format::{parse_internal_key, InternalKey, ParsedInternalKey, ReadableKey, ValueType};
+pub use db::orphan::OrphanFilesReport;
+pub use db::persistent_stats::{DBStats, StatsSnapshot, PERSISTENT_STATS_CF};
+pub use db::read_stats::{LevelLatencyStats, ReadStats};
+pub use db::recovery::RecoveryReport;
+pub use db::repair::{repair_db, RepairReport};
+pub use db::scrub::{ScrubListener, ScrubStats};
+pub use db::shadow::{Divergence, ShadowDB, ShadowIterator, ShadowOptions};
+pub use db::wal_updates::WalUpdatesIterator;
+pub use db::write_stall::{WriteStallCondition, WriteStallListener, WriteStallReason};
 pub use db::{WickDB, DB};
 pub use error::{Error, Result};
 pub use filter::bloom::BloomFilter;
+pub use filter::chained::{ChainedFilterPolicy, FilterKeys};
 pub use iterator::Iterator;
 pub use log::{LevelFilter, Log};
-pub use options::{CompressionType, Options, ReadOptions, WriteOptions};
+pub use mem::arena::{ArenaBlockPool, ArenaOptions, BlockCacheAllocator, ChunkedArena};
+pub use mem::rep::{
+    HashSkipListRepFactory, MemTableRep, MemTableRepFactory, SkipListRepFactory, VectorRepFactory,
+};
+pub use mem::write_buffer_manager::WriteBufferManager;
+pub use options::{
+    BackgroundPanicPolicy, BottommostLevelCompaction, ChecksumType, ColdStore, CompactRangeOptions,
+    CompactionDecision, CompactionFilter, CompactionPri, CompactionStyle, CompressionType,
+    IndexType, IngestOptions, MergeOperator, OpenMode, Options, PreCommitHook, ReadOptions,
+    UniversalCompactionOptions, ValueProjector, WriteOptions,
+};
 pub use sstable::block::Block;
+pub use sstable::bulk_load::BulkLoader;
+pub use sstable::cuckoo::{
+    CuckooTable, CuckooTableBuilder, CuckooTableFactory, CuckooTableOptions,
+};
+pub use sstable::dedup::{analyze_dedup, Chunking, DedupStats};
+pub use sstable::dump::{dump_table, DumpOptions, DumpStats};
+pub use sstable::factory::{
+    BlockBasedTableFactory, TableEntryHandler, TableFactory, TableReader, TableReaderIterator,
+    TableWriter,
+};
+pub use sstable::merge::{merge_ssts, MergeOutput};
+pub use sstable::properties::{
+    TableProperties, TablePropertiesCollector, TablePropertiesCollectorFactory,
+};
+pub use sstable::split::{split_table, SplitOutput};
+pub use sstable::sst_file::{ExternalSstFileInfo, SstFileIterator, SstFileReader, SstFileWriter};
 pub use storage::*;
-pub use util::comparator::{BytewiseComparator, Comparator};
+pub use transaction::optimistic::{OptimisticTransaction, OptimisticTransactionDB};
+pub use transaction::pessimistic::{Transaction, TransactionDB, TransactionDBOptions};
+pub use util::comparator::{
+    key_with_timestamp, split_timestamp, BigEndianU64Comparator, BytewiseComparator,
+    BytewiseComparatorWithU64Ts, Comparator, TIMESTAMP_SIZE,
+};
+pub use util::histogram::HistogramData;
+pub use util::slice_transform::{FixedPrefixTransform, SliceTransform};
 pub use util::varint::*;
+pub use version::manifest_builder::ManifestBuilder;
@@ -1,5 +1,7 @@
 use std::cell::RefCell;
+use std::fmt;
 use std::mem;
+use std::ops::Deref;
 use std::ptr;
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
@@ -15,12 +17,31 @@ pub trait Arena {
     unsafe fn allocate<T>(&self, chunk: usize, align: usize) -> *mut T;
     /// Return the size of memory that has been allocated.
     fn memory_used(&self) -> usize;
+    /// Returns the memory held by the arena, including the unused part of its
+    /// blocks
+    fn approximate_memory_usage(&self) -> usize {
+        self.memory_used()
+    }
+}
+
+/// How the memory of an `OffsetArena` is allocated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArenaOptions {
+    /// If not 0, the arena is backed by the huge pages of this size (e.g. 2MB),
+    /// which must be a power of 2. It falls back to the normal pages if no
+    /// huge page is available, which is always the case on the systems other
+    /// than Linux.
+    pub huge_page_size: usize,
+    /// Whether all the pages are faulted in when the arena is created, so the
+    /// allocations never wait for the page faults
+    pub preallocate: bool,
 }
 
 struct OffsetArenaInner {
     len: AtomicUsize,  // 起始偏移量
     cap: usize,        // arena容量
     ptr: *mut u8,      //分配在堆上的指针
+    mapped: bool,      // 是否是 mmap 映射的大页
 }
 
 #[derive(Clone)]
@@ -31,13 +52,7 @@ pub struct OffsetArena {
 impl Drop for OffsetArenaInner {
     fn drop(&mut self) {
         // manully drop ArenaInner
-        if !self.ptr.is_null() {
-            unsafe {
-                let ptr = self.ptr as *mut u64;
-                let cap = self.cap / 8;
-                Vec::from_raw_parts(ptr, 0, cap);
-            }
-        }
+        free_buffer(self.ptr, self.cap, self.mapped);
     }
 }
 
@@ -61,20 +76,39 @@ unsafe impl Sync for OffsetArena {}
 impl OffsetArena {
     // The real cap will be aligned with 8
     pub fn with_capacity(cap: usize) -> Self {
-        let mut buf: Vec<u64> = Vec::with_capacity(cap / 8);
-        let ptr = buf.as_mut_ptr() as *mut u8;
-        let cap = buf.capacity() * 8;
-        // 防止内存释放  防止buf 离开作用域时自动释放内存
-        mem::forget(buf);
+        Self::with_options(cap, ArenaOptions::default())
+    }
+
+    /// Creates an arena of `cap` bytes allocated as `options`. The real cap is
+    /// aligned with 8, or with the huge page size if backed by huge pages.
+    pub fn with_options(cap: usize, options: ArenaOptions) -> Self {
+        let (ptr, cap, mapped) = alloc_buffer(cap, options);
         OffsetArena {
             inner: Arc::new(OffsetArenaInner {
                 len: AtomicUsize::new(1),
                 cap,
                 ptr,
+                mapped,
             }),
         }
     }
 
+    // Maps the huge pages for `cap` bytes if required, returning the pointer
+    // and the cap rounded up to the huge page size
+    fn map(cap: usize, options: ArenaOptions) -> Option<(*mut u8, usize)> {
+        let page = options.huge_page_size;
+        if page == 0 || !page.is_power_of_two() {
+            return None;
+        }
+        let cap = (cap.max(1) + page - 1) & !(page - 1);
+        map_huge_pages(cap, page, options.preallocate).map(|ptr| (ptr, cap))
+    }
+
+    /// Returns true if the arena is backed by huge pages
+    pub fn is_huge_page_backed(&self) -> bool {
+        self.inner.mapped
+    }
+
     // Allocates `size` bytes aligned with `align`
     //  返回指针偏移
     fn alloc(&self, align: usize, size: usize) -> Result<usize, String> {
@@ -121,6 +155,418 @@ impl OffsetArena {
 
 
 
+// Allocates a buffer of `cap` bytes as `options`, returning the pointer, the
+// real cap and whether the buffer is mapped from the huge pages
+fn alloc_buffer(cap: usize, options: ArenaOptions) -> (*mut u8, usize, bool) {
+    match OffsetArena::map(cap, options) {
+        Some((ptr, cap)) => (ptr, cap, true),
+        None => {
+            let mut buf: Vec<u64> = Vec::with_capacity(cap / 8);
+            let ptr = buf.as_mut_ptr() as *mut u8;
+            let cap = buf.capacity() * 8;
+            // 防止内存释放  防止buf 离开作用域时自动释放内存
+            mem::forget(buf);
+            if options.preallocate {
+                // 写入每一页，让缺页在创建时而不是分配时发生
+                unsafe { ptr::write_bytes(ptr, 0, cap) };
+            }
+            (ptr, cap, false)
+        }
+    }
+}
+
+// Frees a buffer returned by `alloc_buffer`
+fn free_buffer(ptr: *mut u8, cap: usize, mapped: bool) {
+    if mapped {
+        unmap_huge_pages(ptr, cap);
+    } else if !ptr.is_null() {
+        unsafe {
+            Vec::from_raw_parts(ptr as *mut u64, 0, cap / 8);
+        }
+    }
+}
+
+// Maps `cap` bytes of anonymous huge pages of the given size, or returns `None`
+// if there aren't enough huge pages reserved
+#[cfg(target_os = "linux")]
+fn map_huge_pages(cap: usize, huge_page_size: usize, populate: bool) -> Option<*mut u8> {
+    let mut flags = libc::MAP_PRIVATE
+        | libc::MAP_ANONYMOUS
+        | libc::MAP_HUGETLB
+        | ((huge_page_size.trailing_zeros() as libc::c_int) << libc::MAP_HUGE_SHIFT);
+    if populate {
+        flags |= libc::MAP_POPULATE;
+    }
+    let ptr = unsafe {
+        libc::mmap(
+            null_mut(),
+            cap,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        None
+    } else {
+        Some(ptr as *mut u8)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_huge_pages(_cap: usize, _huge_page_size: usize, _populate: bool) -> Option<*mut u8> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn unmap_huge_pages(ptr: *mut u8, cap: usize) {
+    unsafe {
+        libc::munmap(ptr as *mut libc::c_void, cap);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmap_huge_pages(_ptr: *mut u8, _cap: usize) {}
+
+// The range of the block size of a `ChunkedArena`
+const MIN_CHUNK_SIZE: usize = 4096;
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+/// Returns the block size of the `ChunkedArena` of a memtable with the given
+/// write buffer size. The arena only holds the skiplist nodes while the entries
+/// are allocated separately, so the blocks are much smaller than the buffer.
+pub fn chunk_size_for(write_buffer_size: usize) -> usize {
+    (write_buffer_size / 32).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+// A block of a `ChunkedArena`
+struct Chunk {
+    // The requested size, which is smaller than `cap` if rounded up to the
+    // huge page size
+    size: usize,
+    ptr: *mut u8,
+    cap: usize,
+    mapped: bool,
+    // 下一次分配的偏移，分配失败后可能超过 `cap`
+    used: AtomicUsize,
+}
+
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+impl Chunk {
+    fn new(size: usize, options: ArenaOptions) -> Self {
+        // The cap of a buffer is aligned down with 8
+        let (ptr, cap, mapped) = alloc_buffer((size + 7) & !7, options);
+        Self {
+            size,
+            ptr,
+            cap,
+            mapped,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    // Maps the huge pages for a block of `size` bytes, or returns `None` if no huge
+    // page is available
+    fn map(size: usize, options: ArenaOptions) -> Option<Self> {
+        OffsetArena::map(size, options).map(|(ptr, cap)| Self {
+            size,
+            ptr,
+            cap,
+            mapped: true,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    // Allocates `size` bytes aligned with `align` like `OffsetArena::alloc`.
+    // Returns null if the block has no room.
+    fn alloc(&self, size: usize, align: usize) -> *mut u8 {
+        let align_mask = align - 1;
+        let size = size + align_mask;
+        let offset = self.used.fetch_add(size, Ordering::SeqCst);
+        if offset + size > self.cap {
+            return null_mut();
+        }
+        let addr = self.ptr as usize + offset;
+        ((addr + align_mask) & !align_mask) as *mut u8
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        free_buffer(self.ptr, self.cap, self.mapped);
+    }
+}
+
+/// `ArenaBlockPool` keeps the last block of a dropped `ChunkedArena`, which is
+/// reused as the first block of the next arena created with the same pool. A
+/// db rotating its memtables hence doesn't return the block to the allocator
+/// and fault its pages in again for every memtable.
+#[derive(Default)]
+pub struct ArenaBlockPool {
+    block: Mutex<Option<Chunk>>,
+}
+
+impl ArenaBlockPool {
+    /// Returns the bytes of the block kept in the pool
+    pub fn pooled_bytes(&self) -> usize {
+        self.block.lock().unwrap().as_ref().map_or(0, |c| c.cap)
+    }
+
+    // Takes the pooled block if its size is `size`
+    fn take(&self, size: usize) -> Option<Chunk> {
+        let mut block = self.block.lock().unwrap();
+        if block.as_ref().is_some_and(|c| c.size == size) {
+            block.take().inspect(|c| c.used.store(0, Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    fn put(&self, chunk: Chunk) {
+        *self.block.lock().unwrap() = Some(chunk);
+    }
+}
+
+/// `BlockCacheAllocator` copies the blocks kept in the block cache into the
+/// huge pages, which reduces the TLB misses of a large block cache. The blocks
+/// are packed into regions of one huge page, and a region is unmapped once all
+/// the blocks in it are dropped, so a few blocks still cached may pin a region.
+///
+/// A block larger than a quarter of the huge page gets its dedicated region.
+/// If no huge page is available, the blocks stay in the normal memory.
+pub struct BlockCacheAllocator {
+    options: ArenaOptions,
+    // 当前用于分配的区域
+    current: Mutex<Option<Arc<Region>>>,
+    // 所有未解除映射的区域的字节数
+    mapped: Arc<AtomicUsize>,
+}
+
+// A region of huge pages shared by the blocks copied into it
+struct Region {
+    chunk: Chunk,
+    mapped: Arc<AtomicUsize>,
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        self.mapped.fetch_sub(self.chunk.cap, Ordering::SeqCst);
+    }
+}
+
+impl BlockCacheAllocator {
+    /// Creates an allocator mapping the huge pages as `options`. The blocks are
+    /// never copied if `options.huge_page_size` is 0.
+    pub fn new(options: ArenaOptions) -> Self {
+        Self {
+            options,
+            current: Mutex::new(None),
+            mapped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the bytes of the huge pages mapped and not unmapped yet
+    pub fn mapped_bytes(&self) -> usize {
+        self.mapped.load(Ordering::SeqCst)
+    }
+
+    /// Copies `data` into the huge pages, or returns `None` if no huge page is
+    /// available
+    pub fn copy(&self, data: &[u8]) -> Option<AllocatedBytes> {
+        let page = self.options.huge_page_size;
+        if page == 0 || data.is_empty() {
+            return None;
+        }
+        let (region, ptr) = if data.len() > page / 4 {
+            let region = self.new_region(data.len())?;
+            let ptr = region.chunk.alloc(data.len(), 1);
+            (region, ptr)
+        } else {
+            let mut current = self.current.lock().unwrap();
+            let ptr = current
+                .as_ref()
+                .map_or(null_mut(), |r| r.chunk.alloc(data.len(), 1));
+            if ptr.is_null() {
+                // The full region is released once its blocks are dropped
+                *current = self.new_region(page);
+                let region = current.clone()?;
+                let ptr = region.chunk.alloc(data.len(), 1);
+                (region, ptr)
+            } else {
+                (current.clone().unwrap(), ptr)
+            }
+        };
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+        Some(AllocatedBytes {
+            _region: region,
+            ptr,
+            len: data.len(),
+        })
+    }
+
+    fn new_region(&self, size: usize) -> Option<Arc<Region>> {
+        let chunk = Chunk::map(size, self.options)?;
+        self.mapped.fetch_add(chunk.cap, Ordering::SeqCst);
+        Some(Arc::new(Region {
+            chunk,
+            mapped: self.mapped.clone(),
+        }))
+    }
+}
+
+/// The bytes copied into the huge pages by `BlockCacheAllocator`, which keep
+/// their region mapped
+pub struct AllocatedBytes {
+    _region: Arc<Region>,
+    ptr: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for AllocatedBytes {}
+unsafe impl Sync for AllocatedBytes {}
+
+impl Deref for AllocatedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl fmt::Debug for AllocatedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AllocatedBytes")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// `ChunkedArena` allocates the memory from the blocks of a fixed size, which
+/// are allocated only when the previous one is used up, so an arena never
+/// runs out of memory and the memory it holds is known precisely by
+/// `approximate_memory_usage`.
+///
+/// An allocation larger than a quarter of the block size gets a dedicated
+/// block so the rest of the current block isn't wasted.
+#[derive(Clone)]
+pub struct ChunkedArena {
+    inner: Arc<ChunkedArenaInner>,
+}
+
+struct ChunkedArenaInner {
+    chunk_size: usize,
+    options: ArenaOptions,
+    pool: Option<Arc<ArenaBlockPool>>,
+    // 最后一个是当前用于分配的块
+    chunks: RwLock<Vec<Chunk>>,
+    // 已经分配出去的字节数
+    allocated: AtomicUsize,
+    // 所有块的字节数
+    reserved: AtomicUsize,
+}
+
+impl Drop for ChunkedArenaInner {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            let chunks = self.chunks.get_mut().unwrap();
+            if let Some(chunk) = chunks.pop() {
+                if chunk.size == self.chunk_size {
+                    pool.put(chunk);
+                }
+            }
+        }
+    }
+}
+
+impl ChunkedArena {
+    /// Creates an arena allocating the blocks of `chunk_size` bytes as `options`
+    pub fn new(chunk_size: usize, options: ArenaOptions) -> Self {
+        Self::with_pool(chunk_size, options, None)
+    }
+
+    /// Creates an arena taking its first block from `pool` if possible and
+    /// returning its last block to `pool` when dropped
+    pub fn with_pool(
+        chunk_size: usize,
+        options: ArenaOptions,
+        pool: Option<Arc<ArenaBlockPool>>,
+    ) -> Self {
+        assert!(chunk_size > 0, "[arena] the block size must be positive");
+        Self {
+            inner: Arc::new(ChunkedArenaInner {
+                chunk_size,
+                options,
+                pool,
+                chunks: RwLock::new(vec![]),
+                allocated: AtomicUsize::new(0),
+                reserved: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    // Returns a new block of `size` bytes, which is taken from the pool if possible
+    fn new_chunk(&self, size: usize) -> Chunk {
+        let inner = &self.inner;
+        let chunk = inner
+            .pool
+            .as_ref()
+            .and_then(|pool| pool.take(size))
+            .unwrap_or_else(|| Chunk::new(size, inner.options));
+        inner.reserved.fetch_add(chunk.cap, Ordering::SeqCst);
+        chunk
+    }
+}
+
+impl Arena for ChunkedArena {
+    unsafe fn allocate<T>(&self, chunk: usize, align: usize) -> *mut T {
+        assert!(chunk > 0 && align.is_power_of_two());
+        let inner = &self.inner;
+        let needed = chunk + align - 1;
+        if needed > inner.chunk_size / 4 {
+            let block = self.new_chunk(needed);
+            let p = block.alloc(chunk, align);
+            let mut chunks = inner.chunks.write().unwrap();
+            // Keep the current block as the last one
+            let i = chunks.len().saturating_sub(1);
+            chunks.insert(i, block);
+            inner.allocated.fetch_add(needed, Ordering::SeqCst);
+            return p as *mut T;
+        }
+        {
+            let chunks = inner.chunks.read().unwrap();
+            if let Some(p) = chunks.last().map(|c| c.alloc(chunk, align)) {
+                if !p.is_null() {
+                    inner.allocated.fetch_add(needed, Ordering::SeqCst);
+                    return p as *mut T;
+                }
+            }
+        }
+        let mut chunks = inner.chunks.write().unwrap();
+        // Another thread may have added a block
+        let mut p = chunks
+            .last()
+            .map_or(null_mut(), |c| c.alloc(chunk, align));
+        if p.is_null() {
+            let block = self.new_chunk(inner.chunk_size);
+            p = block.alloc(chunk, align);
+            chunks.push(block);
+        }
+        inner.allocated.fetch_add(needed, Ordering::SeqCst);
+        p as *mut T
+    }
+
+    fn memory_used(&self) -> usize {
+        self.inner.allocated.load(Ordering::SeqCst)
+    }
+
+    fn approximate_memory_usage(&self) -> usize {
+        self.inner.reserved.load(Ordering::SeqCst)
+    }
+}
+
 /// `BlockArena` 是一个线程安全的内存池，用于动态分配和管理 Node 内存。
 #[derive(Clone)]
 pub struct BlockArena {
@@ -247,7 +693,11 @@ unsafe impl Sync for BlockArena {}
 
 #[cfg(test)]
 mod tests {
-    use crate::mem::arena::{ Arena, BlockArena, OffsetArena, BLOCK_SIZE};
+    use crate::mem::arena::{
+        Arena, ArenaBlockPool, ArenaOptions, BlockArena, BlockCacheAllocator, ChunkedArena,
+        OffsetArena, BLOCK_SIZE,
+    };
+    use std::sync::Arc;
     use rand::Rng;
     use std::{mem, ptr};
     use std::sync::atomic::Ordering;
@@ -383,6 +833,167 @@ mod tests {
         assert_eq!(arena.memory_used(), 10 * 1000 * mem::size_of::<AlignedStruct>() + 1 );
     }
 
+    #[test]
+    fn test_offset_arena_options() {
+        for options in [
+            ArenaOptions {
+                huge_page_size: 0,
+                preallocate: true,
+            },
+            // Falls back to the normal pages without huge pages reserved
+            ArenaOptions {
+                huge_page_size: 2 << 20,
+                preallocate: false,
+            },
+            ArenaOptions {
+                huge_page_size: 2 << 20,
+                preallocate: true,
+            },
+        ] {
+            let arena = OffsetArena::with_options(1 << 20, options);
+            if arena.is_huge_page_backed() {
+                assert_eq!(arena.inner.cap, 2 << 20);
+            } else {
+                assert_eq!(arena.inner.cap, 1 << 20);
+            }
+            let align = mem::align_of::<AlignedStruct>();
+            let mut allocated = vec![];
+            for i in 0..1000 {
+                let ptr = unsafe { arena.allocate::<AlignedStruct>(64, align) };
+                assert!(!ptr.is_null());
+                unsafe { (*ptr).data = [(i % 256) as u8; 64] };
+                allocated.push(ptr);
+            }
+            for (i, ptr) in allocated.into_iter().enumerate() {
+                assert_eq!(unsafe { (*ptr).data }, [(i % 256) as u8; 64]);
+            }
+            assert_eq!(arena.memory_used(), 1000 * 64 + 1);
+        }
+    }
+
+    #[test]
+    fn test_block_cache_allocator() {
+        let allocator = BlockCacheAllocator::new(ArenaOptions::default());
+        assert!(allocator.copy(b"block").is_none());
+
+        let allocator = BlockCacheAllocator::new(ArenaOptions {
+            huge_page_size: 2 << 20,
+            preallocate: false,
+        });
+        let small = vec![1u8; 4096];
+        let large = vec![2u8; 1 << 20];
+        let a = allocator.copy(&small);
+        let b = allocator.copy(&small);
+        let c = allocator.copy(&large);
+        match (a, b, c) {
+            (Some(a), Some(b), Some(c)) => {
+                assert_eq!(&*a, small.as_slice());
+                assert_eq!(&*b, small.as_slice());
+                assert_eq!(&*c, large.as_slice());
+                // The small blocks share a region while the large one has its own
+                assert_eq!(allocator.mapped_bytes(), 4 << 20);
+                drop(c);
+                assert_eq!(allocator.mapped_bytes(), 2 << 20);
+                drop(a);
+                drop(b);
+                // The current region is kept for the next blocks
+                assert_eq!(allocator.mapped_bytes(), 2 << 20);
+            }
+            // Falls back to the normal memory without huge pages reserved
+            (a, b, c) => {
+                assert!(a.is_none() && b.is_none() && c.is_none());
+                assert_eq!(allocator.mapped_bytes(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_arena() {
+        let arena = ChunkedArena::new(4096, ArenaOptions::default());
+        assert_eq!(arena.approximate_memory_usage(), 0);
+        let align = mem::align_of::<AlignedStruct>();
+        let mut allocated = vec![];
+        for i in 0..1000 {
+            let ptr = unsafe { arena.allocate::<AlignedStruct>(64, align) };
+            assert_eq!(ptr as usize % align, 0);
+            unsafe { (*ptr).data = [(i % 256) as u8; 64] };
+            allocated.push(ptr);
+        }
+        for (i, ptr) in allocated.into_iter().enumerate() {
+            assert_eq!(unsafe { (*ptr).data }, [(i % 256) as u8; 64]);
+        }
+        // The blocks are allocated as needed
+        let used = arena.memory_used();
+        assert_eq!(used, 1000 * (64 + align - 1));
+        let blocks = arena.inner.chunks.read().unwrap().len();
+        assert_eq!(blocks, used.div_ceil(4096));
+        assert_eq!(arena.approximate_memory_usage(), blocks * 4096);
+
+        // A large allocation gets its own block
+        let ptr = unsafe { arena.allocate::<u8>(2048, 8) };
+        assert!(!ptr.is_null());
+        assert_eq!(arena.inner.chunks.read().unwrap().len(), blocks + 1);
+        assert_eq!(arena.approximate_memory_usage(), blocks * 4096 + 2056);
+        let next = unsafe { arena.allocate::<u8>(8, 8) };
+        assert!(!next.is_null());
+        assert_eq!(arena.inner.chunks.read().unwrap().len(), blocks + 1);
+    }
+
+    #[test]
+    fn test_chunked_arena_concurrency() {
+        let arena = ChunkedArena::new(4096, ArenaOptions::default());
+        let align = mem::align_of::<AlignedStruct>();
+        let handles: Vec<_> = (0..10u8)
+            .map(|t| {
+                let arena = arena.clone();
+                thread::spawn(move || {
+                    let ptrs: Vec<_> = (0..1000)
+                        .map(|_| {
+                            let ptr = unsafe { arena.allocate::<AlignedStruct>(64, align) };
+                            unsafe { (*ptr).data = [t; 64] };
+                            ptr as usize
+                        })
+                        .collect();
+                    for ptr in ptrs {
+                        assert_eq!(unsafe { (*(ptr as *mut AlignedStruct)).data }, [t; 64]);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(arena.memory_used(), 10 * 1000 * (64 + align - 1));
+        assert!(arena.approximate_memory_usage() >= arena.memory_used());
+    }
+
+    #[test]
+    fn test_arena_block_pool() {
+        let pool = Arc::new(ArenaBlockPool::default());
+        let arena = ChunkedArena::with_pool(4096, ArenaOptions::default(), Some(pool.clone()));
+        let first = unsafe { arena.allocate::<u8>(100, 8) };
+        // The dedicated block is not recycled
+        unsafe { arena.allocate::<u8>(4000, 8) };
+        assert_eq!(pool.pooled_bytes(), 0);
+        drop(arena);
+        // The current block is kept
+        assert_eq!(pool.pooled_bytes(), 4096);
+
+        // An arena of another block size doesn't take it
+        let other = ChunkedArena::with_pool(8192, ArenaOptions::default(), Some(pool.clone()));
+        unsafe { other.allocate::<u8>(8, 8) };
+        assert_eq!(pool.pooled_bytes(), 4096);
+
+        let arena = ChunkedArena::with_pool(4096, ArenaOptions::default(), Some(pool.clone()));
+        let reused = unsafe { arena.allocate::<u8>(100, 8) };
+        assert_eq!(pool.pooled_bytes(), 0);
+        assert_eq!(reused, first);
+        assert_eq!(arena.approximate_memory_usage(), 4096);
+        drop(other);
+        // The pool keeps one block
+        assert_eq!(pool.pooled_bytes(), 8192);
+    }
+
     #[test]
     fn test_block_arena_concurrency() {
         let arena = BlockArena::default();
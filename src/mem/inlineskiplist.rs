@@ -280,9 +280,9 @@ impl<C, A> InlineSkipList<C, A> where C: Comparator, A: Arena + Clone + Send + S
     }
 
     #[inline]
-    // 计算并返回跳表当前使用的总内存大小  分配器使用的内存大小(node结构大小)+ key的大小
+    // 计算并返回跳表当前使用的总内存大小  分配器占用的内存大小(node结构所在的块)+ key的大小
     pub fn total_size(&self) -> usize {
-        self.inner.size.load(Ordering::SeqCst) + self.inner.arena.memory_used()
+        self.inner.size.load(Ordering::SeqCst) + self.inner.arena.approximate_memory_usage()
     }
     // 找到最后一个节点的指针
     fn find_last(&self) -> *mut Node {
@@ -1,16 +1,28 @@
 pub mod arena;
 pub mod inlineskiplist;
+pub mod rep;
 pub mod skiplist;
+pub mod write_buffer_manager;
 
-use crate::db::format::{InternalKeyComparator, LookupKey, ValueType, INTERNAL_KEY_TAIL};
+use crate::db::format::{
+    pack_seq_and_type, unpack_seq_and_type, InternalKeyComparator, LookupKey, ValueType,
+    INTERNAL_KEY_TAIL,
+};
+use crate::db::range_del::{max_covering_seq, RangeTombstone};
 use crate::iterator::Iterator;
-use crate::mem::arena::OffsetArena;
-use crate::mem::inlineskiplist::{InlineSkipList, InlineSkiplistIterator};
-use crate::util::coding::{decode_fixed_64, put_fixed_64};
+use crate::mem::arena::{chunk_size_for, ArenaOptions, ChunkedArena};
+use crate::mem::rep::{MemTableRep, MemTableRepFactory, SkipListRepFactory};
+use crate::mem::write_buffer_manager::WriteBufferManager;
+use crate::options::{project_value, ValueProjector};
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
 use crate::util::comparator::Comparator;
+use crate::util::crc32;
 use crate::util::varint::VarintU32;
 use crate::{Error, Result};
 use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, RwLock};
 
 // KeyComparator 是InternalKeyComparator 的包装器。用于跳表，跳表中存的是entry
 #[derive(Clone, Default)]
@@ -52,28 +64,125 @@ impl<C: Comparator> Comparator for KeyComparator<C> {
 pub struct MemTable<C: Comparator> {
     cmp: KeyComparator<C>,
     // 内存有序表
-    table: InlineSkipList<KeyComparator<C>, OffsetArena>,
+    table: Box<dyn MemTableRep>,
+    // 范围删除的墓碑，与点数据分开存放
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
+    // 每个 entry 的末尾是否带有 checksum
+    entry_checksum: bool,
+    // 内存占用记账到的 `WriteBufferManager`
+    write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    // 已经记账到 `write_buffer_manager` 的字节数
+    charged: AtomicUsize,
 }
 
-impl<C: Comparator> MemTable<C> {
+impl<C: Comparator> Drop for MemTable<C> {
+    fn drop(&mut self) {
+        if let Some(manager) = &self.write_buffer_manager {
+            manager.free(*self.charged.get_mut());
+        }
+    }
+}
+
+impl<C: Comparator + 'static> MemTable<C> {
     /// 创建
     pub fn new(max_mem_size: usize, icmp: InternalKeyComparator<C>) -> Self {
-        let arena = OffsetArena::with_capacity(max_mem_size);
+        Self::with_entry_checksum(max_mem_size, icmp, false)
+    }
+
+    /// 创建一个 memtable，如果 `entry_checksum` 为 true，每个 entry 插入时计算 checksum，
+    /// 并可以在 flush 前通过 `verify_checksums` 校验
+    pub fn with_entry_checksum(
+        max_mem_size: usize,
+        icmp: InternalKeyComparator<C>,
+        entry_checksum: bool,
+    ) -> Self {
+        Self::with_arena_options(max_mem_size, icmp, entry_checksum, ArenaOptions::default())
+    }
+
+    /// 与 `with_entry_checksum` 相同，但 arena 的内存按 `arena_options` 分配
+    pub fn with_arena_options(
+        max_mem_size: usize,
+        icmp: InternalKeyComparator<C>,
+        entry_checksum: bool,
+        arena_options: ArenaOptions,
+    ) -> Self {
+        Self::with_factory(
+            max_mem_size,
+            icmp,
+            entry_checksum,
+            arena_options,
+            &SkipListRepFactory,
+        )
+    }
+
+    /// 与 `with_arena_options` 相同，但 entry 保存在 `factory` 创建的 `MemTableRep` 中
+    pub fn with_factory(
+        max_mem_size: usize,
+        icmp: InternalKeyComparator<C>,
+        entry_checksum: bool,
+        arena_options: ArenaOptions,
+        factory: &dyn MemTableRepFactory<C>,
+    ) -> Self {
+        let arena = ChunkedArena::new(chunk_size_for(max_mem_size), arena_options);
+        Self::with_arena(icmp, entry_checksum, arena, factory)
+    }
+
+    /// 创建一个 memtable，`factory` 创建的 `MemTableRep` 从 `arena` 分配内存
+    pub fn with_arena(
+        icmp: InternalKeyComparator<C>,
+        entry_checksum: bool,
+        arena: ChunkedArena,
+        factory: &dyn MemTableRepFactory<C>,
+    ) -> Self {
         let kcmp = KeyComparator { icmp };
-        let table = InlineSkipList::new(kcmp.clone(), arena);
-        Self { cmp: kcmp, table }
+        let table = factory.create(kcmp.clone(), arena);
+        Self {
+            cmp: kcmp,
+            table,
+            range_tombstones: RwLock::new(vec![]),
+            entry_checksum,
+            write_buffer_manager: None,
+            charged: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<C: Comparator> MemTable<C> {
+    /// 将内存占用记账到 `manager`，memtable 被释放时归还
+    pub fn with_write_buffer_manager(mut self, manager: Arc<WriteBufferManager>) -> Self {
+        self.write_buffer_manager = Some(manager);
+        self.charge();
+        self
+    }
+
+    // 将新增的内存占用记账到 `write_buffer_manager`
+    fn charge(&self) {
+        if let Some(manager) = &self.write_buffer_manager {
+            let usage = self.approximate_memory_usage();
+            let charged = self.charged.fetch_max(usage, AtomicOrdering::AcqRel);
+            if usage > charged {
+                manager.reserve(usage - charged);
+            }
+        }
     }
 
     ///返回当前使用的估计内存大小
     #[inline]
     pub fn approximate_memory_usage(&self) -> usize {
-        self.table.total_size()
+        self.table.memory_usage()
+            + self
+                .range_tombstones
+                .read()
+                .unwrap()
+                .iter()
+                .map(|t| t.approximate_size())
+                .sum::<usize>()
     }
 
     /// `MemTableIterator`
     #[inline]
     pub fn iter(&self) -> MemTableIterator<C> {
-        MemTableIterator::new(self.table.clone())
+        MemTableIterator::new(self.table.iter())
     }
 
     /// Returns current elements count in inner Skiplist
@@ -82,9 +191,24 @@ impl<C: Comparator> MemTable<C> {
         self.table.len()
     }
 
+    /// Returns true if the memtable contains neither entries nor range tombstones
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.table.len() == 0
+        self.table.is_empty() && self.range_tombstones.read().unwrap().is_empty()
+    }
+
+    /// 添加一个删除 `[begin, end)` 的范围墓碑
+    pub fn add_range_tombstone(&self, seq_number: u64, begin: &[u8], end: &[u8]) {
+        self.range_tombstones
+            .write()
+            .unwrap()
+            .push(RangeTombstone::new(begin, end, seq_number));
+        self.charge();
+    }
+
+    /// Returns all the range tombstones in the memtable
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().unwrap().clone()
     }
 
     ///  Type标识插入（Put）还是删除（Delete）如果类型为“Deletion”，通常value将为空。
@@ -103,6 +227,8 @@ impl<C: Comparator> MemTable<C> {
     ///   +---------------------------------+
     ///   | value bytes                     |
     ///   +---------------------------------+
+    ///   | checksum (4) (optional)         |
+    ///   +---------------------------------+
     /// ```
     ///  添加一个entry ，seq，type，kv
     pub fn add(&self, seq_number: u64, val_type: ValueType, key: &[u8], value: &[u8]) {
@@ -114,24 +240,69 @@ impl<C: Comparator> MemTable<C> {
         // 追加key
         buf.extend_from_slice(key);
         // 写入序列号和值类型 固定8位
-        put_fixed_64(
-            &mut buf,
-            (seq_number << INTERNAL_KEY_TAIL) | val_type as u64,
-        );
-
-        VarintU32::put_varint_prefixed_slice(&mut buf, value);
+        put_fixed_64(&mut buf, pack_seq_and_type(seq_number, val_type));
+
+        // 空的 value 也写入长度，保证 checksum 之前的内容可以被完整解析
+        VarintU32::put_varint(&mut buf, value.len() as u32);
+        buf.extend_from_slice(value);
+        if self.entry_checksum {
+            let crc = crc32::mask(crc32::hash(&buf));
+            put_fixed_32(&mut buf, crc);
+        }
         // entry存储到表中
-        self.table.put(buf);
+        self.table.insert(buf);
+        self.charge();
+    }
+
+    /// 校验所有 entry 的 checksum，在 memtable 被写入 SST 之前调用，
+    /// 以避免内存中的位翻转被持久化。没有开启 entry checksum 时什么都不做。
+    pub fn verify_checksums(&self) -> Result<()> {
+        if !self.entry_checksum {
+            return Ok(());
+        }
+        let mut iter = self.table.iter();
+        iter.seek_to_first();
+        while iter.valid() {
+            verify_entry_checksum(iter.key())?;
+            iter.next();
+        }
+        Ok(())
     }
 
     /// 如果 memtable 包含 key 的值, returns it in `Some(Ok())`.
     /// 如果 memtable 包含 key 已删除, returns `Some(Err(Status::NotFound))` .
     /// 不包含key, return `None`
-    pub fn get(&self, key: &LookupKey) -> Option<Result<Vec<u8>>> {
+    /// 在值或删除之前遇到的合并操作数按从新到旧的顺序追加到 `operands` 中
+    /// `max_covering_tombstone_seq` 是覆盖 key 的最大范围墓碑序列号，会先用本 memtable 中的墓碑更新，
+    /// 序列号比它小的条目视为已删除
+    pub fn get(
+        &self,
+        key: &LookupKey,
+        max_covering_tombstone_seq: &mut u64,
+        operands: &mut Vec<Vec<u8>>,
+    ) -> Option<Result<Vec<u8>>> {
+        self.get_projected(key, max_covering_tombstone_seq, operands, None)
+    }
+
+    /// 与 `get` 相同，但如果没有合并操作数，返回由 `projector` 从值中提取的部分，不复制整个值
+    pub fn get_projected(
+        &self,
+        key: &LookupKey,
+        max_covering_tombstone_seq: &mut u64,
+        operands: &mut Vec<Vec<u8>>,
+        projector: Option<&dyn ValueProjector>,
+    ) -> Option<Result<Vec<u8>>> {
+        let tombstone_seq = max_covering_seq(
+            &self.cmp.icmp.user_comparator,
+            self.range_tombstones.read().unwrap().iter(),
+            key.user_key(),
+            key.sequence(),
+        );
+        *max_covering_tombstone_seq = tombstone_seq.max(*max_covering_tombstone_seq);
         let mk = key.mem_key();
-        let mut iter = InlineSkiplistIterator::new(self.table.clone());
+        let mut iter = self.table.lookup_iter(key.user_key());
         iter.seek(mk);
-        if iter.valid() {
+        while iter.valid() {
             let mut e = iter.key();
             let ikey = extract_varint32_encoded_slice(&mut e);
             let key_size = ikey.len();
@@ -144,16 +315,27 @@ impl<C: Comparator> MemTable<C> {
             {
                 Ordering::Equal => {
                     let tag = decode_fixed_64(&ikey[key_size - INTERNAL_KEY_TAIL..]);
-                    match ValueType::from(tag & 0xff_u64) {
+                    let (seq, value_type) = unpack_seq_and_type(tag);
+                    if seq < *max_covering_tombstone_seq {
+                        // 被范围墓碑删除
+                        return Some(Err(Error::NotFound(None)));
+                    }
+                    match value_type {
                         ValueType::Value => {
-                            return Some(Ok(extract_varint32_encoded_slice(&mut e).to_vec()))
+                            let value = extract_varint32_encoded_slice(&mut e);
+                            return Some(Ok(project_value(value, operands, projector)));
                         }
                         ValueType::Deletion => return Some(Err(Error::NotFound(None))),
-                        ValueType::Unknown => { /* fallback to None*/ }
+                        // 堆叠操作数，继续查找更旧的条目
+                        ValueType::Merge => {
+                            operands.push(extract_varint32_encoded_slice(&mut e).to_vec())
+                        }
+                        ValueType::RangeDeletion | ValueType::Unknown => return None,
                     }
                 }
                 _ => return None,
             }
+            iter.next();
         }
         None
     }
@@ -161,15 +343,19 @@ impl<C: Comparator> MemTable<C> {
 
 // 迭代器
 pub struct MemTableIterator<C: Comparator> {
-    iter: InlineSkiplistIterator<KeyComparator<C>, OffsetArena>,
+    iter: Box<dyn Iterator>,
     // 调用 `seek` 时将 `InternalKey` 编码为 `LookupKey` 的临时缓冲区
     tmp: Vec<u8>,
+    _cmp: PhantomData<C>,
 }
 
 impl<C: Comparator> MemTableIterator<C> {
-    pub fn new(table: InlineSkipList<KeyComparator<C>, OffsetArena>) -> Self {
-        let iter = InlineSkiplistIterator::new(table);
-        Self { iter, tmp: vec![] }
+    pub fn new(iter: Box<dyn Iterator>) -> Self {
+        Self {
+            iter,
+            tmp: vec![],
+            _cmp: PhantomData,
+        }
     }
 }
 
@@ -219,8 +405,26 @@ impl<C: Comparator> Iterator for MemTableIterator<C> {
     }
 }
 
+// 校验单个带 checksum 的 entry
+fn verify_entry_checksum(entry: &[u8]) -> Result<()> {
+    let mut rest = entry;
+    let ikey = VarintU32::get_varint_prefixed_slice(&mut rest);
+    let value = VarintU32::get_varint_prefixed_slice(&mut rest);
+    let ok = ikey.is_some()
+        && value.is_some()
+        && rest.len() == 4
+        && crc32::unmask(decode_fixed_32(rest)) == crc32::hash(&entry[..entry.len() - 4]);
+    if !ok {
+        return Err(Error::Corruption(format!(
+            "memtable entry checksum mismatch for key {:?}",
+            ikey.map(|k| &k[..k.len().saturating_sub(INTERNAL_KEY_TAIL)])
+        )));
+    }
+    Ok(())
+}
+
 // src中读取长度编码，在长度编码后面读取字节序并返回
-fn extract_varint32_encoded_slice<'a>(src: &mut &'a [u8]) -> &'a [u8] {
+pub(crate) fn extract_varint32_encoded_slice<'a>(src: &mut &'a [u8]) -> &'a [u8] {
     if src.is_empty() {
         return src;
     }
@@ -231,8 +435,9 @@ fn extract_varint32_encoded_slice<'a>(src: &mut &'a [u8]) -> &'a [u8] {
 mod tests {
     use crate::db::format::{InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType};
     use crate::iterator::Iterator;
-    use crate::mem::MemTable;
+    use crate::mem::{verify_entry_checksum, MemTable};
     use crate::util::comparator::BytewiseComparator;
+    use crate::Error;
     use std::str;
 
     fn new_mem_table() -> MemTable<BytewiseComparator> {
@@ -265,20 +470,80 @@ mod tests {
         memtable.add(4, ValueType::Value, b"foo", b"val3");
         memtable.add(2, ValueType::Value, b"boo", b"boo");
 
-        let v = memtable.get(&LookupKey::new(b"null", 10));
+        let v = memtable.get(&LookupKey::new(b"null", 10), &mut 0, &mut vec![]);
         assert!(v.is_none());
-        let v = memtable.get(&LookupKey::new(b"foo", 10));
+        let v = memtable.get(&LookupKey::new(b"foo", 10), &mut 0, &mut vec![]);
         assert_eq!(b"val3", v.unwrap().unwrap().as_slice());
-        let v = memtable.get(&LookupKey::new(b"foo", 0));
+        let v = memtable.get(&LookupKey::new(b"foo", 0), &mut 0, &mut vec![]);
         assert!(v.is_none());
-        let v = memtable.get(&LookupKey::new(b"foo", 1));
+        let v = memtable.get(&LookupKey::new(b"foo", 1), &mut 0, &mut vec![]);
         assert_eq!(b"val1", v.unwrap().unwrap().as_slice());
-        let v = memtable.get(&LookupKey::new(b"foo", 3));
+        let v = memtable.get(&LookupKey::new(b"foo", 3), &mut 0, &mut vec![]);
         assert!(v.unwrap().is_err());
-        let v = memtable.get(&LookupKey::new(b"boo", 3));
+        let v = memtable.get(&LookupKey::new(b"boo", 3), &mut 0, &mut vec![]);
         assert_eq!(b"boo", v.unwrap().unwrap().as_slice());
     }
 
+    #[test]
+    fn test_memtable_get_merge_operands() {
+        let memtable = new_mem_table();
+        memtable.add(1, ValueType::Value, b"foo", b"val1");
+        memtable.add(2, ValueType::Merge, b"foo", b"op1");
+        memtable.add(3, ValueType::Merge, b"foo", b"op2");
+        memtable.add(4, ValueType::Merge, b"bar", b"op3");
+
+        let mut operands = vec![];
+        let v = memtable.get(&LookupKey::new(b"foo", 10), &mut 0, &mut operands);
+        assert_eq!(b"val1", v.unwrap().unwrap().as_slice());
+        assert_eq!(operands, vec![b"op2".to_vec(), b"op1".to_vec()]);
+        let mut operands = vec![];
+        let v = memtable.get(&LookupKey::new(b"foo", 2), &mut 0, &mut operands);
+        assert_eq!(b"val1", v.unwrap().unwrap().as_slice());
+        assert_eq!(operands, vec![b"op1".to_vec()]);
+        // The operands without a base value in the memtable
+        let mut operands = vec![];
+        assert!(memtable
+            .get(&LookupKey::new(b"bar", 10), &mut 0, &mut operands)
+            .is_none());
+        assert_eq!(operands, vec![b"op3".to_vec()]);
+    }
+
+    #[test]
+    fn test_memtable_get_range_tombstone() {
+        let memtable = new_mem_table();
+        memtable.add(1, ValueType::Value, b"foo", b"val1");
+        memtable.add_range_tombstone(2, b"a", b"g");
+        memtable.add(3, ValueType::Merge, b"foo", b"op1");
+        assert!(!memtable.is_empty());
+
+        let mut tombstone_seq = 0;
+        let v = memtable.get(&LookupKey::new(b"foo", 1), &mut tombstone_seq, &mut vec![]);
+        assert_eq!(b"val1", v.unwrap().unwrap().as_slice());
+        assert_eq!(tombstone_seq, 0);
+        let mut operands = vec![];
+        let v = memtable.get(
+            &LookupKey::new(b"foo", 3),
+            &mut tombstone_seq,
+            &mut operands,
+        );
+        assert!(v.unwrap().is_err());
+        assert_eq!(tombstone_seq, 2);
+        assert_eq!(operands, vec![b"op1".to_vec()]);
+        // The tombstones of the newer sources apply too
+        let mut tombstone_seq = 5;
+        let v = memtable.get(&LookupKey::new(b"foo", 10), &mut tombstone_seq, &mut vec![]);
+        assert!(v.unwrap().is_err());
+        let mut tombstone_seq = 0;
+        let v = memtable.get(&LookupKey::new(b"g", 10), &mut tombstone_seq, &mut vec![]);
+        assert!(v.is_none());
+        assert_eq!(tombstone_seq, 0);
+
+        let memtable = new_mem_table();
+        memtable.add_range_tombstone(1, b"a", b"b");
+        assert!(!memtable.is_empty());
+        assert_eq!(memtable.len(), 0);
+    }
+
     #[test]
     fn test_memtable_iter() {
         let memtable = new_mem_table();
@@ -333,4 +598,37 @@ mod tests {
         }
         assert!(!iter.valid());
     }
+
+    #[test]
+    fn test_memtable_entry_checksum() {
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let memtable = MemTable::with_entry_checksum(1 << 32, icmp, true);
+        let entries = add_test_data_set(&memtable);
+        memtable.verify_checksums().unwrap();
+        // The checksum is invisible to the reads
+        let v = memtable.get(&LookupKey::new(b"foo", 10), &mut 0, &mut vec![]);
+        assert_eq!(b"val3", v.unwrap().unwrap().as_slice());
+        let v = memtable.get(&LookupKey::new(b"foo", 3), &mut 0, &mut vec![]);
+        assert!(v.unwrap().is_err());
+        let mut iter = memtable.iter();
+        iter.seek_to_first();
+        for (key, value) in entries.iter() {
+            let pkey = ParsedInternalKey::decode_from(iter.key()).unwrap();
+            assert_eq!(pkey.as_str(), *key);
+            assert_eq!(str::from_utf8(iter.value()).unwrap(), *value);
+            iter.next();
+        }
+
+        // Simulate a bit flip in the RAM on a copy of the first entry
+        let mut iter = memtable.table.iter();
+        iter.seek_to_first();
+        let mut entry = iter.key().to_vec();
+        verify_entry_checksum(&entry).unwrap();
+        let n = entry.len();
+        entry[n - 5] ^= 1;
+        match verify_entry_checksum(&entry) {
+            Err(Error::Corruption(_)) => {}
+            other => panic!("expect corruption but got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,487 @@
+use crate::db::format::INTERNAL_KEY_TAIL;
+use crate::iterator::{Iterator, KMergeIter, SimpleKMerger};
+use crate::mem::arena::{Arena, ChunkedArena};
+use crate::mem::inlineskiplist::{InlineSkipList, InlineSkiplistIterator};
+use crate::mem::{extract_varint32_encoded_slice, KeyComparator};
+use crate::util::comparator::Comparator;
+use crate::util::slice_transform::SliceTransform;
+use crate::Result;
+use bytes::Bytes;
+use std::cmp::Ordering as CmpOrdering;
+use std::hash::Hasher;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// `MemTableRep` keeps the encoded entries of a `MemTable` in the order of a
+/// `KeyComparator`. The entries are never equal to each other.
+///
+/// The inserts may be called concurrently, and the iterators may be created
+/// while inserting. An iterator may or may not see the entries inserted after
+/// it's created.
+pub trait MemTableRep: Send + Sync {
+    /// Inserts an encoded entry
+    fn insert(&self, entry: Vec<u8>);
+
+    /// Returns the number of the entries
+    fn len(&self) -> usize;
+
+    /// Returns true if there is no entry
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the approximate memory used by the entries
+    fn memory_usage(&self) -> usize;
+
+    /// Returns an iterator over all the entries in order, whose `key` is the
+    /// whole encoded entry
+    fn iter(&self) -> Box<dyn Iterator>;
+
+    /// Returns an iterator for a point lookup of `user_key`, which is only
+    /// required to yield the entries of `user_key` in order
+    fn lookup_iter(&self, _user_key: &[u8]) -> Box<dyn Iterator> {
+        self.iter()
+    }
+}
+
+/// `MemTableRepFactory` creates the `MemTableRep` of each new memtable
+pub trait MemTableRepFactory<C: Comparator>: Send + Sync {
+    /// Returns the name of the representation
+    fn name(&self) -> &str;
+
+    /// Creates an empty `MemTableRep` ordered by `cmp`. The representation
+    /// allocates its memory from `arena` if it uses one, so the memory is
+    /// accounted by the arena.
+    fn create(&self, cmp: KeyComparator<C>, arena: ChunkedArena) -> Box<dyn MemTableRep>;
+}
+
+/// Creates the skiplist memtables, which support the lock-free concurrent
+/// inserts and the cheap ordered reads. This is the default representation.
+#[derive(Default, Clone, Copy)]
+pub struct SkipListRepFactory;
+
+impl<C: Comparator + 'static> MemTableRepFactory<C> for SkipListRepFactory {
+    fn name(&self) -> &str {
+        "SkipListRepFactory"
+    }
+
+    fn create(&self, cmp: KeyComparator<C>, arena: ChunkedArena) -> Box<dyn MemTableRep> {
+        Box::new(SkipListRep {
+            list: InlineSkipList::new(cmp, arena),
+            count: AtomicUsize::new(0),
+        })
+    }
+}
+
+struct SkipListRep<C: Comparator> {
+    list: InlineSkipList<KeyComparator<C>, ChunkedArena>,
+    count: AtomicUsize,
+}
+
+impl<C: Comparator + 'static> MemTableRep for SkipListRep<C> {
+    fn insert(&self, entry: Vec<u8>) {
+        self.list.put(entry);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.list.total_size()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator> {
+        Box::new(InlineSkiplistIterator::new(self.list.clone()))
+    }
+}
+
+/// Creates the memtables hashing the entries into the buckets by the prefixes
+/// of their user keys, each of which is a skiplist. A point lookup only
+/// searches the bucket of its key, while an ordered scan merges all the
+/// buckets and is slower than the one of `SkipListRepFactory`.
+///
+/// The keys out of the domain of the prefix extractor are hashed as a whole.
+pub struct HashSkipListRepFactory {
+    prefix_extractor: Arc<dyn SliceTransform>,
+    bucket_count: usize,
+}
+
+impl HashSkipListRepFactory {
+    pub fn new(prefix_extractor: Arc<dyn SliceTransform>, bucket_count: usize) -> Self {
+        assert!(
+            bucket_count > 0,
+            "[memtable] the bucket count of a hash skiplist must be positive"
+        );
+        Self {
+            prefix_extractor,
+            bucket_count,
+        }
+    }
+}
+
+impl<C: Comparator + 'static> MemTableRepFactory<C> for HashSkipListRepFactory {
+    fn name(&self) -> &str {
+        "HashSkipListRepFactory"
+    }
+
+    fn create(&self, cmp: KeyComparator<C>, arena: ChunkedArena) -> Box<dyn MemTableRep> {
+        Box::new(HashSkipListRep {
+            cmp,
+            // All the buckets share the arena
+            arena,
+            prefix_extractor: self.prefix_extractor.clone(),
+            buckets: (0..self.bucket_count).map(|_| OnceLock::new()).collect(),
+            count: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+        })
+    }
+}
+
+type Bucket<C> = InlineSkipList<KeyComparator<C>, ChunkedArena>;
+type BucketIterator<C> = InlineSkiplistIterator<KeyComparator<C>, ChunkedArena>;
+
+struct HashSkipListRep<C: Comparator> {
+    cmp: KeyComparator<C>,
+    arena: ChunkedArena,
+    prefix_extractor: Arc<dyn SliceTransform>,
+    // The buckets are created at the first insert, so a memtable with a few
+    // prefixes doesn't allocate the heads of all the buckets
+    buckets: Vec<OnceLock<Bucket<C>>>,
+    count: AtomicUsize,
+    // The total size of the entries
+    size: AtomicUsize,
+}
+
+impl<C: Comparator> HashSkipListRep<C> {
+    fn bucket_index(&self, user_key: &[u8]) -> usize {
+        let prefix = if self.prefix_extractor.in_domain(user_key) {
+            self.prefix_extractor.transform(user_key)
+        } else {
+            user_key
+        };
+        let mut hasher = fxhash::FxHasher::default();
+        hasher.write(prefix);
+        hasher.finish() as usize % self.buckets.len()
+    }
+
+    fn merge(
+        &self,
+        children: Vec<BucketIterator<C>>,
+    ) -> KMergeIter<SimpleKMerger<BucketIterator<C>, KeyComparator<C>>> {
+        KMergeIter::new(SimpleKMerger::new(self.cmp.clone(), children))
+    }
+}
+
+impl<C: Comparator + 'static> MemTableRep for HashSkipListRep<C> {
+    fn insert(&self, entry: Vec<u8>) {
+        let mut e = entry.as_slice();
+        let ikey = extract_varint32_encoded_slice(&mut e);
+        let user_key = &ikey[..ikey.len().saturating_sub(INTERNAL_KEY_TAIL)];
+        let bucket = self.buckets[self.bucket_index(user_key)]
+            .get_or_init(|| InlineSkipList::new(self.cmp.clone(), self.arena.clone()));
+        self.size.fetch_add(entry.len(), Ordering::Relaxed);
+        bucket.put(entry);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.size.load(Ordering::Relaxed) + self.arena.approximate_memory_usage()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator> {
+        let children = self
+            .buckets
+            .iter()
+            .filter_map(|b| b.get())
+            .map(|b| InlineSkiplistIterator::new(b.clone()))
+            .collect();
+        Box::new(self.merge(children))
+    }
+
+    fn lookup_iter(&self, user_key: &[u8]) -> Box<dyn Iterator> {
+        match self.buckets[self.bucket_index(user_key)].get() {
+            Some(b) => Box::new(InlineSkiplistIterator::new(b.clone())),
+            // Merging nothing is an empty iterator
+            None => Box::new(self.merge(vec![])),
+        }
+    }
+}
+
+/// Creates the memtables appending the entries to a vector, which is sorted
+/// when it's read. The inserts are much cheaper than the ones of a skiplist,
+/// but each read after some inserts sorts the vector again, so it's only
+/// suitable for the write-only workloads like the bulk loads.
+#[derive(Default, Clone, Copy)]
+pub struct VectorRepFactory;
+
+impl<C: Comparator + 'static> MemTableRepFactory<C> for VectorRepFactory {
+    fn name(&self) -> &str {
+        "VectorRepFactory"
+    }
+
+    fn create(&self, cmp: KeyComparator<C>, _arena: ChunkedArena) -> Box<dyn MemTableRep> {
+        Box::new(VectorRep {
+            cmp,
+            entries: RwLock::new(SortedEntries {
+                entries: Arc::new(vec![]),
+                sorted: true,
+            }),
+            size: AtomicUsize::new(0),
+        })
+    }
+}
+
+struct SortedEntries {
+    // Shared with the iterators, which keep reading the old entries when the
+    // new ones are inserted
+    entries: Arc<Vec<Bytes>>,
+    sorted: bool,
+}
+
+struct VectorRep<C: Comparator> {
+    cmp: KeyComparator<C>,
+    entries: RwLock<SortedEntries>,
+    size: AtomicUsize,
+}
+
+impl<C: Comparator + 'static> MemTableRep for VectorRep<C> {
+    fn insert(&self, entry: Vec<u8>) {
+        self.size
+            .fetch_add(entry.len() + mem::size_of::<Bytes>(), Ordering::Relaxed);
+        let mut e = self.entries.write().unwrap();
+        Arc::make_mut(&mut e.entries).push(Bytes::from(entry));
+        e.sorted = false;
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().entries.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator> {
+        let entries = {
+            let e = self.entries.read().unwrap();
+            if e.sorted {
+                e.entries.clone()
+            } else {
+                drop(e);
+                let mut e = self.entries.write().unwrap();
+                if !e.sorted {
+                    let cmp = &self.cmp;
+                    Arc::make_mut(&mut e.entries).sort_unstable_by(|a, b| cmp.compare(a, b));
+                    e.sorted = true;
+                }
+                e.entries.clone()
+            }
+        };
+        Box::new(VectorRepIterator {
+            cmp: self.cmp.clone(),
+            pos: entries.len(),
+            entries,
+        })
+    }
+}
+
+struct VectorRepIterator<C: Comparator> {
+    cmp: KeyComparator<C>,
+    entries: Arc<Vec<Bytes>>,
+    // `entries.len()` if the iterator is invalid
+    pos: usize,
+}
+
+impl<C: Comparator> Iterator for VectorRepIterator<C> {
+    fn valid(&self) -> bool {
+        self.pos < self.entries.len()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.pos = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.pos = self.entries.len().saturating_sub(1);
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.pos = self
+            .entries
+            .partition_point(|e| self.cmp.compare(e, target) == CmpOrdering::Less);
+    }
+
+    fn next(&mut self) {
+        self.pos += 1;
+    }
+
+    fn prev(&mut self) {
+        self.pos = if self.pos == 0 {
+            self.entries.len()
+        } else {
+            self.pos - 1
+        };
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entries[self.pos]
+    }
+
+    fn value(&self) -> &[u8] {
+        let mut entry: &[u8] = &self.entries[self.pos];
+        extract_varint32_encoded_slice(&mut entry);
+        extract_varint32_encoded_slice(&mut entry)
+    }
+
+    fn status(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::{InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType};
+    use crate::mem::arena::ArenaOptions;
+    use crate::mem::MemTable;
+    use crate::util::comparator::BytewiseComparator;
+    use crate::util::slice_transform::FixedPrefixTransform;
+    use crate::Error;
+    use std::thread;
+
+    fn factories() -> Vec<Box<dyn MemTableRepFactory<BytewiseComparator>>> {
+        vec![
+            Box::new(SkipListRepFactory),
+            Box::new(HashSkipListRepFactory::new(
+                Arc::new(FixedPrefixTransform::new(2)),
+                16,
+            )),
+            Box::new(VectorRepFactory),
+        ]
+    }
+
+    fn new_memtable(
+        factory: &dyn MemTableRepFactory<BytewiseComparator>,
+    ) -> MemTable<BytewiseComparator> {
+        MemTable::with_factory(
+            1 << 20,
+            InternalKeyComparator::new(BytewiseComparator::default()),
+            false,
+            ArenaOptions::default(),
+            factory,
+        )
+    }
+
+    #[test]
+    fn test_memtable_reps() {
+        for factory in factories() {
+            let mem = new_memtable(factory.as_ref());
+            assert!(mem.is_empty());
+            // Insert in the reverse order with the keys out of the prefix domain
+            let mut seq = 0;
+            for i in (0..100).rev() {
+                seq += 1;
+                let key = format!("{:02}{}", i % 10, i);
+                mem.add(seq, ValueType::Value, key.as_bytes(), key.as_bytes());
+            }
+            seq += 1;
+            mem.add(seq, ValueType::Deletion, b"55", b"");
+            mem.add(seq + 1, ValueType::Value, b"x", b"x");
+            assert_eq!(mem.len(), 102, "{}", factory.name());
+            assert!(mem.approximate_memory_usage() > 0);
+
+            let get = |key: &[u8], seq| mem.get(&LookupKey::new(key, seq), &mut 0, &mut vec![]);
+            assert_eq!(get(b"0333", 1000).unwrap().unwrap(), b"0333");
+            assert!(matches!(get(b"55", 1000), Some(Err(Error::NotFound(_)))));
+            assert_eq!(get(b"x", 1000).unwrap().unwrap(), b"x");
+            assert!(get(b"x", seq).is_none());
+            assert!(get(b"0334", 1000).is_none());
+
+            let mut iter = mem.iter();
+            let mut keys = vec![];
+            iter.seek_to_first();
+            while iter.valid() {
+                keys.push(
+                    ParsedInternalKey::decode_from(iter.key())
+                        .unwrap()
+                        .as_str()
+                        .to_owned(),
+                );
+                iter.next();
+            }
+            let mut expected = keys.clone();
+            expected.sort();
+            assert_eq!(keys, expected, "{}", factory.name());
+            assert_eq!(keys.len(), 102);
+
+            iter.seek_to_last();
+            let mut reversed = vec![];
+            while iter.valid() {
+                reversed.push(
+                    ParsedInternalKey::decode_from(iter.key())
+                        .unwrap()
+                        .as_str()
+                        .to_owned(),
+                );
+                iter.prev();
+            }
+            reversed.reverse();
+            assert_eq!(keys, reversed, "{}", factory.name());
+
+            iter.seek(LookupKey::new(b"0750", 1000).internal_key());
+            assert_eq!(
+                ParsedInternalKey::decode_from(iter.key()).unwrap().user_key,
+                b"0757"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vector_rep_iterator_value() {
+        let mem = new_memtable(&VectorRepFactory);
+        mem.add(1, ValueType::Value, b"k1", b"v1");
+        mem.add(2, ValueType::Value, b"k2", b"");
+        let mut iter = mem.table.iter();
+        iter.seek_to_first();
+        assert_eq!(iter.value(), b"v1");
+        iter.next();
+        assert_eq!(iter.value(), b"");
+    }
+
+    #[test]
+    fn test_memtable_reps_concurrent_insert() {
+        for factory in factories() {
+            let mem = Arc::new(new_memtable(factory.as_ref()));
+            let writers: Vec<_> = (0..4u64)
+                .map(|w| {
+                    let mem = mem.clone();
+                    thread::spawn(move || {
+                        for i in 0..500 {
+                            let key = format!("{:04}", i);
+                            mem.add(w * 1000 + i + 1, ValueType::Value, key.as_bytes(), b"v");
+                        }
+                    })
+                })
+                .collect();
+            for w in writers {
+                w.join().unwrap();
+            }
+            assert_eq!(mem.len(), 2000, "{}", factory.name());
+            let mut iter = mem.iter();
+            iter.seek_to_first();
+            let mut count = 0;
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+            assert_eq!(count, 2000);
+        }
+    }
+}
@@ -44,19 +44,13 @@ impl Node {
 
     #[inline]
     fn get_next(&self, height: usize) -> *mut Node {
-        unsafe {
-            self.next_nodes
-                .get_unchecked(height - 1)
-                .load(Ordering::Acquire)
-        }
+        unsafe { (*self.next_nodes.as_ptr().add(height - 1)).load(Ordering::Acquire) }
     }
 
     #[inline]
     fn set_next(&self, height: usize, node: *mut Node) {
         unsafe {
-            self.next_nodes
-                .get_unchecked(height - 1)
-                .store(node, Ordering::Release);
+            (*self.next_nodes.as_ptr().add(height - 1)).store(node, Ordering::Release);
         }
     }
 
@@ -264,12 +258,7 @@ impl<C: Comparator, A: Arena> Iterator for SkiplistIterator<C, A> {
     /// Position at the first node in list
     #[inline]
     fn seek_to_first(&mut self) {
-        unsafe {
-            self.node = (*self.skl.head)
-                .next_nodes
-                .get_unchecked(0)
-                .load(Ordering::Acquire);
-        }
+        self.node = unsafe { (*self.skl.head).get_next(1) };
     }
 
     /// Position at the last node in list
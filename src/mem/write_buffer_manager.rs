@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// `WriteBufferManager` limits the total memory of the memtables sharing it.
+///
+/// Each memtable created with the manager charges its memory usage to the
+/// manager as it grows, and releases the charge when it's dropped. Once the
+/// total reaches the budget, a db writing to a non-empty memtable rotates it
+/// and flushes it, and the writes stall while the immutable memtables are
+/// still being flushed. A manager can be shared by several dbs to bound the
+/// memory of the write buffers of all of them, which covers all the column
+/// families since they share the memtables of their db.
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    memory_used: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    /// Creates a manager with a budget of `buffer_size` bytes. A budget of 0
+    /// only accounts the memory without limiting it.
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            memory_used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the budget of the memtables
+    #[inline]
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Returns the memory charged by all the living memtables
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.memory_used.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the memtables exceed the budget and should be flushed
+    #[inline]
+    pub fn should_flush(&self) -> bool {
+        self.buffer_size > 0 && self.memory_usage() >= self.buffer_size
+    }
+
+    pub(crate) fn reserve(&self, bytes: usize) {
+        self.memory_used.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    pub(crate) fn free(&self, bytes: usize) {
+        self.memory_used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
@@ -1,30 +1,356 @@
+use crate::batch::WriteBatch;
 use crate::cache::lru::LRUCache;
 use crate::cache::{Cache, ShardedCache};
 use crate::db::format::InternalFilterPolicy;
+use crate::db::scrub::ScrubListener;
+use crate::db::write_stall::WriteStallListener;
+use crate::filter::chained::{ChainedFilterPolicy, FilterKeys};
 use crate::filter::FilterPolicy;
 use crate::logger::Logger;
+use crate::mem::arena::BlockCacheAllocator;
+use crate::mem::rep::{MemTableRepFactory, SkipListRepFactory};
+use crate::mem::write_buffer_manager::WriteBufferManager;
 use crate::snapshot::Snapshot;
 use crate::sstable::block::Block;
-use crate::storage::{File, Storage};
+use crate::sstable::factory::{BlockBasedTableFactory, TableFactory};
+use crate::sstable::properties::TablePropertiesCollectorFactory;
+use crate::storage::{File, IoPriority, Storage};
+use crate::util::collection::HashSet;
 use crate::util::comparator::Comparator;
-use crate::{BloomFilter, LevelFilter, Log};
+use crate::util::slice_transform::SliceTransform;
+use crate::{BloomFilter, Error, LevelFilter, Log, Result};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const DEFAULT_CACHE_SHARDS: usize = 8;
 
-#[derive(Clone, Copy, Debug, FromPrimitive)]
+/// The ratio between the target sizes of two adjacent levels
+pub(crate) const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum CompressionType {
     NoCompression = 0,
     SnappyCompression = 1,
+    ZstdCompression = 2,
+    Lz4Compression = 3,
+    /// The high compression mode of LZ4, which compresses slower but decompresses
+    /// as fast as `Lz4Compression`
+    Lz4hcCompression = 4,
     Unknown,
 }
 
+impl CompressionType {
+    /// Returns `Error::NotSupported` if the crate is built without the cargo
+    /// feature the compression type needs
+    pub fn check_supported(self) -> Result<()> {
+        match self {
+            #[cfg(not(feature = "zstd"))]
+            CompressionType::ZstdCompression => Err(self.not_supported()),
+            #[cfg(not(feature = "lz4"))]
+            CompressionType::Lz4Compression | CompressionType::Lz4hcCompression => {
+                Err(self.not_supported())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn not_supported(self) -> Error {
+        let feature = match self {
+            CompressionType::ZstdCompression => "zstd",
+            CompressionType::Lz4Compression | CompressionType::Lz4hcCompression => "lz4",
+            _ => "",
+        };
+        Error::NotSupported(format!("{:?} needs the `{}` cargo feature", self, feature))
+    }
+}
+
 impl From<u8> for CompressionType {
     fn from(i: u8) -> Self {
-        num_traits::FromPrimitive::from_u8(i).unwrap()
+        num_traits::FromPrimitive::from_u8(i).unwrap_or(CompressionType::Unknown)
+    }
+}
+
+/// The checksum algorithm of the block trailers in sstables
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumType {
+    /// The IEEE CRC-32, the only checksum of the tables written by the format version 0
+    Crc32 = 0,
+    XxHash64 = 1,
+    Xxh3 = 2,
+    /// CRC-32C (Castagnoli), which is computed by the SSE4.2 or ARMv8 CRC
+    /// instructions if the CPU supports them
+    Crc32c = 3,
+}
+
+impl ChecksumType {
+    /// Returns the checksum type encoded as `v`, or `None` if it's unknown
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(ChecksumType::Crc32),
+            1 => Some(ChecksumType::XxHash64),
+            2 => Some(ChecksumType::Xxh3),
+            3 => Some(ChecksumType::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+/// The format of the index of the sstables
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexType {
+    /// A single index block with an entry per data block, which is loaded into
+    /// memory when the table is opened
+    BinarySearch = 0,
+    /// The index entries are split into partitions of about `block_size`, and a
+    /// top-level index block has an entry per partition. Only the top-level index
+    /// stays in memory while the partitions are read through the block cache.
+    TwoLevelIndexSearch = 1,
+}
+
+/// How `WickDB::open_db` treats the existing db in the path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Creates a new db, and fails with `Error::AlreadyExists` if the db exists
+    CreateNew,
+    /// Opens the existing db, and fails with `Error::NotFound` if the db is missing
+    OpenExisting,
+    /// Opens the existing db or creates a new one if it's missing
+    OpenOrCreate,
+    /// Opens the existing db without changing any file in it. The writes and the
+    /// compactions fail with `Error::InvalidArgument`, and the WAL files are
+    /// replayed into the memtables instead of being flushed. The LOCK file is held
+    /// like a writable db, so it fails with `Error::Busy` if the db is opened by
+    /// another instance.
+    ReadOnly,
+    /// Same as `ReadOnly` but doesn't take the LOCK file, so it could be opened
+    /// alongside the instance writing the db. It sees the db as it is when opened,
+    /// and the reads of the tables deleted by the compactions of the writing
+    /// instance afterwards may fail unless they're already opened.
+    Secondary,
+}
+
+impl OpenMode {
+    /// Returns true if the db is opened without changing any file in it
+    pub fn is_read_only(self) -> bool {
+        matches!(self, OpenMode::ReadOnly | OpenMode::Secondary)
     }
 }
 
+/// How the tables are organized and picked for the compactions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// Each level except L0 is a sorted run whose size limit grows by 10x per
+    /// level, and a compaction merges a few files into the next level. It keeps
+    /// the read and space amplification low at the cost of rewriting the data
+    /// many times.
+    Level,
+    /// Size-tiered compaction. Every L0 file and every non-empty level is a
+    /// sorted run, and a compaction merges several adjacent sorted runs of
+    /// similar sizes into one, which rewrites the data much less often than
+    /// `Level` but keeps more sorted runs and needs up to twice the disk space.
+    /// See `UniversalCompactionOptions`.
+    Universal,
+    /// All the tables stay in level 0 and are never rewritten. The oldest tables
+    /// are dropped when the total size exceeds `Options::fifo_max_table_files_size`
+    /// or they expire after `Options::fifo_ttl`, which suits a bounded log or
+    /// cache store.
+    Fifo,
+}
+
+/// How a level compaction picks the file to compact in the level whose score is
+/// the highest. The overlapping files in the next level are always added, and a
+/// file whose compaction would exceed `Options::max_compaction_bytes` is skipped
+/// if there is another one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionPri {
+    /// The first file after the one compacted last time, wrapping around the key
+    /// space, so the whole level is compacted in turn
+    RoundRobin,
+    /// The file with the fewest bytes overlapping the next level relative to its
+    /// own size, which minimizes the write amplification of skewed workloads
+    /// where the hot keys are repeatedly overwritten
+    MinOverlappingRatio,
+    /// The file created the earliest, i.e. whose key range has waited the longest
+    /// to be compacted to the next level
+    OldestSmallestSeqFirst,
+    /// The largest file, whose size is compensated by its point deletions so the
+    /// tombstones are pushed down and reclaim the space sooner. The deletions are
+    /// read from the table properties.
+    ByCompensatedSize,
+}
+
+/// What the db does when a flush or a compaction job panics, e.g. because of a
+/// bug in a user-provided `CompactionFilter` or `MergeOperator`. Either way the
+/// panic is logged, counted in `DBStats::background_panics` and never kills the
+/// background threads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundPanicPolicy {
+    /// The panic is recorded as a background error, so the next write fails with
+    /// it and no background job runs until then
+    Fail,
+    /// The job is scheduled again after `backoff`. The panic is recorded as a
+    /// background error once the jobs have panicked more than `max_restarts`
+    /// times in a row.
+    Restart {
+        max_restarts: usize,
+        backoff: Duration,
+    },
+}
+
+/// Options that control the picking of the universal compactions. The sorted
+/// runs are ordered from the newest to the oldest, and a compaction is only
+/// picked when there are more than `Options::l0_compaction_threshold` of them.
+#[derive(Clone, Copy, Debug)]
+pub struct UniversalCompactionOptions {
+    /// The percentage of size flexibility. A sorted run is merged with the newer
+    /// runs if its size is no larger than their total size plus this percent of
+    /// it. Default is 1.
+    pub size_ratio: u32,
+
+    /// The minimum number of sorted runs merged by a size-ratio compaction.
+    /// Default is 2.
+    pub min_merge_width: usize,
+
+    /// The maximum number of sorted runs merged by a size-ratio compaction.
+    /// Default is unlimited.
+    pub max_merge_width: usize,
+
+    /// If the total size of the sorted runs except the oldest one is larger than
+    /// this percent of the size of the oldest one, all the runs are merged
+    /// together to reclaim the space of the obsolete entries. Default is 200.
+    pub max_size_amplification_percent: u64,
+}
+
+impl Default for UniversalCompactionOptions {
+    fn default() -> Self {
+        UniversalCompactionOptions {
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::MAX,
+            max_size_amplification_percent: 200,
+        }
+    }
+}
+
+/// A `MergeOperator` combines the operands written by `WriteBatch::merge` with
+/// the existing value of a key, which enables the read-modify-write patterns
+/// like counters or appending without reading the value first.
+pub trait MergeOperator: Send + Sync {
+    /// The name of the operator
+    fn name(&self) -> &str;
+
+    /// Apply the `operands` (ordered from the oldest to the newest) to the
+    /// existing value of `key`. `existing` is `None` if the key does not exist
+    /// or has been deleted.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Result<Vec<u8>>;
+
+    /// Combine two operands into one, where `left` is older than `right`.
+    /// Returns `None` if they can't be combined without the existing value,
+    /// which is the default.
+    fn partial_merge(&self, _key: &[u8], _left: &[u8], _right: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// A `ValueProjector` extracts a subset of a value, e.g. a few fields of a
+/// serialized struct, for `WickDB::get_with_projection`.
+///
+/// The projector reads the raw bytes of the value in the memtable or the data
+/// block, so the whole value is never copied out unless merge operands have to
+/// be applied to it first.
+pub trait ValueProjector {
+    /// Returns the projected part of `value`
+    fn project(&self, value: &[u8]) -> Vec<u8>;
+}
+
+// Copies the part of `value` picked by `projector` out, or the whole value if the
+// merge operands have to be applied to it
+pub(crate) fn project_value(
+    value: &[u8],
+    operands: &[Vec<u8>],
+    projector: Option<&dyn ValueProjector>,
+) -> Vec<u8> {
+    match projector {
+        Some(projector) if operands.is_empty() => projector.project(value),
+        _ => value.to_vec(),
+    }
+}
+
+/// The decision of a `CompactionFilter` on an entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// Keep the entry as it is
+    Keep,
+    /// Remove the entry as if it's deleted
+    Remove,
+    /// Keep the entry with the new value
+    ChangeValue(Vec<u8>),
+}
+
+/// A `CompactionFilter` decides whether to keep, remove or change the value of
+/// each entry rewritten by the compactions, which implements the TTL or the
+/// soft-deletes without a separate GC pass.
+///
+/// The filter is only invoked for the newest version of a key that is a value
+/// and is seen by all the snapshots, so the snapshot reads are never changed.
+/// The deletions and the merge operands are not filtered.
+pub trait CompactionFilter: Send + Sync {
+    /// The name of the filter
+    fn name(&self) -> &str;
+
+    /// Decides the fate of the entry `key -> value` in a compaction of the
+    /// given input `level`. The `key` is the user key without the column
+    /// family it belongs to.
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> CompactionDecision;
+}
+
+/// A `PreCommitHook` is invoked for every write group after it's appended to
+/// the log but before it's applied to the memtable, and could veto the commit.
+/// This lets an external consensus (e.g. a raft log) decide whether the writes
+/// take effect.
+///
+/// A vetoed group is followed by a veto record in the log, so it's never
+/// replayed. But if the db crashes before the veto record is written, the last
+/// group of the log is undecided and `recover` is asked whether to replay it.
+pub trait PreCommitHook: Send + Sync {
+    /// The name of the hook
+    fn name(&self) -> &str;
+
+    /// Decides whether to apply `batch` whose first operation has the given
+    /// `sequence`. Returning an error vetoes the whole group of writes, and no
+    /// sequence is consumed by it.
+    fn before_apply(&self, sequence: u64, batch: &WriteBatch) -> Result<()>;
+
+    /// Decides whether to replay the last group of writes in the log when
+    /// recovering, which might be vetoed without being recorded. All the
+    /// earlier groups without a veto record have been accepted.
+    fn recover(&self, sequence: u64, batch: &WriteBatch) -> bool {
+        self.before_apply(sequence, batch).is_ok()
+    }
+}
+
+/// A `ColdStore` holds the full dataset remotely (e.g. in an object store or
+/// another service), so the db only keeps the hot keys and serves as a cache
+/// over it.
+///
+/// The cold store is consulted by `DB::get` when the key is not found locally.
+/// A key deleted locally is never read from the cold store, so the deletions
+/// are kept by the compactions when a cold store is configured.
+pub trait ColdStore: Send + Sync {
+    /// The name of the cold store
+    fn name(&self) -> &str;
+
+    /// Reads the value of `key` from the cold store. Returns `None` if the key
+    /// doesn't exist there.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
 /// Options to control the behavior of a database (passed to `DB::Open`)
 #[derive(Clone)]
 pub struct Options<C: Comparator> {
@@ -38,11 +364,9 @@ pub struct Options<C: Comparator> {
     /// comparator provided to previous open calls on the same DB.
     pub comparator: C,
 
-    /// If true, the database will be created if it is missing.
-    pub create_if_missing: bool,
-
-    /// If true, an error is raised if the database already exists.
-    pub error_if_exists: bool,
+    /// 打开数据库的方式，决定数据库不存在或已存在时的行为，以及是否可写
+    /// Default: OpenMode::OpenOrCreate
+    pub open_mode: OpenMode,
 
     /// If true, the implementation will do aggressive checking of the
     /// data it is processing and will stop early if it detects any
@@ -51,6 +375,11 @@ pub struct Options<C: Comparator> {
     /// become unreadable or for the entire DB to become unopenable.
     pub paranoid_checks: bool,
 
+    /// 如果为 true，每个 entry 写入 memtable 时计算 checksum，并在 memtable 被 flush
+    /// 成 SST 之前校验，在没有 ECC 内存的机器上避免内存中的位翻转被持久化。
+    /// 校验失败时 flush 会失败并进入后台错误状态。Default is false.
+    pub memtable_entry_checksum: bool,
+
     // -------------------
     // Parameters that affect compaction:
     /// The max number of levels except L0
@@ -72,6 +401,15 @@ pub struct Options<C: Comparator> {
     /// number of bytes for a level is exceeded, compaction is requested.
     pub l1_max_bytes: u64,
 
+    /// If true, the target size of each level is derived from the actual size of
+    /// the last level instead of growing from `l1_max_bytes`. The target of a
+    /// level is a tenth of the next one, and the level 0 files are compacted
+    /// straight into the first level whose target is at least `l1_max_bytes`
+    /// (the base level), leaving the levels above it empty. This bounds the
+    /// space amplification to about 1.11x no matter how large the db is.
+    /// Default is false.
+    pub level_compaction_dynamic_level_bytes: bool,
+
     /// Maximum level to which a new compacted memtable is pushed if it
     /// does not create overlap.  We try to push to level 2 to avoid the
     /// relatively expensive level 0=>1 compactions and to avoid some
@@ -83,18 +421,157 @@ pub struct Options<C: Comparator> {
     /// Approximate gap in bytes between samples of data read during iteration
     pub read_bytes_period: u64,
 
+    /// If set, the db checks the foreground activity at this interval and
+    /// compacts a level 0 file when it's idle, which reduces the read
+    /// amplification before the next burst.
+    pub idle_compaction_interval: Option<Duration>,
+
+    /// The db is considered idle if no more than this number of reads and
+    /// writes are issued within `idle_compaction_interval`.
+    pub idle_compaction_max_ops: u64,
+
+    /// If set, the db scans for the files that are not referenced by any live
+    /// version or running job when it's opened and then at this interval.
+    pub orphan_file_scan_interval: Option<Duration>,
+
+    /// An orphaned file is deleted only if it has been found orphaned for
+    /// longer than this period.
+    pub orphan_file_grace_period: Duration,
+
+    /// If set, a background thread verifies `block_scrub_blocks_per_run` data
+    /// blocks picked randomly from the live tables at this interval, so that a
+    /// corrupted block is found long before a query reads it. The corruptions
+    /// are logged and reported to `block_scrub_listener`.
+    pub block_scrub_interval: Option<Duration>,
+
+    /// The number of the data blocks verified at each `block_scrub_interval`.
+    /// The tables are picked in proportion to their sizes. Default is 16.
+    pub block_scrub_blocks_per_run: usize,
+
+    /// Notified when the background scrubber finds a corrupted block
+    pub block_scrub_listener: Option<Arc<dyn ScrubListener>>,
+
+    /// If set, a snapshot of `WickDB::db_stats` is persisted at this interval
+    /// into the reserved column family `PERSISTENT_STATS_CF`, so the history of
+    /// the compaction and write stall metrics survives restarts. The column family
+    /// is created when the db is opened, which requires a bytewise comparator. See
+    /// `WickDB::stats_history`.
+    pub stats_persist_period: Option<Duration>,
+
+    /// The persisted statistics snapshots older than this are deleted. Default is
+    /// 7 days.
+    pub stats_history_retention: Duration,
+
+    /// The wall time budget of a compaction job. A compaction running longer
+    /// than it checkpoints its progress at the next key splitting none of its
+    /// input files and yields. The rest of the inputs are left to the following
+    /// compactions, so a giant compaction is done in chunks instead of
+    /// monopolizing the background thread. A memtable flush is never chunked.
+    pub compaction_job_max_time: Option<Duration>,
+
+    /// The budget of the bytes written by a compaction job, which works like
+    /// `compaction_job_max_time`.
+    pub compaction_job_max_bytes: Option<u64>,
+
+    /// 一次压缩最多拆分成的子压缩数量。大于 1 时，按输入文件的起始 user key 把压缩的键范围
+    /// 切分成多段，在多个线程上并行合并，各自产生输出文件后合并到同一个 `VersionEdit` 中，
+    /// 避免大的 Level 0 压缩只占用一个核而拖慢写入。设置了 `compaction_job_max_time` 或
+    /// `compaction_job_max_bytes` 时不拆分。
+    pub max_subcompactions: usize,
+
+    /// 后台任务线程池的线程总数上限。刷盘任务在 HIGH 优先级的线程池中运行，占其中的四分之一，
+    /// 其余的线程组成运行压缩任务的 LOW 优先级线程池，每个线程池至少有一个线程，这样刷盘不会排在
+    /// 耗时很长的压缩后面而阻塞写入。线程按需创建，空闲一段时间后自动退出。
+    pub max_background_jobs: usize,
+
+    /// 刷盘或压缩任务 panic 时的处理方式，未完成的输出文件在重新打开数据库时删除，
+    /// 等待中的手动压缩返回错误。Default is `BackgroundPanicPolicy::Fail`.
+    pub background_panic_policy: BackgroundPanicPolicy,
+
+    /// 如果为 true，压缩从输入文件读取每个块后建议操作系统丢弃该范围的页缓存
+    /// （`POSIX_FADV_DONTNEED`），避免大压缩把前台读取的热数据挤出页缓存。
+    pub compaction_drop_page_cache: bool,
+
+    /// 压缩风格。默认是 `CompactionStyle::Level`；写入量很大的场景（例如时序数据导入）
+    /// 可以使用 `CompactionStyle::Universal`，以更多的 sorted run 和空间放大换取更低的写放大。
+    pub compaction_style: CompactionStyle,
+
+    /// How the input file of a `CompactionStyle::Level` compaction is picked.
+    /// Default is `CompactionPri::RoundRobin`.
+    pub compaction_pri: CompactionPri,
+
+    /// `CompactionStyle::Universal` 的压缩选取参数
+    pub universal_compaction: UniversalCompactionOptions,
+
+    /// `CompactionStyle::Fifo` 下所有 SST 文件的总大小上限，超过时删除最旧的文件。Default is 1GB.
+    pub fifo_max_table_files_size: u64,
+
+    /// 如果设置，`CompactionStyle::Fifo` 下创建时间早于该时长的 SST 文件会被删除。
+    /// 过期检查在选取压缩时进行（例如每次刷盘之后），没有写入时文件不会被删除。
+    pub fifo_ttl: Option<Duration>,
+
     // -------------------
     // Parameters that affect performance:
     /// Amount of data to build up in memory (backed by an unsorted log
     /// on disk) before converting to a sorted on-disk file.
     ///
     /// Larger values increase performance, especially during bulk loads.
-    /// Up to two write buffers may be held in memory at the same time,
-    /// so you may wish to adjust this parameter to control memory usage.
+    /// Up to `max_write_buffer_number` write buffers may be held in memory
+    /// at the same time, so you may wish to adjust this parameter to control memory usage.
     /// Also, a larger write buffer will result in a longer recovery time
     /// the next time the database is opened.
     pub write_buffer_size: usize,
 
+    /// 内存中最多同时存在的内存表数量（包括当前可写的内存表），至少为 2。
+    /// 当前内存表写满后转为不可变内存表排队等待刷盘，只有排队的不可变内存表达到
+    /// `max_write_buffer_number - 1` 个时写入才会停顿，因此较慢的刷盘不会立即阻塞写入。
+    pub max_write_buffer_number: usize,
+
+    /// 如果非 0，当前 WAL 写入的字节数达到该值时也会切换内存表并刷盘，即使内存表还没有达到
+    /// `write_buffer_size`。用于在内存表占用与 WAL 大小差别很大时限制重启恢复需要重放的日志量。
+    pub max_wal_bytes_per_memtable: usize,
+
+    /// 如果设置，不再需要的 WAL 文件移动到 db 目录下的 `archive/` 目录中，保留该时长后才删除，
+    /// 使 `WickDB::get_updates_since` 能够读到已经刷盘的更新，供复制程序把更新流发送给副本。
+    /// 默认为 `None`，WAL 文件不再需要时直接删除。
+    pub wal_archive_ttl: Option<Duration>,
+
+    /// 打开数据库时重放 WAL 的线程数。大于 1 时由当前线程解码 WAL 中的 WriteBatch，
+    /// 由这些线程并发插入内存表，每个线程负责按 key 哈希分区的一部分记录，
+    /// 所以同一个 key 的记录仍然按序列号顺序插入。可以缩短大量未刷盘的 WAL 的恢复时间。
+    /// Default is 1, which replays the WAL files in the current thread.
+    pub wal_recovery_threads: usize,
+
+    /// 如果为 true，写入流水线化：一个写入组写完 WAL 后由单独的线程插入内存表，
+    /// 同时下一个写入组就可以开始写 WAL。写入仍然在插入内存表并发布序列号之后才返回。
+    pub enable_pipelined_write: bool,
+
+    /// 如果为 true，一个写入组中较大的批次由各自等待中的写入线程并发插入内存表（跳表支持无锁的并发插入），
+    /// 以提高大批量写入时的吞吐。较小的批次仍然由处理写入组的线程插入。
+    pub allow_concurrent_memtable_write: bool,
+
+    /// 如果非 0，内存表的 arena 使用该大小的大页（如 2MB）分配以减少 TLB miss，
+    /// 必须是 2 的幂。只在 Linux 上并且系统预留了足够的大页时生效，否则使用普通内存。
+    /// 块缓存的大页见 `block_cache_allocator`。
+    pub memtable_huge_page_size: usize,
+
+    /// 如果为 true，arena 分配每个内存块时预先写入块的全部内存页，避免写入时因缺页产生的延迟抖动。
+    pub memtable_preallocate: bool,
+
+    /// 创建内存表中保存 entry 的数据结构。默认为支持并发插入的跳表 `SkipListRepFactory`，
+    /// 也可以使用按前缀分桶的 `HashSkipListRepFactory`（点查只搜索一个桶）或者
+    /// 读取时才排序的 `VectorRepFactory`（插入最快，适合只写的批量导入）。
+    pub memtable_factory: Arc<dyn MemTableRepFactory<C>>,
+
+    /// 决定刷盘和 compaction 写出的 sstable 的格式，table cache 也通过它打开 sstable。
+    /// 默认为支持全部功能的 `BlockBasedTableFactory`，也可以使用点查只需一次读取的
+    /// `CuckooTableFactory`（只适合定长的 key 和 value、不删除也不覆盖写的场景）。
+    pub table_factory: Arc<dyn TableFactory<C>>,
+
+    /// 如果非空，内存表的内存占用记账到该 `WriteBufferManager`，总占用超过它的预算时切换内存表并刷盘。
+    /// 可以在多个 db 之间共享以限制它们的内存表的总内存。
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+
     /// Number of open files that can be used by the DB.  You may need to
     /// increase this if your database has a large working set (budget
     /// one open file per 2MB of working set).
@@ -107,6 +584,11 @@ pub struct Options<C: Comparator> {
     /// If null, we will automatically create and use an 8MB internal cache.
     pub block_cache: Option<Arc<dyn Cache<Vec<u8>, Arc<Block>>>>,
 
+    /// 如果非空，放入块缓存的块被复制到该 `BlockCacheAllocator` 映射的大页（如 2MB）中，
+    /// 以减少大容量块缓存的 TLB miss。没有可用的大页时块仍然使用普通内存。
+    /// 共享块缓存的多个 db 也可以共享同一个分配器。
+    pub block_cache_allocator: Option<Arc<BlockCacheAllocator>>,
+
     /// Number of sstables that remains out of table cache
     pub non_table_cache_files: usize,
 
@@ -131,17 +613,131 @@ pub struct Options<C: Comparator> {
     /// initially populating a large database.
     pub max_file_size: u64,
 
+    /// 表构建器在调用 `File::write` 之前缓冲写入的字节数。写入总是以该大小的块发出，
+    /// 设置为页大小的整数倍时写入的偏移是对齐的。压缩时的小数据块不再逐块写入文件，
+    /// 系统调用次数因此大大减少。为 0 时不缓冲，每个块直接写入文件。
+    pub table_write_buffer_size: usize,
+
+    /// 打开 sstable 时从文件末尾一次读取的字节数，footer、元索引块、索引块以及过滤器块等
+    /// 落在这段数据中的块不再单独读取，使打开一个文件在远程或对象存储上只需要一次 IO。
+    /// 为 0 时只预读 footer，每个块单独读取。Default is 512KB.
+    pub table_open_prefetch_size: usize,
+
+    /// 如果非 0，对点查询读取的块进行抽样，关闭 db 时把读取最频繁的至多这么多个块的位置
+    /// 写入 db 目录下的 `HOT_BLOCKS` 文件，用于下次打开时预热块缓存（见 `preload_hot_blocks`）。
+    /// Default is 0.
+    pub hot_blocks_to_persist: usize,
+
+    /// 如果为 true，打开 db 时把上次关闭时记录在 `HOT_BLOCKS` 文件中的块读入块缓存，
+    /// 使重启后缓存重新填满之前的尾延迟不会骤增。预热在 `open_db` 返回之前完成，
+    /// 已经被压缩删除的文件中的块会被跳过。Default is false.
+    pub preload_hot_blocks: bool,
+
+    /// 一次压缩的输入文件（包括下一层的重叠文件）的总字节数上限，同时限制每个输出文件与祖父层
+    /// 重叠的字节数。为 0 时使用 `25 * max_file_size`。用于避免一个文件与下一层的大量数据重叠时
+    /// 产生一次巨大的压缩：按层大小压缩时会跳过超过该上限的文件。
+    pub max_compaction_bytes: u64,
+
+    /// 如果非 0，一个非 0 层的文件数超过该值并且平均文件大小小于 `max_file_size` 的 1/4 时，
+    /// 即使按层大小计算的得分不需要压缩，也会把该层中相邻的小文件在同一层内合并成较大的文件。
+    /// 用于整理大量导入外部文件等操作后碎片化成成千上万个小文件的层级。Default is 1000.
+    pub small_file_compaction_trigger: usize,
+
     /// Compress blocks using the specified compression algorithm.  This
     /// parameter can be changed dynamically. Default is SnappyCompression.
     pub compression: CompressionType,
 
+    /// 如果非空，按层级覆盖 `compression`：第 n 层的文件使用 `compression_per_level[n]`，
+    /// 超出长度的层级使用最后一个元素。memtable flush 产生的文件使用第 0 层的设置。
+    /// 例如 `[NoCompression, SnappyCompression, ZstdCompression]` 让较浅的层级压缩得更快，
+    /// 而数据量最大的深层获得更高的压缩率。
+    pub compression_per_level: Vec<CompressionType>,
+
+    /// 如果非空，写入最底层（更深的层级中没有文件）的 compaction 输出使用该压缩算法，
+    /// 覆盖 `compression_per_level`。最底层保存了绝大部分数据且很少被重写，
+    /// 适合使用 `Lz4hcCompression` 或 `ZstdCompression` 这种压缩慢但压缩率高的算法。
+    pub bottommost_compression: Option<CompressionType>,
+
+    /// The compression level used by `ZstdCompression`. Default is 3.
+    pub zstd_compression_level: i32,
+
+    /// The compression level used by `Lz4hcCompression`. Default is 9.
+    pub lz4hc_compression_level: i32,
+
+    /// sstable 中块的校验和算法。`Crc32c` 在支持 SSE4.2 或 ARMv8 CRC 指令的 CPU 上由硬件计算，
+    /// xxHash64 和 XXH3 在没有这些指令的 CPU 上更快。
+    /// 除 `Crc32` 以外的算法使用记录了校验和类型的 format version 1 的 footer，
+    /// 旧版本无法打开这些文件，因此需要显式开启。已有的文件总是按照其 footer 中记录的算法校验。
+    /// Default is `Crc32`.
+    pub checksum: ChecksumType,
+
+    /// sstable 的索引格式。`TwoLevelIndexSearch` 使得巨大的 sstable 不需要常驻内存的
+    /// 数兆字节的索引块。旧格式（单个索引块）的文件总是可以读取。
+    /// Default is `BinarySearch`.
+    pub index_type: IndexType,
+
     /// 如果为 true，将重用现有的 MANIFEST 和日志文件
     /// 可以显著加快打开速度。
     pub reuse_logs: bool,
 
+    /// 当前 MANIFEST 写入的字节数达到该值后，下一次版本变更会写入新的 MANIFEST。
+    /// 被替换的 MANIFEST 作为 `MANIFEST.bak` 保留一代，新的 MANIFEST 校验失败时打开 db 会回退到它。
+    /// Default is 1GB.
+    pub max_manifest_file_size: u64,
+
+    /// 为 `MANIFEST.bak` 保留的已过期文件（被压缩掉的 sstable 以及之后的日志）的最大数量。
+    /// 超过后丢弃该备份并删除这些文件，此后无法再回退到该备份。
+    /// Default is 64.
+    pub max_manifest_backup_files: usize,
+
     /// 如果非空，则使用指定的过滤策略来减少磁盘读取。
+    /// 使用 `ChainedFilterPolicy` 可以组合多个策略（例如完整键的布隆过滤器加上前缀的布隆过滤器），
+    /// 每个策略的过滤器作为单独的元数据块存储，点查询和前缀查找会检查所有的过滤器。
     pub filter_policy: Option<Arc<dyn FilterPolicy>>,
 
+    /// 如果非空，键的前缀也会被加入过滤器，使得前缀查找可以跳过不包含该前缀的块。
+    /// 修改前缀提取器后，旧的 sstable 中的过滤器会被忽略。
+    pub prefix_extractor: Option<Arc<dyn SliceTransform>>,
+
+    /// 如果为 true，完整的用户键会被加入过滤器。只在设置了 `prefix_extractor` 时
+    /// 可以关闭，此时过滤器只包含前缀，点查会探测键的前缀。使用 `ChainedFilterPolicy` 时
+    /// 由每个策略的 `FilterKeys` 决定。
+    /// Default is true.
+    pub whole_key_filtering: bool,
+
+    /// 如果非空，每当写入限流状态（正常/延迟/停止）变化时都会被通知
+    pub write_stall_listener: Option<Arc<dyn WriteStallListener>>,
+
+    /// 用于合并 `WriteBatch::merge` 写入的操作数。
+    /// 如果为空，读取到合并操作数时会返回错误
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// 如果非空，压缩时会对每个键的最新版本调用该过滤器，决定保留、删除还是修改值
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+
+    /// 如果非空，每组写入追加到日志之后、写入内存表之前都会调用该钩子，钩子可以否决提交
+    pub pre_commit_hook: Option<Arc<dyn PreCommitHook>>,
+
+    /// 如果非空，点查在本地找不到键时会从该远程冷存储读取，此时本地的删除标记不会被压缩丢弃
+    pub cold_store: Option<Arc<dyn ColdStore>>,
+
+    /// 冷存储中不存在的键会被缓存，避免重复的远程查找。这是缓存的键的总字节数上限，为 0 时不缓存。
+    /// Default is 1MB
+    pub cold_store_negative_cache_size: usize,
+
+    /// 如果为 true，迭代器在每一层的表上执行 seek 和 next 的延迟会按层记录到直方图中，
+    /// 通过 `WickDB::iter_latency_stats` 获取，用于判断扫描变慢是因为 L0 文件堆积还是底层磁盘延迟。
+    /// 每次移动迭代器都会额外读取两次时钟。Default is false.
+    pub enable_iter_latency_stats: bool,
+
+    /// 如果为 true，允许通过 `WickDB::raw_iter` 读取包含序列号和值类型的内部条目，包括被覆盖的旧版本、
+    /// 删除标记和合并操作数，供备份、复制工具和调试使用。Default is false.
+    pub allow_raw_iterator: bool,
+
+    /// 每个 sstable 构建时都会由这些工厂创建收集器，收集到的用户自定义属性
+    /// 写入 sstable 的属性块中。
+    pub table_properties_collector_factories: Vec<Arc<dyn TablePropertiesCollectorFactory>>,
+
     /// 日志记录
     /// 在开发模式下，默认使用std输出
     /// 在release模式下，默认使用文件`LOG`进行输出
@@ -155,14 +751,18 @@ impl<C: Comparator> Options<C> {
     /// Maximum number of bytes in all compacted files.  We avoid expanding
     /// the lower level file set of a compaction if it would make the
     /// total compaction cover more than this many bytes.
-    pub(crate) fn expanded_compaction_byte_size_limit(&self) -> u64 {
-        25 * self.max_file_size
+    pub(crate) fn max_compaction_bytes(&self) -> u64 {
+        if self.max_compaction_bytes > 0 {
+            self.max_compaction_bytes
+        } else {
+            25 * self.max_file_size
+        }
     }
 
     /// Maximum bytes of overlaps in grandparent (i.e., level+2) before we
     /// stop building a single file in a level-> level+1 compaction.
     pub(crate) fn max_grandparent_overlap_bytes(&self) -> u64 {
-        10 * self.max_file_size
+        (10 * self.max_file_size).min(self.max_compaction_bytes())
     }
 
     /// Maximum bytes of total files in a given level
@@ -173,12 +773,53 @@ impl<C: Comparator> Options<C> {
         // Result for both level-0 and level-1
         let mut result = self.l1_max_bytes;
         while level > 1 {
-            result *= 10;
+            result *= LEVEL_SIZE_MULTIPLIER;
             level -= 1;
         }
         result
     }
 
+    /// The compression of the tables written into the given level
+    pub(crate) fn compression_for_level(&self, level: usize) -> CompressionType {
+        match self.compression_per_level.last() {
+            Some(last) => *self.compression_per_level.get(level).unwrap_or(last),
+            None => self.compression,
+        }
+    }
+
+    /// Returns `Error::NotSupported` if any configured compression type is
+    /// unavailable in this build
+    pub(crate) fn check_compressions(&self) -> Result<()> {
+        self.compression.check_supported()?;
+        for c in self
+            .compression_per_level
+            .iter()
+            .chain(self.bottommost_compression.iter())
+        {
+            c.check_supported()?;
+        }
+        Ok(())
+    }
+
+    /// Apply the merge `operands` (ordered from the newest to the oldest) to
+    /// the `existing` value of `key` by the `merge_operator`
+    pub(crate) fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>> {
+        match &self.merge_operator {
+            Some(op) => {
+                let operands: Vec<&[u8]> = operands.iter().rev().map(|o| o.as_slice()).collect();
+                op.full_merge(key, existing, &operands)
+            }
+            None => Err(Error::InvalidArgument(
+                "Merge operands found but no merge operator is configured".to_owned(),
+            )),
+        }
+    }
+
     /// Reserve `non_table_cache_files` files or so for other uses and give the rest to TableCache
     pub(crate) fn table_cache_size(&self) -> usize {
         self.max_open_files - self.non_table_cache_files
@@ -189,13 +830,21 @@ impl<C: Comparator> Options<C> {
         &mut self,
         db_path: &str,
         storage: &S,
-    ) {
+    ) where
+        C: 'static,
+    {
         if self.max_mem_compact_level < 2 {
             self.max_mem_compact_level = 2
         }
         self.max_open_files =
             Self::clip_range(self.max_open_files, 64 + self.non_table_cache_files, 50000);
         self.write_buffer_size = Self::clip_range(self.write_buffer_size, 64 << 10, 1 << 30);
+        self.max_write_buffer_number = self.max_write_buffer_number.max(2);
+        self.max_subcompactions = self.max_subcompactions.max(1);
+        self.max_background_jobs = self.max_background_jobs.max(2);
+        let universal = &mut self.universal_compaction;
+        universal.min_merge_width = universal.min_merge_width.max(2);
+        universal.max_merge_width = universal.max_merge_width.max(universal.min_merge_width);
         self.max_file_size = Self::clip_range(self.max_file_size, 1 << 20, 1 << 30);
         self.block_size = Self::clip_range(self.block_size, 1 << 10, 4 << 20);
         self.apply_logger(storage, db_path);
@@ -206,12 +855,38 @@ impl<C: Comparator> Options<C> {
             }
             self.block_cache = Some(Arc::new(ShardedCache::new(shards)))
         }
-        if let Some(fp) = std::mem::replace(&mut self.filter_policy, None) {
-            self.filter_policy = Some(Arc::new(InternalFilterPolicy::new(fp)));
-        } else {
-            let bf = BloomFilter::new(10);
-            self.filter_policy = Some(Arc::new(InternalFilterPolicy::new(Arc::new(bf))))
-        }
+        let user_policy = self
+            .filter_policy
+            .take()
+            .unwrap_or_else(|| Arc::new(BloomFilter::new(10)));
+        self.filter_policy = Some(match user_policy.as_chained() {
+            Some(chain) => {
+                // 链中的每个策略各自构建一个过滤器，同名的过滤器只保留第一个
+                let mut policies = ChainedFilterPolicy::new();
+                let mut names = HashSet::default();
+                for (p, keys) in chain.policies() {
+                    let policy = InternalFilterPolicy::new(p.clone(), self.comparator.clone());
+                    let policy = match (keys, &self.prefix_extractor) {
+                        (FilterKeys::WholeKey, _) => policy,
+                        (FilterKeys::Prefix, Some(extractor)) => {
+                            policy.with_prefix_extractor(extractor.clone(), false)
+                        }
+                        (FilterKeys::Prefix, None) => continue,
+                    };
+                    if names.insert(policy.name().to_owned()) {
+                        policies = policies.chain(Arc::new(policy), *keys);
+                    }
+                }
+                Arc::new(policies)
+            }
+            None => {
+                let mut policy = InternalFilterPolicy::new(user_policy, self.comparator.clone());
+                if let Some(extractor) = self.prefix_extractor.clone() {
+                    policy = policy.with_prefix_extractor(extractor, self.whole_key_filtering);
+                }
+                Arc::new(policy)
+            }
+        });
     }
 
     fn apply_logger<S: Storage>(&mut self, storage: &S, db_path: &str) {
@@ -235,30 +910,88 @@ impl<C: Comparator> Options<C> {
     }
 }
 
-impl<C: Comparator> Default for Options<C> {
+impl<C: Comparator + 'static> Default for Options<C> {
     fn default() -> Self {
         Options {
             comparator: C::default(),
-            create_if_missing: true,
-            error_if_exists: false,
+            open_mode: OpenMode::OpenOrCreate,
             paranoid_checks: false,
+            memtable_entry_checksum: false,
             max_levels: 7,
             l0_compaction_threshold: 4,
             l0_slowdown_writes_threshold: 8,
             l0_stop_writes_threshold: 12,
             l1_max_bytes: 64 * 1024 * 1024, // 64MB
+            level_compaction_dynamic_level_bytes: false,
             max_mem_compact_level: 2,
             read_bytes_period: 1048576,
+            idle_compaction_interval: None,
+            idle_compaction_max_ops: 0,
+            orphan_file_scan_interval: None,
+            orphan_file_grace_period: Duration::from_secs(3600),
+            block_scrub_interval: None,
+            block_scrub_blocks_per_run: 16,
+            block_scrub_listener: None,
+            stats_persist_period: None,
+            stats_history_retention: Duration::from_secs(7 * 24 * 3600),
+            compaction_job_max_time: None,
+            compaction_job_max_bytes: None,
+            max_subcompactions: 1,
+            max_background_jobs: 2,
+            background_panic_policy: BackgroundPanicPolicy::Fail,
+            compaction_drop_page_cache: true,
+            compaction_style: CompactionStyle::Level,
+            compaction_pri: CompactionPri::RoundRobin,
+            universal_compaction: UniversalCompactionOptions::default(),
+            fifo_max_table_files_size: 1 << 30,
+            fifo_ttl: None,
             write_buffer_size: 4 * 1024 * 1024, // 4MB
+            max_write_buffer_number: 2,
+            max_wal_bytes_per_memtable: 0,
+            wal_archive_ttl: None,
+            wal_recovery_threads: 1,
+            enable_pipelined_write: false,
+            allow_concurrent_memtable_write: false,
+            memtable_huge_page_size: 0,
+            memtable_preallocate: false,
+            memtable_factory: Arc::new(SkipListRepFactory),
+            table_factory: Arc::new(BlockBasedTableFactory),
+            write_buffer_manager: None,
             max_open_files: 500,
             block_cache: None,
+            block_cache_allocator: None,
             non_table_cache_files: 10,
             block_size: 4 * 1024, // 4KB
             block_restart_interval: 16,
             max_file_size: 2 * 1024 * 1024, // 2MB
+            table_write_buffer_size: 1024 * 1024, // 1MB
+            table_open_prefetch_size: 512 * 1024, // 512KB
+            hot_blocks_to_persist: 0,
+            preload_hot_blocks: false,
+            max_compaction_bytes: 0,
+            small_file_compaction_trigger: 1000,
             compression: CompressionType::SnappyCompression,
+            compression_per_level: vec![],
+            bottommost_compression: None,
+            zstd_compression_level: 3,
+            lz4hc_compression_level: 9,
+            checksum: ChecksumType::Crc32,
+            index_type: IndexType::BinarySearch,
             reuse_logs: false,
+            max_manifest_file_size: 1 << 30,
+            max_manifest_backup_files: 64,
             filter_policy: None,
+            prefix_extractor: None,
+            whole_key_filtering: true,
+            write_stall_listener: None,
+            merge_operator: None,
+            compaction_filter: None,
+            pre_commit_hook: None,
+            cold_store: None,
+            cold_store_negative_cache_size: 1 << 20,
+            enable_iter_latency_stats: false,
+            allow_raw_iterator: false,
+            table_properties_collector_factories: vec![],
             logger: None,
             logger_level: LevelFilter::Warn,
         }
@@ -279,6 +1012,26 @@ pub struct ReadOptions {
     /// 如果“snapshot”为“None”，则从提供的快照开始读取（该快照必须属于正在读取且不得已释放的数据库）。
     /// 如果“snapshot”为“None”，则使用此读取操作开始时状态的隐式快照。
     pub snapshot: Option<Snapshot>,
+
+    /// If set, the read waits until the db has published the given sequence, which
+    /// is usually returned by `WickDB::write_with_sequence`, so the read is guaranteed
+    /// to see that write. The read fails if the sequence is not visible in time or
+    /// `snapshot` is older than it.
+    pub min_sequence_visible: Option<u64>,
+
+    /// 如果用户键带有时间戳（见 `Comparator::timestamp_size`），只读取时间戳不晚于它的版本，
+    /// 为 `None` 时可以读取所有时间戳的版本。用户键不带时间戳时必须为 `None`。
+    pub timestamp: Option<u64>,
+
+    /// 如果非空，读取在每次读取块之前检查是否已超过该时间点，超过时返回 `Error::TimedOut`。
+    /// 只在内存表中完成的读取不会超时。迭代器超时后变为无效，`status` 返回该错误。
+    pub deadline: Option<Instant>,
+
+    /// 读取块时传给存储层的 IO 优先级提示，压缩的读取使用 `IoPriority::Background`。
+    pub io_priority: IoPriority,
+
+    /// 如果为 true，从文件读取块后建议操作系统丢弃该范围的页缓存，用于只读一次的数据。
+    pub drop_page_cache: bool,
 }
 
 impl Default for ReadOptions {
@@ -287,12 +1040,17 @@ impl Default for ReadOptions {
             verify_checksums: false,
             fill_cache: true,
             snapshot: None,
+            min_sequence_visible: None,
+            timestamp: None,
+            deadline: None,
+            io_priority: IoPriority::Foreground,
+            drop_page_cache: false,
         }
     }
 }
 
 /// Options that control write operations
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct WriteOptions {
     /// If true, the write will be flushed from the operating system
     /// buffer cache before the write is considered complete.
@@ -309,3 +1067,72 @@ pub struct WriteOptions {
     /// system call followed by "fsync()".
     pub sync: bool,
 }
+
+/// Whether a manual compaction rewrites the files in the bottommost level holding
+/// data of the range
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BottommostLevelCompaction {
+    /// The bottommost files are only rewritten when they overlap the compacted
+    /// files of the upper levels
+    Skip,
+    /// Rewrite the bottommost files if `Options::compaction_filter` is set, so that
+    /// the filter sees every entry in the range
+    IfHaveCompactionFilter,
+    /// Always rewrite the bottommost files, e.g. to drop the obsolete versions and
+    /// the tombstones left by bulk deletes
+    Force,
+}
+
+/// Options that control `WickDB::compact_range_with`
+#[derive(Clone, Copy, Debug)]
+pub struct CompactRangeOptions {
+    /// If set, the range is compacted down into this level instead of the
+    /// bottommost level holding data of the range. It must not be above any level
+    /// holding data of the range since a compaction only moves data down.
+    pub target_level: Option<usize>,
+
+    /// If true, no automatic compaction is picked until the manual compaction
+    /// finishes, so the files of the range are not rearranged by other compactions
+    /// between its steps. Memtable flushes are not blocked.
+    pub exclusive_manual_compaction: bool,
+
+    /// Whether the bottommost files of the range are rewritten. Ignored if
+    /// `target_level` is set.
+    pub bottommost_level_compaction: BottommostLevelCompaction,
+}
+
+impl Default for CompactRangeOptions {
+    fn default() -> Self {
+        CompactRangeOptions {
+            target_level: None,
+            exclusive_manual_compaction: true,
+            bottommost_level_compaction: BottommostLevelCompaction::Skip,
+        }
+    }
+}
+
+/// Options that control `WickDB::ingest_external_file`
+#[derive(Clone, Copy, Debug)]
+pub struct IngestOptions {
+    /// If true, the memtables are flushed when they overlap the key ranges of
+    /// the ingested files. Otherwise the ingestion fails in that case.
+    pub allow_blocking_flush: bool,
+
+    /// If true, a global sequence number is assigned to the ingested files when
+    /// they overlap the existing data or a snapshot exists. Otherwise the
+    /// ingestion fails in that case.
+    pub allow_global_seqno: bool,
+
+    /// If true, the external files are deleted after they're ingested.
+    pub move_files: bool,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        IngestOptions {
+            allow_blocking_flush: true,
+            allow_global_seqno: true,
+            move_files: false,
+        }
+    }
+}
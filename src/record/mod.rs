@@ -232,7 +232,8 @@ mod tests {
 
         // Replace the current writer with a new one created from the current StringFile
         pub fn reopen_for_append(&mut self) {
-            let writer = Writer::new(StringFile::new(self.source.clone()));
+            let len = self.source.borrow().len() as u64;
+            let writer = Writer::with_length(StringFile::new(self.source.clone()), len);
             self.writer = writer;
         }
 
@@ -457,6 +458,8 @@ mod tests {
         assert_eq!(BLOCK_SIZE - HEADER_SIZE + 4, log.written_bytes());
         log.write("");
         log.write("bar");
+        // The padding of the trailer is counted too
+        assert_eq!(log.written_bytes() as u64, log.writer.written_bytes());
         assert_eq!(big_string("foo", n).as_str(), log.read());
         assert_eq!("", log.read());
         assert_eq!("bar", log.read());
@@ -479,6 +482,8 @@ mod tests {
         log.write("hello");
         log.reopen_for_append();
         log.write("world");
+        // The bytes written before reopening are counted too
+        assert_eq!(log.written_bytes() as u64, log.writer.written_bytes());
         assert_eq!("hello", log.read());
         assert_eq!("world", log.read());
         assert_eq!(EOF, log.read());
@@ -63,6 +63,8 @@ pub struct Reader<F: File> {
     eof: bool,
     // Offset of the last record returned by `read_record`.
     last_record_offset: u64,
+    // Offset of the first location past the last record returned by `read_record`.
+    last_record_end_offset: u64,
     // Offset of the first location past the end of buf.
     end_of_buffer_offset: u64,
     // cache for current reading block
@@ -91,6 +93,7 @@ impl<F: File> Reader<F> {
             buf_length: 0,
             eof: false,
             last_record_offset: 0,
+            last_record_end_offset: 0,
             end_of_buffer_offset: 0,
             initial_offset,
             resyncing: initial_offset > 0,
@@ -142,6 +145,8 @@ impl<F: File> Reader<F> {
                             }
                             // update record offset
                             self.last_record_offset = physical_record_offset;
+                            self.last_record_end_offset =
+                                self.end_of_buffer_offset - self.buf_length as u64;
                             buf.clear();
                             buf.append(&mut record.data);
                             return true;
@@ -190,6 +195,8 @@ impl<F: File> Reader<F> {
                                 buf.extend(record.data);
                                 // notice that we update the last_record_offset after we get the Last part but not the First
                                 self.last_record_offset = prospective_record_offset;
+                                self.last_record_end_offset =
+                                    self.end_of_buffer_offset - self.buf_length as u64;
                                 return true;
                             }
                         }
@@ -226,6 +233,13 @@ impl<F: File> Reader<F> {
         }
     }
 
+    /// Returns the offset of the first location past the last record returned
+    /// by `read_record`. The bytes after it are not read out as records.
+    #[inline]
+    pub fn last_record_end_offset(&self) -> u64 {
+        self.last_record_end_offset
+    }
+
     // Returns the last_record_offset.
     // Temporary for test.
     #[inline]
@@ -12,6 +12,8 @@ pub struct Writer<F: File> {
     block_offset: usize,
     // 缓存存储了不同记录类型的初始CRC值，为了和data一起计算新的crc
     crc_cache: [u32; (RecordType::Last as usize + 1) as usize],
+    // 通过该 writer 写入文件的总字节数（包括头部和填充）
+    written: u64,
 }
 
 
@@ -30,9 +32,19 @@ impl<F: File> Writer<F> {
             dest,
             block_offset: 0,
             crc_cache: cache,
+            written: 0,
         }
     }
 
+    /// 创建一个向已有 `dest_length` 字节的文件追加记录的 Writer，
+    /// 从文件末尾所在块的偏移处继续写入，已有的字节也计入 `written_bytes`
+    pub fn with_length(dest: F, dest_length: u64) -> Self {
+        let mut w = Self::new(dest);
+        w.block_offset = (dest_length % BLOCK_SIZE as u64) as usize;
+        w.written = dest_length;
+        w
+    }
+
     /// 将一个字节切片追加到底层日志文件中
     pub fn add_record(&mut self, s: &[u8]) -> Result<()> {
         let mut left = s.len(); // 剩余要写入的数据长度
@@ -84,13 +96,22 @@ impl<F: File> Writer<F> {
     fn fill_block_with_zeros(&mut self, leftover: usize) -> Result<()> {
         if leftover > 0 {
             self.dest.write(&vec![0; leftover])?;
+            self.written += leftover as u64;
         }
         Ok(())
     }
+
+    /// Returns the total bytes written to the file by this writer, including
+    /// the record headers and the block paddings
+    #[inline]
+    pub fn written_bytes(&self) -> u64 {
+        self.written
+    }
+
     /// Sync the underlying file
     #[inline]
     pub fn sync(&mut self) -> Result<()> {
-        self.dest.flush()
+        self.dest.sync()
     }
 
     // 将格式化的字节写入文件中 输入 rt（记录类型）和 data（字节数组)
@@ -128,6 +149,7 @@ impl<F: File> Writer<F> {
         // self.dest.flush()?;
         // 更新块偏移量
         self.block_offset += HEADER_SIZE + size;
+        self.written += (HEADER_SIZE + size) as u64;
         Ok(())
     }
 }
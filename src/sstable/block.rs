@@ -1,14 +1,22 @@
+use crate::db::format::{pack_seq_and_type, unpack_seq_and_type, INTERNAL_KEY_TAIL};
 use crate::iterator::Iterator;
-use crate::util::coding::{decode_fixed_32, put_fixed_32};
+use crate::mem::arena::{AllocatedBytes, BlockCacheAllocator};
+use crate::util::buffer_pool::PooledBuffer;
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
 use crate::util::comparator::Comparator;
 use crate::util::varint::VarintU32;
 use crate::{Error, Result};
 use std::cmp::{min, Ordering};
+use std::ops::Deref;
 use std::sync::Arc;
 
 // TODO: remove all magic number
 const U32_LEN: usize = std::mem::size_of::<u32>();
 
+// The max number of the restart points probed by interpolation in a seek before
+// falling back to the binary search, which bounds the cost on skewed keys
+const MAX_INTERPOLATION_PROBES: usize = 4;
+
 /// `Block` is consist of one or more key/value entries and a block trailer.
 /// Block entry shares key prefix with its preceding key until a `restart`
 /// point reached. A block should contains at least one restart point.
@@ -27,7 +35,7 @@ const U32_LEN: usize = std::mem::size_of::<u32>();
 ///
 #[derive(Clone, Debug)]
 pub struct Block {
-    data: Arc<Vec<u8>>,
+    data: Arc<BlockContents>,
     // 重启点数组在data中的偏移
     restart_offset: u32,
     //  重启点数组的长度
@@ -49,7 +57,7 @@ impl Block {
             // make sure the size is enough for restarts
             if restarts_len as usize <= max_restarts_allowed {
                 return Ok(Self {
-                    data: Arc::new(data),
+                    data: Arc::new(BlockContents::Heap(data)),
                     restart_offset: (size - (1 + restarts_len as usize) * U32_LEN) as u32,
                     restarts_len,
                 });
@@ -60,6 +68,22 @@ impl Block {
         ))
     }
 
+    /// Creates a `Block` like `new` and copies its contents into the huge pages
+    /// of `allocator` if possible, which is used for the blocks put into the
+    /// block cache.
+    pub fn new_in(data: Vec<u8>, allocator: &BlockCacheAllocator) -> Result<Self> {
+        let mut block = Self::new(data)?;
+        if let Some(bytes) = allocator.copy(&block.data) {
+            block.data = Arc::new(BlockContents::HugePages(bytes));
+        }
+        Ok(block)
+    }
+
+    /// Returns true if the contents are copied into the huge pages
+    pub fn is_huge_page_backed(&self) -> bool {
+        matches!(*self.data, BlockContents::HugePages(_))
+    }
+
     /// 通过Iterator对象，调用者就可以遍历访问Block的存储的k/v对
     pub fn iter<C: Comparator>(&self, cmp: C) -> BlockIterator<C> {
         BlockIterator::new(
@@ -81,20 +105,39 @@ impl Block {
 impl Default for Block {
     fn default() -> Self {
         Self {
-            data: Arc::new(vec![]),
+            data: Arc::new(BlockContents::Heap(vec![])),
             restart_offset: 0,
             restarts_len: 0,
         }
     }
 }
 
+// The memory holding the contents of a block
+#[derive(Debug)]
+pub(crate) enum BlockContents {
+    Heap(Vec<u8>),
+    // Copied by a `BlockCacheAllocator`
+    HugePages(AllocatedBytes),
+}
+
+impl Deref for BlockContents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BlockContents::Heap(data) => data,
+            BlockContents::HugePages(bytes) => bytes,
+        }
+    }
+}
+
 /// Iterator for every entry in the block
 pub struct BlockIterator<C: Comparator> {
     cmp: C,
     err: Option<Error>,
 
     // underlying block data
-    data: Arc<Vec<u8>>,
+    data: Arc<BlockContents>,
 
     /*
       restarts
@@ -116,10 +159,14 @@ pub struct BlockIterator<C: Comparator> {
     // Buffer for a completed key
     // The key is saperated in multiple segments in `data`.
     key: Vec<u8>,
+    // The sequence number replacing the ones of the keys if it's not 0
+    global_seqno: u64,
+    // `key` with the sequence number replaced by `global_seqno`
+    seqno_key: Vec<u8>,
 }
 
 impl<C: Comparator> BlockIterator<C> {
-    pub fn new(cmp: C, data: Arc<Vec<u8>>, restarts: u32, restarts_len: u32) -> Self {
+    pub(crate) fn new(cmp: C, data: Arc<BlockContents>, restarts: u32, restarts_len: u32) -> Self {
         Self {
             cmp,
             err: None,
@@ -133,6 +180,26 @@ impl<C: Comparator> BlockIterator<C> {
             value_len: 0,
             key_offset: 0,
             key: vec![],
+            global_seqno: 0,
+            seqno_key: vec![],
+        }
+    }
+
+    /// Replaces the sequence numbers of all the keys with `seqno`, which is how
+    /// the data blocks of an ingested table are read. The keys must be internal
+    /// keys. Nothing is replaced if `seqno` is 0.
+    pub fn with_global_seqno(mut self, seqno: u64) -> Self {
+        self.global_seqno = seqno;
+        self
+    }
+
+    // Returns the current key with the global sequence number applied
+    #[inline]
+    fn current_key(&self) -> &[u8] {
+        if self.global_seqno == 0 {
+            &self.key
+        } else {
+            &self.seqno_key
         }
     }
 
@@ -147,6 +214,38 @@ impl<C: Comparator> BlockIterator<C> {
         decode_fixed_32(&self.data[self.restarts as usize + index as usize * 4..])
     }
 
+    // Returns the key of the restart point, which is completely stored, or `None`
+    // if the entry is corrupted
+    fn restart_key(&self, index: u32) -> Option<&[u8]> {
+        let region_offset = self.get_restart_point(index);
+        let src = &self.data[region_offset as usize..];
+        let (shared, n0) = VarintU32::common_read(src);
+        let (not_shared, n1) = VarintU32::common_read(&src[n0 as usize..]);
+        let (_, n2) = VarintU32::common_read(&src[(n1 + n0) as usize..]);
+        if shared != 0 {
+            return None;
+        }
+        let key_offset = region_offset as usize + (n0 + n1 + n2) as usize;
+        Some(&self.data[key_offset..key_offset + not_shared as usize])
+    }
+
+    // Estimates the restart point in (left, right] to probe by the numeric values
+    // of the keys, assuming they're uniformly distributed between the keys of
+    // `left` and `right`. Returns `None` if the values can't tell.
+    fn interpolate(&self, left: u32, right: u32, target: u64) -> Option<u32> {
+        let lo = self.cmp.numeric_key(self.restart_key(left)?)?;
+        let hi = self.cmp.numeric_key(self.restart_key(right)?)?;
+        if target <= lo {
+            return Some(left + 1);
+        }
+        if target >= hi {
+            return Some(right);
+        }
+        let span = (right - left - 1) as u128;
+        let offset = (target - lo) as u128 * span / (hi - lo) as u128;
+        Some(left + 1 + offset as u32)
+    }
+
     fn seek_to_restart_point(&mut self, index: u32) {
         self.key.clear();
         self.restart_index = index;
@@ -183,6 +282,10 @@ impl<C: Comparator> BlockIterator<C> {
         for i in shared as usize..total_key_len {
             self.key[i] = delta[i - shared as usize]
         }
+        // The raw key is kept since the next key shares its prefix
+        if self.global_seqno != 0 {
+            replace_seqno(&mut self.seqno_key, &self.key, self.global_seqno);
+        }
 
         // update restart index
         while self.restart_index + 1 < self.restarts_len
@@ -236,24 +339,36 @@ impl<C: Comparator> Iterator for BlockIterator<C> {
 
     // find the first entry in block with key>= target
     fn seek(&mut self, target: &[u8]) {
-        // binary search in restart array to find the last restart point with a key < target
+        // search in restart array to find the last restart point with a key < target, by
+        // binary search or by interpolation if the comparator declares numeric keys
         let mut left = 0;
         let mut right = self.restarts_len - 1;
+        let target_num = self.cmp.numeric_key(target);
+        let mut probes = 0;
         while left < right {
-            let mid = (left + right + 1) / 2;
-            let region_offset = self.get_restart_point(mid);
-            let src = &self.data[region_offset as usize..];
-            let (shared, n0) = VarintU32::common_read(src);
-            let (not_shared, n1) = VarintU32::common_read(&src[n0 as usize..]);
-            let (_, n2) = VarintU32::common_read(&src[(n1 + n0) as usize..]);
-            if shared != 0 {
-                // The first key from restart offset should be completely stored.
-                self.corruption_err();
-                return;
+            // Any restart point in (left, right] keeps the search correct, so the
+            // interpolation only changes how fast the range shrinks
+            let mid = match target_num {
+                Some(t) if probes < MAX_INTERPOLATION_PROBES => {
+                    probes += 1;
+                    self.interpolate(left, right, t)
+                }
+                _ => None,
+            }
+            .unwrap_or((left + right).div_ceil(2));
+            let mut mid_key = match self.restart_key(mid) {
+                Some(key) => key,
+                None => {
+                    // The first key from restart offset should be completely stored.
+                    self.corruption_err();
+                    return;
+                }
+            };
+            let mut seqno_key = vec![];
+            if self.global_seqno != 0 {
+                replace_seqno(&mut seqno_key, mid_key, self.global_seqno);
+                mid_key = &seqno_key;
             }
-            let key_offset = region_offset + (n0 + n1 + n2) as u32;
-            let key_len = (shared + not_shared) as usize;
-            let mid_key = &self.data[key_offset as usize..key_offset as usize + key_len];
             match self.cmp.compare(mid_key, target) {
                 Ordering::Less => left = mid,
                 _ => right = mid - 1,
@@ -268,7 +383,7 @@ impl<C: Comparator> Iterator for BlockIterator<C> {
             if !self.parse_block_entry() {
                 return;
             }
-            match self.cmp.compare(self.key.as_slice(), target) {
+            match self.cmp.compare(self.current_key(), target) {
                 Ordering::Less => {}
                 _ => return,
             }
@@ -305,7 +420,7 @@ impl<C: Comparator> Iterator for BlockIterator<C> {
 
     fn key(&self) -> &[u8] {
         self.valid_or_panic();
-        &self.key
+        self.current_key()
     }
 
     fn value(&self) -> &[u8] {
@@ -322,14 +437,27 @@ impl<C: Comparator> Iterator for BlockIterator<C> {
     }
 }
 
+// Copies the internal key `key` into `dst` with its sequence number replaced
+// by `seqno`
+fn replace_seqno(dst: &mut Vec<u8>, key: &[u8], seqno: u64) {
+    dst.clear();
+    dst.extend_from_slice(key);
+    if key.len() >= INTERNAL_KEY_TAIL {
+        let n = key.len() - INTERNAL_KEY_TAIL;
+        let (_, t) = unpack_seq_and_type(decode_fixed_64(&key[n..]));
+        dst.truncate(n);
+        put_fixed_64(dst, pack_seq_and_type(seqno, t));
+    }
+}
+
 /// `BlockBuilder` 用于构建block
 /// block的尾部存储了所有重启点的偏移量，查找时可用于进行二分查找对于特定的键。
 pub struct BlockBuilder<C: Comparator> {
     //每个重启点之间的键值对数量 options.block_restart_interval 设置，默认16
     block_restart_interval: usize,
     cmp: C,
-    // block的内容
-    buffer: Vec<u8>,
+    // block的内容，从当前线程的缓冲池中获取，在 builder 销毁时归还
+    buffer: PooledBuffer,
     // 重启点
     restarts: Vec<u32>,
     //重启后生成的entry数
@@ -349,7 +477,7 @@ impl<C: Comparator> BlockBuilder<C> {
         Self {
             block_restart_interval,
             cmp,
-            buffer: vec![],
+            buffer: PooledBuffer::new(),
             finished: false,
             counter: 0,
             restarts: vec![0; 1], //first restart point is at offset 0
@@ -456,9 +584,12 @@ mod tests {
     use crate::sstable::block::BlockBuilder;
     use crate::sstable::block::{Block, BlockIterator};
     use crate::util::coding::{decode_fixed_32, put_fixed_32};
-    use crate::util::comparator::BytewiseComparator;
+    use crate::util::comparator::{BigEndianU64Comparator, BytewiseComparator, Comparator};
     use crate::util::varint::VarintU32;
+    use std::cmp::Ordering;
     use std::str;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::Arc;
 
     fn new_test_block() -> Vec<u8> {
         let mut samples = vec!["1", "12", "123", "abc", "abd", "acd", "bbb"];
@@ -717,4 +848,71 @@ mod tests {
             assert!(!iter.valid());
         }
     }
+
+    #[derive(Default, Clone)]
+    struct CountingComparator<C: Comparator> {
+        inner: C,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl<C: Comparator> Comparator for CountingComparator<C> {
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            self.count.fetch_add(1, AtomicOrdering::Relaxed);
+            self.inner.compare(a, b)
+        }
+
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        fn separator(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+            self.inner.separator(a, b)
+        }
+
+        fn successor(&self, key: &[u8]) -> Vec<u8> {
+            self.inner.successor(key)
+        }
+
+        fn numeric_key(&self, key: &[u8]) -> Option<u64> {
+            self.inner.numeric_key(key)
+        }
+    }
+
+    #[test]
+    fn test_interpolation_seek() {
+        let mut builder = BlockBuilder::new(1, BytewiseComparator::default());
+        for i in 0..10000u64 {
+            let key = (i * 7).to_be_bytes();
+            builder.add(&key, &i.to_le_bytes());
+        }
+        let block = Block::new(Vec::from(builder.finish())).unwrap();
+        let binary = CountingComparator::<BytewiseComparator>::default();
+        let interpolation = CountingComparator::<BigEndianU64Comparator>::default();
+        let mut binary_iter = block.iter(binary.clone());
+        let mut interpolation_iter = block.iter(interpolation.clone());
+        let mut targets: Vec<u64> = (0..500).map(|i| i * 139 + 3).collect();
+        // Out of the range and the exact keys
+        targets.extend_from_slice(&[0, 7, 69993, 69994, u64::MAX]);
+        for t in targets {
+            let target = t.to_be_bytes();
+            binary_iter.seek(&target);
+            interpolation_iter.seek(&target);
+            assert_eq!(binary_iter.valid(), interpolation_iter.valid(), "{}", t);
+            if binary_iter.valid() {
+                assert_eq!(binary_iter.key(), interpolation_iter.key(), "{}", t);
+                assert_eq!(binary_iter.value(), interpolation_iter.value(), "{}", t);
+            }
+        }
+        // The shorter keys are ordered bytewise as well
+        interpolation_iter.seek(&[0, 0, 0, 0, 0, 1]);
+        assert_eq!(interpolation_iter.key(), &65541u64.to_be_bytes()[..]);
+        let binary = binary.count.load(AtomicOrdering::Relaxed);
+        let interpolation = interpolation.count.load(AtomicOrdering::Relaxed);
+        assert!(
+            interpolation * 2 < binary,
+            "{} vs {}",
+            interpolation,
+            binary
+        );
+    }
 }
@@ -0,0 +1,315 @@
+use crate::db::format::{
+    extract_user_key, InternalKey, InternalKeyComparator, ParsedInternalKey, ValueType,
+};
+use crate::iterator::{Iterator, KMergeIter, SimpleKMerger};
+use crate::options::{Options, ReadOptions};
+use crate::sstable::sst_file::{ExternalSstFileInfo, SstFileWriter};
+use crate::sstable::table::{new_table_iterator, Table, TableBuilder};
+use crate::storage::{File, Storage};
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// The most sorted runs read at once by a merge pass. More runs are merged in
+// several passes so the blocks being read stay bounded.
+const MAX_MERGE_WIDTH: usize = 64;
+
+// The memory taken by an entry in the buffer besides its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// `BulkLoader` turns the key/value pairs added in any order into sorted sst
+/// files ready for `WickDB::ingest_external_file`, using at most about
+/// `memory_limit` bytes of memory for the entries.
+///
+/// The entries are buffered in memory and, once the buffer is full, sorted and
+/// spilled into a run table in the temporary directory. `finish` merges the
+/// runs, a bounded number at a time, into the output tables. If a key is added
+/// more than once, the last value added wins.
+///
+/// The outputs are built with the block size, compression, checksum, filter
+/// policy and properties collectors in `options` like `SstFileWriter` does, and
+/// each one is cut after it reaches `options.max_file_size`.
+pub struct BulkLoader<S: Storage, C: Comparator> {
+    storage: S,
+    options: Options<C>,
+    // The options to build the runs, which need no filter
+    run_options: Arc<Options<C>>,
+    icmp: InternalKeyComparator<C>,
+    temp_dir: PathBuf,
+    memory_limit: usize,
+    // The entries not spilled yet as (internal key, value). The sequence
+    // numbers in the keys are the orders they're added in.
+    buffer: Vec<(Vec<u8>, Vec<u8>)>,
+    buffered_bytes: usize,
+    next_sequence: u64,
+    next_run_number: u64,
+    runs: Vec<PathBuf>,
+}
+
+impl<S: Storage, C: Comparator + 'static> BulkLoader<S, C> {
+    /// Creates a `BulkLoader` spilling the sorted runs into `temp_dir`, which is
+    /// created if missing. The runs are removed by `finish` or when the loader
+    /// is dropped.
+    pub fn new<P: AsRef<Path>>(
+        storage: S,
+        options: Options<C>,
+        temp_dir: P,
+        memory_limit: usize,
+    ) -> Result<Self> {
+        storage.mkdir_all(temp_dir.as_ref())?;
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let run_options = Arc::new(Options {
+            filter_policy: None,
+            ..options.clone()
+        });
+        Ok(Self {
+            storage,
+            options,
+            run_options,
+            icmp,
+            temp_dir: temp_dir.as_ref().to_path_buf(),
+            memory_limit,
+            buffer: vec![],
+            buffered_bytes: 0,
+            next_sequence: 1,
+            next_run_number: 1,
+            runs: vec![],
+        })
+    }
+
+    /// Adds a key/value pair
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ikey = InternalKey::new(key, self.next_sequence, ValueType::Value);
+        self.next_sequence += 1;
+        self.buffered_bytes += ikey.data().len() + value.len() + ENTRY_OVERHEAD;
+        self.buffer.push((ikey.data().to_vec(), value.to_vec()));
+        if self.buffered_bytes >= self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Adds all the key/value pairs in `entries`
+    pub fn add_all<K, V, I>(&mut self, entries: I) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in entries {
+            self.add(key.as_ref(), value.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of the sorted runs spilled so far
+    #[inline]
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Merges all the entries added into the sst files `000001.sst`,
+    /// `000002.sst`, ... in `output_dir`, whose key ranges are increasing and
+    /// don't overlap each other. Returns an empty `Vec` if nothing is added.
+    pub fn finish<P: AsRef<Path>>(mut self, output_dir: P) -> Result<Vec<ExternalSstFileInfo>> {
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        while self.runs.len() > MAX_MERGE_WIDTH {
+            let inputs = self.runs.drain(..MAX_MERGE_WIDTH).collect::<Vec<_>>();
+            let path = self.new_run_path();
+            let mut builder = TableBuilder::new(
+                self.storage.create(&path)?,
+                self.icmp.clone(),
+                &self.run_options,
+            );
+            self.runs.push(path);
+            self.merge_runs(&inputs, |ikey, value| builder.add(ikey, value))?;
+            builder.finish(true)?;
+            for input in inputs {
+                self.storage.remove(input)?;
+            }
+        }
+
+        self.storage.mkdir_all(output_dir.as_ref())?;
+        let mut outputs = vec![];
+        let mut writer: Option<SstFileWriter<S::F, C>> = None;
+        let runs = std::mem::take(&mut self.runs);
+        let res = self.merge_runs(&runs, |ikey, value| {
+            if writer.is_none() {
+                let path = output_dir
+                    .as_ref()
+                    .join(format!("{:06}.sst", outputs.len() + 1));
+                writer = Some(SstFileWriter::create(
+                    &self.storage,
+                    self.options.clone(),
+                    path,
+                )?);
+            }
+            let w = writer.as_mut().unwrap();
+            // Every user key is passed once, so an output could end at any key
+            w.put(extract_user_key(ikey), value)?;
+            if w.file_size() >= self.options.max_file_size {
+                outputs.push(writer.take().unwrap().finish()?);
+            }
+            Ok(())
+        });
+        // Restores the runs so they're removed by `drop`
+        self.runs = runs;
+        res?;
+        if let Some(w) = writer {
+            outputs.push(w.finish()?);
+        }
+        Ok(outputs)
+    }
+
+    // Sorts the buffered entries and writes them into a new run. Only the last
+    // value added for a user key is kept.
+    fn spill(&mut self) -> Result<()> {
+        let icmp = self.icmp.clone();
+        self.buffer.sort_by(|a, b| icmp.compare(&a.0, &b.0));
+        let path = self.new_run_path();
+        let mut builder =
+            TableBuilder::new(self.storage.create(&path)?, icmp.clone(), &self.run_options);
+        self.runs.push(path);
+        let ucmp = &self.options.comparator;
+        let mut last_user_key: Option<&[u8]> = None;
+        for (ikey, value) in self.buffer.iter() {
+            let user_key = extract_user_key(ikey);
+            if last_user_key.is_some_and(|k| ucmp.compare(k, user_key) == Ordering::Equal) {
+                continue;
+            }
+            last_user_key = Some(user_key);
+            builder.add(ikey, value)?;
+        }
+        builder.finish(true)?;
+        self.buffer.clear();
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    // Merges the runs and calls `f` with the newest entry of every user key in
+    // the increasing order
+    fn merge_runs<F>(&self, runs: &[PathBuf], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<()>,
+    {
+        let read_opt = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let mut children = Vec::with_capacity(runs.len());
+        for run in runs {
+            let file = self.storage.open(run)?;
+            let file_size = file.len()?;
+            let table = Arc::new(Table::open_uncached(
+                file,
+                file_size,
+                self.run_options.clone(),
+                self.icmp.clone(),
+            )?);
+            children.push(new_table_iterator(self.icmp.clone(), table, read_opt));
+        }
+        let ucmp = &self.options.comparator;
+        let mut iter = KMergeIter::new(SimpleKMerger::new(self.icmp.clone(), children));
+        iter.seek_to_first();
+        let mut current_ukey: Option<Vec<u8>> = None;
+        while iter.valid() {
+            let ikey = iter.key();
+            let key = ParsedInternalKey::decode_from(ikey).ok_or_else(|| {
+                Error::Corruption(format!("invalid internal key {:?} in sorted runs", ikey))
+            })?;
+            if current_ukey
+                .as_ref()
+                .is_none_or(|k| ucmp.compare(key.user_key, k) != Ordering::Equal)
+            {
+                // The newest entry of the user key comes first
+                current_ukey = Some(key.user_key.to_vec());
+                f(ikey, iter.value())?;
+            }
+            iter.next();
+        }
+        iter.status()
+    }
+
+    fn new_run_path(&mut self) -> PathBuf {
+        let path = self
+            .temp_dir
+            .join(format!("{:06}.run", self.next_run_number));
+        self.next_run_number += 1;
+        path
+    }
+}
+
+impl<S: Storage, C: Comparator> Drop for BulkLoader<S, C> {
+    fn drop(&mut self) {
+        for run in self.runs.iter() {
+            let _ = self.storage.remove(run);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::sst_file::SstFileReader;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use rand::seq::SliceRandom;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_bulk_loader() {
+        let storage = MemStorage::default();
+        let options = Options::<BytewiseComparator> {
+            max_file_size: 4096,
+            ..Options::default()
+        };
+        let mut keys = (0..3000).map(|i| i % 2000).collect::<Vec<_>>();
+        keys.shuffle(&mut rand::thread_rng());
+        let mut expected = BTreeMap::new();
+        let mut loader = BulkLoader::new(storage.clone(), options.clone(), "tmp", 1024).unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            let key = format!("key{:06}", k).into_bytes();
+            let value = format!("value{}", i).into_bytes();
+            loader.add(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+        // More runs than a merge pass reads
+        assert!(loader.num_runs() > MAX_MERGE_WIDTH);
+
+        let outputs = loader.finish("out").unwrap();
+        assert!(outputs.len() > 1);
+        let mut entries = vec![];
+        for (i, output) in outputs.iter().enumerate() {
+            assert_eq!(
+                output.path,
+                Path::new("out").join(format!("{:06}.sst", i + 1))
+            );
+            if i > 0 {
+                assert!(outputs[i - 1].largest_key < output.smallest_key);
+            }
+            let reader = SstFileReader::open(&storage, options.clone(), &output.path).unwrap();
+            let mut iter = reader.iter(ReadOptions::default());
+            iter.seek_to_first();
+            while iter.valid() {
+                assert_eq!(iter.sequence(), 0);
+                entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.next();
+            }
+        }
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+        // The runs are removed
+        assert!(storage.list("tmp").unwrap().is_empty());
+
+        let loader = BulkLoader::new(storage.clone(), options.clone(), "tmp", 1024).unwrap();
+        assert!(loader.finish("empty").unwrap().is_empty());
+        let mut loader = BulkLoader::new(storage.clone(), options, "tmp", 64).unwrap();
+        loader.add(b"k", b"v").unwrap();
+        assert_eq!(loader.num_runs(), 1);
+        drop(loader);
+        assert!(storage.list("tmp").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,748 @@
+use crate::db::format::{
+    extract_user_key, InternalKeyComparator, ParsedInternalKey, ValueType, INTERNAL_KEY_TAIL,
+};
+use crate::db::range_del::RangeTombstone;
+use crate::db::read_stats::ReadCounters;
+use crate::iterator::Iterator;
+use crate::options::{Options, ReadOptions};
+use crate::sstable::factory::{
+    BlockBasedTableFactory, TableEntryHandler, TableFactory, TableReader, TableReaderIterator,
+    TableWriter,
+};
+use crate::storage::File;
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
+use crate::util::comparator::Comparator;
+use crate::util::hash::hash;
+use crate::{Error, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Ordering as CmpOrdering;
+use std::mem;
+use std::sync::Arc;
+
+// The footer is `num_entries` (8 bytes), `num_buckets` (8 bytes), `key_len` (4 bytes),
+// `value_len` (4 bytes), `num_hash_func` (4 bytes), `cuckoo_block_size` (4 bytes),
+// `key_suffix_len` (4 bytes) and the magic number (8 bytes).
+const FOOTER_SIZE: usize = 44;
+const CUCKOO_TABLE_MAGIC: u64 = 0x6375_636b_6f6f_7462;
+// The seed of the `i`th hash function is `HASH_SEED + i`
+const HASH_SEED: u32 = 0x5bd1_e995;
+// The tag of a bucket holding an entry
+const BUCKET_USED: u8 = 1;
+// The times the buckets are grown by 10% when the entries can't be placed
+const MAX_REBUILDS: usize = 8;
+
+/// Options of `CuckooTableBuilder`
+#[derive(Clone, Copy, Debug)]
+pub struct CuckooTableOptions {
+    /// The expected ratio of the entries to the buckets, which trades the space
+    /// for the chance of placing an entry in its first block of buckets.
+    pub hash_table_ratio: f64,
+    /// The number of hash functions, each of which picks a block of buckets an
+    /// entry can be placed in
+    pub num_hash_func: usize,
+    /// The number of the adjacent buckets read together by a probe. An entry
+    /// placed in any bucket of the block picked by a hash function is found by a
+    /// single read.
+    pub cuckoo_block_size: usize,
+    /// The most entries moved to place a new one before the buckets are grown
+    pub max_search_depth: usize,
+}
+
+impl Default for CuckooTableOptions {
+    fn default() -> Self {
+        Self {
+            hash_table_ratio: 0.9,
+            num_hash_func: 2,
+            cuckoo_block_size: 5,
+            max_search_depth: 100,
+        }
+    }
+}
+
+/// `CuckooTableBuilder` writes a cuckoo hash table, a static table format for the
+/// point-lookup-only datasets whose keys all have the same length and whose
+/// values all have the same length.
+///
+/// An entry is placed in a bucket of one of the blocks picked by the hash
+/// functions of its key, so a lookup reads at most `num_hash_func` blocks and
+/// usually only the first one. The table doesn't support range scans,
+/// deletions or snapshots. Use `CuckooTableFactory` to store the tables of a db
+/// in this format.
+pub struct CuckooTableBuilder<F: File> {
+    file: F,
+    options: CuckooTableOptions,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    // The length of the key suffix not hashed, e.g. the tail of an internal key
+    key_suffix_len: usize,
+    closed: bool,
+}
+
+impl<F: File> CuckooTableBuilder<F> {
+    pub fn new(file: F, options: CuckooTableOptions) -> Self {
+        Self::with_key_suffix(file, options, 0)
+    }
+
+    /// Creates a builder whose keys are hashed and looked up without their last
+    /// `key_suffix_len` bytes
+    pub(crate) fn with_key_suffix(
+        file: F,
+        options: CuckooTableOptions,
+        key_suffix_len: usize,
+    ) -> Self {
+        Self {
+            file,
+            options,
+            entries: vec![],
+            key_suffix_len,
+            closed: false,
+        }
+    }
+
+    /// Adds an entry. The entries are buffered in memory until `finish`.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::InvalidArgument` if the key or the value has a different
+    /// length from the first entry.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        assert!(
+            !self.closed,
+            "[cuckoo table builder] add to a closed builder"
+        );
+        if key.len() < self.key_suffix_len {
+            return Err(Error::InvalidArgument(format!(
+                "cuckoo table expects keys of at least {} bytes but got {}",
+                self.key_suffix_len,
+                key.len()
+            )));
+        }
+        if let Some((k, v)) = self.entries.first() {
+            if k.len() != key.len() || v.len() != value.len() {
+                return Err(Error::InvalidArgument(format!(
+                    "cuckoo table expects {} bytes keys and {} bytes values but got {} and {}",
+                    k.len(),
+                    v.len(),
+                    key.len(),
+                    value.len()
+                )));
+            }
+        }
+        self.entries.push((key.to_vec(), value.to_vec()));
+        Ok(())
+    }
+
+    #[inline]
+    pub fn num_entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the expected size of the table if it's finished now
+    pub fn estimated_file_size(&self) -> u64 {
+        let bucket_size = self
+            .entries
+            .first()
+            .map_or(0, |(k, v)| 1 + k.len() + v.len());
+        let buckets = self.entries.len() as f64 / self.options.hash_table_ratio;
+        (buckets * bucket_size as f64) as u64 + FOOTER_SIZE as u64
+    }
+
+    /// Abandons the table and drops the buffered entries
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.entries = vec![];
+    }
+
+    /// Places all the entries into the buckets and writes the table. Returns the
+    /// size of the table file.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::InvalidArgument` if a key is added more than once or the
+    /// options are invalid, and `Error::Corruption` if the entries can't be
+    /// placed even after growing the buckets.
+    pub fn finish(&mut self, sync: bool) -> Result<u64> {
+        assert!(
+            !self.closed,
+            "[cuckoo table builder] finish a closed builder"
+        );
+        self.closed = true;
+        let opts = self.options;
+        if opts.num_hash_func == 0
+            || opts.cuckoo_block_size == 0
+            || opts.hash_table_ratio <= 0.0
+            || opts.hash_table_ratio > 1.0
+        {
+            return Err(Error::InvalidArgument(format!(
+                "invalid cuckoo table options {:?}",
+                opts
+            )));
+        }
+        let entries = mem::take(&mut self.entries);
+        let suffix = self.key_suffix_len;
+        let mut keys: Vec<&[u8]> = entries
+            .iter()
+            .map(|(k, _)| &k[..k.len() - suffix])
+            .collect();
+        keys.sort_unstable();
+        if let Some(w) = keys.windows(2).find(|w| w[0] == w[1]) {
+            return Err(Error::InvalidArgument(format!(
+                "key {:?} is added to the cuckoo table more than once",
+                w[0]
+            )));
+        }
+        let (key_len, value_len) = entries.first().map_or((0, 0), |(k, v)| (k.len(), v.len()));
+        let mut num_buckets = if entries.is_empty() {
+            0
+        } else {
+            (entries.len() as f64 / opts.hash_table_ratio).ceil() as u64
+        };
+        let mut placed = None;
+        for _ in 0..=MAX_REBUILDS {
+            placed = place_entries(&entries, suffix, num_buckets, &opts);
+            if placed.is_some() {
+                break;
+            }
+            num_buckets += num_buckets / 10 + 1;
+        }
+        let buckets = placed.ok_or_else(|| {
+            Error::Corruption(format!(
+                "failed to place {} entries into the cuckoo table",
+                entries.len()
+            ))
+        })?;
+
+        let bucket_size = 1 + key_len + value_len;
+        let mut buf = Vec::with_capacity(bucket_size);
+        let mut offset = 0;
+        for bucket in buckets {
+            buf.clear();
+            match bucket {
+                Some(i) => {
+                    let (key, value) = &entries[i];
+                    buf.push(BUCKET_USED);
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(value);
+                }
+                None => buf.resize(bucket_size, 0),
+            }
+            self.file.write(&buf)?;
+            offset += bucket_size as u64;
+        }
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        put_fixed_64(&mut footer, entries.len() as u64);
+        put_fixed_64(&mut footer, num_buckets);
+        put_fixed_32(&mut footer, key_len as u32);
+        put_fixed_32(&mut footer, value_len as u32);
+        put_fixed_32(&mut footer, opts.num_hash_func as u32);
+        put_fixed_32(&mut footer, opts.cuckoo_block_size as u32);
+        put_fixed_32(&mut footer, suffix as u32);
+        put_fixed_64(&mut footer, CUCKOO_TABLE_MAGIC);
+        self.file.write(&footer)?;
+        offset += footer.len() as u64;
+        if sync {
+            self.file.sync()?;
+            self.file.close()?;
+        }
+        Ok(offset)
+    }
+}
+
+// Returns the first bucket of the block picked by the `i`th hash function
+#[inline]
+fn block_start(key: &[u8], i: usize, num_buckets: u64) -> u64 {
+    u64::from(hash(key, HASH_SEED.wrapping_add(i as u32))) % num_buckets
+}
+
+// Places the entries into `num_buckets + cuckoo_block_size - 1` buckets, where the
+// last block never wraps around. Returns the index of the entry held by each bucket,
+// or `None` if an entry can't be placed within `max_search_depth` moves.
+fn place_entries(
+    entries: &[(Vec<u8>, Vec<u8>)],
+    key_suffix_len: usize,
+    num_buckets: u64,
+    opts: &CuckooTableOptions,
+) -> Option<Vec<Option<usize>>> {
+    if entries.is_empty() {
+        return Some(vec![]);
+    }
+    let block_size = opts.cuckoo_block_size;
+    let mut buckets = vec![None; num_buckets as usize + block_size - 1];
+    // The seed is fixed so that the same entries always make the same table
+    let mut rng = StdRng::seed_from_u64(u64::from(HASH_SEED));
+    let candidates = |key: &[u8]| -> Vec<usize> {
+        (0..opts.num_hash_func)
+            .flat_map(|i| {
+                let start = block_start(key, i, num_buckets) as usize;
+                start..start + block_size
+            })
+            .collect()
+    };
+    for i in 0..entries.len() {
+        let mut homeless = i;
+        let mut depth = 0;
+        loop {
+            let key = &entries[homeless].0;
+            let candidates = candidates(&key[..key.len() - key_suffix_len]);
+            if let Some(b) = candidates.iter().find(|b| buckets[**b].is_none()) {
+                buckets[*b] = Some(homeless);
+                break;
+            }
+            if depth == opts.max_search_depth {
+                return None;
+            }
+            depth += 1;
+            // Kick out the entry in a random candidate bucket
+            let b = candidates[rng.gen_range(0, candidates.len())];
+            homeless = buckets[b].replace(homeless).unwrap();
+        }
+    }
+    Some(buckets)
+}
+
+/// A cuckoo hash table written by `CuckooTableBuilder`
+pub struct CuckooTable<F: File> {
+    file: F,
+    num_entries: u64,
+    num_buckets: u64,
+    key_len: usize,
+    value_len: usize,
+    num_hash_func: usize,
+    cuckoo_block_size: usize,
+    key_suffix_len: usize,
+}
+
+// Returns true if the file of `file_size` bytes ends with the magic number of
+// the cuckoo tables
+fn is_cuckoo_table<F: File + ?Sized>(file: &F, file_size: u64) -> Result<bool> {
+    if file_size < FOOTER_SIZE as u64 {
+        return Ok(false);
+    }
+    let mut magic = [0; 8];
+    file.read_exact_at(&mut magic, file_size - 8)?;
+    Ok(decode_fixed_64(&magic) == CUCKOO_TABLE_MAGIC)
+}
+
+impl<F: File> CuckooTable<F> {
+    /// Opens the table of `file_size` bytes by reading its footer
+    pub fn open(file: F, file_size: u64) -> Result<Self> {
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(Error::Corruption(
+                "file is too short to be a cuckoo table".to_owned(),
+            ));
+        }
+        let mut footer = vec![0; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, file_size - FOOTER_SIZE as u64)?;
+        if decode_fixed_64(&footer[36..]) != CUCKOO_TABLE_MAGIC {
+            return Err(Error::Corruption(
+                "not a cuckoo table (bad magic number)".to_owned(),
+            ));
+        }
+        let table = Self {
+            file,
+            num_entries: decode_fixed_64(&footer),
+            num_buckets: decode_fixed_64(&footer[8..]),
+            key_len: decode_fixed_32(&footer[16..]) as usize,
+            value_len: decode_fixed_32(&footer[20..]) as usize,
+            num_hash_func: decode_fixed_32(&footer[24..]) as usize,
+            cuckoo_block_size: decode_fixed_32(&footer[28..]) as usize,
+            key_suffix_len: decode_fixed_32(&footer[32..]) as usize,
+        };
+        let buckets = if table.num_buckets == 0 {
+            0
+        } else {
+            table.num_buckets + table.cuckoo_block_size as u64 - 1
+        };
+        if table.key_suffix_len > table.key_len
+            || buckets * table.bucket_size() as u64 + FOOTER_SIZE as u64 != file_size
+        {
+            return Err(Error::Corruption(format!(
+                "cuckoo table of {} buckets doesn't match the file size {}",
+                table.num_buckets, file_size
+            )));
+        }
+        Ok(table)
+    }
+
+    #[inline]
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    #[inline]
+    fn bucket_size(&self) -> usize {
+        1 + self.key_len + self.value_len
+    }
+
+    /// Returns the value of `key`. The block picked by each hash function is read
+    /// by a single `read_at` until the key is found.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.find(key)?.map(|(_, value)| value))
+    }
+
+    /// Returns the entry whose key without the suffix is `hash_key`
+    pub(crate) fn find(&self, hash_key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let hash_key_len = self.key_len - self.key_suffix_len;
+        if self.num_entries == 0 || hash_key.len() != hash_key_len {
+            return Ok(None);
+        }
+        let bucket_size = self.bucket_size();
+        let mut block = vec![0; bucket_size * self.cuckoo_block_size];
+        for i in 0..self.num_hash_func {
+            let start = block_start(hash_key, i, self.num_buckets);
+            self.file
+                .read_exact_at(&mut block, start * bucket_size as u64)?;
+            for bucket in block.chunks_exact(bucket_size) {
+                if bucket[0] == BUCKET_USED && &bucket[1..=hash_key_len] == hash_key {
+                    let (key, value) = bucket[1..].split_at(self.key_len);
+                    return Ok(Some((key.to_vec(), value.to_vec())));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns all the entries in the order of the buckets by reading the whole
+    /// table
+    pub(crate) fn entries(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if self.num_entries == 0 {
+            return Ok(vec![]);
+        }
+        let bucket_size = self.bucket_size();
+        let buckets = self.num_buckets as usize + self.cuckoo_block_size - 1;
+        let mut data = vec![0; buckets * bucket_size];
+        self.file.read_exact_at(&mut data, 0)?;
+        Ok(data
+            .chunks_exact(bucket_size)
+            .filter(|bucket| bucket[0] == BUCKET_USED)
+            .map(|bucket| {
+                let (key, value) = bucket[1..].split_at(self.key_len);
+                (key.to_vec(), value.to_vec())
+            })
+            .collect())
+    }
+}
+
+/// `CuckooTableFactory` stores the tables of a db in the cuckoo table format, so a
+/// point lookup in a table costs a single read in most cases. See
+/// `CuckooTableBuilder`.
+///
+/// The format only fits the datasets whose user keys all have the same length
+/// and whose values all have the same length, with no deletion, merge or range
+/// deletion, and with at most one version of a user key in a table (e.g. no
+/// overwrite while a snapshot is held). Flushes and compactions writing other
+/// entries fail with `Error::NotSupported`. An iterator over a table reads the
+/// whole table into memory.
+///
+/// The block-based tables, e.g. the ones written before switching to this
+/// factory or ingested by `ingest_external_file`, are still opened by
+/// `BlockBasedTableFactory`.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct CuckooTableFactory {
+    pub options: CuckooTableOptions,
+}
+
+impl CuckooTableFactory {
+    pub fn new(options: CuckooTableOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl<C: Comparator + 'static> TableFactory<C> for CuckooTableFactory {
+    fn name(&self) -> &str {
+        "CuckooTableFactory"
+    }
+
+    fn new_builder(
+        &self,
+        file: Box<dyn File>,
+        _icmp: InternalKeyComparator<C>,
+        _options: &Arc<Options<C>>,
+    ) -> Box<dyn TableWriter> {
+        Box::new(CuckooTableWriter {
+            builder: CuckooTableBuilder::with_key_suffix(file, self.options, INTERNAL_KEY_TAIL),
+            last_key: vec![],
+            num_range_tombstones: 0,
+            file_size: None,
+        })
+    }
+
+    fn open(
+        &self,
+        file: Box<dyn File>,
+        file_number: u64,
+        file_size: u64,
+        icmp: InternalKeyComparator<C>,
+        options: &Arc<Options<C>>,
+    ) -> Result<Arc<dyn TableReader>> {
+        if !is_cuckoo_table(file.as_ref(), file_size)? {
+            return BlockBasedTableFactory.open(file, file_number, file_size, icmp, options);
+        }
+        Ok(Arc::new(CuckooTableReader {
+            table: CuckooTable::open(file, file_size)?,
+            icmp,
+        }))
+    }
+}
+
+// Writes the internal keys into a cuckoo table, which is looked up by the user
+// keys
+struct CuckooTableWriter<F: File> {
+    builder: CuckooTableBuilder<F>,
+    last_key: Vec<u8>,
+    num_range_tombstones: usize,
+    // The size of the finished table
+    file_size: Option<u64>,
+}
+
+impl<F: File> TableWriter for CuckooTableWriter<F> {
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let parsed = ParsedInternalKey::decode_from(key)
+            .ok_or_else(|| Error::Corruption("bad internal key".to_owned()))?;
+        if parsed.value_type != ValueType::Value {
+            return Err(Error::NotSupported(format!(
+                "cuckoo table doesn't support the entries of {:?}",
+                parsed.value_type
+            )));
+        }
+        if !self.last_key.is_empty() && extract_user_key(&self.last_key) == parsed.user_key {
+            return Err(Error::NotSupported(format!(
+                "cuckoo table doesn't support multiple versions of user key {:?}",
+                parsed.user_key
+            )));
+        }
+        self.builder.add(key, value)?;
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        Ok(())
+    }
+
+    fn add_range_tombstone(&mut self, _tombstone: RangeTombstone) {
+        self.num_range_tombstones += 1;
+    }
+
+    fn num_range_tombstones(&self) -> usize {
+        self.num_range_tombstones
+    }
+
+    fn finish(&mut self, sync: bool) -> Result<()> {
+        if self.num_range_tombstones > 0 {
+            self.builder.close();
+            return Err(Error::NotSupported(
+                "cuckoo table doesn't support range tombstones".to_owned(),
+            ));
+        }
+        self.file_size = Some(self.builder.finish(sync)?);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.builder.close()
+    }
+
+    fn num_entries(&self) -> usize {
+        self.builder.num_entries()
+    }
+
+    fn file_size(&self) -> u64 {
+        self.file_size
+            .unwrap_or_else(|| self.builder.estimated_file_size())
+    }
+}
+
+struct CuckooTableReader<C: Comparator> {
+    table: CuckooTable<Box<dyn File>>,
+    icmp: InternalKeyComparator<C>,
+}
+
+impl<C: Comparator + 'static> TableReader for CuckooTableReader<C> {
+    fn internal_get(
+        &self,
+        _options: ReadOptions,
+        key: &[u8],
+        _stats: Option<&ReadCounters>,
+    ) -> Result<Option<TableReaderIterator>> {
+        match self.table.find(extract_user_key(key))? {
+            // The entry is skipped if it's newer than the lookup key, since there
+            // is no older version of the user key in the table
+            Some(entry) if self.icmp.compare(&entry.0, key) != CmpOrdering::Less => {
+                let mut iter = CuckooTableIterator::new(self.icmp.clone(), vec![entry], None);
+                iter.seek_to_first();
+                Ok(Some(Box::new(iter)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn new_iter(self: Arc<Self>, _options: ReadOptions) -> TableReaderIterator {
+        let (entries, err) = match self.table.entries() {
+            Ok(mut entries) => {
+                entries.sort_unstable_by(|a, b| self.icmp.compare(&a.0, &b.0));
+                (entries, None)
+            }
+            Err(e) => (vec![], Some(e)),
+        };
+        Box::new(CuckooTableIterator::new(self.icmp.clone(), entries, err))
+    }
+
+    fn scan(&self, f: &mut TableEntryHandler<'_>) -> Result<usize> {
+        let mut entries = self.table.entries()?;
+        entries.sort_unstable_by(|a, b| self.icmp.compare(&a.0, &b.0));
+        for (key, value) in entries {
+            f(&key, &value)?;
+        }
+        Ok(0)
+    }
+}
+
+// Iterates the entries of a cuckoo table sorted by the internal keys
+struct CuckooTableIterator<C: Comparator> {
+    icmp: InternalKeyComparator<C>,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    // `entries.len()` if the iterator is invalid
+    pos: usize,
+    err: Option<Error>,
+}
+
+impl<C: Comparator> CuckooTableIterator<C> {
+    fn new(
+        icmp: InternalKeyComparator<C>,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        err: Option<Error>,
+    ) -> Self {
+        Self {
+            icmp,
+            pos: entries.len(),
+            entries,
+            err,
+        }
+    }
+}
+
+impl<C: Comparator> Iterator for CuckooTableIterator<C> {
+    fn valid(&self) -> bool {
+        self.pos < self.entries.len()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.pos = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.pos = self.entries.len().saturating_sub(1);
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.pos = self
+            .entries
+            .partition_point(|(k, _)| self.icmp.compare(k, target) == CmpOrdering::Less);
+    }
+
+    fn next(&mut self) {
+        self.pos += 1;
+    }
+
+    fn prev(&mut self) {
+        self.pos = if self.pos == 0 {
+            self.entries.len()
+        } else {
+            self.pos - 1
+        };
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entries[self.pos].0
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.entries[self.pos].1
+    }
+
+    fn status(&mut self) -> Result<()> {
+        match self.err.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+    use crate::storage::Storage;
+
+    fn build(storage: &MemStorage, n: usize, options: CuckooTableOptions) -> u64 {
+        let mut builder = CuckooTableBuilder::new(storage.create("cuckoo").unwrap(), options);
+        for i in 0..n {
+            builder
+                .add(
+                    format!("key{:06}", i).as_bytes(),
+                    format!("v{:04}", i % 10000).as_bytes(),
+                )
+                .unwrap();
+        }
+        assert_eq!(builder.num_entries(), n);
+        builder.finish(true).unwrap()
+    }
+
+    #[test]
+    fn test_cuckoo_table() {
+        for (n, options) in [
+            (0, CuckooTableOptions::default()),
+            (1, CuckooTableOptions::default()),
+            (10000, CuckooTableOptions::default()),
+            (
+                1000,
+                CuckooTableOptions {
+                    hash_table_ratio: 1.0,
+                    num_hash_func: 3,
+                    cuckoo_block_size: 1,
+                    ..CuckooTableOptions::default()
+                },
+            ),
+        ] {
+            let storage = MemStorage::default();
+            let size = build(&storage, n, options);
+            let table = CuckooTable::open(storage.open("cuckoo").unwrap(), size).unwrap();
+            assert_eq!(table.num_entries(), n as u64);
+            for i in 0..n {
+                assert_eq!(
+                    table.get(format!("key{:06}", i).as_bytes()).unwrap(),
+                    Some(format!("v{:04}", i % 10000).into_bytes()),
+                    "n: {}, key: {}",
+                    n,
+                    i
+                );
+            }
+            assert_eq!(table.get(format!("key{:06}", n).as_bytes()).unwrap(), None);
+            assert_eq!(table.get(b"key").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_cuckoo_table_errors() {
+        let storage = MemStorage::default();
+        let mut builder = CuckooTableBuilder::new(
+            storage.create("cuckoo").unwrap(),
+            CuckooTableOptions::default(),
+        );
+        builder.add(b"k1", b"v1").unwrap();
+        assert!(matches!(
+            builder.add(b"k22", b"v2"),
+            Err(Error::InvalidArgument(_))
+        ));
+        builder.add(b"k1", b"v2").unwrap();
+        assert!(matches!(
+            builder.finish(true),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let size = build(&storage, 10, CuckooTableOptions::default());
+        let file = storage.open("cuckoo").unwrap();
+        assert!(matches!(
+            CuckooTable::open(file, size - 1),
+            Err(Error::Corruption(_))
+        ));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::db::format::InternalKeyComparator;
+use crate::options::Options;
+use crate::sstable::table::Table;
+use crate::storage::{File, Storage};
+use crate::util::comparator::BytewiseComparator;
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// How `analyze_dedup` cuts the files into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of the given size. The last chunk of a table could be shorter.
+    FixedSize(usize),
+    /// Chunks cut where a rolling hash of the content matches, so the same
+    /// content is cut into the same chunks even if it's shifted in the tables.
+    /// The chunks are about the given size on average.
+    ContentDefined(usize),
+}
+
+/// The duplicate content found by `analyze_dedup`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    pub num_files: usize,
+    pub num_chunks: u64,
+    /// The size of the decoded data blocks
+    pub total_bytes: u64,
+    /// The chunks whose content is seen for the first time
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+    /// The chunks whose content is already seen in the same or a previous table
+    pub duplicate_chunks: u64,
+    pub duplicate_bytes: u64,
+    /// The part of `duplicate_bytes` first seen in a different table
+    pub cross_file_duplicate_bytes: u64,
+}
+
+impl DedupStats {
+    /// Returns the fraction of the total bytes a dedup could save
+    pub fn saving_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            self.duplicate_bytes as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// Reports how much content is duplicated across the given sst files, which
+/// quantifies the space a dedup or a shared compression dictionary could save
+/// on a dataset. The data blocks of every table are decoded, i.e. decompressed
+/// and stripped of the trailers, so the same entries are found regardless of the
+/// compression and the checksums. The decoded blocks of a table are concatenated
+/// and cut into chunks by `chunking`, and the chunks are compared by their
+/// hashes, so the tables are never held in memory at the same time.
+///
+/// # Error
+///
+/// Returns `Error::InvalidArgument` if the chunk size is 0, or the error of
+/// reading a table, e.g. `Error::Corruption` if a block fails the checksum.
+pub fn analyze_dedup<S: Storage, P: AsRef<Path>>(
+    storage: &S,
+    files: &[P],
+    chunking: Chunking,
+) -> Result<DedupStats> {
+    let chunker = match chunking {
+        Chunking::FixedSize(0) | Chunking::ContentDefined(0) => {
+            return Err(Error::InvalidArgument(
+                "the dedup chunk size must be positive".to_owned(),
+            ))
+        }
+        Chunking::FixedSize(size) => Chunker::Fixed(size),
+        Chunking::ContentDefined(avg) => Chunker::ContentDefined(Box::new(GearChunker::new(avg))),
+    };
+    let mut stats = DedupStats {
+        num_files: files.len(),
+        ..DedupStats::default()
+    };
+    // (the hash, the length) of a chunk -> the file it's first seen in
+    let mut seen: HashMap<(u64, usize), usize> = HashMap::new();
+    let options = Arc::new(Options::<BytewiseComparator>::default());
+    let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+    let mut data = vec![];
+    for (i, file) in files.iter().enumerate() {
+        let file = storage.open(file.as_ref())?;
+        let file_len = file.len()?;
+        let table = Table::open_uncached(file, file_len, options.clone(), icmp.clone())?;
+        data.clear();
+        table.for_each_data_block(icmp.clone(), |block| {
+            data.extend_from_slice(block);
+            Ok(())
+        })?;
+        stats.total_bytes += data.len() as u64;
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let len = chunker.next_chunk_len(rest);
+            let chunk = &rest[..len];
+            rest = &rest[len..];
+            stats.num_chunks += 1;
+            let key = (xxh3_64(chunk), len);
+            match seen.get(&key) {
+                Some(first) => {
+                    stats.duplicate_chunks += 1;
+                    stats.duplicate_bytes += len as u64;
+                    if *first != i {
+                        stats.cross_file_duplicate_bytes += len as u64;
+                    }
+                }
+                None => {
+                    seen.insert(key, i);
+                    stats.unique_chunks += 1;
+                    stats.unique_bytes += len as u64;
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+enum Chunker {
+    Fixed(usize),
+    ContentDefined(Box<GearChunker>),
+}
+
+impl Chunker {
+    // Returns the length of the chunk at the start of `data`
+    fn next_chunk_len(&self, data: &[u8]) -> usize {
+        match self {
+            Chunker::Fixed(size) => data.len().min(*size),
+            Chunker::ContentDefined(gear) => gear.next_chunk_len(data),
+        }
+    }
+}
+
+// A content-defined chunker driven by the gear rolling hash. A chunk ends
+// where the hash has all the bits of `mask` cleared, and its length is
+// bounded by `[min_size, max_size]`.
+struct GearChunker {
+    gear: [u64; 256],
+    mask: u64,
+    min_size: usize,
+    max_size: usize,
+}
+
+impl GearChunker {
+    fn new(avg_size: usize) -> Self {
+        // Fill the table by splitmix64 so the chunks are stable across runs
+        let mut gear = [0u64; 256];
+        let mut state = 0u64;
+        for g in gear.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *g = z ^ (z >> 31);
+        }
+        Self {
+            gear,
+            mask: avg_size.next_power_of_two() as u64 - 1,
+            min_size: (avg_size / 4).max(1),
+            max_size: avg_size.saturating_mul(4),
+        }
+    }
+
+    fn next_chunk_len(&self, data: &[u8]) -> usize {
+        let end = data.len().min(self.max_size);
+        let mut hash = 0u64;
+        for (i, b) in data[..end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(self.gear[*b as usize]);
+            if i + 1 >= self.min_size && hash & self.mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::ValueType;
+    use crate::options::{CompressionType, IndexType};
+    use crate::sstable::test_util;
+    use crate::storage::mem::MemStorage;
+    use rand::Rng;
+
+    fn build_table(
+        storage: &MemStorage,
+        name: &str,
+        entries: &[(String, Vec<u8>)],
+        compression: CompressionType,
+        index_type: IndexType,
+    ) -> u64 {
+        let options = Options::<BytewiseComparator> {
+            block_size: 4096,
+            compression,
+            index_type,
+            ..Options::default()
+        };
+        let entries = entries
+            .iter()
+            .map(|(key, value)| (key, 1, ValueType::Value, value));
+        test_util::build_table(storage, name, options, entries, vec![])
+    }
+
+    fn random_entries(keys: std::ops::Range<usize>) -> Vec<(String, Vec<u8>)> {
+        let mut rnd = rand::thread_rng();
+        keys.map(|i| {
+            let value: Vec<u8> = (0..100).map(|_| rnd.gen_range(b'a', b'e')).collect();
+            (format!("k{:05}", i), value)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_fixed_size_dedup() {
+        let storage = MemStorage::default();
+        let entries = random_entries(0..500);
+        // The same entries are compressed differently but decoded into the same
+        // blocks
+        let a = build_table(
+            &storage,
+            "a",
+            &entries,
+            CompressionType::NoCompression,
+            IndexType::BinarySearch,
+        );
+        let b = build_table(
+            &storage,
+            "b",
+            &entries,
+            CompressionType::SnappyCompression,
+            IndexType::TwoLevelIndexSearch,
+        );
+        assert!(b < a);
+        let stats = analyze_dedup(&storage, &["a", "b"], Chunking::FixedSize(256)).unwrap();
+        assert_eq!(stats.num_files, 2);
+        assert!(stats.total_bytes > a);
+        assert_eq!(stats.cross_file_duplicate_bytes * 2, stats.total_bytes);
+        assert_eq!(
+            stats.unique_chunks + stats.duplicate_chunks,
+            stats.num_chunks
+        );
+        assert!(stats.saving_ratio() >= 0.5);
+    }
+
+    #[test]
+    fn test_content_defined_dedup() {
+        let storage = MemStorage::default();
+        let entries = random_entries(100..600);
+        // The entries are shifted in the blocks of the second table so the
+        // fixed-size chunks can't find them
+        let mut shifted = random_entries(0..1);
+        shifted.extend_from_slice(&entries);
+        build_table(
+            &storage,
+            "a",
+            &entries,
+            CompressionType::NoCompression,
+            IndexType::BinarySearch,
+        );
+        build_table(
+            &storage,
+            "b",
+            &shifted,
+            CompressionType::NoCompression,
+            IndexType::BinarySearch,
+        );
+        let fixed = analyze_dedup(&storage, &["a", "b"], Chunking::FixedSize(1024)).unwrap();
+        assert!(
+            fixed.duplicate_bytes < fixed.total_bytes / 20,
+            "{:?}",
+            fixed
+        );
+        let cdc = analyze_dedup(&storage, &["a", "b"], Chunking::ContentDefined(256)).unwrap();
+        // More than half of the second table is found in the first one
+        assert!(
+            cdc.cross_file_duplicate_bytes > cdc.total_bytes / 4,
+            "{:?}",
+            cdc
+        );
+        assert_eq!(cdc.total_bytes, fixed.total_bytes);
+        assert_eq!(cdc.unique_bytes + cdc.duplicate_bytes, cdc.total_bytes);
+    }
+
+    #[test]
+    fn test_invalid_chunk_size() {
+        let storage = MemStorage::default();
+        for chunking in [Chunking::FixedSize(0), Chunking::ContentDefined(0)] {
+            assert!(matches!(
+                analyze_dedup::<_, &str>(&storage, &[], chunking),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use crate::db::format::{parse_internal_key, ReadableKey};
+use crate::db::range_del::decode_range_tombstones;
+use crate::iterator::Iterator;
+use crate::options::{ChecksumType, CompressionType};
+use crate::sstable::block::Block;
+use crate::sstable::properties::{TableProperties, PROPERTIES_BLOCK_KEY};
+use crate::sstable::table::{block_checksum, decode_block, INDEX_TYPE_KEY, RANGE_DEL_BLOCK_KEY};
+use crate::sstable::{BlockHandle, Footer, BLOCK_TRAILER_SIZE, FOOTER_ENCODED_LENGTH_V1};
+use crate::storage::File;
+use crate::util::coding::decode_fixed_32;
+use crate::util::comparator::BytewiseComparator;
+use crate::{Error, Result};
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// What `dump_table` prints besides the footer, the meta blocks, the index and
+/// the stats of the data blocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DumpOptions {
+    /// Prints all the entries of the data blocks
+    pub print_entries: bool,
+    /// Prints the raw internal keys and the values of the entries in hex
+    /// besides the decoded ones
+    pub hex: bool,
+}
+
+/// The summary of a table dumped by `dump_table`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DumpStats {
+    pub num_data_blocks: usize,
+    pub num_entries: u64,
+    /// The data blocks (and the index partitions) failing the checksums or
+    /// the decoding, whose entries are not counted
+    pub corrupted_blocks: usize,
+}
+
+/// Prints the structure of the sstable in `file` to `out` for debugging: the
+/// footer, the meta index block, the properties, the range tombstones, the
+/// index, then the offset, size, compression, checksum and number of entries of
+/// every data block, and all the entries if `options.print_entries` is set.
+///
+/// The blocks are read directly instead of by a `Table`, so a table with a
+/// corrupted meta block or data block is still dumped as far as possible. The
+/// keys are printed as the internal keys with the stored sequence numbers, i.e.
+/// the global sequence number of an ingested table is not applied.
+///
+/// # Error
+///
+/// Returns an error if the footer or the index block can't be read. The
+/// corrupted data blocks are reported in the output and counted by
+/// `DumpStats::corrupted_blocks` instead.
+pub fn dump_table<F: File, W: Write>(
+    file: &F,
+    file_len: u64,
+    options: DumpOptions,
+    out: &mut W,
+) -> Result<DumpStats> {
+    let mut d = Dumper {
+        file,
+        checksum: ChecksumType::Crc32,
+        out,
+        stats: DumpStats::default(),
+    };
+    let footer_len = file_len.min(FOOTER_ENCODED_LENGTH_V1 as u64);
+    let mut tail = vec![0; footer_len as usize];
+    file.read_exact_at(&mut tail, file_len - footer_len)?;
+    let (footer, _) = Footer::decode_from(&tail)?;
+    d.checksum = footer.checksum;
+    d.line(format!("File size: {}", file_len))?;
+    d.line("Footer:")?;
+    d.line(format!("  format version: {}", footer.format_version()))?;
+    d.line(format!("  checksum: {:?}", footer.checksum))?;
+    d.line(format!(
+        "  meta index block: {}",
+        fmt_handle(&footer.meta_index_handle)
+    ))?;
+    d.line(format!(
+        "  index block: {}",
+        fmt_handle(&footer.index_handle)
+    ))?;
+
+    let mut partitioned_index = false;
+    if footer.meta_index_handle.size > 0 {
+        match d.read(&footer.meta_index_handle) {
+            Ok((meta_block, _)) => partitioned_index = d.dump_meta_blocks(&meta_block)?,
+            Err(e) => d.line(format!("Meta index block: {}", e))?,
+        }
+    }
+
+    let (index_block, _) = d.read(&footer.index_handle)?;
+    let mut handles = vec![];
+    d.line(format!(
+        "Index block ({}):",
+        if partitioned_index {
+            "partitioned"
+        } else {
+            "binary search"
+        }
+    ))?;
+    for (key, value) in d.entries(&index_block)? {
+        let (handle, _) = BlockHandle::decode_from(&value)?;
+        d.line(format!("  {} => {}", fmt_key(&key), fmt_handle(&handle)))?;
+        if !partitioned_index {
+            handles.push(handle);
+            continue;
+        }
+        match d
+            .read(&handle)
+            .and_then(|(partition, _)| d.entries(&partition))
+        {
+            Ok(entries) => {
+                for (key, value) in entries {
+                    let (handle, _) = BlockHandle::decode_from(&value)?;
+                    d.line(format!("    {} => {}", fmt_key(&key), fmt_handle(&handle)))?;
+                    handles.push(handle);
+                }
+            }
+            Err(e) => {
+                d.line(format!("    {}", e))?;
+                d.stats.corrupted_blocks += 1;
+            }
+        }
+    }
+
+    d.line("Data blocks:")?;
+    let mut blocks = vec![];
+    let (mut size, mut uncompressed_size) = (0, 0);
+    for (i, handle) in handles.iter().enumerate() {
+        d.stats.num_data_blocks += 1;
+        size += handle.size;
+        let res = d.read(handle).and_then(|(block, compression)| {
+            let entries = d.entries(&block)?;
+            Ok((block, compression, entries))
+        });
+        match res {
+            Ok((block, compression, entries)) => {
+                uncompressed_size += block.len() as u64;
+                d.stats.num_entries += entries.len() as u64;
+                d.line(format!(
+                    "  #{} {}, uncompressed {}, {:?}, {} entries",
+                    i,
+                    fmt_handle(handle),
+                    block.len(),
+                    compression,
+                    entries.len()
+                ))?;
+                if options.print_entries {
+                    blocks.push(entries);
+                }
+            }
+            Err(e) => {
+                d.stats.corrupted_blocks += 1;
+                d.line(format!("  #{} {}: {}", i, fmt_handle(handle), e))?;
+            }
+        }
+    }
+    d.line(format!(
+        "  total: {} blocks, {} entries, size {}, uncompressed {}, {} corrupted",
+        d.stats.num_data_blocks,
+        d.stats.num_entries,
+        size,
+        uncompressed_size,
+        d.stats.corrupted_blocks
+    ))?;
+
+    if options.print_entries {
+        d.line("Entries:")?;
+        for (key, value) in blocks.iter().flatten() {
+            let mut line = format!("  {} => {:?}", fmt_key(key), ReadableKey(value));
+            if options.hex {
+                let _ = write!(line, " [key {}, value {}]", hex(key), hex(value));
+            }
+            d.line(line)?;
+        }
+    }
+    Ok(d.stats)
+}
+
+struct Dumper<'a, F: File, W: Write> {
+    file: &'a F,
+    checksum: ChecksumType,
+    out: &'a mut W,
+    stats: DumpStats,
+}
+
+impl<'a, F: File, W: Write> Dumper<'a, F, W> {
+    fn line<S: AsRef<str>>(&mut self, s: S) -> Result<()> {
+        writeln!(self.out, "{}", s.as_ref()).map_err(Error::IO)
+    }
+
+    // Reads the block with its trailer, returns the decompressed contents and
+    // the compression type
+    fn read(&self, handle: &BlockHandle) -> Result<(Vec<u8>, CompressionType)> {
+        let n = handle.size as usize;
+        let mut buffer = vec![0; n + BLOCK_TRAILER_SIZE];
+        self.file.read_exact_at(&mut buffer, handle.offset)?;
+        let compression = buffer[n];
+        let expect = decode_fixed_32(&buffer[n + 1..]);
+        let actual = block_checksum(self.checksum, &buffer[..n], compression);
+        if expect != actual {
+            return Err(Error::Corruption(format!(
+                "block checksum mismatch: expect {:#010x}, actual {:#010x}",
+                expect, actual
+            )));
+        }
+        let contents = decode_block(buffer, self.checksum, false)?;
+        Ok((contents, CompressionType::from(compression)))
+    }
+
+    // Returns all the entries of the block
+    fn entries(&self, contents: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let block = Block::new(contents.to_vec())?;
+        let mut iter = block.iter(BytewiseComparator::default());
+        let mut entries = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        iter.status()?;
+        Ok(entries)
+    }
+
+    // Prints the meta index block and the properties and range deletion blocks
+    // it points to. Returns true if the table has a partitioned index.
+    fn dump_meta_blocks(&mut self, meta_block: &[u8]) -> Result<bool> {
+        let entries = match self.entries(meta_block) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.line(format!("Meta index block: {}", e))?;
+                return Ok(false);
+            }
+        };
+        let mut partitioned_index = false;
+        let mut properties = None;
+        let mut range_del = None;
+        self.line("Meta index block:")?;
+        for (key, value) in entries.iter() {
+            let name = ReadableKey(key);
+            if key.as_slice() == INDEX_TYPE_KEY.as_bytes() {
+                partitioned_index = value.as_slice() == [1];
+                self.line(format!("  {} => {:?}", name, value))?;
+                continue;
+            }
+            match BlockHandle::decode_from(value) {
+                Ok((handle, _)) => {
+                    self.line(format!("  {} => {}", name, fmt_handle(&handle)))?;
+                    if key.as_slice() == PROPERTIES_BLOCK_KEY.as_bytes() {
+                        properties = Some(handle);
+                    } else if key.as_slice() == RANGE_DEL_BLOCK_KEY.as_bytes() {
+                        range_del = Some(handle);
+                    }
+                }
+                Err(e) => self.line(format!("  {} => {:?}: {}", name, ReadableKey(value), e))?,
+            }
+        }
+        if let Some(handle) = properties {
+            self.line("Properties:")?;
+            match self
+                .read(&handle)
+                .and_then(|(contents, _)| TableProperties::decode_from(&contents))
+            {
+                Ok(props) => self.dump_properties(&props)?,
+                Err(e) => self.line(format!("  {}", e))?,
+            }
+        }
+        if let Some(handle) = range_del {
+            self.line("Range tombstones:")?;
+            match self
+                .read(&handle)
+                .and_then(|(contents, _)| decode_range_tombstones(&contents))
+            {
+                Ok(tombstones) => {
+                    for t in tombstones.iter() {
+                        self.line(format!(
+                            "  [{:?}, {:?}) @ {}",
+                            ReadableKey(&t.begin),
+                            ReadableKey(&t.end),
+                            t.seq
+                        ))?;
+                    }
+                }
+                Err(e) => self.line(format!("  {}", e))?,
+            }
+        }
+        Ok(partitioned_index)
+    }
+
+    fn dump_properties(&mut self, props: &TableProperties) -> Result<()> {
+        let lines = [
+            ("num entries", props.num_entries.to_string()),
+            ("num deletions", props.num_deletions.to_string()),
+            ("raw key size", props.raw_key_size.to_string()),
+            ("raw value size", props.raw_value_size.to_string()),
+            ("data size", props.data_size.to_string()),
+            ("index size", props.index_size.to_string()),
+            ("filter size", props.filter_size.to_string()),
+            ("smallest key", fmt_key(&props.smallest_key)),
+            ("largest key", fmt_key(&props.largest_key)),
+            ("creation time", props.creation_time.to_string()),
+            ("global seqno", props.global_seqno.to_string()),
+        ];
+        for (name, value) in lines.iter() {
+            self.line(format!("  {}: {}", name, value))?;
+        }
+        for (name, value) in props.user_collected.iter() {
+            self.line(format!("  {}: {:?}", name, ReadableKey(value)))?;
+        }
+        Ok(())
+    }
+}
+
+// Formats the internal key decoded, or the raw bytes if it's not an internal key
+fn fmt_key(key: &[u8]) -> String {
+    match parse_internal_key(key) {
+        Ok(parsed) => parsed.to_string(),
+        Err(_) => format!("{:?}", ReadableKey(key)),
+    }
+}
+
+fn fmt_handle(handle: &BlockHandle) -> String {
+    format!("offset {}, size {}", handle.offset, handle.size)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::{InternalKey, ValueType};
+    use crate::db::range_del::RangeTombstone;
+    use crate::options::{IndexType, Options};
+    use crate::sstable::test_util;
+    use crate::storage::mem::MemStorage;
+    use crate::storage::Storage;
+
+    fn build_table(storage: &MemStorage, index_type: IndexType) -> u64 {
+        let options = Options::<BytewiseComparator> {
+            block_size: 256,
+            index_type,
+            ..Options::default()
+        };
+        let entries =
+            (0..100u64).map(|i| (format!("k{:02}", i), 100 + i, ValueType::Value, "value"));
+        let tombstones = vec![RangeTombstone::new(b"k10", b"k20", 300)];
+        test_util::build_table(storage, "table", options, entries, tombstones)
+    }
+
+    fn dump(storage: &MemStorage, options: DumpOptions) -> (Result<DumpStats>, String) {
+        let file = storage.open("table").unwrap();
+        let len = file.len().unwrap();
+        let mut out = vec![];
+        let res = dump_table(&file, len, options, &mut out);
+        (res, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_dump_table() {
+        for index_type in [IndexType::BinarySearch, IndexType::TwoLevelIndexSearch] {
+            let storage = MemStorage::default();
+            build_table(&storage, index_type);
+            let (res, out) = dump(&storage, DumpOptions::default());
+            let stats = res.unwrap();
+            assert_eq!(stats.num_entries, 100);
+            assert!(stats.num_data_blocks > 1);
+            assert_eq!(stats.corrupted_blocks, 0);
+            assert!(out.contains("num entries: 100"), "{}", out);
+            assert!(out.contains("['k10', 'k20') @ 300"), "{}", out);
+            assert_eq!(
+                out.contains("Index block (partitioned)"),
+                index_type == IndexType::TwoLevelIndexSearch
+            );
+            assert!(!out.contains("Entries:"));
+
+            let (_, out) = dump(
+                &storage,
+                DumpOptions {
+                    print_entries: true,
+                    hex: true,
+                },
+            );
+            let key = InternalKey::new(b"k42", 142, ValueType::Value);
+            assert!(
+                out.contains(&format!(
+                    "'k42' @ 142 : Put => 'value' [key {}, value {}]",
+                    hex(key.data()),
+                    hex(b"value")
+                )),
+                "{}",
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn test_dump_corrupted_table() {
+        let storage = MemStorage::default();
+        let file_size = build_table(&storage, IndexType::BinarySearch);
+        let mut contents = vec![];
+        storage
+            .open("table")
+            .unwrap()
+            .read_all(&mut contents)
+            .unwrap();
+        // Corrupt the first data block
+        contents[10] ^= 0xff;
+        storage.remove("table").unwrap();
+        storage.create("table").unwrap().write(&contents).unwrap();
+
+        let (res, out) = dump(&storage, DumpOptions::default());
+        let stats = res.unwrap();
+        assert_eq!(stats.corrupted_blocks, 1);
+        assert!(stats.num_entries < 100);
+        assert!(out.contains("#0 offset 0"), "{}", out);
+        assert!(out.contains("block checksum mismatch"), "{}", out);
+
+        // The footer is required
+        storage.remove("table").unwrap();
+        storage
+            .create("table")
+            .unwrap()
+            .write(&contents[..file_size as usize - 1])
+            .unwrap();
+        assert!(dump(&storage, DumpOptions::default()).0.is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::db::format::InternalKeyComparator;
+use crate::db::range_del::RangeTombstone;
+use crate::db::read_stats::ReadCounters;
+use crate::iterator::Iterator;
+use crate::options::{CompressionType, Options, ReadOptions};
+use crate::sstable::properties::TableProperties;
+use crate::sstable::table::{new_table_iterator, Table, TableBuilder};
+use crate::sstable::BlockHandle;
+use crate::storage::{File, IoPriority};
+use crate::util::comparator::Comparator;
+use crate::Result;
+use std::sync::Arc;
+
+/// The iterator over the entries of a `TableReader`
+pub type TableReaderIterator = Box<dyn Iterator + Send>;
+
+/// The function given the entries of a table by `TableReader::scan`
+pub type TableEntryHandler<'a> = dyn FnMut(&[u8], &[u8]) -> Result<()> + 'a;
+
+/// `TableFactory` decides the format of the tables of the db. The tables are
+/// written by its builders in the flushes and the compactions, and opened by
+/// it in the table cache for the reads.
+///
+/// The keys of the tables are internal keys ordered by the given
+/// `InternalKeyComparator`.
+pub trait TableFactory<C: Comparator>: Send + Sync {
+    /// Returns the name of the table format
+    fn name(&self) -> &str;
+
+    /// Creates a builder writing a new table into `file`
+    fn new_builder(
+        &self,
+        file: Box<dyn File>,
+        icmp: InternalKeyComparator<C>,
+        options: &Arc<Options<C>>,
+    ) -> Box<dyn TableWriter>;
+
+    /// Opens the table `file_number` stored in the `file_size` bytes of `file`
+    fn open(
+        &self,
+        file: Box<dyn File>,
+        file_number: u64,
+        file_size: u64,
+        icmp: InternalKeyComparator<C>,
+        options: &Arc<Options<C>>,
+    ) -> Result<Arc<dyn TableReader>>;
+}
+
+/// `TableWriter` writes the entries added in the order of their internal keys
+/// into a table
+pub trait TableWriter: Send {
+    /// Sets the compression of the blocks written after. It's ignored by the
+    /// formats without compression.
+    fn set_compression(&mut self, _compression: CompressionType) {}
+
+    /// Sets the priority hint of the writes. It's ignored by default.
+    fn set_io_priority(&mut self, _priority: IoPriority) {}
+
+    /// Adds an entry whose key is larger than all the added ones
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Adds a range tombstone, which is written by `finish`
+    fn add_range_tombstone(&mut self, tombstone: RangeTombstone);
+
+    /// Returns the number of the range tombstones added
+    fn num_range_tombstones(&self) -> usize;
+
+    /// Finishes building the table and syncs the file if `sync` is true
+    fn finish(&mut self, sync: bool) -> Result<()>;
+
+    /// Abandons the table. The file should be deleted by the caller.
+    fn close(&mut self);
+
+    /// Returns the number of the entries added
+    fn num_entries(&self) -> usize;
+
+    /// Returns the size of the file, which is an estimation before `finish`
+    fn file_size(&self) -> u64;
+}
+
+/// `TableReader` reads a table opened by a `TableFactory`
+pub trait TableReader: Send + Sync {
+    /// Returns an iterator positioned at the first entry at or after the internal
+    /// key `key` if the table may contain the user key of `key`, or `None` if it
+    /// doesn't. The reads are recorded into `stats` if given.
+    fn internal_get(
+        &self,
+        options: ReadOptions,
+        key: &[u8],
+        stats: Option<&ReadCounters>,
+    ) -> Result<Option<TableReaderIterator>>;
+
+    /// Returns an iterator over all the entries of the table
+    fn new_iter(self: Arc<Self>, options: ReadOptions) -> TableReaderIterator;
+
+    /// Passes all the readable entries of the table to `f` in order for salvaging
+    /// a corrupted table. Returns the number of the entries or blocks skipped
+    /// since they can't be read.
+    fn scan(&self, f: &mut TableEntryHandler<'_>) -> Result<usize>;
+
+    /// Returns the statistics recorded when the table is built
+    fn properties(&self) -> Option<&TableProperties> {
+        None
+    }
+
+    /// Returns true if the table is opened without some of its metadata, e.g. a
+    /// corrupted filter, so the reads return the same results but slower
+    fn is_degraded(&self) -> bool {
+        false
+    }
+
+    /// Returns the range tombstones stored in the table
+    fn range_tombstones(&self) -> &[RangeTombstone] {
+        &[]
+    }
+
+    /// Returns whether the table may contain each of the internal `keys`
+    /// without reading the data
+    fn keys_may_match(&self, _options: ReadOptions, keys: &[&[u8]]) -> Result<Vec<bool>> {
+        Ok(vec![true; keys.len()])
+    }
+
+    /// Returns whether the table may contain a key with `prefix` at or after the
+    /// internal key `target`
+    fn prefix_may_match(
+        &self,
+        _options: ReadOptions,
+        _target: &[u8],
+        _prefix: &[u8],
+    ) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Returns the approximate offset in the file where the data of the internal
+    /// key `key` begins
+    fn approximate_offset_of(&self, _key: &[u8]) -> u64 {
+        0
+    }
+
+    /// Reads the block of `handle` into the block cache. Returns false if the
+    /// table has no block cache.
+    fn preload_block(&self, _handle: &BlockHandle) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Verifies a part of the data picked by `pick`, which is given the number of
+    /// the candidates. Returns the handle of the verified block, or `None` if
+    /// there is nothing to verify.
+    fn verify_data_block(
+        &self,
+        _pick: &mut dyn FnMut(usize) -> usize,
+    ) -> Result<Option<BlockHandle>> {
+        Ok(None)
+    }
+}
+
+/// Creates the block-based tables, which is the default format supporting all
+/// the features of the db. See `Table`.
+#[derive(Default, Clone, Copy)]
+pub struct BlockBasedTableFactory;
+
+impl<C: Comparator + 'static> TableFactory<C> for BlockBasedTableFactory {
+    fn name(&self) -> &str {
+        "BlockBasedTableFactory"
+    }
+
+    fn new_builder(
+        &self,
+        file: Box<dyn File>,
+        icmp: InternalKeyComparator<C>,
+        options: &Arc<Options<C>>,
+    ) -> Box<dyn TableWriter> {
+        Box::new(TableBuilder::new(file, icmp, options))
+    }
+
+    fn open(
+        &self,
+        file: Box<dyn File>,
+        file_number: u64,
+        file_size: u64,
+        icmp: InternalKeyComparator<C>,
+        options: &Arc<Options<C>>,
+    ) -> Result<Arc<dyn TableReader>> {
+        let table = Table::open(file, file_number, file_size, options.clone(), icmp.clone())?;
+        Ok(Arc::new(BlockBasedTableReader {
+            table: Arc::new(table),
+            icmp,
+        }))
+    }
+}
+
+impl<C: Comparator, F: File> TableWriter for TableBuilder<C, F> {
+    fn set_compression(&mut self, compression: CompressionType) {
+        TableBuilder::set_compression(self, compression)
+    }
+
+    fn set_io_priority(&mut self, priority: IoPriority) {
+        TableBuilder::set_io_priority(self, priority)
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        TableBuilder::add(self, key, value)
+    }
+
+    fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        TableBuilder::add_range_tombstone(self, tombstone)
+    }
+
+    fn num_range_tombstones(&self) -> usize {
+        TableBuilder::num_range_tombstones(self)
+    }
+
+    fn finish(&mut self, sync: bool) -> Result<()> {
+        TableBuilder::finish(self, sync)
+    }
+
+    fn close(&mut self) {
+        TableBuilder::close(self)
+    }
+
+    fn num_entries(&self) -> usize {
+        TableBuilder::num_entries(self)
+    }
+
+    fn file_size(&self) -> u64 {
+        TableBuilder::file_size(self)
+    }
+}
+
+struct BlockBasedTableReader<C: Comparator> {
+    table: Arc<Table<Box<dyn File>>>,
+    icmp: InternalKeyComparator<C>,
+}
+
+impl<C: Comparator + 'static> TableReader for BlockBasedTableReader<C> {
+    fn internal_get(
+        &self,
+        options: ReadOptions,
+        key: &[u8],
+        stats: Option<&ReadCounters>,
+    ) -> Result<Option<TableReaderIterator>> {
+        let iter = self
+            .table
+            .internal_get(options, self.icmp.clone(), key, stats)?;
+        Ok(iter.map(|iter| Box::new(iter) as TableReaderIterator))
+    }
+
+    fn new_iter(self: Arc<Self>, options: ReadOptions) -> TableReaderIterator {
+        Box::new(new_table_iterator(
+            self.icmp.clone(),
+            self.table.clone(),
+            options,
+        ))
+    }
+
+    fn scan(&self, f: &mut TableEntryHandler<'_>) -> Result<usize> {
+        self.table.scan_blocks(self.icmp.clone(), f)
+    }
+
+    fn properties(&self) -> Option<&TableProperties> {
+        self.table.properties()
+    }
+
+    fn is_degraded(&self) -> bool {
+        self.table.is_degraded()
+    }
+
+    fn range_tombstones(&self) -> &[RangeTombstone] {
+        self.table.range_tombstones()
+    }
+
+    fn keys_may_match(&self, options: ReadOptions, keys: &[&[u8]]) -> Result<Vec<bool>> {
+        self.table.keys_may_match(options, self.icmp.clone(), keys)
+    }
+
+    fn prefix_may_match(&self, options: ReadOptions, target: &[u8], prefix: &[u8]) -> Result<bool> {
+        self.table
+            .prefix_may_match(options, self.icmp.clone(), target, prefix)
+    }
+
+    fn approximate_offset_of(&self, key: &[u8]) -> u64 {
+        self.table.approximate_offset_of(self.icmp.clone(), key)
+    }
+
+    fn preload_block(&self, handle: &BlockHandle) -> Result<bool> {
+        self.table.preload_block(handle)
+    }
+
+    fn verify_data_block(
+        &self,
+        pick: &mut dyn FnMut(usize) -> usize,
+    ) -> Result<Option<BlockHandle>> {
+        self.table.verify_data_block(self.icmp.clone(), pick)
+    }
+}
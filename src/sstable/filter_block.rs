@@ -1,5 +1,6 @@
-use crate::filter::FilterPolicy;
+use crate::filter::{FilterBuilder, FilterPolicy};
 use crate::util::coding::{decode_fixed_32, put_fixed_32};
+use crate::{Error, Result};
 use std::sync::Arc;
 
 const FILTER_BASE_LG: usize = 11;
@@ -11,8 +12,15 @@ const FILTER_OFFSET_LEN: usize = 4; // u32 length
 /// a special block in the Table.
 pub struct FilterBlockBuilder {
     policy: Arc<dyn FilterPolicy>,
-    // 存储当前需要生成过滤器的键的集合
-    keys: Vec<Vec<u8>>,
+    // The incremental builder of `policy`. If the policy provides one, the keys
+    // are digested as soon as they're added instead of being buffered.
+    builder: Option<Box<dyn FilterBuilder>>,
+    // 存储当前需要生成过滤器的键的集合 (only used if `builder` is `None`)
+    //
+    // All the keys are flattened into `keys` and `key_starts` records
+    // the start offset of each key
+    keys: Vec<u8>,
+    key_starts: Vec<usize>,
     // 存储生成的过滤器数据
     //
     // |----- filter data -----|----- filter offsets ----|--- filter offsets len ---|--- BASE_LG ---|
@@ -24,19 +32,39 @@ pub struct FilterBlockBuilder {
 
 impl FilterBlockBuilder {
     pub fn new(policy: Arc<dyn FilterPolicy>) -> Self {
+        let builder = policy.new_filter_builder();
         Self {
             policy,
+            builder,
             keys: vec![],
+            key_starts: vec![],
             filter_offsets: vec![],
             data: vec![],
         }
     }
 
+    /// Returns the name of the policy building the filters
+    #[inline]
+    pub fn policy_name(&self) -> &str {
+        self.policy.name()
+    }
+
     /// 将给定的键添加到 keys 向量中
     pub fn add_key(&mut self, key: &[u8]) {
-        // TODO: remove this clone
-        let key = Vec::from(key);
-        self.keys.push(key);
+        if let Some(builder) = self.builder.as_mut() {
+            builder.add_key(key);
+        } else {
+            self.key_starts.push(self.keys.len());
+            self.keys.extend_from_slice(key);
+        }
+    }
+
+    // Returns the number of keys pending for the next filter
+    fn num_keys(&self) -> usize {
+        match &self.builder {
+            Some(builder) => builder.num_keys(),
+            None => self.key_starts.len(),
+        }
     }
 
     /// 根据给定的`block_offset`生成filter data
@@ -65,7 +93,7 @@ impl FilterBlockBuilder {
     /// 附加过滤器块的尾部并以字节为单位返回过滤器块数据
     pub fn finish(&mut self) -> &[u8] {
         // 如果有剩余的键未处理，调用 generate_filter 方法生成过滤器。
-        if !self.keys.is_empty() {
+        if self.num_keys() > 0 {
             // clean up the remaining keys
             self.generate_filter();
         };
@@ -83,7 +111,7 @@ impl FilterBlockBuilder {
     // 将 keys 转换为编码的过滤器向量并追加到 data 中
     fn generate_filter(&mut self) {
         // 如果 keys 为空
-        if self.keys.is_empty() {
+        if self.num_keys() == 0 {
             // 记录当前数据长度作为过滤器的起始偏移量并返回。
             self.filter_offsets.push(self.data.len() as u32);
             return;
@@ -91,11 +119,25 @@ impl FilterBlockBuilder {
         // 如果有键，也记录当前数据长度作为过滤器的起始偏移量
         self.filter_offsets.push(self.data.len() as u32);
         // 使用当前积累的键集合生成过滤器
-        let filter = self.policy.create_filter(&self.keys.iter().map(|vec| vec.as_slice()).collect());
+        let filter = if let Some(builder) = self.builder.as_mut() {
+            builder.finish()
+        } else {
+            let mut keys = Vec::with_capacity(self.key_starts.len());
+            for (i, start) in self.key_starts.iter().enumerate() {
+                let end = self
+                    .key_starts
+                    .get(i + 1)
+                    .copied()
+                    .unwrap_or(self.keys.len());
+                keys.push(&self.keys[*start..end]);
+            }
+            self.policy.create_filter(&keys)
+        };
         // 将生成的过滤器数据追加到当前的数据存储中
         self.data.extend(filter);
         // clear the keys
         self.keys.clear();
+        self.key_starts.clear();
     }
 }
 
@@ -111,30 +153,78 @@ pub struct FilterBlockReader {
 }
 
 impl FilterBlockReader {
-    pub fn new(policy: Arc<dyn FilterPolicy>, mut filter_block: Vec<u8>) -> Self {
-        let mut r = FilterBlockReader {
-            policy,
-            data: vec![],
-            num: 0,
-            base_lg: 0,
-        };
+    /// Parses the filter block built by `FilterBlockBuilder`. Returns a
+    /// `Corruption` if the offsets of the filters are invalid.
+    pub fn new(policy: Arc<dyn FilterPolicy>, mut filter_block: Vec<u8>) -> Result<Self> {
         let n = filter_block.len();
         if n < FILTER_META_LENGTH {
-            return r;
+            return Err(Error::Corruption("filter block is too short".to_owned()));
         }
-        r.num = decode_fixed_32(&filter_block[n - FILTER_META_LENGTH..n - 1]) as usize;
+        let num = decode_fixed_32(&filter_block[n - FILTER_META_LENGTH..n - 1]) as usize;
         // invalid filter offsets length
-        if r.num * FILTER_OFFSET_LEN + FILTER_META_LENGTH > n {
-            return r;
+        if num.saturating_mul(FILTER_OFFSET_LEN) + FILTER_META_LENGTH > n {
+            return Err(Error::Corruption(
+                "invalid filter offsets length".to_owned(),
+            ));
+        }
+        let base_lg = filter_block[n - 1] as usize;
+        if base_lg >= 64 {
+            return Err(Error::Corruption(format!(
+                "invalid filter base lg {}",
+                base_lg
+            )));
         }
-        r.base_lg = filter_block[n - 1] as usize;
         filter_block.truncate(n - FILTER_META_LENGTH);
-        r.data = filter_block;
-        r
+        // Every filter must lie in the filter data before the offsets
+        let filters_len = filter_block.len() - num * FILTER_OFFSET_LEN;
+        let mut last = 0;
+        for i in 0..num {
+            let offset = decode_fixed_32(&filter_block[filters_len + i * FILTER_OFFSET_LEN..])
+                as usize;
+            if offset < last || offset > filters_len {
+                return Err(Error::Corruption(format!(
+                    "invalid offset {} of filter {}",
+                    offset, i
+                )));
+            }
+            last = offset;
+        }
+        Ok(FilterBlockReader {
+            policy,
+            data: filter_block,
+            num,
+            base_lg,
+        })
     }
 
     /// Returns true if the given key is probably contained in the given `block_offset` block
     pub fn key_may_match(&self, block_offset: u64, key: &[u8]) -> bool {
+        match self.filter(block_offset) {
+            Some(filter) => self.policy.may_contain(filter, key),
+            None => true,
+        }
+    }
+
+    /// Returns whether each of the given keys is probably contained in the given
+    /// `block_offset` block, probing the filter for all of them at once
+    pub fn keys_may_match(&self, block_offset: u64, keys: &[&[u8]]) -> Vec<bool> {
+        match self.filter(block_offset) {
+            Some(filter) => self.policy.may_contain_many(filter, keys),
+            None => vec![true; keys.len()],
+        }
+    }
+
+    /// Returns true if a key with the given prefix is probably contained in
+    /// the given `block_offset` block
+    pub fn prefix_may_match(&self, block_offset: u64, prefix: &[u8]) -> bool {
+        match self.filter(block_offset) {
+            Some(filter) => self.policy.may_contain_prefix(filter, prefix),
+            None => true,
+        }
+    }
+
+    // Returns the filter of the given `block_offset` block
+    fn filter(&self, block_offset: u64) -> Option<&[u8]> {
         let i = block_offset as usize >> self.base_lg; // a >> b == a / (1 << b)
         if i < self.num {
             let (filter, offsets) = &self
@@ -153,19 +243,18 @@ impl FilterBlockReader {
                     ) as usize
                 }
             };
-            let filter = &self.data[start..end];
-            return self.policy.may_contain(filter, key);
+            return Some(&self.data[start..end]);
         }
         // errors are treated as potential matches
         // so the iterator will look up the block
-        true
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::filter::FilterPolicy;
+    use crate::filter::bloom::BloomFilter;
     use crate::util::hash::hash;
 
     struct TestHashFilter {}
@@ -201,7 +290,7 @@ mod tests {
         FilterBlockBuilder::new(Arc::new(TestHashFilter {}))
     }
     fn new_test_reader(block: Vec<u8>) -> FilterBlockReader {
-        FilterBlockReader::new(Arc::new(TestHashFilter {}), block)
+        FilterBlockReader::new(Arc::new(TestHashFilter {}), block).unwrap()
     }
 
     #[test]
@@ -279,4 +368,65 @@ mod tests {
         assert_eq!(r.key_may_match(9000, "bar".as_bytes()), false);
         assert_eq!(r.key_may_match(9000, "hello".as_bytes()), true);
     }
+
+    #[test]
+    fn test_incremental_filter_builder() {
+        let policy = Arc::new(BloomFilter::new(10));
+        assert!(policy.new_filter_builder().is_some());
+        let mut b = FilterBlockBuilder::new(policy.clone());
+        b.start_block(0);
+        b.add_key("foo".as_bytes());
+        b.add_key("bar".as_bytes());
+        b.start_block(3100);
+        b.add_key("box".as_bytes());
+        b.start_block(9000);
+        b.add_key("hello".as_bytes());
+        let block = Vec::from(b.finish());
+
+        // must be the same as the filter block built from buffered keys
+        let mut expected = vec![];
+        for keys in [vec!["foo", "bar"], vec!["box"], vec![], vec!["hello"]].iter() {
+            let keys = keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>();
+            if !keys.is_empty() {
+                expected.extend(policy.create_filter(&keys));
+            }
+        }
+        assert_eq!(&block[..expected.len()], expected.as_slice());
+
+        let r = FilterBlockReader::new(policy, block).unwrap();
+        assert!(r.key_may_match(0, "foo".as_bytes()));
+        assert!(r.key_may_match(0, "bar".as_bytes()));
+        assert!(r.key_may_match(3100, "box".as_bytes()));
+        assert!(!r.key_may_match(4100, "box".as_bytes()));
+        assert!(r.key_may_match(9000, "hello".as_bytes()));
+    }
+
+    #[test]
+    fn test_corrupted_filter_block() {
+        let policy = Arc::new(TestHashFilter {});
+        let mut b = new_test_builder();
+        b.start_block(0);
+        b.add_key("foo".as_bytes());
+        b.start_block(3100);
+        b.add_key("box".as_bytes());
+        let block = Vec::from(b.finish());
+        assert!(FilterBlockReader::new(policy.clone(), block.clone()).is_ok());
+
+        assert!(FilterBlockReader::new(policy.clone(), vec![0, 0, 11]).is_err());
+        // Too many offsets
+        let mut bad = block.clone();
+        let n = bad.len();
+        bad[n - 5..n - 1].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(FilterBlockReader::new(policy.clone(), bad).is_err());
+        // The first offset points past the filter data
+        let mut bad = block.clone();
+        let num = decode_fixed_32(&bad[n - 5..n - 1]) as usize;
+        let offsets = n - 5 - num * FILTER_OFFSET_LEN;
+        bad[offsets..offsets + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(FilterBlockReader::new(policy.clone(), bad).is_err());
+        // The base lg overflows the shift
+        let mut bad = block;
+        bad[n - 1] = 200;
+        assert!(FilterBlockReader::new(policy, bad).is_err());
+    }
 }
@@ -0,0 +1,272 @@
+use crate::db::format::{InternalKeyComparator, ParsedInternalKey, ValueType};
+use crate::db::range_del::FragmentedRangeTombstones;
+use crate::iterator::{Iterator, KMergeIter, SimpleKMerger};
+use crate::options::{Options, ReadOptions};
+use crate::sstable::table::{new_table_iterator, Table, TableBuilder};
+use crate::storage::{File, Storage};
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The table written by `merge_ssts`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOutput {
+    pub file_size: u64,
+    pub num_entries: usize,
+    pub num_range_tombstones: usize,
+    /// The entries dropped for being shadowed by newer versions or deleted
+    pub num_dropped_entries: usize,
+}
+
+/// Merges the sst files `inputs` into the single table `output` like a
+/// compaction does, but entirely outside a running db. This is useful for
+/// the build pipelines pre-compacting bulk data before ingesting it.
+///
+/// The versions of a user key are collapsed as seen by `smallest_snapshot`:
+/// the versions newer than it are all kept, and among the others only the
+/// newest one is kept. The entries covered by a newer range tombstone not
+/// newer than `smallest_snapshot` are dropped.
+///
+/// If `bottommost` is true, the inputs are assumed to hold all the data of
+/// their key range, so the deletions and the range tombstones not newer than
+/// `smallest_snapshot` are dropped as well. Otherwise they are kept to hide
+/// the data outside the inputs.
+///
+/// The output is built with the block size, compression, checksum and filter
+/// policy in `options`. The merge operands are kept as they are.
+///
+/// # Error
+///
+/// Returns `Error::InvalidArgument` if `inputs` is empty, or
+/// `Error::Corruption` if an input holds an invalid internal key.
+pub fn merge_ssts<S: Storage, C: Comparator + 'static, P: AsRef<Path>>(
+    storage: &S,
+    options: Options<C>,
+    inputs: &[P],
+    output: P,
+    smallest_snapshot: u64,
+    bottommost: bool,
+) -> Result<MergeOutput> {
+    if inputs.is_empty() {
+        return Err(Error::InvalidArgument("no sst file to merge".to_owned()));
+    }
+    let ucmp = options.comparator.clone();
+    let icmp = InternalKeyComparator::new(ucmp.clone());
+    let options = Arc::new(options);
+    let read_opt = ReadOptions {
+        fill_cache: false,
+        ..ReadOptions::default()
+    };
+    let mut tombstones = vec![];
+    let mut children = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let file = storage.open(input.as_ref())?;
+        let file_size = file.len()?;
+        let table = Arc::new(Table::open_uncached(
+            file,
+            file_size,
+            options.clone(),
+            icmp.clone(),
+        )?);
+        tombstones.extend_from_slice(table.range_tombstones());
+        children.push(new_table_iterator(icmp.clone(), table, read_opt));
+    }
+    let range_del = FragmentedRangeTombstones::new(ucmp.clone(), tombstones.iter());
+
+    let mut builder = TableBuilder::new(storage.create(output.as_ref())?, icmp.clone(), &options);
+    let mut num_dropped_entries = 0;
+    let mut iter = KMergeIter::new(SimpleKMerger::new(icmp.clone(), children));
+    iter.seek_to_first();
+    let mut current_ukey: Option<Vec<u8>> = None;
+    let mut last_sequence_for_key = u64::MAX;
+    let mut last_key: Vec<u8> = vec![];
+    while iter.valid() {
+        let ikey = iter.key();
+        let key = ParsedInternalKey::decode_from(ikey).ok_or_else(|| {
+            Error::Corruption(format!("invalid internal key {:?} in sst files", ikey))
+        })?;
+        if current_ukey
+            .as_ref()
+            .is_none_or(|k| ucmp.compare(key.user_key, k) != Ordering::Equal)
+        {
+            // First occurrence of this user key
+            current_ukey = Some(key.user_key.to_vec());
+            last_sequence_for_key = u64::MAX;
+        }
+        // The same entry could be in more than one input
+        let duplicated = !last_key.is_empty() && icmp.compare(ikey, &last_key) == Ordering::Equal;
+        let drop = duplicated
+            || last_sequence_for_key <= smallest_snapshot
+            || (bottommost
+                && key.value_type == ValueType::Deletion
+                && key.seq <= smallest_snapshot
+                // Hiding the versions of the key with older timestamps or in the
+                // cold store
+                && ucmp.timestamp_size() == 0
+                && options.cold_store.is_none())
+            || range_del.should_delete(key.user_key, key.seq, smallest_snapshot);
+        if !duplicated {
+            last_sequence_for_key = key.seq;
+        }
+        if drop {
+            num_dropped_entries += 1;
+        } else {
+            if key.value_type == ValueType::Merge {
+                // The older entries of the key are needed by this operand
+                last_sequence_for_key = u64::MAX;
+            }
+            builder.add(ikey, iter.value())?;
+            last_key.clear();
+            last_key.extend_from_slice(ikey);
+        }
+        iter.next();
+    }
+    iter.status()?;
+
+    for t in tombstones {
+        if !t.is_empty(&ucmp) && (!bottommost || t.seq > smallest_snapshot) {
+            builder.add_range_tombstone(t);
+        }
+    }
+    builder.finish(true)?;
+    Ok(MergeOutput {
+        file_size: builder.file_size(),
+        num_entries: builder.num_entries(),
+        num_range_tombstones: builder.num_range_tombstones(),
+        num_dropped_entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::range_del::RangeTombstone;
+    use crate::sstable::test_util;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    fn build_table(
+        storage: &MemStorage,
+        name: &str,
+        entries: &[(&str, u64, ValueType)],
+        tombstones: Vec<RangeTombstone>,
+    ) {
+        let entries = entries
+            .iter()
+            .map(|(ukey, seq, t)| (*ukey, *seq, *t, format!("{}@{}", ukey, seq)));
+        test_util::build_table(storage, name, Options::default(), entries, tombstones);
+    }
+
+    fn read_table(storage: &MemStorage, name: &str) -> (Vec<String>, Vec<RangeTombstone>) {
+        let (entries, tombstones) = test_util::read_table(storage, name);
+        let entries = entries
+            .into_iter()
+            .map(|(ukey, seq, t)| format!("{}@{}:{:?}", ukey, seq, t))
+            .collect();
+        (entries, tombstones)
+    }
+
+    fn build_inputs(storage: &MemStorage) {
+        build_table(
+            storage,
+            "input0",
+            &[
+                ("a", 10, ValueType::Value),
+                ("b", 12, ValueType::Deletion),
+                ("c", 3, ValueType::Value),
+                ("d", 20, ValueType::Merge),
+                ("e", 4, ValueType::Value),
+            ],
+            vec![RangeTombstone::new(b"e", b"f", 5)],
+        );
+        build_table(
+            storage,
+            "input1",
+            &[
+                ("a", 8, ValueType::Value),
+                ("a", 2, ValueType::Value),
+                ("b", 1, ValueType::Value),
+                ("c", 3, ValueType::Value),
+                ("d", 6, ValueType::Value),
+                ("g", 30, ValueType::Value),
+            ],
+            vec![RangeTombstone::new(b"g", b"h", 25)],
+        );
+    }
+
+    #[test]
+    fn test_merge_ssts() {
+        let storage = MemStorage::default();
+        build_inputs(&storage);
+        let res = merge_ssts(
+            &storage,
+            Options::<BytewiseComparator>::default(),
+            &["input0", "input1"],
+            "output",
+            9,
+            false,
+        )
+        .unwrap();
+        let (entries, tombstones) = read_table(&storage, "output");
+        assert_eq!(
+            entries,
+            vec![
+                "a@10:Value",
+                "a@8:Value",
+                "b@12:Deletion",
+                "b@1:Value",
+                "c@3:Value",
+                "d@20:Merge",
+                "d@6:Value",
+                "g@30:Value",
+            ]
+        );
+        assert_eq!(tombstones.len(), 2);
+        assert_eq!(res.num_entries, 8);
+        assert_eq!(res.num_range_tombstones, 2);
+        // "a@2", the duplicated "c@3" and "e@4" deleted by the tombstone
+        assert_eq!(res.num_dropped_entries, 3);
+
+        let res = merge_ssts(
+            &storage,
+            Options::<BytewiseComparator>::default(),
+            &["input0", "input1"],
+            "bottommost_output",
+            100,
+            true,
+        )
+        .unwrap();
+        let (entries, tombstones) = read_table(&storage, "bottommost_output");
+        assert_eq!(
+            entries,
+            vec![
+                "a@10:Value",
+                "c@3:Value",
+                "d@20:Merge",
+                "d@6:Value",
+                "g@30:Value"
+            ]
+        );
+        assert!(tombstones.is_empty());
+        assert_eq!(res.num_dropped_entries, 6);
+    }
+
+    #[test]
+    fn test_merge_ssts_no_input() {
+        let storage = MemStorage::default();
+        let inputs: Vec<&str> = vec![];
+        assert!(matches!(
+            merge_ssts(
+                &storage,
+                Options::<BytewiseComparator>::default(),
+                &inputs,
+                "output",
+                0,
+                true,
+            ),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
@@ -36,8 +36,11 @@
 ///     | compression type (1-byte) | checksum (4-byte) |
 ///     +---------------------------+-------------------+
 ///
-///     The checksum is a CRC-32 computed using Castagnoli's polynomial. Compression
-///     type also included in the checksum.
+///     The checksum is the masked IEEE CRC-32 (`ChecksumType::Crc32`, the only
+///     checksum of format version 0), the masked CRC-32C computed using Castagnoli's
+///     polynomial, or the lower 32 bits of xxHash64 / XXH3. The checksum type is
+///     recorded in the footer of format version 1 (see `Footer`). Compression type
+///     also included in the checksum.
 ///
 /// ```
 ///
@@ -193,16 +196,32 @@
 ///
 /// NOTE: All fixed-length integer are little-endian.
 pub mod block;
+pub mod bulk_load;
+pub mod cuckoo;
+pub mod dedup;
+pub mod dump;
+pub mod factory;
 mod filter_block;
+pub mod merge;
+pub mod properties;
+pub mod split;
+pub mod sst_file;
 pub mod table;
 
-use crate::util::coding::{decode_fixed_64, put_fixed_64};
+use crate::options::ChecksumType;
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
 use crate::util::varint::{VarintU64, MAX_VARINT_LEN_U64};
 use crate::{Error, Result};
 
 // magic
 const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
 
+// format version 1 的魔数，footer 中记录了校验和类型
+const TABLE_MAGIC_NUMBER_V1: u64 = 0x88e241b785f4cff7;
+
+// 记录了块校验和类型的 format version
+const FORMAT_VERSION_V1: u32 = 1;
+
 // 1byte compression type + 4bytes CRC
 const BLOCK_TRAILER_SIZE: usize = 5;
 
@@ -212,6 +231,10 @@ const MAX_BLOCK_HANDLE_ENCODE_LENGTH: usize = 2 * MAX_VARINT_LEN_U64;
 // 页脚的编码长度。它由两个block handle和一个magic组成。  40+8 byte
 const FOOTER_ENCODED_LENGTH: usize = 2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH + 8;
 
+// format version 1 的页脚长度: 1 byte 校验和类型 + 40 byte + 4 byte version + 8 byte magic。
+// 也是页脚的最大长度
+const FOOTER_ENCODED_LENGTH_V1: usize = 1 + 2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH + 4 + 8;
+
 /// `BlockHandle`处理块存储中的偏移和大小信息
 /// 通过长度和偏移来确定每个block的位置
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -235,6 +258,16 @@ impl BlockHandle {
     pub fn set_size(&mut self, size: u64) {
         self.size = size
     }
+    // 返回 size，不包含 block trailer
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    // 返回 offset
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
 
     /// 将 varint 编码的 offset 和 size 附加到给定的 `dst`
     #[inline]
@@ -273,41 +306,91 @@ impl BlockHandle {
 }
 
 ///` Footer `用于封装存储在每个sstable文件末尾的固定信息 408byte(meta_index_handle+index_handle)+8byte(magic)
+///
+/// 使用 `ChecksumType::Crc32` 以外的校验和的 table 使用 format version 1 的 footer，
+/// 在前面加上 1 字节的校验和类型，在魔数前加上 4 字节的 format version，并使用新的魔数：
+///
+/// ```text
+///     +----------------------+------------------ 40-bytes -----------------+--------------------+-----------------+
+///     | checksum type (1-b)  | metaindex handle / index handle / padding   | version (4-bytes)  | magic (8-bytes) |
+///     +----------------------+---------------------------------------------+--------------------+-----------------+
+/// ```
 #[derive(Debug)]
 pub struct Footer {
     meta_index_handle: BlockHandle,
     index_handle: BlockHandle,
+    checksum: ChecksumType,
 }
 
 impl Footer {
     #[inline]
-    pub fn new(meta_index_handle: BlockHandle, index_handle: BlockHandle) -> Self {
+    pub fn new(
+        meta_index_handle: BlockHandle,
+        index_handle: BlockHandle,
+        checksum: ChecksumType,
+    ) -> Self {
         Self {
             meta_index_handle,
             index_handle,
+            checksum,
         }
     }
 
-    // 从字节数组中解码 Footer，并返回解码的长度
+    /// Returns the format version of the table. The legacy format (version 0)
+    /// is kept for `ChecksumType::Crc32` so the tables are still readable by older versions.
+    #[inline]
+    pub fn format_version(&self) -> u32 {
+        match self.checksum {
+            ChecksumType::Crc32 => 0,
+            _ => FORMAT_VERSION_V1,
+        }
+    }
+
+    // 从文件尾部的字节数组中解码 Footer，并返回解码的长度
     ///
     /// # Error
     ///
-    /// Returns `Status::Corruption` when decoding meta index or index handle fails
+    /// Returns `Status::Corruption` when decoding meta index or index handle fails,
+    /// or the format version or checksum type is unknown
     ///
     pub fn decode_from(src: &[u8]) -> Result<(Self, usize)> {
-        // (40,48]
-        let magic = decode_fixed_64(&src[FOOTER_ENCODED_LENGTH - 8..]);
-        if magic != TABLE_MAGIC_NUMBER {
-            return Err(Error::Corruption(
-                "not an sstable (bad magic number)".to_owned(),
-            ));
+        if src.len() < FOOTER_ENCODED_LENGTH {
+            return Err(Error::Corruption("footer is too short".to_owned()));
+        }
+        let magic = decode_fixed_64(&src[src.len() - 8..]);
+        let (handles, checksum) = match magic {
+            // (40,48]
+            TABLE_MAGIC_NUMBER => (
+                &src[src.len() - FOOTER_ENCODED_LENGTH..],
+                ChecksumType::Crc32,
+            ),
+            TABLE_MAGIC_NUMBER_V1 if src.len() >= FOOTER_ENCODED_LENGTH_V1 => {
+                let footer = &src[src.len() - FOOTER_ENCODED_LENGTH_V1..];
+                let version = decode_fixed_32(&footer[FOOTER_ENCODED_LENGTH_V1 - 12..]);
+                if version != FORMAT_VERSION_V1 {
+                    return Err(Error::Corruption(format!(
+                        "unsupported sstable format version {}",
+                        version
+                    )));
+                }
+                let checksum = ChecksumType::from_u8(footer[0]).ok_or_else(|| {
+                    Error::Corruption(format!("unknown checksum type {}", footer[0]))
+                })?;
+                (&footer[1..], checksum)
+            }
+            _ => {
+                return Err(Error::Corruption(
+                    "not an sstable (bad magic number)".to_owned(),
+                ))
+            }
         };
-        let (meta_index_handle, n) = BlockHandle::decode_from(src)?;
-        let (index_handle, m) = BlockHandle::decode_from(&src[n..])?;
+        let (meta_index_handle, n) = BlockHandle::decode_from(handles)?;
+        let (index_handle, m) = BlockHandle::decode_from(&handles[n..])?;
         Ok((
             Self {
                 meta_index_handle,
                 index_handle,
+                checksum,
             },
             m + n,
         ))
@@ -315,32 +398,110 @@ impl Footer {
 
     // 编码 Footer 并返回编码后的字节数组
     pub fn encoded(&self) -> Vec<u8> {
+        let version = self.format_version();
         let mut v = vec![];
+        if version != 0 {
+            v.push(self.checksum as u8);
+        }
         // 编码 meta index handle
         self.meta_index_handle.encoded_to(&mut v);
         // 编码 index handle
         self.index_handle.encoded_to(&mut v);
-        v.resize(2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH, 0);
+        let (magic, expect) = if version == 0 {
+            v.resize(2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH, 0);
+            (TABLE_MAGIC_NUMBER, FOOTER_ENCODED_LENGTH)
+        } else {
+            v.resize(1 + 2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH, 0);
+            put_fixed_32(&mut v, version);
+            (TABLE_MAGIC_NUMBER_V1, FOOTER_ENCODED_LENGTH_V1)
+        };
         // 添加魔数
-        put_fixed_64(&mut v, TABLE_MAGIC_NUMBER);
+        put_fixed_64(&mut v, magic);
         assert_eq!(
             v.len(),
-            FOOTER_ENCODED_LENGTH,
+            expect,
             "[footer] the length of encoded footer is {}, expect {}",
             v.len(),
-            FOOTER_ENCODED_LENGTH
+            expect
         );
         v
     }
 }
 
+// The helpers building and reading the tables of internal keys, shared by the
+// tests of the offline sstable tools
+#[cfg(test)]
+pub(crate) mod test_util {
+    use crate::db::format::{
+        extract_user_key, InternalKey, InternalKeyComparator, ParsedInternalKey, ValueType,
+    };
+    use crate::db::range_del::RangeTombstone;
+    use crate::iterator::Iterator;
+    use crate::sstable::table::{new_table_iterator, Table, TableBuilder};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use crate::{File, Options, ReadOptions, Storage};
+    use std::sync::Arc;
+
+    // Builds the table `name` with the entries of (user key, sequence, value type,
+    // value) in order and the range tombstones. Returns the file size.
+    pub fn build_table<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        storage: &MemStorage,
+        name: &str,
+        options: Options<BytewiseComparator>,
+        entries: impl IntoIterator<Item = (K, u64, ValueType, V)>,
+        tombstones: Vec<RangeTombstone>,
+    ) -> u64 {
+        let options = Arc::new(options);
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let mut builder = TableBuilder::new(storage.create(name).unwrap(), icmp, &options);
+        for (ukey, seq, value_type, value) in entries {
+            let ikey = InternalKey::new(ukey.as_ref(), seq, value_type);
+            builder.add(ikey.data(), value.as_ref()).unwrap();
+        }
+        for t in tombstones {
+            builder.add_range_tombstone(t);
+        }
+        builder.finish(true).unwrap();
+        builder.file_size()
+    }
+
+    // Returns the (user key, sequence, value type) of all the entries in the table
+    // `name` and its range tombstones
+    pub fn read_table(
+        storage: &MemStorage,
+        name: &str,
+    ) -> (Vec<(String, u64, ValueType)>, Vec<RangeTombstone>) {
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let file = storage.open(name).unwrap();
+        let len = file.len().unwrap();
+        let options = Arc::new(Options::<BytewiseComparator>::default());
+        let table = Arc::new(Table::open_uncached(file, len, options, icmp.clone()).unwrap());
+        let mut iter = new_table_iterator(icmp, table.clone(), ReadOptions::default());
+        iter.seek_to_first();
+        let mut entries = vec![];
+        while iter.valid() {
+            let key = ParsedInternalKey::decode_from(iter.key()).unwrap();
+            let ukey = String::from_utf8(extract_user_key(iter.key()).to_vec()).unwrap();
+            entries.push((ukey, key.seq, key.value_type));
+            iter.next();
+        }
+        (entries, table.range_tombstones().to_vec())
+    }
+}
+
 #[cfg(test)]
 mod test_footer {
-    use crate::sstable::{BlockHandle, Footer};
+    use crate::options::ChecksumType;
+    use crate::sstable::{BlockHandle, Footer, FOOTER_ENCODED_LENGTH, FOOTER_ENCODED_LENGTH_V1};
 
     #[test]
     fn test_footer_corruption() {
-        let footer = Footer::new(BlockHandle::new(300, 100), BlockHandle::new(401, 1000));
+        let footer = Footer::new(
+            BlockHandle::new(300, 100),
+            BlockHandle::new(401, 1000),
+            ChecksumType::Crc32,
+        );
         let mut encoded = footer.encoded();
         let last = encoded.last_mut().unwrap();
         *last += 1;
@@ -355,11 +516,69 @@ mod test_footer {
 
     #[test]
     fn test_encode_decode() {
-        let footer = Footer::new(BlockHandle::new(300, 100), BlockHandle::new(401, 1000));
+        let footer = Footer::new(
+            BlockHandle::new(300, 100),
+            BlockHandle::new(401, 1000),
+            ChecksumType::Crc32,
+        );
         let encoded = footer.encoded();
         let (footer, _) = Footer::decode_from(&encoded).expect("footer decoding should work");
         assert_eq!(footer.index_handle, BlockHandle::new(401, 1000));
         assert_eq!(footer.meta_index_handle, BlockHandle::new(300, 100));
+        assert_eq!(encoded.len(), FOOTER_ENCODED_LENGTH);
+        assert_eq!(footer.format_version(), 0);
+    }
+
+    #[test]
+    fn test_encode_decode_v1() {
+        for checksum in [
+            ChecksumType::Crc32c,
+            ChecksumType::XxHash64,
+            ChecksumType::Xxh3,
+        ] {
+            let footer = Footer::new(
+                BlockHandle::new(300, 100),
+                BlockHandle::new(401, 1000),
+                checksum,
+            );
+            let encoded = footer.encoded();
+            assert_eq!(encoded.len(), FOOTER_ENCODED_LENGTH_V1);
+            // The footer is decoded from the tail of a file
+            let mut tail = vec![7u8; 20];
+            tail.extend_from_slice(&encoded);
+            let (footer, _) = Footer::decode_from(&tail).expect("footer decoding should work");
+            assert_eq!(footer.index_handle, BlockHandle::new(401, 1000));
+            assert_eq!(footer.meta_index_handle, BlockHandle::new(300, 100));
+            assert_eq!(footer.checksum, checksum);
+            assert_eq!(footer.format_version(), 1);
+        }
+        // A legacy footer at the tail
+        let legacy = Footer::new(
+            BlockHandle::new(1, 2),
+            BlockHandle::new(3, 4),
+            ChecksumType::Crc32,
+        );
+        let mut tail = vec![7u8; 5];
+        tail.extend_from_slice(&legacy.encoded());
+        let (footer, _) = Footer::decode_from(&tail).unwrap();
+        assert_eq!(footer.checksum, ChecksumType::Crc32);
+        assert_eq!(footer.index_handle, BlockHandle::new(3, 4));
+
+        // Unknown checksum type and format version
+        let footer = Footer::new(
+            BlockHandle::new(1, 2),
+            BlockHandle::new(3, 4),
+            ChecksumType::Xxh3,
+        );
+        let mut encoded = footer.encoded();
+        encoded[0] = 100;
+        assert!(Footer::decode_from(&encoded).is_err());
+        let mut encoded = footer.encoded();
+        encoded[FOOTER_ENCODED_LENGTH_V1 - 12] = 2;
+        assert_eq!(
+            Footer::decode_from(&encoded).unwrap_err().to_string(),
+            "data corruption: unsupported sstable format version 2"
+        );
     }
 }
 
@@ -369,10 +588,10 @@ mod tests {
         InternalKey, InternalKeyComparator, ParsedInternalKey, ValueType, MAX_KEY_SEQUENCE,
         VALUE_TYPE_FOR_SEEK,
     };
-    use crate::db::{WickDB, WickDBIterator, DB};
+    use crate::db::{WickDB, DB};
     use crate::iterator::Iterator;
     use crate::mem::{MemTable, MemTableIterator};
-    use crate::options::{Options, ReadOptions};
+    use crate::options::{OpenMode, Options, ReadOptions};
     use crate::sstable::block::*;
     use crate::sstable::table::*;
     use crate::storage::mem::{FileNode, MemStorage};
@@ -752,7 +971,7 @@ mod tests {
     }
 
     struct DBIterWrapper {
-        inner: WickDBIterator<MemStorage, TestComparator>,
+        inner: <WickDB<MemStorage, TestComparator> as DB>::Iterator,
         key_buf: Vec<u8>,
         value_buf: Vec<u8>,
     }
@@ -816,7 +1035,7 @@ mod tests {
             let mut options = Options::<TestComparator>::default();
             let env = MemStorage::default();
             options.write_buffer_size = 10000; // Something small to force merging
-            options.error_if_exists = true;
+            options.open_mode = OpenMode::CreateNew;
             options.comparator = TestComparator::new(is_reversed);
             let db = WickDB::open_db(options, "table_testdb", env).expect("could not open db");
             Self { inner: db }
@@ -0,0 +1,199 @@
+use crate::util::varint::{VarintU32, VarintU64};
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::str;
+
+/// The key of the properties block in the meta index block
+pub const PROPERTIES_BLOCK_KEY: &str = "wickdb.properties";
+
+const CREATION_TIME: &str = "wickdb.creation.time";
+const DATA_SIZE: &str = "wickdb.data.size";
+const FILTER_SIZE: &str = "wickdb.filter.size";
+const GLOBAL_SEQNO: &str = "wickdb.global.seqno";
+const INDEX_SIZE: &str = "wickdb.index.size";
+const LARGEST_KEY: &str = "wickdb.largest.key";
+const NUM_DELETIONS: &str = "wickdb.num.deletions";
+const NUM_ENTRIES: &str = "wickdb.num.entries";
+const RAW_KEY_SIZE: &str = "wickdb.raw.key.size";
+const RAW_VALUE_SIZE: &str = "wickdb.raw.value.size";
+const SMALLEST_KEY: &str = "wickdb.smallest.key";
+
+// The prefix of the names of the builtin properties
+const BUILTIN_PREFIX: &str = "wickdb.";
+
+/// A `TablePropertiesCollector` collects the user defined properties of a
+/// table while it's being built. The properties are written into the
+/// properties block of the table and are readable by
+/// `TableProperties::user_collected`.
+pub trait TablePropertiesCollector: Send {
+    /// Called for each entry added into the table in order. The `key` is the
+    /// user key if the table is built by the db.
+    fn add(&mut self, key: &[u8], value: &[u8]);
+
+    /// Returns the collected properties when the table is finished. The names
+    /// starting with `wickdb.` are reserved for the builtin properties and
+    /// are ignored.
+    fn finish(&mut self) -> Vec<(String, Vec<u8>)>;
+}
+
+/// Creates a `TablePropertiesCollector` for each table built
+pub trait TablePropertiesCollectorFactory: Send + Sync {
+    fn create(&self) -> Box<dyn TablePropertiesCollector>;
+}
+
+/// `TableProperties` is the statistics of a table recorded when the table is
+/// built, so they could be used without scanning the table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// The number of the entries
+    pub num_entries: u64,
+    /// The number of the point deletions
+    pub num_deletions: u64,
+    /// The total size of the keys before compression
+    pub raw_key_size: u64,
+    /// The total size of the values before compression
+    pub raw_value_size: u64,
+    /// The size of the data blocks in the file
+    pub data_size: u64,
+    /// The size of the index blocks in the file
+    pub index_size: u64,
+    /// The size of the filter block in the file
+    pub filter_size: u64,
+    /// The first key of the table. Empty if the table has no entry.
+    pub smallest_key: Vec<u8>,
+    /// The last key of the table. Empty if the table has no entry.
+    pub largest_key: Vec<u8>,
+    /// The seconds since the unix epoch when the table is built
+    pub creation_time: u64,
+    /// The sequence number replacing the ones of all the keys when the table
+    /// is read, which is assigned by `WickDB::ingest_external_file`. The keys
+    /// keep their own sequence numbers if it's 0.
+    pub global_seqno: u64,
+    /// The properties collected by the `TablePropertiesCollector`s
+    pub user_collected: BTreeMap<String, Vec<u8>>,
+}
+
+impl TableProperties {
+    /// Encodes the properties into the contents of a properties block, which
+    /// is a list of name/value pairs sorted by the names
+    ///
+    /// ```text
+    ///   +-------------------+-----------+--------------------+------------+
+    ///   | name len (varint) | name data | value len (varint) | value data |  ...
+    ///   +-------------------+-----------+--------------------+------------+
+    /// ```
+    ///
+    /// The numbers are encoded as varints in the values. The user collected
+    /// properties are in the same list.
+    pub fn encode(&self) -> Vec<u8> {
+        let num = |n: u64| {
+            let mut v = vec![];
+            VarintU64::put_varint(&mut v, n);
+            v
+        };
+        let mut props = vec![
+            (CREATION_TIME, num(self.creation_time)),
+            (DATA_SIZE, num(self.data_size)),
+            (FILTER_SIZE, num(self.filter_size)),
+            (GLOBAL_SEQNO, num(self.global_seqno)),
+            (INDEX_SIZE, num(self.index_size)),
+            (LARGEST_KEY, self.largest_key.clone()),
+            (NUM_DELETIONS, num(self.num_deletions)),
+            (NUM_ENTRIES, num(self.num_entries)),
+            (RAW_KEY_SIZE, num(self.raw_key_size)),
+            (RAW_VALUE_SIZE, num(self.raw_value_size)),
+            (SMALLEST_KEY, self.smallest_key.clone()),
+        ];
+        props.extend(
+            self.user_collected
+                .iter()
+                .filter(|(name, _)| !name.starts_with(BUILTIN_PREFIX))
+                .map(|(name, value)| (name.as_str(), value.clone())),
+        );
+        props.sort_by_key(|(name, _)| *name);
+        let mut dst = vec![];
+        for (name, value) in props.iter() {
+            VarintU32::put_varint_prefixed_slice(&mut dst, name.as_bytes());
+            VarintU32::put_varint_prefixed_slice(&mut dst, value);
+        }
+        dst
+    }
+
+    /// Decodes the properties from the contents of a properties block. The
+    /// unknown builtin properties are ignored so the tables written by a newer
+    /// version could still be read.
+    pub fn decode_from(mut src: &[u8]) -> Result<Self> {
+        let corruption = || Error::Corruption("bad table properties block".to_owned());
+        let mut props = TableProperties::default();
+        while !src.is_empty() {
+            let name = VarintU32::get_varint_prefixed_slice(&mut src).ok_or_else(corruption)?;
+            let value = VarintU32::get_varint_prefixed_slice(&mut src).ok_or_else(corruption)?;
+            let name = str::from_utf8(name).map_err(|_| corruption())?;
+            let field = match name {
+                LARGEST_KEY => {
+                    props.largest_key = value.to_vec();
+                    continue;
+                }
+                SMALLEST_KEY => {
+                    props.smallest_key = value.to_vec();
+                    continue;
+                }
+                CREATION_TIME => &mut props.creation_time,
+                DATA_SIZE => &mut props.data_size,
+                FILTER_SIZE => &mut props.filter_size,
+                GLOBAL_SEQNO => &mut props.global_seqno,
+                INDEX_SIZE => &mut props.index_size,
+                NUM_DELETIONS => &mut props.num_deletions,
+                NUM_ENTRIES => &mut props.num_entries,
+                RAW_KEY_SIZE => &mut props.raw_key_size,
+                RAW_VALUE_SIZE => &mut props.raw_value_size,
+                _ => {
+                    if !name.starts_with(BUILTIN_PREFIX) {
+                        props.user_collected.insert(name.to_owned(), value.to_vec());
+                    }
+                    continue;
+                }
+            };
+            *field = VarintU64::read(value).ok_or_else(corruption)?.0;
+        }
+        Ok(props)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_table_properties() {
+        let props = TableProperties {
+            num_entries: 100,
+            num_deletions: 3,
+            raw_key_size: 1 << 20,
+            raw_value_size: 1 << 40,
+            data_size: 4096,
+            index_size: 128,
+            filter_size: 0,
+            smallest_key: b"a".to_vec(),
+            largest_key: b"z".to_vec(),
+            creation_time: 1_700_000_000,
+            global_seqno: 42,
+            user_collected: vec![("tenants".to_owned(), b"3".to_vec())]
+                .into_iter()
+                .collect(),
+        };
+        let mut encoded = props.encode();
+        assert_eq!(TableProperties::decode_from(&encoded).unwrap(), props);
+
+        // Unknown properties are skipped
+        VarintU32::put_varint_prefixed_slice(&mut encoded, b"wickdb.unknown");
+        VarintU32::put_varint_prefixed_slice(&mut encoded, b"value");
+        assert_eq!(TableProperties::decode_from(&encoded).unwrap(), props);
+
+        encoded.truncate(encoded.len() - 1);
+        assert!(matches!(
+            TableProperties::decode_from(&encoded),
+            Err(Error::Corruption(_))
+        ));
+    }
+}
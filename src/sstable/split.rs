@@ -0,0 +1,211 @@
+use crate::db::format::{extract_user_key, InternalKeyComparator};
+use crate::db::range_del::truncate_range_tombstones;
+use crate::iterator::Iterator;
+use crate::options::{Options, ReadOptions};
+use crate::sstable::table::{new_table_iterator, Table, TableBuilder};
+use crate::storage::{File, Storage};
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A table written by `split_table`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitOutput {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub num_entries: usize,
+    pub num_range_tombstones: usize,
+}
+
+/// Splits the sst file `input` into smaller ones at the given user keys, which
+/// is useful before ingesting a large table into a db with a small target file
+/// size. The entries are streamed from the input table to the outputs.
+///
+/// The `i`th output holds the user keys in `[boundaries[i - 1], boundaries[i])`,
+/// so all the versions of a user key end up in the same table. The range
+/// tombstones spanning a boundary are cut into the outputs on both sides. An
+/// output with no entry and no tombstone is not created.
+///
+/// The outputs are built with the block size, compression, checksum and filter
+/// policy in `options`.
+///
+/// # Error
+///
+/// Returns `Error::InvalidArgument` if `outputs.len()` is not
+/// `boundaries.len() + 1` or the boundaries are not strictly increasing.
+pub fn split_table<S: Storage, C: Comparator + 'static, P: AsRef<Path>>(
+    storage: &S,
+    options: Options<C>,
+    input: P,
+    boundaries: &[&[u8]],
+    outputs: &[P],
+) -> Result<Vec<SplitOutput>> {
+    if outputs.len() != boundaries.len() + 1 {
+        return Err(Error::InvalidArgument(format!(
+            "{} boundaries split a table into {} parts but {} outputs are given",
+            boundaries.len(),
+            boundaries.len() + 1,
+            outputs.len()
+        )));
+    }
+    let ucmp = options.comparator.clone();
+    if boundaries
+        .windows(2)
+        .any(|w| ucmp.compare(w[0], w[1]) != Ordering::Less)
+    {
+        return Err(Error::InvalidArgument(
+            "split boundaries must be strictly increasing".to_owned(),
+        ));
+    }
+    let icmp = InternalKeyComparator::new(ucmp.clone());
+    let options = Arc::new(options);
+    let file = storage.open(input.as_ref())?;
+    let file_size = file.len()?;
+    let table = Arc::new(Table::open_uncached(
+        file,
+        file_size,
+        options.clone(),
+        icmp.clone(),
+    )?);
+
+    let mut result = vec![];
+    let mut iter = new_table_iterator(icmp.clone(), table.clone(), ReadOptions::default());
+    iter.seek_to_first();
+    for (i, output) in outputs.iter().enumerate() {
+        let lower = if i == 0 {
+            None
+        } else {
+            Some(boundaries[i - 1])
+        };
+        let upper = boundaries.get(i).copied();
+        let tombstones = truncate_range_tombstones(&ucmp, table.range_tombstones(), lower, upper);
+        let mut builder = None;
+        while iter.valid() {
+            let key = iter.key();
+            if upper.is_some_and(|u| ucmp.compare(extract_user_key(key), u) != Ordering::Less) {
+                break;
+            }
+            if builder.is_none() {
+                builder = Some(TableBuilder::new(
+                    storage.create(output.as_ref())?,
+                    icmp.clone(),
+                    &options,
+                ));
+            }
+            builder.as_mut().unwrap().add(key, iter.value())?;
+            iter.next();
+        }
+        iter.status()?;
+        if builder.is_none() && !tombstones.is_empty() {
+            builder = Some(TableBuilder::new(
+                storage.create(output.as_ref())?,
+                icmp.clone(),
+                &options,
+            ));
+        }
+        if let Some(mut builder) = builder {
+            for t in tombstones {
+                builder.add_range_tombstone(t);
+            }
+            builder.finish(true)?;
+            result.push(SplitOutput {
+                path: output.as_ref().to_path_buf(),
+                file_size: builder.file_size(),
+                num_entries: builder.num_entries(),
+                num_range_tombstones: builder.num_range_tombstones(),
+            });
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::ValueType;
+    use crate::db::range_del::RangeTombstone;
+    use crate::sstable::test_util::{self, build_table};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    fn build_input(storage: &MemStorage) {
+        let mut entries = vec![];
+        for i in 0..100 {
+            let ukey = format!("k{:02}", i);
+            // Two versions of each key
+            for seq in [200 - i, 100 - i] {
+                entries.push((ukey.clone(), seq, ValueType::Value, format!("v{}", seq)));
+            }
+        }
+        build_table(
+            storage,
+            "input",
+            Options::default(),
+            entries,
+            vec![RangeTombstone::new(b"k45", b"k55", 300)],
+        );
+    }
+
+    fn read_table(storage: &MemStorage, name: &str) -> (Vec<String>, Vec<RangeTombstone>) {
+        let (entries, tombstones) = test_util::read_table(storage, name);
+        (entries.into_iter().map(|(k, _, _)| k).collect(), tombstones)
+    }
+
+    #[test]
+    fn test_split_table() {
+        let storage = MemStorage::default();
+        build_input(&storage);
+        let res = split_table(
+            &storage,
+            Options::<BytewiseComparator>::default(),
+            "input",
+            &[b"k10", b"k50", b"z"],
+            &["out0", "out1", "out2", "out3"],
+        )
+        .unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(
+            res.iter().map(|o| o.num_entries).collect::<Vec<_>>(),
+            vec![20, 80, 100]
+        );
+        assert!(!storage.exists("out3"));
+
+        let (keys, tombstones) = read_table(&storage, "out0");
+        assert_eq!(keys.first().unwrap(), "k00");
+        assert_eq!(keys.last().unwrap(), "k09");
+        assert!(tombstones.is_empty());
+        let (keys, tombstones) = read_table(&storage, "out1");
+        assert_eq!(keys.first().unwrap(), "k10");
+        assert_eq!(keys.last().unwrap(), "k49");
+        assert_eq!(tombstones[0].begin, b"k45");
+        assert_eq!(tombstones[0].end, b"k50");
+        let (keys, tombstones) = read_table(&storage, "out2");
+        assert_eq!(keys.first().unwrap(), "k50");
+        assert_eq!(keys.last().unwrap(), "k99");
+        assert_eq!(tombstones[0].begin, b"k50");
+        assert_eq!(tombstones[0].end, b"k55");
+    }
+
+    #[test]
+    fn test_split_table_invalid_arguments() {
+        let storage = MemStorage::default();
+        build_input(&storage);
+        for (boundaries, outputs) in [
+            (vec![&b"k10"[..]], vec!["out0"]),
+            (vec![&b"k50"[..], &b"k10"[..]], vec!["out0", "out1", "out2"]),
+        ] {
+            assert!(matches!(
+                split_table(
+                    &storage,
+                    Options::<BytewiseComparator>::default(),
+                    "input",
+                    &boundaries,
+                    &outputs,
+                ),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+}
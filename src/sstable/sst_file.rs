@@ -0,0 +1,382 @@
+use crate::db::format::{
+    extract_user_key, parse_internal_key, InternalKey, InternalKeyComparator, ParsedInternalKey,
+    ValueType, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK,
+};
+use crate::db::range_del::RangeTombstone;
+use crate::iterator::Iterator;
+use crate::options::{Options, ReadOptions};
+use crate::sstable::properties::TableProperties;
+use crate::sstable::table::{new_table_iterator, Table, TableBuilder, TableIterator};
+use crate::storage::{File, Storage};
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A table written by `SstFileWriter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalSstFileInfo {
+    pub path: PathBuf,
+    /// The first user key of the entries. Empty if the table has no entry.
+    pub smallest_key: Vec<u8>,
+    /// The last user key of the entries. Empty if the table has no entry.
+    pub largest_key: Vec<u8>,
+    pub file_size: u64,
+    pub num_entries: usize,
+    pub num_range_tombstones: usize,
+}
+
+/// `SstFileWriter` builds an sst file outside a db, which can be ingested by
+/// `WickDB::ingest_external_file`.
+///
+/// The user keys are written as the internal keys with sequence number 0, and
+/// must be added in strictly increasing order by the comparator in `options`.
+/// The table is built with the block size, compression, checksum, filter policy
+/// and properties collectors in `options` as the tables of a db are, so the
+/// options should be the same as the db ingesting it.
+pub struct SstFileWriter<F: File, C: Comparator> {
+    builder: TableBuilder<InternalKeyComparator<C>, F>,
+    ucmp: C,
+    path: PathBuf,
+    smallest_key: Vec<u8>,
+    largest_key: Vec<u8>,
+}
+
+impl<F: File, C: Comparator + 'static> SstFileWriter<F, C> {
+    /// Creates the file at `path` in `storage` to write the table into. An
+    /// existing file is truncated.
+    pub fn create<S: Storage<F = F>, P: AsRef<Path>>(
+        storage: &S,
+        options: Options<C>,
+        path: P,
+    ) -> Result<Self> {
+        let ucmp = options.comparator.clone();
+        let file = storage.create(path.as_ref())?;
+        let builder = TableBuilder::new(
+            file,
+            InternalKeyComparator::new(ucmp.clone()),
+            &Arc::new(options),
+        );
+        Ok(Self {
+            builder,
+            ucmp,
+            path: path.as_ref().to_path_buf(),
+            smallest_key: vec![],
+            largest_key: vec![],
+        })
+    }
+
+    /// Adds a key/value pair
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.add(key, ValueType::Value, value)
+    }
+
+    /// Adds a merge operand, which is merged into the value of the key in the
+    /// db by the `MergeOperator`
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.add(key, ValueType::Merge, operand)
+    }
+
+    /// Adds a deletion of the key
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.add(key, ValueType::Deletion, &[])
+    }
+
+    /// Adds a range tombstone deleting the user keys in `[begin, end)`. Unlike
+    /// the point entries, the tombstones could be added in any order.
+    pub fn delete_range(&mut self, begin: &[u8], end: &[u8]) -> Result<()> {
+        if self.ucmp.compare(begin, end) != Ordering::Less {
+            return Err(Error::InvalidArgument(
+                "the begin of a range deletion must be less than the end".to_owned(),
+            ));
+        }
+        self.builder
+            .add_range_tombstone(RangeTombstone::new(begin, end, 0));
+        Ok(())
+    }
+
+    fn add(&mut self, key: &[u8], value_type: ValueType, value: &[u8]) -> Result<()> {
+        if self.builder.num_entries() > 0 {
+            if self.ucmp.compare(key, &self.largest_key) != Ordering::Greater {
+                return Err(Error::InvalidArgument(format!(
+                    "key {:?} is not greater than the last key {:?}",
+                    key, self.largest_key
+                )));
+            }
+        } else {
+            self.smallest_key = key.to_vec();
+        }
+        self.builder
+            .add(InternalKey::new(key, 0, value_type).data(), value)?;
+        self.largest_key.clear();
+        self.largest_key.extend_from_slice(key);
+        Ok(())
+    }
+
+    /// Returns the number of the entries added so far
+    #[inline]
+    pub fn num_entries(&self) -> usize {
+        self.builder.num_entries()
+    }
+
+    /// Returns the size of the table written so far
+    #[inline]
+    pub fn file_size(&self) -> u64 {
+        self.builder.file_size()
+    }
+
+    /// Finishes the table and syncs the file.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::InvalidArgument` if nothing is added since an empty
+    /// table can't be ingested.
+    pub fn finish(mut self) -> Result<ExternalSstFileInfo> {
+        if self.builder.num_entries() == 0 && self.builder.num_range_tombstones() == 0 {
+            self.builder.close();
+            return Err(Error::InvalidArgument(
+                "no entry is added into the sst file".to_owned(),
+            ));
+        }
+        self.builder.finish(true)?;
+        Ok(ExternalSstFileInfo {
+            path: self.path,
+            smallest_key: self.smallest_key,
+            largest_key: self.largest_key,
+            file_size: self.builder.file_size(),
+            num_entries: self.builder.num_entries(),
+            num_range_tombstones: self.builder.num_range_tombstones(),
+        })
+    }
+}
+
+/// `SstFileReader` reads an sst file outside a db, e.g. to validate a table
+/// written by `SstFileWriter` before ingesting it. The table is read with the
+/// filter policy in the given `options`.
+pub struct SstFileReader<F: File, C: Comparator> {
+    table: Arc<Table<F>>,
+    icmp: InternalKeyComparator<C>,
+}
+
+impl<F: File, C: Comparator + 'static> SstFileReader<F, C> {
+    /// Opens the table at `path` in `storage`
+    pub fn open<S: Storage<F = F>, P: AsRef<Path>>(
+        storage: &S,
+        options: Options<C>,
+        path: P,
+    ) -> Result<Self> {
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let file = storage.open(path.as_ref())?;
+        let file_size = file.len()?;
+        let table = Table::open_uncached(file, file_size, Arc::new(options), icmp.clone())?;
+        Ok(Self {
+            table: Arc::new(table),
+            icmp,
+        })
+    }
+
+    /// Returns the statistics recorded when the table is built, or `None` if
+    /// the table is written by an old version without the properties block
+    #[inline]
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.table.properties()
+    }
+
+    /// Returns the range tombstones of the table
+    #[inline]
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        self.table.range_tombstones()
+    }
+
+    /// Returns an iterator over the entries of the table, whose keys are the
+    /// user keys
+    pub fn iter(&self, options: ReadOptions) -> SstFileIterator<F, C> {
+        SstFileIterator {
+            inner: new_table_iterator(self.icmp.clone(), self.table.clone(), options),
+        }
+    }
+
+    /// Reads all the entries of the table and verifies the checksums of the
+    /// blocks and the order of the keys
+    pub fn verify_checksum(&self) -> Result<()> {
+        let options = ReadOptions {
+            verify_checksums: true,
+            fill_cache: false,
+            ..Default::default()
+        };
+        let mut iter = new_table_iterator(self.icmp.clone(), self.table.clone(), options);
+        let mut last_key: Vec<u8> = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            let parsed = parse_internal_key(key)?;
+            if !last_key.is_empty() && self.icmp.compare(&last_key, key) != Ordering::Less {
+                return Err(Error::Corruption(format!("key {} is out of order", parsed)));
+            }
+            last_key.clear();
+            last_key.extend_from_slice(key);
+            iter.next();
+        }
+        iter.status()
+    }
+}
+
+/// The iterator over the entries of an `SstFileReader`. The keys are the user
+/// keys and the targets of the seeks are user keys too.
+pub struct SstFileIterator<F: File, C: Comparator> {
+    inner: TableIterator<InternalKeyComparator<C>, F>,
+}
+
+impl<F: File, C: Comparator> SstFileIterator<F, C> {
+    /// Returns the type of the current entry
+    pub fn value_type(&self) -> ValueType {
+        ParsedInternalKey::decode_from(self.inner.key())
+            .map_or(ValueType::Unknown, |k| k.value_type)
+    }
+
+    /// Returns the sequence number of the current entry, which is the global
+    /// sequence number for an ingested table
+    pub fn sequence(&self) -> u64 {
+        ParsedInternalKey::decode_from(self.inner.key()).map_or(0, |k| k.seq)
+    }
+}
+
+impl<F: File, C: Comparator> Iterator for SstFileIterator<F, C> {
+    fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.inner.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.inner.seek_to_last()
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.inner
+            .seek(InternalKey::new(target, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK).data())
+    }
+
+    fn next(&mut self) {
+        self.inner.next()
+    }
+
+    fn prev(&mut self) {
+        self.inner.prev()
+    }
+
+    fn key(&self) -> &[u8] {
+        extract_user_key(self.inner.key())
+    }
+
+    fn value(&self) -> &[u8] {
+        self.inner.value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.inner.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::lru::LRUCache;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    #[test]
+    fn test_sst_file_writer_and_reader() {
+        let storage = MemStorage::default();
+        let options = Options::<BytewiseComparator>::default();
+        let mut writer = SstFileWriter::create(&storage, options.clone(), "a.sst").unwrap();
+        writer.put(b"k1", b"v1").unwrap();
+        writer.delete(b"k2").unwrap();
+        writer.merge(b"k3", b"o3").unwrap();
+        writer.delete_range(b"k5", b"k7").unwrap();
+        // The keys must be strictly increasing
+        assert!(matches!(
+            writer.put(b"k3", b"v3"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            writer.delete_range(b"k9", b"k8"),
+            Err(Error::InvalidArgument(_))
+        ));
+        writer.put(b"k4", b"v4").unwrap();
+        let info = writer.finish().unwrap();
+        assert_eq!(info.path, PathBuf::from("a.sst"));
+        assert_eq!(info.smallest_key, b"k1".to_vec());
+        assert_eq!(info.largest_key, b"k4".to_vec());
+        assert_eq!(info.num_entries, 4);
+        assert_eq!(info.num_range_tombstones, 1);
+        assert_eq!(
+            info.file_size,
+            storage.open("a.sst").unwrap().len().unwrap()
+        );
+
+        let reader = SstFileReader::open(&storage, options.clone(), "a.sst").unwrap();
+        reader.verify_checksum().unwrap();
+        let props = reader.properties().unwrap();
+        assert_eq!(props.num_entries, 4);
+        assert_eq!(props.num_deletions, 1);
+        assert_eq!(props.global_seqno, 0);
+        assert_eq!(
+            reader.range_tombstones(),
+            &[RangeTombstone::new(b"k5", b"k7", 0)]
+        );
+        let mut iter = reader.iter(ReadOptions::default());
+        iter.seek_to_first();
+        let mut entries = vec![];
+        while iter.valid() {
+            assert_eq!(iter.sequence(), 0);
+            entries.push((
+                iter.key().to_vec(),
+                iter.value_type(),
+                iter.value().to_vec(),
+            ));
+            iter.next();
+        }
+        assert_eq!(
+            entries,
+            vec![
+                (b"k1".to_vec(), ValueType::Value, b"v1".to_vec()),
+                (b"k2".to_vec(), ValueType::Deletion, vec![]),
+                (b"k3".to_vec(), ValueType::Merge, b"o3".to_vec()),
+                (b"k4".to_vec(), ValueType::Value, b"v4".to_vec()),
+            ]
+        );
+        iter.seek(b"k3");
+        assert_eq!(iter.key(), b"k3");
+        iter.seek(b"k35");
+        assert_eq!(iter.key(), b"k4");
+
+        // An empty table can't be ingested
+        let writer = SstFileWriter::create(&storage, options.clone(), "empty.sst").unwrap();
+        assert!(matches!(writer.finish(), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_sst_file_readers_sharing_block_cache() {
+        let storage = MemStorage::default();
+        let options = Options::<BytewiseComparator> {
+            block_cache: Some(Arc::new(LRUCache::new(1 << 20))),
+            ..Default::default()
+        };
+        for (path, value) in [("a.sst", b"va"), ("b.sst", b"vb")] {
+            let mut writer = SstFileWriter::create(&storage, options.clone(), path).unwrap();
+            writer.put(b"k", value).unwrap();
+            writer.finish().unwrap();
+        }
+        let a = SstFileReader::open(&storage, options.clone(), "a.sst").unwrap();
+        let b = SstFileReader::open(&storage, options, "b.sst").unwrap();
+        for (reader, value) in [(&a, b"va"), (&b, b"vb"), (&a, b"va")] {
+            let mut iter = reader.iter(ReadOptions::default());
+            iter.seek_to_first();
+            assert_eq!(iter.value(), value);
+        }
+    }
+}
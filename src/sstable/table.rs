@@ -1,18 +1,44 @@
 use crate::cache::Cache;
-use crate::filter::FilterPolicy;
+use crate::db::format::{ParsedInternalKey, ValueType, INTERNAL_KEY_COMPARATOR_NAME};
+use crate::db::range_del::{decode_range_tombstones, encode_range_tombstones, RangeTombstone};
+use crate::db::read_stats::ReadCounters;
+use crate::filter::table_filter_policies;
 use crate::iterator::{ConcatenateIterator, DerivedIterFactory, Iterator};
-use crate::options::{CompressionType, Options, ReadOptions};
+use crate::mem::arena::BlockCacheAllocator;
+use crate::options::{ChecksumType, CompressionType, IndexType, Options, ReadOptions};
 use crate::sstable::block::{Block, BlockBuilder, BlockIterator};
 use crate::sstable::filter_block::{FilterBlockBuilder, FilterBlockReader};
-use crate::sstable::{BlockHandle, Footer, BLOCK_TRAILER_SIZE, FOOTER_ENCODED_LENGTH};
-use crate::storage::File;
-use crate::util::coding::{decode_fixed_32, put_fixed_32, put_fixed_64};
+use crate::sstable::properties::{TableProperties, TablePropertiesCollector, PROPERTIES_BLOCK_KEY};
+use crate::sstable::{
+    BlockHandle, Footer, BLOCK_TRAILER_SIZE, FOOTER_ENCODED_LENGTH, FOOTER_ENCODED_LENGTH_V1,
+};
+use crate::storage::{File, IoPriority};
+use crate::util::buffer_pool::PooledBuffer;
+use crate::util::coding::{decode_fixed_32, encode_fixed_32, put_fixed_64};
 use crate::util::comparator::Comparator;
-use crate::util::crc32::{extend, hash, mask, unmask};
+use crate::util::crc32::{extend, hash, mask};
+use crate::util::crc32c;
 use crate::{Error, Result};
+#[cfg(feature = "lz4")]
+use lz4::block::CompressionMode;
 use snap::raw::max_compress_len;
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::Xxh3;
+use xxhash_rust::xxh64::Xxh64;
+
+// The size of the chunks a `TableCopy` copies the blocks in
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/// The key of the range deletion block in the meta index block
+pub const RANGE_DEL_BLOCK_KEY: &str = "wickdb.range_del";
+
+// The key of the index type in the meta block. Its value is a single byte of
+// `IndexType` and it only exists in the tables with a partitioned index.
+pub(crate) const INDEX_TYPE_KEY: &str = "wickdb.index_type";
 
 /// A `Table` is a sorted map from strings to strings, which must be immutable and persistent.
 /// A `Table` may be safely accessed from multiple threads
@@ -21,10 +47,18 @@ use std::sync::Arc;
 pub struct Table<F: File> {
     file: F, // sstable 对应的磁盘文件
     file_number: u64,
-    filter_reader: Option<FilterBlockReader>,  // 过滤器块
+    filter_readers: Vec<FilterBlockReader>,  // 过滤器块，每个过滤策略一个
     meta_block_handle: Option<BlockHandle>,
     index_block: Block,  // 索引块 逻辑意义上是插入在 sst 文件各个 dataBlock 之间的记录桩点: 需要保证大于等于前一个 dataBlock 中的最大 key，小于后一个 dataBlock 中的最小 key
+    partitioned_index: bool, // 为 true 时 index_block 是顶层索引块，其中每个条目指向一个索引分区
+    range_tombstones: Vec<RangeTombstone>, // 范围删除块中的墓碑
+    checksum: ChecksumType, // footer 中记录的块校验和算法
+    properties: Option<TableProperties>, // 属性块中的统计信息，旧版本的文件没有属性块
+    degraded: bool,     // 元索引块或过滤器块损坏，只能通过索引块和数据块读取
+    global_seqno: u64,  // 导入的文件读取时用于替换所有 key 的序列号，为 0 时不替换
     block_cache: Option<Arc<dyn Cache<Vec<u8>, Arc<Block>>>>,
+    // 复制块缓存中的块到大页的分配器
+    block_cache_allocator: Option<Arc<BlockCacheAllocator>>,
 }
 
 impl<F: File> Table<F> {
@@ -39,57 +73,158 @@ impl<F: File> Table<F> {
         file_len: u64,
         options: Arc<Options<UC>>,
         cmp: TC,
+    ) -> Result<Self> {
+        let block_cache = options.block_cache.clone();
+        Self::open_with_cache(file, file_number, file_len, options, cmp, block_cache)
+    }
+
+    /// Opens a table outside of a db (e.g. an external sst file), which has no
+    /// unique file number to key its blocks in the block cache. So the blocks are
+    /// read without the block cache even if `options.block_cache` is set.
+    pub fn open_uncached<UC: Comparator, TC: Comparator>(
+        file: F,
+        file_len: u64,
+        options: Arc<Options<UC>>,
+        cmp: TC,
+    ) -> Result<Self> {
+        Self::open_with_cache(file, 0, file_len, options, cmp, None)
+    }
+
+    fn open_with_cache<UC: Comparator, TC: Comparator>(
+        file: F,
+        file_number: u64,
+        file_len: u64,
+        options: Arc<Options<UC>>,
+        cmp: TC,
+        block_cache: Option<Arc<dyn Cache<Vec<u8>, Arc<Block>>>>,
     ) -> Result<Self> {
         if file_len < FOOTER_ENCODED_LENGTH as u64 {
             return Err(Error::Corruption(
                 "file is too short to be an sstable".to_owned(),
             ));
         };
-        // Read footer
-        let mut footer_space = vec![0; FOOTER_ENCODED_LENGTH];
-        file.read_exact_at(
-            footer_space.as_mut_slice(),
-            file_len - FOOTER_ENCODED_LENGTH as u64,
-        )?;
-        let (footer, _) = Footer::decode_from(footer_space.as_slice())?;
+        // Read the tail of the file in one IO, which holds the footer and usually
+        // the index block and the meta blocks. The length of the footer depends on
+        // the format version so read the max length and let the magic number decide.
+        let footer_len = file_len.min(FOOTER_ENCODED_LENGTH_V1 as u64);
+        let tail_len = file_len.min(footer_len.max(options.table_open_prefetch_size as u64));
+        let mut tail = TailPrefetch {
+            offset: file_len - tail_len,
+            data: vec![0; tail_len as usize],
+        };
+        file.read_exact_at(tail.data.as_mut_slice(), tail.offset)?;
+        let (footer, _) = Footer::decode_from(&tail.data[(tail_len - footer_len) as usize..])?;
+        let checksum = footer.checksum;
+        let read_meta_block = |file: &F, handle: &BlockHandle| match tail.block(handle) {
+            Some(buffer) => decode_block(buffer.to_vec(), checksum, options.paranoid_checks),
+            None => read_block(
+                file,
+                handle,
+                checksum,
+                options.paranoid_checks,
+                IoPriority::Foreground,
+                false,
+            ),
+        };
         // Read the index block
-        let index_block_contents =
-            read_block(&file, &footer.index_handle, options.paranoid_checks)?;
+        let index_block_contents = read_meta_block(&file, &footer.index_handle)?;
         let index_block = Block::new(index_block_contents)?;
+        let block_cache_allocator = block_cache
+            .as_ref()
+            .and(options.block_cache_allocator.clone());
         let mut t = Self {
-            block_cache: options.block_cache.clone(),
+            block_cache,
+            block_cache_allocator,
             file,
             file_number,
-            filter_reader: None,
+            filter_readers: vec![],
             meta_block_handle: None,
             index_block,
+            partitioned_index: false,
+            range_tombstones: vec![],
+            checksum,
+            properties: None,
+            degraded: false,
+            global_seqno: 0,
         };
         // Read meta block
-        if footer.meta_index_handle.size > 0 && options.filter_policy.is_some() {
-            // ignore the reading errors since meta info is not needed for operation
-            if let Ok(meta_block_contents) =
-                read_block(&t.file, &footer.meta_index_handle, options.paranoid_checks)
-            {
-                if let Ok(meta_block) = Block::new(meta_block_contents) {
-                    t.meta_block_handle = Some(footer.meta_index_handle);
+        if footer.meta_index_handle.size > 0 {
+            // The errors of the meta index block and the filter blocks don't fail
+            // the opening since they're not needed for operation. The table is
+            // served from the index block and the data blocks in the degraded mode.
+            let meta_block =
+                read_meta_block(&t.file, &footer.meta_index_handle).and_then(Block::new);
+            match meta_block {
+                Err(e) => {
+                    warn!(
+                        "Table #{} is opened without the meta index block: {:?}",
+                        file_number, e
+                    );
+                    t.degraded = true;
+                }
+                Ok(meta_block) => {
                     let mut iter = meta_block.iter(cmp);
-                    let filter_key = if let Some(fp) = &options.filter_policy {
-                        "filter.".to_owned() + fp.name()
-                    } else {
-                        String::from("")
-                    };
                     // Read filter block
-                    iter.seek(filter_key.as_bytes());
-                    if iter.valid() && iter.key() == filter_key.as_bytes() {
-                        if let Ok((filter_handle, _)) = BlockHandle::decode_from(iter.value()) {
-                            if let Ok(filter_block) =
-                                read_block(&t.file, &filter_handle, options.paranoid_checks)
-                            {
-                                t.filter_reader = Some(FilterBlockReader::new(
-                                    options.filter_policy.clone().unwrap(),
-                                    filter_block,
-                                ));
+                    if let Some(policy) = &options.filter_policy {
+                        t.meta_block_handle = Some(footer.meta_index_handle);
+                        for fp in table_filter_policies(policy) {
+                            let filter_key = "filter.".to_owned() + fp.name();
+                            iter.seek(filter_key.as_bytes());
+                            if !iter.valid() || iter.key() != filter_key.as_bytes() {
+                                continue;
                             }
+                            let reader = BlockHandle::decode_from(iter.value()).and_then(
+                                |(filter_handle, _)| {
+                                    let filter_block = read_meta_block(&t.file, &filter_handle)?;
+                                    FilterBlockReader::new(fp.clone(), filter_block)
+                                },
+                            );
+                            match reader {
+                                Ok(reader) => t.filter_readers.push(reader),
+                                Err(e) => {
+                                    warn!(
+                                        "Table #{} is opened without the filter {}: {:?}",
+                                        file_number,
+                                        fp.name(),
+                                        e
+                                    );
+                                    t.degraded = true;
+                                }
+                            }
+                        }
+                    }
+                    iter.seek(INDEX_TYPE_KEY.as_bytes());
+                    if iter.valid() && iter.key() == INDEX_TYPE_KEY.as_bytes() {
+                        t.partitioned_index = match iter.value() {
+                            [0] => false,
+                            [1] => true,
+                            _ => return Err(Error::Corruption("unknown index type".to_owned())),
+                        };
+                    }
+                    // Read properties block. The errors are ignored like the filter
+                    // since the properties are only used as hints.
+                    iter.seek(PROPERTIES_BLOCK_KEY.as_bytes());
+                    if iter.valid() && iter.key() == PROPERTIES_BLOCK_KEY.as_bytes() {
+                        if let Ok((handle, _)) = BlockHandle::decode_from(iter.value()) {
+                            t.properties = read_meta_block(&t.file, &handle)
+                                .and_then(|contents| TableProperties::decode_from(&contents))
+                                .ok();
+                        }
+                    }
+                    // Read range deletion block. Unlike the filter, the tombstones are
+                    // required for correctness so the errors are returned.
+                    iter.seek(RANGE_DEL_BLOCK_KEY.as_bytes());
+                    if iter.valid() && iter.key() == RANGE_DEL_BLOCK_KEY.as_bytes() {
+                        let (handle, _) = BlockHandle::decode_from(iter.value())?;
+                        let contents = read_meta_block(&t.file, &handle)?;
+                        t.range_tombstones = decode_range_tombstones(&contents)?;
+                    }
+                    // The keys of an ingested table are written with sequence number 0
+                    // and get the global one assigned at the ingestion when read
+                    t.global_seqno = t.properties.as_ref().map_or(0, |p| p.global_seqno);
+                    if t.global_seqno != 0 {
+                        for tombstone in t.range_tombstones.iter_mut() {
+                            tombstone.seq = t.global_seqno;
                         }
                     }
                 }
@@ -98,52 +233,182 @@ impl<F: File> Table<F> {
         Ok(t)
     }
 
+    /// Returns the statistics recorded when the table is built, or `None` if
+    /// the table is written by an old version without the properties block
+    #[inline]
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.properties.as_ref()
+    }
+
+    /// Returns true if the meta index block or a filter block of the table is
+    /// corrupted. A degraded table returns the same results but every lookup
+    /// reads the data blocks.
+    #[inline]
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Returns the sequence number assigned to all the keys of an ingested table,
+    /// or 0 if the keys keep their own sequence numbers
+    #[inline]
+    pub fn global_seqno(&self) -> u64 {
+        self.global_seqno
+    }
+
+    /// Returns the range tombstones stored in the table
+    #[inline]
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    // Returns the key of the block at `offset` in the block cache
+    fn block_cache_key(&self, offset: u64) -> Vec<u8> {
+        let mut key = vec![0; 16];
+        put_fixed_64(&mut key, self.file_number);
+        put_fixed_64(&mut key, offset);
+        key
+    }
+
+    /// Reads the block identified by `handle` into the block cache unless it's
+    /// already cached. Returns false if the table has no block cache.
+    pub(crate) fn preload_block(&self, handle: &BlockHandle) -> Result<bool> {
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => return Ok(false),
+        };
+        let key = self.block_cache_key(handle.offset);
+        if cache.get(&key).is_none() {
+            let data = read_block(
+                &self.file,
+                handle,
+                self.checksum,
+                true,
+                IoPriority::Background,
+                false,
+            )?;
+            let charge = data.len();
+            cache.insert(key, Arc::new(self.new_cached_block(data)?), charge);
+        }
+        Ok(true)
+    }
+
+    // Creates a block to be put into the block cache, whose contents are copied
+    // into the huge pages if `Options::block_cache_allocator` is set
+    fn new_cached_block(&self, data: Vec<u8>) -> Result<Block> {
+        match &self.block_cache_allocator {
+            Some(allocator) => Block::new_in(data, allocator),
+            None => Block::new(data),
+        }
+    }
+
     // Converts an BlockHandle into an iterator over the contents of the corresponding block.
+    // Whether the block is found in the block cache is recorded into `stats` if given.
     fn block_reader<CC: Comparator>(
         &self,
         cmp: CC,
         data_block_handle: BlockHandle,
         options: ReadOptions,
+        stats: Option<&ReadCounters>,
     ) -> Result<BlockIterator<CC>> {
+        trace_span!(
+            "wickdb.data_block",
+            file = self.file_number,
+            offset = data_block_handle.offset
+        );
+        if let Some(deadline) = options.deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::TimedOut(format!(
+                    "read deadline exceeded before reading block {} of table #{}",
+                    data_block_handle.offset, self.file_number
+                )));
+            }
+        }
         let iter = if let Some(cache) = &self.block_cache {
-            let mut cache_key_buffer = vec![0; 16];
-            put_fixed_64(&mut cache_key_buffer, self.file_number);
-            put_fixed_64(&mut cache_key_buffer, data_block_handle.offset);
+            let cache_key_buffer = self.block_cache_key(data_block_handle.offset);
+            if let Some(stats) = stats {
+                stats.sample_block(self.file_number, &data_block_handle);
+            }
             if let Some(b) = cache.get(&cache_key_buffer) {
+                if let Some(stats) = stats {
+                    stats.record_block_read(true);
+                }
                 b.iter(cmp)
             } else {
-                let data = read_block(&self.file, &data_block_handle, options.verify_checksums)?;
+                if let Some(stats) = stats {
+                    stats.record_block_read(false);
+                }
+                let data = read_block(
+                    &self.file,
+                    &data_block_handle,
+                    self.checksum,
+                    options.verify_checksums,
+                    options.io_priority,
+                    options.drop_page_cache,
+                )?;
                 let charge = data.len();
-                let new_block = Block::new(data)?;
-                let b = Arc::new(new_block);
-                let iter = b.iter(cmp);
                 if options.fill_cache {
+                    let b = Arc::new(self.new_cached_block(data)?);
+                    let iter = b.iter(cmp);
                     cache.insert(cache_key_buffer, b, charge);
+                    iter
+                } else {
+                    Block::new(data)?.iter(cmp)
                 }
-                iter
             }
         } else {
-            let data = read_block(&self.file, &data_block_handle, options.verify_checksums)?;
+            if let Some(stats) = stats {
+                stats.record_block_read(false);
+            }
+            let data = read_block(
+                &self.file,
+                &data_block_handle,
+                self.checksum,
+                options.verify_checksums,
+                options.io_priority,
+                options.drop_page_cache,
+            )?;
             let b = Block::new(data)?;
             b.iter(cmp)
         };
         Ok(iter)
     }
 
+    // Returns the iterator of the index block (or the index partition) positioned
+    // at the first index entry whose key is equal or greater than `key`
+    fn seek_index<TC: Comparator>(
+        &self,
+        cmp: TC,
+        options: ReadOptions,
+        key: &[u8],
+        stats: Option<&ReadCounters>,
+    ) -> Result<BlockIterator<TC>> {
+        let mut index_iter = self.index_block.iter(cmp.clone());
+        index_iter.seek(key);
+        if self.partitioned_index && index_iter.valid() {
+            // The key of a partition in the top-level index is the last key in the partition
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+            let mut partition_iter = self.block_reader(cmp, handle, options, stats)?;
+            partition_iter.seek(key);
+            return Ok(partition_iter);
+        }
+        Ok(index_iter)
+    }
+
     /// Finds the first entry with the key equal or greater than target and
     /// returns the block iterator direclty
     ///
     /// The given `key` is an internal key so the `cmp` must be a InternalKeyComparator
     ///
-    pub fn internal_get<TC: Comparator>(
+    /// The block cache hits and misses of the lookup are recorded into `stats` if given.
+    pub(crate) fn internal_get<TC: Comparator>(
         &self,
         options: ReadOptions,
         cmp: TC,
         key: &[u8],
+        stats: Option<&ReadCounters>,
     ) -> Result<Option<BlockIterator<TC>>> {
-        let mut index_iter = self.index_block.iter(cmp.clone());
         // seek to the first 'last key' bigger than 'key'
-        index_iter.seek(key);
+        let mut index_iter = self.seek_index(cmp.clone(), options, key, stats)?;
         if index_iter.valid() {
             // It's called 'maybe_contained' not only because the filter policy may report the falsy result,
             // but also even if we've found a block with the last key bigger than the target
@@ -151,17 +416,20 @@ impl<F: File> Table<F> {
             let mut maybe_contained = true;
 
             let handle_val = index_iter.value();
-            // check the filter block
-            if let Some(filter) = &self.filter_reader {
+            // check the filter blocks
+            if !self.filter_readers.is_empty() {
                 if let Ok((handle, _)) = BlockHandle::decode_from(handle_val) {
-                    if !filter.key_may_match(handle.offset, key) {
-                        maybe_contained = false;
-                    }
+                    maybe_contained = self
+                        .filter_readers
+                        .iter()
+                        .all(|filter| filter.key_may_match(handle.offset, key));
                 }
             }
             if maybe_contained {
                 let (data_block_handle, _) = BlockHandle::decode_from(handle_val)?;
-                let mut block_iter = self.block_reader(cmp, data_block_handle, options)?;
+                let mut block_iter = self
+                    .block_reader(cmp, data_block_handle, options, stats)?
+                    .with_global_seqno(self.global_seqno);
                 block_iter.seek(key);
                 if block_iter.valid() {
                     return Ok(Some(block_iter));
@@ -177,6 +445,76 @@ impl<F: File> Table<F> {
         Ok(None)
     }
 
+    /// Returns whether the table may contain each of the given `keys` by the
+    /// filters, without reading any data block. The keys falling into the same
+    /// data block are probed against its filter at once.
+    ///
+    /// The given `keys` are internal keys so the `cmp` must be a InternalKeyComparator
+    pub(crate) fn keys_may_match<TC: Comparator>(
+        &self,
+        options: ReadOptions,
+        cmp: TC,
+        keys: &[&[u8]],
+    ) -> Result<Vec<bool>> {
+        let mut result = vec![true; keys.len()];
+        // data block offset -> the indexes of the keys in the block
+        let mut blocks: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let mut index_iter = self.seek_index(cmp.clone(), options, key, None)?;
+            if !index_iter.valid() {
+                index_iter.status()?;
+                // The key is after the last key of the table
+                result[i] = false;
+                continue;
+            }
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+            blocks.entry(handle.offset).or_default().push(i);
+        }
+        for filter in self.filter_readers.iter() {
+            for (offset, indexes) in blocks.iter() {
+                let block_keys = indexes.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+                for (&i, may_match) in indexes
+                    .iter()
+                    .zip(filter.keys_may_match(*offset, &block_keys))
+                {
+                    result[i] &= may_match;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns whether the table may contain a key with the given `prefix`
+    /// at or after `target`, which is the probe of a prefix seek. Unlike
+    /// `internal_get`, the filter is probed with the prefix instead of the
+    /// whole key and no data block is read.
+    ///
+    /// The given `target` is an internal key starting with `prefix` so the
+    /// `cmp` must be a InternalKeyComparator
+    pub fn prefix_may_match<TC: Comparator>(
+        &self,
+        options: ReadOptions,
+        cmp: TC,
+        target: &[u8],
+        prefix: &[u8],
+    ) -> Result<bool> {
+        let mut index_iter = self.seek_index(cmp, options, target, None)?;
+        if !index_iter.valid() {
+            index_iter.status()?;
+            return Ok(false);
+        }
+        // Keys are sorted so the keys with the prefix at or after `target` must
+        // start in the block holding `target`
+        if !self.filter_readers.is_empty() {
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+            return Ok(self
+                .filter_readers
+                .iter()
+                .all(|filter| filter.prefix_may_match(handle.offset, prefix)));
+        }
+        Ok(true)
+    }
+
     /// Given a key, return an approximate byte offset in the file where
     /// the data for that key begins (or would begin if the key were
     /// present in the file).  The returned value is in terms of file
@@ -184,12 +522,12 @@ impl<F: File> Table<F> {
     /// E.g., the approximate offset of the last key in the table will
     /// be close to the file length.
     pub(crate) fn approximate_offset_of<TC: Comparator>(&self, cmp: TC, key: &[u8]) -> u64 {
-        let mut index_iter = self.index_block.iter(cmp);
-        index_iter.seek(key);
-        if index_iter.valid() {
-            let val = index_iter.value();
-            if let Ok((h, _)) = BlockHandle::decode_from(val) {
-                return h.offset;
+        if let Ok(index_iter) = self.seek_index(cmp, ReadOptions::default(), key, None) {
+            if index_iter.valid() {
+                let val = index_iter.value();
+                if let Ok((h, _)) = BlockHandle::decode_from(val) {
+                    return h.offset;
+                }
             }
         }
         if let Some(meta) = &self.meta_block_handle {
@@ -197,12 +535,199 @@ impl<F: File> Table<F> {
         }
         0
     }
+
+    /// Reads a data block picked by `pick`, bypassing the block cache, and verifies
+    /// its checksum and entries. `pick` is given the number of the candidates
+    /// (the index entries, then the entries of the picked index partition) and
+    /// returns the one to read. Returns the handle of the verified block, or
+    /// `None` if the table has no data blocks.
+    pub(crate) fn verify_data_block<TC: Comparator>(
+        &self,
+        cmp: TC,
+        pick: &mut dyn FnMut(usize) -> usize,
+    ) -> Result<Option<BlockHandle>> {
+        // Tells the corrupted block in the error
+        let locate = |e: Error, handle: &BlockHandle| match e {
+            Error::Corruption(hint) => Error::Corruption(format!(
+                "{} [block offset {} of table #{}]",
+                hint, handle.offset, self.file_number
+            )),
+            e => e,
+        };
+        let read = |handle: &BlockHandle| -> Result<Block> {
+            read_block(
+                &self.file,
+                handle,
+                self.checksum,
+                true,
+                IoPriority::Background,
+                false,
+            )
+            .and_then(Block::new)
+            .map_err(|e| locate(e, handle))
+        };
+        let mut handle = pick_block_handle(&mut self.index_block.iter(cmp.clone()), pick)?;
+        if self.partitioned_index {
+            handle = match handle {
+                Some(h) => pick_block_handle(&mut read(&h)?.iter(cmp.clone()), pick)?,
+                None => None,
+            };
+        }
+        if let Some(h) = &handle {
+            let mut iter = read(h)?.iter(cmp);
+            iter.seek_to_first();
+            while iter.valid() {
+                iter.next();
+            }
+            iter.status().map_err(|e| locate(e, h))?;
+        }
+        Ok(handle)
+    }
+
+    /// Reads all the data blocks bypassing the block cache and passes the entries
+    /// of the intact ones to `f` in order, skipping the blocks (and the index
+    /// partitions) failing the checksums. Returns the number of the skipped blocks.
+    /// It's used to salvage the entries of a corrupted table.
+    pub(crate) fn scan_blocks<TC: Comparator, V: FnMut(&[u8], &[u8]) -> Result<()>>(
+        &self,
+        cmp: TC,
+        mut f: V,
+    ) -> Result<usize> {
+        let read = |handle: &BlockHandle| -> Result<Option<Block>> {
+            match read_block(
+                &self.file,
+                handle,
+                self.checksum,
+                true,
+                IoPriority::Background,
+                false,
+            )
+            .and_then(Block::new)
+            {
+                Ok(block) => Ok(Some(block)),
+                Err(e @ Error::Corruption(_)) | Err(e @ Error::CompressionFailed(_)) => {
+                    warn!(
+                        "Skip block {} of table #{}: {}",
+                        handle.offset, self.file_number, e
+                    );
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            }
+        };
+        let mut skipped = 0;
+        let mut handles = vec![];
+        let mut index_iter = self.index_block.iter(cmp.clone());
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+            if !self.partitioned_index {
+                handles.push(handle);
+            } else if let Some(partition) = read(&handle)? {
+                let mut iter = partition.iter(cmp.clone());
+                iter.seek_to_first();
+                while iter.valid() {
+                    handles.push(BlockHandle::decode_from(iter.value())?.0);
+                    iter.next();
+                }
+                if iter.status().is_err() {
+                    skipped += 1;
+                }
+            } else {
+                skipped += 1;
+            }
+            index_iter.next();
+        }
+        index_iter.status()?;
+        for handle in handles.iter() {
+            let block = match read(handle)? {
+                Some(block) => block,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let mut iter = block.iter(cmp.clone()).with_global_seqno(self.global_seqno);
+            iter.seek_to_first();
+            while iter.valid() {
+                f(iter.key(), iter.value())?;
+                iter.next();
+            }
+            if iter.status().is_err() {
+                skipped += 1;
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Reads all the data blocks bypassing the block cache and passes their
+    /// decoded contents, which are decompressed and without the trailers, to `f`
+    /// in order.
+    pub(crate) fn for_each_data_block<TC: Comparator, V: FnMut(&[u8]) -> Result<()>>(
+        &self,
+        cmp: TC,
+        mut f: V,
+    ) -> Result<()> {
+        let read = |handle: &BlockHandle| {
+            read_block(
+                &self.file,
+                handle,
+                self.checksum,
+                true,
+                IoPriority::Background,
+                false,
+            )
+        };
+        let mut index_iter = self.index_block.iter(cmp.clone());
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+            if self.partitioned_index {
+                let mut iter = Block::new(read(&handle)?)?.iter(cmp.clone());
+                iter.seek_to_first();
+                while iter.valid() {
+                    f(&read(&BlockHandle::decode_from(iter.value())?.0)?)?;
+                    iter.next();
+                }
+                iter.status()?;
+            } else {
+                f(&read(&handle)?)?;
+            }
+            index_iter.next();
+        }
+        index_iter.status()
+    }
+}
+
+// Returns the block handle in the entry of the index `iter` picked by `pick`
+fn pick_block_handle(
+    iter: &mut dyn Iterator,
+    pick: &mut dyn FnMut(usize) -> usize,
+) -> Result<Option<BlockHandle>> {
+    let mut n = 0;
+    iter.seek_to_first();
+    while iter.valid() {
+        n += 1;
+        iter.next();
+    }
+    iter.status()?;
+    if n == 0 {
+        return Ok(None);
+    }
+    iter.seek_to_first();
+    for _ in 0..pick(n).min(n - 1) {
+        iter.next();
+    }
+    let (handle, _) = BlockHandle::decode_from(iter.value())?;
+    Ok(Some(handle))
 }
 
 pub struct TableIterFactory<C: Comparator, F: File> {
     options: ReadOptions,
     table: Arc<Table<F>>,
     cmp: C,
+    // Only applied to the data blocks
+    global_seqno: u64,
 }
 
 impl<C: Comparator, F: File> DerivedIterFactory for TableIterFactory<C, F> {
@@ -210,12 +735,67 @@ impl<C: Comparator, F: File> DerivedIterFactory for TableIterFactory<C, F> {
     fn derive(&self, value: &[u8]) -> Result<Self::Iter> {
         BlockHandle::decode_from(value).and_then(|(handle, _)| {
             self.table
-                .block_reader(self.cmp.clone(), handle, self.options)
+                .block_reader(self.cmp.clone(), handle, self.options, None)
+                .map(|iter| iter.with_global_seqno(self.global_seqno))
         })
     }
 }
 
-pub type TableIterator<C, F> = ConcatenateIterator<BlockIterator<C>, TableIterFactory<C, F>>;
+/// The iterator over the index entries of a table, whose values are the handles
+/// of the data blocks
+pub enum IndexIterator<C: Comparator, F: File> {
+    Single(BlockIterator<C>),
+    Partitioned(ConcatenateIterator<BlockIterator<C>, TableIterFactory<C, F>>),
+}
+
+macro_rules! dispatch_index_iter {
+    ($self:ident, $iter:ident => $e:expr) => {
+        match $self {
+            IndexIterator::Single($iter) => $e,
+            IndexIterator::Partitioned($iter) => $e,
+        }
+    };
+}
+
+impl<C: Comparator, F: File> Iterator for IndexIterator<C, F> {
+    fn valid(&self) -> bool {
+        dispatch_index_iter!(self, iter => iter.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        dispatch_index_iter!(self, iter => iter.seek_to_first())
+    }
+
+    fn seek_to_last(&mut self) {
+        dispatch_index_iter!(self, iter => iter.seek_to_last())
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        dispatch_index_iter!(self, iter => iter.seek(target))
+    }
+
+    fn next(&mut self) {
+        dispatch_index_iter!(self, iter => iter.next())
+    }
+
+    fn prev(&mut self) {
+        dispatch_index_iter!(self, iter => iter.prev())
+    }
+
+    fn key(&self) -> &[u8] {
+        dispatch_index_iter!(self, iter => iter.key())
+    }
+
+    fn value(&self) -> &[u8] {
+        dispatch_index_iter!(self, iter => iter.value())
+    }
+
+    fn status(&mut self) -> Result<()> {
+        dispatch_index_iter!(self, iter => iter.status())
+    }
+}
+
+pub type TableIterator<C, F> = ConcatenateIterator<IndexIterator<C, F>, TableIterFactory<C, F>>;
 
 /// Create a new `ConcatenateIterator` as table iterator.
 /// This iterator is able to yield all the key/values in the given `table` file
@@ -228,9 +808,23 @@ pub fn new_table_iterator<C: Comparator, F: File>(
     table: Arc<Table<F>>,
     options: ReadOptions,
 ) -> TableIterator<C, F> {
-    let index_iter = table.index_block.iter(cmp.clone());
+    let top_level_iter = table.index_block.iter(cmp.clone());
+    let index_iter = if table.partitioned_index {
+        // The index partitions are derived from the top-level index the same
+        // way as the data blocks from the index
+        let factory = TableIterFactory {
+            options,
+            table: table.clone(),
+            cmp: cmp.clone(),
+            global_seqno: 0,
+        };
+        IndexIterator::Partitioned(ConcatenateIterator::new(top_level_iter, factory))
+    } else {
+        IndexIterator::Single(top_level_iter)
+    };
     let factory = TableIterFactory {
         options,
+        global_seqno: table.global_seqno,
         table,
         cmp,
     };
@@ -243,7 +837,7 @@ pub fn new_table_iterator<C: Comparator, F: File>(
 pub struct TableBuilder<C: Comparator, F: File> {
     cmp: C,
     // underlying sst file
-    file: F,
+    file: BufferedFile<F>,
     // the written data length
     // updated only after the pending_handle is stored in the index block
     offset: u64,
@@ -255,7 +849,8 @@ pub struct TableBuilder<C: Comparator, F: File> {
     // number of key/value pairs in the file
     num_entries: usize,
     closed: bool,
-    filter_block: Option<FilterBlockBuilder>,
+    // One filter block for each of the filter policies
+    filter_blocks: Vec<FilterBlockBuilder>,
     // Indicates whether we have to add a index to index_block
     //
     // We do not emit the index entry for a block until we have seen the
@@ -264,30 +859,54 @@ pub struct TableBuilder<C: Comparator, F: File> {
     pending_index_entry: bool,
     // handle for current block to add to index block
     pending_handle: BlockHandle,
+    // The scratch buffer of the encoded `pending_handle`
+    handle_encoding: Vec<u8>,
+    // The scratch buffer of the compressed blocks taken from the buffer pool
+    compressed: PooledBuffer,
+    // range tombstones to be written into the range deletion block
+    range_tombstones: Vec<RangeTombstone>,
+    // The finished index partitions (the last key, the block contents), which
+    // are written before the top-level index when finishing the table
+    index_partitions: Vec<(Vec<u8>, Vec<u8>)>,
+    // The last key added into the current index partition
+    last_index_key: Vec<u8>,
+    // The statistics written into the properties block
+    properties: TableProperties,
+    // Whether the keys are internal keys, in which case the deletions are counted
+    internal_keys: bool,
+    // The collectors of the user defined properties
+    collectors: Vec<Box<dyn TablePropertiesCollector>>,
+    // The priority hint of all the writes
+    io_priority: IoPriority,
 
     // Fields from `Options`
     block_size: usize,
     block_restart_interval: usize,
     compression: CompressionType,
-    filter_policy: Option<Arc<dyn FilterPolicy>>,
+    checksum: ChecksumType,
+    index_type: IndexType,
+    zstd_compression_level: i32,
+    lz4hc_compression_level: i32,
 }
 
 impl<C: Comparator, F: File> TableBuilder<C, F> {
     pub fn new<UC: Comparator>(file: F, cmp: C, options: &Arc<Options<UC>>) -> Self {
         let opt = options.clone();
+        let internal_keys = cmp.name() == INTERNAL_KEY_COMPARATOR_NAME;
         let db_builder = BlockBuilder::new(options.block_restart_interval, cmp.clone());
         let ib_builder = BlockBuilder::new(options.block_restart_interval, cmp.clone());
-        let fb = {
-            if let Some(policy) = opt.filter_policy.clone() {
-                let mut f = FilterBlockBuilder::new(policy.clone());
+        let fb = opt
+            .filter_policy
+            .iter()
+            .flat_map(table_filter_policies)
+            .map(|policy| {
+                let mut f = FilterBlockBuilder::new(policy);
                 f.start_block(0);
-                Some(f)
-            } else {
-                None
-            }
-        };
+                f
+            })
+            .collect();
         Self {
-            file,
+            file: BufferedFile::new(file, opt.table_write_buffer_size),
             cmp,
             offset: 0,
             data_block: db_builder,
@@ -295,13 +914,50 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             last_key: vec![],
             num_entries: 0,
             closed: false,
-            filter_block: fb,
+            filter_blocks: fb,
             pending_index_entry: false,
             pending_handle: BlockHandle::new(0, 0),
+            handle_encoding: vec![],
+            compressed: PooledBuffer::new(),
+            range_tombstones: vec![],
+            index_partitions: vec![],
+            last_index_key: vec![],
+            properties: TableProperties::default(),
+            internal_keys,
+            collectors: opt
+                .table_properties_collector_factories
+                .iter()
+                .map(|f| f.create())
+                .collect(),
+            io_priority: IoPriority::Foreground,
             compression: opt.compression,
+            checksum: opt.checksum,
+            index_type: opt.index_type,
+            zstd_compression_level: opt.zstd_compression_level,
+            lz4hc_compression_level: opt.lz4hc_compression_level,
             block_size: opt.block_size,
             block_restart_interval: opt.block_restart_interval,
-            filter_policy: opt.filter_policy.clone(),
+        }
+    }
+
+    /// Overrides the compression of the blocks written later, which is
+    /// `Options::compression` by default
+    pub fn set_compression(&mut self, compression: CompressionType) {
+        self.compression = compression;
+    }
+
+    /// Sets the priority hint of the writes to the file, which is
+    /// `IoPriority::Foreground` by default
+    pub fn set_io_priority(&mut self, priority: IoPriority) {
+        self.io_priority = priority;
+    }
+
+    // The level of the configured compression algorithm if it supports any
+    fn compression_level(&self) -> i32 {
+        match self.compression {
+            CompressionType::ZstdCompression => self.zstd_compression_level,
+            CompressionType::Lz4hcCompression => self.lz4hc_compression_level,
+            _ => 0,
         }
     }
 
@@ -326,9 +982,28 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         // Check whether we need to create a new index entry
         self.maybe_append_index_block(Some(key));
         // Update filter block
-        if let Some(fb) = self.filter_block.as_mut() {
+        for fb in self.filter_blocks.iter_mut() {
             fb.add_key(key)
         }
+        if self.num_entries == 0 {
+            self.properties.smallest_key = key.to_vec();
+        }
+        self.properties.raw_key_size += key.len() as u64;
+        self.properties.raw_value_size += value.len() as u64;
+        if self.internal_keys {
+            if let Some(k) = ParsedInternalKey::decode_from(key) {
+                if k.value_type == ValueType::Deletion {
+                    self.properties.num_deletions += 1;
+                }
+                for c in self.collectors.iter_mut() {
+                    c.add(k.user_key, value);
+                }
+            }
+        } else {
+            for c in self.collectors.iter_mut() {
+                c.add(key, value);
+            }
+        }
         // TODO: avoid the copy
         self.last_key.resize(key.len(), 0);
         self.last_key.copy_from_slice(key);
@@ -343,6 +1018,24 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         Ok(())
     }
 
+    /// Adds a range tombstone to the table being constructed. The tombstones are
+    /// stored in the range deletion block in the order they are added.
+    ///
+    /// # Panics
+    ///
+    /// * TableBuilder is closed
+    ///
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.assert_not_closed();
+        self.range_tombstones.push(tombstone);
+    }
+
+    /// Returns the number of range tombstones added so far.
+    #[inline]
+    pub fn num_range_tombstones(&self) -> usize {
+        self.range_tombstones.len()
+    }
+
     /// Flushes any buffered key/value pairs to file.
     /// Can be used to ensure that two adjacent entries never live in
     /// the same data block. Most clients should not need to use this method.
@@ -355,19 +1048,23 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         self.assert_not_closed();
         if !self.data_block.is_empty() {
             assert!(!self.pending_index_entry, "[table builder] the index for the previous data block should never remain when flushing current block data");
+            let level = self.compression_level();
             let data_block = self.data_block.finish();
-            let (compressed, compression) = compress_block(data_block, self.compression)?;
+            let (compressed, compression) =
+                compress_block(data_block, self.compression, level, &mut self.compressed)?;
             write_raw_block(
                 &mut self.file,
-                compressed.as_slice(),
+                compressed,
                 compression,
+                self.checksum,
                 &mut self.pending_handle,
                 &mut self.offset,
+                self.io_priority,
             )?;
             self.data_block.reset();
             self.pending_index_entry = true;
-            self.file.flush()?;
-            if let Some(fb) = &mut self.filter_block {
+            self.file.end_block()?;
+            for fb in self.filter_blocks.iter_mut() {
                 fb.start_block(self.offset)
             }
         }
@@ -385,58 +1082,133 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         self.flush()?;
         self.assert_not_closed();
         self.closed = true;
-        // write filter block
-        let mut filter_block_handler = BlockHandle::new(0, 0);
-        let mut has_filter_block = false;
-        if let Some(fb) = &mut self.filter_block {
+        self.properties.data_size = self.offset;
+        // write filter blocks
+        let mut filter_block_handles = Vec::with_capacity(self.filter_blocks.len());
+        for fb in self.filter_blocks.iter_mut() {
+            let mut handle = BlockHandle::new(0, 0);
             let data = fb.finish();
             write_raw_block(
                 &mut self.file,
                 data,
                 CompressionType::NoCompression,
-                &mut filter_block_handler,
+                self.checksum,
+                &mut handle,
                 &mut self.offset,
+                self.io_priority,
             )?;
-            has_filter_block = true;
+            filter_block_handles.push(("filter.".to_owned() + fb.policy_name(), handle));
         }
+        self.properties.filter_size = self.offset - self.properties.data_size;
 
-        // write meta block
-        let mut meta_block_handle = BlockHandle::new(0, 0);
-        let mut meta_block_builder =
-            BlockBuilder::new(self.block_restart_interval, self.cmp.clone());
-        let meta_block = {
-            if has_filter_block {
-                let filter_key = if let Some(fp) = &self.filter_policy {
-                    "filter.".to_owned() + fp.name()
-                } else {
-                    String::from("")
-                };
-                meta_block_builder.add(filter_key.as_bytes(), &filter_block_handler.encoded());
-            }
-            meta_block_builder.finish()
-        };
-        self.write_block(meta_block, &mut meta_block_handle)?;
+        // write range deletion block
+        let mut range_del_block_handle = BlockHandle::new(0, 0);
+        let has_range_del_block = !self.range_tombstones.is_empty();
+        if has_range_del_block {
+            let data = encode_range_tombstones(&self.range_tombstones);
+            write_raw_block(
+                &mut self.file,
+                &data,
+                CompressionType::NoCompression,
+                self.checksum,
+                &mut range_del_block_handle,
+                &mut self.offset,
+                self.io_priority,
+            )?;
+        }
 
-        // Write index block
+        // Write index block. It's written before the meta block so that its
+        // size is known by the properties.
+        let index_start = self.offset;
         self.maybe_append_index_block(None); // flush the last index first
+        if self.index_type == IndexType::TwoLevelIndexSearch {
+            // Write the index partitions and build the top-level index
+            if !self.index_block.is_empty() {
+                self.cut_index_partition();
+            }
+            for (last_key, partition) in std::mem::take(&mut self.index_partitions) {
+                let mut handle = BlockHandle::new(0, 0);
+                self.write_block(&partition, &mut handle)?;
+                self.index_block.add(&last_key, &handle.encoded());
+            }
+        }
+        let level = self.compression_level();
         let index_block = self.index_block.finish();
         let mut index_block_handle = BlockHandle::new(0, 0);
-        let (c_index_block, ct) = compress_block(index_block, self.compression)?;
+        let (c_index_block, ct) =
+            compress_block(index_block, self.compression, level, &mut self.compressed)?;
         write_raw_block(
             &mut self.file,
-            c_index_block.as_slice(),
+            c_index_block,
             ct,
+            self.checksum,
             &mut index_block_handle,
             &mut self.offset,
+            self.io_priority,
         )?;
         self.index_block.reset();
+        self.properties.index_size = self.offset - index_start;
+
+        // write properties block
+        self.properties.num_entries = self.num_entries as u64;
+        if self.num_entries > 0 {
+            self.properties.largest_key = self.last_key.clone();
+        }
+        for c in self.collectors.iter_mut() {
+            self.properties.user_collected.extend(c.finish());
+        }
+        self.properties.creation_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut properties_block_handle = BlockHandle::new(0, 0);
+        write_raw_block(
+            &mut self.file,
+            &self.properties.encode(),
+            CompressionType::NoCompression,
+            self.checksum,
+            &mut properties_block_handle,
+            &mut self.offset,
+            self.io_priority,
+        )?;
+
+        // write meta block
+        let mut meta_block_handle = BlockHandle::new(0, 0);
+        let mut meta_block_builder =
+            BlockBuilder::new(self.block_restart_interval, self.cmp.clone());
+        let meta_block = {
+            let mut entries = vec![(
+                PROPERTIES_BLOCK_KEY.to_owned(),
+                properties_block_handle.encoded(),
+            )];
+            for (key, handle) in filter_block_handles {
+                entries.push((key, handle.encoded()));
+            }
+            if has_range_del_block {
+                entries.push((
+                    RANGE_DEL_BLOCK_KEY.to_owned(),
+                    range_del_block_handle.encoded(),
+                ));
+            }
+            if self.index_type == IndexType::TwoLevelIndexSearch {
+                entries.push((INDEX_TYPE_KEY.to_owned(), vec![self.index_type as u8]));
+            }
+            // the keys in a block must be sorted by the comparator
+            entries.sort_by(|(a, _), (b, _)| self.cmp.compare(a.as_bytes(), b.as_bytes()));
+            for (key, value) in entries {
+                meta_block_builder.add(key.as_bytes(), &value);
+            }
+            meta_block_builder.finish()
+        };
+        self.write_block(meta_block, &mut meta_block_handle)?;
+
         // write footer
-        let footer = Footer::new(meta_block_handle, index_block_handle).encoded();
-        self.file.write(footer.as_slice())?;
+        let footer = Footer::new(meta_block_handle, index_block_handle, self.checksum).encoded();
+        self.file.write(footer.as_slice(), self.io_priority)?;
         self.offset += footer.len() as u64;
+        self.file.write_buffer(self.io_priority)?;
         if sync {
-            self.file.flush()?;
-            self.file.close()?;
+            self.file.file.sync()?;
+            self.file.file.close()?;
         }
         Ok(())
     }
@@ -450,7 +1222,7 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         );
         self.closed = true;
         // TODO: maybe return Result<()> ?
-        let _ = self.file.close();
+        let _ = self.file.file.close();
     }
 
     /// Returns the number of key/value added so far.
@@ -484,83 +1256,419 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             } else {
                 self.cmp.successor(&self.last_key)
             };
-            // TODO: use a allocted buffer instead
-            let mut handle_encoding = vec![];
-            self.pending_handle.encoded_to(&mut handle_encoding);
-            self.index_block.add(&s, &handle_encoding);
+            self.handle_encoding.clear();
+            self.pending_handle.encoded_to(&mut self.handle_encoding);
+            self.index_block.add(&s, &self.handle_encoding);
             self.pending_index_entry = false;
+            if self.index_type == IndexType::TwoLevelIndexSearch {
+                self.last_index_key = s;
+                if self.index_block.current_size_estimate() >= self.block_size {
+                    self.cut_index_partition();
+                }
+            }
             return true;
         }
         false
     }
 
+    // Finishes the current index partition
+    fn cut_index_partition(&mut self) {
+        let partition = self.index_block.finish().to_vec();
+        self.index_block.reset();
+        self.index_partitions
+            .push((std::mem::take(&mut self.last_index_key), partition));
+    }
+
     fn write_block(&mut self, raw_block: &[u8], handle: &mut BlockHandle) -> Result<()> {
-        let (data, compression) = compress_block(raw_block, self.compression)?;
-        write_raw_block(&mut self.file, &data, compression, handle, &mut self.offset)?;
+        let level = self.compression_level();
+        let (data, compression) =
+            compress_block(raw_block, self.compression, level, &mut self.compressed)?;
+        write_raw_block(
+            &mut self.file,
+            data,
+            compression,
+            self.checksum,
+            handle,
+            &mut self.offset,
+            self.io_priority,
+        )?;
         Ok(())
     }
 }
 
-// Compresses the give raw block by configured compression algorithm.
-// Returns the compressed data and compression data.
-fn compress_block(
-    raw_block: &[u8],
+// Compresses the give raw block by configured compression algorithm into `dst`.
+// Returns the compressed data, which is `raw_block` itself without compression,
+// and the compression type.
+#[cfg_attr(
+    not(any(feature = "zstd", feature = "lz4")),
+    allow(unused_variables)
+)]
+fn compress_block<'a>(
+    raw_block: &'a [u8],
     compression: CompressionType,
-) -> Result<(Vec<u8>, CompressionType)> {
-    match compression {
+    level: i32,
+    dst: &'a mut Vec<u8>,
+) -> Result<(&'a [u8], CompressionType)> {
+    dst.clear();
+    let size = match compression {
         CompressionType::SnappyCompression => {
-            let mut enc = snap::raw::Encoder::new();
-            // TODO: avoid this allocation ?
-            let mut buffer = vec![0; max_compress_len(raw_block.len())];
-            match enc.compress(raw_block, buffer.as_mut_slice()) {
-                Ok(size) => buffer.truncate(size),
-                Err(e) => return Err(Error::CompressionFailed(e)),
-            }
-            Ok((buffer, CompressionType::SnappyCompression))
+            dst.resize(max_compress_len(raw_block.len()), 0);
+            SNAPPY_ENCODER
+                .with(|enc| enc.borrow_mut().compress(raw_block, dst))
+                .map_err(Error::CompressionFailed)?
         }
-        CompressionType::NoCompression | CompressionType::Unknown => {
-            Ok((Vec::from(raw_block), CompressionType::NoCompression))
+        #[cfg(feature = "zstd")]
+        CompressionType::ZstdCompression => {
+            dst.resize(zstd::zstd_safe::compress_bound(raw_block.len()), 0);
+            ZSTD_COMPRESSOR
+                .with(|c| {
+                    let mut c = c.borrow_mut();
+                    match c.as_mut() {
+                        Some((l, compressor)) if *l == level => {
+                            compressor.compress_to_buffer(raw_block, dst.as_mut_slice())
+                        }
+                        _ => {
+                            let mut compressor = zstd::bulk::Compressor::new(level)?;
+                            let size = compressor.compress_to_buffer(raw_block, dst.as_mut_slice());
+                            *c = Some((level, compressor));
+                            size
+                        }
+                    }
+                })
+                .map_err(Error::IO)?
+        }
+        #[cfg(not(feature = "zstd"))]
+        CompressionType::ZstdCompression => return Err(compression.not_supported()),
+        #[cfg(feature = "lz4")]
+        CompressionType::Lz4Compression | CompressionType::Lz4hcCompression => {
+            let mode = if compression == CompressionType::Lz4hcCompression {
+                CompressionMode::HIGHCOMPRESSION(level)
+            } else {
+                CompressionMode::DEFAULT
+            };
+            // The decompressed size is prepended to the block
+            let bound = lz4::block::compress_bound(raw_block.len()).map_err(Error::IO)?;
+            dst.resize(bound + 4, 0);
+            lz4::block::compress_to_buffer(raw_block, Some(mode), true, dst).map_err(Error::IO)?
+        }
+        #[cfg(not(feature = "lz4"))]
+        CompressionType::Lz4Compression | CompressionType::Lz4hcCompression => {
+            return Err(compression.not_supported())
+        }
+        CompressionType::NoCompression | CompressionType::Unknown => {
+            return Ok((raw_block, CompressionType::NoCompression))
+        }
+    };
+    dst.truncate(size);
+    Ok((dst.as_slice(), compression))
+}
+
+// The compression contexts reused by the tables built on the same thread
+thread_local!(static SNAPPY_ENCODER: RefCell<snap::raw::Encoder> = RefCell::new(snap::raw::Encoder::new()));
+#[cfg(feature = "zstd")]
+thread_local!(static ZSTD_COMPRESSOR: RefCell<Option<(i32, zstd::bulk::Compressor<'static>)>> = const { RefCell::new(None) });
+
+/// Copies a table into another file with the properties rewritten, which is how
+/// an external table is ingested with a global sequence number. The blocks
+/// before the properties block are copied as is by `start`, and the properties
+/// block, the meta index block and the footer are rewritten by `finish`.
+pub struct TableCopy<C: Comparator, F: File> {
+    cmp: C,
+    file: BufferedFile<F>,
+    offset: u64,
+    checksum: ChecksumType,
+    index_handle: BlockHandle,
+    properties: TableProperties,
+    // The entries of the meta index block except the properties in their order
+    meta_entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<C: Comparator, F: File> TableCopy<C, F> {
+    /// Copies the table stored in bytes `[0..src_len)` of `src` into `dst` up
+    /// to its properties block. `cmp` must be the comparator the meta index
+    /// block is built with.
+    pub fn start(src: &F, src_len: u64, dst: F, cmp: C) -> Result<Self> {
+        if src_len < FOOTER_ENCODED_LENGTH as u64 {
+            return Err(Error::Corruption(
+                "file is too short to be an sstable".to_owned(),
+            ));
+        };
+        let footer_len = src_len.min(FOOTER_ENCODED_LENGTH_V1 as u64);
+        let mut footer_space = vec![0; footer_len as usize];
+        src.read_exact_at(footer_space.as_mut_slice(), src_len - footer_len)?;
+        let (footer, _) = Footer::decode_from(footer_space.as_slice())?;
+        let checksum = footer.checksum;
+        let meta_block = Block::new(read_block(
+            src,
+            &footer.meta_index_handle,
+            checksum,
+            true,
+            IoPriority::Background,
+            false,
+        )?)?;
+        let mut properties_handle = None;
+        let mut meta_entries = vec![];
+        let mut iter = meta_block.iter(cmp.clone());
+        iter.seek_to_first();
+        while iter.valid() {
+            if iter.key() == PROPERTIES_BLOCK_KEY.as_bytes() {
+                properties_handle = Some(BlockHandle::decode_from(iter.value())?.0);
+            } else {
+                meta_entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            }
+            iter.next();
+        }
+        iter.status()?;
+        let properties_handle = properties_handle.ok_or_else(|| {
+            Error::InvalidArgument("the table has no properties block".to_owned())
+        })?;
+        // The properties block is always written right before the meta index block
+        if properties_handle.offset + properties_handle.size + BLOCK_TRAILER_SIZE as u64
+            != footer.meta_index_handle.offset
+        {
+            return Err(Error::Corruption(
+                "the properties block is not followed by the meta index block".to_owned(),
+            ));
+        }
+        let properties = TableProperties::decode_from(&read_block(
+            src,
+            &properties_handle,
+            checksum,
+            true,
+            IoPriority::Background,
+            false,
+        )?)?;
+        let mut file = BufferedFile::new(dst, 0);
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < properties_handle.offset {
+            let n = (properties_handle.offset - offset).min(COPY_CHUNK_SIZE as u64) as usize;
+            src.read_exact_at_with_priority(&mut buf[..n], offset, IoPriority::Background)?;
+            file.write(&buf[..n], IoPriority::Background)?;
+            offset += n as u64;
         }
+        Ok(Self {
+            cmp,
+            file,
+            offset,
+            checksum,
+            index_handle: footer.index_handle,
+            properties,
+            meta_entries,
+        })
+    }
+
+    /// Writes the properties with the given `global_seqno`, the meta index block
+    /// and the footer, then syncs and closes the file. Returns the file size.
+    pub fn finish(mut self, global_seqno: u64) -> Result<u64> {
+        self.properties.global_seqno = global_seqno;
+        let mut properties_handle = BlockHandle::new(0, 0);
+        write_raw_block(
+            &mut self.file,
+            &self.properties.encode(),
+            CompressionType::NoCompression,
+            self.checksum,
+            &mut properties_handle,
+            &mut self.offset,
+            IoPriority::Background,
+        )?;
+        let mut entries = std::mem::take(&mut self.meta_entries);
+        entries.push((
+            PROPERTIES_BLOCK_KEY.as_bytes().to_vec(),
+            properties_handle.encoded(),
+        ));
+        entries.sort_by(|(a, _), (b, _)| self.cmp.compare(a, b));
+        let mut meta_block_builder = BlockBuilder::new(entries.len().max(1), self.cmp.clone());
+        for (key, value) in entries.iter() {
+            meta_block_builder.add(key, value);
+        }
+        let mut meta_block_handle = BlockHandle::new(0, 0);
+        write_raw_block(
+            &mut self.file,
+            meta_block_builder.finish(),
+            CompressionType::NoCompression,
+            self.checksum,
+            &mut meta_block_handle,
+            &mut self.offset,
+            IoPriority::Background,
+        )?;
+        let footer = Footer::new(meta_block_handle, self.index_handle, self.checksum).encoded();
+        self.file.write(&footer, IoPriority::Background)?;
+        self.offset += footer.len() as u64;
+        self.file.file.sync()?;
+        self.file.file.close()?;
+        Ok(self.offset)
     }
 }
 
+// The file of a `TableBuilder` coalescing the writes in a buffer
+struct BufferedFile<F: File> {
+    file: F,
+    buf: Vec<u8>,
+    // The writes are issued in the chunks of `capacity` bytes except the last
+    // one. The writes are not buffered if it's 0.
+    capacity: usize,
+}
+
+impl<F: File> BufferedFile<F> {
+    fn new(file: F, capacity: usize) -> Self {
+        Self {
+            file,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn write(&mut self, mut data: &[u8], priority: IoPriority) -> Result<()> {
+        if self.capacity == 0 {
+            return write_all(&mut self.file, data, priority);
+        }
+        while !data.is_empty() {
+            let n = (self.capacity - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == self.capacity {
+                self.write_buffer(priority)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes out the buffered data
+    fn write_buffer(&mut self, priority: IoPriority) -> Result<()> {
+        if !self.buf.is_empty() {
+            write_all(&mut self.file, &self.buf, priority)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Called after a data block is written. The file is flushed only if the
+    // writes are not buffered.
+    fn end_block(&mut self) -> Result<()> {
+        if self.capacity == 0 {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_all<F: File>(file: &mut F, mut data: &[u8], priority: IoPriority) -> Result<()> {
+    while !data.is_empty() {
+        let n = file.write_with_priority(data, priority)?;
+        if n == 0 {
+            return Err(Error::IO(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                "failed to write the table",
+            )));
+        }
+        data = &data[n..];
+    }
+    Ok(())
+}
+
 // Write given block data into the file with block trailer
 fn write_raw_block<F: File>(
-    file: &mut F,
+    file: &mut BufferedFile<F>,
     data: &[u8],
     compression: CompressionType,
+    checksum: ChecksumType,
     handle: &mut BlockHandle,
     offset: &mut u64,
+    priority: IoPriority,
 ) -> Result<()> {
     // write block data
-    file.write(data)?;
+    file.write(data, priority)?;
     // update the block handle
     handle.set_offset(*offset);
     handle.set_size(data.len() as u64);
     // write trailer
-    // TODO: use pre-allocated buf
-    let mut trailer = vec![compression as u8];
-    let crc = mask(extend(hash(data), &[compression as u8]));
-    put_fixed_32(&mut trailer, crc);
-    assert_eq!(trailer.len(), BLOCK_TRAILER_SIZE);
-    file.write(trailer.as_slice())?;
+    let mut trailer = [0; BLOCK_TRAILER_SIZE];
+    trailer[0] = compression as u8;
+    encode_fixed_32(
+        &mut trailer[1..],
+        block_checksum(checksum, data, compression as u8),
+    );
+    file.write(&trailer, priority)?;
     // update offset
     *offset += (data.len() + BLOCK_TRAILER_SIZE) as u64;
     Ok(())
 }
 
+// Returns the checksum stored in the block trailer. The compression type is
+// included in the checksum.
+pub(crate) fn block_checksum(checksum: ChecksumType, data: &[u8], compression: u8) -> u32 {
+    match checksum {
+        ChecksumType::Crc32 => mask(extend(hash(data), &[compression])),
+        ChecksumType::Crc32c => mask(crc32c::extend(crc32c::hash(data), &[compression])),
+        ChecksumType::XxHash64 => {
+            let mut h = Xxh64::new(0);
+            h.update(data);
+            h.update(&[compression]);
+            h.digest() as u32
+        }
+        ChecksumType::Xxh3 => {
+            let mut h = Xxh3::new();
+            h.update(data);
+            h.update(&[compression]);
+            h.digest() as u32
+        }
+    }
+}
+
 // Read the block identified from `file` according to the given `handle`.
 // If the read data does not match the checksum, return a error marked as `Status::Corruption`
-fn read_block<F: File>(file: &F, handle: &BlockHandle, verify_checksum: bool) -> Result<Vec<u8>> {
+fn read_block<F: File>(
+    file: &F,
+    handle: &BlockHandle,
+    checksum: ChecksumType,
+    verify_checksum: bool,
+    priority: IoPriority,
+    drop_page_cache: bool,
+) -> Result<Vec<u8>> {
+    trace_span!(
+        "wickdb.read_block",
+        offset = handle.offset,
+        size = handle.size
+    );
     let n = handle.size as usize;
     // TODO: use pre-allocated buf
     let mut buffer = vec![0; n + BLOCK_TRAILER_SIZE];
-    file.read_exact_at(buffer.as_mut_slice(), handle.offset)?;
+    file.read_exact_at_with_priority(buffer.as_mut_slice(), handle.offset, priority)?;
+    if drop_page_cache {
+        file.advise_dont_need(handle.offset, buffer.len() as u64);
+    }
+    decode_block(buffer, checksum, verify_checksum)
+}
+
+// The tail of a table file read at once when the table is opened
+struct TailPrefetch {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl TailPrefetch {
+    // Returns the block identified by `handle` with its trailer if it's entirely
+    // in the tail
+    fn block(&self, handle: &BlockHandle) -> Option<&[u8]> {
+        let start = handle.offset.checked_sub(self.offset)? as usize;
+        let end = start
+            .checked_add(handle.size as usize)?
+            .checked_add(BLOCK_TRAILER_SIZE)?;
+        self.data.get(start..end)
+    }
+}
+
+// Verifies and decompresses the block read from the file with its trailer
+pub(crate) fn decode_block(
+    mut buffer: Vec<u8>,
+    checksum: ChecksumType,
+    verify_checksum: bool,
+) -> Result<Vec<u8>> {
+    let n = buffer.len() - BLOCK_TRAILER_SIZE;
     if verify_checksum {
-        let crc = unmask(decode_fixed_32(&buffer[n + 1..]));
-        // Compression type is included in CRC checksum
-        let actual = hash(&buffer[..=n]);
-        if crc != actual {
+        let expect = decode_fixed_32(&buffer[n + 1..]);
+        if expect != block_checksum(checksum, &buffer[..n], buffer[n]) {
             return Err(Error::Corruption("block checksum mismatch".to_owned()));
         }
     }
@@ -587,6 +1695,38 @@ fn read_block<F: File>(file: &F, handle: &BlockHandle, verify_checksum: bool) ->
                 }
                 decompressed
             }
+            #[cfg(feature = "zstd")]
+            CompressionType::ZstdCompression => {
+                let mut decompressed = vec![];
+                if let Err(e) = zstd::stream::copy_decode(&buffer[..n], &mut decompressed) {
+                    return Err(Error::Corruption(format!(
+                        "zstd decompression failed: {}",
+                        e
+                    )));
+                }
+                decompressed
+            }
+            #[cfg(not(feature = "zstd"))]
+            CompressionType::ZstdCompression => {
+                return Err(CompressionType::ZstdCompression.not_supported())
+            }
+            // Both the modes of LZ4 share the same format
+            #[cfg(feature = "lz4")]
+            CompressionType::Lz4Compression | CompressionType::Lz4hcCompression => {
+                match lz4::block::decompress(&buffer[..n], None) {
+                    Ok(decompressed) => decompressed,
+                    Err(e) => {
+                        return Err(Error::Corruption(format!(
+                            "lz4 decompression failed: {}",
+                            e
+                        )))
+                    }
+                }
+            }
+            #[cfg(not(feature = "lz4"))]
+            c @ (CompressionType::Lz4Compression | CompressionType::Lz4hcCompression) => {
+                return Err(c.not_supported())
+            }
             CompressionType::Unknown => {
                 return Err(Error::Corruption("bad block compression type".to_owned()))
             }
@@ -597,15 +1737,31 @@ fn read_block<F: File>(file: &F, handle: &BlockHandle, verify_checksum: bool) ->
 
 #[cfg(test)]
 mod tests {
+    use crate::cache::lru::LRUCache;
+    use crate::db::format::{
+        extract_user_key, InternalFilterPolicy, InternalKey, InternalKeyComparator, ValueType,
+        MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK,
+    };
     use crate::filter::bloom::BloomFilter;
+    use crate::filter::chained::{ChainedFilterPolicy, FilterKeys};
+    use crate::filter::FilterPolicy;
     use crate::iterator::Iterator;
+    use crate::mem::arena::{ArenaOptions, BlockCacheAllocator};
     use crate::sstable::block::Block;
-    use crate::sstable::table::{read_block, Table, TableBuilder};
-    use crate::sstable::BlockHandle;
+    use crate::sstable::properties::{TablePropertiesCollector, TablePropertiesCollectorFactory};
+    use crate::sstable::table::{new_table_iterator, read_block, Table, TableBuilder};
+    use crate::sstable::{BlockHandle, Footer, FOOTER_ENCODED_LENGTH_V1};
     use crate::storage::mem::MemStorage;
+    use crate::storage::IoPriority;
+    use crate::util::buffer_pool::allocated_buffers;
+    use crate::util::collection::HashSet;
     use crate::util::comparator::BytewiseComparator;
-    use crate::{File, Options, ReadOptions, Storage};
-    use std::sync::Arc;
+    use crate::util::slice_transform::FixedPrefixTransform;
+    use crate::{
+        ChecksumType, CompressionType, Error, File, IndexType, Options, ReadOptions, Storage,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::thread;
 
     #[test]
     fn test_build_empty_table_with_meta_block() {
@@ -621,7 +1777,7 @@ mod tests {
         let file = s.open("test").unwrap();
         let file_len = file.len().unwrap();
         let table = Table::open(file, 0, file_len, opt.clone(), cmp).unwrap();
-        assert!(table.filter_reader.is_some());
+        assert!(!table.filter_readers.is_empty());
         assert!(table.meta_block_handle.is_some());
     }
 
@@ -637,10 +1793,10 @@ mod tests {
         let file_len = file.len().unwrap();
         let cmp = BytewiseComparator::default();
         let table = Table::open(file, 0, file_len, opt, cmp).unwrap();
-        assert!(table.filter_reader.is_none());
+        assert!(table.filter_readers.is_empty());
         assert!(table.meta_block_handle.is_none()); // no filter block means no meta block
         let read_opt = ReadOptions::default();
-        let res = table.internal_get(read_opt, cmp, b"test").unwrap();
+        let res = table.internal_get(read_opt, cmp, b"test", None).unwrap();
         assert!(res.is_none());
     }
 
@@ -669,8 +1825,17 @@ mod tests {
         let block = Vec::from(tb.data_block.finish());
         let mut bh = BlockHandle::new(0, 0);
         tb.write_block(&block, &mut bh).unwrap();
+        tb.file.write_buffer(IoPriority::Foreground).unwrap();
         let file = s.open("test").expect("file open should work");
-        let res = read_block(&file, &bh, true).unwrap();
+        let res = read_block(
+            &file,
+            &bh,
+            ChecksumType::Crc32,
+            true,
+            IoPriority::Foreground,
+            false,
+        )
+        .unwrap();
         assert_eq!(res, block);
         let block = Block::new(res).unwrap();
         let mut iter = block.iter(cmp);
@@ -706,16 +1871,920 @@ mod tests {
             verify_checksums: true,
             fill_cache: true,
             snapshot: None,
+            min_sequence_visible: None,
+            timestamp: None,
+            deadline: None,
+            io_priority: IoPriority::Foreground,
+            drop_page_cache: false,
         };
         for (key, val) in tests.clone().drain(..) {
             assert_eq!(
                 val.as_bytes(),
                 table
-                    .internal_get(read_opt, cmp, key.as_bytes())
+                    .internal_get(read_opt, cmp, key.as_bytes(), None)
                     .unwrap()
                     .unwrap()
                     .value()
             );
         }
     }
+
+    #[test]
+    fn test_block_cache_allocator() {
+        let s = MemStorage::default();
+        let allocator = Arc::new(BlockCacheAllocator::new(ArenaOptions {
+            huge_page_size: 2 << 20,
+            preallocate: false,
+        }));
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            block_size: 1024,
+            block_cache: Some(Arc::new(LRUCache::new(1 << 20))),
+            block_cache_allocator: Some(allocator.clone()),
+            ..Options::default()
+        });
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), cmp, &opt);
+        let kvs: Vec<_> = (0..1000)
+            .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+            .collect();
+        for (k, v) in kvs.iter() {
+            tb.add(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, 1, file_len, opt.clone(), cmp).unwrap();
+        // The blocks are read from the disk and then from the block cache
+        for _ in 0..2 {
+            for (k, v) in kvs.iter() {
+                let iter = table
+                    .internal_get(ReadOptions::default(), cmp, k.as_bytes(), None)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(iter.value(), v.as_bytes());
+            }
+        }
+        // Falls back to the normal memory without huge pages reserved
+        let block = opt
+            .block_cache
+            .as_ref()
+            .unwrap()
+            .get(&table.block_cache_key(0))
+            .unwrap();
+        assert_eq!(block.is_huge_page_backed(), allocator.mapped_bytes() > 0);
+        // The huge pages are unmapped once the cached blocks are dropped
+        drop(block);
+        drop(table);
+        drop(opt);
+        assert_eq!(allocator.mapped_bytes(), 0);
+    }
+
+    #[test]
+    fn test_compressed_block_write_and_read() {
+        let s = MemStorage::default();
+        let new_file = s.create("test").unwrap();
+        let opt = Arc::new(Options::<BytewiseComparator>::default());
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(new_file, cmp, &opt);
+        let value = "the quick brown fox jumps over the lazy dog ".repeat(100);
+        for i in 0..10 {
+            tb.data_block
+                .add(format!("k{}", i).as_bytes(), value.as_bytes());
+        }
+        let block = Vec::from(tb.data_block.finish());
+        // The blocks in one file can be compressed by different algorithms
+        let mut handles = vec![];
+        for compression in [
+            CompressionType::SnappyCompression,
+            CompressionType::ZstdCompression,
+            CompressionType::Lz4Compression,
+            CompressionType::Lz4hcCompression,
+        ] {
+            if compression.check_supported().is_err() {
+                let mut bh = BlockHandle::new(0, 0);
+                tb.set_compression(compression);
+                assert!(matches!(
+                    tb.write_block(&block, &mut bh),
+                    Err(Error::NotSupported(_))
+                ));
+                continue;
+            }
+            let mut bh = BlockHandle::new(0, 0);
+            tb.set_compression(compression);
+            tb.write_block(&block, &mut bh).unwrap();
+            tb.file.write_buffer(IoPriority::Foreground).unwrap();
+            assert!((bh.size as usize) < block.len() / 10);
+            handles.push((compression, bh));
+        }
+        let file = s.open("test").unwrap();
+        for (compression, bh) in handles {
+            let mut trailer = [0; 1];
+            file.read_exact_at(&mut trailer, bh.offset + bh.size)
+                .unwrap();
+            assert_eq!(CompressionType::from(trailer[0]), compression);
+            assert_eq!(
+                read_block(
+                    &file,
+                    &bh,
+                    ChecksumType::Crc32,
+                    true,
+                    IoPriority::Foreground,
+                    false
+                )
+                .unwrap(),
+                block
+            );
+        }
+    }
+    #[test]
+    fn test_table_checksum() {
+        for checksum in [
+            ChecksumType::Crc32,
+            ChecksumType::Crc32c,
+            ChecksumType::XxHash64,
+            ChecksumType::Xxh3,
+        ] {
+            let s = MemStorage::default();
+            let new_file = s.create("test").unwrap();
+            let opt = Arc::new(Options::<BytewiseComparator> {
+                checksum,
+                ..Default::default()
+            });
+            let cmp = BytewiseComparator::default();
+            let mut tb = TableBuilder::new(new_file, cmp, &opt);
+            for i in 0..100 {
+                tb.add(format!("k{:03}", i).as_bytes(), b"value").unwrap();
+            }
+            tb.finish(false).unwrap();
+            let file = s.open("test").unwrap();
+            let file_len = file.len().unwrap();
+            // The checksum is read from the footer regardless of the options
+            let table = Table::open(
+                file,
+                0,
+                file_len,
+                Arc::new(Options::<BytewiseComparator>::default()),
+                cmp,
+            )
+            .unwrap();
+            assert_eq!(table.checksum, checksum);
+            let read_opt = ReadOptions {
+                verify_checksums: true,
+                ..Default::default()
+            };
+            let v = table
+                .internal_get(read_opt, cmp, b"k042", None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(v.value(), b"value");
+
+            // Corrupt a byte of the first data block
+            let mut file = s.open("test").unwrap();
+            let mut data = vec![];
+            file.read_all(&mut data).unwrap();
+            data[3] ^= 0x80;
+            let mut f = s.create("corrupted").unwrap();
+            f.write(&data).unwrap();
+            let f = s.open("corrupted").unwrap();
+            let table = Table::open(
+                f,
+                0,
+                file_len,
+                Arc::new(Options::<BytewiseComparator>::default()),
+                cmp,
+            )
+            .unwrap();
+            match table.internal_get(read_opt, cmp, b"k000", None) {
+                Err(crate::Error::Corruption(msg)) => assert_eq!(msg, "block checksum mismatch"),
+                r => panic!(
+                    "expect checksum mismatch with {:?}, got {:?}",
+                    checksum,
+                    r.map(|_| ())
+                ),
+            }
+        }
+    }
+
+    // Records the ranges advised to drop from the page cache
+    struct AdvisedFile {
+        inner: <MemStorage as Storage>::F,
+        advised: Arc<Mutex<Vec<(u64, u64)>>>,
+    }
+
+    impl File for AdvisedFile {
+        fn write(&mut self, buf: &[u8]) -> crate::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> crate::Result<()> {
+            self.inner.flush()
+        }
+        fn close(&mut self) -> crate::Result<()> {
+            self.inner.close()
+        }
+        fn seek(&mut self, pos: std::io::SeekFrom) -> crate::Result<u64> {
+            self.inner.seek(pos)
+        }
+        fn read(&mut self, buf: &mut [u8]) -> crate::Result<usize> {
+            self.inner.read(buf)
+        }
+        fn read_all(&mut self, buf: &mut Vec<u8>) -> crate::Result<usize> {
+            self.inner.read_all(buf)
+        }
+        fn len(&self) -> crate::Result<u64> {
+            self.inner.len()
+        }
+        fn lock(&self) -> crate::Result<()> {
+            self.inner.lock()
+        }
+        fn unlock(&self) -> crate::Result<()> {
+            self.inner.unlock()
+        }
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> crate::Result<usize> {
+            self.inner.read_at(buf, offset)
+        }
+        fn advise_dont_need(&self, offset: u64, len: u64) {
+            self.advised.lock().unwrap().push((offset, len));
+        }
+    }
+
+    #[test]
+    fn test_drop_page_cache() {
+        let s = MemStorage::default();
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            block_size: 128,
+            ..Default::default()
+        });
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), cmp, &opt);
+        for i in 0..100 {
+            tb.add(format!("k{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        tb.finish(false).unwrap();
+        let advised = Arc::new(Mutex::new(vec![]));
+        let file = AdvisedFile {
+            inner: s.open("test").unwrap(),
+            advised: advised.clone(),
+        };
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, 0, file_len, opt, cmp).unwrap();
+        // The blocks read when opening the table are kept
+        assert!(advised.lock().unwrap().is_empty());
+        table
+            .internal_get(ReadOptions::default(), cmp, b"k000", None)
+            .unwrap();
+        assert!(advised.lock().unwrap().is_empty());
+
+        let read_opt = ReadOptions {
+            drop_page_cache: true,
+            ..Default::default()
+        };
+        table.internal_get(read_opt, cmp, b"k000", None).unwrap();
+        table.internal_get(read_opt, cmp, b"k099", None).unwrap();
+        let advised = advised.lock().unwrap();
+        assert_eq!(advised.len(), 2);
+        // The first data block starts at the beginning of the file
+        assert_eq!(advised[0].0, 0);
+        assert!(advised[1].0 > advised[0].0 + advised[0].1);
+    }
+
+    // Records the sizes of the writes
+    struct WriteRecordingFile {
+        inner: <MemStorage as Storage>::F,
+        writes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl File for WriteRecordingFile {
+        fn write(&mut self, buf: &[u8]) -> crate::Result<usize> {
+            self.writes.lock().unwrap().push(buf.len());
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> crate::Result<()> {
+            self.inner.flush()
+        }
+        fn close(&mut self) -> crate::Result<()> {
+            self.inner.close()
+        }
+        fn seek(&mut self, pos: std::io::SeekFrom) -> crate::Result<u64> {
+            self.inner.seek(pos)
+        }
+        fn read(&mut self, buf: &mut [u8]) -> crate::Result<usize> {
+            self.inner.read(buf)
+        }
+        fn read_all(&mut self, buf: &mut Vec<u8>) -> crate::Result<usize> {
+            self.inner.read_all(buf)
+        }
+        fn len(&self) -> crate::Result<u64> {
+            self.inner.len()
+        }
+        fn lock(&self) -> crate::Result<()> {
+            self.inner.lock()
+        }
+        fn unlock(&self) -> crate::Result<()> {
+            self.inner.unlock()
+        }
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> crate::Result<usize> {
+            self.inner.read_at(buf, offset)
+        }
+    }
+
+    #[test]
+    fn test_table_write_buffer() {
+        let s = MemStorage::default();
+        let cmp = BytewiseComparator::default();
+        let build = |name: &str, table_write_buffer_size| {
+            let opt = Arc::new(Options::<BytewiseComparator> {
+                block_size: 128,
+                compression: CompressionType::NoCompression,
+                table_write_buffer_size,
+                ..Default::default()
+            });
+            let writes = Arc::new(Mutex::new(vec![]));
+            let file = WriteRecordingFile {
+                inner: s.create(name).unwrap(),
+                writes: writes.clone(),
+            };
+            let mut tb = TableBuilder::new(file, cmp, &opt);
+            for i in 0..1000 {
+                tb.add(format!("k{:04}", i).as_bytes(), b"value").unwrap();
+            }
+            tb.finish(false).unwrap();
+            let file = s.open(name).unwrap();
+            let file_len = file.len().unwrap();
+            assert_eq!(file_len, tb.file_size());
+            let table = Arc::new(Table::open(file, 0, file_len, opt, cmp).unwrap());
+            let mut iter = new_table_iterator(cmp, table, ReadOptions::default());
+            iter.seek_to_first();
+            for i in 0..1000 {
+                assert_eq!(iter.key(), format!("k{:04}", i).as_bytes());
+                iter.next();
+            }
+            assert!(!iter.valid());
+            let writes = writes.lock().unwrap().clone();
+            writes
+        };
+        let unbuffered = build("unbuffered", 0);
+        let buffered = build("buffered", 4096);
+        assert_eq!(
+            buffered.iter().sum::<usize>(),
+            unbuffered.iter().sum::<usize>()
+        );
+        // The writes are issued in the chunks of the buffer size except the last one
+        let (last, chunks) = buffered.split_last().unwrap();
+        assert!(chunks.iter().all(|n| *n == 4096));
+        assert!(*last <= 4096);
+        assert!(buffered.len() * 10 < unbuffered.len());
+    }
+
+    #[test]
+    fn test_table_builder_reuses_buffers() {
+        // The pool belongs to the thread, so a new one starts empty
+        thread::spawn(|| {
+            let s = MemStorage::default();
+            let cmp = BytewiseComparator::default();
+            for compression in [
+                CompressionType::NoCompression,
+                CompressionType::SnappyCompression,
+                CompressionType::ZstdCompression,
+                CompressionType::Lz4Compression,
+            ]
+            .iter()
+            .filter(|c| c.check_supported().is_ok())
+            {
+                let opt = Arc::new(Options::<BytewiseComparator> {
+                    block_size: 128,
+                    compression: *compression,
+                    ..Default::default()
+                });
+                let build = |name: &str| {
+                    let mut tb = TableBuilder::new(s.create(name).unwrap(), cmp, &opt);
+                    for i in 0..1000 {
+                        tb.add(format!("k{:04}", i).as_bytes(), b"value").unwrap();
+                    }
+                    tb.finish(false).unwrap();
+                    let file = s.open(name).unwrap();
+                    let file_len = file.len().unwrap();
+                    let table = Table::open(file, 0, file_len, opt.clone(), cmp).unwrap();
+                    let mut iter = new_table_iterator(cmp, Arc::new(table), ReadOptions::default());
+                    iter.seek_to_first();
+                    for i in 0..1000 {
+                        assert_eq!(iter.key(), format!("k{:04}", i).as_bytes());
+                        iter.next();
+                    }
+                    assert!(!iter.valid());
+                };
+                build(&format!("{:?}-0", compression));
+                // The tables built later reuse the buffers of the first one
+                let allocated = allocated_buffers();
+                for i in 1..4 {
+                    build(&format!("{:?}-{}", compression, i));
+                }
+                assert_eq!(allocated_buffers(), allocated);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let s = MemStorage::default();
+        let new_file = s.create("test").unwrap();
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            index_type: IndexType::TwoLevelIndexSearch,
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilter::new(10))),
+            ..Default::default()
+        });
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(new_file, cmp, &opt);
+        let n = 2000;
+        for i in 0..n {
+            tb.add(
+                format!("k{:05}", i * 2).as_bytes(),
+                format!("v{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Arc::new(Table::open(file, 0, file_len, opt.clone(), cmp).unwrap());
+        assert!(table.partitioned_index);
+        let mut top_level_iter = table.index_block.iter(cmp);
+        top_level_iter.seek_to_first();
+        let mut partitions = 0;
+        while top_level_iter.valid() {
+            partitions += 1;
+            top_level_iter.next();
+        }
+        assert!(partitions > 1, "partitions: {}", partitions);
+
+        for i in 0..n {
+            let key = format!("k{:05}", i * 2);
+            let v = table
+                .internal_get(ReadOptions::default(), cmp, key.as_bytes(), None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(v.key(), key.as_bytes());
+            assert_eq!(v.value(), format!("v{}", i).as_bytes());
+        }
+        assert!(table
+            .internal_get(ReadOptions::default(), cmp, b"z", None)
+            .unwrap()
+            .is_none());
+        assert!(
+            table.approximate_offset_of(cmp, b"k00100")
+                < table.approximate_offset_of(cmp, b"k03000")
+        );
+
+        let mut iter = new_table_iterator(cmp, table.clone(), ReadOptions::default());
+        iter.seek_to_first();
+        for i in 0..n {
+            assert!(iter.valid());
+            assert_eq!(iter.key(), format!("k{:05}", i * 2).as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        for i in (0..n).rev() {
+            assert!(iter.valid());
+            assert_eq!(iter.value(), format!("v{}", i).as_bytes());
+            iter.prev();
+        }
+        assert!(!iter.valid());
+        iter.seek(b"k01001");
+        assert_eq!(iter.key(), b"k01002");
+        iter.seek(b"k99999");
+        assert!(!iter.valid());
+        iter.status().unwrap();
+    }
+
+    #[test]
+    fn test_verify_data_block() {
+        for index_type in [IndexType::BinarySearch, IndexType::TwoLevelIndexSearch] {
+            let s = MemStorage::default();
+            let new_file = s.create("test").unwrap();
+            let opt = Arc::new(Options::<BytewiseComparator> {
+                index_type,
+                block_size: 256,
+                ..Default::default()
+            });
+            let cmp = BytewiseComparator::default();
+            let mut tb = TableBuilder::new(new_file, cmp, &opt);
+            for i in 0..500 {
+                tb.add(format!("k{:05}", i).as_bytes(), b"value").unwrap();
+            }
+            tb.finish(false).unwrap();
+            let mut file = s.open("test").unwrap();
+            let file_len = file.len().unwrap();
+            let table = Table::open(file.clone(), 0, file_len, opt.clone(), cmp).unwrap();
+            let mut offsets = HashSet::default();
+            for i in 0..20 {
+                let handle = table
+                    .verify_data_block(cmp, &mut |n| i % n)
+                    .unwrap()
+                    .unwrap();
+                offsets.insert(handle.offset);
+            }
+            assert!(offsets.len() > 1);
+            assert!(offsets.contains(&0));
+
+            // Corrupt a byte of the first data block
+            let mut data = vec![];
+            file.read_all(&mut data).unwrap();
+            data[3] ^= 0x80;
+            let mut f = s.create("corrupted").unwrap();
+            f.write(&data).unwrap();
+            let f = s.open("corrupted").unwrap();
+            let table = Table::open(f, 7, file_len, opt, cmp).unwrap();
+            match table.verify_data_block(cmp, &mut |_| 0) {
+                Err(crate::Error::Corruption(msg)) => {
+                    assert_eq!(msg, "block checksum mismatch [block offset 0 of table #7]")
+                }
+                r => panic!("expect checksum mismatch, got {:?}", r),
+            }
+            // The other blocks are still fine
+            table.verify_data_block(cmp, &mut |n| n - 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_prefix_may_match() {
+        let s = MemStorage::default();
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let prefix_policy = |whole_key_filtering| {
+            let policy: Arc<dyn FilterPolicy> = Arc::new(
+                InternalFilterPolicy::new(
+                    Arc::new(BloomFilter::new(10)),
+                    BytewiseComparator::default(),
+                )
+                .with_prefix_extractor(Arc::new(FixedPrefixTransform::new(2)), whole_key_filtering),
+            );
+            policy
+        };
+        for whole_key_filtering in [true, false] {
+            let name = format!("test_{}", whole_key_filtering);
+            let opt = Arc::new(Options::<BytewiseComparator> {
+                block_size: 128,
+                filter_policy: Some(prefix_policy(whole_key_filtering)),
+                ..Default::default()
+            });
+            let mut tb = TableBuilder::new(s.create(&name).unwrap(), icmp.clone(), &opt);
+            // Prefixes "a0", "a2", "a4", "a6" and "a8"
+            for p in (0..10).step_by(2) {
+                for i in 0..20 {
+                    let key =
+                        InternalKey::new(format!("a{}{:02}", p, i).as_bytes(), 1, ValueType::Value);
+                    tb.add(key.data(), b"value").unwrap();
+                }
+            }
+            tb.finish(false).unwrap();
+            let file = s.open(&name).unwrap();
+            let file_len = file.len().unwrap();
+            let table = Table::open(file, 0, file_len, opt.clone(), icmp.clone()).unwrap();
+            assert!(!table.filter_readers.is_empty());
+            let probe = |prefix: &str, ukey: &str| {
+                let target =
+                    InternalKey::new(ukey.as_bytes(), MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+                table
+                    .prefix_may_match(
+                        ReadOptions::default(),
+                        icmp.clone(),
+                        target.data(),
+                        prefix.as_bytes(),
+                    )
+                    .unwrap()
+            };
+            assert!(probe("a4", "a4"));
+            assert!(probe("a4", "a410"));
+            assert!(!probe("a5", "a5"));
+            // No key is after the target
+            assert!(!probe("b0", "b0"));
+
+            // The point lookups still find the keys with the prefix-only filters
+            let key = InternalKey::new(b"a607", MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+            let v = table
+                .internal_get(ReadOptions::default(), icmp.clone(), key.data(), None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(extract_user_key(v.key()), b"a607");
+
+            // The filters of a different setting are ignored
+            let opt = Arc::new(Options::<BytewiseComparator> {
+                filter_policy: Some(prefix_policy(!whole_key_filtering)),
+                ..Default::default()
+            });
+            let file = s.open(&name).unwrap();
+            let table = Table::open(file, 0, file_len, opt, icmp.clone()).unwrap();
+            assert!(table.filter_readers.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_chained_filter_blocks() {
+        let s = MemStorage::default();
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let whole_key: Arc<dyn FilterPolicy> = Arc::new(InternalFilterPolicy::new(
+            Arc::new(BloomFilter::new(10)),
+            BytewiseComparator::default(),
+        ));
+        let prefix: Arc<dyn FilterPolicy> = Arc::new(
+            InternalFilterPolicy::new(
+                Arc::new(BloomFilter::new(10)),
+                BytewiseComparator::default(),
+            )
+            .with_prefix_extractor(Arc::new(FixedPrefixTransform::new(2)), false),
+        );
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            block_size: 128,
+            filter_policy: Some(Arc::new(
+                ChainedFilterPolicy::new()
+                    .chain(whole_key.clone(), FilterKeys::WholeKey)
+                    .chain(prefix, FilterKeys::Prefix),
+            )),
+            ..Default::default()
+        });
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), icmp.clone(), &opt);
+        // Prefixes "a0", "a2", "a4", "a6" and "a8" with the even suffixes
+        for p in (0..10).step_by(2) {
+            for i in (0..40).step_by(2) {
+                let key =
+                    InternalKey::new(format!("a{}{:02}", p, i).as_bytes(), 1, ValueType::Value);
+                tb.add(key.data(), b"value").unwrap();
+            }
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, 0, file_len, opt, icmp.clone()).unwrap();
+        assert_eq!(table.filter_readers.len(), 2);
+        let prefix_may_match = |table: &Table<_>, prefix: &str| {
+            let target = InternalKey::new(prefix.as_bytes(), MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+            table
+                .prefix_may_match(
+                    ReadOptions::default(),
+                    icmp.clone(),
+                    target.data(),
+                    prefix.as_bytes(),
+                )
+                .unwrap()
+        };
+        let get = |table: &Table<_>, ukey: &str| {
+            let key = InternalKey::new(ukey.as_bytes(), MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+            table
+                .internal_get(ReadOptions::default(), icmp.clone(), key.data(), None)
+                .unwrap()
+                .is_some_and(|iter| extract_user_key(iter.key()) == ukey.as_bytes())
+        };
+        // The prefix filter serves the prefix seeks
+        assert!(prefix_may_match(&table, "a4"));
+        assert!(!prefix_may_match(&table, "a5"));
+        // and the whole key filter rules out the missing keys of the existing prefixes
+        assert!(get(&table, "a406"));
+        let ruled_out = (1..40)
+            .step_by(2)
+            .filter(|i| !get(&table, &format!("a4{:02}", i)))
+            .count();
+        assert!(ruled_out >= 18);
+
+        // A table is still filtered by the filters matching the configured policies
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            filter_policy: Some(whole_key),
+            ..Default::default()
+        });
+        let file = s.open("test").unwrap();
+        let table = Table::open(file, 0, file_len, opt, icmp.clone()).unwrap();
+        assert_eq!(table.filter_readers.len(), 1);
+        assert!(prefix_may_match(&table, "a5"));
+        assert!(get(&table, "a406"));
+    }
+
+    #[test]
+    fn test_degraded_table() {
+        let s = MemStorage::default();
+        let policy = Arc::new(BloomFilter::new(10));
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            block_size: 256,
+            paranoid_checks: true,
+            filter_policy: Some(policy.clone()),
+            ..Default::default()
+        });
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), cmp, &opt);
+        for i in 0..100 {
+            tb.add(format!("k{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        tb.finish(false).unwrap();
+        let mut file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file.clone(), 0, file_len, opt.clone(), cmp).unwrap();
+        assert!(!table.is_degraded());
+
+        // Locate the meta index block and the filter block
+        let mut data = vec![];
+        file.read_all(&mut data).unwrap();
+        let footer_len = data.len().min(FOOTER_ENCODED_LENGTH_V1);
+        let (footer, _) = Footer::decode_from(&data[data.len() - footer_len..]).unwrap();
+        let meta_block = read_block(
+            &file,
+            &footer.meta_index_handle,
+            footer.checksum,
+            true,
+            IoPriority::Foreground,
+            false,
+        )
+        .unwrap();
+        let mut iter = Block::new(meta_block).unwrap().iter(cmp);
+        let filter_key = "filter.".to_owned() + policy.name();
+        iter.seek(filter_key.as_bytes());
+        assert_eq!(iter.key(), filter_key.as_bytes());
+        let (filter_handle, _) = BlockHandle::decode_from(iter.value()).unwrap();
+
+        for (name, offset) in [
+            ("bad_filter", filter_handle.offset),
+            ("bad_meta_index", footer.meta_index_handle.offset),
+        ] {
+            let mut corrupted = data.clone();
+            corrupted[offset as usize + 1] ^= 0x80;
+            let mut f = s.create(name).unwrap();
+            f.write(&corrupted).unwrap();
+            let f = s.open(name).unwrap();
+            let table = Table::open(f, 1, file_len, opt.clone(), cmp).unwrap();
+            assert!(table.is_degraded(), "{}", name);
+            assert!(table.filter_readers.is_empty());
+            // The results are served from the data blocks
+            let get = |key: &str| {
+                table
+                    .internal_get(ReadOptions::default(), cmp, key.as_bytes(), None)
+                    .unwrap()
+                    .is_some_and(|iter| iter.key() == key.as_bytes())
+            };
+            assert!((0..100).all(|i| get(&format!("k{:03}", i))));
+            assert!(!get("k0505"));
+        }
+    }
+
+    #[test]
+    fn test_table_open_prefetch() {
+        let mut s = MemStorage::default();
+        s.count_random_reads = true;
+        let opt = Options::<BytewiseComparator> {
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilter::new(10))),
+            ..Default::default()
+        };
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), cmp, &Arc::new(opt.clone()));
+        for i in 0..100 {
+            tb.add(format!("k{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        // The footer, the index block, the meta index block, the filter block and
+        // the properties block
+        for (prefetch_size, reads) in [(512 * 1024, 1), (0, 5), (100, 5)] {
+            let opt = Arc::new(Options {
+                table_open_prefetch_size: prefetch_size,
+                ..opt.clone()
+            });
+            s.random_read_counter
+                .store(0, std::sync::atomic::Ordering::Release);
+            let table = Table::open(file.clone(), 0, file_len, opt, cmp).unwrap();
+            assert_eq!(
+                s.random_read_counter
+                    .load(std::sync::atomic::Ordering::Acquire),
+                reads,
+                "{}",
+                prefetch_size
+            );
+            assert_eq!(table.filter_readers.len(), 1);
+            assert!(table.properties().is_some());
+            for i in 0..100 {
+                let key = format!("k{:03}", i);
+                let found = table
+                    .internal_get(ReadOptions::default(), cmp, key.as_bytes(), None)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(found.key(), key.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_table_properties() {
+        let s = MemStorage::default();
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilter::new(10))),
+            ..Default::default()
+        });
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), icmp.clone(), &opt);
+        let (mut raw_key_size, mut raw_value_size) = (0, 0);
+        for i in 0..100 {
+            let value_type = if i % 10 == 0 {
+                ValueType::Deletion
+            } else {
+                ValueType::Value
+            };
+            let key = InternalKey::new(format!("k{:03}", i).as_bytes(), 1, value_type);
+            let value = format!("value{}", i);
+            tb.add(key.data(), value.as_bytes()).unwrap();
+            raw_key_size += key.data().len() as u64;
+            raw_value_size += value.len() as u64;
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, 0, file_len, opt, icmp).unwrap();
+        let props = table.properties().unwrap();
+        assert_eq!(props.num_entries, 100);
+        assert_eq!(props.num_deletions, 10);
+        assert_eq!(props.raw_key_size, raw_key_size);
+        assert_eq!(props.raw_value_size, raw_value_size);
+        assert_eq!(extract_user_key(&props.smallest_key), b"k000");
+        assert_eq!(extract_user_key(&props.largest_key), b"k099");
+        assert!(props.data_size > 0 && props.index_size > 0 && props.filter_size > 0);
+        assert!(props.data_size + props.index_size + props.filter_size < file_len);
+        assert!(props.creation_time > 0);
+
+        // The deletions are not counted for the tables of user keys
+        let opt = Arc::new(Options::<BytewiseComparator>::default());
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(s.create("user_keys").unwrap(), cmp, &opt);
+        tb.add(&[0; 8], b"").unwrap();
+        tb.finish(false).unwrap();
+        let file = s.open("user_keys").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, 0, file_len, opt, cmp).unwrap();
+        let props = table.properties().unwrap();
+        assert_eq!(props.num_entries, 1);
+        assert_eq!(props.num_deletions, 0);
+        assert_eq!(props.filter_size, 0);
+    }
+
+    // Counts the distinct tenants, which are the key parts before ':'
+    #[derive(Default)]
+    struct TenantCounter {
+        tenants: std::collections::HashSet<Vec<u8>>,
+    }
+
+    impl TablePropertiesCollector for TenantCounter {
+        fn add(&mut self, key: &[u8], _value: &[u8]) {
+            if let Some(pos) = key.iter().position(|b| *b == b':') {
+                self.tenants.insert(key[..pos].to_vec());
+            }
+        }
+
+        fn finish(&mut self) -> Vec<(String, Vec<u8>)> {
+            vec![
+                (
+                    "tenants".to_owned(),
+                    self.tenants.len().to_string().into_bytes(),
+                ),
+                // Reserved for the builtin properties
+                ("wickdb.num.entries".to_owned(), b"0".to_vec()),
+            ]
+        }
+    }
+
+    struct TenantCounterFactory {}
+
+    impl TablePropertiesCollectorFactory for TenantCounterFactory {
+        fn create(&self) -> Box<dyn TablePropertiesCollector> {
+            Box::new(TenantCounter::default())
+        }
+    }
+
+    #[test]
+    fn test_table_properties_collector() {
+        let s = MemStorage::default();
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let opt = Arc::new(Options::<BytewiseComparator> {
+            table_properties_collector_factories: vec![Arc::new(TenantCounterFactory {})],
+            ..Default::default()
+        });
+        for (name, tenants) in [("t1", 3), ("t2", 5)] {
+            let mut tb = TableBuilder::new(s.create(name).unwrap(), icmp.clone(), &opt);
+            for t in 0..tenants {
+                for i in 0..10 {
+                    let ukey = format!("tenant{}:{}", t, i);
+                    let key = InternalKey::new(ukey.as_bytes(), 1, ValueType::Value);
+                    tb.add(key.data(), b"").unwrap();
+                }
+            }
+            tb.finish(false).unwrap();
+            let file = s.open(name).unwrap();
+            let file_len = file.len().unwrap();
+            let table = Table::open(file, 0, file_len, opt.clone(), icmp.clone()).unwrap();
+            let props = table.properties().unwrap();
+            assert_eq!(props.num_entries, tenants * 10);
+            assert_eq!(props.user_collected.len(), 1);
+            assert_eq!(
+                props.user_collected["tenants"],
+                tenants.to_string().into_bytes()
+            );
+        }
+    }
 }
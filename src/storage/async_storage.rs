@@ -0,0 +1,181 @@
+use crate::storage::{File, Storage};
+use crate::Result;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+/// The asynchronous version of `Storage`.
+///
+/// Backends that are asynchronous by nature (e.g. io_uring or a remote object
+/// store) implement this directly instead of blocking a thread pool behind
+/// `Storage`. Any `Storage` can be used as an `AsyncStorage` via `SyncAdapter`.
+pub trait AsyncStorage: Send + Sync {
+    type F: AsyncFile + 'static;
+
+    /// Create a file if it does not exist and truncates exist one.
+    fn create<P: AsRef<Path> + Send>(
+        &self,
+        name: P,
+    ) -> impl Future<Output = Result<Self::F>> + Send;
+
+    /// Open a file for writing and reading
+    fn open<P: AsRef<Path> + Send>(&self, name: P) -> impl Future<Output = Result<Self::F>> + Send;
+
+    /// Delete the named file
+    fn remove<P: AsRef<Path> + Send>(&self, name: P) -> impl Future<Output = Result<()>> + Send;
+
+    /// Removes a directory at this path. If `recursively`, removes all its contents.
+    fn remove_dir<P: AsRef<Path> + Send>(
+        &self,
+        dir: P,
+        recursively: bool,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Returns true iff the named file exists.
+    fn exists<P: AsRef<Path> + Send>(&self, name: P) -> impl Future<Output = bool> + Send;
+
+    /// Rename a file or directory to a new name, replacing the original file if
+    /// `new` already exists.
+    fn rename<P: AsRef<Path> + Send>(
+        &self,
+        old: P,
+        new: P,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Recursively create a directory and all of its parent components if they
+    /// are missing.
+    fn mkdir_all<P: AsRef<Path> + Send>(&self, dir: P) -> impl Future<Output = Result<()>> + Send;
+
+    /// Returns a list of the full-path to each file in given directory
+    fn list<P: AsRef<Path> + Send>(
+        &self,
+        dir: P,
+    ) -> impl Future<Output = Result<Vec<PathBuf>>> + Send;
+}
+
+/// The asynchronous version of `File`
+pub trait AsyncFile: Send + Sync {
+    fn write(&mut self, buf: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send;
+    fn close(&mut self) -> impl Future<Output = Result<()>> + Send;
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> impl Future<Output = Result<usize>> + Send;
+    fn len(&self) -> impl Future<Output = Result<u64>> + Send;
+    fn is_empty(&self) -> impl Future<Output = bool> + Send {
+        async move { self.len().await.is_ok_and(|length| length == 0) }
+    }
+
+    /// Reads bytes from an offset in this source into a buffer, returning how
+    /// many bytes were read.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> impl Future<Output = Result<usize>> + Send;
+}
+
+/// `SyncAdapter` turns a `Storage` into an `AsyncStorage` and a `File` into an
+/// `AsyncFile`.
+///
+/// The wrapped operations run to completion on the polling thread, so the
+/// adapter only suits the storages that never block for long (e.g. `MemStorage`).
+#[derive(Clone, Default)]
+pub struct SyncAdapter<T>(pub T);
+
+impl<S: Storage> AsyncStorage for SyncAdapter<S> {
+    type F = SyncAdapter<S::F>;
+
+    async fn create<P: AsRef<Path> + Send>(&self, name: P) -> Result<Self::F> {
+        self.0.create(name).map(SyncAdapter)
+    }
+
+    async fn open<P: AsRef<Path> + Send>(&self, name: P) -> Result<Self::F> {
+        self.0.open(name).map(SyncAdapter)
+    }
+
+    async fn remove<P: AsRef<Path> + Send>(&self, name: P) -> Result<()> {
+        self.0.remove(name)
+    }
+
+    async fn remove_dir<P: AsRef<Path> + Send>(&self, dir: P, recursively: bool) -> Result<()> {
+        self.0.remove_dir(dir, recursively)
+    }
+
+    async fn exists<P: AsRef<Path> + Send>(&self, name: P) -> bool {
+        self.0.exists(name)
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, old: P, new: P) -> Result<()> {
+        self.0.rename(old, new)
+    }
+
+    async fn mkdir_all<P: AsRef<Path> + Send>(&self, dir: P) -> Result<()> {
+        self.0.mkdir_all(dir)
+    }
+
+    async fn list<P: AsRef<Path> + Send>(&self, dir: P) -> Result<Vec<PathBuf>> {
+        self.0.list(dir)
+    }
+}
+
+impl<F: File> AsyncFile for SyncAdapter<F> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.0.close()
+    }
+
+    async fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        self.0.read_all(buf)
+    }
+
+    async fn len(&self) -> Result<u64> {
+        self.0.len()
+    }
+
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    // The futures of `SyncAdapter` are always ready at the first poll
+    fn block_on<T>(f: impl Future<Output = T>) -> T {
+        let mut f = pin!(f);
+        match f.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(v) => v,
+            Poll::Pending => panic!("the future of SyncAdapter should be ready"),
+        }
+    }
+
+    #[test]
+    fn test_sync_adapter() {
+        let store = SyncAdapter(MemStorage::default());
+        block_on(async {
+            store.mkdir_all("db").await.unwrap();
+            let mut f = store.create("db/a").await.unwrap();
+            assert_eq!(f.write(b"hello world").await.unwrap(), 11);
+            f.flush().await.unwrap();
+            f.close().await.unwrap();
+            assert!(store.exists("db/a").await);
+
+            store.rename("db/a", "db/b").await.unwrap();
+            assert!(!store.exists("db/a").await);
+            let f = store.open("db/b").await.unwrap();
+            assert_eq!(f.len().await.unwrap(), 11);
+            let mut buf = vec![0; 5];
+            assert_eq!(f.read_at(&mut buf, 6).await.unwrap(), 5);
+            assert_eq!(buf, b"world");
+            assert_eq!(store.list("db").await.unwrap().len(), 1);
+
+            store.remove("db/b").await.unwrap();
+            assert!(!store.exists("db/b").await);
+        });
+    }
+}
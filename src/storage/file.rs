@@ -1,9 +1,9 @@
-use crate::storage::{File, Storage};
+use crate::storage::{File, IoPriority, Storage};
 use crate::{Error, Result};
 use fs2::FileExt;
 use std::fs::{
-    create_dir_all, read_dir, remove_dir, remove_dir_all, remove_file, rename, File as SysFile,
-    OpenOptions,
+    create_dir_all, hard_link, read_dir, remove_dir, remove_dir_all, remove_file, rename,
+    File as SysFile, OpenOptions,
 };
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
@@ -60,6 +60,15 @@ impl Storage for FileStorage {
         map_io_res!(r)
     }
 
+    fn link<P: AsRef<Path>>(&self, src: P, dst: P) -> Result<()> {
+        map_io_res!(hard_link(src, dst))
+    }
+
+    #[cfg(unix)]
+    fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        map_io_res!(SysFile::open(dir).and_then(|d| d.sync_all()))
+    }
+
     fn list<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>> {
         if dir.as_ref().is_dir() {
             let mut v = vec![];
@@ -85,10 +94,19 @@ impl File for SysFile {
         map_io_res!(Write::write(self, buf))
     }
 
+    fn write_with_priority(&mut self, buf: &[u8], priority: IoPriority) -> Result<usize> {
+        set_thread_io_priority(priority);
+        File::write(self, buf)
+    }
+
     fn flush(&mut self) -> Result<()> {
         map_io_res!(Write::flush(self))
     }
 
+    fn sync(&mut self) -> Result<()> {
+        map_io_res!(Write::flush(self).and_then(|_| self.sync_all()))
+    }
+
     fn close(&mut self) -> Result<()> {
         Ok(())
     }
@@ -134,7 +152,71 @@ impl File for SysFile {
         let r = std::os::windows::prelude::FileExt::seek_read(self, buf, offset);
         map_io_res!(r)
     }
+
+    #[cfg(target_os = "linux")]
+    fn advise_dont_need(&self, offset: u64, len: u64) {
+        use std::os::unix::io::AsRawFd;
+        // Only a hint so the failure is ignored
+        unsafe {
+            libc::posix_fadvise(
+                self.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            );
+        }
+    }
+
+    fn read_at_with_priority(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<usize> {
+        set_thread_io_priority(priority);
+        File::read_at(self, buf, offset)
+    }
+}
+
+// The IO priority set to the current thread by `ioprio_set(2)` lastly
+#[cfg(target_os = "linux")]
+thread_local!(static THREAD_IO_PRIORITY: std::cell::Cell<Option<IoPriority>> = const { std::cell::Cell::new(None) });
+
+// Maps the priority hint to the best-effort class of the IO scheduler. The
+// level of the foreground is 4 (the default of a thread with nice 0) and the
+// background is 7 (the lowest). The priority belongs to the calling thread, so
+// it's only changed when the thread switches between the two.
+#[cfg(target_os = "linux")]
+fn set_thread_io_priority(priority: IoPriority) {
+    // See linux/ioprio.h
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    THREAD_IO_PRIORITY.with(|current| {
+        if current.get() == Some(priority) {
+            return;
+        }
+        let level = match priority {
+            IoPriority::Foreground => 4,
+            IoPriority::Background => 7,
+        };
+        // `who` 0 is the calling thread. It's only a hint so the failure (e.g.
+        // under a sandbox forbidding the syscall) is ignored.
+        unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level,
+            );
+        }
+        current.set(Some(priority));
+    })
 }
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_io_priority(_priority: IoPriority) {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,376 @@
+use crate::storage::{File, IoPriority, Storage};
+use crate::Result;
+use rand::Rng;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// The types of the operations whose latency can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IoOp {
+    /// `read`, `read_all` and `read_at` of a file
+    Read,
+    /// `write` of a file
+    Write,
+    /// `flush` and `sync` (fsync) of a file
+    Sync,
+    /// All the operations on the namespace, e.g. `create`, `open`, `remove`,
+    /// `rename` and `list`
+    Metadata,
+}
+
+const IO_OPS: usize = 4;
+
+impl IoOp {
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The distribution the injected latency is sampled from
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LatencyDistribution {
+    /// No latency is injected
+    #[default]
+    None,
+    /// Every operation takes the same extra time
+    Constant(Duration),
+    /// The latency is uniformly distributed in `[min, max]`
+    Uniform { min: Duration, max: Duration },
+    /// Most operations take `base` while a `probability` of them take `slow`,
+    /// which models the tail latency of a disk stalled by GC or other tenants
+    Bimodal {
+        base: Duration,
+        slow: Duration,
+        probability: f64,
+    },
+    /// The latency is exponentially distributed with the given mean, which has
+    /// a long tail (p99 is about 4.6x of the mean)
+    Exponential { mean: Duration },
+}
+
+impl LatencyDistribution {
+    /// Samples a latency from the distribution
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        match self {
+            LatencyDistribution::None => Duration::default(),
+            LatencyDistribution::Constant(d) => *d,
+            LatencyDistribution::Uniform { min, max } => {
+                if max <= min {
+                    *min
+                } else {
+                    let nanos = rng.gen_range(min.as_nanos() as u64, max.as_nanos() as u64 + 1);
+                    Duration::from_nanos(nanos)
+                }
+            }
+            LatencyDistribution::Bimodal {
+                base,
+                slow,
+                probability,
+            } => {
+                if rng.gen::<f64>() < *probability {
+                    *slow
+                } else {
+                    *base
+                }
+            }
+            LatencyDistribution::Exponential { mean } => {
+                // inverse transform sampling, `1 - u` is in (0, 1]
+                let u: f64 = rng.gen();
+                mean.mul_f64(-(1.0 - u).ln())
+            }
+        }
+    }
+}
+
+// The shared state of a `LatencyInjectionStorage` and all its files
+#[derive(Default)]
+struct Injector {
+    distributions: RwLock<[LatencyDistribution; IO_OPS]>,
+    // The number of operations of each type
+    operations: [AtomicU64; IO_OPS],
+    // The total injected latency in nanoseconds of each type
+    injected: [AtomicU64; IO_OPS],
+}
+
+impl Injector {
+    fn inject(&self, op: IoOp) {
+        let i = op.index();
+        let latency = self.distributions.read().unwrap()[i].sample(&mut rand::thread_rng());
+        self.operations[i].fetch_add(1, Ordering::Relaxed);
+        if latency > Duration::default() {
+            self.injected[i].fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+            thread::sleep(latency);
+        }
+    }
+}
+
+/// `LatencyInjectionStorage` wraps a `Storage` and delays each operation by a
+/// latency sampled from the distribution configured for its type.
+///
+/// It's meant to reproduce the p99 behavior of slow disks in tests and
+/// benchmarks, e.g. to validate the effectiveness of the write stall and
+/// readahead settings. The distributions can be changed at any time and apply
+/// to the files already opened.
+#[derive(Clone)]
+pub struct LatencyInjectionStorage<S: Storage> {
+    inner: S,
+    injector: Arc<Injector>,
+}
+
+impl<S: Storage> LatencyInjectionStorage<S> {
+    /// Creates a `LatencyInjectionStorage` without any latency injected
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            injector: Arc::new(Injector::default()),
+        }
+    }
+
+    /// Returns the wrapped storage
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Sets the latency distribution of the given operation type
+    pub fn set_latency(&self, op: IoOp, distribution: LatencyDistribution) {
+        self.injector.distributions.write().unwrap()[op.index()] = distribution;
+    }
+
+    /// Removes the injected latency of all the operation types
+    pub fn clear_latency(&self) {
+        *self.injector.distributions.write().unwrap() = Default::default();
+    }
+
+    /// Returns the number of operations of the given type done so far
+    pub fn operations(&self, op: IoOp) -> u64 {
+        self.injector.operations[op.index()].load(Ordering::Relaxed)
+    }
+
+    /// Returns the total latency injected into the operations of the given type
+    pub fn injected_latency(&self, op: IoOp) -> Duration {
+        Duration::from_nanos(self.injector.injected[op.index()].load(Ordering::Relaxed))
+    }
+
+    fn wrap(&self, f: S::F) -> LatencyInjectionFile<S::F> {
+        LatencyInjectionFile {
+            inner: f,
+            injector: self.injector.clone(),
+        }
+    }
+}
+
+impl<S: Storage> Storage for LatencyInjectionStorage<S> {
+    type F = LatencyInjectionFile<S::F>;
+
+    fn create<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.create(name).map(|f| self.wrap(f))
+    }
+
+    fn open<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.open(name).map(|f| self.wrap(f))
+    }
+
+    fn remove<P: AsRef<Path>>(&self, name: P) -> Result<()> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.remove(name)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, dir: P, recursively: bool) -> Result<()> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.remove_dir(dir, recursively)
+    }
+
+    fn exists<P: AsRef<Path>>(&self, name: P) -> bool {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.exists(name)
+    }
+
+    fn rename<P: AsRef<Path>>(&self, old: P, new: P) -> Result<()> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.rename(old, new)
+    }
+
+    fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.list(dir)
+    }
+
+    fn link<P: AsRef<Path>>(&self, src: P, dst: P) -> Result<()> {
+        self.injector.inject(IoOp::Metadata);
+        self.inner.link(src, dst)
+    }
+
+    fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.injector.inject(IoOp::Sync);
+        self.inner.sync_dir(dir)
+    }
+
+    fn recover_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.inner.recover_dir(dir)
+    }
+}
+
+/// The `File` of `LatencyInjectionStorage`
+pub struct LatencyInjectionFile<F: File> {
+    inner: F,
+    injector: Arc<Injector>,
+}
+
+impl<F: File> File for LatencyInjectionFile<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.injector.inject(IoOp::Write);
+        self.inner.write(buf)
+    }
+
+    fn write_with_priority(&mut self, buf: &[u8], priority: IoPriority) -> Result<usize> {
+        self.injector.inject(IoOp::Write);
+        self.inner.write_with_priority(buf, priority)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.injector.inject(IoOp::Sync);
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.injector.inject(IoOp::Sync);
+        self.inner.sync()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.inner.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.injector.inject(IoOp::Read);
+        self.inner.read(buf)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        self.injector.inject(IoOp::Read);
+        self.inner.read_all(buf)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.inner.len()
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.inner.unlock()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.injector.inject(IoOp::Read);
+        self.inner.read_at(buf, offset)
+    }
+
+    fn advise_dont_need(&self, offset: u64, len: u64) {
+        self.inner.advise_dont_need(offset, len)
+    }
+
+    fn read_at_with_priority(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<usize> {
+        self.injector.inject(IoOp::Read);
+        self.inner.read_at_with_priority(buf, offset, priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+    use std::time::Instant;
+
+    #[test]
+    fn test_sample_latency_distribution() {
+        let mut rng = rand::thread_rng();
+        let ms = Duration::from_millis;
+        assert_eq!(LatencyDistribution::None.sample(&mut rng), ms(0));
+        assert_eq!(LatencyDistribution::Constant(ms(3)).sample(&mut rng), ms(3));
+        let bimodal = LatencyDistribution::Bimodal {
+            base: ms(1),
+            slow: ms(100),
+            probability: 0.1,
+        };
+        let uniform = LatencyDistribution::Uniform {
+            min: ms(2),
+            max: ms(4),
+        };
+        let exponential = LatencyDistribution::Exponential { mean: ms(10) };
+        let mut slow = 0;
+        let mut total = Duration::default();
+        for _ in 0..10000 {
+            let d = bimodal.sample(&mut rng);
+            assert!(d == ms(1) || d == ms(100));
+            if d == ms(100) {
+                slow += 1;
+            }
+            let d = uniform.sample(&mut rng);
+            assert!(d >= ms(2) && d <= ms(4));
+            total += exponential.sample(&mut rng);
+        }
+        assert!(slow > 500 && slow < 1500, "slow: {}", slow);
+        let mean = total / 10000;
+        assert!(mean > ms(8) && mean < ms(12), "mean: {:?}", mean);
+    }
+
+    #[test]
+    fn test_latency_injection_storage() {
+        let store = LatencyInjectionStorage::new(MemStorage::default());
+        let mut f = store.create("a").unwrap();
+        f.write(b"hello").unwrap();
+        assert_eq!(store.injected_latency(IoOp::Write), Duration::default());
+
+        store.set_latency(
+            IoOp::Write,
+            LatencyDistribution::Constant(Duration::from_millis(5)),
+        );
+        let now = Instant::now();
+        f.write(b" world").unwrap();
+        f.write(b"!").unwrap();
+        assert!(now.elapsed() >= Duration::from_millis(10));
+        assert_eq!(store.operations(IoOp::Write), 3);
+        assert_eq!(
+            store.injected_latency(IoOp::Write),
+            Duration::from_millis(10)
+        );
+
+        // Reads are not affected
+        let mut buf = vec![0; 5];
+        f.read_at(&mut buf, 6).unwrap();
+        assert_eq!(buf, b"world");
+        assert_eq!(store.operations(IoOp::Read), 1);
+        assert_eq!(store.injected_latency(IoOp::Read), Duration::default());
+
+        store.clear_latency();
+        f.write(b"!").unwrap();
+        assert_eq!(
+            store.injected_latency(IoOp::Write),
+            Duration::from_millis(10)
+        );
+    }
+}
@@ -50,6 +50,9 @@ pub struct MemStorage {
     pub count_random_reads: bool,
 
     pub random_read_counter: Arc<AtomicUsize>,
+
+    /// The count of `sync()` calls to files
+    pub sync_counter: Arc<AtomicUsize>,
 }
 
 impl Default for MemStorage {
@@ -67,6 +70,7 @@ impl Default for MemStorage {
             manifest_write_error: Arc::new(AtomicBool::new(false)),
             count_random_reads: false,
             random_read_counter: Arc::new(AtomicUsize::new(0)),
+            sync_counter: Arc::new(AtomicUsize::new(0)),
         }
     }
 }
@@ -119,7 +123,7 @@ impl MemStorage {
 
 // Remove all the relative part (also the root prefix) and rebuild a new `PathBuf`
 // by concatenating all normal components.
-fn clean<P: AsRef<Path>>(path: P) -> PathBuf {
+pub(crate) fn clean<P: AsRef<Path>>(path: P) -> PathBuf {
     path.as_ref()
         .components()
         .filter_map(|c| match c {
@@ -153,6 +157,7 @@ impl Storage for MemStorage {
         file_node.manifest_write_error = self.manifest_write_error.clone();
         file_node.count_random_reads = Arc::new(AtomicBool::new(self.count_random_reads));
         file_node.random_read_counter = self.random_read_counter.clone();
+        file_node.sync_counter = self.sync_counter.clone();
         match self.inner.write().unwrap().entry(name) {
             Entry::Occupied(n) => match n.get() {
                 Node::File(f) => return Ok(f.clone()),
@@ -336,10 +341,14 @@ impl Storage for MemStorage {
                 format!("{}: No such directory", &path),
             )));
         }
+        // Only the direct children are listed like `FileStorage` does. A string
+        // prefix match would also list the entries of the sub-directories (e.g. the
+        // logs archived in `db/archive` would look like the live logs in `db`) and
+        // the siblings sharing the prefix (e.g. `ab` when listing `a`)
         Ok(map
             .keys()
-            .filter(|k| *k != &path && k.starts_with(&path))
-            .map(|p| p.into())
+            .map(PathBuf::from)
+            .filter(|p| p.parent() == Some(Path::new(&path)))
             .collect::<Vec<PathBuf>>())
     }
 }
@@ -379,6 +388,7 @@ pub struct FileNode {
 
     count_random_reads: Arc<AtomicBool>,
     random_read_counter: Arc<AtomicUsize>,
+    sync_counter: Arc<AtomicUsize>,
 
     inner: Arc<RwLock<InmemFile>>,
 }
@@ -441,6 +451,11 @@ impl File for FileNode {
         }
     }
 
+    fn sync(&mut self) -> Result<()> {
+        self.sync_counter.fetch_add(1, Ordering::Release);
+        self.flush()
+    }
+
     fn close(&mut self) -> Result<()> {
         self.inner.write().unwrap().close()
     }
@@ -869,11 +884,39 @@ mod tests {
         for i in 0..1000 {
             store.create(i.to_string()).unwrap();
         }
+        store.mkdir_all("a").unwrap();
+        store.create("a/b").unwrap();
+        store.create("ab").unwrap();
         let list = store.list("/").unwrap();
+        assert_eq!(list.len(), 1002);
         for name in list {
-            store.assert_file_exists(name);
+            assert!(store.exists(name));
         }
+        assert_eq!(store.list("a").unwrap(), vec![PathBuf::from("/a/b")]);
     }
+    #[test]
+    fn test_storage_atomic_write() {
+        let store = MemStorage::default();
+        let read = |name: &str| {
+            let mut buf = vec![];
+            store.open(name).unwrap().read_all(&mut buf).unwrap();
+            buf
+        };
+        store.mkdir_all("a").unwrap();
+        store.atomic_write("a/b", b"hello").unwrap();
+        assert_eq!(read("a/b"), b"hello");
+        // A temp file left by a crash
+        let mut tmp = store.create("a/b.dbtmp").unwrap();
+        tmp.write(b"garbage").unwrap();
+        store.atomic_write("a/b", b"world").unwrap();
+        assert_eq!(read("a/b"), b"world");
+        assert!(!store.exists("a/b.dbtmp"));
+
+        store.non_writable.store(true, Ordering::Release);
+        assert!(store.atomic_write("a/b", b"foo").is_err());
+        assert_eq!(read("a/b"), b"world");
+    }
+
     #[test]
     fn test_path_clean() {
         let tests = if cfg!(windows) {
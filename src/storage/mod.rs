@@ -1,5 +1,13 @@
+#[cfg(feature = "async")]
+pub mod async_storage;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod file;
+pub mod latency;
 pub mod mem;
+pub mod rate_limiter;
+pub mod rename;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 use crate::{Error, Result};
 use std::io;
@@ -34,14 +42,86 @@ pub trait Storage: Send + Sync {
     /// are missing.
     fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> Result<()>;
 
-    /// Returns a list of the full-path to each file in given directory
+    /// Returns the full paths of the direct children (files and directories) of
+    /// the given directory like `std::fs::read_dir`. The entries of the
+    /// sub-directories are not listed.
     fn list<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>>;
+
+    /// Create `dst` as a hard link to the file `src`, so they share the content.
+    /// The default copies the content of `src` into `dst` and syncs it for the
+    /// storages without hard links.
+    fn link<P: AsRef<Path>>(&self, src: P, dst: P) -> Result<()> {
+        let mut data = vec![];
+        self.open(src)?.read_all(&mut data)?;
+        let mut file = self.create(dst)?;
+        file.write(&data)?;
+        file.sync()?;
+        file.close()
+    }
+
+    /// Make the entries (e.g. the renamed files) of the directory durable
+    fn sync_dir<P: AsRef<Path>>(&self, _dir: P) -> Result<()> {
+        Ok(())
+    }
+
+    /// Complete or roll back the operations left unfinished in the directory by
+    /// a crash (e.g. an emulated rename). It's called before the db in the
+    /// directory is recovered.
+    fn recover_dir<P: AsRef<Path>>(&self, _dir: P) -> Result<()> {
+        Ok(())
+    }
+
+    /// Replace the content of the named file with `data` atomically.
+    /// The data is written to a temp file first, which is then synced and renamed
+    /// to `name`, so the file holds either the old or the new content even if
+    /// the process crashes in the middle.
+    fn atomic_write<P: AsRef<Path>>(&self, name: P, data: &[u8]) -> Result<()> {
+        let name = name.as_ref();
+        let mut tmp = name.as_os_str().to_owned();
+        tmp.push(".dbtmp");
+        let tmp = PathBuf::from(tmp);
+        // The temp file left by a crash must not be appended to
+        if self.exists(&tmp) {
+            self.remove(&tmp)?;
+        }
+        let mut file = self.create(&tmp)?;
+        let result = file
+            .write(data)
+            .and_then(|_| file.sync())
+            .and_then(|_| file.close())
+            .and_then(|_| self.rename(tmp.as_path(), name));
+        if let Err(e) = result {
+            let _ = self.remove(&tmp);
+            return Err(e);
+        }
+        // The rename is durable only after the directory is synced
+        match name.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => self.sync_dir(dir),
+            _ => self.sync_dir("."),
+        }
+    }
+}
+
+/// The priority hint of an IO operation, which tells the foreground reads and
+/// writes from the background flushes and compactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoPriority {
+    /// The operations a user waits for, e.g. `get` and the WAL writes
+    #[default]
+    Foreground,
+    /// The operations of the flushes and compactions
+    Background,
 }
 
 /// A file abstraction for IO operations
 pub trait File: Send + Sync {
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
     fn flush(&mut self) -> Result<()>;
+    /// Flushes the file and makes its content durable. It's the same as `flush`
+    /// by default for the storages with nothing to persist.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()
+    }
     fn close(&mut self) -> Result<()>;
     fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
@@ -75,9 +155,39 @@ pub trait File: Send + Sync {
     ///
     /// See [`Read::read_exact()`](https://doc.rust-lang.org/std/io/trait.Read.html#method.read_exact)
     /// for details.
-    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.read_exact_at_with_priority(buf, offset, IoPriority::Foreground)
+    }
+
+    /// Advises the OS that the range of the file won't be read again soon, so
+    /// its page cache can be dropped. It's only a hint and ignored by default.
+    fn advise_dont_need(&self, _offset: u64, _len: u64) {}
+
+    /// Same as `write` but with a priority hint. The hint is ignored by default.
+    fn write_with_priority(&mut self, buf: &[u8], _priority: IoPriority) -> Result<usize> {
+        self.write(buf)
+    }
+
+    /// Same as `read_at` but with a priority hint. The hint is ignored by default.
+    fn read_at_with_priority(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        _priority: IoPriority,
+    ) -> Result<usize> {
+        self.read_at(buf, offset)
+    }
+
+    /// Same as `read_exact_at` but with a priority hint passed to each
+    /// `read_at_with_priority`
+    fn read_exact_at_with_priority(
+        &self,
+        mut buf: &mut [u8],
+        mut offset: u64,
+        priority: IoPriority,
+    ) -> Result<()> {
         while !buf.is_empty() {
-            match self.read_at(buf, offset) {
+            match self.read_at_with_priority(buf, offset, priority) {
                 Ok(0) => break,
                 Ok(n) => {
                     let tmp = buf;
@@ -103,6 +213,86 @@ pub trait File: Send + Sync {
     }
 }
 
+impl<F: File + ?Sized> File for Box<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        (**self).close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        (**self).seek(pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        (**self).read_all(buf)
+    }
+
+    fn len(&self) -> Result<u64> {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn lock(&self) -> Result<()> {
+        (**self).lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        (**self).unlock()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+
+    fn advise_dont_need(&self, offset: u64, len: u64) {
+        (**self).advise_dont_need(offset, len)
+    }
+
+    fn write_with_priority(&mut self, buf: &[u8], priority: IoPriority) -> Result<usize> {
+        (**self).write_with_priority(buf, priority)
+    }
+
+    fn read_at_with_priority(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<usize> {
+        (**self).read_at_with_priority(buf, offset, priority)
+    }
+
+    fn read_exact_at_with_priority(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<()> {
+        (**self).read_exact_at_with_priority(buf, offset, priority)
+    }
+}
+
 /// 目的是将给定的字符串数据 data 写入到一个文件中，并基于参数 should_sync 决定是否同步文件到磁盘。
 pub fn do_write_string_to_file<S: Storage, P: AsRef<Path>>(
     env: &S,
@@ -113,7 +303,7 @@ pub fn do_write_string_to_file<S: Storage, P: AsRef<Path>>(
     let mut file = env.create(&file_name)?;
     file.write(data.as_bytes())?;
     if should_sync {
-        file.flush()?;
+        file.sync()?;
     }
     if file.close().is_err() {
         env.remove(&file_name)?;
@@ -0,0 +1,375 @@
+use crate::storage::{File, IoPriority, Storage};
+use crate::Result;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// The longest time a blocked request sleeps before checking again
+const MAX_WAIT: Duration = Duration::from_millis(10);
+
+#[inline]
+fn index(priority: IoPriority) -> usize {
+    match priority {
+        IoPriority::Foreground => 0,
+        IoPriority::Background => 1,
+    }
+}
+
+struct LimiterState {
+    // The bytes can be granted now. It's negative after granting a request
+    // larger than the available bytes, which is paid back by the later refills.
+    available: f64,
+    last_refill: Instant,
+    // The number of the blocked requests of each priority
+    waiting: [usize; 2],
+    // The foreground requests granted since the last background one
+    foreground_streak: u32,
+}
+
+/// `RateLimiter` limits the bytes per second of the IO requests with a token
+/// bucket.
+///
+/// The blocked foreground requests are granted before the background ones, but
+/// a background request is granted after every `fairness` foreground ones, so
+/// the flushes and compactions are slowed down but never starved by the reads
+/// and writes of the users.
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    // The most bytes can be accumulated when there is no request
+    burst: f64,
+    fairness: u32,
+    state: Mutex<LimiterState>,
+    granted: Condvar,
+    // The total bytes granted of each priority
+    total: [AtomicU64; 2],
+}
+
+impl RateLimiter {
+    /// Creates a `RateLimiter` granting `bytes_per_sec` bytes per second with
+    /// a burst of 100ms and a fairness of 10
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_fairness(bytes_per_sec, 10)
+    }
+
+    /// Creates a `RateLimiter` granting a blocked background request after
+    /// every `fairness` foreground ones
+    pub fn with_fairness(bytes_per_sec: u64, fairness: u32) -> Self {
+        assert!(
+            bytes_per_sec > 0,
+            "[rate limiter] the rate must be positive"
+        );
+        let bytes_per_sec = bytes_per_sec as f64;
+        Self {
+            bytes_per_sec,
+            burst: (bytes_per_sec / 10.0).max(1.0),
+            fairness: fairness.max(1),
+            state: Mutex::new(LimiterState {
+                available: 0.0,
+                last_refill: Instant::now(),
+                waiting: [0; 2],
+                foreground_streak: 0,
+            }),
+            granted: Condvar::new(),
+            total: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    /// Blocks until `bytes` can be read or written with the given priority
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        if bytes == 0 {
+            return;
+        }
+        let i = index(priority);
+        let mut state = self.state.lock().unwrap();
+        state.waiting[i] += 1;
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.available = (state.available + elapsed * self.bytes_per_sec).min(self.burst);
+            state.last_refill = now;
+            let turn = match priority {
+                IoPriority::Foreground => {
+                    state.waiting[1] == 0 || state.foreground_streak < self.fairness
+                }
+                IoPriority::Background => {
+                    state.waiting[0] == 0 || state.foreground_streak >= self.fairness
+                }
+            };
+            if turn && state.available > 0.0 {
+                state.available -= bytes as f64;
+                state.waiting[i] -= 1;
+                match priority {
+                    IoPriority::Foreground => state.foreground_streak += 1,
+                    IoPriority::Background => state.foreground_streak = 0,
+                }
+                self.total[i].fetch_add(bytes as u64, Ordering::Relaxed);
+                // The others may take the turn now
+                self.granted.notify_all();
+                return;
+            }
+            let wait = if state.available > 0.0 {
+                MAX_WAIT
+            } else {
+                Duration::from_secs_f64(-state.available / self.bytes_per_sec).min(MAX_WAIT)
+            };
+            state = self.granted.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    /// Returns the total bytes granted to the requests of the given priority
+    pub fn total_bytes(&self, priority: IoPriority) -> u64 {
+        self.total[index(priority)].load(Ordering::Relaxed)
+    }
+}
+
+/// `RateLimitedStorage` wraps a `Storage` and passes the reads and writes of
+/// its files through a `RateLimiter` with their priority hints. The calls
+/// without a hint are considered as `IoPriority::Foreground`.
+#[derive(Clone)]
+pub struct RateLimitedStorage<S: Storage> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S: Storage> RateLimitedStorage<S> {
+    pub fn new(inner: S, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+
+    /// Returns the wrapped storage
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn limiter(&self) -> &Arc<RateLimiter> {
+        &self.limiter
+    }
+
+    fn wrap(&self, f: S::F) -> RateLimitedFile<S::F> {
+        RateLimitedFile {
+            inner: f,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<S: Storage> Storage for RateLimitedStorage<S> {
+    type F = RateLimitedFile<S::F>;
+
+    fn create<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        self.inner.create(name).map(|f| self.wrap(f))
+    }
+
+    fn open<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        self.inner.open(name).map(|f| self.wrap(f))
+    }
+
+    fn remove<P: AsRef<Path>>(&self, name: P) -> Result<()> {
+        self.inner.remove(name)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, dir: P, recursively: bool) -> Result<()> {
+        self.inner.remove_dir(dir, recursively)
+    }
+
+    fn exists<P: AsRef<Path>>(&self, name: P) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename<P: AsRef<Path>>(&self, old: P, new: P) -> Result<()> {
+        self.inner.rename(old, new)
+    }
+
+    fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn link<P: AsRef<Path>>(&self, src: P, dst: P) -> Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn recover_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.inner.recover_dir(dir)
+    }
+}
+
+/// The `File` of `RateLimitedStorage`
+pub struct RateLimitedFile<F: File> {
+    inner: F,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<F: File> File for RateLimitedFile<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write_with_priority(buf, IoPriority::Foreground)
+    }
+
+    fn write_with_priority(&mut self, buf: &[u8], priority: IoPriority) -> Result<usize> {
+        self.limiter.request(buf.len(), priority);
+        self.inner.write_with_priority(buf, priority)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.inner.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+
+    // The size of a sequential read is unknown before it's done, so it's
+    // charged afterwards
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.limiter.request(n, IoPriority::Foreground);
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let n = self.inner.read_all(buf)?;
+        self.limiter.request(n, IoPriority::Foreground);
+        Ok(n)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.inner.len()
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.inner.unlock()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.read_at_with_priority(buf, offset, IoPriority::Foreground)
+    }
+
+    fn advise_dont_need(&self, offset: u64, len: u64) {
+        self.inner.advise_dont_need(offset, len)
+    }
+
+    fn read_at_with_priority(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        priority: IoPriority,
+    ) -> Result<usize> {
+        self.limiter.request(buf.len(), priority);
+        self.inner.read_at_with_priority(buf, offset, priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{WickDB, DB};
+    use crate::options::{CompressionType, Options, ReadOptions, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(100 * 1024);
+        let now = Instant::now();
+        for _ in 0..10 {
+            limiter.request(4 * 1024, IoPriority::Foreground);
+        }
+        // 40KB takes about 400ms at 100KB/s
+        assert!(now.elapsed() >= Duration::from_millis(300));
+        assert_eq!(limiter.total_bytes(IoPriority::Foreground), 40 * 1024);
+        assert_eq!(limiter.total_bytes(IoPriority::Background), 0);
+    }
+
+    #[test]
+    fn test_foreground_first_without_starving_background() {
+        let limiter = Arc::new(RateLimiter::with_fairness(1024 * 1024, 4));
+        let stop = Arc::new(AtomicBool::new(false));
+        // Two threads of each priority
+        let priorities = [
+            IoPriority::Foreground,
+            IoPriority::Foreground,
+            IoPriority::Background,
+            IoPriority::Background,
+        ];
+        let handles: Vec<_> = priorities
+            .iter()
+            .map(|&priority| {
+                let limiter = limiter.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        limiter.request(8 * 1024, priority);
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(500));
+        stop.store(true, Ordering::Relaxed);
+        for h in handles {
+            h.join().unwrap();
+        }
+        let foreground = limiter.total_bytes(IoPriority::Foreground);
+        let background = limiter.total_bytes(IoPriority::Background);
+        assert!(background > 0);
+        assert!(
+            foreground > background * 2,
+            "foreground: {}, background: {}",
+            foreground,
+            background
+        );
+    }
+
+    #[test]
+    fn test_db_io_priority() {
+        let limiter = Arc::new(RateLimiter::new(1 << 30));
+        let store = RateLimitedStorage::new(MemStorage::default(), limiter.clone());
+        let opts = Options::<BytewiseComparator> {
+            write_buffer_size: 64 * 1024,
+            compression: CompressionType::NoCompression,
+            ..Default::default()
+        };
+        let db = WickDB::open_db(opts, "rate_limiter", store).unwrap();
+        let value = vec![b'x'; 1024];
+        for i in 0..500 {
+            db.put(
+                WriteOptions::default(),
+                format!("{:04}", i).as_bytes(),
+                &value,
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        // The flushes and compactions write the tables in background
+        let background = limiter.total_bytes(IoPriority::Background);
+        assert!(background >= 500 * 1024, "background: {}", background);
+        let foreground = limiter.total_bytes(IoPriority::Foreground);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"0042").unwrap(),
+            Some(value)
+        );
+        assert!(limiter.total_bytes(IoPriority::Foreground) > foreground);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::storage::{File, Storage};
+use crate::util::coding::{decode_fixed_32, put_fixed_32};
+use crate::util::crc32;
+use crate::util::varint::VarintU32;
+use crate::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The name of the rename journal in each directory
+pub const RENAME_JOURNAL: &str = "RENAME.journal";
+
+/// `JournaledRenameStorage` emulates a crash-consistent `rename` for the
+/// backends without an atomic rename (e.g. object stores where a rename is a
+/// copy followed by a delete).
+///
+/// A rename is done in the following steps:
+/// 1. Write the journal `RENAME.journal` recording `old` and `new` into the
+///    directory of `new`. The journal is protected by a checksum so a torn
+///    journal is detected. The journal and its directory are synced before
+///    going on.
+/// 2. Copy `old` to `new`, then sync `new` and its directory
+/// 3. Remove `old`
+/// 4. Remove the journal
+///
+/// If the process crashes in the middle, `recover_dir` redoes the copy while
+/// `old` still exists, otherwise the copy has completed and only the journal is
+/// removed. So after the recovery `new` holds either the whole content of `old`
+/// or is left untouched if the journal itself was torn, which keeps the
+/// CURRENT/MANIFEST update protocol correct.
+///
+/// All the other operations are delegated to the inner storage.
+#[derive(Clone)]
+pub struct JournaledRenameStorage<S: Storage> {
+    inner: S,
+    // Only one rename is allowed in flight since there is one journal per directory
+    rename_lock: Arc<Mutex<()>>,
+}
+
+impl<S: Storage> JournaledRenameStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            rename_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Returns the wrapped storage
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn journal_path(dir: Option<&Path>) -> PathBuf {
+        match dir {
+            Some(dir) => dir.join(RENAME_JOURNAL),
+            None => PathBuf::from(RENAME_JOURNAL),
+        }
+    }
+
+    fn path_str(path: &Path) -> Result<&str> {
+        path.to_str().ok_or_else(|| {
+            Error::InvalidArgument(format!("non UTF-8 path {:?} can't be journaled", path))
+        })
+    }
+
+    // Sync the directory containing `name`
+    fn sync_parent(&self, name: &Path) -> Result<()> {
+        match name.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => self.inner.sync_dir(dir),
+            _ => self.inner.sync_dir("."),
+        }
+    }
+
+    fn write_journal(&self, journal: &Path, old: &Path, new: &Path) -> Result<()> {
+        let mut data = vec![];
+        VarintU32::put_varint_prefixed_slice(&mut data, Self::path_str(old)?.as_bytes());
+        VarintU32::put_varint_prefixed_slice(&mut data, Self::path_str(new)?.as_bytes());
+        let crc = crc32::mask(crc32::hash(&data));
+        put_fixed_32(&mut data, crc);
+        self.remove_if_exists(journal)?;
+        let mut f = self.inner.create(journal)?;
+        f.write(&data)?;
+        f.sync()?;
+        f.close()?;
+        // The journal must be durable before `new` is touched
+        self.sync_parent(journal)
+    }
+
+    // Returns the `old` and `new` recorded in the journal or `None` if the
+    // journal is torn
+    fn read_journal(&self, journal: &Path) -> Result<Option<(PathBuf, PathBuf)>> {
+        let mut data = vec![];
+        self.inner.open(journal)?.read_all(&mut data)?;
+        if data.len() < 4 {
+            return Ok(None);
+        }
+        let (mut body, checksum) = data.split_at(data.len() - 4);
+        if crc32::unmask(decode_fixed_32(checksum)) != crc32::hash(body) {
+            return Ok(None);
+        }
+        let old = VarintU32::get_varint_prefixed_slice(&mut body);
+        let new = VarintU32::get_varint_prefixed_slice(&mut body);
+        match (old, new) {
+            (Some(old), Some(new)) => Ok(Some((
+                PathBuf::from(String::from_utf8_lossy(old).into_owned()),
+                PathBuf::from(String::from_utf8_lossy(new).into_owned()),
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    // Replace `new` by a copy of `old`. The copy is durable once it returns so
+    // `old` can be removed safely.
+    fn copy(&self, old: &Path, new: &Path) -> Result<()> {
+        let mut data = vec![];
+        self.inner.open(old)?.read_all(&mut data)?;
+        self.remove_if_exists(new)?;
+        let mut f = self.inner.create(new)?;
+        f.write(&data)?;
+        f.sync()?;
+        f.close()?;
+        self.sync_parent(new)
+    }
+
+    fn remove_if_exists(&self, name: &Path) -> Result<()> {
+        if self.inner.exists(name) {
+            self.inner.remove(name)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: Storage> Storage for JournaledRenameStorage<S> {
+    type F = S::F;
+
+    fn create<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        self.inner.create(name)
+    }
+
+    fn open<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        self.inner.open(name)
+    }
+
+    fn remove<P: AsRef<Path>>(&self, name: P) -> Result<()> {
+        self.inner.remove(name)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, dir: P, recursively: bool) -> Result<()> {
+        self.inner.remove_dir(dir, recursively)
+    }
+
+    fn exists<P: AsRef<Path>>(&self, name: P) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename<P: AsRef<Path>>(&self, old: P, new: P) -> Result<()> {
+        let (old, new) = (old.as_ref(), new.as_ref());
+        let _guard = self.rename_lock.lock().unwrap();
+        let journal = Self::journal_path(new.parent());
+        self.write_journal(&journal, old, new)?;
+        self.copy(old, new)?;
+        self.inner.remove(old)?;
+        self.inner.remove(&journal)
+    }
+
+    fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn link<P: AsRef<Path>>(&self, src: P, dst: P) -> Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn sync_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn recover_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let _guard = self.rename_lock.lock().unwrap();
+        let journal = Self::journal_path(Some(dir.as_ref()));
+        if !self.inner.exists(&journal) {
+            return Ok(());
+        }
+        match self.read_journal(&journal)? {
+            Some((old, new)) => {
+                if self.inner.exists(&old) {
+                    info!("Redo the unfinished rename from {:?} to {:?}", &old, &new);
+                    self.copy(&old, &new)?;
+                    self.inner.remove(&old)?;
+                }
+            }
+            None => warn!("Discard the torn rename journal {:?}", &journal),
+        }
+        self.inner.remove(&journal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    fn read(store: &impl Storage, name: &str) -> Vec<u8> {
+        let mut buf = vec![];
+        store.open(name).unwrap().read_all(&mut buf).unwrap();
+        buf
+    }
+
+    fn write(store: &impl Storage, name: &str, data: &[u8]) {
+        let mut f = store.create(name).unwrap();
+        f.write(data).unwrap();
+        f.close().unwrap();
+    }
+
+    #[test]
+    fn test_journaled_rename() {
+        let store = JournaledRenameStorage::new(MemStorage::default());
+        store.mkdir_all("db").unwrap();
+        write(&store, "db/a", b"new");
+        write(&store, "db/b", b"old content");
+        store.rename("db/a", "db/b").unwrap();
+        assert!(!store.exists("db/a"));
+        assert!(!store.exists("db/RENAME.journal"));
+        assert_eq!(read(&store, "db/b"), b"new");
+        store
+            .atomic_write("db/CURRENT", b"MANIFEST-000002\n")
+            .unwrap();
+        assert_eq!(read(&store, "db/CURRENT"), b"MANIFEST-000002\n");
+        assert_eq!(store.list("db").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_recover_unfinished_rename() {
+        let store = JournaledRenameStorage::new(MemStorage::default());
+        store.mkdir_all("db").unwrap();
+        let journal = Path::new("db").join(RENAME_JOURNAL);
+
+        // Crash in the middle of the copy
+        write(&store, "db/a", b"hello world");
+        write(&store, "db/b", b"hel");
+        store
+            .write_journal(&journal, Path::new("db/a"), Path::new("db/b"))
+            .unwrap();
+        store.recover_dir("db").unwrap();
+        assert!(!store.exists("db/a") && !store.exists(&journal));
+        assert_eq!(read(&store, "db/b"), b"hello world");
+
+        // Crash after removing `old`
+        store
+            .write_journal(&journal, Path::new("db/a"), Path::new("db/b"))
+            .unwrap();
+        store.recover_dir("db").unwrap();
+        assert!(!store.exists(&journal));
+        assert_eq!(read(&store, "db/b"), b"hello world");
+
+        // Crash while writing the journal
+        write(&store, "db/a", b"new");
+        store
+            .write_journal(&journal, Path::new("db/a"), Path::new("db/b"))
+            .unwrap();
+        let mut data = read(&store, journal.to_str().unwrap());
+        data.pop();
+        store.remove(&journal).unwrap();
+        write(&store, journal.to_str().unwrap(), &data);
+        store.recover_dir("db").unwrap();
+        assert!(!store.exists(&journal));
+        assert_eq!(read(&store, "db/a"), b"new");
+        assert_eq!(read(&store, "db/b"), b"hello world");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rename_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let store = JournaledRenameStorage::new(MemStorage::default());
+        store.mkdir_all("db").unwrap();
+        write(&store, "db/a", b"new");
+        let new = Path::new("db").join(OsStr::from_bytes(b"\xff"));
+        match store.rename(Path::new("db/a"), new.as_path()) {
+            Err(Error::InvalidArgument(_)) => {}
+            res => panic!("expect InvalidArgument but got {:?}", res),
+        }
+        assert_eq!(read(&store, "db/a"), b"new");
+    }
+}
@@ -0,0 +1,354 @@
+use crate::storage::mem::{clean, FileNode, MemStorage};
+use crate::storage::{File, Storage};
+use crate::util::collection::HashMap;
+use crate::Result;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// `PersistentBackend` is a flat blob store keyed by the file path, which is
+/// provided by the host environment (e.g. an IndexedDB object store, OPFS or
+/// `localStorage` through JS bindings) to persist the files of a `WasmStorage`.
+///
+/// All the files of the db are append-only, so the backend only needs to support
+/// appending to a blob.
+pub trait PersistentBackend: Send + Sync {
+    /// Returns all the persisted files and their contents
+    fn load(&self) -> Result<Vec<(String, Vec<u8>)>>;
+
+    /// Append `data` to the named blob, creating it if it does not exist
+    fn append(&self, path: &str, data: &[u8]) -> Result<()>;
+
+    /// Rename a blob, replacing the `new` one if it exists
+    fn rename(&self, old: &str, new: &str) -> Result<()>;
+
+    /// Delete the named blob. Deleting a missing blob is not an error.
+    fn delete(&self, path: &str) -> Result<()>;
+}
+
+/// A `PersistentBackend` keeping the blobs in memory.
+///
+/// The blobs outlive the `WasmStorage`s using it, so a db can be reopened in the
+/// same page. It's also handy for tests.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl PersistentBackend for MemoryBackend {
+    fn load(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    fn append(&self, path: &str, data: &[u8]) -> Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        blobs
+            .entry(path.to_owned())
+            .or_default()
+            .extend_from_slice(data);
+        Ok(())
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        if let Some(data) = blobs.remove(old) {
+            blobs.insert(new.to_owned(), data);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.blobs.lock().unwrap().remove(path);
+        Ok(())
+    }
+}
+
+/// A `Storage` for wasm32 where neither `std::fs` nor file locking is available.
+///
+/// The files live in a `MemStorage` and are written through to a
+/// `PersistentBackend` when they are flushed (synced) or closed, so the data
+/// written after the last sync is lost if the page is closed, just like an OS
+/// crash loses the unsynced data of a `FileStorage`. The directories are not
+/// persisted but implied by the file paths.
+///
+/// NOTICE: the db runs its background work in threads, so the wasm target must
+/// support threads (e.g. built with the `atomics` feature).
+#[derive(Clone)]
+pub struct WasmStorage<B: PersistentBackend> {
+    mem: MemStorage,
+    backend: Arc<B>,
+    // The persisted length of each file
+    persisted: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl<B: PersistentBackend> WasmStorage<B> {
+    /// Creates a `WasmStorage` with all the files persisted in `backend`
+    pub fn open(backend: B) -> Result<Self> {
+        let mem = MemStorage::default();
+        let mut persisted = HashMap::default();
+        for (path, data) in backend.load()? {
+            if let Some(dir) = Path::new(&path).parent() {
+                mem.mkdir_all(dir)?;
+            }
+            let mut f = mem.create(&path)?;
+            f.write(&data)?;
+            persisted.insert(path, data.len() as u64);
+        }
+        Ok(Self {
+            mem,
+            backend: Arc::new(backend),
+            persisted: Arc::new(Mutex::new(persisted)),
+        })
+    }
+
+    // The same key as the one in `MemStorage`
+    fn key<P: AsRef<Path>>(name: P) -> String {
+        clean(name).to_str().unwrap().to_owned()
+    }
+
+    // Collect all the files under `dir` and its sub-directories
+    fn collect_files(&self, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for p in self.mem.list(dir)? {
+            if self.mem.open(&p).is_ok() {
+                files.push(p);
+            } else {
+                self.collect_files(&p, files)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_file(&self, path: String, node: FileNode) -> WasmFile<B> {
+        WasmFile {
+            node,
+            path,
+            backend: self.backend.clone(),
+            persisted: self.persisted.clone(),
+        }
+    }
+}
+
+impl<B: PersistentBackend + 'static> Storage for WasmStorage<B> {
+    type F = WasmFile<B>;
+
+    fn create<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        let path = Self::key(&name);
+        // An existing file is truncated like `FileStorage` does, so neither the
+        // old content in memory nor the old blob survives
+        if self.mem.exists(&path) {
+            self.mem.remove(&path)?;
+        }
+        let node = self.mem.create(&path)?;
+        let mut persisted = self.persisted.lock().unwrap();
+        if persisted.contains_key(&path) {
+            self.backend.delete(&path)?;
+        }
+        // Persist the empty file so that it exists after reopening
+        self.backend.append(&path, &[])?;
+        persisted.insert(path.clone(), 0);
+        Ok(self.new_file(path, node))
+    }
+
+    fn open<P: AsRef<Path>>(&self, name: P) -> Result<Self::F> {
+        let path = Self::key(&name);
+        let node = self.mem.open(&path)?;
+        Ok(self.new_file(path, node))
+    }
+
+    fn remove<P: AsRef<Path>>(&self, name: P) -> Result<()> {
+        let path = Self::key(&name);
+        self.mem.remove(&path)?;
+        self.persisted.lock().unwrap().remove(&path);
+        self.backend.delete(&path)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, dir: P, recursively: bool) -> Result<()> {
+        let mut files = vec![];
+        if recursively {
+            self.collect_files(dir.as_ref(), &mut files)?;
+        }
+        self.mem.remove_dir(&dir, recursively)?;
+        let mut persisted = self.persisted.lock().unwrap();
+        for f in files {
+            let path = Self::key(&f);
+            persisted.remove(&path);
+            self.backend.delete(&path)?;
+        }
+        Ok(())
+    }
+
+    fn exists<P: AsRef<Path>>(&self, name: P) -> bool {
+        self.mem.exists(name)
+    }
+
+    fn rename<P: AsRef<Path>>(&self, old: P, new: P) -> Result<()> {
+        let (old, new) = (Self::key(&old), Self::key(&new));
+        if let Ok(node) = self.mem.open(&old) {
+            // Persist the unsynced data first so the renamed blob is complete
+            self.new_file(old.clone(), node).persist()?;
+        }
+        self.mem.rename(old.as_str(), new.as_str())?;
+        let mut persisted = self.persisted.lock().unwrap();
+        if let Some(len) = persisted.remove(&old) {
+            self.backend.rename(&old, &new)?;
+            persisted.insert(new, len);
+        }
+        Ok(())
+    }
+
+    fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        self.mem.mkdir_all(dir)
+    }
+
+    fn list<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>> {
+        self.mem.list(dir)
+    }
+}
+
+/// The `File` of `WasmStorage`
+pub struct WasmFile<B: PersistentBackend> {
+    node: FileNode,
+    path: String,
+    backend: Arc<B>,
+    persisted: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl<B: PersistentBackend> WasmFile<B> {
+    // Append the data not persisted yet to the backend
+    fn persist(&self) -> Result<()> {
+        let mut persisted = self.persisted.lock().unwrap();
+        let offset = match persisted.get(&self.path) {
+            Some(offset) => *offset,
+            // The file has been removed or renamed
+            None => return Ok(()),
+        };
+        let len = self.node.len()?;
+        if len > offset {
+            let mut buf = vec![0; (len - offset) as usize];
+            self.node.read_exact_at(&mut buf, offset)?;
+            self.backend.append(&self.path, &buf)?;
+        }
+        persisted.insert(self.path.clone(), len);
+        Ok(())
+    }
+}
+
+impl<B: PersistentBackend> File for WasmFile<B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.node.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.node.flush()?;
+        self.persist()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.node.close()?;
+        self.persist()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.node.seek(pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.node.read(buf)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        self.node.read_all(buf)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.node.len()
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.node.lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.node.unlock()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.node.read_at(buf, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BytewiseComparator, Options, ReadOptions, WickDB, WriteOptions, DB};
+
+    #[test]
+    fn test_wasm_storage_persistence() {
+        let backend = MemoryBackend::default();
+        let store = WasmStorage::open(backend.clone()).unwrap();
+        store.mkdir_all("db").unwrap();
+        let mut f = store.create("db/a").unwrap();
+        f.write(b"hello").unwrap();
+        f.flush().unwrap();
+        // Unsynced data is not persisted
+        f.write(b" world").unwrap();
+        store.create("db/b").unwrap();
+        let reopened = WasmStorage::open(backend.clone()).unwrap();
+        let mut buf = vec![];
+        reopened.open("db/a").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        assert!(reopened.exists("db/b"));
+
+        store.rename("db/a", "db/c").unwrap();
+        store.remove("db/b").unwrap();
+        let reopened = WasmStorage::open(backend.clone()).unwrap();
+        assert!(!reopened.exists("db/a") && !reopened.exists("db/b"));
+        let mut buf = vec![];
+        reopened.open("db/c").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"hello world");
+
+        // Creating an existing file truncates it
+        let mut f = store.create("db/c").unwrap();
+        f.write(b"new").unwrap();
+        f.flush().unwrap();
+        let mut buf = vec![];
+        store.open("db/c").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"new");
+        let reopened = WasmStorage::open(backend.clone()).unwrap();
+        let mut buf = vec![];
+        reopened.open("db/c").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"new");
+
+        store.mkdir_all("db/archive").unwrap();
+        store.create("db/archive/d").unwrap();
+        store.remove_dir("db", true).unwrap();
+        assert!(backend.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wasm_storage_reopen_db() {
+        let backend = MemoryBackend::default();
+        let opts = Options::<BytewiseComparator>::default;
+        let mut db =
+            WickDB::open_db(opts(), "db", WasmStorage::open(backend.clone()).unwrap()).unwrap();
+        for i in 0..100 {
+            db.put(
+                WriteOptions { sync: true },
+                format!("k{}", i).as_bytes(),
+                b"v",
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+        let mut db = WickDB::open_db(opts(), "db", WasmStorage::open(backend).unwrap()).unwrap();
+        for i in 0..100 {
+            assert_eq!(
+                db.get(ReadOptions::default(), format!("k{}", i).as_bytes())
+                    .unwrap(),
+                Some(b"v".to_vec())
+            );
+        }
+        db.close().unwrap();
+    }
+}
@@ -1,13 +1,15 @@
 use crate::cache::lru::LRUCache;
 use crate::cache::Cache;
 use crate::db::filename::{generate_filename, FileType};
+use crate::db::format::InternalKeyComparator;
+use crate::db::read_stats::ReadCounters;
 use crate::options::{Options, ReadOptions};
-use crate::sstable::block::BlockIterator;
-use crate::sstable::table::{new_table_iterator, Table, TableIterator};
+use crate::sstable::factory::{TableReader, TableReaderIterator};
 use crate::storage::Storage;
 use crate::util::comparator::Comparator;
 use crate::Result;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
 /// A `TableCache` is the cache for the sst files and the sstable in them
 pub struct TableCache<S: Storage + Clone, C: Comparator> {
@@ -15,42 +17,44 @@ pub struct TableCache<S: Storage + Clone, C: Comparator> {
     db_path: String,
     options: Arc<Options<C>>,
     // the key is the file number
-    cache: Arc<dyn Cache<u64, Arc<Table<S::F>>>>,
+    cache: Arc<dyn Cache<u64, Arc<dyn TableReader>>>,
+    // the file numbers of the tables opened in the degraded mode
+    degraded: Arc<Mutex<HashSet<u64>>>,
 }
 
 impl<S: Storage + Clone, C: Comparator + 'static> TableCache<S, C> {
     pub fn new(db_path: String, options: Arc<Options<C>>, size: usize, storage: S) -> Self {
-        let cache = Arc::new(LRUCache::<u64, Arc<Table<S::F>>>::new(size));
+        let cache = Arc::new(LRUCache::<u64, Arc<dyn TableReader>>::new(size));
         Self {
             storage,
             db_path,
             options,
             cache,
+            degraded: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
-    /// Try to find the sst file from cache. If not found, try to find the file from storage and insert it into the cache
-    pub fn find_table<TC: Comparator>(
-        &self,
-        cmp: TC,
-        file_number: u64,
-        file_size: u64,
-    ) -> Result<Arc<Table<S::F>>> {
+    /// Try to find the sst file from cache. If not found, try to find the file from storage and insert it into the cache.
+    /// The table is opened by `Options::table_factory`.
+    pub fn find_table(&self, file_number: u64, file_size: u64) -> Result<Arc<dyn TableReader>> {
         match self.cache.get(&file_number) {
             Some(v) => Ok(v),
             None => {
+                trace_span!("wickdb.open_table", file = file_number, file_size);
                 let filename = generate_filename(&self.db_path, FileType::Table, file_number);
                 let table_file = self.storage.open(&filename)?;
-                let table = Table::open(
-                    table_file,
+                let table = self.options.table_factory.open(
+                    Box::new(table_file),
                     file_number,
                     file_size,
-                    self.options.clone(),
-                    cmp,
+                    InternalKeyComparator::new(self.options.comparator.clone()),
+                    &self.options,
                 )?;
-                let value = Arc::new(table);
-                let _ = self.cache.insert(file_number, value.clone(), 1);
-                Ok(value)
+                if table.is_degraded() {
+                    self.degraded.lock().unwrap().insert(file_number);
+                }
+                let _ = self.cache.insert(file_number, table.clone(), 1);
+                Ok(table)
             }
         }
     }
@@ -58,19 +62,27 @@ impl<S: Storage + Clone, C: Comparator + 'static> TableCache<S, C> {
     /// Evict any entry for the specified file number
     pub fn evict(&self, file_number: u64) {
         self.cache.erase(&file_number);
+        self.degraded.lock().unwrap().remove(&file_number);
+    }
+
+    /// Returns the number of the tables opened in the degraded mode whose files
+    /// haven't been evicted. See `Table::is_degraded`.
+    pub fn num_degraded_tables(&self) -> usize {
+        self.degraded.lock().unwrap().len()
     }
 
     /// Returns the result of a seek to internal key `key` in specified file
-    pub fn get<TC: Comparator>(
+    pub(crate) fn get(
         &self,
-        cmp: TC,
         options: ReadOptions,
         key: &[u8],
         file_number: u64,
         file_size: u64,
-    ) -> Result<Option<BlockIterator<TC>>> {
-        let table = self.find_table(cmp.clone(), file_number, file_size)?;
-        table.internal_get(options, cmp, key)
+        stats: Option<&ReadCounters>,
+    ) -> Result<Option<TableReaderIterator>> {
+        trace_span!("wickdb.table_get", file = file_number);
+        let table = self.find_table(file_number, file_size)?;
+        table.internal_get(options, key, stats)
     }
 
     /// Create an iterator for the specified `file_number` (the corresponding
@@ -80,16 +92,14 @@ impl<S: Storage + Clone, C: Comparator + 'static> TableCache<S, C> {
     /// Entry format:
     ///     key: internal key
     ///     value: value of user key
-    pub fn new_iter<TC: Comparator>(
+    pub fn new_iter(
         &self,
-        cmp: TC,
         options: ReadOptions,
         file_number: u64,
         file_size: u64,
-    ) -> Result<TableIterator<TC, S::F>> {
-        let t = self.find_table(cmp.clone(), file_number, file_size)?;
-        let iter = new_table_iterator(cmp, t, options);
-        Ok(iter)
+    ) -> Result<TableReaderIterator> {
+        let t = self.find_table(file_number, file_size)?;
+        Ok(t.new_iter(options))
     }
 }
 
@@ -100,6 +110,7 @@ impl<S: Storage + Clone, C: Comparator> Clone for TableCache<S, C> {
             db_path: self.db_path.clone(),
             options: self.options.clone(),
             cache: self.cache.clone(),
+            degraded: self.degraded.clone(),
         }
     }
 }
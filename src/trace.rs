@@ -0,0 +1,14 @@
+/// Enters a `tracing` span at DEBUG level which is exited at the end of the
+/// current scope. The span can carry fields in the syntax of `tracing::span!`.
+///
+/// Expands to nothing unless the `tracing` feature is enabled.
+macro_rules! trace_span {
+    ($name:expr) => {
+        #[cfg(feature = "tracing")]
+        let _trace_span_guard = tracing::debug_span!($name).entered();
+    };
+    ($name:expr, $($fields:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _trace_span_guard = tracing::debug_span!($name, $($fields)+).entered();
+    };
+}
@@ -0,0 +1,155 @@
+use crate::util::collection::HashMap;
+use crate::{Error, Result};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// `LockManager` keeps the exclusive row locks of the pessimistic transactions.
+///
+/// A transaction waiting for a lock records the owner it waits for, so a lock
+/// request closing a cycle of waiting transactions fails at once instead of
+/// waiting until the timeout.
+#[derive(Default)]
+pub(crate) struct LockManager {
+    table: Mutex<LockTable>,
+    // Notified whenever some locks are released
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockTable {
+    // The transaction holding the lock of each key
+    owners: HashMap<Vec<u8>, u64>,
+    // The keys locked by each transaction
+    held: HashMap<u64, Vec<Vec<u8>>>,
+    // The transaction each blocked transaction waits for
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    // Returns true if `txn` waiting for `owner` closes a cycle
+    fn would_deadlock(&self, txn: u64, owner: u64) -> bool {
+        let mut current = owner;
+        // Each transaction waits for at most one other, so a chain longer
+        // than the waiting transactions must be a cycle not containing `txn`
+        for _ in 0..=self.waits_for.len() {
+            if current == txn {
+                return true;
+            }
+            match self.waits_for.get(&current) {
+                Some(next) => current = *next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    /// Locks `key` for the transaction `txn`, waiting at most `timeout` for the
+    /// lock held by another transaction. Locking a key held by `txn` itself
+    /// succeeds immediately.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::Busy` if the request times out or causes a deadlock.
+    pub fn lock(&self, txn: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock().unwrap();
+        loop {
+            let owner = match table.owners.get(key) {
+                Some(owner) => *owner,
+                None => {
+                    table.owners.insert(key.to_vec(), txn);
+                    table.held.entry(txn).or_default().push(key.to_vec());
+                    table.waits_for.remove(&txn);
+                    return Ok(());
+                }
+            };
+            if owner == txn {
+                table.waits_for.remove(&txn);
+                return Ok(());
+            }
+            if table.would_deadlock(txn, owner) {
+                table.waits_for.remove(&txn);
+                return Err(Error::Busy(format!(
+                    "deadlock detected when transaction {} locks key {:?} held by transaction {}",
+                    txn, key, owner
+                )));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits_for.remove(&txn);
+                return Err(Error::Busy(format!(
+                    "timeout when transaction {} locks key {:?} held by transaction {}",
+                    txn, key, owner
+                )));
+            }
+            table.waits_for.insert(txn, owner);
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Releases all the locks held by the transaction `txn`
+    pub fn unlock_all(&self, txn: u64) {
+        let mut table = self.table.lock().unwrap();
+        if let Some(keys) = table.held.remove(&txn) {
+            for key in keys {
+                table.owners.remove(&key);
+            }
+            self.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_lock_and_timeout() {
+        let locks = LockManager::default();
+        let timeout = Duration::from_millis(10);
+        locks.lock(1, b"a", timeout).unwrap();
+        locks.lock(1, b"a", timeout).unwrap();
+        assert!(matches!(locks.lock(2, b"a", timeout), Err(Error::Busy(_))));
+        locks.lock(2, b"b", timeout).unwrap();
+        locks.unlock_all(1);
+        locks.lock(2, b"a", timeout).unwrap();
+        assert!(matches!(locks.lock(1, b"b", timeout), Err(Error::Busy(_))));
+    }
+
+    #[test]
+    fn test_wait_for_release() {
+        let locks = Arc::new(LockManager::default());
+        locks.lock(1, b"a", Duration::from_secs(1)).unwrap();
+        let l = locks.clone();
+        let waiter = thread::spawn(move || l.lock(2, b"a", Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(20));
+        locks.unlock_all(1);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let locks = Arc::new(LockManager::default());
+        locks.lock(1, b"a", Duration::from_secs(1)).unwrap();
+        locks.lock(2, b"b", Duration::from_secs(1)).unwrap();
+        let l = locks.clone();
+        let waiter = thread::spawn(move || l.lock(1, b"b", Duration::from_secs(10)));
+        // Wait until transaction 1 is blocked
+        while !locks.table.lock().unwrap().waits_for.contains_key(&1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Transaction 2 closes the cycle and fails without waiting
+        let start = Instant::now();
+        assert!(matches!(
+            locks.lock(2, b"a", Duration::from_secs(10)),
+            Err(Error::Busy(_))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        locks.unlock_all(2);
+        waiter.join().unwrap().unwrap();
+    }
+}
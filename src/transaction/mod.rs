@@ -0,0 +1,74 @@
+mod lock_manager;
+pub mod optimistic;
+pub mod pessimistic;
+
+use crate::batch::WriteBatch;
+use crate::db::format::{InternalKeyComparator, ParsedInternalKey, ValueType};
+use crate::iterator::Iterator;
+use crate::mem::MemTable;
+use crate::util::collection::HashMap;
+use crate::util::comparator::BytewiseComparator;
+use crate::Result;
+
+/// The writes of a transaction buffered until it commits
+#[derive(Default, Clone)]
+pub(crate) struct WriteBuffer {
+    batch: WriteBatch,
+    // The newest value written to each key, or `None` if the key is deleted
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl WriteBuffer {
+    /// Rebuilds the buffer of the puts and deletes in `batch`
+    pub fn from_batch(batch: WriteBatch) -> Result<Self> {
+        // Enough for the head and the tallest node of each entry
+        let capacity = (batch.get_count() as usize + 1) * 512 + batch.approximate_size() * 2;
+        let mem = MemTable::new(
+            capacity,
+            InternalKeyComparator::new(BytewiseComparator::default()),
+        );
+        batch.insert_into(&mem)?;
+        let mut writes = HashMap::default();
+        let mut iter = mem.iter();
+        iter.seek_to_first();
+        while iter.valid() {
+            if let Some(pkey) = ParsedInternalKey::decode_from(iter.key()) {
+                // The newest write of a key comes first
+                if !writes.contains_key(pkey.user_key) {
+                    let value = match pkey.value_type {
+                        ValueType::Value => Some(iter.value().to_vec()),
+                        _ => None,
+                    };
+                    writes.insert(pkey.user_key.to_vec(), value);
+                }
+            }
+            iter.next();
+        }
+        Ok(Self { batch, writes })
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.put(key, value);
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.batch.delete(key);
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Returns the newest write to `key` in the buffer. The inner `None` means
+    /// the key is deleted.
+    pub fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.writes.get(key).map(|v| v.as_deref())
+    }
+
+    /// Returns the keys written
+    pub fn keys(&self) -> impl std::iter::Iterator<Item = &[u8]> {
+        self.writes.keys().map(|k| k.as_slice())
+    }
+
+    pub fn batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+}
@@ -0,0 +1,262 @@
+use crate::db::{WickDB, DB};
+use crate::options::{ReadOptions, WriteOptions};
+use crate::snapshot::Snapshot;
+use crate::storage::Storage;
+use crate::transaction::WriteBuffer;
+use crate::util::collection::HashSet;
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::sync::{Arc, Mutex};
+
+/// `OptimisticTransactionDB` wraps a `WickDB` to run the optimistic
+/// transactions on it. A transaction never blocks others: it buffers its writes
+/// and checks the conflicts only when it commits.
+pub struct OptimisticTransactionDB<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: WickDB<S, C>,
+    // Checking the conflicts and writing of a commit are done under this lock,
+    // so a commit always sees the writes of the ones before it
+    commit_lock: Mutex<()>,
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> OptimisticTransactionDB<S, C> {
+    pub fn new(db: WickDB<S, C>) -> Self {
+        Self {
+            db,
+            commit_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the underlying db. The writes done by the db directly are not
+    /// checked against the transactions committing at the same time.
+    pub fn db(&self) -> &WickDB<S, C> {
+        &self.db
+    }
+
+    /// Returns the underlying db
+    pub fn into_db(self) -> WickDB<S, C> {
+        self.db
+    }
+
+    /// Begins a transaction reading the current state of the db. The writes of
+    /// the transaction are committed with `options`.
+    pub fn begin_transaction(&self, options: WriteOptions) -> OptimisticTransaction<'_, S, C> {
+        OptimisticTransaction {
+            txn_db: self,
+            options,
+            snapshot: self.db.snapshot(),
+            buffer: WriteBuffer::default(),
+            tracked: HashSet::default(),
+        }
+    }
+}
+
+/// A transaction of an `OptimisticTransactionDB`. The reads see the db at the
+/// moment the transaction began and the writes of the transaction itself.
+///
+/// The commit fails with `Error::Busy` if a key read or written by the
+/// transaction has been written by others since the transaction began, in
+/// which case none of the writes are applied. Dropping the transaction without
+/// committing rolls it back.
+pub struct OptimisticTransaction<'a, S: Storage + Clone + 'static, C: Comparator + 'static> {
+    txn_db: &'a OptimisticTransactionDB<S, C>,
+    options: WriteOptions,
+    snapshot: Arc<Snapshot>,
+    buffer: WriteBuffer,
+    // The keys whose conflicts are checked at commit
+    tracked: HashSet<Vec<u8>>,
+}
+
+impl<'a, S: Storage + Clone, C: Comparator + 'static> OptimisticTransaction<'a, S, C> {
+    /// The sequence of the snapshot read by the transaction
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.tracked.insert(key.to_vec());
+        self.buffer.put(key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.tracked.insert(key.to_vec());
+        self.buffer.delete(key);
+    }
+
+    /// Reads `key` from the writes of the transaction, or from the snapshot of
+    /// the transaction if it's not written. `options.snapshot` is ignored.
+    pub fn get(&mut self, options: ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.buffer.get(key) {
+            return Ok(value.map(|v| v.to_vec()));
+        }
+        self.tracked.insert(key.to_vec());
+        let options = ReadOptions {
+            snapshot: Some(*self.snapshot),
+            ..options
+        };
+        self.txn_db.db.get(options, key)
+    }
+
+    /// Applies the writes of the transaction atomically if no key it read or
+    /// wrote has been written by others since it began.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::Busy` if there is a conflict.
+    pub fn commit(self) -> Result<()> {
+        let _guard = self.txn_db.commit_lock.lock().unwrap();
+        for key in self.tracked.iter() {
+            if let Some(seq) = self.txn_db.db.latest_sequence_of(key)? {
+                if seq > self.snapshot.sequence() {
+                    return Err(Error::Busy(format!(
+                        "key {:?} is written at sequence {} after the transaction began at {}",
+                        key,
+                        seq,
+                        self.snapshot.sequence()
+                    )));
+                }
+            }
+        }
+        if self.buffer.batch().is_empty() {
+            return Ok(());
+        }
+        self.txn_db
+            .db
+            .write(self.options.clone(), self.buffer.batch().clone())
+    }
+
+    /// Discards the writes of the transaction
+    pub fn rollback(self) {}
+}
+
+impl<'a, S: Storage + Clone, C: Comparator + 'static> Drop for OptimisticTransaction<'a, S, C> {
+    fn drop(&mut self) {
+        self.txn_db.db.release_snapshot(self.snapshot.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    fn new_txn_db() -> OptimisticTransactionDB<MemStorage, BytewiseComparator> {
+        let db = WickDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "optimistic",
+            MemStorage::default(),
+        )
+        .unwrap();
+        OptimisticTransactionDB::new(db)
+    }
+
+    fn get(
+        txn_db: &OptimisticTransactionDB<MemStorage, BytewiseComparator>,
+        key: &[u8],
+    ) -> Option<Vec<u8>> {
+        txn_db.db().get(ReadOptions::default(), key).unwrap()
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        let txn_db = new_txn_db();
+        txn_db
+            .db()
+            .put(WriteOptions::default(), b"a", b"va")
+            .unwrap();
+        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+        assert_eq!(
+            txn.get(ReadOptions::default(), b"a").unwrap(),
+            Some(b"va".to_vec())
+        );
+        txn.put(b"b", b"vb");
+        txn.delete(b"a");
+        assert_eq!(txn.get(ReadOptions::default(), b"a").unwrap(), None);
+        assert_eq!(
+            txn.get(ReadOptions::default(), b"b").unwrap(),
+            Some(b"vb".to_vec())
+        );
+        // Not visible before the commit
+        assert_eq!(get(&txn_db, b"b"), None);
+        txn.commit().unwrap();
+        assert_eq!(get(&txn_db, b"a"), None);
+        assert_eq!(get(&txn_db, b"b"), Some(b"vb".to_vec()));
+
+        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+        txn.put(b"c", b"vc");
+        txn.rollback();
+        assert_eq!(get(&txn_db, b"c"), None);
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let txn_db = new_txn_db();
+        let mut t1 = txn_db.begin_transaction(WriteOptions::default());
+        let mut t2 = txn_db.begin_transaction(WriteOptions::default());
+        t1.put(b"a", b"1");
+        t2.put(b"a", b"2");
+        t1.commit().unwrap();
+        assert!(matches!(t2.commit(), Err(Error::Busy(_))));
+        assert_eq!(get(&txn_db, b"a"), Some(b"1".to_vec()));
+
+        // A key read by the transaction is written by others
+        let mut t1 = txn_db.begin_transaction(WriteOptions::default());
+        assert_eq!(
+            t1.get(ReadOptions::default(), b"a").unwrap(),
+            Some(b"1".to_vec())
+        );
+        t1.put(b"b", b"from a");
+        txn_db
+            .db()
+            .delete_range(WriteOptions::default(), b"a", b"b")
+            .unwrap();
+        assert!(matches!(t1.commit(), Err(Error::Busy(_))));
+        assert_eq!(get(&txn_db, b"b"), None);
+
+        // The writes to other keys or before the transaction don't conflict
+        txn_db
+            .db()
+            .put(WriteOptions::default(), b"c", b"vc")
+            .unwrap();
+        let mut t1 = txn_db.begin_transaction(WriteOptions::default());
+        t1.get(ReadOptions::default(), b"c").unwrap();
+        t1.put(b"c", b"vc2");
+        txn_db
+            .db()
+            .put(WriteOptions::default(), b"d", b"vd")
+            .unwrap();
+        t1.commit().unwrap();
+        assert_eq!(get(&txn_db, b"c"), Some(b"vc2".to_vec()));
+    }
+
+    #[test]
+    fn test_concurrent_increments() {
+        let txn_db = Arc::new(new_txn_db());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let txn_db = txn_db.clone();
+                std::thread::spawn(move || {
+                    let mut done = 0;
+                    while done < 20 {
+                        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+                        let n = txn
+                            .get(ReadOptions::default(), b"counter")
+                            .unwrap()
+                            .map_or(0, |v| String::from_utf8(v).unwrap().parse::<u64>().unwrap());
+                        txn.put(b"counter", (n + 1).to_string().as_bytes());
+                        match txn.commit() {
+                            Ok(()) => done += 1,
+                            Err(Error::Busy(_)) => {}
+                            Err(e) => panic!("{:?}", e),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(get(&txn_db, b"counter"), Some(b"80".to_vec()));
+    }
+}
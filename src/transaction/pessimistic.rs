@@ -0,0 +1,526 @@
+use crate::batch::WriteBatch;
+use crate::db::column_family::ColumnFamilyHandle;
+use crate::db::{WickDB, DB};
+use crate::iterator::Iterator;
+use crate::options::{ReadOptions, WriteOptions};
+use crate::storage::Storage;
+use crate::transaction::lock_manager::LockManager;
+use crate::transaction::WriteBuffer;
+use crate::util::collection::HashSet;
+use crate::util::comparator::Comparator;
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// The column family keeping the writes of the prepared transactions by name
+const PREPARED_TRANSACTIONS_CF: &str = "wickdb.transaction.prepared";
+
+/// Options to control the behavior of a `TransactionDB`
+#[derive(Clone)]
+pub struct TransactionDBOptions {
+    /// How long a transaction waits for a lock held by another one before
+    /// giving up with `Error::Busy`.
+    /// Default: 1s
+    pub lock_timeout: Duration,
+}
+
+impl Default for TransactionDBOptions {
+    fn default() -> Self {
+        Self {
+            lock_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// `TransactionDB` wraps a `WickDB` to run the pessimistic transactions on it.
+/// A transaction locks every key it writes or reads by `get_for_update` until
+/// it's committed or rolled back, so a commit never conflicts.
+///
+/// A named transaction could be committed in two phases. `prepare` persists the
+/// writes of the transaction through the WAL, and the prepared transactions
+/// left by a crash are recovered by `TransactionDB::open` with their locks, so
+/// they could be committed or rolled back by the coordinator later.
+///
+/// The prepared writes are kept in a column family, so the db requires a
+/// comparator supporting the column families.
+pub struct TransactionDB<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: WickDB<S, C>,
+    options: TransactionDBOptions,
+    locks: LockManager,
+    next_id: AtomicU64,
+    prepared_cf: ColumnFamilyHandle,
+    // The names of the live transactions
+    names: Mutex<HashSet<String>>,
+    // The prepared transactions recovered or dropped but not taken yet
+    recovered: Mutex<Vec<(u64, String, WriteBuffer)>>,
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> TransactionDB<S, C> {
+    /// Wraps `db` and recovers the prepared transactions in it
+    pub fn open(db: WickDB<S, C>, options: TransactionDBOptions) -> Result<Self> {
+        let prepared_cf = match db.cf_handle(PREPARED_TRANSACTIONS_CF) {
+            Some(cf) => cf,
+            None => db.create_cf(PREPARED_TRANSACTIONS_CF)?,
+        };
+        let txn_db = Self {
+            db,
+            options,
+            locks: LockManager::default(),
+            next_id: AtomicU64::new(1),
+            prepared_cf,
+            names: Mutex::new(HashSet::default()),
+            recovered: Mutex::new(vec![]),
+        };
+        let mut iter = txn_db
+            .db
+            .iter_cf(ReadOptions::default(), &txn_db.prepared_cf)?;
+        iter.seek_to_first();
+        while iter.valid() {
+            let name = String::from_utf8(iter.key().to_vec()).map_err(|_| {
+                Error::Corruption("invalid name of a prepared transaction".to_owned())
+            })?;
+            let mut contents = iter.value().to_vec();
+            let mut batch = WriteBatch::default();
+            batch.set_contents(&mut contents);
+            let buffer = WriteBuffer::from_batch(batch)?;
+            let id = txn_db.next_id.fetch_add(1, Ordering::SeqCst);
+            for key in buffer.keys() {
+                // No one else holds a lock yet
+                txn_db.locks.lock(id, key, Duration::from_secs(0))?;
+            }
+            info!("Recovered prepared transaction {}", &name);
+            txn_db.names.lock().unwrap().insert(name.clone());
+            txn_db.recovered.lock().unwrap().push((id, name, buffer));
+            iter.next();
+        }
+        iter.status()?;
+        Ok(txn_db)
+    }
+
+    /// Returns the underlying db. The writes done by the db directly don't
+    /// respect the locks of the transactions.
+    pub fn db(&self) -> &WickDB<S, C> {
+        &self.db
+    }
+
+    /// Returns the underlying db
+    pub fn into_db(self) -> WickDB<S, C> {
+        self.db
+    }
+
+    /// Begins a transaction whose writes are committed with `options`
+    pub fn begin_transaction(&self, options: WriteOptions) -> Transaction<'_, S, C> {
+        Transaction {
+            txn_db: self,
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            options,
+            name: None,
+            state: TransactionState::Started,
+            buffer: WriteBuffer::default(),
+            savepoints: vec![],
+        }
+    }
+
+    /// Takes the prepared transactions recovered by `open` or dropped without
+    /// being committed or rolled back, which should be committed or rolled back.
+    /// Their writes are committed with `options`.
+    pub fn recovered_transactions(&self, options: WriteOptions) -> Vec<Transaction<'_, S, C>> {
+        let recovered = std::mem::take(&mut *self.recovered.lock().unwrap());
+        recovered
+            .into_iter()
+            .map(|(id, name, buffer)| Transaction {
+                txn_db: self,
+                id,
+                options: options.clone(),
+                name: Some(name),
+                state: TransactionState::Prepared,
+                buffer,
+                savepoints: vec![],
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TransactionState {
+    Started,
+    // The writes are persisted and can't be changed any more
+    Prepared,
+    // Committed or rolled back
+    Finished,
+}
+
+/// A transaction of a `TransactionDB`. The reads see the latest committed
+/// writes and the writes of the transaction itself.
+///
+/// Dropping a transaction without committing rolls it back, except the
+/// prepared one, which is only rolled back by `rollback`. A prepared transaction
+/// dropped (e.g. after its commit fails) keeps its locks and is returned by
+/// `TransactionDB::recovered_transactions` again.
+pub struct Transaction<'a, S: Storage + Clone + 'static, C: Comparator + 'static> {
+    txn_db: &'a TransactionDB<S, C>,
+    id: u64,
+    options: WriteOptions,
+    name: Option<String>,
+    state: TransactionState,
+    buffer: WriteBuffer,
+    // The buffers at each savepoint
+    savepoints: Vec<WriteBuffer>,
+}
+
+impl<'a, S: Storage + Clone, C: Comparator + 'static> Transaction<'a, S, C> {
+    /// Returns the name set by `set_name`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Names the transaction, which is required by `prepare`. The name must be
+    /// unique among the live transactions.
+    pub fn set_name(&mut self, name: &str) -> Result<()> {
+        self.check_started()?;
+        if self.name.is_some() {
+            return Err(Error::InvalidArgument(
+                "the transaction is named already".to_owned(),
+            ));
+        }
+        if !self.txn_db.names.lock().unwrap().insert(name.to_owned()) {
+            return Err(Error::InvalidArgument(format!(
+                "transaction {} already exists",
+                name
+            )));
+        }
+        self.name = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Locks `key` and writes `key -> value` in the transaction
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.lock(key)?;
+        self.buffer.put(key, value);
+        Ok(())
+    }
+
+    /// Locks `key` and deletes it in the transaction
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.lock(key)?;
+        self.buffer.delete(key);
+        Ok(())
+    }
+
+    /// Reads `key` from the writes of the transaction, or from the db if it's
+    /// not written
+    pub fn get(&self, options: ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.buffer.get(key) {
+            return Ok(value.map(|v| v.to_vec()));
+        }
+        self.txn_db.db.get(options, key)
+    }
+
+    /// Locks `key` and reads it like `get`, so the value read can't be changed
+    /// by others until the transaction ends
+    pub fn get_for_update(&mut self, options: ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key)?;
+        self.get(options, key)
+    }
+
+    /// Records the current writes of the transaction, which could be restored
+    /// by `rollback_to_savepoint`
+    pub fn set_savepoint(&mut self) {
+        self.savepoints.push(self.buffer.clone());
+    }
+
+    /// Discards the writes since the last savepoint and removes it. The locks
+    /// taken since then are kept until the transaction ends.
+    pub fn rollback_to_savepoint(&mut self) -> Result<()> {
+        self.check_started()?;
+        match self.savepoints.pop() {
+            Some(buffer) => {
+                self.buffer = buffer;
+                Ok(())
+            }
+            None => Err(Error::InvalidArgument("no savepoint is set".to_owned())),
+        }
+    }
+
+    /// Persists the writes of the named transaction as the first phase of the
+    /// two-phase commit. The transaction can't be written any more.
+    pub fn prepare(&mut self) -> Result<()> {
+        self.check_started()?;
+        let name = match &self.name {
+            Some(name) => name,
+            None => {
+                return Err(Error::InvalidArgument(
+                    "an unnamed transaction can't be prepared".to_owned(),
+                ))
+            }
+        };
+        self.txn_db.db.put_cf(
+            self.options.clone(),
+            &self.txn_db.prepared_cf,
+            name.as_bytes(),
+            self.buffer.batch().data(),
+        )?;
+        self.state = TransactionState::Prepared;
+        Ok(())
+    }
+
+    /// Applies the writes of the transaction atomically and releases its locks
+    pub fn commit(mut self) -> Result<()> {
+        let mut batch = self.buffer.batch().clone();
+        if self.state == TransactionState::Prepared {
+            // Removes the prepared writes in the same batch
+            batch.delete_cf(
+                &self.txn_db.prepared_cf,
+                self.name.as_ref().unwrap().as_bytes(),
+            );
+        }
+        if !batch.is_empty() {
+            self.txn_db.db.write(self.options.clone(), batch)?;
+        }
+        self.state = TransactionState::Finished;
+        Ok(())
+    }
+
+    /// Discards the writes of the transaction and releases its locks
+    pub fn rollback(mut self) -> Result<()> {
+        if self.state == TransactionState::Prepared {
+            self.txn_db.db.delete_cf(
+                self.options.clone(),
+                &self.txn_db.prepared_cf,
+                self.name.as_ref().unwrap().as_bytes(),
+            )?;
+        }
+        self.state = TransactionState::Finished;
+        Ok(())
+    }
+
+    fn lock(&mut self, key: &[u8]) -> Result<()> {
+        self.check_started()?;
+        self.txn_db
+            .locks
+            .lock(self.id, key, self.txn_db.options.lock_timeout)
+    }
+
+    fn check_started(&self) -> Result<()> {
+        match self.state {
+            TransactionState::Started => Ok(()),
+            TransactionState::Prepared => Err(Error::InvalidArgument(
+                "the transaction is prepared".to_owned(),
+            )),
+            TransactionState::Finished => Err(Error::InvalidArgument(
+                "the transaction is finished".to_owned(),
+            )),
+        }
+    }
+}
+
+impl<'a, S: Storage + Clone, C: Comparator + 'static> Drop for Transaction<'a, S, C> {
+    fn drop(&mut self) {
+        if self.state == TransactionState::Prepared {
+            // Only the coordinator decides a prepared transaction, so it keeps the
+            // locks and the name until it's taken again
+            warn!(
+                "Prepared transaction {:?} is dropped without being committed or rolled back",
+                self.name
+            );
+            self.txn_db.recovered.lock().unwrap().push((
+                self.id,
+                self.name.take().unwrap(),
+                std::mem::take(&mut self.buffer),
+            ));
+            return;
+        }
+        self.txn_db.locks.unlock_all(self.id);
+        if let Some(name) = &self.name {
+            self.txn_db.names.lock().unwrap().remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn open_txn_db(store: &MemStorage) -> TransactionDB<MemStorage, BytewiseComparator> {
+        let db = WickDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "pessimistic",
+            store.clone(),
+        )
+        .unwrap();
+        TransactionDB::open(
+            db,
+            TransactionDBOptions {
+                lock_timeout: Duration::from_millis(50),
+            },
+        )
+        .unwrap()
+    }
+
+    fn get(txn_db: &TransactionDB<MemStorage, BytewiseComparator>, key: &[u8]) -> Option<Vec<u8>> {
+        txn_db.db().get(ReadOptions::default(), key).unwrap()
+    }
+
+    #[test]
+    fn test_locks() {
+        let txn_db = open_txn_db(&MemStorage::default());
+        let mut t1 = txn_db.begin_transaction(WriteOptions::default());
+        let mut t2 = txn_db.begin_transaction(WriteOptions::default());
+        t1.put(b"a", b"1").unwrap();
+        assert_eq!(
+            t1.get(ReadOptions::default(), b"a").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(t2.get(ReadOptions::default(), b"a").unwrap(), None);
+        assert!(matches!(t2.put(b"a", b"2"), Err(Error::Busy(_))));
+        t2.get_for_update(ReadOptions::default(), b"b").unwrap();
+        assert!(matches!(t1.delete(b"b"), Err(Error::Busy(_))));
+        t1.commit().unwrap();
+        // The locks of `t1` are released
+        t2.put(b"a", b"2").unwrap();
+        t2.commit().unwrap();
+        assert_eq!(get(&txn_db, b"a"), Some(b"2".to_vec()));
+
+        let mut t3 = txn_db.begin_transaction(WriteOptions::default());
+        t3.put(b"c", b"3").unwrap();
+        drop(t3);
+        assert_eq!(get(&txn_db, b"c"), None);
+        let mut t4 = txn_db.begin_transaction(WriteOptions::default());
+        t4.put(b"c", b"4").unwrap();
+        t4.commit().unwrap();
+        assert_eq!(get(&txn_db, b"c"), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let txn_db = Arc::new(open_txn_db(&MemStorage::default()));
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = [(b"a", b"b"), (b"b", b"a")]
+            .iter()
+            .map(|(first, second)| {
+                let txn_db = txn_db.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut txn = txn_db.begin_transaction(WriteOptions::default());
+                    txn.put(*first, b"v").unwrap();
+                    barrier.wait();
+                    let res = txn.put(*second, b"v");
+                    if res.is_ok() {
+                        txn.commit().unwrap();
+                    }
+                    res.is_ok()
+                })
+            })
+            .collect();
+        let succeeded: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        // One of them fails by the deadlock detection or the timeout
+        assert_eq!(succeeded.iter().filter(|ok| **ok).count(), 1);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let txn_db = open_txn_db(&MemStorage::default());
+        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+        assert!(txn.rollback_to_savepoint().is_err());
+        txn.put(b"a", b"1").unwrap();
+        txn.set_savepoint();
+        txn.put(b"a", b"2").unwrap();
+        txn.put(b"b", b"2").unwrap();
+        txn.set_savepoint();
+        txn.delete(b"a").unwrap();
+        txn.rollback_to_savepoint().unwrap();
+        assert_eq!(
+            txn.get(ReadOptions::default(), b"a").unwrap(),
+            Some(b"2".to_vec())
+        );
+        txn.rollback_to_savepoint().unwrap();
+        assert_eq!(
+            txn.get(ReadOptions::default(), b"a").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(txn.get(ReadOptions::default(), b"b").unwrap(), None);
+        txn.commit().unwrap();
+        assert_eq!(get(&txn_db, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get(&txn_db, b"b"), None);
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let store = MemStorage::default();
+        let txn_db = open_txn_db(&store);
+        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+        txn.put(b"a", b"1").unwrap();
+        assert!(txn.prepare().is_err());
+        txn.set_name("t1").unwrap();
+        let mut other = txn_db.begin_transaction(WriteOptions::default());
+        assert!(other.set_name("t1").is_err());
+        txn.prepare().unwrap();
+        assert!(txn.put(b"b", b"1").is_err());
+        assert_eq!(get(&txn_db, b"a"), None);
+        txn.commit().unwrap();
+        assert_eq!(get(&txn_db, b"a"), Some(b"1".to_vec()));
+        // The name is released
+        other.set_name("t1").unwrap();
+        drop(other);
+
+        // A dropped prepared transaction is kept with its locks until it's
+        // rolled back explicitly
+        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+        txn.set_name("t4").unwrap();
+        txn.put(b"d", b"1").unwrap();
+        txn.prepare().unwrap();
+        drop(txn);
+        let mut other = txn_db.begin_transaction(WriteOptions::default());
+        assert!(matches!(other.put(b"d", b"v"), Err(Error::Busy(_))));
+        assert!(other.set_name("t4").is_err());
+        let mut recovered = txn_db.recovered_transactions(WriteOptions::default());
+        assert_eq!(recovered.len(), 1);
+        let t4 = recovered.pop().unwrap();
+        drop(recovered);
+        assert_eq!(t4.name(), Some("t4"));
+        t4.rollback().unwrap();
+        other.put(b"d", b"v").unwrap();
+        drop(other);
+        assert_eq!(get(&txn_db, b"d"), None);
+
+        // The prepared transactions survive a crash with their locks
+        for (name, key) in [("t2", b"b"), ("t3", b"c")].iter() {
+            let mut txn = txn_db.begin_transaction(WriteOptions::default());
+            txn.set_name(name).unwrap();
+            txn.put(*key, name.as_bytes()).unwrap();
+            txn.prepare().unwrap();
+            std::mem::forget(txn);
+        }
+        txn_db.into_db().close().unwrap();
+        let txn_db = open_txn_db(&store);
+        let mut recovered = txn_db.recovered_transactions(WriteOptions::default());
+        recovered.sort_by(|a, b| a.name().cmp(&b.name()));
+        assert_eq!(recovered.len(), 2);
+        assert_eq!(
+            recovered[0].get(ReadOptions::default(), b"b").unwrap(),
+            Some(b"t2".to_vec())
+        );
+        let mut txn = txn_db.begin_transaction(WriteOptions::default());
+        assert!(matches!(txn.put(b"b", b"v"), Err(Error::Busy(_))));
+        let t3 = recovered.pop().unwrap();
+        let t2 = recovered.pop().unwrap();
+        drop(recovered);
+        t2.commit().unwrap();
+        t3.rollback().unwrap();
+        txn.put(b"c", b"v").unwrap();
+        drop(txn);
+        assert_eq!(get(&txn_db, b"b"), Some(b"t2".to_vec()));
+        assert_eq!(get(&txn_db, b"c"), None);
+        txn_db.into_db().close().unwrap();
+        let txn_db = open_txn_db(&store);
+        assert!(txn_db
+            .recovered_transactions(WriteOptions::default())
+            .is_empty());
+    }
+}
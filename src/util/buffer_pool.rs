@@ -0,0 +1,139 @@
+use std::cell::{Cell, RefCell};
+use std::ops::{Deref, DerefMut};
+
+// The max number of the buffers kept by the pool of a thread
+const MAX_POOLED_BUFFERS: usize = 16;
+
+// The buffers larger than this are dropped instead of being kept by the pool,
+// so a huge block doesn't pin its memory in an idle thread
+const MAX_POOLED_CAPACITY: usize = 4 << 20;
+
+// The scratch buffers of the blocks being built, the compressed blocks and the
+// keys, which are reused by the compactions running on the same thread
+thread_local!(static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(vec![]) });
+
+// The number of the buffers allocated by the pool of the current thread
+thread_local!(static ALLOCATED: Cell<u64> = const { Cell::new(0) });
+
+/// Takes an empty buffer from the pool of the current thread, or allocates a
+/// new one if the pool is empty
+pub fn take_buffer() -> Vec<u8> {
+    POOL.with(|pool| pool.borrow_mut().pop())
+        .unwrap_or_else(|| {
+            ALLOCATED.with(|n| n.set(n.get() + 1));
+            vec![]
+        })
+}
+
+/// Returns the buffer to the pool of the current thread. The buffer is dropped
+/// if it's too large or the pool is full.
+pub fn recycle_buffer(mut buf: Vec<u8>) {
+    if buf.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    buf.clear();
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(buf);
+        }
+    })
+}
+
+// Returns the number of the buffers allocated by the pool of the current thread
+// so far. It stops growing once the pool is warmed up by the first tables built
+// on the thread.
+#[cfg(test)]
+pub fn allocated_buffers() -> u64 {
+    ALLOCATED.with(|n| n.get())
+}
+
+/// `PooledBuffer` is a `Vec<u8>` taken from the pool of the current thread and
+/// returned to the pool of the dropping thread when dropped.
+#[derive(Debug)]
+pub struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    pub fn new() -> Self {
+        PooledBuffer(take_buffer())
+    }
+}
+
+impl Default for PooledBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&[u8]> for PooledBuffer {
+    fn from(data: &[u8]) -> Self {
+        let mut buf = Self::new();
+        buf.extend_from_slice(data);
+        buf
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        recycle_buffer(std::mem::take(&mut self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_buffer_pool() {
+        // Each test runs in its own thread so the pool starts empty
+        thread::spawn(|| {
+            let mut buf = PooledBuffer::new();
+            buf.extend_from_slice(b"hello");
+            let ptr = buf.as_ptr();
+            drop(buf);
+            assert_eq!(allocated_buffers(), 1);
+            // The memory is reused and the content is cleared
+            let buf = PooledBuffer::new();
+            assert!(buf.is_empty());
+            assert_eq!(buf.as_ptr(), ptr);
+            assert!(buf.capacity() >= 5);
+            assert_eq!(allocated_buffers(), 1);
+
+            let other = PooledBuffer::new();
+            assert_eq!(allocated_buffers(), 2);
+            drop(other);
+            drop(buf);
+            let _a = PooledBuffer::new();
+            let _b = PooledBuffer::new();
+            assert_eq!(allocated_buffers(), 2);
+
+            // The too large ones are dropped
+            recycle_buffer(vec![0; MAX_POOLED_CAPACITY + 1]);
+            POOL.with(|pool| assert!(pool.borrow().is_empty()));
+            drop(_a);
+            POOL.with(|pool| assert_eq!(pool.borrow().len(), 1));
+            // At most `MAX_POOLED_BUFFERS` are kept
+            for _ in 0..MAX_POOLED_BUFFERS + 1 {
+                recycle_buffer(vec![0; 1]);
+            }
+            POOL.with(|pool| assert_eq!(pool.borrow().len(), MAX_POOLED_BUFFERS));
+        })
+        .join()
+        .unwrap();
+    }
+}
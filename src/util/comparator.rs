@@ -1,5 +1,9 @@
 use std::cmp::{min, Ordering};
 
+/// The size of the timestamps suffixed to the user keys by the comparators
+/// with timestamps. A timestamp is a big-endian u64.
+pub const TIMESTAMP_SIZE: usize = 8;
+
 /// Comparator 对象提供了“Slice”之间的总顺序，
 // 通常用在如 SSTables或数据库
 /// 比较器实现必须是线程安全的，需要多个线程同时调用
@@ -28,6 +32,52 @@ pub trait Comparator: Send + Sync + Clone + Default {
     /// 这些方法加在一起，为数据结构如 SSTables 或数据库提供了一个全面的键管理框架，能够有效地支持插入、删除、查找和索引操作。
     /// 实现这个 Comparator trait 需要考虑线程安全，因为可能会从多个线程并发调用其方法，所以它包含了 Send 和 Sync trait，确保可以安全地在多线程环境中使用。
     fn successor(&self, key: &[u8]) -> Vec<u8>;
+
+    /// 用户键末尾携带的时间戳的字节数，0 表示用户键不带时间戳，否则必须是 `TIMESTAMP_SIZE`。
+    /// 带时间戳的用户键先按去掉时间戳的部分排序，同一个键的不同时间戳按从新到旧排序，
+    /// 读取时只能看到不晚于 `ReadOptions::timestamp` 的版本。
+    fn timestamp_size(&self) -> usize {
+        0
+    }
+
+    /// 比较两个带时间戳的用户键去掉时间戳后的部分
+    fn compare_without_timestamp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.compare(a, b)
+    }
+
+    /// 把键映射成一个保序的数值：对任意 `compare(a, b) == Less` 都有
+    /// `numeric_key(a) <= numeric_key(b)`。返回 `Some` 的比较器声明了这种映射，
+    /// block 的 seek 会在重启点上用插值查找代替二分查找，对均匀分布的数值键可以减少比较次数。
+    /// 默认返回 `None`，即总是使用二分查找。
+    fn numeric_key(&self, _key: &[u8]) -> Option<u64> {
+        None
+    }
+
+    /// 如果比较器和 `BytewiseComparator` 一样按字节序比较键，返回 true。
+    /// 列族的键共享同一个前缀，只有按字节序比较时才连续排列，所以列族要求该方法返回 true。
+    fn is_bytewise(&self) -> bool {
+        false
+    }
+}
+
+/// Returns `key` suffixed with the timestamp `ts`
+pub fn key_with_timestamp(key: &[u8], ts: u64) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + TIMESTAMP_SIZE);
+    k.extend_from_slice(key);
+    k.extend_from_slice(&ts.to_be_bytes());
+    k
+}
+
+/// Splits a user key into the key without timestamp and the timestamp. The
+/// timestamp is 0 if the key is too short to have one.
+pub fn split_timestamp(key: &[u8]) -> (&[u8], u64) {
+    if key.len() < TIMESTAMP_SIZE {
+        return (key, 0);
+    }
+    let (k, ts) = key.split_at(key.len() - TIMESTAMP_SIZE);
+    let mut buf = [0; TIMESTAMP_SIZE];
+    buf.copy_from_slice(ts);
+    (k, u64::from_be_bytes(buf))
 }
 
 #[derive(Default, Clone, Copy)]
@@ -83,6 +133,86 @@ impl Comparator for BytewiseComparator {
         }
         key.to_owned()
     }
+
+    #[inline]
+    fn is_bytewise(&self) -> bool {
+        true
+    }
+}
+
+/// A bytewise comparator for the keys encoded as big-endian u64s (e.g. the ids
+/// or the timestamps), which declares the first 8 bytes of the keys as their
+/// numeric values so the blocks are searched by interpolation. The keys of other
+/// lengths are still ordered bytewise.
+#[derive(Default, Clone, Copy)]
+pub struct BigEndianU64Comparator {}
+
+impl Comparator for BigEndianU64Comparator {
+    #[inline]
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn name(&self) -> &str {
+        "wickdb.BigEndianU64Comparator"
+    }
+
+    fn separator(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        BytewiseComparator::default().separator(a, b)
+    }
+
+    fn successor(&self, key: &[u8]) -> Vec<u8> {
+        BytewiseComparator::default().successor(key)
+    }
+
+    // The shorter keys are padded with zeros, which keeps the bytewise order
+    #[inline]
+    fn numeric_key(&self, key: &[u8]) -> Option<u64> {
+        let mut buf = [0; 8];
+        let n = min(key.len(), 8);
+        buf[..n].copy_from_slice(&key[..n]);
+        Some(u64::from_be_bytes(buf))
+    }
+
+    #[inline]
+    fn is_bytewise(&self) -> bool {
+        true
+    }
+}
+
+/// A comparator ordering the user keys suffixed with the u64 timestamps (see
+/// `key_with_timestamp`). The keys without timestamps are ordered bytewise and
+/// the timestamps of the same key are ordered from the newest to the oldest.
+#[derive(Default, Clone, Copy)]
+pub struct BytewiseComparatorWithU64Ts {}
+
+impl Comparator for BytewiseComparatorWithU64Ts {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a, a_ts) = split_timestamp(a);
+        let (b, b_ts) = split_timestamp(b);
+        a.cmp(b).then(b_ts.cmp(&a_ts))
+    }
+
+    fn name(&self) -> &str {
+        "wickdb.BytewiseComparator.u64ts"
+    }
+
+    // The keys are not shortened since a key without a whole timestamp is invalid
+    fn separator(&self, a: &[u8], _b: &[u8]) -> Vec<u8> {
+        a.to_owned()
+    }
+
+    fn successor(&self, key: &[u8]) -> Vec<u8> {
+        key.to_owned()
+    }
+
+    fn timestamp_size(&self) -> usize {
+        TIMESTAMP_SIZE
+    }
+
+    fn compare_without_timestamp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        split_timestamp(a).0.cmp(split_timestamp(b).0)
+    }
 }
 
 #[cfg(test)]
@@ -132,4 +262,29 @@ mod tests {
             assert_eq!(res, expect)
         }
     }
+
+    #[test]
+    fn test_bytewise_comparator_with_u64_ts() {
+        let c = BytewiseComparatorWithU64Ts::default();
+        let mut keys = [
+            key_with_timestamp(b"b", 1),
+            key_with_timestamp(b"a", 1),
+            key_with_timestamp(b"a", 300),
+            key_with_timestamp(b"ab", 2),
+            key_with_timestamp(b"a", 2),
+        ];
+        keys.sort_by(|a, b| c.compare(a, b));
+        let expect: Vec<(&[u8], u64)> =
+            vec![(b"a", 300), (b"a", 2), (b"a", 1), (b"ab", 2), (b"b", 1)];
+        let got: Vec<(&[u8], u64)> = keys.iter().map(|k| split_timestamp(k)).collect();
+        assert_eq!(got, expect);
+        assert_eq!(
+            c.compare_without_timestamp(&keys[0], &keys[2]),
+            Ordering::Equal
+        );
+        assert_eq!(
+            c.compare_without_timestamp(&keys[2], &keys[3]),
+            Ordering::Less
+        );
+    }
 }
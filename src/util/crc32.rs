@@ -2,7 +2,7 @@ use crc32fast::Hasher;
 
 const MASK_DELTA: u32 = 0xa282ead8;
 
-/// Returns a `u32` crc checksum for give data
+/// Returns the IEEE CRC-32 (not CRC-32C) checksum of `data`
 pub fn hash(data: &[u8]) -> u32 {
     let mut h = Hasher::new();
     h.update(data);
@@ -0,0 +1,172 @@
+// CRC-32C (Castagnoli polynomial) with runtime dispatch.
+//
+// `crc32` computes the IEEE CRC-32 which has no dedicated instruction. CRC-32C is
+// computed by the `crc32` instruction of SSE4.2 on x86_64 and the CRC extension of
+// ARMv8, which are detected at runtime. The other CPUs fall back to a
+// slicing-by-8 table implementation.
+
+// The reversed Castagnoli polynomial
+const POLY: u32 = 0x82f6_3b78;
+
+static TABLE: [[u32; 256]; 8] = make_table();
+
+const fn make_table() -> [[u32; 256]; 8] {
+    let mut table = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[0][i] = crc;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 256 {
+        let mut k = 1;
+        while k < 8 {
+            let prev = table[k - 1][i];
+            table[k][i] = (prev >> 8) ^ table[0][(prev & 0xff) as usize];
+            k += 1;
+        }
+        i += 1;
+    }
+    table
+}
+
+/// Returns the CRC-32C checksum of `data`
+#[inline]
+pub fn hash(data: &[u8]) -> u32 {
+    extend(0, data)
+}
+
+/// Returns the CRC-32C checksum of the concatenation of the data whose checksum
+/// is `crc` and `data`
+pub fn extend(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            // Safety: the CPU supports SSE4.2
+            return unsafe { extend_sse42(crc, data) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("crc") {
+            // Safety: the CPU supports the CRC extension
+            return unsafe { extend_armv8(crc, data) };
+        }
+    }
+    extend_software(crc, data)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn extend_sse42(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut crc = u64::from(!crc);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word));
+    }
+    let mut crc = crc as u32;
+    for &b in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, b);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "crc")]
+unsafe fn extend_armv8(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+    let mut crc = !crc;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        crc = __crc32cd(crc, u64::from_le_bytes(word));
+    }
+    for &b in chunks.remainder() {
+        crc = __crc32cb(crc, b);
+    }
+    !crc
+}
+
+// slicing-by-8
+fn extend_software(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let lo = crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let hi = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        crc = TABLE[7][(lo & 0xff) as usize]
+            ^ TABLE[6][((lo >> 8) & 0xff) as usize]
+            ^ TABLE[5][((lo >> 16) & 0xff) as usize]
+            ^ TABLE[4][(lo >> 24) as usize]
+            ^ TABLE[3][(hi & 0xff) as usize]
+            ^ TABLE[2][((hi >> 8) & 0xff) as usize]
+            ^ TABLE[1][((hi >> 16) & 0xff) as usize]
+            ^ TABLE[0][(hi >> 24) as usize];
+    }
+    for &b in chunks.remainder() {
+        crc = (crc >> 8) ^ TABLE[0][((crc ^ u32::from(b)) & 0xff) as usize];
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_standard_crc32c_results() {
+        let mut buf = vec![0u8; 32];
+        let mut cases = vec![];
+        cases.push((buf.clone(), 0x8a91_36aa));
+        buf.iter_mut().for_each(|b| *b = 0xff);
+        cases.push((buf.clone(), 0x62a8_ab43));
+        buf.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        cases.push((buf.clone(), 0x46dd_794e));
+        buf.iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = 31 - i as u8);
+        cases.push((buf.clone(), 0x113f_db5c));
+        cases.push((b"123456789".to_vec(), 0xe306_9283));
+        for (data, expect) in cases {
+            assert_eq!(hash(&data), expect);
+            assert_eq!(extend_software(0, &data), expect);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_matches_software() {
+        let mut rng = rand::thread_rng();
+        let data: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+        // all the lengths and alignments of the tails
+        for start in 0..8 {
+            for end in (start..data.len()).step_by(7) {
+                let d = &data[start..end];
+                assert_eq!(hash(d), extend_software(0, d));
+            }
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        assert_eq!(hash(b"hello world"), extend(hash(b"hello "), b"world"));
+        assert_eq!(
+            extend_software(extend_software(0, b"hello "), b"world"),
+            hash(b"hello world")
+        );
+    }
+}
@@ -37,13 +37,13 @@ pub fn hash(data: &[u8], seed: u32) -> u32 {
     // Pick up remaining bytes
     let diff = n - i;
     if diff >= 3 {
-        h += (u32::from(data[i + 2])) << 16
+        h = h.wrapping_add(u32::from(data[i + 2]) << 16)
     };
     if diff >= 2 {
-        h += (u32::from(data[i + 1])) << 8
+        h = h.wrapping_add(u32::from(data[i + 1]) << 8)
     };
     if diff >= 1 {
-        h += u32::from(data[i]);
+        h = h.wrapping_add(u32::from(data[i]));
         h = h.wrapping_mul(m);
         h ^= h >> 24;
     }
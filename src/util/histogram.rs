@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The bucket `i` holds the values in `[2^(i-1), 2^i)` and the bucket 0 holds 0.
+// The last bucket also holds all the values larger than its range.
+const NUM_BUCKETS: usize = 48;
+
+/// `Histogram` records the distribution of values, e.g. latencies in nanoseconds,
+/// in exponential buckets without locking.
+pub struct Histogram {
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    buckets: Vec<AtomicU64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+            buckets: (0..NUM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, value: u64) {
+        let bucket = (64 - value.leading_zeros() as usize).min(NUM_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the recorded values
+    pub fn data(&self) -> HistogramData {
+        let count = self.count.load(Ordering::Relaxed);
+        HistogramData {
+            count,
+            sum: self.sum.load(Ordering::Relaxed),
+            min: if count == 0 {
+                0
+            } else {
+                self.min.load(Ordering::Relaxed)
+            },
+            max: self.max.load(Ordering::Relaxed),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// A snapshot of a `Histogram`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramData {
+    /// The number of the recorded values
+    pub count: u64,
+    /// The sum of the recorded values
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    /// The number of values in each bucket. The bucket `i` holds the values in
+    /// `[2^(i-1), 2^i)`, the bucket 0 holds 0 and the last bucket also holds
+    /// all the larger values.
+    pub buckets: Vec<u64>,
+}
+
+impl HistogramData {
+    pub fn average(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Returns the estimated value below which `p` percent of the values fall.
+    /// The values are assumed to be evenly distributed in a bucket.
+    pub fn percentile(&self, p: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let threshold = self.count as f64 * p.clamp(0.0, 100.0) / 100.0;
+        let mut sum = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            sum += n;
+            if sum as f64 >= threshold && *n > 0 {
+                let (low, high) = match i {
+                    0 => (0.0, 0.0),
+                    // The last bucket is unbounded
+                    _ if i == NUM_BUCKETS - 1 => ((1u64 << (i - 1)) as f64, self.max as f64),
+                    _ => ((1u64 << (i - 1)) as f64, (1u64 << i) as f64),
+                };
+                let pos = (threshold - (sum - n) as f64) / *n as f64;
+                let value = low + (high - low) * pos;
+                return value.clamp(self.min as f64, self.max as f64);
+            }
+        }
+        self.max as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::default();
+        assert_eq!(h.data().count, 0);
+        assert_eq!(h.data().min, 0);
+        assert_eq!(h.data().percentile(50.0), 0.0);
+        for v in 1..=100 {
+            h.record(v);
+        }
+        h.record(0);
+        let data = h.data();
+        assert_eq!(data.count, 101);
+        assert_eq!(data.sum, 5050);
+        assert_eq!(data.min, 0);
+        assert_eq!(data.max, 100);
+        assert_eq!(data.buckets[0], 1);
+        assert_eq!(data.buckets[1], 1);
+        // [64, 128)
+        assert_eq!(data.buckets[7], 37);
+        assert!((data.average() - 50.0).abs() < 1e-9);
+        let median = data.percentile(50.0);
+        assert!((32.0..64.0).contains(&median), "median: {}", median);
+        assert_eq!(data.percentile(100.0), 100.0);
+        assert_eq!(data.percentile(0.0), 0.0);
+
+        h.record(u64::MAX);
+        assert_eq!(h.data().buckets[NUM_BUCKETS - 1], 1);
+        assert_eq!(h.data().percentile(100.0), u64::MAX as f64);
+    }
+}
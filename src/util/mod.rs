@@ -13,9 +13,13 @@
 
 pub mod coding;
 pub mod collection;
+pub mod buffer_pool;
 pub mod comparator;
 pub mod crc32;
+pub mod crc32c;
 pub mod hash;
+pub mod histogram;
 pub mod reporter;
 pub mod slice;
+pub mod slice_transform;
 pub mod varint;
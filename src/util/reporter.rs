@@ -24,6 +24,7 @@ pub struct LogReporter {
 struct LogReporterInner {
     ok: bool,
     reason: String,
+    dropped_bytes: u64,
 }
 
 impl LogReporter {
@@ -32,6 +33,7 @@ impl LogReporter {
             inner: Rc::new(RefCell::new(LogReporterInner {
                 ok: true,
                 reason: "".to_owned(),
+                dropped_bytes: 0,
             })),
         }
     }
@@ -43,11 +45,18 @@ impl LogReporter {
             Err(Error::Corruption(inner.reason.clone()))
         }
     }
+
+    /// Returns the total bytes dropped due to the corruptions
+    pub fn dropped_bytes(&self) -> u64 {
+        self.inner.borrow().dropped_bytes
+    }
 }
 
 impl Reporter for LogReporter {
-    fn corruption(&mut self, _bytes: u64, reason: &str) {
-        self.inner.borrow_mut().ok = false;
-        self.inner.borrow_mut().reason = reason.to_owned();
+    fn corruption(&mut self, bytes: u64, reason: &str) {
+        let mut inner = self.inner.borrow_mut();
+        inner.ok = false;
+        inner.reason = reason.to_owned();
+        inner.dropped_bytes += bytes;
     }
 }
@@ -0,0 +1,48 @@
+/// `SliceTransform` extracts the prefix of a user key, which is used to add
+/// the key prefixes into the filters so a prefix seek could skip the blocks
+/// without any key of the prefix.
+///
+/// The prefixes of the keys in a table are recorded by the name of the
+/// extractor. Changing the extraction logic must come with a new name,
+/// otherwise the filters built by the old one will be used.
+pub trait SliceTransform: Send + Sync {
+    /// Returns the name of this transform
+    fn name(&self) -> &str;
+
+    /// Returns the prefix of the given key. The key must be `in_domain`.
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8];
+
+    /// Returns whether the key has a prefix. The keys out of the domain are
+    /// not added into the filters as prefixes.
+    fn in_domain(&self, key: &[u8]) -> bool;
+}
+
+/// A `SliceTransform` taking the first `n` bytes of a key as its prefix.
+/// The keys shorter than `n` bytes have no prefix.
+pub struct FixedPrefixTransform {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefixTransform {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            name: format!("wickdb.FixedPrefix.{}", len),
+        }
+    }
+}
+
+impl SliceTransform for FixedPrefixTransform {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..self.len]
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.len
+    }
+}
@@ -0,0 +1,65 @@
+use crate::compaction::{Compaction, CompactionReason};
+use crate::storage::Storage;
+use crate::table_cache::TableCache;
+use crate::util::comparator::Comparator;
+use crate::version::version_set::total_file_size;
+use crate::version::Version;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Picks a FIFO compaction dropping the oldest level 0 tables of `version`, or
+/// returns `None` if nothing should be dropped.
+///
+/// The tables older than `fifo_ttl` are dropped first, and then the oldest of the
+/// rest until their total size is within `fifo_max_table_files_size`. The ages
+/// are read from the `creation_time` property of the tables, and a table without
+/// it never expires.
+pub fn pick_fifo_compaction<S: Storage + Clone, C: Comparator + 'static>(
+    version: &Arc<Version<C>>,
+    table_cache: &TableCache<S, C>,
+) -> Option<Compaction<C>> {
+    let options = &version.options;
+    // The oldest tables come first
+    let mut files = version.files[0].clone();
+    files.sort_by_key(|f| f.number);
+    let mut expired = 0;
+    if let Some(ttl) = options.fifo_ttl {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        expired = files
+            .iter()
+            .take_while(|f| match table_cache.find_table(f.number, f.file_size) {
+                Ok(table) => table.properties().is_some_and(|p| {
+                    p.creation_time > 0 && p.creation_time.saturating_add(ttl.as_secs()) <= now
+                }),
+                Err(e) => {
+                    warn!(
+                        "Fail to read the creation time of table #{}: {:?}",
+                        f.number, e
+                    );
+                    false
+                }
+            })
+            .count();
+    }
+    let mut dropped = expired;
+    let mut total = total_file_size(&files[dropped..]);
+    while dropped < files.len() && total > options.fifo_max_table_files_size {
+        total -= files[dropped].file_size;
+        dropped += 1;
+    }
+    if dropped == 0 {
+        return None;
+    }
+    let reason = if expired > 0 {
+        CompactionReason::FifoTtl
+    } else {
+        CompactionReason::FifoMaxSize
+    };
+    let mut c = Compaction::new(options.clone(), 0, reason);
+    c.output_level = 0;
+    c.inputs.base = files[..dropped].to_vec();
+    c.input_version = Some(version.clone());
+    Some(c)
+}
@@ -0,0 +1,315 @@
+use crate::db::filename::{generate_filename, parse_filename, update_current, FileType};
+use crate::db::format::{
+    InternalKey, InternalKeyComparator, ParsedInternalKey, LEGACY_SEQUENCE_BITS, SEQUENCE_BITS,
+};
+use crate::db::range_del::extend_key_range;
+use crate::iterator::Iterator;
+use crate::options::{Options, ReadOptions};
+use crate::record::writer::Writer;
+use crate::storage::{File, Storage};
+use crate::util::comparator::Comparator;
+use crate::version::version_edit::VersionEdit;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// `ManifestBuilder` constructs a MANIFEST offline from a list of sst files in
+/// the db directory, which is used to repair a db whose MANIFEST is lost, to
+/// assemble a db from exported tables or by disaster-recovery tooling.
+///
+/// The smallest / largest keys and the max sequence number of each table are
+/// read from the file. `finish` writes a new MANIFEST and points CURRENT to it,
+/// so the db must not be opened while building.
+///
+/// NOTE: The lookups visit the tables by decreasing file numbers, so if two
+/// tables contain the same user key, the one with the newer entries must have
+/// the larger file number.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut builder = ManifestBuilder::new(storage, "db", Options::default());
+/// builder.add_table(0, 5)?.add_table(6, 7)?;
+/// let manifest_number = builder.finish()?;
+/// ```
+pub struct ManifestBuilder<S: Storage, C: Comparator> {
+    storage: S,
+    db_path: String,
+    options: Arc<Options<C>>,
+    icmp: InternalKeyComparator<C>,
+    edit: VersionEdit,
+    // the max sequence number in the added tables
+    max_sequence: u64,
+    last_sequence: Option<u64>,
+    log_number: u64,
+}
+
+impl<S: Storage, C: Comparator + 'static> ManifestBuilder<S, C> {
+    pub fn new(storage: S, db_path: &str, options: Options<C>) -> Self {
+        let mut edit = VersionEdit::new(options.max_levels);
+        edit.set_comparator_name(options.comparator.name().to_owned());
+        if SEQUENCE_BITS != LEGACY_SEQUENCE_BITS {
+            edit.set_sequence_bits(SEQUENCE_BITS);
+        }
+        Self {
+            storage,
+            db_path: db_path.to_owned(),
+            icmp: InternalKeyComparator::new(options.comparator.clone()),
+            options: Arc::new(options),
+            edit,
+            max_sequence: 0,
+            last_sequence: None,
+            log_number: 0,
+        }
+    }
+
+    /// Adds the table `file_number` in the db directory to `level`. The key range
+    /// and the max sequence number are read from the table.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::InvalidArgument` if the level is out of range or the table
+    /// is empty, and the error of reading the table.
+    pub fn add_table(&mut self, level: usize, file_number: u64) -> Result<&mut Self> {
+        if level >= self.options.max_levels {
+            return Err(Error::InvalidArgument(format!(
+                "level {} exceeds the max level {}",
+                level,
+                self.options.max_levels - 1
+            )));
+        }
+        let filename = generate_filename(&self.db_path, FileType::Table, file_number);
+        let file = self.storage.open(&filename)?;
+        let file_size = file.len()?;
+        let table = self.options.table_factory.open(
+            Box::new(file),
+            file_number,
+            file_size,
+            self.icmp.clone(),
+            &self.options,
+        )?;
+        let mut smallest = InternalKey::default();
+        let mut largest = InternalKey::default();
+        let mut iter = table.clone().new_iter(ReadOptions::default());
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            match ParsedInternalKey::decode_from(key) {
+                Some(parsed) => self.max_sequence = self.max_sequence.max(parsed.seq),
+                None => {
+                    return Err(Error::Corruption(format!(
+                        "bad internal key in table {}",
+                        file_number
+                    )))
+                }
+            }
+            if smallest.is_empty() {
+                smallest = InternalKey::decoded_from(key);
+            }
+            largest = InternalKey::decoded_from(key);
+            iter.next();
+        }
+        iter.status()?;
+        let tombstones = table.range_tombstones();
+        extend_key_range(&self.icmp, tombstones, &mut smallest, &mut largest);
+        for t in tombstones {
+            self.max_sequence = self.max_sequence.max(t.seq);
+        }
+        if smallest.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "table {} is empty",
+                file_number
+            )));
+        }
+        self.edit
+            .add_file(level, file_number, file_size, smallest, largest);
+        Ok(self)
+    }
+
+    /// Sets the last sequence number of the db. It's the max sequence number in
+    /// the added tables by default and can't be less than that.
+    pub fn set_last_sequence(&mut self, seq: u64) -> &mut Self {
+        self.last_sequence = Some(seq);
+        self
+    }
+
+    /// Sets the number of the WAL to recover from. The WALs whose numbers are not
+    /// less than it are replayed when opening the db. Default is 0, which
+    /// replays all the WALs in the directory.
+    pub fn set_log_number(&mut self, number: u64) -> &mut Self {
+        self.log_number = number;
+        self
+    }
+
+    /// Writes the MANIFEST and updates CURRENT. Returns the file number of the
+    /// new MANIFEST.
+    ///
+    /// # Error
+    ///
+    /// Returns `Error::InvalidArgument` if the tables overlap in a level other
+    /// than level 0 or the last sequence number is less than the ones in the tables.
+    pub fn finish(mut self) -> Result<u64> {
+        let last_sequence = self.last_sequence.unwrap_or(self.max_sequence);
+        if last_sequence < self.max_sequence {
+            return Err(Error::InvalidArgument(format!(
+                "last sequence {} is less than the max sequence {} in the tables",
+                last_sequence, self.max_sequence
+            )));
+        }
+        self.check_overlapping()?;
+        // The MANIFEST never reuses a file number in the directory
+        let mut max_number = self.log_number;
+        for path in self.storage.list(&self.db_path)? {
+            if let Some((_, number)) = parse_filename(&path) {
+                max_number = max_number.max(number);
+            }
+        }
+        for (_, f) in self.edit.file_delta.new_files.iter() {
+            max_number = max_number.max(f.number);
+        }
+        let manifest_number = max_number + 1;
+        self.edit.set_log_number(self.log_number);
+        self.edit.set_next_file(manifest_number + 1);
+        self.edit.set_last_sequence(last_sequence);
+
+        let manifest_filename =
+            generate_filename(&self.db_path, FileType::Manifest, manifest_number);
+        debug!("Build manifest file: {}", &manifest_filename);
+        let mut writer = Writer::new(self.storage.create(&manifest_filename)?);
+        let mut record = vec![];
+        self.edit.encode_to(&mut record);
+        let res = writer
+            .add_record(&record)
+            .and_then(|_| writer.sync())
+            .and_then(|_| update_current(&self.storage, &self.db_path, manifest_number));
+        if let Err(e) = res {
+            self.storage.remove(&manifest_filename)?;
+            return Err(e);
+        }
+        Ok(manifest_number)
+    }
+
+    // The files in a level other than level 0 must not overlap
+    fn check_overlapping(&self) -> Result<()> {
+        for level in 1..self.options.max_levels {
+            let mut files: Vec<_> = self
+                .edit
+                .file_delta
+                .new_files
+                .iter()
+                .filter(|(l, _)| *l == level)
+                .map(|(_, f)| f)
+                .collect();
+            files.sort_by(|a, b| self.icmp.compare(a.smallest.data(), b.smallest.data()));
+            for w in files.windows(2) {
+                if self.icmp.compare(w[0].largest.data(), w[1].smallest.data()) != Ordering::Less {
+                    return Err(Error::InvalidArgument(format!(
+                        "table {} overlaps with table {} in level {}",
+                        w[0].number, w[1].number, level
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::ValueType;
+    use crate::sstable::table::TableBuilder;
+    use crate::storage::mem::MemStorage;
+    use crate::{BytewiseComparator, WickDB, WriteOptions, DB};
+
+    fn write_table(storage: &MemStorage, number: u64, entries: &[(&str, u64)]) {
+        let options = Arc::new(Options::<BytewiseComparator>::default());
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let file = storage
+            .create(generate_filename("db", FileType::Table, number))
+            .unwrap();
+        let mut builder = TableBuilder::new(file, icmp, &options);
+        for (key, seq) in entries {
+            let ikey = InternalKey::new(key.as_bytes(), *seq, ValueType::Value);
+            builder
+                .add(ikey.data(), format!("{}{}", key, seq).as_bytes())
+                .unwrap();
+        }
+        builder.finish(true).unwrap();
+    }
+
+    #[test]
+    fn test_build_manifest() {
+        let storage = MemStorage::default();
+        storage.mkdir_all("db").unwrap();
+        write_table(&storage, 5, &[("a", 1), ("d", 8)]);
+        write_table(&storage, 7, &[("e", 10), ("f", 9)]);
+        write_table(&storage, 9, &[("a", 3), ("b", 2), ("c", 1)]);
+        let mut builder = ManifestBuilder::new(
+            storage.clone(),
+            "db",
+            Options::<BytewiseComparator>::default(),
+        );
+        builder
+            .add_table(0, 9)
+            .unwrap()
+            .add_table(1, 5)
+            .unwrap()
+            .add_table(1, 7)
+            .unwrap();
+        assert_eq!(builder.finish().unwrap(), 10);
+
+        let mut db = WickDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "db",
+            storage.clone(),
+        )
+        .unwrap();
+        let get = |key: &str| db.get(ReadOptions::default(), key.as_bytes()).unwrap();
+        assert_eq!(get("a"), Some(b"a3".to_vec()));
+        assert_eq!(get("d"), Some(b"d8".to_vec()));
+        assert_eq!(get("f"), Some(b"f9".to_vec()));
+        // The new writes use the sequence numbers after the ones in the tables
+        db.put(WriteOptions::default(), b"e", b"new").unwrap();
+        assert_eq!(get("e"), Some(b"new".to_vec()));
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_manifest_errors() {
+        let storage = MemStorage::default();
+        storage.mkdir_all("db").unwrap();
+        write_table(&storage, 1, &[("a", 1), ("c", 2)]);
+        write_table(&storage, 2, &[("b", 3)]);
+        let new_builder = || {
+            ManifestBuilder::new(
+                storage.clone(),
+                "db",
+                Options::<BytewiseComparator>::default(),
+            )
+        };
+
+        let mut builder = new_builder();
+        assert!(builder.add_table(7, 1).is_err());
+        assert!(builder.add_table(1, 100).is_err());
+
+        // The tables overlap in level 1 but not in level 0
+        let mut builder = new_builder();
+        builder.add_table(1, 1).unwrap().add_table(1, 2).unwrap();
+        match builder.finish() {
+            Err(Error::InvalidArgument(msg)) => assert!(msg.contains("overlaps")),
+            r => panic!("expect overlapping error but got {:?}", r),
+        }
+        let mut builder = new_builder();
+        builder.add_table(0, 1).unwrap().add_table(0, 2).unwrap();
+        builder.set_last_sequence(2);
+        assert!(builder.finish().is_err());
+        assert!(!storage.exists(generate_filename("db", FileType::Current, 0)));
+
+        let mut builder = new_builder();
+        builder.add_table(0, 1).unwrap().add_table(0, 2).unwrap();
+        builder.set_last_sequence(100);
+        builder.finish().unwrap();
+        assert!(storage.exists(generate_filename("db", FileType::Current, 0)));
+    }
+}
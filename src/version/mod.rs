@@ -2,8 +2,12 @@ use crate::db::format::{
     InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType, MAX_KEY_SEQUENCE,
     VALUE_TYPE_FOR_SEEK,
 };
+use crate::db::range_del::{max_covering_seq, RangeTombstone};
+use crate::db::read_stats::ReadCounters;
 use crate::iterator::Iterator;
-use crate::options::{Options, ReadOptions};
+use crate::options::{
+    project_value, CompactionStyle, Options, ReadOptions, ValueProjector, LEVEL_SIZE_MULTIPLIER,
+};
 use crate::storage::Storage;
 use crate::table_cache::TableCache;
 use crate::util::coding::encode_fixed_64;
@@ -13,12 +17,16 @@ use crate::version::version_set::total_file_size;
 use crate::{Error, Result};
 use std::cell::{Cell, RefCell};
 use std::cmp::Ordering as CmpOrdering;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::mem;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
+pub mod fifo;
+pub mod manifest_builder;
+pub mod universal;
 pub mod version_edit;
 pub mod version_set;
 
@@ -31,6 +39,9 @@ pub struct SeekStats {
     pub level: usize,
 }
 
+/// 点查询的结果：可能的值、搜索统计信息以及第一个包含该键条目的层级
+pub type GetResult = (Option<Vec<u8>>, Option<SeekStats>, Option<usize>);
+
 /// `Version `是不同级别的磁盘表的文件元数据的集合
 /// 内存中的DB被写入0级表，压缩将数据从N级迁移到N+1级。这些表将内部键（包括用户键、删除或设置位以及序列号）映射到用户值。
 /// 级别0的表是通过增加fileNum进行排序的。
@@ -56,6 +67,15 @@ pub struct Version<C: Comparator> {
     compaction_score: f32,
     // 应该被压缩的层级索引,这通常是根据 compaction_score 决定的
     compaction_level: usize,
+    // 小文件过多需要在层内合并的层级，在 finalize 中计算，见 `Options::small_file_compaction_trigger`
+    small_file_level: Option<usize>,
+    // level 0 的文件压缩到的层级，只有开启 `level_compaction_dynamic_level_bytes` 时才可能大于 1
+    base_level: usize,
+    // 每个层级的目标大小，在 finalize 中计算
+    level_max_bytes: Vec<u64>,
+
+    // 所有文件中的范围墓碑，第一次创建迭代器时加载
+    range_tombstones: RwLock<Option<Arc<Vec<RangeTombstone>>>>,
 }
 
 impl<C: Comparator> fmt::Debug for Version<C> {
@@ -93,19 +113,112 @@ impl<C: Comparator + 'static> Version<C> {
             file_to_compact_level: AtomicUsize::new(0),
             compaction_score: 0f32,
             compaction_level: 0,
+            small_file_level: None,
+            base_level: 1,
+            level_max_bytes: vec![u64::MAX; max_levels],
+            range_tombstones: RwLock::new(None),
         }
     }
 
     /// 按sstables中给定的键逐级搜索值 table_cache 是一个表缓存，用于访问存储文件
-    /// 返回 包含可能的值（Vec<u8>）和搜索统计信息（SeekStats）
-    pub fn get<S: Storage + Clone + 'static>( &self, options: ReadOptions, key: LookupKey,table_cache: &TableCache<S, C>,) -> Result<(Option<Vec<u8>>, Option<SeekStats>)> {
+    /// 返回 包含可能的值（Vec<u8>）、搜索统计信息（SeekStats）以及第一个包含该键条目的层级
+    /// 在值或删除之前遇到的合并操作数按从新到旧的顺序追加到 `operands` 中
+    /// 读取的块是否命中 block cache 记录到 `stats` 中
+    /// 如果没有合并操作数，返回由 `projector` 从值中提取的部分
+    #[allow(clippy::too_many_arguments)]
+    pub fn get<S: Storage + Clone + 'static>(
+        &self,
+        options: ReadOptions,
+        key: LookupKey,
+        table_cache: &TableCache<S, C>,
+        max_covering_tombstone_seq: &mut u64,
+        operands: &mut Vec<Vec<u8>>,
+        stats: Option<&ReadCounters>,
+        projector: Option<&dyn ValueProjector>,
+    ) -> Result<GetResult> {
+        let files_to_seek = self.files_to_seek(&key);
+        self.get_from_files(
+            options,
+            &key,
+            files_to_seek.into_iter().map(|(f, level)| (f, level, true)),
+            table_cache,
+            max_covering_tombstone_seq,
+            operands,
+            stats,
+            projector,
+        )
+    }
+
+    /// Searches the keys like `get` does, except that the filters of a table are
+    /// probed for all the keys looked up in it at once by
+    /// `FilterPolicy::may_contain_many`, so the probes of a batch share the cache
+    /// misses of the filter blocks. The tables that may hold any of the keys are
+    /// all opened first, even the ones a key is found before reaching.
+    ///
+    /// `max_covering_tombstone_seqs` and `operands` hold the states of the keys
+    /// as `get` takes them, and the results are in the order of `keys`.
+    #[allow(clippy::type_complexity)]
+    pub fn multi_get<S: Storage + Clone + 'static>(
+        &self,
+        options: ReadOptions,
+        keys: &[LookupKey],
+        table_cache: &TableCache<S, C>,
+        max_covering_tombstone_seqs: &mut [u64],
+        operands: &mut [Vec<Vec<u8>>],
+        stats: Option<&ReadCounters>,
+    ) -> Result<Vec<GetResult>> {
+        let files_to_seek = keys
+            .iter()
+            .map(|key| self.files_to_seek(key))
+            .collect::<Vec<_>>();
+        // file number -> (file, the indexes of the keys to look up in it)
+        let mut key_groups: HashMap<u64, (&Arc<FileMetaData>, Vec<usize>)> = HashMap::new();
+        for (i, files) in files_to_seek.iter().enumerate() {
+            for (f, _) in files.iter() {
+                key_groups.entry(f.number).or_insert((f, vec![])).1.push(i);
+            }
+        }
+        // (key index, file number) of the files the filters rule out for a key
+        let mut excluded = HashSet::new();
+        for (f, indexes) in key_groups.values() {
+            let table = table_cache.find_table(f.number, f.file_size)?;
+            let ikeys = indexes
+                .iter()
+                .map(|&i| keys[i].internal_key())
+                .collect::<Vec<_>>();
+            let may_match = table.keys_may_match(options, &ikeys)?;
+            for (&i, may_match) in indexes.iter().zip(may_match) {
+                if !may_match {
+                    excluded.insert((i, f.number));
+                }
+            }
+        }
+        let mut results = Vec::with_capacity(keys.len());
+        for (i, files) in files_to_seek.into_iter().enumerate() {
+            let files = files
+                .into_iter()
+                .map(|(f, level)| (f, level, !excluded.contains(&(i, f.number))));
+            results.push(self.get_from_files(
+                options,
+                &keys[i],
+                files,
+                table_cache,
+                &mut max_covering_tombstone_seqs[i],
+                &mut operands[i],
+                stats,
+                None,
+            )?);
+        }
+        Ok(results)
+    }
+
+    // 返回可能包含 key 的文件及其层级，从新到旧
+    fn files_to_seek(&self, key: &LookupKey) -> Vec<(&Arc<FileMetaData>, usize)> {
         // 初始化键和比较器
         let ikey = key.internal_key();
         let ukey = key.user_key();
         let ucmp = &self.icmp.user_comparator;
-        //搜索统计信息
-        let mut seek_stats = None;
-        //将要搜索的文件列表
+        //将要搜索的文件列表，从新到旧
         let mut files_to_seek = vec![];
         // 遍历各层文件，找到要查找的文件列表
         for (level, files) in self.files.iter().enumerate() {
@@ -115,14 +228,16 @@ impl<C: Comparator + 'static> Version<C> {
             }
             // 对于 0 级，需要考虑文件重叠的问题，检查所有可能包含 ukey 的文件。
             if level == 0 {
-                // 重叠 user_key 并按照从最新到最旧的顺序处理它们(序列号大小)，因为最后一个 0 级文件总是有最新的条目。
-                for f in files.iter().rev() {
+                for f in files.iter() {
                     if ucmp.compare(ukey, f.largest.user_key()) != CmpOrdering::Greater
                         && ucmp.compare(ukey, f.smallest.user_key()) != CmpOrdering::Less
                     {
                         files_to_seek.push((f, 0));
                     }
                 }
+                // 按文件编号从大到小排序，因为编号较大的 0 级文件有更新的条目。
+                // 只对 0 级排序：较深层级的文件编号可能更大（由压缩产生），但其条目总是比较浅层级的旧
+                files_to_seek.sort_by_key(|(f, _)| std::cmp::Reverse(f.number));
             } else {
                 // 对于非 0 级，使用二分查找确定 ikey 可能存在的文件。
                 // file.largest>=ikey
@@ -132,17 +247,38 @@ impl<C: Comparator + 'static> Version<C> {
                 } else {
                     let target = &files[index];
                     // 用户键大于或等于文件的最小键 添加到files_to_seek 中
-                    if ucmp.compare(ukey, target.smallest.user_key()) != CmpOrdering::Less{
+                    if ucmp.compare(ukey, target.smallest.user_key()) != CmpOrdering::Less {
                         files_to_seek.push((target, level));
                     }
                 }
             }
         }
-        // 按文件编号从大到小排序，以确定访问顺序
-        files_to_seek.sort_by(|(a, _), (b, _)| b.number.cmp(&a.number));
-        // 遍历排序后的文件，使用 table_cache 来加载并检查数据块。
-        for (file, level) in files_to_seek {
+        files_to_seek
+    }
 
+    // 按从新到旧的顺序在 `files_to_seek` 中搜索 key，参数和返回值同 `get`。
+    // 布尔值为 false 表示过滤器已排除该文件包含 key，只检查其中的范围墓碑
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+    fn get_from_files<'a, S: Storage + Clone + 'static>(
+        &self,
+        options: ReadOptions,
+        key: &LookupKey,
+        files_to_seek: impl IntoIterator<Item = (&'a Arc<FileMetaData>, usize, bool)>,
+        table_cache: &TableCache<S, C>,
+        max_covering_tombstone_seq: &mut u64,
+        operands: &mut Vec<Vec<u8>>,
+        stats: Option<&ReadCounters>,
+        projector: Option<&dyn ValueProjector>,
+    ) -> Result<GetResult> {
+        let ikey = key.internal_key();
+        let ukey = key.user_key();
+        let ucmp = &self.icmp.user_comparator;
+        //搜索统计信息
+        let mut seek_stats = None;
+        // 第一个包含该键条目的层级
+        let mut served_level = None;
+        // 按层级顺序遍历文件，使用 table_cache 来加载并检查数据块，在第一个匹配处停止。
+        for (file, level, may_match) in files_to_seek {
             if seek_stats.is_none() {
                 // TODO：当 Seek Compaction 触发时，LevelDB 首先确定哪些文件被频繁查询。通常，它会记录第一个或最初几个在查询过程中访问的文件 
                 // Seek Compaction，每个文件的 seek miss 次数都有一个阈值，如果超过了这个阈值，那么认为这个文件需要Compact。
@@ -151,13 +287,15 @@ impl<C: Comparator + 'static> Version<C> {
                     level,
                 });
             }
-            match table_cache.get(
-                self.icmp.clone(),
-                options,
-                ikey,
-                file.number,
-                file.file_size,
-            )? {
+            // 文件按从新到旧的顺序访问，所以文件中的墓碑不会删除已访问过的更新的条目
+            let table = table_cache.find_table(file.number, file.file_size)?;
+            let tombstone_seq =
+                max_covering_seq(ucmp, table.range_tombstones(), ukey, key.sequence());
+            *max_covering_tombstone_seq = tombstone_seq.max(*max_covering_tombstone_seq);
+            if !may_match {
+                continue;
+            }
+            match table_cache.get(options, ikey, file.number, file.file_size, stats)? {
                 None => continue,
                 Some(block_iter) => {
                     let encoded_key = block_iter.key();
@@ -172,11 +310,64 @@ impl<C: Comparator + 'static> Version<C> {
                                 .compare(parsed_key.user_key, key.user_key())
                                 == CmpOrdering::Equal
                             {
+                                served_level.get_or_insert(level);
+                                if parsed_key.seq < *max_covering_tombstone_seq {
+                                    // Deleted by a range tombstone
+                                    return Ok((None, seek_stats, served_level));
+                                }
                                 match parsed_key.value_type {
                                     ValueType::Value => {
-                                        return Ok((Some(value.to_vec()), seek_stats))
+                                        let value = project_value(value, operands, projector);
+                                        return Ok((Some(value), seek_stats, served_level));
+                                    }
+                                    ValueType::Deletion => {
+                                        return Ok((None, seek_stats, served_level))
+                                    }
+                                    ValueType::Merge => {
+                                        // The older entries of the key might be in the next
+                                        // blocks, so collect them by a table iterator
+                                        let mut iter = table_cache.new_iter(
+                                            options,
+                                            file.number,
+                                            file.file_size,
+                                        )?;
+                                        iter.seek(ikey);
+                                        while iter.valid() {
+                                            let parsed =
+                                                match ParsedInternalKey::decode_from(iter.key()) {
+                                                    Some(k)
+                                                        if ucmp.compare(k.user_key, ukey)
+                                                            == CmpOrdering::Equal =>
+                                                    {
+                                                        k
+                                                    }
+                                                    _ => break,
+                                                };
+                                            if parsed.seq < *max_covering_tombstone_seq {
+                                                return Ok((None, seek_stats, served_level));
+                                            }
+                                            match parsed.value_type {
+                                                ValueType::Value => {
+                                                    return Ok((
+                                                        Some(iter.value().to_vec()),
+                                                        seek_stats,
+                                                        served_level,
+                                                    ))
+                                                }
+                                                ValueType::Deletion => {
+                                                    return Ok((None, seek_stats, served_level))
+                                                }
+                                                ValueType::Merge => {
+                                                    operands.push(iter.value().to_vec())
+                                                }
+                                                ValueType::RangeDeletion | ValueType::Unknown => {
+                                                    break
+                                                }
+                                            }
+                                            iter.next();
+                                        }
+                                        iter.status()?;
                                     }
-                                    ValueType::Deletion => return Ok((None, seek_stats)),
                                     _ => {}
                                 }
                             }
@@ -185,7 +376,41 @@ impl<C: Comparator + 'static> Version<C> {
                 }
             }
         }
-        Ok((None, seek_stats))
+        Ok((None, seek_stats, served_level))
+    }
+
+    /// Returns the range tombstones in the files of the version overlapping the
+    /// user key range `[lower, upper)`. `None` means unbounded.
+    /// The tombstones of all the files are loaded on the first unbounded call and
+    /// cached, while a bounded call only opens the overlapping files.
+    pub fn range_tombstones<S: Storage + Clone + 'static>(
+        &self,
+        table_cache: &TableCache<S, C>,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<Arc<Vec<RangeTombstone>>> {
+        if let Some(tombstones) = self.range_tombstones.read().unwrap().as_ref() {
+            return Ok(tombstones.clone());
+        }
+        let bounded = lower.is_some() || upper.is_some();
+        let ucmp = &self.icmp.user_comparator;
+        let mut tombstones = vec![];
+        // The key range of a file covers all its range tombstones so the files
+        // outside the bounds can't hide any key within them
+        for f in self
+            .files
+            .iter()
+            .flatten()
+            .filter(|f| file_in_range(ucmp, f, lower, upper))
+        {
+            let table = table_cache.find_table(f.number, f.file_size)?;
+            tombstones.extend_from_slice(table.range_tombstones());
+        }
+        let tombstones = Arc::new(tombstones);
+        if !bounded {
+            *self.range_tombstones.write().unwrap() = Some(tombstones.clone());
+        }
+        Ok(tombstones)
     }
 
     /// 该方法 update_stats 的作用是更新 SSTable 文件的查询（seek）统计，并根据统计结果可能将文件标记为需要压缩
@@ -212,9 +437,37 @@ impl<C: Comparator + 'static> Version<C> {
         false
     }
 
+    /// 在没有其他标记文件时，将 `level` 中最旧的文件标记为待压缩，
+    /// 之后会像 seek 触发的压缩一样被处理。返回是否标记成功
+    pub fn mark_file_to_compact(&self, level: usize) -> bool {
+        let mut file_to_compact = self.file_to_compact.write().unwrap();
+        if file_to_compact.is_some() {
+            return false;
+        }
+        match self.files[level].iter().min_by_key(|f| f.number) {
+            Some(f) => {
+                *file_to_compact = Some(f.clone());
+                self.file_to_compact_level.store(level, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// version是否需要压缩 compaction_score 或者有标记文件
+    /// universal 和 FIFO 压缩不处理标记的文件
     pub fn needs_compaction(&self) -> bool {
-        self.compaction_score > 1.0 || self.file_to_compact.read().unwrap().is_some()
+        if self.compaction_score > 1.0 {
+            return true;
+        }
+        match self.options.compaction_style {
+            CompactionStyle::Level => {
+                self.file_to_compact.read().unwrap().is_some() || self.small_file_level.is_some()
+            }
+            CompactionStyle::Universal => false,
+            // 文件是否过期只能在选取压缩时读取文件的创建时间才知道
+            CompactionStyle::Fifo => self.options.fifo_ttl.is_some() && !self.files[0].is_empty(),
+        }
     }
 
     /// 查看每个level中的文件数量
@@ -238,6 +491,10 @@ impl<C: Comparator + 'static> Version<C> {
         largest_ukey: &[u8],
     ) -> usize {
         let mut level = 0;
+        // 动态层级大小下 base level 以上的层级必须为空
+        if self.options.level_compaction_dynamic_level_bytes {
+            return level;
+        }
         // 调用 overlap_in_level 方法检查在层级 0 是否存在与给定键范围重叠的文件
         // 如果没有重叠，考虑将数据推送到更高的层级。
         if !self.overlap_in_level(level, Some(smallest_ukey), Some(largest_ukey)) {
@@ -270,11 +527,34 @@ impl<C: Comparator + 'static> Version<C> {
 
     // 用于计算 LSM 树的每个level的score得分，并确定哪个level最需要进行压缩
     pub fn finalize(&mut self) {
+        // universal 压缩按 sorted run 的数量计算得分，FIFO 压缩按文件总大小计算得分
+        match self.options.compaction_style {
+            CompactionStyle::Level => {}
+            CompactionStyle::Universal => {
+                self.compaction_level = 0;
+                self.compaction_score = universal::sorted_run_count(self) as f32
+                    / self.options.l0_compaction_threshold as f32;
+                return;
+            }
+            CompactionStyle::Fifo => {
+                self.compaction_level = 0;
+                self.compaction_score = total_file_size(&self.files[0]) as f32
+                    / self.options.fifo_max_table_files_size.max(1) as f32;
+                return;
+            }
+        }
+        self.compute_level_max_bytes();
+        // 动态层级大小下最后一层的目标大小由它自身决定，不需要再压缩
+        let levels = if self.options.level_compaction_dynamic_level_bytes {
+            self.options.max_levels - 1
+        } else {
+            self.options.max_levels
+        };
         // pre-computed best level for next compaction
         let mut best_level = 0;
         let mut best_score = 0.0;
         //循环遍历所有层级（从0开始到最大层级数）
-        for level in 0..self.options.max_levels as usize {
+        for level in 0..levels {
             let score = {
                 // （level-0）特殊处理
                 // 使用文件数量与配置的阈值（l0_compaction_threshold）比较，而不是使用文件的总大小
@@ -285,9 +565,9 @@ impl<C: Comparator + 'static> Version<C> {
                 if level == 0 {
                     self.files[level].len() as f64 / self.options.l0_compaction_threshold as f64
                 } else {
-                    //其他层级的得分计算则基于文件总大小与该level允许的最大字节量（由 self.options.max_bytes_for_level(level) 给出）的比例
+                    //其他层级的得分计算则基于文件总大小与该level允许的最大字节量（由 self.max_bytes_for_level(level) 给出）的比例
                     let level_bytes = total_file_size(self.files[level].as_ref());
-                    level_bytes as f64 / self.options.max_bytes_for_level(level) as f64
+                    level_bytes as f64 / self.max_bytes_for_level(level) as f64
                 }
             };
             if score > best_score {
@@ -297,6 +577,99 @@ impl<C: Comparator + 'static> Version<C> {
         }
         self.compaction_level = best_level;
         self.compaction_score = best_score as f32;
+        self.small_file_level = self.pick_small_file_level();
+    }
+
+    // 返回文件数超过 `small_file_compaction_trigger` 并且平均文件大小小于 `max_file_size`
+    // 的 1/SMALL_FILE_SIZE_RATIO 的层级中文件数最多的一个。level 0 按文件数计算得分，不需要处理
+    fn pick_small_file_level(&self) -> Option<usize> {
+        let trigger = self.options.small_file_compaction_trigger;
+        if trigger == 0 {
+            return None;
+        }
+        let small_file_size = self.options.max_file_size / SMALL_FILE_SIZE_RATIO;
+        (1..self.options.max_levels)
+            .filter(|level| {
+                let files = &self.files[*level];
+                files.len() > trigger
+                    && total_file_size(files) / (files.len() as u64) < small_file_size
+            })
+            .max_by_key(|level| self.files[*level].len())
+    }
+
+    // 计算每个层级的目标大小和 base level
+    //
+    // 开启 `level_compaction_dynamic_level_bytes` 时，从最大的层级的实际大小开始
+    // 逐层除以 `LEVEL_SIZE_MULTIPLIER` 得到上面各层的目标大小，第一个目标大小不超过
+    // `l1_max_bytes` 的层级作为 base level，level 0 的文件直接压缩到 base level
+    fn compute_level_max_bytes(&mut self) {
+        let max_levels = self.options.max_levels;
+        self.level_max_bytes = vec![u64::MAX; max_levels];
+        if !self.options.level_compaction_dynamic_level_bytes {
+            self.base_level = 1;
+            for level in 1..max_levels {
+                self.level_max_bytes[level] = self.options.max_bytes_for_level(level);
+            }
+            return;
+        }
+        let mut first_non_empty = None;
+        let mut max_level_size = 0;
+        for level in 1..max_levels {
+            let size = total_file_size(&self.files[level]);
+            if size > 0 && first_non_empty.is_none() {
+                first_non_empty = Some(level);
+            }
+            max_level_size = max_level_size.max(size);
+        }
+        let first_non_empty = match first_non_empty {
+            Some(level) => level,
+            None => {
+                // 没有数据时 level 0 直接压缩到最后一层
+                self.base_level = max_levels - 1;
+                return;
+            }
+        };
+        let base_bytes_max = self.options.l1_max_bytes;
+        let base_bytes_min = base_bytes_max / LEVEL_SIZE_MULTIPLIER;
+        let mut cur_level_size = max_level_size;
+        for _ in first_non_empty..max_levels - 1 {
+            cur_level_size /= LEVEL_SIZE_MULTIPLIER;
+        }
+        self.base_level = first_non_empty;
+        let base_level_size = if cur_level_size <= base_bytes_min {
+            // 最后一层还很小，base level 至少保留 base_bytes_min
+            base_bytes_min + 1
+        } else {
+            while self.base_level > 1 && cur_level_size > base_bytes_max {
+                self.base_level -= 1;
+                cur_level_size /= LEVEL_SIZE_MULTIPLIER;
+            }
+            // 如果 level 1 也放不下，level 1 的目标大小为 base_bytes_max
+            cur_level_size.min(base_bytes_max)
+        };
+        let mut level_size = base_level_size;
+        for level in self.base_level..max_levels {
+            if level > self.base_level {
+                level_size = level_size.saturating_mul(LEVEL_SIZE_MULTIPLIER);
+            }
+            // 任何层级的目标大小都不小于 base_bytes_max，否则 level 1 以下的层级会比
+            // level 0 小，压缩得分偏向它们而让 level 0 堆积
+            self.level_max_bytes[level] = level_size.max(base_bytes_max);
+        }
+    }
+
+    /// Returns the level the level 0 files are compacted into. It's always 1
+    /// unless `Options::level_compaction_dynamic_level_bytes` is true.
+    #[inline]
+    pub fn base_level(&self) -> usize {
+        self.base_level
+    }
+
+    /// Returns the target size of the given level. The levels above the base
+    /// level have no target.
+    #[inline]
+    pub fn max_bytes_for_level(&self, level: usize) -> u64 {
+        self.level_max_bytes[level]
     }
 
     /// Returns `icmp`
@@ -442,10 +815,8 @@ impl<C: Comparator + 'static> Version<C> {
                     }
                 } else {
                     // 如果 ikey 在文件的键范围内，使用 table_cache 访问该文件并计算 ikey 在文件内的大致偏移量，然后累加到 result。
-                    if let Ok(table) =
-                        table_cache.find_table(self.icmp.clone(), f.number, f.file_size)
-                    {
-                        result += table.approximate_offset_of(self.icmp.clone(), ikey.data());
+                    if let Ok(table) = table_cache.find_table(f.number, f.file_size) {
+                        result += table.approximate_offset_of(ikey.data());
                     }
                 }
             }
@@ -604,6 +975,8 @@ fn key_is_before_file<C: Comparator>(
 /// 16个字节
 pub const FILE_META_LENGTH: usize = 2 * mem::size_of::<u64>();
 
+// 平均文件大小小于 `max_file_size` 的 1/SMALL_FILE_SIZE_RATIO 的层级被认为碎片化成了小文件
+const SMALL_FILE_SIZE_RATIO: u64 = 4;
 
 /// 每个level中的文件的迭代器
 /// key() 是文件中出现的最大键，
@@ -698,6 +1071,18 @@ impl<C: Comparator + 'static> Iterator for LevelFileNumIterator<C> {
     }
 }
 
+/// Returns whether the key range of the file overlaps the user key range
+/// `[lower, upper)`. `None` means unbounded.
+pub(crate) fn file_in_range<C: Comparator>(
+    ucmp: &C,
+    file: &FileMetaData,
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+) -> bool {
+    lower.is_none_or(|l| ucmp.compare(file.largest.user_key(), l) != CmpOrdering::Less)
+        && upper.is_none_or(|u| ucmp.compare(file.smallest.user_key(), u) == CmpOrdering::Less)
+}
+
 #[cfg(test)]
 mod find_file_tests {
     use super::*;
@@ -904,3 +1289,75 @@ mod find_file_tests {
         }
     }
 }
+
+#[cfg(test)]
+mod level_max_bytes_tests {
+    use super::*;
+    use crate::db::format::{InternalKey, InternalKeyComparator, ValueType};
+    use crate::util::comparator::BytewiseComparator;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn new_version(dynamic: bool, levels: &[(usize, u64)]) -> Version<BytewiseComparator> {
+        let options = Options::<BytewiseComparator> {
+            l1_max_bytes: 10 * MB,
+            level_compaction_dynamic_level_bytes: dynamic,
+            ..Default::default()
+        };
+        let mut v = Version::new(Arc::new(options), InternalKeyComparator::default());
+        for (number, (level, size)) in levels.iter().enumerate() {
+            v.files[*level].push(Arc::new(FileMetaData {
+                number: number as u64 + 1,
+                file_size: *size,
+                smallest: InternalKey::new(b"a", 1, ValueType::Value),
+                largest: InternalKey::new(b"z", 1, ValueType::Value),
+                ..Default::default()
+            }));
+        }
+        v.finalize();
+        v
+    }
+
+    #[test]
+    fn test_static_level_max_bytes() {
+        let v = new_version(false, &[(6, 100 * MB)]);
+        assert_eq!(v.base_level(), 1);
+        assert_eq!(v.max_bytes_for_level(1), 10 * MB);
+        assert_eq!(v.max_bytes_for_level(3), 1000 * MB);
+    }
+
+    #[test]
+    fn test_dynamic_level_max_bytes() {
+        // An empty db compacts level 0 into the last level
+        let v = new_version(true, &[]);
+        assert_eq!(v.base_level(), 6);
+
+        // A small last level is the base level
+        let v = new_version(true, &[(6, 8 * MB)]);
+        assert_eq!(v.base_level(), 6);
+        assert_eq!(v.max_bytes_for_level(6), 10 * MB);
+        let v = new_version(true, &[(6, 50 * MB)]);
+        assert_eq!(v.base_level(), 5);
+        assert_eq!(v.max_bytes_for_level(5), 10 * MB);
+
+        // The base level moves up as the last level grows
+        let v = new_version(true, &[(6, 5000 * MB)]);
+        assert_eq!(v.base_level(), 3);
+        assert_eq!(v.max_bytes_for_level(2), u64::MAX);
+        assert_eq!(v.max_bytes_for_level(3), 10 * MB);
+        assert_eq!(v.max_bytes_for_level(4), 50 * MB);
+        assert_eq!(v.max_bytes_for_level(5), 500 * MB);
+        assert!(!v.needs_compaction());
+
+        // Level 5 exceeds a tenth of the last level
+        let v = new_version(true, &[(4, 10 * MB), (5, 600 * MB), (6, 5000 * MB)]);
+        assert_eq!(v.base_level(), 3);
+        assert!(v.needs_compaction());
+        assert_eq!(v.compaction_level, 5);
+
+        // Level 1 can't hold a hundred-thousandth of the last level
+        let v = new_version(true, &[(6, 2_000_000 * MB)]);
+        assert_eq!(v.base_level(), 1);
+        assert_eq!(v.max_bytes_for_level(1), 10 * MB);
+    }
+}
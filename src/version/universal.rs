@@ -0,0 +1,262 @@
+use crate::compaction::{Compaction, CompactionReason};
+use crate::options::UniversalCompactionOptions;
+use crate::util::comparator::Comparator;
+use crate::version::version_edit::FileMetaData;
+use crate::version::version_set::total_file_size;
+use crate::version::Version;
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+// A sorted run is either a level 0 file or a whole non-empty level
+struct SortedRun {
+    level: usize,
+    files: Vec<Arc<FileMetaData>>,
+    size: u64,
+}
+
+// Returns the sorted runs of `version` ordered from the newest to the oldest.
+// The level 0 files are sorted by key in a version, so they are reordered by
+// the file numbers.
+fn sorted_runs<C: Comparator>(version: &Version<C>) -> Vec<SortedRun> {
+    let mut level0 = version.files[0].clone();
+    level0.sort_by_key(|f| Reverse(f.number));
+    let mut runs: Vec<SortedRun> = level0
+        .into_iter()
+        .map(|f| SortedRun {
+            level: 0,
+            size: f.file_size,
+            files: vec![f],
+        })
+        .collect();
+    for (level, files) in version.files.iter().enumerate().skip(1) {
+        if !files.is_empty() {
+            runs.push(SortedRun {
+                level,
+                files: files.clone(),
+                size: total_file_size(files),
+            });
+        }
+    }
+    runs
+}
+
+/// Returns the number of the sorted runs in `version`
+pub fn sorted_run_count<C: Comparator>(version: &Version<C>) -> usize {
+    version.files[0].len() + version.files[1..].iter().filter(|f| !f.is_empty()).count()
+}
+
+/// Picks a universal compaction merging adjacent sorted runs of `version`, or
+/// returns `None` unless there are more than `l0_compaction_threshold` sorted
+/// runs.
+///
+/// The candidates are tried in this order:
+/// 1. All the runs if the newer runs are too large compared to the oldest one
+/// 2. The newest window of runs whose sizes are similar
+/// 3. The newest runs to get the number of runs below the threshold
+pub fn pick_universal_compaction<C: Comparator + 'static>(
+    version: &Arc<Version<C>>,
+) -> Option<Compaction<C>> {
+    let runs = sorted_runs(version);
+    let trigger = version.options.l0_compaction_threshold.max(1);
+    if runs.len() <= trigger {
+        return None;
+    }
+    let opts = &version.options.universal_compaction;
+    if exceeds_size_amplification(&runs, opts) {
+        let c = new_compaction(
+            version,
+            &runs,
+            0,
+            runs.len(),
+            CompactionReason::UniversalSizeAmplification,
+        );
+        if c.is_some() {
+            return c;
+        }
+    }
+    for start in 0..runs.len() {
+        let end = size_ratio_window_end(&runs, start, opts);
+        if end - start >= opts.min_merge_width {
+            let c = new_compaction(
+                version,
+                &runs,
+                start,
+                end,
+                CompactionReason::UniversalSizeRatio,
+            );
+            if c.is_some() {
+                return c;
+            }
+        }
+    }
+    let count = (runs.len() + 1 - trigger).min(opts.max_merge_width).max(2);
+    new_compaction(
+        version,
+        &runs,
+        0,
+        count,
+        CompactionReason::UniversalSortedRunNum,
+    )
+}
+
+// Reports whether the total size of the runs except the oldest one is larger
+// than `max_size_amplification_percent` of the oldest one
+fn exceeds_size_amplification(runs: &[SortedRun], opts: &UniversalCompactionOptions) -> bool {
+    let (oldest, newer) = runs.split_last().unwrap();
+    let newer_size: u64 = newer.iter().map(|r| r.size).sum();
+    newer_size.saturating_mul(100)
+        > oldest
+            .size
+            .saturating_mul(opts.max_size_amplification_percent)
+}
+
+// Returns the end of the window starting at `start` in which every run is no
+// larger than the total size of the newer runs in the window by `size_ratio`
+// percent
+fn size_ratio_window_end(
+    runs: &[SortedRun],
+    start: usize,
+    opts: &UniversalCompactionOptions,
+) -> usize {
+    let mut total = runs[start].size;
+    let mut end = start + 1;
+    while end < runs.len() && end - start < opts.max_merge_width {
+        let limit = total.saturating_mul(100 + opts.size_ratio as u64);
+        if runs[end].size.saturating_mul(100) > limit {
+            break;
+        }
+        total += runs[end].size;
+        end += 1;
+    }
+    end
+}
+
+// Creates the compaction merging the runs in `[start, end)`. The output goes to
+// the level of the oldest run in the window. If the oldest run is a level 0 file,
+// the window is extended to all the older level 0 files, and the output goes to
+// the empty level right above the remaining runs, or L1 is merged too if there
+// is no such level. Returns `None` if the window merges nothing.
+fn new_compaction<C: Comparator + 'static>(
+    version: &Arc<Version<C>>,
+    runs: &[SortedRun],
+    start: usize,
+    end: usize,
+    reason: CompactionReason,
+) -> Option<Compaction<C>> {
+    let level0_runs = runs.iter().take_while(|r| r.level == 0).count();
+    let mut end = end;
+    if runs[end - 1].level == 0 {
+        end = level0_runs;
+    }
+    let output_level = match runs[end - 1].level {
+        0 => match runs.get(end) {
+            None => version.options.max_levels - 1,
+            Some(next) if next.level > 1 => next.level - 1,
+            Some(next) => {
+                end += 1;
+                next.level
+            }
+        },
+        level => level,
+    };
+    if runs[start].level == output_level {
+        return None;
+    }
+    let mut c = Compaction::new(version.options.clone(), runs[start].level, reason);
+    c.output_level = output_level;
+    for run in runs[start..end].iter() {
+        if run.level == c.level {
+            c.inputs.base.extend(run.files.iter().cloned());
+        } else if run.level == output_level {
+            c.inputs.parent = run.files.clone();
+        } else {
+            c.inputs.intermediate.push((run.level, run.files.clone()));
+        }
+    }
+    c.input_version = Some(version.clone());
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::{InternalKey, InternalKeyComparator, ValueType};
+    use crate::options::{CompactionStyle, Options};
+    use crate::util::comparator::BytewiseComparator;
+
+    fn new_version(l0_sizes: &[u64], levels: &[(usize, u64)]) -> Arc<Version<BytewiseComparator>> {
+        let options = Options::<BytewiseComparator> {
+            compaction_style: CompactionStyle::Universal,
+            ..Default::default()
+        };
+        let mut v = Version::new(Arc::new(options), InternalKeyComparator::default());
+        let mut number = 0;
+        let mut new_file = |size| {
+            number += 1;
+            Arc::new(FileMetaData {
+                number,
+                file_size: size,
+                smallest: InternalKey::new(b"a", number, ValueType::Value),
+                largest: InternalKey::new(b"z", number, ValueType::Value),
+                ..Default::default()
+            })
+        };
+        // The older runs are created first
+        for (level, size) in levels.iter().rev() {
+            v.files[*level].push(new_file(*size));
+        }
+        for size in l0_sizes.iter().rev() {
+            v.files[0].push(new_file(*size));
+        }
+        v.finalize();
+        Arc::new(v)
+    }
+
+    fn pick(v: &Arc<Version<BytewiseComparator>>) -> Option<Compaction<BytewiseComparator>> {
+        pick_universal_compaction(v)
+    }
+
+    #[test]
+    fn test_pick_universal_compaction() {
+        // Too few sorted runs
+        let v = new_version(&[10, 10], &[(6, 1000)]);
+        assert!(!v.needs_compaction());
+        assert!(pick(&v).is_none());
+
+        // The L0 files of similar sizes are merged into the empty level above L6
+        let v = new_version(&[10, 10, 10, 10], &[(6, 1000)]);
+        let c = pick(&v).unwrap();
+        assert!(matches!(c.reason, CompactionReason::UniversalSizeRatio));
+        assert_eq!((c.level, c.output_level), (0, 5));
+        assert_eq!(c.inputs.base.len(), 4);
+        assert!(c.inputs.parent.is_empty());
+
+        // The newer runs are much larger than the oldest one
+        let v = new_version(&[10, 10, 10, 10], &[(5, 50), (6, 40)]);
+        let c = pick(&v).unwrap();
+        assert!(matches!(
+            c.reason,
+            CompactionReason::UniversalSizeAmplification
+        ));
+        assert_eq!((c.level, c.output_level), (0, 6));
+        assert_eq!(c.inputs.intermediate.len(), 1);
+        assert_eq!(c.inputs.intermediate[0].0, 5);
+        assert_eq!(c.inputs.parent.len(), 1);
+
+        // The size ratio window spans the levels
+        let v = new_version(&[100], &[(2, 1000), (3, 1000), (4, 2000), (6, 100_000)]);
+        let c = pick(&v).unwrap();
+        assert!(matches!(c.reason, CompactionReason::UniversalSizeRatio));
+        assert_eq!((c.level, c.output_level), (2, 4));
+        assert_eq!(c.inputs.intermediate[0].0, 3);
+
+        // No similar sizes, so the newest runs are merged to reduce the number of
+        // runs, into L1 since no level is empty above it
+        let v = new_version(&[1, 10], &[(1, 100), (2, 1000), (3, 100_000)]);
+        let c = pick(&v).unwrap();
+        assert!(matches!(c.reason, CompactionReason::UniversalSortedRunNum));
+        assert_eq!((c.level, c.output_level), (0, 1));
+        assert_eq!(c.inputs.base.len(), 2);
+        assert_eq!(c.inputs.parent.len(), 1);
+    }
+}
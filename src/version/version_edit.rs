@@ -2,8 +2,8 @@ use crate::db::format::InternalKey;
 use crate::util::collection::HashSet;
 use crate::util::varint::{VarintU32, VarintU64};
 use crate::version::version_edit::Tag::{
-    CompactPointer, Comparator, DeletedFile, LastSequence, LogNumber, NewFile, NextFileNumber,
-    PrevLogNumber, Unknown,
+    ColumnFamilyAdd, ColumnFamilyDrop, CompactPointer, Comparator, DeletedFile, LastSequence,
+    LogNumber, NewFile, NextFileNumber, PrevLogNumber, SequenceBits, StagedFile, Unknown,
 };
 use crate::{Error, Options, Result};
 use std::fmt::{Debug, Formatter};
@@ -20,8 +20,12 @@ enum Tag {
     DeletedFile = 6,  //标记用于记录已删除的文件的信息
     NewFile = 7,    //标记用于记录新添加的文件的信息
     // 8 was used for large value refs
-    PrevLogNumber = 9,  //标记用于存储之前的日志文件编号
-    Unknown, // unknown tag
+    PrevLogNumber = 9,     //标记用于存储之前的日志文件编号
+    StagedFile = 10,       //标记用于记录正在生成的压缩输出文件
+    ColumnFamilyAdd = 11,  //标记用于记录新建的列族
+    ColumnFamilyDrop = 12, //标记用于记录被删除的列族
+    SequenceBits = 13,     //标记用于记录 internal key 中序列号的位数
+    Unknown,           // unknown tag
 }
 
 impl From<u32> for Tag {
@@ -35,6 +39,10 @@ impl From<u32> for Tag {
             6 => Tag::DeletedFile,
             7 => Tag::NewFile,
             9 => Tag::PrevLogNumber,
+            10 => Tag::StagedFile,
+            11 => Tag::ColumnFamilyAdd,
+            12 => Tag::ColumnFamilyDrop,
+            13 => Tag::SequenceBits,
             _ => Tag::Unknown,
         }
     }
@@ -121,8 +129,18 @@ pub struct VersionEdit {
     pub next_file_number: Option<u64>,
     // the last used sequence number
     pub last_sequence: Option<u64>,
+    // internal key 中序列号的位数，只在不是 `LEGACY_SEQUENCE_BITS` 时记录，
+    // 因此旧版本可以继续打开默认格式的数据库
+    pub sequence_bits: Option<u32>,
 
     pub file_delta: FileDelta,
+    // 正在生成但尚未安装的压缩输出文件 (level, file_number)
+    // 这些文件在对应的 NewFile 被记录之后才算完成
+    pub staged_files: Vec<(usize, u64)>,
+    // 新建的列族 (id, name)
+    pub column_families_added: Vec<(u32, String)>,
+    // 被删除的列族 id
+    pub column_families_dropped: Vec<u32>,
 }
 
 impl VersionEdit {
@@ -134,11 +152,15 @@ impl VersionEdit {
             prev_log_number: None,
             next_file_number: None,
             last_sequence: None,
+            sequence_bits: None,
             file_delta: FileDelta {
                 deleted_files: HashSet::default(),
                 new_files: Vec::new(),
                 compaction_pointers: Vec::new(),
             },
+            staged_files: Vec::new(),
+            column_families_added: Vec::new(),
+            column_families_dropped: Vec::new(),
         }
     }
 
@@ -150,8 +172,12 @@ impl VersionEdit {
         self.prev_log_number = None;
         self.next_file_number = None;
         self.last_sequence = None;
+        self.sequence_bits = None;
         self.file_delta.deleted_files.clear();
         self.file_delta.new_files.clear();
+        self.staged_files.clear();
+        self.column_families_added.clear();
+        self.column_families_dropped.clear();
         // NOTICE: compaction pointers are not cleared here
     }
 
@@ -182,6 +208,23 @@ impl VersionEdit {
         self.file_delta.deleted_files.insert((level, file_number));
     }
 
+    /// Record that the file is being written as an output of a compaction to the
+    /// specified level. It's orphaned if no `NewFile` is recorded for it.
+    #[inline]
+    pub fn stage_file(&mut self, level: usize, file_number: u64) {
+        self.staged_files.push((level, file_number));
+    }
+
+    #[inline]
+    pub fn add_column_family(&mut self, id: u32, name: String) {
+        self.column_families_added.push((id, name));
+    }
+
+    #[inline]
+    pub fn drop_column_family(&mut self, id: u32) {
+        self.column_families_dropped.push(id);
+    }
+
     #[inline]
     pub fn set_comparator_name(&mut self, name: String) {
         self.comparator_name = Some(name);
@@ -207,6 +250,11 @@ impl VersionEdit {
         self.last_sequence = Some(seq);
     }
 
+    #[inline]
+    pub fn set_sequence_bits(&mut self, bits: u32) {
+        self.sequence_bits = Some(bits);
+    }
+
     /// 将VersionEdit的信息保存到dst中
     /// 并且将其写入到manifest
     pub fn encode_to(&self, dst: &mut Vec<u8>) {
@@ -232,6 +280,11 @@ impl VersionEdit {
             VarintU64::put_varint(dst, *last_seq);
         }
 
+        if let Some(bits) = &self.sequence_bits {
+            VarintU32::put_varint(dst, SequenceBits as u32);
+            VarintU32::put_varint(dst, *bits);
+        }
+
         for (level, key) in self.file_delta.compaction_pointers.iter() {
             VarintU32::put_varint(dst, CompactPointer as u32);
             VarintU32::put_varint(dst, *level as u32);
@@ -252,6 +305,23 @@ impl VersionEdit {
             VarintU32::put_varint_prefixed_slice(dst, file_meta.smallest.data());
             VarintU32::put_varint_prefixed_slice(dst, file_meta.largest.data());
         }
+
+        for (level, file_num) in self.staged_files.iter() {
+            VarintU32::put_varint(dst, StagedFile as u32);
+            VarintU32::put_varint(dst, *level as u32);
+            VarintU64::put_varint(dst, *file_num);
+        }
+
+        for (id, name) in self.column_families_added.iter() {
+            VarintU32::put_varint(dst, ColumnFamilyAdd as u32);
+            VarintU32::put_varint(dst, *id);
+            VarintU32::put_varint_prefixed_slice(dst, name.as_bytes());
+        }
+
+        for id in self.column_families_dropped.iter() {
+            VarintU32::put_varint(dst, ColumnFamilyDrop as u32);
+            VarintU32::put_varint(dst, *id);
+        }
     }
     // 将输入的二进制数组 src 解码并填充到调用对象的各个属性中
     pub fn decoded_from(&mut self, src: &[u8]) -> Result<()> {
@@ -351,6 +421,47 @@ impl VersionEdit {
                         msg.push_str("new-file entry");
                         break;
                     }
+                    StagedFile => {
+                        if let Some(level) = get_level(self.max_levels, &mut s) {
+                            if let Some(file_num) = VarintU64::drain_read(&mut s) {
+                                self.staged_files.push((level as usize, file_num));
+                                continue;
+                            }
+                        }
+                        msg.push_str("staged file");
+                        break;
+                    }
+                    ColumnFamilyAdd => {
+                        if let Some(id) = VarintU32::drain_read(&mut s) {
+                            if let Some(name) = VarintU32::get_varint_prefixed_slice(&mut s) {
+                                match String::from_utf8(name.to_owned()) {
+                                    Ok(name) => {
+                                        self.column_families_added.push((id, name));
+                                        continue;
+                                    }
+                                    Err(e) => return Err(Error::UTF8Error(e)),
+                                }
+                            }
+                        }
+                        msg.push_str("column family");
+                        break;
+                    }
+                    ColumnFamilyDrop => {
+                        if let Some(id) = VarintU32::drain_read(&mut s) {
+                            self.column_families_dropped.push(id);
+                        } else {
+                            msg.push_str("dropped column family");
+                            break;
+                        }
+                    }
+                    SequenceBits => {
+                        if let Some(bits) = VarintU32::drain_read(&mut s) {
+                            self.sequence_bits = Some(bits);
+                        } else {
+                            msg.push_str("sequence bits");
+                            break;
+                        }
+                    }
                     PrevLogNumber => {
                         // decode pre log number
                         if let Some(pre_ln) = VarintU64::drain_read(&mut s) {
@@ -398,6 +509,9 @@ impl Debug for VersionEdit {
         if let Some(last_seq) = &self.last_sequence {
             write!(f, "\n  LastSeq: {}", last_seq)?;
         }
+        if let Some(bits) = &self.sequence_bits {
+            write!(f, "\n  SequenceBits: {}", bits)?;
+        }
         for (level, key) in self.file_delta.compaction_pointers.iter() {
             write!(f, "\n  CompactPointer: @{} {:?}", level, key)?;
         }
@@ -411,6 +525,15 @@ impl Debug for VersionEdit {
                 level, meta.number, meta.file_size, meta.smallest, meta.largest
             )?;
         }
+        for (level, file_num) in self.staged_files.iter() {
+            write!(f, "\n  StagedFile: @{} #{}", level, file_num)?;
+        }
+        for (id, name) in self.column_families_added.iter() {
+            write!(f, "\n  ColumnFamilyAdd: {} {:?}", id, name)?;
+        }
+        for id in self.column_families_dropped.iter() {
+            write!(f, "\n  ColumnFamilyDrop: {}", id)?;
+        }
         write!(f, "\n}}\n")?;
         Ok(())
     }
@@ -465,6 +588,9 @@ mod tests {
                 InternalKey::new("zoo".as_bytes(), k_big + 700 + i, ValueType::Deletion),
             );
             edit.delete_file(4, k_big + 700 + i);
+            edit.stage_file(5, k_big + 800 + i);
+            edit.add_column_family(i as u32 + 1, format!("cf{}", i));
+            edit.drop_column_family(i as u32 + 1);
             edit.add_compaction_pointer(
                 i as usize,
                 InternalKey::new("x".as_bytes(), k_big + 900 + i, ValueType::Value),
@@ -474,6 +600,7 @@ mod tests {
         edit.set_log_number(k_big + 100);
         edit.set_next_file(k_big + 200);
         edit.set_last_sequence(k_big + 1000);
+        edit.set_sequence_bits(60);
         assert_encode_decode(&edit);
     }
 
@@ -2,32 +2,42 @@ use crate::compaction::{
     base_range, total_range, Compaction, CompactionInputs, CompactionReason, CompactionStats,
 };
 use crate::db::build_table;
-use crate::db::filename::{generate_filename, parse_filename, update_current, FileType};
-use crate::db::format::{InternalKey, InternalKeyComparator};
+use crate::db::column_family::ColumnFamilySet;
+use crate::db::filename::{
+    decode_current, generate_filename, parse_filename, update_current, FileType,
+};
+use crate::db::format::{
+    InternalKey, InternalKeyComparator, LEGACY_SEQUENCE_BITS, MAX_KEY_SEQUENCE, SEQUENCE_BITS,
+    VALUE_TYPE_FOR_SEEK,
+};
+use crate::db::read_stats::{IterOp, ReadCounters};
+use crate::db::recovery::RecoveryReport;
 use crate::iterator::Iterator;
 use crate::iterator::{ConcatenateIterator, DerivedIterFactory, KMergeCore, KMergeIter};
-use crate::options::Options;
+use crate::mem::MemTable;
+use crate::options::{CompactionPri, CompactionStyle, Options};
 use crate::record::reader::Reader;
 use crate::record::writer::Writer;
 use crate::snapshot::{Snapshot, SnapshotList};
-use crate::sstable::table::{TableBuilder, TableIterator};
-use crate::storage::{File, Storage};
+use crate::sstable::factory::TableReaderIterator;
+use crate::storage::{File, IoPriority, Storage};
 use crate::table_cache::TableCache;
 use crate::util::coding::decode_fixed_64;
-use crate::util::collection::HashSet;
+use crate::util::collection::{HashMap, HashSet};
 use crate::util::comparator::Comparator;
 use crate::util::reporter::LogReporter;
+use crate::version::universal::pick_universal_compaction;
 use crate::version::version_edit::{FileDelta, FileMetaData, VersionEdit};
-use crate::version::{LevelFileNumIterator, Version, FILE_META_LENGTH};
+use crate::version::{file_in_range, LevelFileNumIterator, Version, FILE_META_LENGTH};
 use crate::ReadOptions;
 use crate::{Error, Result};
 use std::cmp::Ordering as CmpOrdering;
-use std::ops::Add;
+use std::ops::{Add, Deref};
 use std::path::MAIN_SEPARATOR;
 use std::process::id;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 // 某个层级中被删除和新增的文件信息
 struct LevelDiff {
@@ -182,6 +192,11 @@ pub struct VersionSet<S: Storage + Clone, C: Comparator> {
     pub snapshots: SnapshotList,
     // 一组表文件的编号，这些表文件因为正在参与压缩操作而不能被删除
     pub pending_outputs: HashSet<u64>,
+    // 已经记录到 MANIFEST 但尚未安装的压缩输出文件 file_number -> level
+    // 崩溃后恢复时，仍处于该状态的文件都是残留的不完整输出
+    pub staged_outputs: HashMap<u64, usize>,
+    // MANIFEST 中记录的列族
+    pub(crate) column_families: ColumnFamilySet,
     // WAL 写入器
     pub record_writer: Option<Writer<S::F>>,
     // 数据库文件存储的路径
@@ -213,6 +228,34 @@ pub struct VersionSet<S: Storage + Clone, C: Comparator> {
 
     // 每一层压缩完成后的进度指针。这有助于数据库决定下一次压缩的起始点，以均衡整个数据库的压缩负载
     compaction_pointer: Vec<InternalKey>,
+
+    // The MANIFEST that describes the latest persisted state
+    live_manifest: LiveManifest,
+    // The backup of the superseded MANIFEST
+    manifest_backup: Option<ManifestBackup>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct LiveManifest {
+    // The number of the `MANIFEST-*` file.
+    // `None` if the state is recovered from `MANIFEST.bak`.
+    number: Option<u64>,
+    // The log numbers persisted in the MANIFEST
+    log_number: u64,
+    prev_log_number: u64,
+}
+
+// The MANIFEST superseded by the last size-based rollover and the files it
+// refers to. These files are not deleted until the next rollover so that the
+// db is still recoverable from the backup. All the logs since the backup are
+// kept too, since the flushes recorded by the newer MANIFEST are lost if it
+// falls back to the backup. The backup is dropped once it keeps more than
+// `max_manifest_backup_files` obsolete files.
+struct ManifestBackup {
+    manifest: LiveManifest,
+    tables: HashSet<u64>,
+    // The number of logs made obsolete since the backup
+    obsolete_logs: usize,
 }
 
 unsafe impl<S: Storage + Clone, C: Comparator> Send for VersionSet<S, C> {}
@@ -231,6 +274,8 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         Self {
             snapshots: SnapshotList::default(),
             pending_outputs: HashSet::default(),
+            staged_outputs: HashMap::default(),
+            column_families: ColumnFamilySet::default(),
             db_path,
             storage,
             record_writer: None,
@@ -244,6 +289,8 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             manifest_writer: None,
             versions,
             compaction_pointer,
+            live_manifest: LiveManifest::default(),
+            manifest_backup: None,
         }
     }
     /// 检查特定层级的文件数目。
@@ -323,33 +370,56 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
     }
 
     /// 返回一个可以遍历当前数据库中所有 SSTable 文件的迭代器
+    ///
+    /// Only the files overlapping the user key range `[lower, upper)` are included,
+    /// so the tables entirely outside the bounds of an iterator are never opened.
+    /// If `prefix` is given, which must be a prefix extracted by
+    /// `Options::prefix_extractor`, the files whose filters rule out the prefix are
+    /// excluded as well.
     pub fn current_sst_iter(
         &self,
         read_opt: ReadOptions,
         table_cache: TableCache<S, C>,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+        prefix: Option<&[u8]>,
+        stats: Option<Arc<ReadCounters>>,
     ) -> Result<KMergeIter<SSTableIters<S, C>>> {
         let version = self.current();
+        let ucmp = &self.icmp.user_comparator;
+        let in_range = |file: &Arc<FileMetaData>| {
+            file_in_range(ucmp, file, lower, upper)
+                && prefix.is_none_or(|prefix| {
+                    file_may_contain_prefix(&table_cache, read_opt, file, prefix)
+                })
+        };
         let mut level0 = vec![];
         //对于 Level 0，遍历所有文件，push table迭代器
-        for file in version.files[0].iter() {
-            level0.push(table_cache.new_iter(
-                self.icmp.clone(),
-                read_opt,
-                file.number,
-                file.file_size,
-            )?);
+        // The tables are opened lazily when the merge reaches their key ranges
+        let factory = FileIterFactory::new(self.icmp.clone(), read_opt, table_cache.clone());
+        for file in version.files[0].iter().filter(|f| in_range(f)) {
+            level0.push(TimedIterator::new(
+                LazyTableIterator::new(file.clone(), factory.clone()),
+                0,
+                stats.clone(),
+            ));
         }
 
         let mut leveln = vec![];
         // 对于大于 Level 0 的其他层级，它们的文件不会互相重叠，因此可以逐个顺序遍历
-        for files in version.files.iter().skip(1) {
+        for (level, files) in version.files.iter().enumerate().skip(1) {
+            let files: Vec<_> = files.iter().filter(|f| in_range(f)).cloned().collect();
             if !files.is_empty() {
-                let level_file_iter = LevelFileNumIterator::new(self.icmp.clone(), files.clone());
+                let level_file_iter = LevelFileNumIterator::new(self.icmp.clone(), files);
                 // 负责为每个文件提供实际的文件迭代器
                 let factory =
                     FileIterFactory::new(self.icmp.clone(), read_opt, table_cache.clone());
                 // push迭代器
-                leveln.push(ConcatenateIterator::new(level_file_iter, factory));
+                leveln.push(TimedIterator::new(
+                    ConcatenateIterator::new(level_file_iter, factory),
+                    level,
+                    stats.clone(),
+                ));
             }
         }
         let iter = KMergeIter::new(SSTableIters {
@@ -367,6 +437,17 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
     ///  一个层级移动到另一个层级
     ///   合并多个层级的多个 SSTable 文件
     pub fn log_and_apply(&mut self, mut edit: VersionEdit) -> Result<()> {
+        let installed: Vec<u64>;
+        // Roll over the MANIFEST once it's too large and keep the superseded one
+        // as the backup
+        let rollover = self
+            .manifest_writer
+            .as_ref()
+            .is_some_and(|w| w.written_bytes() >= self.options.max_manifest_file_size);
+        if rollover {
+            self.manifest_writer = None;
+            self.manifest_file_number = self.inc_next_file_number();
+        }
         let (v, encoded_edit) = {
             let level_summary_before = self.current().level_summary();
             if let Some(target_log) = edit.log_number {
@@ -388,6 +469,12 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
 
             let this = self.current();
             let mut builder = VersionBuilder::new(self.options.max_levels as usize, &this);
+            installed = edit
+                .file_delta
+                .new_files
+                .iter()
+                .map(|(_, f)| f.number)
+                .collect();
             builder.accumulate(edit.file_delta, self);
             let mut v = builder.apply_to_new(&self.icmp);
             v.finalize();
@@ -434,10 +521,31 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                                 self.manifest_writer = None;
                                 return self.storage.remove(new_manifest_file.as_str());
                             }
+                            if rollover {
+                                self.backup_manifest();
+                            } else if !new_manifest_file.is_empty() {
+                                self.live_manifest.number = Some(self.manifest_file_number);
+                            }
                             // install new version
+                            if let Some(backup) = self.manifest_backup.as_mut() {
+                                if edit.log_number.unwrap() > self.log_number {
+                                    backup.obsolete_logs += 1;
+                                }
+                            }
                             self.log_number = edit.log_number.unwrap();
                             self.prev_log_number = edit.prev_log_number.unwrap();
+                            self.live_manifest.log_number = self.log_number;
+                            self.live_manifest.prev_log_number = self.prev_log_number;
+                            for (level, number) in edit.staged_files.iter() {
+                                self.staged_outputs.insert(*number, *level);
+                            }
+                            for number in installed.iter() {
+                                self.staged_outputs.remove(number);
+                            }
+                            self.column_families
+                                .apply(&edit.column_families_added, &edit.column_families_dropped);
                             self.append_new_version(v);
+                            self.bound_manifest_backup();
                         }
                         // omit the sync error
                         Err(e) => {
@@ -456,6 +564,71 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         Ok(())
     }
 
+    // Keeps the state described by the superseded MANIFEST as the backup.
+    // Must be called right after a new MANIFEST is installed and before the new
+    // version is appended.
+    fn backup_manifest(&mut self) {
+        let mut tables = HashSet::default();
+        for files in self.current().files.iter() {
+            for f in files.iter() {
+                tables.insert(f.number);
+            }
+        }
+        self.manifest_backup = Some(ManifestBackup {
+            manifest: self.live_manifest,
+            tables,
+            obsolete_logs: 0,
+        });
+        self.live_manifest.number = Some(self.manifest_file_number);
+    }
+
+    // Drops the backup if it keeps more obsolete files than
+    // `max_manifest_backup_files`
+    fn bound_manifest_backup(&mut self) {
+        if let Some(backup) = self.manifest_backup.as_ref() {
+            let live_tables = self
+                .current()
+                .files
+                .iter()
+                .flat_map(|files| files.iter().map(|f| f.number))
+                .collect::<HashSet<_>>();
+            let obsolete_tables = backup.tables.difference(&live_tables).count();
+            let obsolete_files = obsolete_tables + backup.obsolete_logs;
+            if obsolete_files > self.options.max_manifest_backup_files {
+                info!(
+                    "Drop the backup MANIFEST keeping {} obsolete files",
+                    obsolete_files
+                );
+                self.manifest_backup = None;
+            }
+        }
+    }
+
+    /// Returns whether the superseded MANIFEST is kept as the backup
+    #[inline]
+    pub(crate) fn has_manifest_backup(&self) -> bool {
+        self.manifest_backup.is_some()
+    }
+
+    /// Returns the number of the MANIFEST that should be renamed to the backup MANIFEST
+    #[inline]
+    pub(crate) fn backup_manifest_number(&self) -> Option<u64> {
+        self.manifest_backup
+            .as_ref()
+            .and_then(|backup| backup.manifest.number)
+    }
+
+    /// Returns whether the given table or log file is referred by the backup MANIFEST
+    pub(crate) fn is_backup_file(&self, file_type: &FileType, number: u64) -> bool {
+        match (&self.manifest_backup, file_type) {
+            (Some(backup), FileType::Table) => backup.tables.contains(&number),
+            (Some(backup), FileType::Log) => {
+                number >= backup.manifest.log_number || number == backup.manifest.prev_log_number
+            }
+            _ => false,
+        }
+    }
+
     #[inline]
     fn append_new_version(&mut self, v: Version<C>) {
         self.versions.push(Arc::new(v));
@@ -468,7 +641,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         level: usize,
         begin: Option<&InternalKey>,
         end: Option<&InternalKey>,
-    ) -> Option<Compaction<S::F, C>> {
+    ) -> Option<Compaction<C>> {
         let version = self.current();
         // 查找重叠文件
         let mut overlapping_inputs = version.get_overlapping_inputs(level, begin, end);
@@ -497,9 +670,17 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
     }
 
     /// 用于选择并返回一个合适的压缩操作 如果没有需要进行的压缩，则返回 None
-    pub fn pick_compaction(&mut self) -> Option<Compaction<S::F, C>> {
+    /// The table properties are read from `table_cache` if the files are picked by
+    /// `CompactionPri::ByCompensatedSize`.
+    pub fn pick_compaction(&mut self, table_cache: &TableCache<S, C>) -> Option<Compaction<C>> {
         // 获取当前version和确定压缩触发条件
         let current = self.current();
+        match self.options.compaction_style {
+            CompactionStyle::Level => {}
+            CompactionStyle::Universal => return pick_universal_compaction(&current),
+            // Picked by the db since the creation times are read from the tables
+            CompactionStyle::Fifo => return None,
+        }
         // 基于数据量的压缩需求
         let size_compaction = current.compaction_score >= 1.0;
         let mut file_to_compact = Arc::new(FileMetaData::default());
@@ -526,23 +707,11 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                 );
                 //  基于数据量的压缩
                 let mut compaction = Compaction::new(self.options.clone(), level, CompactionReason::MaxSize);
-                // 选择compact_pointer[level]之后的第一个文件
-                for file in current.files[level].iter() {
-                    if self.compaction_pointer[level].is_empty()
-                        || self
-                            .icmp
-                            .compare(file.largest.data(), self.compaction_pointer[level].data())
-                            == CmpOrdering::Greater
-                    {
-                        compaction.inputs.add_base(file.clone());
-                        break;
-                    }
-                }
-                if compaction.inputs.base.is_empty() {
-                    if let Some(file) = current.files[0].first() {
-                        // Wrap-around to the beginning of the key space
-                        compaction.inputs.add_base(file.clone())
-                    }
+                let files = &current.files[level];
+                if !files.is_empty() {
+                    let candidates = self.order_files_by_priority(&current, level, table_cache);
+                    let i = self.pick_file_within_compaction_bytes(&current, level, candidates);
+                    compaction.inputs.add_base(files[i].clone());
                 }
                 compaction
             } else if seek_compaction {
@@ -556,6 +725,8 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                     // We've run out of the levels
                     return None;
                 }
+            } else if let Some(level) = current.small_file_level {
+                return self.pick_small_file_compaction(&current, level);
             } else {
                 return None;
             }
@@ -570,6 +741,8 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             compaction.inputs.base =
                 current.get_overlapping_inputs(compaction.level, Some(smallest), Some(largest));
             assert!(!compaction.inputs.base.is_empty());
+            // The levels above the base level are empty
+            compaction.output_level = current.base_level();
         }
         // 设置额外的输入文件
         compaction = self.setup_other_inputs(compaction);
@@ -588,13 +761,51 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         Some(compaction)
     }
 
+    // Picks the most consecutive files in `level` whose total size is within
+    // `max_compaction_bytes`, which are merged into fewer files in the same level
+    fn pick_small_file_compaction(
+        &self,
+        current: &Arc<Version<C>>,
+        level: usize,
+    ) -> Option<Compaction<C>> {
+        let files = &current.files[level];
+        let limit = self.options.max_compaction_bytes();
+        let (mut best, mut start, mut size) = ((0, 0), 0, 0);
+        for (end, f) in files.iter().enumerate() {
+            size += f.file_size;
+            while size > limit && start < end {
+                size -= files[start].file_size;
+                start += 1;
+            }
+            if end + 1 - start > best.1 - best.0 {
+                best = (start, end + 1);
+            }
+        }
+        if best.1 - best.0 < 2 {
+            return None;
+        }
+        let mut c = Compaction::new(self.options.clone(), level, CompactionReason::SmallFiles);
+        c.output_level = level;
+        c.inputs.base = files[best.0..best.1].to_vec();
+        // The older versions of the last user key in the next files must be merged
+        // too, or they would be revived if a deletion is dropped
+        add_boundary_inputs_for_compact_files(&self.icmp, files, &mut c.inputs.base);
+        if level + 1 < self.options.max_levels {
+            let (smallest, largest) = base_range(&c.inputs.base, level, &self.icmp);
+            c.grand_parents =
+                current.get_overlapping_inputs(level + 1, Some(smallest), Some(largest));
+        }
+        c.input_version = Some(current.clone());
+        Some(c)
+    }
+
     /// 它用于将内存中的 MemTable 转换成一个 SSTable 文件并将其写入到 Level 0 或根据条件选择更高的层级
     /// 如果 `into_base` 为true, 如果没有太多重叠，文件可以被推入 level1 或 level2。
     pub fn write_level0_files(
         &mut self,
         db_path: &str,
         table_cache: &TableCache<S, C>,
-        mem_iter: &mut dyn Iterator,
+        mem: &MemTable<C>,
         edit: &mut VersionEdit,
         into_base: bool,
     ) -> Result<()> {
@@ -603,31 +814,60 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             number: self.inc_next_file_number(),
             ..Default::default()
         };
+        trace_span!("wickdb.build_level0_table", file = meta.number);
         info!("Level-0 table #{} : start building", meta.number);
+        // 在写入 SST 之前校验 memtable 中的数据没有在内存中损坏
+        mem.verify_checksums()?;
         // 构建 SSTable
         let build_result = build_table(
             self.options.clone(),
             &self.storage,
             db_path,
             table_cache,
-            mem_iter,
+            &mut mem.iter(),
+            &mem.range_tombstones(),
             &mut meta,
         );
-        let mut level = 0;
+        let level = if build_result.is_ok() {
+            self.add_level0_file(&meta, edit, into_base)
+        } else {
+            0
+        };
+        info!(
+            "Compactions stats for Level{}: {:?}",
+            level,
+            CompactionStats {
+                micros: now.elapsed().unwrap().as_micros() as u64,
+                bytes_read: 0,
+                bytes_written: meta.file_size,
+            }
+        );
+        build_result
+    }
 
-        // 如果构建成功并且文件大小大于0，则根据 into_base 的值
-        // 可能会调用 pick_level_for_memtable_output 来选择一个更合适的层级来存储此文件
+    /// 将 memtable 构建出的表文件 `meta` 添加到 `edit` 中并返回其所在的层级
+    /// 如果 `into_base` 为true, 如果没有太多重叠，文件可以被推入 level1 或 level2。
+    pub(crate) fn add_level0_file(
+        &self,
+        meta: &FileMetaData,
+        edit: &mut VersionEdit,
+        into_base: bool,
+    ) -> usize {
+        let mut level = 0;
         // 如果“file_size”为零，则文件已被删除并且不应添加到清单中
-        if build_result.is_ok() && meta.file_size > 0 {
+        if meta.file_size > 0 {
             info!(
-                "Level-0 table #{} : add {} bytes [{:?}] [key range {:?} ... {:?}]",
-                meta.number, meta.file_size, &build_result, &meta.smallest, &meta.largest,
+                "Level-0 table #{} : add {} bytes [key range {:?} ... {:?}]",
+                meta.number, meta.file_size, &meta.smallest, &meta.largest,
             );
-            let smallest_ukey = meta.smallest.user_key();
-            let largest_ukey = meta.largest.user_key();
+            // 根据 into_base 的值可能会调用 pick_level_for_memtable_output
+            // 来选择一个更合适的层级来存储此文件
             if into_base {
                 let base = self.current();
-                level = base.pick_level_for_memtable_output(smallest_ukey, largest_ukey);
+                level = base.pick_level_for_memtable_output(
+                    meta.smallest.user_key(),
+                    meta.largest.user_key(),
+                );
                 debug!(
                     "Pick up new level for table: level {}, table #{}",
                     level, meta.number
@@ -641,28 +881,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                 meta.largest.clone(),
             );
         }
-        info!(
-            "Compactions stats for Level{}: {:?}",
-            level,
-            CompactionStats {
-                micros: now.elapsed().unwrap().as_micros() as u64,
-                bytes_read: 0,
-                bytes_written: meta.file_size,
-            }
-        );
-        build_result
-    }
-
-    /// 这个函数遍历所有版本对象中的文件，将所有“存活”的文件编号添加到 pending_outputs 集合中，防止它们被错误地删除。
-    #[inline]
-    pub fn lock_live_files(&mut self) {
-        for version in self.versions.iter() {
-            for files in version.files.iter() {
-                for f in files.iter() {
-                    self.pending_outputs.insert(f.number);
-                }
-            }
-        }
+        level
     }
 
     /// 返回当前版本元数据中的所有存活文件编号集合
@@ -680,53 +899,175 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
     }
 
     /// 为当前压缩任务（Compaction 对象）创建一个新的输出文件，并准备TableBuilder
-    pub(crate) fn create_compaction_output_file(
-        &mut self,
-        c: &mut Compaction<S::F, C>,
-    ) -> Result<()> {
+    pub(crate) fn create_compaction_output_file(&mut self, c: &mut Compaction<C>) -> Result<()> {
         assert!(c.builder.is_none());
-        // 生成一个新的文件编号
-        let file_number = self.inc_next_file_number();
-        // 将这个新文件编号添加到 pending_outputs 集合
-        self.pending_outputs.insert(file_number);
-        let output = FileMetaData {
+        if c.reserved_outputs.is_empty() {
+            // 在创建文件之前将其编号记录到 MANIFEST，这样即使压缩任务运行很久后崩溃，
+            // 恢复时也能找到并清理不完整的输出。一次登记一批编号，避免每个输出文件
+            // 都写一次 MANIFEST
+            let mut edit = VersionEdit::new(self.options.max_levels);
+            for _ in 0..c.output_numbers_to_reserve() {
+                let number = self.inc_next_file_number();
+                // 将这个新文件编号添加到 pending_outputs 集合
+                self.pending_outputs.insert(number);
+                edit.stage_file(c.output_level, number);
+                c.reserved_outputs.push(number);
+            }
+            // 先从小到大使用
+            c.reserved_outputs.reverse();
+            if let Err(e) = self.log_and_apply(edit) {
+                self.release_reserved_outputs(c);
+                return Err(e);
+            }
+        }
+        let file_number = c.reserved_outputs.pop().unwrap();
+        // 创建一个新的 FileMetaData 对象并设置文件编号
+        // 先加入输出列表，这样即使下面失败，压缩也能把它从 pending_outputs 中移除
+        c.outputs.push(FileMetaData {
             number: file_number,
             ..Default::default()
-        };
-        // 创建一个新的 FileMetaData 对象并设置文件编号
+        });
         let file_name = generate_filename(&self.db_path, FileType::Table, file_number);
         let file = self.storage.create(file_name.as_str())?;
-        // 使用 TableBuilder 为这个文件创建一个新的表构建器
-        c.builder = Some(TableBuilder::new(file, self.icmp.clone(), &self.options));
-        c.outputs.push(output);
+        // 使用 table_factory 为这个文件创建一个新的表构建器
+        let mut builder = self.options.table_factory.new_builder(
+            Box::new(file),
+            self.icmp.clone(),
+            &self.options,
+        );
+        builder.set_compression(c.output_compression());
+        builder.set_io_priority(IoPriority::Background);
+        c.builder = Some(builder);
         Ok(())
     }
 
+    /// Releases the file numbers reserved by the compaction but not used by any
+    /// output. They stay staged in the MANIFEST, which is harmless since no file
+    /// will ever take these numbers.
+    pub(crate) fn release_reserved_outputs(&mut self, c: &mut Compaction<C>) {
+        for number in c.reserved_outputs.drain(..) {
+            self.pending_outputs.remove(&number);
+            self.staged_outputs.remove(&number);
+        }
+    }
+
     /// Recover the last saved Version from MANIFEST file.
     /// Returns whether we need a new MANIFEST file for later usage.
-    pub fn recover(&mut self) -> Result<bool> {
-        let env = self.storage.clone();
+    ///
+    /// If the "CURRENT" file is corrupted, the newest MANIFEST file that can be
+    /// fully recovered from is used instead. If the MANIFEST pointed by "CURRENT"
+    /// is invalid, the backup MANIFEST of the previous generation is used instead.
+    pub fn recover(&mut self, report: &mut RecoveryReport) -> Result<bool> {
         // Read "CURRENT" file, which contains a pointer to the current manifest file
-        let mut current = env.open(&generate_filename(&self.db_path, FileType::Current, 0))?;
+        let mut current =
+            self.storage
+                .open(generate_filename(&self.db_path, FileType::Current, 0))?;
         let mut buf = vec![];
         current.read_all(&mut buf)?;
-        let (current_manifest, file_name) = match String::from_utf8(buf) {
-            Ok(s) => {
-                if s.is_empty() {
-                    return Err(Error::Corruption("CURRENT file is empty".to_owned()));
-                }
+        match decode_current(&buf) {
+            Ok(manifest) => {
                 let mut file_name = self.db_path.to_owned();
                 file_name.push(MAIN_SEPARATOR);
-                let file_name = file_name.add(&s);
-                (env.open(&file_name)?, file_name)
+                let file_name = file_name.add(&manifest);
+                match self.recover_from_manifest(&file_name, false, report) {
+                    Err(e @ Error::Corruption(_)) | Err(e @ Error::IO(_)) => {
+                        self.recover_from_backup_manifest(&file_name, e, report)
+                    }
+                    res => res,
+                }
+            }
+            Err(Error::Corruption(reason)) => {
+                warn!("{}, try recovering from the newest valid MANIFEST", reason);
+                self.recover_from_newest_manifest(reason, report)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Recovers from the backup MANIFEST since the MANIFEST `failed` is invalid.
+    // Returns the error of `failed` if the backup is also unavailable.
+    fn recover_from_backup_manifest(
+        &mut self,
+        failed: &str,
+        err: Error,
+        report: &mut RecoveryReport,
+    ) -> Result<bool> {
+        let backup = generate_filename(&self.db_path, FileType::ManifestBackup, 0);
+        if !self.storage.exists(&backup) {
+            return Err(err);
+        }
+        warn!(
+            "Invalid MANIFEST {}: {:?}, fall back to the backup {}",
+            failed, err, &backup
+        );
+        match self.recover_from_manifest(&backup, true, report) {
+            Ok(should_save_manifest) => {
+                info!("Recovered from the backup {}", &backup);
+                // The files created after the backup, e.g. the tables flushed from
+                // the logs to replay, must not be overwritten by the new files
+                for path in self.storage.list(&self.db_path)? {
+                    if let Some((_, number)) = parse_filename(&path) {
+                        self.mark_file_number_used(number);
+                    }
+                }
+                Ok(should_save_manifest)
             }
             Err(e) => {
-                return Err(Error::Corruption(format!(
-                    "Invalid CURRENT file content: {}",
-                    e
-                )));
+                error!("Invalid backup MANIFEST {}: {:?}", &backup, e);
+                Err(err)
             }
-        };
+        }
+    }
+
+    // Tries all the MANIFEST files in the db directory from the newest to the oldest
+    // and recovers from the first valid one. The backup MANIFEST is tried at last.
+    // A new MANIFEST file is always required so that the "CURRENT" file gets rewritten.
+    fn recover_from_newest_manifest(
+        &mut self,
+        current_err: String,
+        report: &mut RecoveryReport,
+    ) -> Result<bool> {
+        let mut manifests = vec![];
+        for path in self.storage.list(&self.db_path)? {
+            match parse_filename(&path) {
+                Some((FileType::Manifest, number)) => manifests.push((number + 1, path)),
+                Some((FileType::ManifestBackup, _)) => manifests.push((0, path)),
+                _ => {}
+            }
+        }
+        manifests.sort_by_key(|(number, _)| std::cmp::Reverse(*number));
+        for (_, path) in manifests {
+            let file_name = match path.to_str() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            match self.recover_from_manifest(&file_name, true, report) {
+                Ok(should_save_manifest) => {
+                    debug_assert!(should_save_manifest);
+                    info!("Recovered from {} instead of CURRENT", &file_name);
+                    return Ok(should_save_manifest);
+                }
+                Err(e) => warn!("Skip invalid MANIFEST {}: {:?}", &file_name, e),
+            }
+        }
+        Err(Error::Corruption(format!(
+            "{}, and no valid MANIFEST file is found",
+            current_err
+        )))
+    }
+
+    // Recovers the last saved Version from the given MANIFEST file.
+    // The `VersionSet` is only updated if the whole MANIFEST is valid.
+    //
+    // If `is_fallback` is true, the MANIFEST is not the one pointed by "CURRENT". So it's
+    // never reused and all the tables it refers to must exist.
+    fn recover_from_manifest(
+        &mut self,
+        file_name: &str,
+        is_fallback: bool,
+        report: &mut RecoveryReport,
+    ) -> Result<bool> {
+        let current_manifest = self.storage.open(file_name)?;
         let file_length = current_manifest.len()?;
         let base = Version::new(self.options.clone(), self.icmp.clone());
         let mut builder = VersionBuilder::new(self.options.max_levels as usize, &base);
@@ -742,10 +1083,15 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         let mut has_prev_log_number = false;
         let mut last_sequence = 0;
         let mut has_last_sequence = false;
+        let mut sequence_bits = LEGACY_SEQUENCE_BITS;
+        let mut records = 0;
+        let mut staged_outputs = HashMap::default();
+        let mut column_families = ColumnFamilySet::default();
         while reader.read_record(&mut buf) {
             if let Err(e) = reporter.result() {
                 return Err(e);
             }
+            records += 1;
             let mut edit = VersionEdit::new(self.options.max_levels);
             edit.decoded_from(&buf)?;
             debug!("Decoded manifest record: {:?}", &edit);
@@ -756,6 +1102,13 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                     ));
                 }
             }
+            for (level, number) in edit.staged_files.iter() {
+                staged_outputs.insert(*number, *level);
+            }
+            for (_, f) in edit.file_delta.new_files.iter() {
+                staged_outputs.remove(&f.number);
+            }
+            column_families.apply(&edit.column_families_added, &edit.column_families_dropped);
             builder.accumulate(edit.file_delta, self);
             if let Some(n) = edit.next_file_number {
                 next_file_number = n;
@@ -773,6 +1126,9 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                 last_sequence = n;
                 has_last_sequence = true;
             }
+            if let Some(n) = edit.sequence_bits {
+                sequence_bits = n;
+            }
         }
 
         if let Err(e) = reporter.result() {
@@ -799,18 +1155,73 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             prev_log_number = 0;
         }
 
+        // The internal keys are not decodable with a different sequence format
+        if sequence_bits != SEQUENCE_BITS {
+            return Err(Error::InvalidArgument(format!(
+                "the database uses {} bits sequence numbers but {} bits are supported (see the `extended-sequence` feature)",
+                sequence_bits, SEQUENCE_BITS
+            )));
+        }
+
+        let mut new_v = builder.apply_to_new(&self.icmp);
+        if is_fallback {
+            for files in new_v.files.iter() {
+                for f in files.iter() {
+                    let table = generate_filename(&self.db_path, FileType::Table, f.number);
+                    if !self.storage.exists(&table) {
+                        return Err(Error::Corruption(format!(
+                            "{} refers to a missing table {}",
+                            file_name, table
+                        )));
+                    }
+                }
+            }
+            // The files written after this MANIFEST may still exist so their
+            // numbers must not be reused.
+            for path in self.storage.list(&self.db_path)? {
+                if let Some((_, number)) = parse_filename(&path) {
+                    next_file_number = next_file_number.max(number + 1);
+                }
+            }
+        }
+
+        // The compactions that have staged these outputs never finished. The
+        // numbers reserved but never used have no files.
+        let mut orphans_removed = false;
+        for number in staged_outputs.keys() {
+            let table = generate_filename(&self.db_path, FileType::Table, *number);
+            if self.storage.exists(&table) {
+                info!("Delete orphaned compaction output {}", &table);
+                self.storage.remove(&table)?;
+                report.orphaned_outputs_removed += 1;
+                orphans_removed = true;
+            }
+        }
+
         self.mark_file_number_used(prev_log_number);
         self.mark_file_number_used(log_number);
+        report.manifest = file_name.to_owned();
+        report.manifest_fallback = is_fallback;
+        report.manifest_records_applied = records;
+        self.live_manifest = LiveManifest {
+            number: match parse_filename(file_name) {
+                Some((FileType::Manifest, number)) => Some(number),
+                _ => None,
+            },
+            log_number,
+            prev_log_number,
+        };
 
-        let mut new_v = builder.apply_to_new(&self.icmp);
         new_v.finalize();
         self.versions.push(Arc::new(new_v));
+        self.column_families = column_families;
         self.manifest_file_number = next_file_number;
         self.next_file_number = next_file_number + 1;
         self.last_sequence = last_sequence;
         self.log_number = log_number;
         self.prev_log_number = prev_log_number;
-        Ok(!self.should_reuse_manifest(&file_name, file_length))
+        // A new MANIFEST is required to drop the removed outputs
+        Ok(is_fallback || orphans_removed || !self.should_reuse_manifest(file_name, file_length))
     }
 
     /// Forward to `num + 1` as the next file number
@@ -856,6 +1267,9 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         let mut edit = VersionEdit::new(self.options.max_levels);
         // Save metadata
         edit.set_comparator_name(String::from(self.icmp.user_comparator.name()));
+        if SEQUENCE_BITS != LEGACY_SEQUENCE_BITS {
+            edit.set_sequence_bits(SEQUENCE_BITS);
+        }
         // Save compaction pointers
         for level in 0..self.options.max_levels as usize {
             if !self.compaction_pointer[level].is_empty() {
@@ -878,16 +1292,116 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             }
         }
 
+        // Save the outputs of the running compaction
+        for (number, level) in self.staged_outputs.iter() {
+            edit.stage_file(*level, *number);
+        }
+
+        // Save column families
+        self.column_families.save_to(&mut edit);
+
         let mut record = vec![];
         edit.encode_to(&mut record);
         writer.add_record(&record)?;
         Ok(())
     }
 
-    // Pick up files to compact in `c.level+1` based on given compaction
+    // Returns the indexes of the files in `level` in the order they should be
+    // compacted according to `Options::compaction_pri`
+    fn order_files_by_priority(
+        &self,
+        current: &Version<C>,
+        level: usize,
+        table_cache: &TableCache<S, C>,
+    ) -> Vec<usize> {
+        let files = &current.files[level];
+        let pri = if level == 0 {
+            // The overlapping files in level 0 are all picked anyway
+            CompactionPri::RoundRobin
+        } else {
+            self.options.compaction_pri
+        };
+        let key = |f: &FileMetaData| -> u64 {
+            match pri {
+                CompactionPri::RoundRobin => 0,
+                CompactionPri::MinOverlappingRatio => {
+                    let overlaps = current.get_overlapping_inputs(
+                        level + 1,
+                        Some(&f.smallest),
+                        Some(&f.largest),
+                    );
+                    total_file_size(&overlaps).saturating_mul(1024) / f.file_size.max(1)
+                }
+                CompactionPri::OldestSmallestSeqFirst => f.number,
+                CompactionPri::ByCompensatedSize => {
+                    u64::MAX - compensated_file_size(f, table_cache)
+                }
+            }
+        };
+        if pri == CompactionPri::RoundRobin {
+            // 选择compact_pointer[level]之后的第一个文件
+            let start = files
+                .iter()
+                .position(|file| {
+                    self.compaction_pointer[level].is_empty()
+                        || self
+                            .icmp
+                            .compare(file.largest.data(), self.compaction_pointer[level].data())
+                            == CmpOrdering::Greater
+                })
+                // Wrap-around to the beginning of the key space
+                .unwrap_or(0);
+            return (start..files.len()).chain(0..start).collect();
+        }
+        let mut keyed: Vec<(u64, usize)> = files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (key(f), i))
+            .collect();
+        keyed.sort_unstable();
+        keyed.into_iter().map(|(_, i)| i).collect()
+    }
+
+    // Returns the index of the first file in `candidates` whose size plus the
+    // size of its overlapping files in the next level is within
+    // `max_compaction_bytes`. If there is no such file, returns the one with the
+    // fewest bytes so the level is still compacted.
+    fn pick_file_within_compaction_bytes(
+        &self,
+        current: &Version<C>,
+        level: usize,
+        candidates: Vec<usize>,
+    ) -> usize {
+        if level == 0 {
+            // The overlapping files in level 0 are all picked anyway
+            return candidates[0];
+        }
+        let files = &current.files[level];
+        let limit = self.options.max_compaction_bytes();
+        let mut best = (candidates[0], u64::MAX);
+        for i in candidates {
+            let f = &files[i];
+            let overlaps =
+                current.get_overlapping_inputs(level + 1, Some(&f.smallest), Some(&f.largest));
+            let bytes = f.file_size + total_file_size(&overlaps);
+            if bytes <= limit {
+                return i;
+            }
+            if bytes < best.1 {
+                best = (i, bytes);
+            }
+        }
+        info!(
+            "No file in level {} is within max_compaction_bytes {}, picking one of {} bytes",
+            level, limit, best.1
+        );
+        best.0
+    }
+
+    // Pick up files to compact in `c.output_level` based on given compaction
     // The input files in `c.level` might expand because of getting a large key range from newly picked files
-    // in `c.level + 1`. And the final key range in `c.level + 1` should be a subset of `c.level`
-    fn setup_other_inputs(&mut self, c: Compaction<S::F, C>) -> Compaction<S::F, C> {
+    // in `c.output_level`. And the final key range in `c.output_level` should be a subset of `c.level`
+    fn setup_other_inputs(&mut self, c: Compaction<C>) -> Compaction<C> {
         let mut c = self.add_boundary_inputs(c);
         let current = &self.current();
         let inputs = std::mem::take(&mut c.inputs);
@@ -896,7 +1410,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         let (smallest, largest) = base_range(&not_expand, c.level, &self.icmp);
         // figure out the overlapping files in next level
         let overlapping_next_level =
-            current.get_overlapping_inputs(c.level + 1, Some(smallest), Some(largest));
+            current.get_overlapping_inputs(c.output_level, Some(smallest), Some(largest));
         // Re-calculate total key range of inputting files for compaction
         let (all_smallest, all_largest) =
             total_range(&not_expand, &overlapping_next_level, c.level, &self.icmp);
@@ -920,12 +1434,12 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             let next_size = total_file_size(&overlapping_next_level);
             // We do expand the current(`c.level`) inputs and not reach the compaction size limit
             if expanded0.len() > not_expand.len()
-                && next_size + expanded0_size <= self.options.expanded_compaction_byte_size_limit()
+                && next_size + expanded0_size <= self.options.max_compaction_bytes()
             {
                 let (new_smallest, new_largest) = base_range(&expanded0, c.level, &self.icmp);
                 // TODO: use a more sufficient way to checking expanding in L(n+1) ?
                 let expanded_next = current.get_overlapping_inputs(
-                    c.level + 1,
+                    c.output_level,
                     Some(new_smallest),
                     Some(new_largest),
                 );
@@ -950,6 +1464,9 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                     // Use previous un-expanded next level files.
                     (expanded0, overlapping_next_level)
                 }
+            } else if expanded0.len() > not_expand.len() {
+                // Expanding exceeds the limit
+                (not_expand, overlapping_next_level)
             } else {
                 (expanded0, overlapping_next_level)
             }
@@ -961,10 +1478,10 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         let (final_smallest, final_largest) =
             total_range(&current_files, &next_files, c.level, &self.icmp);
         // Compute the set of grandparent files that overlap this compaction
-        // (parent == level+1; grandparent == level+2)
-        if c.level + 2 < self.options.max_levels as usize {
+        // (parent == output_level; grandparent == output_level+1)
+        if c.output_level + 1 < self.options.max_levels {
             c.grand_parents = current.get_overlapping_inputs(
-                c.level + 2,
+                c.output_level + 1,
                 Some(final_smallest),
                 Some(final_largest),
             );
@@ -980,6 +1497,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         self.compaction_pointer[c.level] = final_largest.clone();
         let final_inputs = CompactionInputs {
             base: current_files,
+            intermediate: vec![],
             parent: next_files,
         };
         c.inputs = final_inputs;
@@ -987,7 +1505,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
     }
 
     // A helper of 'add_boundary_input_for_compact_files' for files in `c.level`
-    fn add_boundary_inputs(&self, mut c: Compaction<S::F, C>) -> Compaction<S::F, C> {
+    fn add_boundary_inputs(&self, mut c: Compaction<C>) -> Compaction<C> {
         let level_files = &self.current().files[c.level];
         add_boundary_inputs_for_compact_files(&self.icmp, level_files, &mut c.inputs.base);
         c
@@ -1005,7 +1523,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             match self.storage.open(manifest_file) {
                 Ok(f) => {
                     info!("Reusing MANIFEST {}", manifest_file);
-                    let writer = Writer::new(f);
+                    let writer = Writer::with_length(f, file_size);
                     self.manifest_writer = Some(writer);
                     self.manifest_file_number = file_number;
                     true
@@ -1081,6 +1599,22 @@ fn find_smallest_boundary_file<C: Comparator>(
     smallest_boundary_file.cloned()
 }
 
+// Returns whether the table of `file` may hold a key starting with `prefix` by
+// probing its filters. The table is kept if it can't be opened so the error is
+// reported by the iterator.
+fn file_may_contain_prefix<S: Storage + Clone, C: Comparator + 'static>(
+    table_cache: &TableCache<S, C>,
+    read_opt: ReadOptions,
+    file: &FileMetaData,
+    prefix: &[u8],
+) -> bool {
+    let target = InternalKey::new(prefix, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+    table_cache
+        .find_table(file.number, file.file_size)
+        .and_then(|table| table.prefix_may_match(read_opt, target.data(), prefix))
+        .unwrap_or(true)
+}
+
 pub struct FileIterFactory<S: Storage + Clone, C: Comparator> {
     options: ReadOptions,
     table_cache: TableCache<S, C>,
@@ -1102,7 +1636,7 @@ impl<S: Storage + Clone, C: Comparator> FileIterFactory<S, C> {
 }
 
 impl<S: Storage + Clone, C: Comparator + 'static> DerivedIterFactory for FileIterFactory<S, C> {
-    type Iter = TableIterator<InternalKeyComparator<C>, S::F>;
+    type Iter = TableReaderIterator;
 
     // The value is a bytes with fixed encoded file number and fixed encoded file size
     fn derive(&self, value: &[u8]) -> Result<Self::Iter> {
@@ -1114,7 +1648,300 @@ impl<S: Storage + Clone, C: Comparator + 'static> DerivedIterFactory for FileIte
             let file_number = decode_fixed_64(value);
             let file_size = decode_fixed_64(&value[std::mem::size_of::<u64>()..]);
             self.table_cache
-                .new_iter(self.icmp.clone(), self.options, file_number, file_size)
+                .new_iter(self.options, file_number, file_size)
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator> Clone for FileIterFactory<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            options: self.options,
+            table_cache: self.table_cache.clone(),
+            icmp: self.icmp.clone(),
+        }
+    }
+}
+
+enum LazyState<I> {
+    // Not positioned yet or no entry at the position
+    Invalid,
+    // Positioned at the first entry of the unopened table
+    First,
+    // Positioned at the last entry of the unopened table
+    Last,
+    Opened(I),
+}
+
+/// An iterator over a level 0 table that opens the table only when it's needed.
+///
+/// A seek whose target is outside the key range of the file is answered from
+/// the file metadata. If the target is before the file, the iterator stays
+/// unopened but valid, reporting the smallest key of the file as its key (the
+/// largest key after `seek_to_last`) until `SSTableIters` finds that the merge
+/// has reached the file and calls `open`.
+pub struct LazyTableIterator<S: Storage + Clone, C: Comparator + 'static> {
+    file: Arc<FileMetaData>,
+    factory: FileIterFactory<S, C>,
+    state: LazyState<TableReaderIterator>,
+    err: Option<Error>,
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> LazyTableIterator<S, C> {
+    pub fn new(file: Arc<FileMetaData>, factory: FileIterFactory<S, C>) -> Self {
+        Self {
+            file,
+            factory,
+            state: LazyState::Invalid,
+            err: None,
+        }
+    }
+
+    /// Returns true if the table is not opened but positioned at its first entry
+    #[inline]
+    pub fn pending_first(&self) -> bool {
+        matches!(self.state, LazyState::First)
+    }
+
+    /// Returns true if the table is not opened but positioned at its last entry
+    #[inline]
+    pub fn pending_last(&self) -> bool {
+        matches!(self.state, LazyState::Last)
+    }
+
+    #[inline]
+    pub fn is_opened(&self) -> bool {
+        matches!(self.state, LazyState::Opened(_))
+    }
+
+    /// Opens the table and moves to the pending position
+    pub fn open(&mut self) {
+        if self.is_opened() {
+            return;
+        }
+        match self.factory.table_cache.new_iter(
+            self.factory.options,
+            self.file.number,
+            self.file.file_size,
+        ) {
+            Ok(mut iter) => {
+                match self.state {
+                    LazyState::First => iter.seek_to_first(),
+                    LazyState::Last => iter.seek_to_last(),
+                    _ => {}
+                }
+                self.state = LazyState::Opened(iter);
+            }
+            Err(e) => {
+                if self.err.is_none() {
+                    self.err = Some(e);
+                }
+                self.state = LazyState::Invalid;
+            }
+        }
+    }
+
+    fn opened(&mut self) -> Option<&mut TableReaderIterator> {
+        match &mut self.state {
+            LazyState::Opened(iter) => Some(iter),
+            _ => None,
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> Iterator for LazyTableIterator<S, C> {
+    fn valid(&self) -> bool {
+        match &self.state {
+            LazyState::Invalid => false,
+            LazyState::First | LazyState::Last => true,
+            LazyState::Opened(iter) => iter.valid(),
+        }
+    }
+
+    fn seek_to_first(&mut self) {
+        match self.opened() {
+            Some(iter) => iter.seek_to_first(),
+            None => self.state = LazyState::First,
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        match self.opened() {
+            Some(iter) => iter.seek_to_last(),
+            None => self.state = LazyState::Last,
+        }
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        if let Some(iter) = self.opened() {
+            return iter.seek(target);
+        }
+        let icmp = &self.factory.icmp;
+        if icmp.compare(target, self.file.largest.data()) == CmpOrdering::Greater {
+            self.state = LazyState::Invalid;
+        } else if icmp.compare(target, self.file.smallest.data()) != CmpOrdering::Greater {
+            self.state = LazyState::First;
+        } else {
+            self.open();
+            if let Some(iter) = self.opened() {
+                iter.seek(target);
+            }
+        }
+    }
+
+    fn next(&mut self) {
+        if self.pending_last() {
+            // Nothing after the last entry
+            self.state = LazyState::Invalid;
+            return;
+        }
+        self.open();
+        if let Some(iter) = self.opened() {
+            if iter.valid() {
+                iter.next();
+            }
+        }
+    }
+
+    fn prev(&mut self) {
+        if self.pending_first() {
+            // Nothing before the first entry
+            self.state = LazyState::Invalid;
+            return;
+        }
+        self.open();
+        if let Some(iter) = self.opened() {
+            if iter.valid() {
+                iter.prev();
+            }
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        match &self.state {
+            LazyState::First => self.file.smallest.data(),
+            LazyState::Last => self.file.largest.data(),
+            LazyState::Opened(iter) => iter.key(),
+            LazyState::Invalid => panic!("[lazy table iterator] invalid iterator"),
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        match &self.state {
+            LazyState::Opened(iter) => iter.value(),
+            _ => panic!("[lazy table iterator] table not opened"),
+        }
+    }
+
+    fn status(&mut self) -> Result<()> {
+        if let Some(e) = self.err.take() {
+            return Err(e);
+        }
+        match self.opened() {
+            Some(iter) => iter.status(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An iterator recording the latency of the operations on the tables of a
+/// level into the `ReadCounters` if given
+pub struct TimedIterator<I: Iterator> {
+    inner: I,
+    level: usize,
+    stats: Option<Arc<ReadCounters>>,
+}
+
+impl<I: Iterator> TimedIterator<I> {
+    pub(crate) fn new(inner: I, level: usize, stats: Option<Arc<ReadCounters>>) -> Self {
+        Self {
+            inner,
+            level,
+            stats,
+        }
+    }
+
+    #[inline]
+    fn timed<F: FnOnce(&mut I)>(&mut self, op: IterOp, f: F) {
+        match &self.stats {
+            Some(stats) => {
+                let start = Instant::now();
+                f(&mut self.inner);
+                stats.record_iter_latency(self.level, op, start.elapsed().as_nanos() as u64);
+            }
+            None => f(&mut self.inner),
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> TimedIterator<LazyTableIterator<S, C>> {
+    /// Opens the table, which is timed as a seek
+    pub fn open(&mut self) {
+        self.timed(IterOp::Seek, |iter| iter.open())
+    }
+}
+
+impl<I: Iterator> Deref for TimedIterator<I> {
+    type Target = I;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<I: Iterator> Iterator for TimedIterator<I> {
+    fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.timed(IterOp::Seek, |iter| iter.seek_to_first())
+    }
+
+    fn seek_to_last(&mut self) {
+        self.timed(IterOp::Seek, |iter| iter.seek_to_last())
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.timed(IterOp::Seek, |iter| iter.seek(target))
+    }
+
+    fn next(&mut self) {
+        self.timed(IterOp::Next, |iter| iter.next())
+    }
+
+    fn prev(&mut self) {
+        self.timed(IterOp::Next, |iter| iter.prev())
+    }
+
+    fn key(&self) -> &[u8] {
+        self.inner.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.inner.value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.inner.status()
+    }
+}
+
+// Returns the size of `f` plus twice the average size of its entries for each
+// point deletion, which approximates the space the tombstones would reclaim
+fn compensated_file_size<S: Storage + Clone, C: Comparator + 'static>(
+    f: &FileMetaData,
+    table_cache: &TableCache<S, C>,
+) -> u64 {
+    match table_cache.find_table(f.number, f.file_size) {
+        Ok(table) => match table.properties() {
+            Some(p) if p.num_entries > 0 => f
+                .file_size
+                .saturating_add(2 * p.num_deletions * (f.file_size / p.num_entries)),
+            _ => f.file_size,
+        },
+        Err(e) => {
+            warn!("Fail to read the properties of table #{}: {:?}", f.number, e);
+            f.file_size
         }
     }
 }
@@ -1129,22 +1956,32 @@ pub fn total_file_size(files: &[Arc<FileMetaData>]) -> u64 {
 /// The inner implementation is mostly like a merging iterator.
 pub struct SSTableIters<S: Storage + Clone, C: Comparator + 'static> {
     cmp: InternalKeyComparator<C>,
-    // Level0 table iterators. One iterator for one sst file
-    level0: Vec<TableIterator<InternalKeyComparator<C>, S::F>>,
+    // Level0 table iterators. One iterator for one sst file, opened only when
+    // the merge reaches its key range
+    level0: Vec<TimedIterator<LazyTableIterator<S, C>>>,
     // ConcatenateIterators for opening SST in level n>1 lazily. One iterator for one level
-    leveln: Vec<ConcatenateIterator<LevelFileNumIterator<C>, FileIterFactory<S, C>>>,
+    leveln: Vec<TimedIterator<LevelIterator<S, C>>>,
 }
 
+type LevelIterator<S, C> = ConcatenateIterator<LevelFileNumIterator<C>, FileIterFactory<S, C>>;
+
 impl<S: Storage + Clone, C: Comparator> SSTableIters<S, C> {
     pub fn new(
         cmp: InternalKeyComparator<C>,
-        level0: Vec<TableIterator<InternalKeyComparator<C>, S::F>>,
-        leveln: Vec<ConcatenateIterator<LevelFileNumIterator<C>, FileIterFactory<S, C>>>,
+        level0: Vec<LazyTableIterator<S, C>>,
+        leveln: Vec<LevelIterator<S, C>>,
     ) -> Self {
+        // The levels are not known and not needed without the stats
         Self {
             cmp,
-            level0,
-            leveln,
+            level0: level0
+                .into_iter()
+                .map(|iter| TimedIterator::new(iter, 0, None))
+                .collect(),
+            leveln: leveln
+                .into_iter()
+                .map(|iter| TimedIterator::new(iter, 0, None))
+                .collect(),
         }
     }
 }
@@ -1159,40 +1996,82 @@ impl<S: Storage + Clone, C: Comparator> KMergeCore for SSTableIters<S, C> {
         self.level0.len() + self.leveln.len()
     }
 
-    // Find the iterator with the smallest 'key' and set it as current
+    // Find the iterator with the smallest 'key' and set it as current.
+    // The unopened level 0 tables are opened in the order of their smallest keys
+    // until none of them could have a key smaller than the current smallest one
     fn find_smallest(&mut self) -> usize {
-        let mut smallest: Option<&[u8]> = None;
-        let mut index = self.iters_len();
-        for (i, child) in self.level0.iter().enumerate() {
-            if self.smaller(&mut smallest, child) {
-                index = i
+        loop {
+            let mut smallest: Option<&[u8]> = None;
+            let mut index = self.iters_len();
+            for (i, child) in self.level0.iter().enumerate() {
+                if child.is_opened() && self.smaller(&mut smallest, child) {
+                    index = i
+                }
             }
-        }
 
-        for (i, child) in self.leveln.iter().enumerate() {
-            if self.smaller(&mut smallest, child) {
-                index = i + self.level0.len()
+            for (i, child) in self.leveln.iter().enumerate() {
+                if self.smaller(&mut smallest, child) {
+                    index = i + self.level0.len()
+                }
+            }
+
+            let mut nearest: Option<&[u8]> = None;
+            let mut pending = None;
+            for (i, child) in self.level0.iter().enumerate() {
+                if child.pending_first() && self.smaller(&mut nearest, child) {
+                    pending = Some(i)
+                }
+            }
+            match pending {
+                Some(i)
+                    if smallest.is_none_or(|s| {
+                        self.cmp.compare(nearest.unwrap(), s) != CmpOrdering::Greater
+                    }) =>
+                {
+                    self.level0[i].open()
+                }
+                _ => return index,
             }
         }
-        index
     }
 
-    // Find the iterator with the largest 'key' and set it as current
+    // Find the iterator with the largest 'key' and set it as current.
+    // The unopened level 0 tables are opened in the order of their largest keys
+    // until none of them could have a key larger than the current largest one
     fn find_largest(&mut self) -> usize {
-        let mut largest: Option<&[u8]> = None;
-        let mut index = self.iters_len();
-        for (i, child) in self.level0.iter().enumerate() {
-            if self.larger(&mut largest, child) {
-                index = i
+        loop {
+            let mut largest: Option<&[u8]> = None;
+            let mut index = self.iters_len();
+            for (i, child) in self.level0.iter().enumerate() {
+                if child.is_opened() && self.larger(&mut largest, child) {
+                    index = i
+                }
+            }
+
+            for (i, child) in self.leveln.iter().enumerate() {
+                if self.larger(&mut largest, child) {
+                    index = i + self.level0.len()
+                }
             }
-        }
 
-        for (i, child) in self.leveln.iter().enumerate() {
-            if self.larger(&mut largest, child) {
-                index = i + self.level0.len()
+            let mut nearest: Option<&[u8]> = None;
+            let mut pending = None;
+            for (i, child) in self.level0.iter().enumerate() {
+                if child.pending_last() && self.larger(&mut nearest, child) {
+                    pending = Some(i)
+                }
+            }
+            match pending {
+                Some(i)
+                    if largest.is_none_or(|l| {
+                        self.cmp.compare(nearest.unwrap(), l) != CmpOrdering::Less
+                    }) =>
+                {
+                    self.level0[i].open()
+                }
+                _ => return index,
             }
         }
-        index
     }
 
     fn get_child(&self, i: usize) -> &dyn Iterator {
@@ -1229,18 +2108,15 @@ impl<S: Storage + Clone, C: Comparator> KMergeCore for SSTableIters<S, C> {
     where
         F: FnMut(&mut dyn Iterator, &Self::Cmp),
     {
-        if n < self.level0.len() {
-            for (i, child) in self.level0.iter_mut().enumerate() {
-                if i != n {
-                    f(child as &mut dyn Iterator, &self.cmp)
-                }
+        let level0_len = self.level0.len();
+        for (i, child) in self.level0.iter_mut().enumerate() {
+            if i != n {
+                f(child as &mut dyn Iterator, &self.cmp)
             }
-        } else {
-            let current = n - self.level0.len();
-            for (i, child) in self.leveln.iter_mut().enumerate() {
-                if i != current {
-                    f(child as &mut dyn Iterator, &self.cmp)
-                }
+        }
+        for (i, child) in self.leveln.iter_mut().enumerate() {
+            if i + level0_len != n {
+                f(child as &mut dyn Iterator, &self.cmp)
             }
         }
     }
@@ -1508,6 +2384,137 @@ mod add_boundary_tests {
         }
     }
 
+    #[test]
+    fn test_pick_compaction_within_max_compaction_bytes() {
+        let file = |number: u64, smallest: &str, largest: &str, size: u64| {
+            Arc::new(FileMetaData {
+                allowed_seeks: std::sync::atomic::AtomicUsize::new(0),
+                file_size: size,
+                number,
+                smallest: InternalKey::new(smallest.as_bytes(), 2, ValueType::Value),
+                largest: InternalKey::new(largest.as_bytes(), 1, ValueType::Value),
+            })
+        };
+        for (max_compaction_bytes, expected) in [(0, 1), (10 << 20, 2), (1 << 20, 2)] {
+            let opts = Arc::new(Options::<BytewiseComparator> {
+                max_compaction_bytes,
+                ..Default::default()
+            });
+            let mut vset = VersionSet::new("test".to_owned(), opts.clone(), MemStorage::default());
+            let table_cache =
+                TableCache::new("test".to_owned(), opts.clone(), 10, MemStorage::default());
+            let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+            let mut v = Version::new(opts.clone(), icmp);
+            v.files[1] = vec![file(1, "a", "b", 1 << 20), file(2, "c", "d", 1 << 20)];
+            // File 1 overlaps 20MB in level 2
+            v.files[2] = vec![file(3, "a", "b", 20 << 20), file(4, "c", "d", 2 << 20)];
+            v.compaction_level = 1;
+            v.compaction_score = 1.0;
+            vset.versions.push(Arc::new(v));
+            let c = vset.pick_compaction(&table_cache).unwrap();
+            let base: Vec<_> = c.inputs.base.iter().map(|f| f.number).collect();
+            assert_eq!(base, vec![expected], "{}", max_compaction_bytes);
+            assert_eq!(c.inputs.parent.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_pick_small_file_compaction() {
+        let file = |number: u64, smallest: &str, largest: &str, size: u64| {
+            Arc::new(FileMetaData {
+                allowed_seeks: std::sync::atomic::AtomicUsize::new(0),
+                file_size: size,
+                number,
+                smallest: InternalKey::new(smallest.as_bytes(), 2, ValueType::Value),
+                largest: InternalKey::new(largest.as_bytes(), 1, ValueType::Value),
+            })
+        };
+        for (trigger, expected) in [(0, None), (6, None), (5, Some(vec![4, 5, 6]))] {
+            let opts = Arc::new(Options::<BytewiseComparator> {
+                small_file_compaction_trigger: trigger,
+                max_compaction_bytes: 2600 << 10,
+                ..Default::default()
+            });
+            let mut vset = VersionSet::new("test".to_owned(), opts.clone(), MemStorage::default());
+            let table_cache =
+                TableCache::new("test".to_owned(), opts.clone(), 10, MemStorage::default());
+            let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+            let mut v = Version::new(opts.clone(), icmp);
+            let last_level = opts.max_levels - 1;
+            v.files[last_level] = vec![
+                file(1, "a", "a", 100 << 10),
+                file(2, "b", "b", 100 << 10),
+                file(3, "c", "c", 2500 << 10),
+                file(4, "d", "d", 100 << 10),
+                file(5, "e", "e", 100 << 10),
+                file(6, "f", "f", 100 << 10),
+            ];
+            v.finalize();
+            assert!(v.compaction_score < 1.0);
+            assert_eq!(v.needs_compaction(), expected.is_some());
+            vset.versions.push(Arc::new(v));
+            let c = vset.pick_compaction(&table_cache);
+            assert_eq!(c.is_some(), expected.is_some(), "{}", trigger);
+            if let (Some(c), Some(expected)) = (c, expected) {
+                assert!(matches!(c.reason, CompactionReason::SmallFiles));
+                assert_eq!((c.level, c.output_level), (last_level, last_level));
+                let base: Vec<_> = c.inputs.base.iter().map(|f| f.number).collect();
+                assert_eq!(base, expected);
+                assert!(c.inputs.parent.is_empty());
+                assert!(!c.is_trivial_move());
+            }
+        }
+    }
+
+    #[test]
+    fn test_pick_compaction_by_priority() {
+        let file = |number: u64, smallest: &str, largest: &str, size: u64| {
+            Arc::new(FileMetaData {
+                allowed_seeks: std::sync::atomic::AtomicUsize::new(0),
+                file_size: size,
+                number,
+                smallest: InternalKey::new(smallest.as_bytes(), 2, ValueType::Value),
+                largest: InternalKey::new(largest.as_bytes(), 1, ValueType::Value),
+            })
+        };
+        for (pri, expected) in [
+            (CompactionPri::RoundRobin, 6),
+            (CompactionPri::MinOverlappingRatio, 7),
+            (CompactionPri::OldestSmallestSeqFirst, 5),
+            // The tables don't exist, so their sizes are not compensated
+            (CompactionPri::ByCompensatedSize, 8),
+        ] {
+            let opts = Arc::new(Options::<BytewiseComparator> {
+                compaction_pri: pri,
+                ..Default::default()
+            });
+            let mut vset = VersionSet::new("test".to_owned(), opts.clone(), MemStorage::default());
+            let table_cache =
+                TableCache::new("test".to_owned(), opts.clone(), 10, MemStorage::default());
+            let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+            let mut v = Version::new(opts.clone(), icmp);
+            v.files[1] = vec![
+                file(6, "a", "b", 1 << 20),
+                file(7, "c", "d", 1 << 20),
+                file(8, "e", "f", 4 << 20),
+                file(5, "g", "h", 1 << 20),
+            ];
+            v.files[2] = vec![
+                file(1, "a", "b", 20 << 20),
+                file(2, "c", "d", 2 << 20),
+                file(3, "e", "f", 16 << 20),
+                file(4, "g", "h", 3 << 20),
+            ];
+            v.compaction_level = 1;
+            v.compaction_score = 1.0;
+            vset.versions.push(Arc::new(v));
+            let c = vset.pick_compaction(&table_cache).unwrap();
+            let base: Vec<_> = c.inputs.base.iter().map(|f| f.number).collect();
+            assert_eq!(base, vec![expected], "{:?}", pri);
+            assert_eq!(c.inputs.parent.len(), 1);
+        }
+    }
+
     #[test]
     fn test_version_builder_accumulate_and_apply() {
         let opts = Arc::new(Options::<BytewiseComparator>::default());